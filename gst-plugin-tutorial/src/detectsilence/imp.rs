@@ -0,0 +1,406 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_info, gst_log};
+use gst_base::prelude::*;
+use gst_base::subclass::prelude::*;
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rsdetectsilence",
+        gst::DebugColorFlags::empty(),
+        Some("Rust silence detector"),
+    )
+});
+
+// Default values of properties
+const DEFAULT_THRESHOLD: f64 = -50.0;
+const DEFAULT_HOLD_TIME: u64 = 500 * 1_000_000;
+
+// Length of the windows the RMS energy is measured over. Short enough to place
+// the boundaries precisely, long enough to not react to single quiet samples.
+const WINDOW: gst::ClockTime = gst::ClockTime::from_mseconds(10);
+
+// Property value storage
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    // Level in dBFS below which a window counts as silent
+    threshold: f64,
+    // How long the level has to stay below the threshold before silence starts
+    hold_time: gst::ClockTime,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            threshold: DEFAULT_THRESHOLD,
+            hold_time: gst::ClockTime::from_nseconds(DEFAULT_HOLD_TIME),
+        }
+    }
+}
+
+// Stream state
+#[derive(Debug, Default)]
+struct State {
+    info: Option<gst_audio::AudioInfo>,
+    // Timestamp following the last analysed sample, used for buffers without PTS
+    next_pts: Option<gst::ClockTime>,
+    // Start of the current run of quiet windows that has not reached the hold time yet
+    quiet_since: Option<gst::ClockTime>,
+    // Start of the silence that has been announced, if we are in one
+    silent_since: Option<gst::ClockTime>,
+}
+
+// A silence boundary found while analysing a buffer
+#[derive(Debug, Clone, Copy)]
+enum Boundary {
+    Start(gst::ClockTime),
+    Stop {
+        at: gst::ClockTime,
+        duration: gst::ClockTime,
+    },
+}
+
+// Struct containing all the element data
+#[derive(Default)]
+pub struct DetectSilence {
+    settings: Mutex<Settings>,
+    state: Mutex<State>,
+}
+
+impl DetectSilence {
+    // Level of a window of interleaved samples in dBFS, all channels together
+    fn rms_db(data: &[u8], format: gst_audio::AudioFormat) -> f64 {
+        let (sum, count) = if format == gst_audio::AUDIO_FORMAT_F32 {
+            data.chunks_exact(4)
+                .map(|s| f64::from(f32::from_ne_bytes([s[0], s[1], s[2], s[3]])))
+                .fold((0.0, 0usize), |(sum, n), v| (sum + v * v, n + 1))
+        } else {
+            data.chunks_exact(2)
+                .map(|s| f64::from(i16::from_ne_bytes([s[0], s[1]])) / 32768.0)
+                .fold((0.0, 0usize), |(sum, n), v| (sum + v * v, n + 1))
+        };
+        if count == 0 || sum == 0.0 {
+            return f64::NEG_INFINITY;
+        }
+        10.0 * (sum / count as f64).log10()
+    }
+
+    // Feeds one window into the state machine. Silence starts once the level has
+    // stayed below the threshold for the hold time, and is reported from the first
+    // quiet window. It stops at the first window above the threshold.
+    fn update(
+        state: &mut State,
+        settings: &Settings,
+        pts: gst::ClockTime,
+        end: gst::ClockTime,
+        quiet: bool,
+    ) -> Option<Boundary> {
+        match (quiet, state.silent_since) {
+            (true, Some(_)) => None,
+            (true, None) => {
+                let since = *state.quiet_since.get_or_insert(pts);
+                if end.saturating_sub(since) < settings.hold_time {
+                    return None;
+                }
+                state.quiet_since = None;
+                state.silent_since = Some(since);
+                Some(Boundary::Start(since))
+            }
+            (false, Some(since)) => {
+                state.silent_since = None;
+                Some(Boundary::Stop {
+                    at: pts,
+                    duration: pts.saturating_sub(since),
+                })
+            }
+            (false, None) => {
+                state.quiet_since = None;
+                None
+            }
+        }
+    }
+
+    fn post(&self, element: &super::DetectSilence, boundary: Boundary) {
+        let segment = element.segment();
+        let running_time = |ts: gst::ClockTime| {
+            segment
+                .downcast_ref::<gst::ClockTime>()
+                .and_then(|segment| segment.to_running_time(ts))
+        };
+        let s = match boundary {
+            Boundary::Start(at) => {
+                gst_info!(CAT, obj: element, "Silence started at {}", at);
+                gst::Structure::builder("rsdetectsilence-start")
+                    .field("timestamp", at)
+                    .field("running-time", running_time(at))
+                    .build()
+            }
+            Boundary::Stop { at, duration } => {
+                gst_info!(
+                    CAT,
+                    obj: element,
+                    "Silence stopped at {} after {}",
+                    at,
+                    duration
+                );
+                gst::Structure::builder("rsdetectsilence-stop")
+                    .field("timestamp", at)
+                    .field("running-time", running_time(at))
+                    .field("duration", duration)
+                    .build()
+            }
+        };
+        let _ = element.post_message(gst::message::Element::builder(s).src(element).build());
+    }
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for DetectSilence {
+    const NAME: &'static str = "RsDetectSilence";
+    type Type = super::DetectSilence;
+    type ParentType = gst_base::BaseTransform;
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for DetectSilence {
+    fn properties() -> &'static [glib::ParamSpec] {
+        // Metadata for the properties
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecDouble::new(
+                    "threshold",
+                    "Threshold",
+                    "Level in dBFS below which the audio counts as silent",
+                    -120.0,
+                    0.0,
+                    DEFAULT_THRESHOLD,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecUInt64::new(
+                    "hold-time",
+                    "Hold Time",
+                    "Time in nanoseconds the level has to stay below the threshold before silence starts",
+                    0,
+                    u64::MAX,
+                    DEFAULT_HOLD_TIME,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    // Called whenever a value of a property is changed. It can be called
+    // at any time from any thread.
+    fn set_property(
+        &self,
+        obj: &Self::Type,
+        _id: usize,
+        value: &glib::Value,
+        pspec: &glib::ParamSpec,
+    ) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "threshold" => {
+                let threshold = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing threshold from {} to {}",
+                    settings.threshold,
+                    threshold
+                );
+                settings.threshold = threshold;
+            }
+            "hold-time" => {
+                let hold_time = gst::ClockTime::from_nseconds(
+                    value.get::<u64>().expect("type checked upstream"),
+                );
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing hold-time from {} to {}",
+                    settings.hold_time,
+                    hold_time
+                );
+                settings.hold_time = hold_time;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    // Called whenever a value of a property is read. It can be called
+    // at any time from any thread.
+    fn property(&self, _obj: &Self::Type, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "threshold" => settings.threshold.to_value(),
+            "hold-time" => settings.hold_time.nseconds().to_value(),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl GstObjectImpl for DetectSilence {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for DetectSilence {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Silence Detector",
+                "Filter/Analyzer/Audio",
+                "Posts element messages when the audio becomes silent and when it stops being silent",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // The audio passes through unchanged, so the same caps are used on both pads
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("audio/x-raw")
+                .field(
+                    "format",
+                    gst::List::new([
+                        gst_audio::AUDIO_FORMAT_F32.to_str(),
+                        gst_audio::AUDIO_FORMAT_S16.to_str(),
+                    ]),
+                )
+                .field("layout", "interleaved")
+                .field("rate", gst::IntRange::new(1, i32::MAX))
+                .field("channels", gst::IntRange::new(1, i32::MAX))
+                .build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+            let sink_pad_template = gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![src_pad_template, sink_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+// Implementation of gst_base::BaseTransform virtual methods
+impl BaseTransformImpl for DetectSilence {
+    // We only look at the samples, so buffers are passed through untouched
+    const MODE: gst_base::subclass::BaseTransformMode =
+        gst_base::subclass::BaseTransformMode::AlwaysInPlace;
+    const PASSTHROUGH_ON_SAME_CAPS: bool = true;
+    const TRANSFORM_IP_ON_PASSTHROUGH: bool = true;
+
+    fn start(&self, element: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = Default::default();
+        gst_info!(CAT, obj: element, "Started");
+        Ok(())
+    }
+
+    fn stop(&self, element: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = Default::default();
+        gst_info!(CAT, obj: element, "Stopped");
+        Ok(())
+    }
+
+    fn set_caps(
+        &self,
+        element: &Self::Type,
+        incaps: &gst::Caps,
+        outcaps: &gst::Caps,
+    ) -> Result<(), gst::LoggableError> {
+        let info = gst_audio::AudioInfo::from_caps(incaps)
+            .map_err(|_| gst::loggable_error!(CAT, "Failed to build `AudioInfo` from caps"))?;
+        gst_debug!(CAT, obj: element, "Configuring for caps {}", incaps);
+
+        self.state.lock().unwrap().info = Some(info);
+
+        self.parent_set_caps(element, incaps, outcaps)
+    }
+
+    // A flush starts over, a silence that was going on before is forgotten
+    fn sink_event(&self, element: &Self::Type, event: gst::Event) -> bool {
+        if let gst::EventView::FlushStop(_) = event.view() {
+            let mut state = self.state.lock().unwrap();
+            state.next_pts = None;
+            state.quiet_since = None;
+            state.silent_since = None;
+        }
+        self.parent_sink_event(element, event)
+    }
+
+    fn transform_ip_passthrough(
+        &self,
+        element: &Self::Type,
+        buf: &gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let settings = *self.settings.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let info = state.info.clone().ok_or_else(|| {
+            gst::element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+            gst::FlowError::NotNegotiated
+        })?;
+
+        let map = buf.map_readable().map_err(|_| {
+            gst::element_error!(element, gst::CoreError::Failed, ["Failed to map buffer"]);
+            gst::FlowError::Error
+        })?;
+
+        let rate = u64::from(info.rate());
+        let bpf = info.bpf() as usize;
+        let window_frames = (WINDOW.nseconds() * rate / gst::ClockTime::SECOND.nseconds()).max(1);
+        let start = buf.pts().or(state.next_pts).unwrap_or(gst::ClockTime::ZERO);
+        let frame_time = |frames: u64| {
+            gst::ClockTime::from_nseconds(
+                frames
+                    .mul_div_floor(gst::ClockTime::SECOND.nseconds(), rate)
+                    .unwrap_or(0),
+            )
+        };
+
+        let mut boundaries = Vec::new();
+        let mut frames = 0;
+        for window in map.chunks(window_frames as usize * bpf) {
+            let pts = start + frame_time(frames);
+            frames += (window.len() / bpf) as u64;
+            let end = start + frame_time(frames);
+            let level = Self::rms_db(window, info.format());
+            gst_log!(CAT, obj: element, "Level at {}: {:.1} dB", pts, level);
+            let quiet = level < settings.threshold;
+            if let Some(boundary) = Self::update(&mut state, &settings, pts, end, quiet) {
+                boundaries.push(boundary);
+            }
+        }
+        state.next_pts = Some(start + frame_time(frames));
+        drop(state);
+
+        for boundary in boundaries {
+            self.post(element, boundary);
+        }
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+}
@@ -0,0 +1,363 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_info, gst_log};
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rsframesplit",
+        gst::DebugColorFlags::empty(),
+        Some("Rust even/odd frame splitter"),
+    )
+});
+
+// Names of the sometimes pads, indexed by the parity of the frame number
+const PAD_NAMES: [&str; 2] = ["even", "odd"];
+
+// Stream related state, reset whenever the element goes back to Ready
+#[derive(Default)]
+struct State {
+    // The src pads, created once the first buffer arrives
+    srcpads: Vec<gst::Pad>,
+    // Latest sticky events from upstream, replayed on newly created pads
+    group_id: Option<gst::GroupId>,
+    caps: Option<gst::Caps>,
+    segment: Option<gst::Event>,
+    // Number of the next incoming frame
+    frame: u64,
+    // Combines the flow returns of all src pads into one for upstream
+    flow_combiner: gst_base::UniqueFlowCombiner,
+}
+
+// Struct containing all the element data
+pub struct FrameSplit {
+    sinkpad: gst::Pad,
+    state: Mutex<State>,
+}
+
+impl FrameSplit {
+    // Every other frame goes to the other stream, so the framerate is halved
+    fn output_caps(caps: &gst::Caps) -> gst::Caps {
+        let mut caps = caps.clone();
+        for s in caps.make_mut().iter_mut() {
+            if let Ok(fps) = s.get::<gst::Fraction>("framerate") {
+                if fps.numer() > 0 {
+                    s.set(
+                        "framerate",
+                        gst::Fraction::new(fps.numer(), fps.denom() * 2),
+                    );
+                }
+            }
+        }
+        caps
+    }
+
+    // Creates both sometimes pads. Before the pads are added to the element
+    // they are activated and the stream-start, caps and segment events are
+    // stored on them as sticky events, so that whatever gets linked in the
+    // pad-added handler immediately sees a fully configured stream.
+    fn create_srcpads(&self, element: &super::FrameSplit, state: &mut State) -> Vec<gst::Pad> {
+        let group_id = *state.group_id.get_or_insert_with(gst::GroupId::next);
+        let caps = state.caps.as_ref().map(Self::output_caps);
+
+        let pads = PAD_NAMES
+            .iter()
+            .map(|name| {
+                let templ = element.pad_template(name).unwrap();
+                let pad = gst::Pad::builder_with_template(&templ, Some(name))
+                    .event_function(|pad, parent, event| {
+                        FrameSplit::catch_panic_pad_function(
+                            parent,
+                            || false,
+                            |split, element| split.src_event(pad, element, event),
+                        )
+                    })
+                    .query_function(|pad, parent, query| {
+                        FrameSplit::catch_panic_pad_function(
+                            parent,
+                            || false,
+                            |split, element| split.src_query(pad, element, query),
+                        )
+                    })
+                    .build();
+
+                pad.set_active(true).unwrap();
+                let stream_id = pad.create_stream_id(element, Some(name));
+                pad.push_event(
+                    gst::event::StreamStart::builder(&stream_id)
+                        .group_id(group_id)
+                        .build(),
+                );
+                if let Some(ref caps) = caps {
+                    pad.push_event(gst::event::Caps::new(caps));
+                }
+                if let Some(ref segment) = state.segment {
+                    pad.push_event(segment.clone());
+                }
+
+                state.flow_combiner.add_pad(&pad);
+                pad
+            })
+            .collect::<Vec<_>>();
+
+        state.srcpads = pads.clone();
+        pads
+    }
+
+    // Removes the src pads again, e.g. when going back to Ready
+    fn remove_srcpads(&self, element: &super::FrameSplit) {
+        let pads = std::mem::take(&mut *self.state.lock().unwrap()).srcpads;
+        for pad in pads {
+            gst_debug!(CAT, obj: element, "Removing pad {}", pad.name());
+            let _ = pad.set_active(false);
+            let _ = element.remove_pad(&pad);
+        }
+    }
+
+    // Sends an event to all src pads, returns true if any of them accepted it
+    fn push_to_srcpads(&self, event: gst::Event) -> bool {
+        let pads = self.state.lock().unwrap().srcpads.clone();
+        let mut res = false;
+        for pad in pads {
+            res |= pad.push_event(event.clone());
+        }
+        res
+    }
+
+    fn sink_chain(
+        &self,
+        _pad: &gst::Pad,
+        element: &super::FrameSplit,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let mut state = self.state.lock().unwrap();
+
+        // Pads are created lazily on the first buffer, like a demuxer that
+        // only knows its streams after parsing the beginning of the data
+        let new_pads = if state.srcpads.is_empty() {
+            self.create_srcpads(element, &mut state)
+        } else {
+            Vec::new()
+        };
+
+        let pad = state.srcpads[(state.frame % 2) as usize].clone();
+        state.frame += 1;
+
+        // Adding pads emits pad-added, whose handlers may link and set states,
+        // so neither that nor pushing may happen with our lock held
+        drop(state);
+        if !new_pads.is_empty() {
+            for pad in &new_pads {
+                gst_info!(CAT, obj: element, "Adding pad {}", pad.name());
+                element.add_pad(pad).unwrap();
+            }
+            element.no_more_pads();
+        }
+
+        gst_log!(CAT, obj: &pad, "Pushing {:?}", buffer);
+        let res = pad.push(buffer);
+
+        // A single unlinked stream is fine, only if all of them are unlinked
+        // (or one of them fails) upstream has to stop
+        self.state
+            .lock()
+            .unwrap()
+            .flow_combiner
+            .update_pad_flow(&pad, res)
+    }
+
+    fn sink_event(&self, pad: &gst::Pad, element: &super::FrameSplit, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        gst_log!(CAT, obj: pad, "Handling event {:?}", event);
+        match event.view() {
+            // Our pads get their own stream ids, only the group is taken over
+            EventView::StreamStart(ev) => {
+                self.state.lock().unwrap().group_id = ev.group_id();
+                true
+            }
+            EventView::Caps(ev) => {
+                let caps = ev.caps_owned();
+                let out = Self::output_caps(&caps);
+                self.state.lock().unwrap().caps = Some(caps);
+                self.push_to_srcpads(gst::event::Caps::new(&out));
+                true
+            }
+            EventView::Segment(_) => {
+                self.state.lock().unwrap().segment = Some(event.clone());
+                self.push_to_srcpads(event);
+                true
+            }
+            EventView::FlushStop(_) => {
+                // Forget the flow returns from before the flush, the pads are
+                // usable again afterwards
+                self.state.lock().unwrap().flow_combiner.reset();
+                self.push_to_srcpads(event)
+            }
+            EventView::Eos(_) => {
+                if self.state.lock().unwrap().srcpads.is_empty() {
+                    gst::element_error!(
+                        element,
+                        gst::StreamError::Demux,
+                        ["Received EOS before any frame, no streams were created"]
+                    );
+                    return false;
+                }
+                self.push_to_srcpads(event)
+            }
+            _ => self.push_to_srcpads(event),
+        }
+    }
+
+    // Seeks, QoS and other upstream events from any of the streams go to the
+    // single upstream element
+    fn src_event(&self, pad: &gst::Pad, _element: &super::FrameSplit, event: gst::Event) -> bool {
+        gst_log!(CAT, obj: pad, "Handling event {:?}", event);
+        self.sinkpad.push_event(event)
+    }
+
+    fn src_query(
+        &self,
+        pad: &gst::Pad,
+        _element: &super::FrameSplit,
+        query: &mut gst::QueryRef,
+    ) -> bool {
+        use gst::QueryView;
+
+        gst_log!(CAT, obj: pad, "Handling query {:?}", query);
+        match query.view_mut() {
+            // The caps on our pads are fixed by what upstream sends
+            QueryView::Caps(mut q) => {
+                let caps = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .caps
+                    .as_ref()
+                    .map(Self::output_caps)
+                    .unwrap_or_else(|| pad.pad_template_caps());
+                q.set_result(&caps);
+                true
+            }
+            _ => self.sinkpad.peer_query(query),
+        }
+    }
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for FrameSplit {
+    const NAME: &'static str = "RsFrameSplit";
+    type Type = super::FrameSplit;
+    type ParentType = gst::Element;
+
+    // Called when a new instance is to be created. Only the sink pad exists
+    // from the start, the src pads are created at runtime.
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("sink").unwrap();
+        let sinkpad = gst::Pad::builder_with_template(&templ, Some("sink"))
+            .chain_function(|pad, parent, buffer| {
+                FrameSplit::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |split, element| split.sink_chain(pad, element, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                FrameSplit::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |split, element| split.sink_event(pad, element, event),
+                )
+            })
+            .build();
+
+        Self {
+            sinkpad,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for FrameSplit {
+    // Called right after construction of a new instance
+    fn constructed(&self, obj: &Self::Type) {
+        // Call the parent class' ::constructed() implementation first
+        self.parent_constructed(obj);
+
+        obj.add_pad(&self.sinkpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for FrameSplit {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for FrameSplit {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Even/Odd Frame Splitter",
+                "Demuxer/Video",
+                "Splits a video stream into its even and odd frames",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // The sink pad always exists, the src pads are "sometimes" pads that
+    // appear once data flows and are announced with pad-added
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("video/x-raw").build();
+            let mut templates = vec![gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap()];
+            templates.extend(PAD_NAMES.iter().map(|name| {
+                gst::PadTemplate::new(
+                    name,
+                    gst::PadDirection::Src,
+                    gst::PadPresence::Sometimes,
+                    &caps,
+                )
+                .unwrap()
+            }));
+
+            templates
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+
+    // Called whenever the state of the element should be changed
+    fn change_state(
+        &self,
+        element: &Self::Type,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst_debug!(CAT, obj: element, "Changing state {:?}", transition);
+
+        let res = self.parent_change_state(element, transition)?;
+
+        // The streams only exist while data can flow, a restart creates them anew
+        if transition == gst::StateChange::PausedToReady {
+            self.remove_srcpads(element);
+        }
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+
+mod imp;
+
+// The public Rust wrapper type for our element
+glib::wrapper! {
+    pub struct FrameSplit(ObjectSubclass<imp::FrameSplit>) @extends gst::Element, gst::Object;
+}
+
+// Registers the type for our element, and then registers in GStreamer under
+// the name "rsframesplit" for being able to instantiate it via e.g.
+// gst::ElementFactory::make().
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "rsframesplit",
+        gst::Rank::None,
+        FrameSplit::static_type(),
+    )
+}
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+
+use once_cell::sync::Lazy;
+
+// Struct containing all the element data
+//
+// The bin contains videoconvert ! rsrgb2gray ! videoconvert and exposes the
+// sink pad of the first and the src pad of the last element through ghost pads,
+// so it can be dropped into any pipeline regardless of the upstream format.
+pub struct GrayBin {
+    sinkpad: gst::GhostPad,
+    srcpad: gst::GhostPad,
+    convert_in: gst::Element,
+    filter: gst::Element,
+    convert_out: gst::Element,
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for GrayBin {
+    const NAME: &'static str = "RsGrayBin";
+    type Type = super::GrayBin;
+    type ParentType = gst::Bin;
+
+    // Called when a new instance is to be created. The ghost pads are created
+    // here without a target, they are connected to the child elements once the
+    // object is constructed.
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("sink").unwrap();
+        let sinkpad = gst::GhostPad::from_template(&templ, Some("sink"));
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gst::GhostPad::from_template(&templ, Some("src"));
+
+        let convert_in = gst::ElementFactory::make("videoconvert", Some("convert_in"))
+            .expect("videoconvert is missing");
+        let filter =
+            gst::ElementFactory::make("rsrgb2gray", Some("filter")).expect("rsrgb2gray is missing");
+        let convert_out = gst::ElementFactory::make("videoconvert", Some("convert_out"))
+            .expect("videoconvert is missing");
+
+        Self {
+            sinkpad,
+            srcpad,
+            convert_in,
+            filter,
+            convert_out,
+        }
+    }
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for GrayBin {
+    fn properties() -> &'static [glib::ParamSpec] {
+        // The properties of the filter are exposed on the bin with the same
+        // metadata and forwarded to the child element
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecBoolean::new(
+                    "invert",
+                    "Invert",
+                    "Invert grayscale output",
+                    false,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecUInt::new(
+                    "shift",
+                    "Shift",
+                    "Shift grayscale output (wrapping around)",
+                    0,
+                    255,
+                    0,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(
+        &self,
+        _obj: &Self::Type,
+        _id: usize,
+        value: &glib::Value,
+        pspec: &glib::ParamSpec,
+    ) {
+        match pspec.name() {
+            "invert" | "shift" => self.filter.set_property_from_value(pspec.name(), value),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _obj: &Self::Type, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "invert" | "shift" => self.filter.property_value(pspec.name()),
+            _ => unimplemented!(),
+        }
+    }
+
+    // Called right after construction of a new instance
+    fn constructed(&self, obj: &Self::Type) {
+        // Call the parent class' ::constructed() implementation first
+        self.parent_constructed(obj);
+
+        // Add the children to the bin and link them
+        obj.add_many(&[&self.convert_in, &self.filter, &self.convert_out])
+            .unwrap();
+        gst::Element::link_many(&[&self.convert_in, &self.filter, &self.convert_out]).unwrap();
+
+        // Point the ghost pads at the outermost children and add them to the
+        // bin, which makes them visible from the outside
+        self.sinkpad
+            .set_target(Some(&self.convert_in.static_pad("sink").unwrap()))
+            .unwrap();
+        self.srcpad
+            .set_target(Some(&self.convert_out.static_pad("src").unwrap()))
+            .unwrap();
+        obj.add_pad(&self.sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for GrayBin {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for GrayBin {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "RGB-GRAY Converter Bin",
+                "Filter/Effect/Converter/Video",
+                "Converts any raw video to grayscale with rsrgb2gray",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // The videoconvert elements accept and produce any raw video, so the
+    // ghost pads do the same
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("video/x-raw").build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+            let sink_pad_template = gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![src_pad_template, sink_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+// Implementation of gst::Bin virtual methods. The default behaviour of
+// GstBin is what we want, the children are managed through the ghost pads.
+impl BinImpl for GrayBin {}
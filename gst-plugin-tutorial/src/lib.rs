@@ -3,10 +3,25 @@
 
 use gst::glib;
 
+mod detectsilence;
+mod framesplit;
+mod graybin;
 mod rgb2gray;
+mod roi;
+mod sinegen;
+mod stacker;
+
+#[cfg(feature = "testing")]
+pub mod testing;
 
 fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     rgb2gray::register(plugin)?;
+    roi::register(plugin)?;
+    graybin::register(plugin)?;
+    framesplit::register(plugin)?;
+    stacker::register(plugin)?;
+    sinegen::register(plugin)?;
+    detectsilence::register(plugin)?;
     Ok(())
 }
 
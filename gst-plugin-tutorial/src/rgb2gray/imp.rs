@@ -13,6 +13,7 @@ use gst::gst_debug;
 use gst::gst_info;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
+use gst_base::prelude::*;
 use gst_base::subclass::prelude::*;
 use gst_video::subclass::prelude::*;
 
@@ -51,10 +52,28 @@ impl Default for Settings {
     }
 }
 
+// QoS bookkeeping. earliest_time is the running time before which downstream told us
+// frames would arrive too late to be displayed.
+#[derive(Debug, Default)]
+struct QosState {
+    earliest_time: Option<gst::ClockTime>,
+    processed: u64,
+    skipped: u64,
+}
+
+// Stream state that depends on the negotiated caps. It is (re)created every time
+// caps are negotiated, which can also happen in the middle of the stream.
+struct State {
+    in_info: gst_video::VideoInfo,
+    out_info: gst_video::VideoInfo,
+}
+
 // Struct containing all the element data
 #[derive(Default)]
 pub struct Rgb2Gray {
     settings: Mutex<Settings>,
+    state: Mutex<Option<State>>,
+    qos: Mutex<QosState>,
 }
 
 impl Rgb2Gray {
@@ -82,6 +101,51 @@ impl Rgb2Gray {
             gray
         }
     }
+
+    // Remembers the deadline from a QoS event sent by downstream. Like GstBaseTransform we
+    // add twice the jitter when we are late, to give us some time to catch up again.
+    fn update_qos(&self, element: &super::Rgb2Gray, diff: i64, timestamp: gst::ClockTime) {
+        let earliest_time = if diff > 0 {
+            timestamp + gst::ClockTime::from_nseconds(2 * diff as u64)
+        } else {
+            timestamp.saturating_sub(gst::ClockTime::from_nseconds(diff.unsigned_abs()))
+        };
+
+        gst_debug!(
+            CAT,
+            obj: element,
+            "QoS update: diff {} timestamp {} earliest {}",
+            diff,
+            timestamp,
+            earliest_time
+        );
+        self.qos.lock().unwrap().earliest_time = Some(earliest_time);
+    }
+
+    // Checks if a buffer would arrive too late downstream and counts it either way
+    fn is_late(&self, element: &super::Rgb2Gray, buffer: &gst::BufferRef) -> bool {
+        let mut qos = self.qos.lock().unwrap();
+
+        let late = if element.is_qos() {
+            let segment = element.segment();
+            let running_time = segment
+                .downcast_ref::<gst::ClockTime>()
+                .and_then(|segment| segment.to_running_time(buffer.pts()));
+            matches!(
+                (running_time, qos.earliest_time),
+                (Some(running_time), Some(earliest_time)) if running_time <= earliest_time
+            )
+        } else {
+            false
+        };
+
+        if late {
+            qos.skipped += 1;
+        } else {
+            qos.processed += 1;
+        }
+        late
+    }
 }
 
 // This trait registers our type with the GObject object system and
@@ -130,6 +194,24 @@ impl ObjectImpl for Rgb2Gray {
                     DEFAULT_SHIFT,
                     glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
                 ),
+                glib::ParamSpecUInt64::new(
+                    "processed",
+                    "Processed",
+                    "Number of frames converted",
+                    0,
+                    u64::MAX,
+                    0,
+                    glib::ParamFlags::READABLE,
+                ),
+                glib::ParamSpecUInt64::new(
+                    "skipped",
+                    "Skipped",
+                    "Number of late frames skipped because of QoS",
+                    0,
+                    u64::MAX,
+                    0,
+                    glib::ParamFlags::READABLE,
+                ),
             ]
         });
 
@@ -186,6 +268,14 @@ impl ObjectImpl for Rgb2Gray {
                 let settings = self.settings.lock().unwrap();
                 settings.shift.to_value()
             }
+            "processed" => {
+                let qos = self.qos.lock().unwrap();
+                qos.processed.to_value()
+            }
+            "skipped" => {
+                let qos = self.qos.lock().unwrap();
+                qos.skipped.to_value()
+            }
             _ => unimplemented!(),
         }
     }
@@ -294,6 +384,83 @@ impl BaseTransformImpl for Rgb2Gray {
     const PASSTHROUGH_ON_SAME_CAPS: bool = false;
     const TRANSFORM_IP_ON_PASSTHROUGH: bool = false;
 
+    // Reset the stream and QoS state when going back to READY
+    fn stop(&self, _element: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = None;
+        *self.qos.lock().unwrap() = QosState::default();
+        Ok(())
+    }
+
+    // After a flush all previous deadlines are meaningless
+    fn sink_event(&self, element: &Self::Type, event: gst::Event) -> bool {
+        if let gst::EventView::FlushStop(..) = event.view() {
+            self.qos.lock().unwrap().earliest_time = None;
+        }
+        self.parent_sink_event(element, event)
+    }
+
+    // QoS events travel upstream from the sink and tell how late buffers are arriving there.
+    //
+    // We remember the deadline ourselves so that the logic is visible here, and then chain up
+    // so that GstBaseTransform keeps its own QoS state and forwards the event further upstream
+    // for the sources to throttle.
+    fn src_event(&self, element: &Self::Type, event: gst::Event) -> bool {
+        if let gst::EventView::Qos(qos) = event.view() {
+            let (_type, _proportion, diff, timestamp) = qos.get();
+            if let Some(timestamp) = timestamp {
+                self.update_qos(element, diff, timestamp);
+            }
+        }
+        self.parent_src_event(element, event)
+    }
+
+    // GstBaseTransform drops frames it knows to be late before they reach transform_frame.
+    // Our deadline is never earlier than its own, so checking here first means every
+    // late frame is counted. The "qos" property (enabled by default for video filters)
+    // decides if late frames are skipped. Skipping means not converting and not
+    // outputting anything.
+    fn submit_input_buffer(
+        &self,
+        element: &Self::Type,
+        is_discont: bool,
+        inbuf: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        if self.is_late(element, &inbuf) {
+            gst_debug!(CAT, obj: element, "skipping late frame {:?}", inbuf.pts());
+            return Ok(gst::FlowSuccess::Ok);
+        }
+        self.parent_submit_input_buffer(element, is_discont, inbuf)
+    }
+
+    // Latency queries on the src pad are answered by asking upstream. Every
+    // frame is converted synchronously in the streaming thread and pushed out
+    // right away, so nothing is added on top of the upstream latency. This is
+    // what GstBaseTransform does by default, it is spelled out here to make
+    // the answer visible in the debug log.
+    fn query(
+        &self,
+        element: &Self::Type,
+        direction: gst::PadDirection,
+        query: &mut gst::QueryRef,
+    ) -> bool {
+        let ret = BaseTransformImplExt::parent_query(self, element, direction, query);
+        if direction != gst::PadDirection::Src {
+            return ret;
+        }
+        if let gst::QueryView::Latency(q) = query.view() {
+            let (live, min, max) = q.result();
+            gst_debug!(
+                CAT,
+                obj: element,
+                "Latency live={} min={} max={}, adding none",
+                live,
+                min,
+                max.display()
+            );
+        }
+        ret
+    }
+
     // Called for converting caps from one pad to another to account for any
     // changes in the media format this element is performing.
     //
@@ -357,6 +524,52 @@ impl BaseTransformImpl for Rgb2Gray {
 }
 
 impl VideoFilterImpl for Rgb2Gray {
+    // Called with the negotiated caps before the first buffer and again whenever upstream
+    // changes the caps in the middle of the stream, e.g. a different resolution. We replace
+    // our state and let the application know by posting an element message on the bus.
+    fn set_info(
+        &self,
+        element: &Self::Type,
+        incaps: &gst::Caps,
+        in_info: &gst_video::VideoInfo,
+        outcaps: &gst::Caps,
+        out_info: &gst_video::VideoInfo,
+    ) -> Result<(), gst::LoggableError> {
+        let mut state = self.state.lock().unwrap();
+
+        let renegotiated = match &*state {
+            Some(old) => old.in_info != *in_info || old.out_info != *out_info,
+            None => false,
+        };
+
+        gst_debug!(
+            CAT,
+            obj: element,
+            "Configured for caps {} to {} (renegotiated: {})",
+            incaps,
+            outcaps,
+            renegotiated
+        );
+
+        *state = Some(State {
+            in_info: in_info.clone(),
+            out_info: out_info.clone(),
+        });
+        drop(state);
+
+        if renegotiated {
+            let s = gst::Structure::builder("rsrgb2gray-renegotiated")
+                .field("width", in_info.width())
+                .field("height", in_info.height())
+                .field("in-format", in_info.format().to_str())
+                .field("out-format", out_info.format().to_str())
+                .build();
+            let _ = element.post_message(gst::message::Element::builder(s).src(element).build());
+        }
+
+        self.parent_set_info(element, incaps, in_info, outcaps, out_info)
+    }
+
     // Does the actual transformation of the input buffer to the output buffer
     fn transform_frame(
         &self,
@@ -369,9 +582,19 @@ impl VideoFilterImpl for Rgb2Gray {
         // have to block until this function returns when getting/setting property values
         let settings = *self.settings.lock().unwrap();
 
+        // The frames are mapped with the currently negotiated caps, so they have to match
+        // what we were configured with in set_info.
+        let width = {
+            let state = self.state.lock().unwrap();
+            let state = state.as_ref().ok_or(gst::FlowError::NotNegotiated)?;
+            if in_frame.info() != &state.in_info || out_frame.info() != &state.out_info {
+                return Err(gst::FlowError::NotNegotiated);
+            }
+            state.in_info.width() as usize
+        };
+
         // Keep the various metadata we need for working with the video frames in
         // local variables. This saves some typing below.
-        let width = in_frame.width() as usize;
         let in_stride = in_frame.plane_stride()[0] as usize;
         let in_data = in_frame.plane_data(0).unwrap();
         let out_stride = out_frame.plane_stride()[0] as usize;
@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::gst_info;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst_base::subclass::prelude::*;
+use gst_video::subclass::prelude::*;
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use super::RoiMode;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rsroi",
+        gst::DebugColorFlags::empty(),
+        Some("Rust region of interest masking"),
+    )
+});
+
+// Default values of properties
+const DEFAULT_X: u32 = 0;
+const DEFAULT_Y: u32 = 0;
+const DEFAULT_WIDTH: u32 = 64;
+const DEFAULT_HEIGHT: u32 = 64;
+const DEFAULT_MODE: RoiMode = RoiMode::Black;
+
+// Size of the square blocks used when pixelating
+const PIXELATE_BLOCK: usize = 16;
+// Radius of the box filter used when blurring
+const BLUR_RADIUS: usize = 8;
+
+// Property value storage
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    mode: RoiMode,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            x: DEFAULT_X,
+            y: DEFAULT_Y,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            mode: DEFAULT_MODE,
+        }
+    }
+}
+
+// The region to mask, clipped to the frame and converted to byte offsets
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+    stride: usize,
+    pixel_stride: usize,
+}
+
+impl Region {
+    #[inline]
+    fn offset(&self, x: usize, y: usize) -> usize {
+        y * self.stride + x * self.pixel_stride
+    }
+}
+
+// Struct containing all the element data
+#[derive(Default)]
+pub struct Roi {
+    settings: Mutex<Settings>,
+}
+
+impl Roi {
+    fn fill_black(data: &mut [u8], region: &Region) {
+        let line_bytes = (region.x1 - region.x0) * region.pixel_stride;
+        for y in region.y0..region.y1 {
+            let start = region.offset(region.x0, y);
+            data[start..start + line_bytes].fill(0);
+        }
+    }
+
+    // Replaces each block of the region with the average of its pixels
+    fn pixelate(data: &mut [u8], region: &Region) {
+        let ps = region.pixel_stride;
+
+        for by in (region.y0..region.y1).step_by(PIXELATE_BLOCK) {
+            let by1 = (by + PIXELATE_BLOCK).min(region.y1);
+            for bx in (region.x0..region.x1).step_by(PIXELATE_BLOCK) {
+                let bx1 = (bx + PIXELATE_BLOCK).min(region.x1);
+
+                let mut sum = [0u32; 4];
+                for y in by..by1 {
+                    for x in bx..bx1 {
+                        let p = region.offset(x, y);
+                        for (s, v) in sum.iter_mut().zip(&data[p..p + ps]) {
+                            *s += u32::from(*v);
+                        }
+                    }
+                }
+
+                let count = ((by1 - by) * (bx1 - bx)) as u32;
+                let mut avg = [0u8; 4];
+                for (a, s) in avg.iter_mut().zip(sum) {
+                    *a = (s / count) as u8;
+                }
+
+                for y in by..by1 {
+                    for x in bx..bx1 {
+                        let p = region.offset(x, y);
+                        data[p..p + ps].copy_from_slice(&avg[..ps]);
+                    }
+                }
+            }
+        }
+    }
+
+    // Applies a separable box blur restricted to the region. Pixels outside of the
+    // region are not sampled so nothing of the masked content leaks out of it and
+    // nothing outside of it is modified.
+    fn blur(data: &mut [u8], region: &Region) {
+        let ps = region.pixel_stride;
+        let w = region.x1 - region.x0;
+        let h = region.y1 - region.y0;
+
+        // Copy the region into a tightly packed scratch buffer
+        let mut src = vec![0u8; w * h * ps];
+        for (y, line) in src.chunks_exact_mut(w * ps).enumerate() {
+            let start = region.offset(region.x0, region.y0 + y);
+            line.copy_from_slice(&data[start..start + w * ps]);
+        }
+
+        // Horizontal pass
+        let mut tmp = vec![0u8; w * h * ps];
+        for y in 0..h {
+            for x in 0..w {
+                let lo = x.saturating_sub(BLUR_RADIUS);
+                let hi = (x + BLUR_RADIUS + 1).min(w);
+                for c in 0..ps {
+                    let sum: u32 = (lo..hi)
+                        .map(|sx| u32::from(src[(y * w + sx) * ps + c]))
+                        .sum();
+                    tmp[(y * w + x) * ps + c] = (sum / (hi - lo) as u32) as u8;
+                }
+            }
+        }
+
+        // Vertical pass, written back into the frame
+        for y in 0..h {
+            let lo = y.saturating_sub(BLUR_RADIUS);
+            let hi = (y + BLUR_RADIUS + 1).min(h);
+            for x in 0..w {
+                let p = region.offset(region.x0 + x, region.y0 + y);
+                for c in 0..ps {
+                    let sum: u32 = (lo..hi)
+                        .map(|sy| u32::from(tmp[(sy * w + x) * ps + c]))
+                        .sum();
+                    data[p + c] = (sum / (hi - lo) as u32) as u8;
+                }
+            }
+        }
+    }
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for Roi {
+    const NAME: &'static str = "RsRoi";
+    type Type = super::Roi;
+    type ParentType = gst_video::VideoFilter;
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for Roi {
+    fn properties() -> &'static [glib::ParamSpec] {
+        // Metadata for the properties
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecUInt::new(
+                    "x",
+                    "X",
+                    "Horizontal position of the region",
+                    0,
+                    u32::MAX,
+                    DEFAULT_X,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecUInt::new(
+                    "y",
+                    "Y",
+                    "Vertical position of the region",
+                    0,
+                    u32::MAX,
+                    DEFAULT_Y,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecUInt::new(
+                    "width",
+                    "Width",
+                    "Width of the region",
+                    0,
+                    u32::MAX,
+                    DEFAULT_WIDTH,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecUInt::new(
+                    "height",
+                    "Height",
+                    "Height of the region",
+                    0,
+                    u32::MAX,
+                    DEFAULT_HEIGHT,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecEnum::new(
+                    "mode",
+                    "Mode",
+                    "How to mask the region",
+                    RoiMode::static_type(),
+                    DEFAULT_MODE as i32,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    // Called whenever a value of a property is changed. It can be called
+    // at any time from any thread.
+    fn set_property(
+        &self,
+        obj: &Self::Type,
+        _id: usize,
+        value: &glib::Value,
+        pspec: &glib::ParamSpec,
+    ) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "x" => {
+                let x = value.get().expect("type checked upstream");
+                gst_info!(CAT, obj: obj, "Changing x from {} to {}", settings.x, x);
+                settings.x = x;
+            }
+            "y" => {
+                let y = value.get().expect("type checked upstream");
+                gst_info!(CAT, obj: obj, "Changing y from {} to {}", settings.y, y);
+                settings.y = y;
+            }
+            "width" => {
+                let width = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing width from {} to {}",
+                    settings.width,
+                    width
+                );
+                settings.width = width;
+            }
+            "height" => {
+                let height = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing height from {} to {}",
+                    settings.height,
+                    height
+                );
+                settings.height = height;
+            }
+            "mode" => {
+                let mode = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing mode from {:?} to {:?}",
+                    settings.mode,
+                    mode
+                );
+                settings.mode = mode;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    // Called whenever a value of a property is read. It can be called
+    // at any time from any thread.
+    fn property(&self, _obj: &Self::Type, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "x" => settings.x.to_value(),
+            "y" => settings.y.to_value(),
+            "width" => settings.width.to_value(),
+            "height" => settings.height.to_value(),
+            "mode" => settings.mode.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl GstObjectImpl for Roi {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for Roi {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Region Of Interest Mask",
+                "Filter/Effect/Video",
+                "Blacks out, pixelates or blurs a region of each frame",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // Our element works in-place on packed formats without alpha, so the same caps
+    // are used on both pads and filling with zeroes always results in black.
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("video/x-raw")
+                .field(
+                    "format",
+                    gst::List::new([
+                        gst_video::VideoFormat::Bgrx.to_str(),
+                        gst_video::VideoFormat::Rgbx.to_str(),
+                        gst_video::VideoFormat::Bgr.to_str(),
+                        gst_video::VideoFormat::Rgb.to_str(),
+                        gst_video::VideoFormat::Gray8.to_str(),
+                    ]),
+                )
+                .field("width", gst::IntRange::new(0, i32::MAX))
+                .field("height", gst::IntRange::new(0, i32::MAX))
+                .field(
+                    "framerate",
+                    gst::FractionRange::new(
+                        gst::Fraction::new(0, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                )
+                .build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+            let sink_pad_template = gst::PadTemplate::new(
+                "sink",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![src_pad_template, sink_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+// Implementation of gst_base::BaseTransform virtual methods
+impl BaseTransformImpl for Roi {
+    // The mask is drawn directly into the incoming buffer
+    const MODE: gst_base::subclass::BaseTransformMode =
+        gst_base::subclass::BaseTransformMode::AlwaysInPlace;
+    const PASSTHROUGH_ON_SAME_CAPS: bool = false;
+    const TRANSFORM_IP_ON_PASSTHROUGH: bool = false;
+}
+
+impl VideoFilterImpl for Roi {
+    fn transform_frame_ip(
+        &self,
+        _element: &Self::Type,
+        frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        // Copy the settings so that the property can be changed while we are processing
+        let settings = *self.settings.lock().unwrap();
+
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
+        let x0 = (settings.x as usize).min(width);
+        let y0 = (settings.y as usize).min(height);
+        let region = Region {
+            x0,
+            y0,
+            x1: x0.saturating_add(settings.width as usize).min(width),
+            y1: y0.saturating_add(settings.height as usize).min(height),
+            stride: frame.plane_stride()[0] as usize,
+            pixel_stride: frame.format_info().pixel_stride()[0] as usize,
+        };
+
+        // Nothing to do if the region is completely outside of the frame
+        if region.x0 >= region.x1 || region.y0 >= region.y1 {
+            return Ok(gst::FlowSuccess::Ok);
+        }
+
+        let data = frame.plane_data_mut(0).unwrap();
+        match settings.mode {
+            RoiMode::Black => Roi::fill_black(data, &region),
+            RoiMode::Pixelate => Roi::pixelate(data, &region),
+            RoiMode::Blur => Roi::blur(data, &region),
+        }
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+}
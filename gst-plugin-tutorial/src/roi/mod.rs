@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+
+mod imp;
+
+// How the region of interest is masked
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::Enum)]
+#[repr(u32)]
+#[enum_type(name = "GstRsRoiMode")]
+pub enum RoiMode {
+    #[enum_value(name = "Fill the region with black", nick = "black")]
+    Black = 0,
+    #[enum_value(name = "Pixelate the region", nick = "pixelate")]
+    Pixelate = 1,
+    #[enum_value(name = "Blur the region", nick = "blur")]
+    Blur = 2,
+}
+
+// The public Rust wrapper type for our element
+glib::wrapper! {
+    pub struct Roi(ObjectSubclass<imp::Roi>) @extends gst_base::BaseTransform, gst::Element, gst::Object;
+}
+
+// Registers the type for our element, and then registers in GStreamer under
+// the name "rsroi" for being able to instantiate it via e.g.
+// gst::ElementFactory::make().
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(Some(plugin), "rsroi", gst::Rank::None, Roi::static_type())
+}
@@ -0,0 +1,418 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_info, gst_log};
+use gst_base::prelude::*;
+use gst_base::subclass::base_src::CreateSuccess;
+use gst_base::subclass::prelude::*;
+
+use std::f64::consts::PI;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rssinegen",
+        gst::DebugColorFlags::empty(),
+        Some("Rust sine wave generator"),
+    )
+});
+
+// Default values of properties
+const DEFAULT_SAMPLES_PER_BUFFER: u32 = 1024;
+const DEFAULT_FREQ: u32 = 440;
+const DEFAULT_VOLUME: f64 = 0.8;
+
+// Property value storage
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    samples_per_buffer: u32,
+    freq: u32,
+    volume: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            samples_per_buffer: DEFAULT_SAMPLES_PER_BUFFER,
+            freq: DEFAULT_FREQ,
+            volume: DEFAULT_VOLUME,
+        }
+    }
+}
+
+// Stream state
+#[derive(Debug, Default)]
+struct State {
+    info: Option<gst_audio::AudioInfo>,
+    // Number of the next sample to produce and the sample to stop at. Both are
+    // derived from the segment on every seek.
+    sample_offset: u64,
+    sample_stop: Option<u64>,
+    // Phase of the sine wave at sample_offset, in radians
+    accumulator: f64,
+}
+
+// Struct containing all the element data
+#[derive(Default)]
+pub struct SineGen {
+    settings: Mutex<Settings>,
+    state: Mutex<State>,
+}
+
+impl SineGen {
+    // Phase of the wave at the given sample, so that a seek continues the
+    // wave exactly as if it had been generated from the start
+    fn phase_at(sample: u64, freq: u32, rate: u32) -> f64 {
+        let period = u64::from(rate);
+        let sample = (sample * u64::from(freq)) % period;
+        2.0 * PI * sample as f64 / rate as f64
+    }
+
+    // Writes native endian F32 samples, the same value into every channel
+    fn fill(data: &mut [u8], channels: usize, accumulator: &mut f64, step: f64, volume: f64) {
+        let bpf = channels * std::mem::size_of::<f32>();
+        for frame in data.chunks_exact_mut(bpf) {
+            let value = ((accumulator.sin() * volume) as f32).to_ne_bytes();
+            for sample in frame.chunks_exact_mut(value.len()) {
+                sample.copy_from_slice(&value);
+            }
+            *accumulator += step;
+            if *accumulator >= 2.0 * PI {
+                *accumulator -= 2.0 * PI;
+            }
+        }
+    }
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for SineGen {
+    const NAME: &'static str = "RsSineGen";
+    type Type = super::SineGen;
+    type ParentType = gst_base::PushSrc;
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for SineGen {
+    fn properties() -> &'static [glib::ParamSpec] {
+        // Metadata for the properties
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecUInt::new(
+                    "samples-per-buffer",
+                    "Samples Per Buffer",
+                    "Number of samples per output buffer",
+                    1,
+                    u32::MAX,
+                    DEFAULT_SAMPLES_PER_BUFFER,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_READY,
+                ),
+                glib::ParamSpecUInt::new(
+                    "freq",
+                    "Frequency",
+                    "Frequency of the sine wave in Hz",
+                    1,
+                    u32::MAX,
+                    DEFAULT_FREQ,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+                glib::ParamSpecDouble::new(
+                    "volume",
+                    "Volume",
+                    "Output volume",
+                    0.0,
+                    10.0,
+                    DEFAULT_VOLUME,
+                    glib::ParamFlags::READWRITE | gst::PARAM_FLAG_MUTABLE_PLAYING,
+                ),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    // Called whenever a value of a property is changed. It can be called
+    // at any time from any thread.
+    fn set_property(
+        &self,
+        obj: &Self::Type,
+        _id: usize,
+        value: &glib::Value,
+        pspec: &glib::ParamSpec,
+    ) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "samples-per-buffer" => {
+                let samples_per_buffer = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing samples-per-buffer from {} to {}",
+                    settings.samples_per_buffer,
+                    samples_per_buffer
+                );
+                settings.samples_per_buffer = samples_per_buffer;
+            }
+            "freq" => {
+                let freq = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing freq from {} to {}",
+                    settings.freq,
+                    freq
+                );
+                settings.freq = freq;
+            }
+            "volume" => {
+                let volume = value.get().expect("type checked upstream");
+                gst_info!(
+                    CAT,
+                    obj: obj,
+                    "Changing volume from {} to {}",
+                    settings.volume,
+                    volume
+                );
+                settings.volume = volume;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    // Called whenever a value of a property is read. It can be called
+    // at any time from any thread.
+    fn property(&self, _obj: &Self::Type, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "samples-per-buffer" => settings.samples_per_buffer.to_value(),
+            "freq" => settings.freq.to_value(),
+            "volume" => settings.volume.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    // Called right after construction of a new instance
+    fn constructed(&self, obj: &Self::Type) {
+        // Call the parent class' ::constructed() implementation first
+        self.parent_constructed(obj);
+
+        // We operate in time format, which makes seeks arrive as time segments
+        obj.set_format(gst::Format::Time);
+    }
+}
+
+impl GstObjectImpl for SineGen {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for SineGen {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Sine Wave Source",
+                "Source/Audio",
+                "Generates a seekable sine wave",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("audio/x-raw")
+                .field("format", gst_audio::AUDIO_FORMAT_F32.to_str())
+                .field("layout", "interleaved")
+                .field("rate", gst::IntRange::new(1, i32::MAX))
+                .field("channels", gst::IntRange::new(1, i32::MAX))
+                .build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![src_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+// Implementation of gst_base::BaseSrc virtual methods
+impl BaseSrcImpl for SineGen {
+    fn start(&self, element: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = Default::default();
+        gst_info!(CAT, obj: element, "Started");
+        Ok(())
+    }
+
+    fn stop(&self, element: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = Default::default();
+        gst_info!(CAT, obj: element, "Stopped");
+        Ok(())
+    }
+
+    fn set_caps(&self, element: &Self::Type, caps: &gst::Caps) -> Result<(), gst::LoggableError> {
+        let info = gst_audio::AudioInfo::from_caps(caps)
+            .map_err(|_| gst::loggable_error!(CAT, "Failed to build `AudioInfo` from caps"))?;
+        gst_debug!(CAT, obj: element, "Configuring for caps {}", caps);
+
+        let settings = *self.settings.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        // A rate change keeps the position in time, so convert the sample
+        // counters over to the new rate
+        if let Some(old_rate) = state.info.as_ref().map(|old| old.rate()) {
+            let (from, to) = (u64::from(old_rate), u64::from(info.rate()));
+            state.sample_offset = state
+                .sample_offset
+                .mul_div_floor(to, from)
+                .unwrap_or(state.sample_offset);
+            state.sample_stop = state
+                .sample_stop
+                .and_then(|stop| stop.mul_div_floor(to, from));
+        }
+        state.accumulator = Self::phase_at(state.sample_offset, settings.freq, info.rate());
+        state.info = Some(info);
+
+        Ok(())
+    }
+
+    // Fixate to something sensible if downstream accepts anything
+    fn fixate(&self, element: &Self::Type, mut caps: gst::Caps) -> gst::Caps {
+        caps.truncate();
+        {
+            let caps = caps.make_mut();
+            let s = caps.structure_mut(0).unwrap();
+            s.fixate_field_nearest_int("rate", 48_000);
+            s.fixate_field_nearest_int("channels", 1);
+        }
+
+        self.parent_fixate(element, caps)
+    }
+
+    fn is_seekable(&self, _element: &Self::Type) -> bool {
+        true
+    }
+
+    // Called with the new segment whenever a seek was performed. BaseSrc
+    // already stopped the streaming thread and flushed downstream (for
+    // flushing seeks) before, so we only have to move our position.
+    fn do_seek(&self, element: &Self::Type, segment: &mut gst::Segment) -> bool {
+        // Only forward playback is supported, reverse playback would need
+        // the buffers to be generated backwards
+        if segment.rate() < 0.0 {
+            gst_debug!(CAT, obj: element, "Reverse playback not supported");
+            return false;
+        }
+
+        let segment = match segment.downcast_ref::<gst::format::Time>() {
+            Some(segment) => segment,
+            None => {
+                gst_debug!(CAT, obj: element, "Only time seeks are supported");
+                return false;
+            }
+        };
+
+        let settings = *self.settings.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        // Before the caps are known the counters are recomputed in set_caps,
+        // with a default rate of the fixated caps until then
+        let rate = state.info.as_ref().map_or(48_000, |info| info.rate());
+        let to_sample = |time: gst::ClockTime| {
+            time.nseconds()
+                .mul_div_floor(u64::from(rate), gst::ClockTime::SECOND.nseconds())
+        };
+
+        let start = segment.start().unwrap_or(gst::ClockTime::ZERO);
+        state.sample_offset = to_sample(start).unwrap_or(0);
+        state.sample_stop = segment.stop().and_then(to_sample);
+        state.accumulator = Self::phase_at(state.sample_offset, settings.freq, rate);
+
+        gst_debug!(
+            CAT,
+            obj: element,
+            "Seeked to {} (sample {}), stop at {:?}",
+            start,
+            state.sample_offset,
+            state.sample_stop
+        );
+
+        true
+    }
+}
+
+// Implementation of gst_base::PushSrc virtual methods
+impl PushSrcImpl for SineGen {
+    // Generates the next buffer, or returns EOS once the stop of the segment
+    // is reached
+    fn create(
+        &self,
+        element: &Self::Type,
+        _buffer: Option<&mut gst::BufferRef>,
+    ) -> Result<CreateSuccess, gst::FlowError> {
+        let settings = *self.settings.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        let info = match state.info {
+            Some(ref info) => info.clone(),
+            None => {
+                gst::element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                return Err(gst::FlowError::NotNegotiated);
+            }
+        };
+
+        let samples = match state.sample_stop {
+            Some(stop) if stop <= state.sample_offset => {
+                gst_debug!(CAT, obj: element, "At the end of the segment");
+                return Err(gst::FlowError::Eos);
+            }
+            Some(stop) => (stop - state.sample_offset).min(u64::from(settings.samples_per_buffer)),
+            None => u64::from(settings.samples_per_buffer),
+        };
+
+        let rate = u64::from(info.rate());
+        let channels = info.channels() as usize;
+        let step = 2.0 * PI * f64::from(settings.freq) / rate as f64;
+
+        let mut buffer = gst::Buffer::with_size(samples as usize * info.bpf() as usize)
+            .map_err(|_| gst::FlowError::Error)?;
+        {
+            let buffer = buffer.get_mut().unwrap();
+
+            // Timestamps are derived from the sample counter, so that they stay
+            // exact no matter how many buffers were produced since the seek
+            let pts = gst::ClockTime::SECOND.mul_div_floor(state.sample_offset, rate);
+            let end = gst::ClockTime::SECOND.mul_div_floor(state.sample_offset + samples, rate);
+            buffer.set_pts(pts);
+            buffer.set_duration(end.zip(pts).map(|(end, pts)| end - pts));
+            buffer.set_offset(state.sample_offset);
+            buffer.set_offset_end(state.sample_offset + samples);
+
+            let mut map = buffer.map_writable().unwrap();
+            Self::fill(
+                map.as_mut_slice(),
+                channels,
+                &mut state.accumulator,
+                step,
+                settings.volume,
+            );
+        }
+
+        state.sample_offset += samples;
+        drop(state);
+
+        gst_log!(CAT, obj: element, "Produced buffer {:?}", buffer);
+        Ok(CreateSuccess::NewBuffer(buffer))
+    }
+}
@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use gst::glib;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_error, gst_log};
+use gst_base::prelude::*;
+use gst_base::subclass::prelude::*;
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "rsstacker",
+        gst::DebugColorFlags::empty(),
+        Some("Rust vertical video stacker"),
+    )
+});
+
+// Names of the sink pads, from top to bottom in the output frame
+const SINK_PAD_NAMES: [&str; 2] = ["sink_0", "sink_1"];
+
+// Stream related state, created once the output caps are negotiated
+struct State {
+    // Video info of each input, in the order of SINK_PAD_NAMES
+    in_info: [gst_video::VideoInfo; 2],
+    out_info: gst_video::VideoInfo,
+    // Last frame received on each input. It is reused when the input has
+    // nothing new at a timeout, so that a slow or stalled input does not
+    // hold back the output.
+    last: [Option<gst::Buffer>; 2],
+    // Running position of the output in the src segment
+    position: Option<gst::ClockTime>,
+}
+
+// Struct containing all the element data
+#[derive(Default)]
+pub struct Stacker {
+    state: Mutex<Option<State>>,
+}
+
+impl Stacker {
+    fn sink_pads(&self, agg: &super::Stacker) -> [gst_base::AggregatorPad; 2] {
+        SINK_PAD_NAMES.map(|name| {
+            agg.static_pad(name)
+                .unwrap()
+                .downcast::<gst_base::AggregatorPad>()
+                .unwrap()
+        })
+    }
+
+    // The caps one input may use given the caps of the other one. Only the
+    // height may differ between the inputs, the format and width have to
+    // match for the lines to be stacked on top of each other.
+    fn sink_caps(
+        &self,
+        agg: &super::Stacker,
+        pad: &gst_base::AggregatorPad,
+        filter: Option<&gst::CapsRef>,
+    ) -> gst::Caps {
+        let mut caps = pad.pad_template_caps();
+
+        let other = self
+            .sink_pads(agg)
+            .into_iter()
+            .find(|other| other != pad)
+            .and_then(|other| other.current_caps())
+            .and_then(|caps| gst_video::VideoInfo::from_caps(&caps).ok());
+        if let Some(other) = other {
+            let restriction = gst::Caps::builder("video/x-raw")
+                .field("format", other.format().to_str())
+                .field("width", other.width() as i32)
+                .build();
+            caps = caps.intersect(&restriction);
+        }
+
+        match filter {
+            Some(filter) => filter.intersect_with_mode(&caps, gst::CapsIntersectMode::First),
+            None => caps,
+        }
+    }
+
+    // Copies the lines of an input frame into the output frame starting at
+    // the given line. Without a frame the area is filled with black, which is
+    // all zeroes for the formats we accept.
+    fn copy_lines(
+        out: &mut gst_video::VideoFrame<gst_video::video_frame::Writable>,
+        buffer: Option<&gst::Buffer>,
+        info: &gst_video::VideoInfo,
+        top: usize,
+    ) -> Result<(), gst::FlowError> {
+        let out_stride = out.plane_stride()[0] as usize;
+        let line_bytes = info.width() as usize * info.format_info().pixel_stride()[0] as usize;
+        let height = info.height() as usize;
+        let out_data = out.plane_data_mut(0).map_err(|_| gst::FlowError::Error)?;
+
+        let frame = match buffer {
+            Some(buffer) => Some(
+                gst_video::VideoFrameRef::from_buffer_ref_readable(buffer.as_ref(), info)
+                    .map_err(|_| gst::FlowError::Error)?,
+            ),
+            None => None,
+        };
+
+        for y in 0..height {
+            let dst = (top + y) * out_stride;
+            let dst = &mut out_data[dst..dst + line_bytes];
+            match frame {
+                Some(ref frame) => {
+                    let in_stride = frame.plane_stride()[0] as usize;
+                    let in_data = frame.plane_data(0).map_err(|_| gst::FlowError::Error)?;
+                    dst.copy_from_slice(&in_data[y * in_stride..y * in_stride + line_bytes]);
+                }
+                None => dst.fill(0),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// This trait registers our type with the GObject object system and
+// provides the entry points for creating a new instance and setting
+// up the class data
+#[glib::object_subclass]
+impl ObjectSubclass for Stacker {
+    const NAME: &'static str = "RsStacker";
+    type Type = super::Stacker;
+    type ParentType = gst_base::Aggregator;
+}
+
+// Implementation of glib::Object virtual methods
+impl ObjectImpl for Stacker {}
+
+impl GstObjectImpl for Stacker {}
+
+// Implementation of gst::Element virtual methods
+impl ElementImpl for Stacker {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Vertical Video Stacker",
+                "Filter/Editor/Video/Compositor",
+                "Stacks two video streams on top of each other",
+                "uzuna",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    // Two always sink pads, which have to be aggregator pads, and one src pad.
+    // Filling with zeroes has to result in black, so only packed formats
+    // without alpha are accepted.
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::builder("video/x-raw")
+                .field(
+                    "format",
+                    gst::List::new([
+                        gst_video::VideoFormat::Bgrx.to_str(),
+                        gst_video::VideoFormat::Rgbx.to_str(),
+                        gst_video::VideoFormat::Bgr.to_str(),
+                        gst_video::VideoFormat::Rgb.to_str(),
+                        gst_video::VideoFormat::Gray8.to_str(),
+                    ]),
+                )
+                .field("width", gst::IntRange::new(1, i32::MAX))
+                .field("height", gst::IntRange::new(1, i32::MAX))
+                .field(
+                    "framerate",
+                    gst::FractionRange::new(
+                        gst::Fraction::new(1, 1),
+                        gst::Fraction::new(i32::MAX, 1),
+                    ),
+                )
+                .build();
+
+            let mut templates = vec![gst::PadTemplate::with_gtype(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+                gst_base::AggregatorPad::static_type(),
+            )
+            .unwrap()];
+            templates.extend(SINK_PAD_NAMES.iter().map(|name| {
+                gst::PadTemplate::with_gtype(
+                    name,
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &caps,
+                    gst_base::AggregatorPad::static_type(),
+                )
+                .unwrap()
+            }));
+
+            templates
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+// Implementation of gst_base::Aggregator virtual methods
+impl AggregatorImpl for Stacker {
+    fn start(&self, _agg: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn stop(&self, _agg: &Self::Type) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // The base class answers latency queries by asking all sink pads and
+    // taking the maximum, plus the latency property and our own latency. A
+    // frame is output once all inputs have one for its running time, or at
+    // the timeout, so stacking itself adds nothing. The configured timeout
+    // latency is part of the answer and logged here to make it visible.
+    fn src_query(&self, agg: &Self::Type, query: &mut gst::QueryRef) -> bool {
+        let ret = self.parent_src_query(agg, query);
+        if let gst::QueryView::Latency(q) = query.view() {
+            let (live, min, max) = q.result();
+            gst_debug!(
+                CAT,
+                obj: agg,
+                "Latency live={} min={} max={} (timeout latency {})",
+                live,
+                min,
+                max.display(),
+                agg.latency().display()
+            );
+        }
+        ret
+    }
+
+    // Answer caps queries on a sink pad with what fits the other sink pad
+    fn sink_query(
+        &self,
+        agg: &Self::Type,
+        pad: &gst_base::AggregatorPad,
+        query: &mut gst::QueryRef,
+    ) -> bool {
+        use gst::QueryView;
+
+        match query.view_mut() {
+            QueryView::Caps(mut q) => {
+                let caps = self.sink_caps(agg, pad, q.filter());
+                gst_log!(CAT, obj: pad, "Returning caps {}", caps);
+                q.set_result(&caps);
+                true
+            }
+            QueryView::AcceptCaps(mut q) => {
+                let accepted = q.caps().is_subset(&self.sink_caps(agg, pad, None));
+                q.set_result(accepted);
+                true
+            }
+            _ => self.parent_sink_query(agg, pad, query),
+        }
+    }
+
+    // Called by the base class before aggregating whenever the caps of a sink
+    // pad changed. Returns the caps for the src pad.
+    fn update_src_caps(
+        &self,
+        agg: &Self::Type,
+        _caps: &gst::Caps,
+    ) -> Result<gst::Caps, gst::FlowError> {
+        let mut infos = Vec::with_capacity(2);
+        for pad in self.sink_pads(agg) {
+            // Both inputs are needed to know the output size
+            let caps = match pad.current_caps() {
+                Some(caps) => caps,
+                None => return Err(gst_base::AGGREGATOR_FLOW_NEED_DATA),
+            };
+            let info = gst_video::VideoInfo::from_caps(&caps).map_err(|_| {
+                gst_error!(CAT, obj: &pad, "Invalid caps {}", caps);
+                gst::FlowError::NotNegotiated
+            })?;
+            infos.push(info);
+        }
+
+        if infos[0].format() != infos[1].format() || infos[0].width() != infos[1].width() {
+            gst::element_error!(
+                agg,
+                gst::CoreError::Negotiation,
+                [
+                    "Inputs differ in format or width: {:?} {} and {:?} {}",
+                    infos[0].format(),
+                    infos[0].width(),
+                    infos[1].format(),
+                    infos[1].width()
+                ]
+            );
+            return Err(gst::FlowError::NotNegotiated);
+        }
+
+        // The output runs at the framerate of the top input
+        let out_info = gst_video::VideoInfo::builder(
+            infos[0].format(),
+            infos[0].width(),
+            infos[0].height() + infos[1].height(),
+        )
+        .fps(infos[0].fps())
+        .build()
+        .map_err(|_| gst::FlowError::NotNegotiated)?;
+        let caps = out_info
+            .to_caps()
+            .map_err(|_| gst::FlowError::NotNegotiated)?;
+        gst_debug!(CAT, obj: agg, "Output caps {}", caps);
+
+        // Keep the last frames and the position over a renegotiation
+        let mut state = self.state.lock().unwrap();
+        let (last, position) = match state.take() {
+            Some(old) => (old.last, old.position),
+            None => (Default::default(), None),
+        };
+        *state = Some(State {
+            in_info: [infos[0].clone(), infos[1].clone()],
+            out_info,
+            last,
+            position,
+        });
+
+        Ok(caps)
+    }
+
+    // For live inputs the base class waits until the running time of the next
+    // output frame plus the latency, then aggregate() is called with
+    // timeout=true even if one of the inputs has no data yet
+    fn next_time(&self, agg: &Self::Type) -> Option<gst::ClockTime> {
+        let position = self.state.lock().unwrap().as_ref()?.position?;
+        let segment = agg
+            .static_pad("src")?
+            .downcast::<gst_base::AggregatorPad>()
+            .ok()?
+            .segment();
+        segment
+            .downcast_ref::<gst::ClockTime>()?
+            .to_running_time(position)
+    }
+
+    fn aggregate(
+        &self,
+        agg: &Self::Type,
+        timeout: bool,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let pads = self.sink_pads(agg);
+
+        // Finished once all inputs are finished and drained
+        if pads
+            .iter()
+            .all(|pad| pad.is_eos() && pad.peek_buffer().is_none())
+        {
+            gst_debug!(CAT, obj: agg, "All inputs are EOS");
+            return Err(gst::FlowError::Eos);
+        }
+
+        let mut state_guard = self.state.lock().unwrap();
+        let state = state_guard.as_mut().ok_or(gst::FlowError::NotNegotiated)?;
+
+        // Take one frame from each input that has one. Without a timeout the
+        // base class only calls us once every input that is not EOS has data.
+        let mut pts = None;
+        for (pad, last) in pads.iter().zip(state.last.iter_mut()) {
+            if let Some(buffer) = pad.pop_buffer() {
+                pts = pts.or(buffer.pts());
+                *last = Some(buffer);
+            } else if timeout {
+                gst_log!(CAT, obj: pad, "No data at timeout, reusing the last frame");
+            }
+        }
+
+        let duration = if state.out_info.fps().numer() > 0 {
+            gst::ClockTime::SECOND.mul_div_floor(
+                state.out_info.fps().denom() as u64,
+                state.out_info.fps().numer() as u64,
+            )
+        } else {
+            None
+        };
+        let pts = state.position.or(pts);
+
+        let mut outbuf =
+            gst::Buffer::with_size(state.out_info.size()).map_err(|_| gst::FlowError::Error)?;
+        {
+            let outbuf = outbuf.get_mut().unwrap();
+            outbuf.set_pts(pts);
+            outbuf.set_duration(duration);
+        }
+        let mut out = gst_video::VideoFrame::from_buffer_writable(outbuf, &state.out_info)
+            .map_err(|_| gst::FlowError::Error)?;
+        let mut top = 0;
+        for (info, last) in state.in_info.iter().zip(state.last.iter()) {
+            Self::copy_lines(&mut out, last.as_ref(), info, top)?;
+            top += info.height() as usize;
+        }
+        let outbuf = out.into_buffer();
+
+        state.position = pts.zip(duration).map(|(pts, duration)| pts + duration);
+        drop(state_guard);
+
+        gst_log!(CAT, obj: agg, "Finishing {:?}", outbuf);
+        agg.finish_buffer(outbuf)
+    }
+}
@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+// Helpers for testing elements inside small, closed pipelines.
+//
+// Unlike gst_check::Harness, which drives a single element by hand, these run a
+// whole pipeline from a bounded test source to EOS and hand back every buffer
+// that reached the end. They are only built with the "testing" feature so the
+// plugin itself does not carry them.
+//
+//     let caps = "video/x-raw,format=BGRx,width=64,height=48";
+//     let buffers = testing::collect(
+//         &format!("{} ! videoconvert", testing::video_source(10, caps)),
+//         gst::ClockTime::from_seconds(5),
+//     )?;
+//     assert_eq!(buffers.len(), 10);
+//
+// StreamValidator watches the timestamps on a pad instead, and validate() runs
+// a description to EOS with one attached in front of the sink.
+
+use gst::glib;
+use gst::prelude::*;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// Name of the fakesink that collect() appends to the description
+const SINK_NAME: &str = "testing-sink";
+
+// Everything that can stop a test pipeline before it reaches EOS
+#[derive(Debug)]
+pub enum Error {
+    // The description could not be parsed or the sink was missing
+    Build(glib::Error),
+    // The pipeline refused to change state
+    StateChange(gst::StateChangeError),
+    // An element posted an error message
+    Message {
+        src: Option<String>,
+        error: glib::Error,
+        debug: Option<String>,
+    },
+    // EOS did not arrive in time
+    Timeout(gst::ClockTime),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Build(err) => write!(f, "failed to build pipeline: {}", err),
+            Error::StateChange(err) => write!(f, "state change failed: {}", err),
+            Error::Message { src, error, debug } => write!(
+                f,
+                "error from {}: {} ({})",
+                src.as_deref().unwrap_or("unknown"),
+                error,
+                debug.as_deref().unwrap_or("no debug info")
+            ),
+            Error::Timeout(timeout) => write!(f, "no EOS within {}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// A videotestsrc producing exactly num_buffers frames with the given caps,
+// as a gst-launch fragment to put in front of the elements under test
+pub fn video_source(num_buffers: u32, caps: &str) -> String {
+    format!("videotestsrc num-buffers={} ! {}", num_buffers, caps)
+}
+
+// The same for audiotestsrc. samples_per_buffer fixes the size of every buffer
+pub fn audio_source(num_buffers: u32, samples_per_buffer: u32, caps: &str) -> String {
+    format!(
+        "audiotestsrc num-buffers={} samples-per-buffer={} ! {}",
+        num_buffers, samples_per_buffer, caps
+    )
+}
+
+// Turn an error message into Error::Message
+fn message_error(msg: &gst::Message) -> Option<Error> {
+    match msg.view() {
+        gst::MessageView::Error(err) => Some(Error::Message {
+            src: msg.src().map(|s| s.path_string().to_string()),
+            error: err.error(),
+            debug: err.debug(),
+        }),
+        _ => None,
+    }
+}
+
+// Set the pipeline to Playing and wait for EOS, an error or the timeout.
+// The pipeline is always set back to Null before returning.
+pub fn run_to_eos(pipeline: &gst::Pipeline, timeout: gst::ClockTime) -> Result<(), Error> {
+    let bus = pipeline.bus().expect("pipeline without bus");
+    let result = match pipeline.set_state(gst::State::Playing) {
+        // A failing state change usually posted an error that explains it
+        Err(err) => Err(bus
+            .pop_filtered(&[gst::MessageType::Error])
+            .and_then(|msg| message_error(&msg))
+            .unwrap_or(Error::StateChange(err))),
+        Ok(_) => match bus
+            .timed_pop_filtered(timeout, &[gst::MessageType::Eos, gst::MessageType::Error])
+        {
+            Some(msg) => message_error(&msg).map_or(Ok(()), Err),
+            None => Err(Error::Timeout(timeout)),
+        },
+    };
+    pipeline
+        .set_state(gst::State::Null)
+        .map_err(Error::StateChange)?;
+    result
+}
+
+// Parse the description with a fakesink appended and return it with the sink
+fn build(description: &str) -> Result<(gst::Pipeline, gst::Element), Error> {
+    let pipeline = gst::parse_launch(&format!(
+        "{} ! fakesink name={} sync=false signal-handoffs=true",
+        description, SINK_NAME
+    ))
+    .map_err(Error::Build)?
+    .downcast::<gst::Pipeline>()
+    .expect("not a pipeline");
+
+    let sink = pipeline.by_name(SINK_NAME).ok_or_else(|| {
+        Error::Build(glib::Error::new(
+            gst::CoreError::Failed,
+            "fakesink not found",
+        ))
+    })?;
+    Ok((pipeline, sink))
+}
+
+// Parse the description, append a fakesink and run it to EOS, returning all
+// buffers that arrived at the sink in order
+pub fn collect(description: &str, timeout: gst::ClockTime) -> Result<Vec<gst::Buffer>, Error> {
+    let (pipeline, sink) = build(description)?;
+    let buffers = Arc::new(Mutex::new(Vec::new()));
+    let buffers_clone = buffers.clone();
+    sink.connect("handoff", false, move |args| {
+        let buffer = args[1]
+            .get::<gst::Buffer>()
+            .expect("handoff without buffer");
+        buffers_clone.lock().unwrap().push(buffer);
+        None
+    });
+
+    run_to_eos(&pipeline, timeout)?;
+
+    let buffers = std::mem::take(&mut *buffers.lock().unwrap());
+    Ok(buffers)
+}
+
+// A timestamp problem found by StreamValidator. index counts the buffers seen
+// on the pad since the last stream-start or flush.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    // The buffer carries no PTS
+    MissingPts {
+        index: u64,
+    },
+    // The buffer carries no duration, so the next PTS cannot be predicted
+    MissingDuration {
+        index: u64,
+    },
+    // The PTS went backwards
+    NotMonotonic {
+        index: u64,
+        previous: gst::ClockTime,
+        pts: gst::ClockTime,
+    },
+    // The buffer starts before the previous one ended, i.e. that duration was too long
+    Overlap {
+        index: u64,
+        expected: gst::ClockTime,
+        pts: gst::ClockTime,
+    },
+    // The buffer starts after the previous one ended
+    Gap {
+        index: u64,
+        expected: gst::ClockTime,
+        pts: gst::ClockTime,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingPts { index } => write!(f, "buffer {} has no PTS", index),
+            Issue::MissingDuration { index } => write!(f, "buffer {} has no duration", index),
+            Issue::NotMonotonic {
+                index,
+                previous,
+                pts,
+            } => write!(
+                f,
+                "buffer {} PTS {} is before the previous PTS {}",
+                index, pts, previous
+            ),
+            Issue::Overlap {
+                index,
+                expected,
+                pts,
+            } => write!(
+                f,
+                "buffer {} PTS {} overlaps the previous buffer ending at {}",
+                index, pts, expected
+            ),
+            Issue::Gap {
+                index,
+                expected,
+                pts,
+            } => write!(
+                f,
+                "buffer {} PTS {} leaves a gap of {} after {}",
+                index,
+                pts,
+                pts - *expected,
+                expected
+            ),
+        }
+    }
+}
+
+// Per-stream state of the validator
+#[derive(Debug, Default)]
+struct ValidatorState {
+    index: u64,
+    previous_pts: Option<gst::ClockTime>,
+    // PTS of the previous buffer plus its duration
+    expected_pts: Option<gst::ClockTime>,
+    issues: Vec<Issue>,
+}
+
+impl ValidatorState {
+    // Start over for a new stream. Issues found so far are kept
+    fn reset(&mut self) {
+        self.index = 0;
+        self.previous_pts = None;
+        self.expected_pts = None;
+    }
+
+    fn check(&mut self, buffer: &gst::BufferRef, tolerance: gst::ClockTime) -> Vec<Issue> {
+        let index = self.index;
+        self.index += 1;
+
+        let pts = match buffer.pts() {
+            Some(pts) => pts,
+            None => return vec![Issue::MissingPts { index }],
+        };
+
+        let mut issues = Vec::new();
+        match (self.previous_pts, self.expected_pts) {
+            (Some(previous), _) if pts < previous => issues.push(Issue::NotMonotonic {
+                index,
+                previous,
+                pts,
+            }),
+            (_, Some(expected)) if pts + tolerance < expected => issues.push(Issue::Overlap {
+                index,
+                expected,
+                pts,
+            }),
+            (_, Some(expected)) if pts > expected + tolerance => issues.push(Issue::Gap {
+                index,
+                expected,
+                pts,
+            }),
+            _ => (),
+        }
+        if buffer.duration().is_none() {
+            issues.push(Issue::MissingDuration { index });
+        }
+
+        self.previous_pts = Some(pts);
+        self.expected_pts = buffer.duration().map(|duration| pts + duration);
+        issues
+    }
+}
+
+// Checks PTS monotonicity, duration consistency and gaps of the buffers
+// passing a pad. Every issue is posted as a warning message from the pad's
+// element and also kept, so tests can assert on issues() afterwards.
+// Gap buffers and gap events are not treated specially: a stream that is
+// meant to have holes will report them.
+#[derive(Debug, Clone)]
+pub struct StreamValidator {
+    state: Arc<Mutex<ValidatorState>>,
+}
+
+impl StreamValidator {
+    // Watch the pad. Differences up to tolerance between the expected and the
+    // actual PTS are accepted, to allow for durations rounded to nanoseconds
+    pub fn attach(pad: &gst::Pad, tolerance: gst::ClockTime) -> Self {
+        let state = Arc::new(Mutex::new(ValidatorState::default()));
+        let state_clone = state.clone();
+        pad.add_probe(
+            gst::PadProbeType::BUFFER
+                | gst::PadProbeType::BUFFER_LIST
+                | gst::PadProbeType::EVENT_DOWNSTREAM
+                | gst::PadProbeType::EVENT_FLUSH,
+            move |pad, info| {
+                let mut state = state_clone.lock().unwrap();
+                let issues = match info.data {
+                    Some(gst::PadProbeData::Buffer(ref buffer)) => state.check(buffer, tolerance),
+                    Some(gst::PadProbeData::BufferList(ref list)) => list
+                        .iter()
+                        .flat_map(|buffer| state.check(buffer, tolerance))
+                        .collect(),
+                    Some(gst::PadProbeData::Event(ref event)) => {
+                        if matches!(
+                            event.type_(),
+                            gst::EventType::StreamStart | gst::EventType::FlushStop
+                        ) {
+                            state.reset();
+                        }
+                        Vec::new()
+                    }
+                    _ => Vec::new(),
+                };
+                state.issues.extend(issues.iter().cloned());
+                drop(state);
+
+                if let Some(element) = pad.parent_element() {
+                    for issue in issues {
+                        let msg = gst::message::Warning::builder(
+                            gst::StreamError::Format,
+                            &format!("{}: {}", pad.name(), issue),
+                        )
+                        .src(&element)
+                        .build();
+                        let _ = element.post_message(msg);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+        Self { state }
+    }
+
+    // Every issue found so far, in the order the buffers arrived
+    pub fn issues(&self) -> Vec<Issue> {
+        self.state.lock().unwrap().issues.clone()
+    }
+}
+
+// Parse the description, append a fakesink with a StreamValidator on its sink
+// pad and run it to EOS, returning the issues found
+pub fn validate(
+    description: &str,
+    tolerance: gst::ClockTime,
+    timeout: gst::ClockTime,
+) -> Result<Vec<Issue>, Error> {
+    let (pipeline, sink) = build(description)?;
+    let pad = sink.static_pad("sink").expect("fakesink without sink pad");
+    let validator = StreamValidator::attach(&pad, tolerance);
+
+    run_to_eos(&pipeline, timeout)?;
+
+    Ok(validator.issues())
+}
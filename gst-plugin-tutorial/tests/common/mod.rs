@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+// Helpers shared by the integration tests. Each test binary includes this
+// module with `mod common;`.
+
+// Initializes GStreamer and registers the plugin statically, once per test binary
+pub fn init() {
+    use std::sync::Once;
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        gst::init().unwrap();
+        gstrstutorial::plugin_register_static().expect("rstutorial plugin");
+    });
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use common::init;
+use gst::prelude::*;
+
+const RATE: u64 = 48_000;
+// 0.1s per buffer
+const SAMPLES_PER_BUFFER: u64 = 4800;
+
+fn harness() -> (gst_check::Harness, gst::Bus) {
+    let mut h = gst_check::Harness::new("rsdetectsilence");
+    let element = h.element().unwrap();
+    element.set_property("threshold", -40.0f64);
+    element.set_property("hold-time", gst::ClockTime::from_mseconds(300).nseconds());
+    let bus = gst::Bus::new();
+    element.set_bus(Some(&bus));
+    h.set_src_caps_str("audio/x-raw,format=F32LE,layout=interleaved,rate=48000,channels=1");
+    (h, bus)
+}
+
+// A buffer of mono F32 samples, a square wave of the given amplitude
+fn buffer(index: u64, amplitude: f32) -> gst::Buffer {
+    let mut data = Vec::with_capacity(SAMPLES_PER_BUFFER as usize * 4);
+    for i in 0..SAMPLES_PER_BUFFER {
+        let value = if i % 2 == 0 { amplitude } else { -amplitude };
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    buffer
+        .get_mut()
+        .unwrap()
+        .set_pts(gst::ClockTime::from_nseconds(
+            index * SAMPLES_PER_BUFFER * gst::ClockTime::SECOND.nseconds() / RATE,
+        ));
+    buffer
+}
+
+fn boundaries(bus: &gst::Bus) -> Vec<(String, gst::ClockTime)> {
+    std::iter::from_fn(|| bus.pop_filtered(&[gst::MessageType::Element]))
+        .map(|msg| {
+            let s = msg.structure().unwrap();
+            (
+                s.name().to_string(),
+                s.get::<gst::ClockTime>("timestamp").unwrap(),
+            )
+        })
+        .collect()
+}
+
+// Silence is announced once it lasted for the hold time, with the timestamp of
+// where it began, and ends at the first loud window. The audio passes unchanged.
+#[test]
+fn test_silence_boundaries() {
+    init();
+
+    let (mut h, bus) = harness();
+    let pattern = [0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5];
+    for (i, amplitude) in pattern.iter().enumerate() {
+        let input = buffer(i as u64, *amplitude);
+        let output = h.push_and_pull(input.clone()).unwrap();
+        assert_eq!(
+            output.map_readable().unwrap().as_slice(),
+            input.map_readable().unwrap().as_slice()
+        );
+    }
+
+    assert_eq!(
+        boundaries(&bus),
+        vec![
+            (
+                "rsdetectsilence-start".to_string(),
+                gst::ClockTime::from_mseconds(200)
+            ),
+            (
+                "rsdetectsilence-stop".to_string(),
+                gst::ClockTime::from_mseconds(600)
+            ),
+        ]
+    );
+}
+
+// A pause shorter than the hold time is not silence
+#[test]
+fn test_short_pause_ignored() {
+    init();
+
+    let (mut h, bus) = harness();
+    let pattern = [0.5, 0.0, 0.0, 0.5];
+    for (i, amplitude) in pattern.iter().enumerate() {
+        h.push_and_pull(buffer(i as u64, *amplitude)).unwrap();
+    }
+
+    assert!(boundaries(&bus).is_empty());
+}
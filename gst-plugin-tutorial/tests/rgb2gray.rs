@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use common::init;
+use gst::prelude::*;
+
+fn bgrx_caps(width: i32, height: i32) -> gst::Caps {
+    gst_video::VideoInfo::builder(gst_video::VideoFormat::Bgrx, width as u32, height as u32)
+        .fps(gst::Fraction::new(30, 1))
+        .build()
+        .unwrap()
+        .to_caps()
+        .unwrap()
+}
+
+// Upstream switches the resolution while the element is running. The element has to
+// accept the new caps, convert the following buffers with the new size and tell the
+// application about it with an element message.
+#[test]
+fn test_renegotiate_mid_stream() {
+    init();
+
+    let mut h = gst_check::Harness::new("rsrgb2gray");
+    let element = h.element().unwrap();
+    let bus = gst::Bus::new();
+    element.set_bus(Some(&bus));
+
+    h.set_src_caps(bgrx_caps(320, 240));
+    let out = h
+        .push_and_pull(gst::Buffer::with_size(320 * 240 * 4).unwrap())
+        .unwrap();
+    // GRAY8 is preferred as output format
+    assert_eq!(out.size(), 320 * 240);
+    assert!(bus.pop_filtered(&[gst::MessageType::Element]).is_none());
+
+    h.set_src_caps(bgrx_caps(160, 120));
+    let out = h
+        .push_and_pull(gst::Buffer::with_size(160 * 120 * 4).unwrap())
+        .unwrap();
+    assert_eq!(out.size(), 160 * 120);
+
+    let msg = bus
+        .pop_filtered(&[gst::MessageType::Element])
+        .expect("renegotiation message");
+    let s = msg.structure().unwrap();
+    assert_eq!(s.name(), "rsrgb2gray-renegotiated");
+    assert_eq!(s.get::<u32>("width").unwrap(), 160);
+    assert_eq!(s.get::<u32>("height").unwrap(), 120);
+}
+
+fn bgrx_buffer(width: usize, height: usize, pts: gst::ClockTime) -> gst::Buffer {
+    let mut buffer = gst::Buffer::with_size(width * height * 4).unwrap();
+    buffer.get_mut().unwrap().set_pts(pts);
+    buffer
+}
+
+// Downstream reports with a QoS event that it is running late. The element has to
+// skip the frames that would arrive before the reported deadline, count them in the
+// "skipped" property, and keep converting the ones after it.
+#[test]
+fn test_qos_skips_late_frames() {
+    init();
+
+    let mut h = gst_check::Harness::new("rsrgb2gray");
+    let element = h.element().unwrap();
+    let frame = gst::ClockTime::SECOND / 30;
+
+    h.set_src_caps(bgrx_caps(64, 48));
+    assert!(h
+        .push_and_pull(bgrx_buffer(64, 48, gst::ClockTime::ZERO))
+        .is_ok());
+    assert_eq!(element.property::<u64>("skipped"), 0);
+    assert_eq!(element.property::<u64>("processed"), 1);
+
+    // The first frame was displayed 10ms too late
+    assert!(h.push_upstream_event(gst::event::Qos::new(
+        gst::QOSType::Underflow,
+        1.0,
+        gst::ClockTime::from_mseconds(10).nseconds() as i64,
+        gst::ClockTime::ZERO,
+    )));
+
+    // Earlier than the deadline, so it is dropped
+    h.push(bgrx_buffer(64, 48, gst::ClockTime::from_mseconds(5)))
+        .unwrap();
+    assert!(h.try_pull().is_none());
+    assert_eq!(element.property::<u64>("skipped"), 1);
+
+    // Well after the deadline, so it is converted again
+    let out = h.push_and_pull(bgrx_buffer(64, 48, frame * 3)).unwrap();
+    assert_eq!(out.size(), 64 * 48);
+    assert_eq!(out.pts(), Some(frame * 3));
+    assert_eq!(element.property::<u64>("skipped"), 1);
+    assert_eq!(element.property::<u64>("processed"), 2);
+}
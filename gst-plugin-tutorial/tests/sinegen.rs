@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use common::init;
+use gst::prelude::*;
+
+const RATE: u64 = 48_000;
+// 0.1s per buffer at the fixated rate
+const SAMPLES_PER_BUFFER: u32 = 4800;
+
+fn harness() -> gst_check::Harness {
+    let mut h = gst_check::Harness::new("rssinegen");
+    h.element()
+        .unwrap()
+        .set_property("samples-per-buffer", SAMPLES_PER_BUFFER);
+    h.set_sink_caps_str("audio/x-raw,rate=48000,channels=1");
+    h
+}
+
+fn seek_event(start: gst::ClockTime, stop: impl Into<Option<gst::ClockTime>>) -> gst::Event {
+    gst::event::Seek::new(
+        1.0,
+        gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+        gst::SeekType::Set,
+        Some(start),
+        gst::SeekType::Set,
+        stop.into(),
+    )
+}
+
+// A seek sent before starting is applied when the source starts, so the very
+// first buffer already starts at the seek position
+#[test]
+fn test_seek_before_start() {
+    init();
+
+    let mut h = harness();
+    let element = h.element().unwrap();
+    element.set_state(gst::State::Ready).unwrap();
+    assert!(element.send_event(seek_event(gst::ClockTime::SECOND, gst::ClockTime::NONE)));
+    h.play();
+
+    let buffer = h.pull().unwrap();
+    assert_eq!(buffer.pts(), Some(gst::ClockTime::SECOND));
+    assert_eq!(buffer.offset(), RATE);
+    assert_eq!(buffer.size(), SAMPLES_PER_BUFFER as usize * 4);
+
+    let buffer = h.pull().unwrap();
+    assert_eq!(
+        buffer.pts(),
+        Some(gst::ClockTime::SECOND + gst::ClockTime::from_mseconds(100))
+    );
+}
+
+// The stop of the seek segment ends the stream with EOS after exactly the
+// requested number of samples, the last buffer being cut short
+#[test]
+fn test_seek_stop_eos() {
+    init();
+
+    let mut h = harness();
+    let element = h.element().unwrap();
+    element.set_state(gst::State::Ready).unwrap();
+    assert!(element.send_event(seek_event(
+        gst::ClockTime::ZERO,
+        gst::ClockTime::from_mseconds(250)
+    )));
+    h.play();
+
+    let mut samples = 0;
+    let mut last = None;
+    for _ in 0..3 {
+        let buffer = h.pull().unwrap();
+        samples += buffer.size() as u64 / 4;
+        last = Some(buffer);
+    }
+    assert_eq!(samples, RATE / 4);
+    assert_eq!(last.unwrap().offset_end(), RATE / 4);
+
+    loop {
+        let event = h.pull_event().unwrap();
+        if event.type_() == gst::EventType::Eos {
+            break;
+        }
+    }
+    assert!(h.try_pull().is_none());
+}
+
+// A flushing seek while running restarts the stream at the seek position.
+// Buffers produced before the seek may still be queued in the harness, the
+// seek target is off the 0.1s grid of those so that it can be told apart.
+#[test]
+fn test_flushing_seek_while_playing() {
+    init();
+
+    let mut h = harness();
+    h.play();
+
+    let buffer = h.pull().unwrap();
+    assert_eq!(buffer.pts(), Some(gst::ClockTime::ZERO));
+
+    let target = gst::ClockTime::from_mseconds(2050);
+    assert!(h
+        .element()
+        .unwrap()
+        .send_event(seek_event(target, gst::ClockTime::NONE)));
+
+    let mut found = false;
+    for _ in 0..1000 {
+        let buffer = h.pull().unwrap();
+        if buffer.pts() == Some(target) {
+            assert_eq!(buffer.offset(), RATE * 2050 / 1000);
+            found = true;
+            break;
+        }
+    }
+    assert!(found, "no buffer at the seek position");
+
+    // The stream continues contiguously from there
+    let buffer = h.pull().unwrap();
+    assert_eq!(
+        buffer.pts(),
+        Some(target + gst::ClockTime::from_mseconds(100))
+    );
+
+    // Downstream saw the flush and a new segment starting at the target
+    let mut flushed = false;
+    let mut segment = None;
+    while let Some(event) = h.try_pull_event() {
+        match event.view() {
+            gst::EventView::FlushStop(_) => flushed = true,
+            gst::EventView::Segment(ev) => segment = Some(ev.segment().clone()),
+            _ => {}
+        }
+    }
+    assert!(flushed);
+    let segment = segment.unwrap();
+    let segment = segment.downcast_ref::<gst::ClockTime>().unwrap();
+    assert_eq!(segment.start(), Some(target));
+}
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+mod common;
+
+use common::init;
+use gstrstutorial::testing;
+
+const TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(5);
+
+// rsrgb2gray drops the frames with an even offset, so only the odd frames of
+// the bounded source (offsets 1 and 3) come out converted to GRAY8
+#[test]
+fn test_collect_video() {
+    init();
+
+    let buffers = testing::collect(
+        &format!(
+            "{} ! rsrgb2gray ! video/x-raw,format=GRAY8",
+            testing::video_source(5, "video/x-raw,format=BGRx,width=64,height=48")
+        ),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    assert_eq!(buffers.len(), 2);
+    for buffer in &buffers {
+        assert_eq!(buffer.size(), 64 * 48);
+        assert_eq!(buffer.offset() % 2, 1);
+    }
+}
+
+#[test]
+fn test_collect_audio() {
+    init();
+
+    let buffers = testing::collect(
+        &testing::audio_source(3, 480, "audio/x-raw,format=S16LE,channels=1,rate=48000"),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    assert_eq!(buffers.len(), 3);
+    assert_eq!(buffers[0].size(), 480 * 2);
+}
+
+// Errors posted on the bus are returned instead of waiting for the timeout
+#[test]
+fn test_error_message() {
+    init();
+
+    let err = testing::collect("filesrc location=/nonexistent", TIMEOUT).unwrap_err();
+    assert!(matches!(err, testing::Error::Message { .. }), "{}", err);
+}
+
+// Timestamps generated by our elements are contiguous and carry durations
+#[test]
+fn test_validate_video() {
+    init();
+
+    // rsrgb2gray drops every other frame and would report gaps, so validate
+    // a stream that passes every frame through
+    let issues = testing::validate(
+        &format!(
+            "{} ! videoconvert ! video/x-raw,format=GRAY8",
+            testing::video_source(
+                10,
+                "video/x-raw,format=BGRx,width=64,height=48,framerate=30/1"
+            )
+        ),
+        gst::ClockTime::from_nseconds(1),
+        TIMEOUT,
+    )
+    .unwrap();
+    assert!(issues.is_empty(), "{:?}", issues);
+}
+
+#[test]
+fn test_validate_audio() {
+    init();
+
+    let issues = testing::validate(
+        "rssinegen num-buffers=10 samples-per-buffer=480 ! audio/x-raw,rate=48000,channels=1",
+        gst::ClockTime::ZERO,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert!(issues.is_empty(), "{:?}", issues);
+}
+
+// Only every other frame comes out of the even pad, so each one after the
+// first leaves a gap of one frame
+#[test]
+fn test_validate_gap() {
+    init();
+
+    let frame = gst::ClockTime::SECOND / 30;
+    let issues = testing::validate(
+        &format!(
+            "{} ! rsframesplit name=split split.even",
+            testing::video_source(
+                6,
+                "video/x-raw,format=BGRx,width=64,height=48,framerate=30/1"
+            )
+        ),
+        gst::ClockTime::from_nseconds(1),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    assert_eq!(issues.len(), 2, "{:?}", issues);
+    for issue in &issues {
+        match issue {
+            testing::Issue::Gap { expected, pts, .. } => {
+                assert!(*pts - *expected >= frame - gst::ClockTime::from_nseconds(1))
+            }
+            _ => panic!("unexpected issue {}", issue),
+        }
+    }
+}
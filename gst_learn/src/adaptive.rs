@@ -0,0 +1,89 @@
+//! 受け側から返ってくるロス率を見てエンコーダのビットレートを上下させる制御
+//!
+//! ロス率がLOSS_HIGHを超えたらすぐにDECREASEの割合だけ下げ、
+//! LOSS_LOWを下回る状態がSTABLE_INTERVALS回続いたらINCREASEの割合だけ上げる。
+//! その間のロス率では何もしない。下げるのは速く上げるのは遅くして、
+//! 上げ下げを繰り返して振動しないようにしている。
+//! ビットレートは常にminとmaxの範囲に収める。
+
+/// これを超えるロス率なら混雑しているとみなす
+const LOSS_HIGH: f64 = 0.05;
+/// これを下回るロス率なら余裕があるとみなす
+const LOSS_LOW: f64 = 0.01;
+/// 下げるときに掛ける割合
+const DECREASE: f64 = 0.85;
+/// 上げるときに掛ける割合
+const INCREASE: f64 = 1.05;
+/// 上げるまでに余裕のある状態が続かなければならない回数
+const STABLE_INTERVALS: u32 = 3;
+
+/// 1回の判断
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Decrease(u32),
+    Increase(u32),
+    Hold(u32),
+}
+
+impl Decision {
+    /// 判断の後のビットレート(kbps)
+    pub fn bitrate(&self) -> u32 {
+        match *self {
+            Self::Decrease(bitrate) | Self::Increase(bitrate) | Self::Hold(bitrate) => bitrate,
+        }
+    }
+}
+
+/// ビットレートの制御の状態。ビットレートはkbps
+#[derive(Debug, Clone)]
+pub struct BitrateController {
+    min: u32,
+    max: u32,
+    current: u32,
+    /// 余裕のある状態が続いた回数
+    stable: u32,
+}
+
+impl BitrateController {
+    pub fn new(initial: u32, min: u32, max: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            min > 0 && min <= max,
+            "bitrate bounds must satisfy 0 < min <= max"
+        );
+        Ok(Self {
+            min,
+            max,
+            current: initial.clamp(min, max),
+            stable: 0,
+        })
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.current
+    }
+
+    /// 直近の区間のロス率(0.0-1.0)から次のビットレートを決める
+    pub fn update(&mut self, loss: f64) -> Decision {
+        if loss > LOSS_HIGH {
+            self.stable = 0;
+            let bitrate = ((self.current as f64 * DECREASE) as u32).max(self.min);
+            if bitrate < self.current {
+                self.current = bitrate;
+                return Decision::Decrease(bitrate);
+            }
+        } else if loss < LOSS_LOW {
+            self.stable += 1;
+            if self.stable >= STABLE_INTERVALS {
+                self.stable = 0;
+                let bitrate = ((self.current as f64 * INCREASE).ceil() as u32).min(self.max);
+                if bitrate > self.current {
+                    self.current = bitrate;
+                    return Decision::Increase(bitrate);
+                }
+            }
+        } else {
+            self.stable = 0;
+        }
+        Decision::Hold(self.current)
+    }
+}
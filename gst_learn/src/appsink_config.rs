@@ -0,0 +1,162 @@
+//! appsinkの溜め方と捨て方をそろえて設定し、取り出したサンプルと捨てたサンプルを数える
+//!
+//! appsinkは取り出されるまでサンプルを内部のキューに溜める。
+//! - max-buffers: キューに溜める上限。0なら無制限で、取り出しが遅いとメモリを使い続ける
+//! - drop: 上限に達したとき、falseなら上流のストリーミングスレッドを待たせ(バックプレッシャー)、
+//!   trueなら古いサンプルを捨てて上流は止めない
+//! - emit-signals: new-sampleシグナルを出す。シグナルのハンドラはストリーミングスレッドで呼ばれるので、
+//!   ハンドラが遅いとmax-buffersやdropとは関係なく上流が待たされる
+//!
+//! emit-signalsを使わないときは別スレッドでpull_sampleして取り出すので、取り出しの遅さはキューが吸収する。
+//! --appsink-delayで1サンプルごとに待たせて遅い消費者を真似ると、設定による違いが見える。
+//! appsinkのsink padに届いた数と取り出した数を数え、その差を捨てた数として出す。
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+use structopt::StructOpt;
+
+/// appsinkの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct AppSinkConfig {
+    /// appsinkが溜めるサンプルの上限。0なら無制限
+    #[structopt(long, default_value = "0")]
+    pub appsink_max_buffers: u32,
+    /// 上限に達したら上流を待たせずに古いサンプルを捨てる
+    #[structopt(long)]
+    pub appsink_drop: bool,
+    /// 取り出しのスレッドではなく、ストリーミングスレッドのnew-sampleシグナルでサンプルを処理する
+    #[structopt(long)]
+    pub appsink_emit_signals: bool,
+    /// 遅い消費者を真似て、1サンプルごとに待つ時間(ms)
+    #[structopt(long, default_value = "0")]
+    pub appsink_delay: u64,
+}
+
+/// appsinkに届いた数と取り出した数
+#[derive(Debug, Default)]
+pub struct AppSinkStats {
+    received: AtomicU64,
+    processed: AtomicU64,
+    /// EOSが届いた
+    eos: AtomicBool,
+}
+
+/// サンプルを取り出しているappsink
+/// finishで取り出しのスレッドを待って数を出す
+#[derive(Debug)]
+pub struct AppSinkConsumer {
+    stats: Arc<AppSinkStats>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AppSinkConfig {
+    /// appsinkに設定する
+    pub fn apply(&self, appsink: &AppSink) {
+        appsink.set_max_buffers(self.appsink_max_buffers);
+        appsink.set_drop(self.appsink_drop);
+        appsink.set_property("emit-signals", self.appsink_emit_signals);
+    }
+
+    /// appsinkに設定して、サンプルごとにhandlerを呼ぶ
+    /// emit-signalsならnew-sampleシグナルで、そうでなければ別スレッドで取り出す
+    pub fn consume(
+        &self,
+        appsink: &AppSink,
+        handler: impl Fn(&gst::Sample) + Send + Sync + 'static,
+    ) -> anyhow::Result<AppSinkConsumer> {
+        self.apply(appsink);
+        let stats = Arc::new(AppSinkStats::default());
+
+        let sink_pad = appsink.static_pad("sink").context("appsink sink pad")?;
+        let stats_clone = stats.clone();
+        sink_pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                match info.data {
+                    Some(gst::PadProbeData::Buffer(_)) => {
+                        stats_clone.received.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Some(gst::PadProbeData::Event(ref event))
+                        if event.type_() == gst::EventType::Eos =>
+                    {
+                        stats_clone.eos.store(true, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+
+        let delay = Duration::from_millis(self.appsink_delay);
+        let stats_clone = stats.clone();
+        let process = move |sample: &gst::Sample| {
+            handler(sample);
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+            stats_clone.processed.fetch_add(1, Ordering::Relaxed);
+        };
+
+        let thread = if self.appsink_emit_signals {
+            appsink.connect("new-sample", false, move |args| {
+                let appsink = args[0].get::<AppSink>().expect("new-sample args[0]");
+                let ret = match appsink.pull_sample() {
+                    Ok(sample) => {
+                        process(&sample);
+                        gst::FlowReturn::Ok
+                    }
+                    Err(_) => gst::FlowReturn::Eos,
+                };
+                Some(ret.to_value())
+            });
+            None
+        } else {
+            // EOSかNullにしてflushされるとpull_sampleがErrを返して終わる
+            let appsink = appsink.clone();
+            Some(thread::spawn(move || {
+                while let Ok(sample) = appsink.pull_sample() {
+                    process(&sample);
+                }
+            }))
+        };
+
+        log::info!(
+            "appsink: max-buffers={} drop={} emit-signals={} delay={}ms",
+            self.appsink_max_buffers,
+            self.appsink_drop,
+            self.appsink_emit_signals,
+            self.appsink_delay
+        );
+        Ok(AppSinkConsumer { stats, thread })
+    }
+}
+
+impl AppSinkConsumer {
+    /// パイプラインを止めた後に呼び、取り出しのスレッドを待って届いた数、取り出した数、捨てた数を出す
+    /// EOSまで流れていればキューは空なので、差はdropで捨てた数になる
+    pub fn finish(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let received = self.stats.received.load(Ordering::Relaxed);
+        let processed = self.stats.processed.load(Ordering::Relaxed);
+        let dropped = received.saturating_sub(processed);
+        if self.stats.eos.load(Ordering::Relaxed) {
+            log::info!("appsink: received {received}, processed {processed}, dropped {dropped}");
+        } else {
+            log::info!(
+                "appsink: received {received}, processed {processed}, dropped or left queued {dropped}"
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! 記録・トランスコード・配信で共通に使う音声エンコードの設定
+//!
+//! コーデックごとにエンコーダとmuxerの前に入れるパーサーを決め、ビットレートとチャンネル数を設定する。
+//! 出力(コンテナやHLS、DASH)によって格納できるコーデックが違うので、呼び出し側が対応するコーデックを渡し、
+//! --audio-codecを省略したときはその先頭を使う。
+//! ビットレートは全てのエンコーダでbits/sのbitrateプロパティに設定する。FLACは可逆圧縮なので無視する。
+use std::{fmt, str::FromStr};
+
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    Aac,
+    Vorbis,
+    Flac,
+}
+
+impl FromStr for AudioCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opus" => Ok(Self::Opus),
+            "aac" => Ok(Self::Aac),
+            "vorbis" => Ok(Self::Vorbis),
+            "flac" => Ok(Self::Flac),
+            _ => anyhow::bail!("unknown audio codec {s}, expected opus|aac|vorbis|flac"),
+        }
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Opus => "opus",
+            Self::Aac => "aac",
+            Self::Vorbis => "vorbis",
+            Self::Flac => "flac",
+        })
+    }
+}
+
+impl AudioCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::Opus => "opusenc",
+            Self::Aac => "avenc_aac",
+            Self::Vorbis => "vorbisenc",
+            Self::Flac => "flacenc",
+        }
+    }
+
+    /// muxerの前に必要なパーサー
+    pub fn parser(&self) -> Option<&'static str> {
+        match self {
+            Self::Aac => Some("aacparse"),
+            Self::Flac => Some("flacparse"),
+            Self::Opus | Self::Vorbis => None,
+        }
+    }
+}
+
+/// 音声エンコードの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct AudioEncodeOpt {
+    /// 音声のコーデック(opus, aac, vorbis, flac)。省略すると出力に合わせて選ぶ
+    #[structopt(long)]
+    pub audio_codec: Option<AudioCodec>,
+    /// 音声のビットレート(kbps)。省略するとエンコーダの既定値
+    #[structopt(long)]
+    pub audio_bitrate: Option<u32>,
+    /// 音声のチャンネル数。省略すると入力のまま
+    #[structopt(long)]
+    pub audio_channels: Option<u32>,
+}
+
+impl AudioEncodeOpt {
+    /// 出力が格納できるコーデックから使うものを選ぶ。supportedの先頭が既定
+    pub fn codec(&self, supported: &[AudioCodec]) -> anyhow::Result<AudioCodec> {
+        match self.audio_codec {
+            Some(codec) if supported.contains(&codec) => Ok(codec),
+            Some(codec) => anyhow::bail!(
+                "{codec} is not supported by this output, expected one of {}",
+                supported
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("|")
+            ),
+            None => supported
+                .first()
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("this output has no audio codecs")),
+        }
+    }
+
+    /// audioconvertからパーサーまでをgst-launchの書式で返す
+    pub fn launch(&self, codec: AudioCodec) -> String {
+        let mut description = String::from("audioconvert ! audioresample");
+        if let Some(channels) = self.audio_channels {
+            description.push_str(&format!(" ! audio/x-raw,channels={channels}"));
+        }
+        description.push_str(&format!(" ! {}", codec.encoder()));
+        match (self.audio_bitrate, codec) {
+            (Some(_), AudioCodec::Flac) => log::warn!("flac is lossless, ignoring --audio-bitrate"),
+            (Some(bitrate), _) => description.push_str(&format!(" bitrate={}", bitrate * 1000)),
+            (None, _) => {}
+        }
+        if let Some(parser) = codec.parser() {
+            description.push_str(&format!(" ! {parser}"));
+        }
+        description
+    }
+}
@@ -0,0 +1,421 @@
+//! 画面の点滅と音のビープ(カチンコ)を流してカメラとマイクで取り込み、取り込んだ映像と音のずれを測る
+//!
+//! 生成側はperiodごとに同じ時刻から、画面を白く光らせ(flash)、1kHzのビープを鳴らす(beep)。
+//! videotestsrcとaudiotestsrcのsrc padのプローブで、バッファのタイムスタンプを見て光らせる区間と鳴らす区間を書き込む。
+//! 2つは同じパイプラインのsinkで同期して出るので、出てくるまでの遅れの違いがそのまま測る対象になる。
+//!
+//! 取り込み側はカメラ(v4l2src)とマイクを別のパイプラインで取り込み、appsinkで次の系列を集める。
+//! - 映像: フレームごとの平均の明るさ(0〜1)
+//! - 音声: 1ミリ秒ごとの振幅のピーク(0〜1)
+//!
+//! 同じパイプラインで取り込んだので、2つの系列のタイムスタンプは同じランニングタイムで比べられる。
+//! それぞれ中央値(背景)と最大の中間を閾値にして立ち上がりの時刻を求め、フラッシュごとに一番近いビープとの差を出す。
+//! 差が正なら、画面 -> カメラの経路より、スピーカー -> マイクの経路の方が遅い。
+//! 映像の立ち上がりはフレームの間隔(30fpsなら33ms)の精度でしかわからないので、何回も測って統計を見る。
+//!
+//! --loopbackでは機器を使わず、生成した映像と音をteeで分けてそのまま解析する。
+//! --skewでビープをずらして生成すると、測った差がその値になることで解析を確かめられる。
+use std::{
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use byte_slice_cast::*;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+
+use crate::fallback;
+
+/// 生成と取り込みの音声のサンプリングレート
+const RATE: u64 = 48000;
+/// ビープの周波数
+const BEEP_FREQUENCY: f64 = 1000.0;
+/// 音声のピークを求める区間のサンプル数(1ミリ秒)
+const BLOCK: usize = (RATE / 1000) as usize;
+
+/// 解析に渡す前に縮める映像の形式
+const VIDEO_ANALYSIS: &str = "videoconvert ! videoscale \
+    ! video/x-raw,format=GRAY8,width=160,height=120 ! appsink name=video-capture sync=false";
+/// 解析に渡す音声の形式
+const AUDIO_ANALYSIS: &str = "audioconvert ! audioresample \
+    ! audio/x-raw,format=S16LE,channels=1,rate=48000 ! appsink name=audio-capture sync=false";
+
+/// 点滅とビープの時刻
+#[derive(Debug, Clone, Copy)]
+struct Clapper {
+    period: gst::ClockTime,
+    length: gst::ClockTime,
+    /// フラッシュに対してビープを遅らせる時間(ナノ秒)。負なら早める
+    skew: i64,
+}
+
+impl Clapper {
+    fn flash_on(&self, time: gst::ClockTime) -> bool {
+        time.nseconds() % self.period.nseconds() < self.length.nseconds()
+    }
+
+    fn beep_on(&self, time: gst::ClockTime) -> bool {
+        let shifted = time.nseconds() as i64 - self.skew;
+        shifted >= 0 && (shifted as u64) % self.period.nseconds() < self.length.nseconds()
+    }
+}
+
+/// 取り込む機器
+#[derive(Debug, Clone)]
+pub enum Capture {
+    /// 生成した映像と音をそのまま解析する
+    Loopback,
+    Devices {
+        /// v4l2srcのデバイス。Noneならautovideosrc
+        video: Option<String>,
+        /// pulsesrcのデバイス。Noneならautoaudiosrc
+        audio: Option<String>,
+    },
+}
+
+/// 測定の設定
+#[derive(Debug)]
+pub struct AvSyncOptions {
+    pub capture: Capture,
+    /// 点滅とビープの間隔
+    pub period: Duration,
+    /// 測る時間
+    pub duration: Duration,
+    /// ビープをずらして生成する時間(ミリ秒)
+    pub skew_ms: i64,
+}
+
+/// 取り込んだ映像と音声の系列。(タイムスタンプ, 0〜1の値)
+#[derive(Debug, Default)]
+struct Series {
+    video: Vec<(gst::ClockTime, f64)>,
+    audio: Vec<(gst::ClockTime, f64)>,
+}
+
+/// 生成側のパイプライン。flashとbeepのteeから取り込み側を繋げる
+fn generator_description(loopback: bool) -> String {
+    let mut description = "\
+        videotestsrc name=flash is-live=true pattern=black \
+        ! video/x-raw,format=GRAY8,width=640,height=480,framerate=60/1 \
+        ! tee name=vt ! queue ! videoconvert ! autovideosink \
+        audiotestsrc name=beep is-live=true wave=silence samplesperbuffer=240 \
+        ! audio/x-raw,format=S16LE,channels=1,rate=48000 \
+        ! tee name=at ! queue ! audioconvert ! autoaudiosink"
+        .to_string();
+    if loopback {
+        description.push_str(&format!(
+            " vt. ! queue ! {VIDEO_ANALYSIS} at. ! queue ! {AUDIO_ANALYSIS}"
+        ));
+    }
+    description
+}
+
+fn capture_description(video: &Option<String>, audio: &Option<String>) -> String {
+    let video = match video {
+        Some(device) => format!("v4l2src device=\"{device}\""),
+        None => "autovideosrc".to_string(),
+    };
+    let audio = match audio {
+        Some(device) => format!("pulsesrc device=\"{device}\""),
+        None => "autoaudiosrc".to_string(),
+    };
+    format!("{video} ! {VIDEO_ANALYSIS} {audio} ! {AUDIO_ANALYSIS}")
+}
+
+fn launch(description: &str) -> anyhow::Result<gst::Pipeline> {
+    Ok(fallback::parse_launch(description)?
+        .downcast::<gst::Pipeline>()
+        .unwrap())
+}
+
+/// videotestsrcの黒いフレームを、点滅の区間だけ白にする
+fn add_flash(source: &gst::Element, clapper: Clapper) -> anyhow::Result<()> {
+    let pad = source.static_pad("src").context("flash src pad")?;
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+            if matches!(buffer.pts(), Some(pts) if clapper.flash_on(pts)) {
+                let buffer = buffer.make_mut();
+                if let Ok(mut map) = buffer.map_writable() {
+                    map.as_mut_slice().fill(0xff);
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
+
+/// audiotestsrcの無音に、ビープの区間だけサイン波を書く
+fn add_beep(source: &gst::Element, clapper: Clapper) -> anyhow::Result<()> {
+    let pad = source.static_pad("src").context("beep src pad")?;
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+            let pts = match buffer.pts() {
+                Some(pts) => pts,
+                None => return gst::PadProbeReturn::Ok,
+            };
+            let buffer = buffer.make_mut();
+            if let Ok(mut map) = buffer.map_writable() {
+                if let Ok(samples) = map.as_mut_slice().as_mut_slice_of::<i16>() {
+                    for (i, sample) in samples.iter_mut().enumerate() {
+                        let time =
+                            pts + gst::ClockTime::from_nseconds(i as u64 * 1_000_000_000 / RATE);
+                        *sample = if clapper.beep_on(time) {
+                            let phase = 2.0 * PI * BEEP_FREQUENCY * time.nseconds() as f64 / 1e9;
+                            (phase.sin() * i16::MAX as f64 * 0.8) as i16
+                        } else {
+                            0
+                        };
+                    }
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
+
+fn appsink(pipeline: &gst::Pipeline, name: &str) -> anyhow::Result<AppSink> {
+    Ok(pipeline
+        .by_name(name)
+        .with_context(|| name.to_string())?
+        .dynamic_cast::<AppSink>()
+        .unwrap())
+}
+
+/// フレームの平均の明るさ。幅が4の倍数なので行の詰め物はない
+fn brightness(sample: &gst::Sample) -> Option<(gst::ClockTime, f64)> {
+    let buffer = sample.buffer()?;
+    let pts = buffer.pts()?;
+    let map = buffer.map_readable().ok()?;
+    let data = map.as_slice();
+    let sum = data.iter().map(|&v| v as u64).sum::<u64>();
+    Some((pts, sum as f64 / data.len().max(1) as f64 / 255.0))
+}
+
+/// 1ミリ秒ごとの振幅のピーク
+fn peaks(sample: &gst::Sample) -> Vec<(gst::ClockTime, f64)> {
+    let buffer = match sample.buffer() {
+        Some(buffer) => buffer,
+        None => return Vec::new(),
+    };
+    let (pts, map) = match (buffer.pts(), buffer.map_readable()) {
+        (Some(pts), Ok(map)) => (pts, map),
+        _ => return Vec::new(),
+    };
+    let samples = match map.as_slice().as_slice_of::<i16>() {
+        Ok(samples) => samples,
+        Err(_) => return Vec::new(),
+    };
+    samples
+        .chunks(BLOCK)
+        .enumerate()
+        .map(|(i, block)| {
+            let time = pts + gst::ClockTime::from_mseconds(i as u64);
+            let peak = block
+                .iter()
+                .map(|&s| (s as i32).unsigned_abs())
+                .max()
+                .unwrap_or(0);
+            (time, peak as f64 / i16::MAX as f64)
+        })
+        .collect()
+}
+
+/// 取り込み用のappsinkから系列を集める
+fn collect(pipeline: &gst::Pipeline, series: &Arc<Mutex<Series>>) -> anyhow::Result<()> {
+    let video_series = series.clone();
+    appsink(pipeline, "video-capture")?.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                if let Some(point) = brightness(&sample) {
+                    video_series.lock().unwrap().video.push(point);
+                }
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    let audio_series = series.clone();
+    appsink(pipeline, "audio-capture")?.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                audio_series.lock().unwrap().audio.extend(peaks(&sample));
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    Ok(())
+}
+
+/// 閾値を背景(中央値)と最大の中間にして、下から越えた時刻を返す
+/// 越えてからmin_gapの間は同じ立ち上がりとみなす
+fn onsets(series: &[(gst::ClockTime, f64)], min_gap: gst::ClockTime) -> Vec<gst::ClockTime> {
+    /// 背景との差がこれより小さければ、光っていない、鳴っていないとみなす
+    const MIN_CONTRAST: f64 = 0.05;
+
+    let mut levels = series.iter().map(|(_, level)| *level).collect::<Vec<_>>();
+    levels.sort_by(f64::total_cmp);
+    let (background, peak) = match (levels.get(levels.len() / 2), levels.last()) {
+        (Some(background), Some(peak)) => (*background, *peak),
+        _ => return Vec::new(),
+    };
+    if peak - background < MIN_CONTRAST {
+        return Vec::new();
+    }
+    let threshold = (background + peak) / 2.0;
+
+    let mut result = Vec::new();
+    // 光っている途中から始まったときは数えない
+    let mut above = series[0].1 >= threshold;
+    let mut last: Option<gst::ClockTime> = None;
+    for &(time, level) in series {
+        let now_above = level >= threshold;
+        if now_above && !above && !matches!(last, Some(last) if time < last + min_gap) {
+            result.push(time);
+            last = Some(time);
+        }
+        above = now_above;
+    }
+    result
+}
+
+/// フラッシュごとに一番近いビープとの差(ナノ秒)。半周期より離れたものは対応しないとみなす
+fn offsets(
+    flashes: &[gst::ClockTime],
+    beeps: &[gst::ClockTime],
+    period: gst::ClockTime,
+) -> Vec<i64> {
+    flashes
+        .iter()
+        .filter_map(|flash| {
+            beeps
+                .iter()
+                .map(|beep| beep.nseconds() as i64 - flash.nseconds() as i64)
+                .min_by_key(|offset| offset.abs())
+                .filter(|offset| offset.unsigned_abs() < period.nseconds() / 2)
+        })
+        .collect()
+}
+
+/// 差の統計を出す
+fn report(offsets: &mut [i64]) {
+    let ms = |ns: f64| ns / 1e6;
+    offsets.sort_unstable();
+    let count = offsets.len() as f64;
+    let mean = offsets.iter().sum::<i64>() as f64 / count;
+    let variance = offsets
+        .iter()
+        .map(|&offset| (offset as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    println!(
+        "audio - video offset over {} claps: mean {:+.1}ms median {:+.1}ms min {:+.1}ms max {:+.1}ms stddev {:.1}ms",
+        offsets.len(),
+        ms(mean),
+        ms(offsets[offsets.len() / 2] as f64),
+        ms(offsets[0] as f64),
+        ms(offsets[offsets.len() - 1] as f64),
+        ms(variance.sqrt())
+    );
+    if mean > 0.0 {
+        println!("audio arrives {:.1}ms after video", ms(mean));
+    } else {
+        println!("audio arrives {:.1}ms before video", ms(-mean));
+    }
+}
+
+/// durationの間パイプラインを動かし、どれかがエラーを出せばErr
+fn measure(pipelines: &[gst::Pipeline], duration: Duration) -> anyhow::Result<()> {
+    for pipeline in pipelines {
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Unable to set the pipeline to the `Playing` state")?;
+    }
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        for pipeline in pipelines {
+            let bus = pipeline.bus().context("bus")?;
+            while let Some(msg) = bus.timed_pop(50 * gst::ClockTime::MSECOND) {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    anyhow::bail!(
+                        "{} from {:?} ({:?})",
+                        err.error(),
+                        err.src().map(|s| s.path_string()),
+                        err.debug()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// カチンコを流しながら取り込み、映像と音のずれを測る
+pub fn run(options: &AvSyncOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let clapper = Clapper {
+        period: gst::ClockTime::from_nseconds(options.period.as_nanos() as u64),
+        length: gst::ClockTime::from_nseconds(options.period.as_nanos() as u64 / 10),
+        skew: options.skew_ms * 1_000_000,
+    };
+    anyhow::ensure!(
+        clapper.length >= 50 * gst::ClockTime::MSECOND,
+        "--period is too short to be captured"
+    );
+    anyhow::ensure!(
+        options.skew_ms.unsigned_abs() * 1_000_000 < clapper.period.nseconds() / 2,
+        "--skew must be shorter than half of --period"
+    );
+
+    let loopback = matches!(options.capture, Capture::Loopback);
+    let generator = launch(&generator_description(loopback))?;
+    add_flash(&generator.by_name("flash").context("flash")?, clapper)?;
+    add_beep(&generator.by_name("beep").context("beep")?, clapper)?;
+    let capture = match &options.capture {
+        Capture::Loopback => generator.clone(),
+        Capture::Devices { video, audio } => launch(&capture_description(video, audio))?,
+    };
+    let series = Arc::new(Mutex::new(Series::default()));
+    collect(&capture, &series)?;
+
+    // 取り込みを先に始めて、最初のカチンコを逃さないようにする
+    let pipelines = if loopback {
+        vec![generator]
+    } else {
+        vec![capture, generator]
+    };
+    log::info!(
+        "measuring for {:?}, a flash and a beep every {}",
+        options.duration,
+        clapper.period
+    );
+    let result = measure(&pipelines, options.duration);
+    for pipeline in &pipelines {
+        let _ = pipeline.set_state(gst::State::Null);
+    }
+    result?;
+
+    let series = series.lock().unwrap();
+    let min_gap = gst::ClockTime::from_nseconds(clapper.period.nseconds() / 2);
+    let flashes = onsets(&series.video, min_gap);
+    let beeps = onsets(&series.audio, min_gap);
+    log::info!(
+        "captured {} frames with {} flashes, {}ms of audio with {} beeps",
+        series.video.len(),
+        flashes.len(),
+        series.audio.len(),
+        beeps.len()
+    );
+    let mut offsets = offsets(&flashes, &beeps, clapper.period);
+    anyhow::ensure!(
+        !offsets.is_empty(),
+        "no flash matched a beep, check that the camera sees the screen and the microphone hears the speaker"
+    );
+    report(&mut offsets);
+    Ok(())
+}
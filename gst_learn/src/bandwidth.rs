@@ -0,0 +1,129 @@
+//! ソースから入ってくるデータ量を測る
+//!
+//! ソースのsrc padにプローブを付けて、通ったバイト数を一定の区間ごとに集計し、
+//! 区間ごとの速度の指数移動平均をスループットとする。
+//! playbinではsource-setupでソースが作られた時点で、それ以外のパイプラインでは今あるソースに付ける。
+//! ソースが`stats`プロパティを持っていれば(souphttpsrcなど)、その内容も読めるようにする。
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gst::prelude::*;
+
+/// 速度を1回計算する区間
+const WINDOW: Duration = Duration::from_millis(500);
+/// 指数移動平均で新しい区間に掛ける重み
+const ALPHA: f64 = 0.3;
+
+#[derive(Debug, Default)]
+struct State {
+    total: u64,
+    window_bytes: u64,
+    window_start: Option<Instant>,
+    bytes_per_sec: Option<f64>,
+    source: Option<glib::WeakRef<gst::Element>>,
+}
+
+impl State {
+    fn add(&mut self, bytes: u64) {
+        self.total += bytes;
+        self.window_bytes += bytes;
+        let now = Instant::now();
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed < WINDOW {
+            return;
+        }
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        let ema = match self.bytes_per_sec {
+            Some(ema) => ALPHA * rate + (1.0 - ALPHA) * ema,
+            None => rate,
+        };
+        log::debug!("throughput {:.0} B/s (window {rate:.0} B/s)", ema);
+        self.bytes_per_sec = Some(ema);
+        self.window_bytes = 0;
+        self.window_start = Some(now);
+    }
+}
+
+/// ソースのスループットの計測
+#[derive(Debug, Default)]
+pub struct Meter {
+    state: Mutex<State>,
+}
+
+impl Meter {
+    /// パイプラインのソースにプローブを付ける。playbinならソースが作られたときに付ける
+    pub fn attach(pipeline: &gst::Element) -> Arc<Self> {
+        let meter = Arc::new(Self::default());
+        if glib::subclass::SignalId::lookup("source-setup", pipeline.type_()).is_some() {
+            let meter = meter.clone();
+            pipeline.connect("source-setup", false, move |args| {
+                let source = args[1].get::<gst::Element>().unwrap();
+                meter.watch(&source);
+                None
+            });
+        } else if let Some(bin) = pipeline.downcast_ref::<gst::Bin>() {
+            for source in bin.iterate_sources().into_iter().flatten() {
+                meter.watch(&source);
+            }
+        }
+        meter
+    }
+
+    /// ソースに限らず、指定したpadを通るデータ量を測る
+    pub fn attach_pad(pad: &gst::Pad) -> Arc<Self> {
+        let meter = Arc::new(Self::default());
+        meter.probe(pad);
+        meter
+    }
+
+    /// ソースのsrc padを通るバッファを数える。後から作られるpadにも付ける
+    fn watch(self: &Arc<Self>, source: &gst::Element) {
+        log::debug!("measuring throughput of {}", source.name());
+        self.state.lock().unwrap().source = Some(source.downgrade());
+        for pad in source.src_pads() {
+            self.probe(&pad);
+        }
+        let meter = self.clone();
+        source.connect_pad_added(move |_, pad| {
+            if pad.direction() == gst::PadDirection::Src {
+                meter.probe(pad);
+            }
+        });
+    }
+
+    fn probe(self: &Arc<Self>, pad: &gst::Pad) {
+        let meter = self.clone();
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+            move |_, info| {
+                let bytes = match &info.data {
+                    Some(gst::PadProbeData::Buffer(buffer)) => buffer.size(),
+                    Some(gst::PadProbeData::BufferList(list)) => list.calculate_size(),
+                    _ => 0,
+                };
+                meter.state.lock().unwrap().add(bytes as u64);
+                gst::PadProbeReturn::Ok
+            },
+        );
+    }
+
+    /// 指数移動平均のスループット(bytes/s)
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        self.state.lock().unwrap().bytes_per_sec
+    }
+
+    /// これまでに通ったバイト数
+    pub fn total_bytes(&self) -> u64 {
+        self.state.lock().unwrap().total
+    }
+
+    /// ソースのstatsプロパティ。持っていなければNone
+    pub fn source_stats(&self) -> Option<gst::Structure> {
+        let source = self.state.lock().unwrap().source.as_ref()?.upgrade()?;
+        source.find_property("stats")?;
+        source.try_property::<gst::Structure>("stats").ok()
+    }
+}
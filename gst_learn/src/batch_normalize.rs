@@ -0,0 +1,363 @@
+//! ディレクトリの音声ファイルをそれぞれ同じ音量にそろえたコピーを書く(ファイルごとのラウドネス正規化)
+//!
+//! ファイルごとに2つのパイプラインを順に動かす。
+//! 1. rganalysisで最後まで解析し、EOSの前に出るタグからトラックのゲインとピークを読む。
+//!    ゲインはreference-level(dB)の音量にするための値で、89dBがReplayGainの基準。
+//! 2. volumeでゲインをかけてエンコードし、出力のディレクトリに同じ名前で書く。
+//!    ゲインを上げるとピークが1.0を超えるときは、クリップしないところまでゲインを下げる。
+//!
+//! ファイルはジョブのキューに入れ、--jobsの数のワーカーのスレッドがそれぞれ取り出して処理する。
+//! パイプラインはワーカーごとに作るので、1つのファイルが壊れていても他のファイルの処理は止まらない。
+//! 結果はチャンネルで受け取り、全部終わったらファイルごとのゲインと失敗の理由を表にして出す。
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    audio_encoder::AudioEncodeOpt,
+    container::{self, Container, MuxerOptions},
+    fallback, transcode,
+};
+
+/// 入力として拾う拡張子
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "wav", "flac", "mp3", "ogg", "oga", "opus", "m4a", "aac", "mka", "webm",
+];
+
+/// 一括正規化の設定
+#[derive(Debug, Clone)]
+pub struct BatchNormalizeOptions {
+    /// 入力のファイルを探すディレクトリ。サブディレクトリは見ない
+    pub dir: PathBuf,
+    /// 正規化したファイルを書くディレクトリ
+    pub output: PathBuf,
+    pub container: Container,
+    /// そろえる音量。rganalysisのreference-level(dB)
+    pub reference_level: f64,
+    /// 同時に動かすワーカーの数
+    pub jobs: usize,
+}
+
+/// 1つのファイルの結果
+#[derive(Debug)]
+struct Report {
+    input: PathBuf,
+    result: anyhow::Result<Normalized>,
+    elapsed: Duration,
+}
+
+#[derive(Debug)]
+struct Normalized {
+    output: PathBuf,
+    /// rganalysisが出したゲイン(dB)
+    measured: f64,
+    /// 実際にかけたゲイン(dB)。クリップしないように下げていればmeasuredより小さい
+    applied: f64,
+    /// 元の音声のピーク(1.0がフルスケール)
+    peak: f64,
+    duration: gst::ClockTime,
+}
+
+/// ディレクトリの音声ファイルを名前順に集める
+fn collect_inputs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut inputs = fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+                    .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    inputs.sort();
+    Ok(inputs)
+}
+
+/// 入力をデコードして、最初の音声のpadをbranchに繋ぐパイプラインを作る
+fn decode_into(input: &Path, branch: &str) -> anyhow::Result<(gst::Pipeline, gst::Bin)> {
+    let pipeline = gst::Pipeline::new(None);
+    let decode = transcode::add_source(&pipeline, input.to_str().context("non UTF-8 path")?)?;
+    let branch = fallback::parse_bin_from_description(branch, true)?;
+    pipeline.add(&branch)?;
+
+    let sink_pad = branch.static_pad("sink").context("branch sink pad")?;
+    decode.connect_pad_added(move |_, pad| {
+        let is_audio = pad
+            .current_caps()
+            .and_then(|caps| caps.structure(0).map(|s| s.name().starts_with("audio/")))
+            .unwrap_or_default();
+        // 音声が複数あっても最初の1本だけを使う
+        if !is_audio || sink_pad.is_linked() {
+            return;
+        }
+        if let Err(err) = pad.link(&sink_pad) {
+            log::error!("failed to link {}: {err}", pad.name());
+        }
+    });
+    Ok((pipeline, branch))
+}
+
+/// EOSまでのバスのメッセージから、タグを集める
+fn collect_tags(bus: &gst::Bus) -> anyhow::Result<gst::TagList> {
+    let mut tags = gst::TagList::new();
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => return Ok(tags),
+            MessageView::Tag(tag) => {
+                tags.make_mut()
+                    .insert(&tag.tags(), gst::TagMergeMode::Replace);
+            }
+            MessageView::Error(err) => anyhow::bail!(
+                "{} from {:?}",
+                err.error(),
+                err.src().map(|s| s.path_string())
+            ),
+            _ => {}
+        }
+    }
+    anyhow::bail!("bus closed before EOS")
+}
+
+/// EOSまで流してNullに戻し、途中で出たタグを返す
+fn run_to_eos(pipeline: &gst::Pipeline) -> anyhow::Result<gst::TagList> {
+    let bus = pipeline.bus().context("bus")?;
+    let result = pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")
+        .and_then(|_| collect_tags(&bus));
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
+
+/// rganalysisでゲインとピークを測る
+fn measure(input: &Path, reference_level: f64) -> anyhow::Result<(f64, f64)> {
+    let (pipeline, _) = decode_into(
+        input,
+        &format!(
+            "audioconvert ! audioresample ! rganalysis reference-level={reference_level} \
+             ! fakesink sync=false"
+        ),
+    )?;
+    let tags = run_to_eos(&pipeline)?;
+    let gain = tags
+        .get::<gst::tags::TrackGain>()
+        .context("rganalysis reported no track gain (no audio stream?)")?
+        .get();
+    let peak = tags
+        .get::<gst::tags::TrackPeak>()
+        .map(|peak| peak.get())
+        .unwrap_or(1.0);
+    Ok((gain, peak))
+}
+
+/// ゲインをかけてエンコードし、outputに書く
+fn write(
+    input: &Path,
+    output: &Path,
+    gain: f64,
+    container: Container,
+    audio: &AudioEncodeOpt,
+) -> anyhow::Result<()> {
+    let codec = audio.codec(container.audio_codecs())?;
+    let (pipeline, branch) = decode_into(
+        input,
+        &format!(
+            "audioconvert ! volume volume={} ! {}",
+            10f64.powf(gain / 20.0),
+            audio.launch(codec)
+        ),
+    )?;
+    let mux = container.make_muxer("mux", MuxerOptions::default())?;
+    let sink = gst::ElementFactory::make("filesink", None)?;
+    sink.set_property("location", output.to_str().context("non UTF-8 path")?);
+    pipeline.add_many(&[&mux, &sink])?;
+    mux.link(&sink)?;
+    branch.link(&mux)?;
+    run_to_eos(&pipeline)?;
+    Ok(())
+}
+
+/// 出力のファイル名。拡張子だけをコンテナに合わせて変える
+fn output_path(input: &Path, options: &BatchNormalizeOptions) -> anyhow::Result<PathBuf> {
+    let stem = input.file_stem().context("file without a name")?;
+    Ok(options.output.join(format!(
+        "{}.{}",
+        stem.to_string_lossy(),
+        options.container.extension()
+    )))
+}
+
+/// 1つのファイルを測って正規化したコピーを書く
+fn normalize(
+    input: &Path,
+    options: &BatchNormalizeOptions,
+    audio: &AudioEncodeOpt,
+) -> anyhow::Result<Normalized> {
+    let (measured, peak) = measure(input, options.reference_level)?;
+    // ピークがフルスケールを超えないところまでに抑える
+    let headroom = if peak > 0.0 {
+        -20.0 * peak.log10()
+    } else {
+        f64::INFINITY
+    };
+    let applied = measured.min(headroom);
+
+    let output = output_path(input, options)?;
+    write(input, &output, applied, options.container, audio)?;
+    let duration = container::verify(&output)?;
+    Ok(Normalized {
+        output,
+        measured,
+        applied,
+        peak,
+        duration,
+    })
+}
+
+/// キューが空になるまでファイルを取り出して処理し、結果を送る
+fn worker(
+    queue: Arc<Mutex<VecDeque<PathBuf>>>,
+    reports: mpsc::Sender<Report>,
+    options: BatchNormalizeOptions,
+    audio: AudioEncodeOpt,
+) {
+    loop {
+        let input = match queue.lock().unwrap().pop_front() {
+            Some(input) => input,
+            None => return,
+        };
+        let started = Instant::now();
+        let result = normalize(&input, &options, &audio);
+        let report = Report {
+            input,
+            result,
+            elapsed: started.elapsed(),
+        };
+        if reports.send(report).is_err() {
+            return;
+        }
+    }
+}
+
+fn print_summary(reports: &[Report], elapsed: Duration) {
+    println!(
+        "{:<32} {:>9} {:>9} {:>7} {:>12} {:>8}",
+        "file", "gain dB", "applied", "peak", "duration", "time"
+    );
+    for report in reports {
+        let name = report
+            .input
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match &report.result {
+            Ok(n) => println!(
+                "{name:<32} {:>+9.2} {:>+9.2} {:>7.3} {:>12} {:>7.1}s{}",
+                n.measured,
+                n.applied,
+                n.peak,
+                n.duration.to_string(),
+                report.elapsed.as_secs_f64(),
+                if n.applied < n.measured {
+                    " (limited by peak)"
+                } else {
+                    ""
+                }
+            ),
+            Err(err) => println!("{name:<32} failed: {err:#}"),
+        }
+    }
+    let failed = reports.iter().filter(|r| r.result.is_err()).count();
+    println!(
+        "normalized {} of {} files in {:.1}s, {failed} failed",
+        reports.len() - failed,
+        reports.len(),
+        elapsed.as_secs_f64()
+    );
+}
+
+/// ディレクトリの音声ファイルをワーカーで分けて正規化し、最後に結果をまとめて出す
+/// 失敗したファイルがあればErr
+pub fn run(options: &BatchNormalizeOptions, audio: &AudioEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(options.jobs > 0, "--jobs must be positive");
+    let inputs = collect_inputs(&options.dir)?;
+    anyhow::ensure!(
+        !inputs.is_empty(),
+        "no audio files in {}",
+        options.dir.display()
+    );
+    // 拡張子だけが違う入力は同じ出力のファイルになってしまう
+    let mut outputs = inputs
+        .iter()
+        .map(|input| output_path(input, options))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    outputs.sort();
+    if let Some(pair) = outputs.windows(2).find(|pair| pair[0] == pair[1]) {
+        anyhow::bail!("several inputs would be written to {}", pair[0].display());
+    }
+    fs::create_dir_all(&options.output)
+        .with_context(|| format!("failed to create {}", options.output.display()))?;
+    anyhow::ensure!(
+        options.output.canonicalize()? != options.dir.canonicalize()?,
+        "the output directory must differ from the input directory"
+    );
+
+    let total = inputs.len();
+    let queue = Arc::new(Mutex::new(VecDeque::from(inputs)));
+    let (tx, rx) = mpsc::channel();
+    let started = Instant::now();
+    let workers = (0..options.jobs.min(total))
+        .map(|_| {
+            let queue = queue.clone();
+            let tx = tx.clone();
+            let options = options.clone();
+            let audio = audio.clone();
+            thread::spawn(move || worker(queue, tx, options, audio))
+        })
+        .collect::<Vec<_>>();
+    // ワーカーが全部終わるとチャンネルが閉じる
+    drop(tx);
+
+    let mut reports = Vec::with_capacity(total);
+    for report in rx {
+        match &report.result {
+            Ok(n) => log::info!(
+                "[{}/{total}] {} -> {} ({:+.2} dB)",
+                reports.len() + 1,
+                report.input.display(),
+                n.output.display(),
+                n.applied
+            ),
+            Err(err) => log::warn!(
+                "[{}/{total}] {}: {err:#}",
+                reports.len() + 1,
+                report.input.display()
+            ),
+        }
+        reports.push(report);
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    reports.sort_by(|a, b| a.input.cmp(&b.input));
+    print_summary(&reports, started.elapsed());
+    let failed = reports.iter().filter(|r| r.result.is_err()).count();
+    anyhow::ensure!(failed == 0, "{failed} files failed");
+    Ok(())
+}
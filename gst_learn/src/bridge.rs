@@ -0,0 +1,170 @@
+//! appsinkからappsrcへサンプルを渡して、独立した2つのパイプラインを繋ぐ例
+//!
+//! それぞれのパイプラインは別々のクロックとbase-timeで動くので、受け取ったバッファのPTSは
+//! そのままでは送り先のパイプラインのrunning-timeと合わない。
+//! 最初のサンプルを受け取った時点の送り先のrunning-timeを基準に、送り元のrunning-timeとの差を足して付け替える。
+//! 片方を止めたり作り直したりしても、もう片方に影響しないように分けておけるのが利点。
+use std::sync::Mutex;
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+
+use crate::fallback;
+
+/// 送り元のrunning-timeを送り先のrunning-timeに変換する
+#[derive(Debug, Default)]
+struct Rebaser {
+    /// 送り先 - 送り元 (ns)
+    offset: Option<i64>,
+}
+
+impl Rebaser {
+    fn rebase(&mut self, running_time: gst::ClockTime, now: gst::ClockTime) -> gst::ClockTime {
+        let offset = *self
+            .offset
+            .get_or_insert(now.nseconds() as i64 - running_time.nseconds() as i64);
+        gst::ClockTime::from_nseconds((running_time.nseconds() as i64 + offset).max(0) as u64)
+    }
+}
+
+/// パイプラインの現在のrunning-time
+fn current_running_time(pipeline: &gst::Pipeline) -> Option<gst::ClockTime> {
+    let now = pipeline.clock()?.time()?;
+    now.checked_sub(pipeline.base_time()?)
+}
+
+/// 送り元のサンプルのPTSをrunning-timeにしてから送り先の時刻に付け替える
+fn forward(
+    sample: gst::Sample,
+    appsrc: &AppSrc,
+    consumer: &gst::Pipeline,
+    rebaser: &Mutex<Rebaser>,
+) -> Result<gst::FlowSuccess, gst::FlowError> {
+    let mut buffer = sample.buffer_owned().ok_or(gst::FlowError::Error)?;
+    let segment = sample
+        .segment()
+        .and_then(|s| s.downcast_ref::<gst::ClockTime>().cloned());
+    let running_time = match (buffer.pts(), segment) {
+        (Some(pts), Some(segment)) => segment.to_running_time(pts),
+        _ => None,
+    };
+    // 送り先がまだPlayingになっていなければ時刻を付けずに渡し、appsrcのdo-timestampに任せる
+    let pts = match (running_time, current_running_time(consumer)) {
+        (Some(running_time), Some(now)) => Some(rebaser.lock().unwrap().rebase(running_time, now)),
+        _ => None,
+    };
+
+    {
+        let buffer = buffer.make_mut();
+        buffer.set_pts(pts);
+        buffer.set_dts(gst::ClockTime::NONE);
+    }
+    // 途中でcapsが変わった場合も送り先に伝える
+    if let Some(caps) = sample.caps() {
+        if appsrc.caps().as_deref() != Some(caps) {
+            appsrc.set_caps(Some(&caps.to_owned()));
+        }
+    }
+    appsrc.push_buffer(buffer)
+}
+
+/// バスからEOSかエラーを拾ったらtrue
+fn finished(bus: &gst::Bus, name: &str) -> bool {
+    while let Some(msg) = bus.pop() {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => {
+                log::info!("{name}: EOS");
+                return true;
+            }
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// framesだけ生成する送り元と、表示する送り先を同じプロセスで動かす
+pub fn run(frames: u32) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let producer = fallback::parse_launch(&format!(
+        "videotestsrc is-live=true pattern=ball num-buffers={frames} ! timeoverlay \
+         ! videoconvert ! video/x-raw,format=I420 ! appsink name=sink sync=false"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let consumer = fallback::parse_launch(
+        "appsrc name=src is-live=true format=time do-timestamp=true \
+         ! videoconvert ! autovideosink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    let appsink = producer
+        .by_name("sink")
+        .context("sink")?
+        .dynamic_cast::<AppSink>()
+        .unwrap();
+    let appsrc = consumer
+        .by_name("src")
+        .context("src")?
+        .dynamic_cast::<AppSrc>()
+        .unwrap();
+
+    // コールバックから送り先のパイプラインを強参照すると循環するので弱参照にする
+    let consumer_weak = consumer.downgrade();
+    let appsrc_eos = appsrc.clone();
+    let appsrc_error = appsrc.clone();
+    let rebaser = Mutex::new(Rebaser::default());
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let consumer = consumer_weak.upgrade().ok_or(gst::FlowError::Flushing)?;
+                forward(sample, &appsrc, &consumer, &rebaser)
+            })
+            .eos(move |_| {
+                let _ = appsrc_eos.end_of_stream();
+            })
+            .build(),
+    );
+
+    consumer
+        .set_state(gst::State::Playing)
+        .context("Unable to set the consumer to the `Playing` state")?;
+    producer
+        .set_state(gst::State::Playing)
+        .context("Unable to set the producer to the `Playing` state")?;
+
+    let producer_bus = producer.bus().context("producer bus")?;
+    let consumer_bus = consumer.bus().context("consumer bus")?;
+    let mut producer_done = false;
+    loop {
+        // 送り元が終わってもappsrcに流したEOSが届くまで送り先は動かしておく
+        if finished(&consumer_bus, "consumer") {
+            break;
+        }
+        if !producer_done && finished(&producer_bus, "producer") {
+            producer_done = true;
+            // エラーで止まった場合はappsinkのeosが呼ばれないので、ここでも送り先を終わらせる
+            let _ = appsrc_error.end_of_stream();
+            log::info!("waiting for the consumer to drain");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    producer.set_state(gst::State::Null)?;
+    consumer.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! ストリーミング再生中のバッファの溜まり具合を記録してグラフにする
+//!
+//! Bufferingメッセージは溜まった割合しか教えてくれないが、Bufferingクエリを投げると
+//! queue2(playbinの中のもの)から、受信と消費の平均速度(bytes/s)と、溜め終わるまでの見込み時間も返ってくる。
+//! 受信が消費より遅ければいずれバッファが尽きるので、割合だけを見るより早く問題に気付ける。
+//!
+//! 一定間隔でクエリを投げて1行ずつ棒グラフを出し、終了時に全体の推移を縦のグラフにまとめる。
+//! --stats-outを指定するとCSVにも書き出すので、表計算ソフトなどで後から比べられる。
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+/// 1行の棒グラフの幅
+const BAR_WIDTH: usize = 40;
+/// 終了時のグラフの高さ
+const CHART_HEIGHT: usize = 10;
+/// 終了時のグラフの最大の幅。サンプルが多ければ間引く
+const CHART_WIDTH: usize = 60;
+
+/// 1回のクエリの結果
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// 記録を始めてからの秒数
+    elapsed: f64,
+    percent: i32,
+    mode: gst::BufferingMode,
+    /// 受信の平均速度(bytes/s)
+    avg_in: i32,
+    /// 消費の平均速度(bytes/s)
+    avg_out: i32,
+    /// 溜め終わるまでの見込み(ms)
+    left_ms: i64,
+}
+
+/// 割合を棒グラフにする
+fn bar(percent: i32, width: usize) -> String {
+    let filled = (percent.clamp(0, 100) as usize * width) / 100;
+    format!("{}{}", "#".repeat(filled), ".".repeat(width - filled))
+}
+
+/// バッファの推移の記録
+pub struct BufferingStats {
+    started: Instant,
+    samples: Vec<Sample>,
+    csv: Option<BufWriter<File>>,
+}
+
+impl BufferingStats {
+    /// stats_outがあればCSVの見出しを書いておく
+    pub fn new(stats_out: Option<&Path>) -> anyhow::Result<Self> {
+        let csv = match stats_out {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                let mut csv = BufWriter::new(file);
+                writeln!(
+                    csv,
+                    "elapsed_s,percent,mode,avg_in,avg_out,buffering_left_ms"
+                )?;
+                Some(csv)
+            }
+            None => None,
+        };
+        Ok(Self {
+            started: Instant::now(),
+            samples: Vec::new(),
+            csv,
+        })
+    }
+
+    /// Bufferingクエリを投げて記録し、1行の棒グラフを出す
+    /// 答えるエレメントがなければ何もしない
+    pub fn sample(&mut self, pipeline: &gst::Element) -> anyhow::Result<()> {
+        let mut query = gst::query::Buffering::new(gst::Format::Time);
+        if !pipeline.query(&mut query) {
+            return Ok(());
+        }
+        let (_, percent) = query.percent();
+        let (mode, avg_in, avg_out, left_ms) = query.stats();
+        let sample = Sample {
+            elapsed: self.started.elapsed().as_secs_f64(),
+            percent,
+            mode,
+            avg_in,
+            avg_out,
+            left_ms,
+        };
+        println!(
+            "{:6.1}s [{}] {percent:3}% in {:.1} KB/s out {:.1} KB/s left {left_ms} ms",
+            sample.elapsed,
+            bar(percent, BAR_WIDTH),
+            avg_in as f64 / 1000.0,
+            avg_out as f64 / 1000.0,
+        );
+        if let Some(csv) = &mut self.csv {
+            writeln!(
+                csv,
+                "{:.3},{percent},{mode:?},{avg_in},{avg_out},{left_ms}",
+                sample.elapsed
+            )?;
+        }
+        self.samples.push(sample);
+        Ok(())
+    }
+
+    /// 全体の推移を縦のグラフにする。横軸が時間で、縦軸が溜まった割合
+    pub fn chart(&self) -> String {
+        if self.samples.is_empty() {
+            return String::new();
+        }
+        let step = (self.samples.len() + CHART_WIDTH - 1) / CHART_WIDTH;
+        let columns = self
+            .samples
+            .chunks(step)
+            .map(|chunk| chunk.iter().map(|s| s.percent).min().unwrap_or(0))
+            .collect::<Vec<_>>();
+        let mut chart = String::new();
+        for row in (1..=CHART_HEIGHT).rev() {
+            let threshold = (row * 100 / CHART_HEIGHT) as i32;
+            chart.push_str(&format!("{threshold:3}% |"));
+            for &percent in &columns {
+                chart.push(if percent >= threshold { '#' } else { ' ' });
+            }
+            chart.push('\n');
+        }
+        chart.push_str(&format!("     +{}\n", "-".repeat(columns.len())));
+        let last = self.samples[self.samples.len() - 1].elapsed;
+        chart.push_str(&format!("      0s .. {last:.0}s (min per column)\n"));
+        chart
+    }
+
+    /// CSVを書き切る
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(csv) = &mut self.csv {
+            csv.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+//! LAN内のDLNAレンダラーを探して、ローカルのファイルを再生させる
+//!
+//! SSDPのM-SEARCHをマルチキャストで送り、応答したMediaRendererのデバイス記述を読んで
+//! AVTransportサービスの制御URLを得る。
+//! ファイルはこのプロセスがHTTPで配信し、そのURLをSetAVTransportURIで渡してPlayする。
+//! レンダラーはシークのためにRangeリクエストを送ってくるので、部分的な応答にも対応する。
+//! 配信するMIMEタイプと長さはDiscovererでファイルを調べて決める。
+//! 再生中はGetTransportInfoとGetPositionInfoで状態と位置を出し、停止したら終わる。
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gstreamer_pbutils::{prelude::*, Discoverer};
+
+const SSDP_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+const MEDIA_RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+
+/// キャストの設定
+#[derive(Debug)]
+pub struct CastOptions {
+    pub path: PathBuf,
+    /// friendlyNameに含まれる文字列。Noneなら最初に見つかったレンダラー
+    pub renderer: Option<String>,
+    /// 見つかったレンダラーを出すだけにする
+    pub list: bool,
+    /// HTTPで配信するポート。0なら空いているポート
+    pub port: u16,
+    /// SSDPの応答を待つ時間
+    pub timeout: Duration,
+}
+
+/// 見つかったレンダラー
+#[derive(Debug, Clone)]
+struct Renderer {
+    name: String,
+    location: String,
+    control_url: String,
+}
+
+/// URLをホスト、ポート、パスに分ける。http://だけを扱う
+fn split_url(url: &str) -> anyhow::Result<(String, u16, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .with_context(|| format!("unsupported url {url}"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (authority, 80),
+    };
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// chunkedで送られてきた本文をつなぐ
+fn decode_chunked(body: &str) -> String {
+    let mut out = String::new();
+    let mut rest = body;
+    while let Some((size, tail)) = rest.split_once("\r\n") {
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16);
+        match size {
+            Ok(size) if size > 0 && tail.len() >= size => {
+                out.push_str(&tail[..size]);
+                rest = tail[size..].trim_start_matches("\r\n");
+            }
+            _ => break,
+        }
+    }
+    out
+}
+
+/// 最小限のHTTP/1.1クライアント。ステータスコードと本文を返す
+fn http_request(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> anyhow::Result<(u16, String)> {
+    let (host, port, path) = split_url(url)?;
+    let mut stream = TcpStream::connect((host.as_str(), port))
+        .with_context(|| format!("connect {host}:{port}"))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}:{port}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed http response")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .context("malformed status line")?;
+    let chunked = head.lines().any(|l| {
+        l.to_ascii_lowercase()
+            .starts_with("transfer-encoding: chunked")
+    });
+    let body = if chunked {
+        decode_chunked(body)
+    } else {
+        body.to_string()
+    };
+    Ok((status, body))
+}
+
+/// 名前空間の接頭辞を無視して、最初に見つかった要素の中身を返す
+/// 入れ子になった要素でも、最初の閉じタグまでを返すので葉の要素に使う
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut from = 0;
+    while let Some(i) = xml[from..].find(name) {
+        let start = from + i;
+        let end = start + name.len();
+        from = end;
+        let open = match xml[..start].rfind('<') {
+            Some(open) => open,
+            None => continue,
+        };
+        let tag = &xml[open + 1..end];
+        let is_open_tag = !tag.starts_with('/')
+            && (tag == name || tag.ends_with(&format!(":{name}")))
+            && xml[end..].starts_with(['>', ' ']);
+        if !is_open_tag {
+            continue;
+        }
+        let content = end + xml[end..].find('>')? + 1;
+        let close = xml[content..].find("</")?;
+        return Some(xml[content..content + close].trim());
+    }
+    None
+}
+
+/// XMLの特殊文字をエスケープする
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 相対URLをデバイス記述の場所を基準に解決する
+fn resolve(base: &str, url: &str) -> anyhow::Result<String> {
+    if url.starts_with("http://") {
+        return Ok(url.to_string());
+    }
+    let (host, port, path) = split_url(base)?;
+    let path = if url.starts_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/{url}", &path[..path.rfind('/').unwrap_or(0)])
+    };
+    Ok(format!("http://{host}:{port}{path}"))
+}
+
+/// SSDPでMediaRendererを探し、応答のLOCATIONを集める
+fn search(timeout: Duration) -> anyhow::Result<Vec<String>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+    let mx = timeout.as_secs().max(1);
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: {mx}\r\nST: {MEDIA_RENDERER}\r\n\r\n"
+    );
+    socket.send_to(request.as_bytes(), SSDP_ADDR)?;
+
+    let mut locations = Vec::new();
+    let mut buf = [0u8; 2048];
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            if !locations.contains(&location) {
+                log::debug!("{from}: {location}");
+                locations.push(location);
+            }
+        }
+    }
+    Ok(locations)
+}
+
+/// デバイス記述を読んで、名前とAVTransportの制御URLを得る
+fn describe(location: &str) -> anyhow::Result<Renderer> {
+    let (status, xml) = http_request(location, "GET", &[], "")?;
+    anyhow::ensure!(status == 200, "{location}: HTTP {status}");
+    let name = element(&xml, "friendlyName")
+        .unwrap_or(location)
+        .to_string();
+    let base = element(&xml, "URLBase").unwrap_or(location);
+    let control_url = xml
+        .split("<service>")
+        .skip(1)
+        .find(|service| element(service, "serviceType") == Some(AV_TRANSPORT))
+        .and_then(|service| element(service, "controlURL"))
+        .with_context(|| format!("{name} has no AVTransport service"))?;
+    Ok(Renderer {
+        name,
+        location: location.to_string(),
+        control_url: resolve(base, control_url)?,
+    })
+}
+
+impl Renderer {
+    /// AVTransportのアクションを呼び、応答の本文を返す
+    fn call(&self, action: &str, args: &str) -> anyhow::Result<String> {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+             <u:{action} xmlns:u=\"{AV_TRANSPORT}\"><InstanceID>0</InstanceID>{args}</u:{action}>\
+             </s:Body></s:Envelope>"
+        );
+        let soap_action = format!("\"{AV_TRANSPORT}#{action}\"");
+        let (status, response) = http_request(
+            &self.control_url,
+            "POST",
+            &[
+                ("Content-Type", "text/xml; charset=\"utf-8\""),
+                ("SOAPACTION", &soap_action),
+            ],
+            &body,
+        )?;
+        anyhow::ensure!(
+            status == 200,
+            "{action} failed with HTTP {status}: {}",
+            element(&response, "errorDescription").unwrap_or("")
+        );
+        Ok(response)
+    }
+
+    /// このレンダラーに届く自分のアドレス
+    fn local_ip(&self) -> anyhow::Result<std::net::IpAddr> {
+        let (host, port, _) = split_url(&self.location)?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect((host.as_str(), port))?;
+        Ok(socket.local_addr()?.ip())
+    }
+}
+
+/// 配信するファイルの情報
+#[derive(Debug)]
+struct Media {
+    path: PathBuf,
+    mime: String,
+    duration: Option<gst::ClockTime>,
+    title: String,
+}
+
+impl Media {
+    /// Discovererでコンテナのcapsと長さを調べる
+    fn discover(path: &Path) -> anyhow::Result<Self> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("{}", path.display()))?;
+        let uri = glib::filename_to_uri(&path, None)?;
+        let discoverer = Discoverer::new(5 * gst::ClockTime::SECOND)?;
+        let info = discoverer.discover_uri(&uri)?;
+        let caps_name = info
+            .stream_info()
+            .and_then(|info| info.caps())
+            .and_then(|caps| caps.structure(0).map(|s| s.name().to_string()));
+        let mime = match caps_name.as_deref() {
+            Some("video/quicktime") => "video/mp4".to_string(),
+            Some("application/x-id3") => "audio/mpeg".to_string(),
+            Some("audio/x-m4a") => "audio/mp4".to_string(),
+            Some(name) if name.contains('/') && !name.starts_with("application/x-") => {
+                name.to_string()
+            }
+            _ => "application/octet-stream".to_string(),
+        };
+        let title = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            path,
+            mime,
+            duration: info.duration(),
+            title,
+        })
+    }
+
+    /// CurrentURIMetaDataに渡すDIDL-Lite
+    fn didl(&self, url: &str) -> String {
+        let class = if self.mime.starts_with("audio/") {
+            "object.item.audioItem.musicTrack"
+        } else if self.mime.starts_with("image/") {
+            "object.item.imageItem.photo"
+        } else {
+            "object.item.videoItem"
+        };
+        let duration = self
+            .duration
+            .map(|d| format!(" duration=\"{:.3}\"", d.display()))
+            .unwrap_or_default();
+        format!(
+            "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+             xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+             xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+             <item id=\"0\" parentID=\"-1\" restricted=\"1\">\
+             <dc:title>{}</dc:title><upnp:class>{class}</upnp:class>\
+             <res protocolInfo=\"http-get:*:{}:*\"{duration}>{}</res></item></DIDL-Lite>",
+            escape(&self.title),
+            self.mime,
+            escape(url)
+        )
+    }
+}
+
+/// Rangeヘッダの"bytes=start-end"を解釈する
+fn parse_range(value: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) => (start, end.min(len.checked_sub(1)?)),
+        (Some(start), None) => (start, len.checked_sub(1)?),
+        // "bytes=-N"は末尾のNバイト
+        (None, Some(suffix)) => (len.saturating_sub(suffix), len.checked_sub(1)?),
+        (None, None) => return None,
+    };
+    (start <= end).then_some((start, end))
+}
+
+/// 1つの接続に応答する。GETとHEADだけを扱う
+fn serve(stream: TcpStream, media: &Media) -> anyhow::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+    log::info!("{peer}: {} {range:?}", request_line.trim());
+
+    let mut stream = stream;
+    let method = request_line.split_whitespace().next().unwrap_or("");
+    if method != "GET" && method != "HEAD" {
+        stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let mut file = File::open(&media.path)?;
+    let len = file.metadata()?.len();
+    let (status, start, end) = match range.as_deref().and_then(|r| parse_range(r, len)) {
+        Some((start, end)) => ("206 Partial Content", start, end),
+        None if range.is_some() => {
+            stream.write_all(
+                format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{len}\r\n\r\n"
+                )
+                .as_bytes(),
+            )?;
+            return Ok(());
+        }
+        None => ("200 OK", 0, len.saturating_sub(1)),
+    };
+    let length = if len == 0 { 0 } else { end - start + 1 };
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nContent-Length: {length}\r\n\
+         Accept-Ranges: bytes\r\nConnection: close\r\n\
+         transferMode.dlna.org: Streaming\r\n\
+         contentFeatures.dlna.org: DLNA.ORG_OP=01;DLNA.ORG_FLAGS=01700000000000000000000000000000\r\n",
+        media.mime
+    );
+    if status.starts_with("206") {
+        head.push_str(&format!("Content-Range: bytes {start}-{end}/{len}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    if method == "GET" {
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut file.take(length), &mut stream)?;
+    }
+    Ok(())
+}
+
+/// ファイルを配信するHTTPサーバーを別スレッドで動かす
+fn start_server(media: Arc<Media>, port: u16) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let media = media.clone();
+            thread::spawn(move || {
+                // レンダラーは途中で接続を切ってRangeで読み直すことが多いので、失敗はdebugで出す
+                if let Err(err) = serve(stream, &media) {
+                    log::debug!("http: {err}");
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// レンダラーを探してファイルを再生させ、停止するまで状態を出す
+pub fn run(options: &CastOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    log::info!("searching renderers for {:?}", options.timeout);
+    let renderers = search(options.timeout)?
+        .iter()
+        .filter_map(|location| match describe(location) {
+            Ok(renderer) => Some(renderer),
+            Err(err) => {
+                log::warn!("{location}: {err:#}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if options.list {
+        for renderer in &renderers {
+            println!("{}\t{}", renderer.name, renderer.location);
+        }
+        return Ok(());
+    }
+    let renderer = renderers
+        .into_iter()
+        .find(|r| match &options.renderer {
+            Some(name) => r.name.contains(name.as_str()),
+            None => true,
+        })
+        .context("no matching renderer found")?;
+    log::info!("casting to {} ({})", renderer.name, renderer.control_url);
+
+    let media = Arc::new(Media::discover(&options.path)?);
+    log::info!("{}: {} {:?}", media.title, media.mime, media.duration);
+    let port = start_server(media.clone(), options.port)?.port();
+    let extension = media
+        .path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let url = format!("http://{}:{port}/media{extension}", renderer.local_ip()?);
+    log::info!("serving {url}");
+
+    renderer.call(
+        "SetAVTransportURI",
+        &format!(
+            "<CurrentURI>{}</CurrentURI><CurrentURIMetaData>{}</CurrentURIMetaData>",
+            escape(&url),
+            escape(&media.didl(&url))
+        ),
+    )?;
+    renderer.call("Play", "<Speed>1</Speed>")?;
+
+    // 一度再生が始まったあとにSTOPPEDかNO_MEDIA_PRESENTになったら終わり
+    let mut started = false;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let info = renderer.call("GetTransportInfo", "")?;
+        let state = element(&info, "CurrentTransportState").unwrap_or("UNKNOWN");
+        let position = renderer
+            .call("GetPositionInfo", "")
+            .ok()
+            .and_then(|p| element(&p, "RelTime").map(str::to_string))
+            .unwrap_or_default();
+        println!("{state} {position}");
+        match state {
+            "PLAYING" | "TRANSITIONING" | "PAUSED_PLAYBACK" => started = true,
+            "STOPPED" | "NO_MEDIA_PRESENT" if started => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
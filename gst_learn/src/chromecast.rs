@@ -0,0 +1,436 @@
+//! 入力をChromecastで再生できる形式に変換して配信し、Chromecastに再生させる
+//!
+//! Chromecastの既定のレシーバー(Default Media Receiver)はH.264/AACのMP4を再生できるので、
+//! 入力をtranscodeモジュールと同じ方法でデコードし、fragmented MP4にエンコードしてHTTPで配信する。
+//! 配信のパイプラインはレシーバーが接続してきたときに作り、接続が切れるか、
+//! キャストのセッションが終わったら止める。
+//!
+//! Chromecastの制御(ポート8009のTLSの上のCastMessage)はrust_castに任せる。
+//! 証明書は自己署名なので検証しない。ChromecastからのPINGにPONGを返さないと接続を切られる。
+//! 機器はmDNSで_googlecast._tcp.localを問い合わせて探すか、--deviceでホスト名かアドレスを指定する。
+//! mDNSの問い合わせと応答の解釈はmdns-sdに任せる。
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use rust_cast::{
+    channels::{
+        connection::ConnectionResponse,
+        heartbeat::HeartbeatResponse,
+        media::{Media, MediaResponse, PlayerState, StreamType},
+        receiver::CastDeviceApp,
+    },
+    CastDevice, ChannelMessage,
+};
+
+use crate::{
+    audio_encoder::AudioEncodeOpt,
+    container::{Container, MuxerOptions},
+    deinterlace::DeinterlaceOpt,
+    keyboard::{Key, Keyboard},
+    transcode,
+    video_encoder::VideoEncodeOpt,
+};
+
+/// 探すサービス。mdns-sdには最後の'.'まで書く
+const SERVICE: &str = "_googlecast._tcp.local.";
+const CAST_PORT: u16 = 8009;
+/// 機器そのもの(アプリを起動する前)の宛先
+const RECEIVER_ID: &str = "receiver-0";
+
+/// Chromecastへのキャストの設定
+#[derive(Debug)]
+pub struct ChromecastOptions {
+    /// ファイルパスまたはURI
+    pub input: String,
+    /// 機器のホスト名かアドレス。ポートは省略できる。Noneならmdnsで探す
+    pub device: Option<String>,
+    /// mDNSで見つかった機器から名前に含まれる文字列で選ぶ
+    pub name: Option<String>,
+    /// HTTPで配信するポート。0なら空いているポート
+    pub port: u16,
+    /// mDNSの応答を待つ時間
+    pub timeout: Duration,
+}
+
+/// mDNSで見つかった機器
+#[derive(Debug, Clone)]
+struct Device {
+    name: String,
+    addr: SocketAddr,
+}
+
+/// mDNSでChromecastを探す
+/// 見つかった機器はTXTのfn(機器の名前)とSRVのポートで返す。local_ipがIPv4で繋ぐので、IPv4のアドレスだけを使う
+fn discover(timeout: Duration) -> anyhow::Result<Vec<Device>> {
+    let mdns = ServiceDaemon::new()?;
+    let receiver = mdns.browse(SERVICE)?;
+
+    let mut devices: Vec<Device> = Vec::new();
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let info = match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let ip = match info.get_addresses().iter().find(|ip| ip.is_ipv4()) {
+            Some(ip) => *ip,
+            None => continue,
+        };
+        let addr = SocketAddr::new(ip, info.get_port());
+        if devices.iter().all(|d| d.addr != addr) {
+            let name = info
+                .get_property_val_str("fn")
+                .unwrap_or_else(|| info.get_fullname())
+                .to_string();
+            log::info!("found {name} at {addr}");
+            devices.push(Device { name, addr });
+        }
+    }
+    if let Err(err) = mdns.shutdown() {
+        log::debug!("mdns: {err}");
+    }
+    Ok(devices)
+}
+
+/// 入力をfragmented MP4にしてappsinkから取り出すパイプラインを作る
+fn build_stream(
+    input: &str,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+) -> anyhow::Result<(gst::Pipeline, gstreamer_app::AppSink)> {
+    let pipeline = gst::Pipeline::new(Some("chromecast-stream"));
+    let decode = transcode::add_source(&pipeline, input)?;
+    let container = Container::Mp4;
+    let mux = container.make_muxer(
+        "mux",
+        MuxerOptions {
+            streamable: true,
+            ..Default::default()
+        },
+    )?;
+    let sink = gst::ElementFactory::make("appsink", Some("sink"))?;
+    sink.set_property("sync", false);
+    pipeline.add_many(&[&mux, &sink])?;
+    mux.link(&sink)?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let deinterlace = deinterlace.clone();
+    let video = video.clone();
+    let audio = audio.clone();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(err) = transcode::link_branch(
+            &pipeline,
+            pad,
+            &mux,
+            container,
+            &deinterlace,
+            &video,
+            &audio,
+        ) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+    let sink = sink.downcast::<gstreamer_app::AppSink>().unwrap();
+    Ok((pipeline, sink))
+}
+
+/// 1つの接続に変換したストリームを送る。接続が切れるか、セッションが終わったら止める
+fn stream_to(
+    mut stream: TcpStream,
+    input: &str,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    log::info!("{}: {}", stream.peer_addr()?, request_line.trim());
+    // 長さの分からない生成中のデータなので、Content-Lengthを付けずに接続を閉じて終わりを知らせる
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: video/mp4\r\nConnection: close\r\n\
+          Access-Control-Allow-Origin: *\r\n\r\n",
+    )?;
+    if request_line.starts_with("HEAD") {
+        return Ok(());
+    }
+
+    let (pipeline, sink) = build_stream(input, deinterlace, video, audio)?;
+    pipeline.set_state(gst::State::Playing)?;
+    let result = (|| -> anyhow::Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            // EOSになるとErrが返る
+            let sample = match sink.pull_sample() {
+                Ok(sample) => sample,
+                Err(_) => break,
+            };
+            let buffer = sample.buffer().context("sample without buffer")?;
+            let map = buffer.map_readable()?;
+            stream.write_all(&map)?;
+        }
+        Ok(())
+    })();
+    pipeline.set_state(gst::State::Null)?;
+    log::info!("stream finished: {result:?}");
+    result
+}
+
+/// 変換したストリームを配信するHTTPサーバーを別スレッドで動かす
+fn start_server(
+    input: String,
+    deinterlace: DeinterlaceOpt,
+    video: VideoEncodeOpt,
+    audio: AudioEncodeOpt,
+    port: u16,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let input = input.clone();
+            let deinterlace = deinterlace.clone();
+            let video = video.clone();
+            let audio = audio.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                if let Err(err) = stream_to(stream, &input, &deinterlace, &video, &audio, &stop) {
+                    log::debug!("http: {err:#}");
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+/// 機器に届く自分のアドレス
+fn local_ip(device: SocketAddr) -> anyhow::Result<std::net::IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(device)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// --deviceのアドレスを解決する。ポートを省略したらCAST_PORT
+fn resolve(device: &str) -> anyhow::Result<SocketAddr> {
+    let addrs = match device.to_socket_addrs() {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        // ポートのないホスト名かIPアドレス
+        Err(_) => (device, CAST_PORT)
+            .to_socket_addrs()
+            .with_context(|| format!("resolve {device}"))?
+            .collect(),
+    };
+    addrs
+        .into_iter()
+        .next()
+        .with_context(|| format!("no address for {device}"))
+}
+
+/// 別のスレッドでキーを待ち、qかCtrl-Cが押されたら別の接続からレシーバーのアプリを終了させる
+/// 再生を見ている接続のreceive()は何か届くまで戻らないので、アプリを終了させてその知らせで戻らせる
+/// doneが立つと(再生が終わったとき)キーを待つのをやめる。アプリを終了させたらtrueを返す
+fn watch_keys(
+    addr: SocketAddr,
+    session_id: String,
+    quit: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<bool>> {
+    let keyboard = Keyboard::new()?;
+    Ok(thread::spawn(move || {
+        while !done.load(Ordering::SeqCst) {
+            match keyboard.try_key() {
+                Some(Key::Char('q' | 'Q') | Key::Ctrl('c')) => break,
+                Some(_) => {}
+                None => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        if done.load(Ordering::SeqCst) {
+            return false;
+        }
+        quit.store(true, Ordering::SeqCst);
+        let device =
+            match CastDevice::connect_without_host_verification(addr.ip().to_string(), addr.port())
+            {
+                Ok(device) => device,
+                Err(err) => {
+                    log::warn!("failed to connect {addr}: {err}\r");
+                    return false;
+                }
+            };
+        let stopped = device
+            .connection
+            .connect(RECEIVER_ID)
+            .and_then(|_| device.receiver.stop_app(session_id.as_str()));
+        match stopped {
+            Ok(_) => {
+                log::info!("stopped session {session_id}\r");
+                true
+            }
+            Err(err) => {
+                log::warn!("failed to stop session {session_id}: {err}\r");
+                false
+            }
+        }
+    }))
+}
+
+/// 再生が終わるか、キーでアプリが終了させられるまでChromecastからの知らせを受け取る
+/// media_session_idはメディアの状態が来るたびに更新する。レシーバーが接続を閉じたらtrueを返す
+fn wait_playback(
+    device: &CastDevice,
+    url: &str,
+    media_session_id: &mut Option<i32>,
+    key_quit: &AtomicBool,
+) -> anyhow::Result<bool> {
+    let mut closed = false;
+    let mut quit = false;
+    while !quit {
+        match device.receive()? {
+            ChannelMessage::Heartbeat(HeartbeatResponse::Ping) => device.heartbeat.pong()?,
+            ChannelMessage::Media(MediaResponse::Status(status)) => {
+                for entry in status.entries {
+                    *media_session_id = Some(entry.media_session_id);
+                    log::info!("player {:?}\r", entry.player_state);
+                    if let (PlayerState::Idle, Some(reason)) =
+                        (entry.player_state, entry.idle_reason)
+                    {
+                        log::info!("idle: {reason:?}\r");
+                        *media_session_id = None;
+                        quit = true;
+                    }
+                }
+            }
+            ChannelMessage::Media(
+                MediaResponse::LoadFailed(_) | MediaResponse::LoadCancelled(_),
+            ) => {
+                log::error!("failed to load {url}\r");
+                quit = true;
+            }
+            ChannelMessage::Connection(ConnectionResponse::Close) => {
+                log::info!("receiver closed the connection\r");
+                closed = true;
+                quit = true;
+            }
+            _ => {}
+        }
+        // アプリが終了させられると、その知らせか次のPINGでここに来る
+        if key_quit.load(Ordering::SeqCst) {
+            quit = true;
+        }
+    }
+    Ok(closed)
+}
+
+/// 機器を選んで配信を始め、Default Media Receiverに再生させる
+/// 再生が終わる(IDLEになる)か、qかCtrl-Cが押されると配信のパイプラインも止め、レシーバーのアプリを終了させて戻る
+/// キーは再生の知らせを待つのとは別のスレッドで待つので、押したらすぐに止まる
+pub fn run(
+    options: &ChromecastOptions,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let addr = match &options.device {
+        Some(device) => resolve(device)?,
+        None => {
+            let devices = discover(options.timeout)?;
+            devices
+                .into_iter()
+                .find(|d| match &options.name {
+                    Some(name) => d.name.contains(name.as_str()),
+                    None => true,
+                })
+                .context("no chromecast found")?
+                .addr
+        }
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let port = start_server(
+        options.input.clone(),
+        deinterlace.clone(),
+        video.clone(),
+        audio.clone(),
+        options.port,
+        stop.clone(),
+    )?;
+    let url = format!("http://{}:{port}/stream.mp4", local_ip(addr)?);
+    log::info!("serving {} at {url}", options.input);
+
+    // Chromecastの証明書は自己署名なので検証しない
+    let device = CastDevice::connect_without_host_verification(addr.ip().to_string(), addr.port())
+        .with_context(|| format!("connect {addr}"))?;
+    device.connection.connect(RECEIVER_ID)?;
+    device.heartbeat.ping()?;
+    let app = device
+        .receiver
+        .launch_app(&CastDeviceApp::DefaultMediaReceiver)?;
+    device.connection.connect(app.transport_id.as_str())?;
+    let status = device.media.load(
+        app.transport_id.as_str(),
+        app.session_id.as_str(),
+        &Media {
+            content_id: url.clone(),
+            content_type: "video/mp4".to_string(),
+            stream_type: StreamType::Live,
+            duration: None,
+            metadata: None,
+        },
+    )?;
+    log::info!("loaded {url} into session {}", app.session_id);
+    // アプリのセッション(app)は最後にSTOPを送るまで持っておく。メディアのセッションは状態が来るたびに更新する
+    let mut media_session_id = status.entries.first().map(|e| e.media_session_id);
+
+    println!("press 'q' or Ctrl-C to stop casting");
+    let key_quit = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = watch_keys(addr, app.session_id.clone(), key_quit.clone(), done.clone())?;
+    let result = wait_playback(&device, &url, &mut media_session_id, &key_quit);
+
+    // キーを待つのをやめ、端末を元に戻す
+    done.store(true, Ordering::SeqCst);
+    let stopped = watcher.join().unwrap_or(false);
+
+    // 配信を止め、レシーバーのアプリもまだなら終了させる
+    stop.store(true, Ordering::SeqCst);
+    let closed = result?;
+    if !closed && !stopped {
+        if let Some(media_session_id) = media_session_id {
+            if let Err(err) = device
+                .media
+                .stop(app.transport_id.as_str(), media_session_id)
+            {
+                log::warn!("failed to stop the media: {err}\r");
+            }
+        }
+        device.receiver.stop_app(app.session_id.as_str())?;
+        log::info!("stopped session {}\r", app.session_id);
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! 実時間とは違う速さで進むクロック
+//!
+//! GstClockのサブクラスはinternal_timeで現在時刻を返し、waitでその時刻まで待つ処理を実装する。
+//! ScaledClockは実時間の経過にrateを掛けた時刻を返すので、1.0より大きければ早送り、
+//! 1.0に近い値にすると音声デバイスのクロックとのずれ(ドリフト)を人工的に作れる。
+//! 待つときも時刻の差をrateで割った実時間だけ眠るので、sync=trueのsinkもrate倍の速さで描画する。
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use gst::{prelude::*, subclass::prelude::*};
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 早送りの例で流すフレームレート
+const FPS: i32 = 30;
+
+mod imp {
+    use super::*;
+
+    /// internal_timeの基準
+    #[derive(Debug)]
+    pub(super) struct Base {
+        pub rate: f64,
+        pub real: Instant,
+        pub time: gst::ClockTime,
+    }
+
+    impl Base {
+        pub fn time_at(&self, now: Instant) -> gst::ClockTime {
+            let elapsed = now.duration_since(self.real).as_nanos() as f64 * self.rate;
+            self.time + gst::ClockTime::from_nseconds(elapsed as u64)
+        }
+    }
+
+    pub struct ScaledClock {
+        pub(super) base: Mutex<Base>,
+        /// unscheduleされたときに待っているスレッドを起こす
+        wakeup: Mutex<()>,
+        cond: Condvar,
+    }
+
+    impl Default for ScaledClock {
+        fn default() -> Self {
+            Self {
+                base: Mutex::new(Base {
+                    rate: 1.0,
+                    real: Instant::now(),
+                    time: gst::ClockTime::ZERO,
+                }),
+                wakeup: Mutex::new(()),
+                cond: Condvar::new(),
+            }
+        }
+    }
+
+    impl ScaledClock {
+        /// idの時刻まで待つ。unscheduleされたらUnscheduledを返す
+        /// 戻り値の2つ目はjitter(正なら遅れて起きた)
+        pub(super) fn wait_until(
+            &self,
+            clock: &super::ScaledClock,
+            id: &gst::ClockId,
+        ) -> (
+            Result<gst::ClockSuccess, gst::ClockError>,
+            gst::ClockTimeDiff,
+        ) {
+            let target = id.time().nseconds() as i64;
+            let mut guard = self.wakeup.lock().unwrap();
+            let mut waited = false;
+            loop {
+                // unscheduleされたIDは呼び出し元がstatusをUnscheduledにしている
+                if id.status().load() == gst::ClockReturn::Unscheduled {
+                    return (Err(gst::ClockError::Unscheduled), 0);
+                }
+                let now = clock.time().map_or(0, |t| t.nseconds() as i64);
+                let diff = target - now;
+                if diff <= 0 {
+                    // 待つ前から過ぎていた場合はEarlyを返す
+                    let res = if waited {
+                        Ok(gst::ClockSuccess::Ok)
+                    } else {
+                        Err(gst::ClockError::Early)
+                    };
+                    return (res, -diff);
+                }
+
+                let rate = self.base.lock().unwrap().rate;
+                let real = Duration::from_nanos((diff as f64 / rate) as u64);
+                guard = self.cond.wait_timeout(guard, real).unwrap().0;
+                waited = true;
+            }
+        }
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for ScaledClock {
+        const NAME: &'static str = "GstLearnScaledClock";
+        type Type = super::ScaledClock;
+        type ParentType = gst::Clock;
+    }
+
+    impl ObjectImpl for ScaledClock {}
+    impl GstObjectImpl for ScaledClock {}
+
+    impl ClockImpl for ScaledClock {
+        fn internal_time(&self, _clock: &Self::Type) -> gst::ClockTime {
+            self.base.lock().unwrap().time_at(Instant::now())
+        }
+
+        fn wait(
+            &self,
+            clock: &Self::Type,
+            id: &gst::ClockId,
+        ) -> (
+            Result<gst::ClockSuccess, gst::ClockError>,
+            gst::ClockTimeDiff,
+        ) {
+            self.wait_until(clock, id)
+        }
+
+        /// 非同期の待ちは専用のスレッドで待ってからコールバックを呼ぶ
+        /// 周期的なIDはwake_idが次の時刻に進めるので、unscheduleされるまで繰り返す
+        fn wait_async(
+            &self,
+            clock: &Self::Type,
+            id: &gst::ClockId,
+        ) -> Result<gst::ClockSuccess, gst::ClockError> {
+            let clock = clock.clone();
+            let id = id.clone();
+            std::thread::spawn(move || loop {
+                let imp = clock.imp();
+                match imp.wait_until(&clock, &id).0 {
+                    Ok(_) | Err(gst::ClockError::Early) => imp.wake_id(&id),
+                    Err(_) => break,
+                }
+                if id.type_() != gst::ClockEntryType::Periodic {
+                    break;
+                }
+            });
+            Ok(gst::ClockSuccess::Ok)
+        }
+
+        fn unschedule(&self, _clock: &Self::Type, _id: &gst::ClockId) {
+            let _guard = self.wakeup.lock().unwrap();
+            self.cond.notify_all();
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct ScaledClock(ObjectSubclass<imp::ScaledClock>) @extends gst::Clock, gst::Object;
+}
+
+// GstClockはスレッドから使うことを前提にしていて、内部の状態もMutexで守っている
+unsafe impl Send for ScaledClock {}
+unsafe impl Sync for ScaledClock {}
+
+impl ScaledClock {
+    pub fn new(rate: f64) -> Self {
+        let clock = glib::Object::new::<Self>(&[]).expect("Failed to create ScaledClock");
+        clock.set_rate(rate);
+        clock
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.imp().base.lock().unwrap().rate
+    }
+
+    /// 速さを変える。時刻が飛ばないように、今の時刻を新しい基準にする
+    pub fn set_rate(&self, rate: f64) {
+        assert!(rate > 0.0, "rate must be positive");
+        let mut base = self.imp().base.lock().unwrap();
+        let now = Instant::now();
+        base.time = base.time_at(now);
+        base.real = now;
+        base.rate = rate;
+    }
+}
+
+/// rate倍の速さのクロックでframesだけ描画し、ストリームの長さと実際にかかった時間を比べる
+/// sinkはsync=trueのままなので、フレームは捨てられずに全部描画される
+pub fn run(rate: f64, frames: u32, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    anyhow::ensure!(rate > 0.0, "rate must be positive");
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "videotestsrc pattern=ball num-buffers={frames} \
+         ! video/x-raw,framerate={FPS}/1 ! timeoverlay ! videoconvert \
+         ! autovideosink sync=true"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let clock = ScaledClock::new(rate);
+    pipeline.use_clock(Some(&clock));
+
+    let started = Instant::now();
+    runner::run(&pipeline, bus_loop)?;
+    let elapsed = started.elapsed();
+
+    let stream = Duration::from_secs_f64(frames as f64 / FPS as f64);
+    log::info!(
+        "rendered {frames} frames ({:.2}s of stream) in {:.2}s at rate {rate}",
+        stream.as_secs_f64(),
+        elapsed.as_secs_f64()
+    );
+    Ok(())
+}
@@ -0,0 +1,360 @@
+//! TOMLに書いたエレメントとリンクからパイプラインを組み立てる
+//!
+//! parse_launchの1行では読みにくくなる大きなパイプラインを、エレメントごとに分けて書けるようにする。
+//! 書き換えても再コンパイルせずに試せる。
+//!
+//! ```toml
+//! [[element]]
+//! factory = "videotestsrc"
+//! name = "src"
+//! properties = { pattern = "ball", num-buffers = 300 }
+//!
+//! [[element]]
+//! factory = "videoconvert"
+//! name = "convert"
+//!
+//! [[element]]
+//! factory = "autovideosink"
+//! name = "sink"
+//!
+//! [[link]]
+//! from = "src"
+//! to = "convert"
+//! caps = "video/x-raw,width=640,height=480"
+//!
+//! [[link]]
+//! from = "convert.src"
+//! to = "sink.sink"
+//! ```
+//!
+//! propertiesの値は文字列にしてからプロパティの型に変換するので、enumはnick("ball")でも書ける。
+//! linkのfromとtoは`エレメント名`か`エレメント名.pad名`で、request padは名前(`mux.video_%u`など)で頼む。
+//! decodebinのsometimes padのようにまだないpadからのリンクは、padが出てきたときに繋ぐ。
+//! そのときcapsは、どのpadを繋ぐかを選ぶのに使う。
+//!
+//! 間違いは何番目のelementかlinkかを添えて返す。
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    launch,
+    runner::{self, BusLoopOpt},
+};
+
+/// エレメント1つの設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElementConfig {
+    pub factory: String,
+    pub name: Option<String>,
+    /// プロパティの名前と、文字列にした値
+    pub properties: Vec<(String, String)>,
+}
+
+impl ElementConfig {
+    fn from_toml(value: &toml::Value) -> anyhow::Result<Self> {
+        let table = value.as_table().context("element must be a table")?;
+        let factory = table
+            .get("factory")
+            .and_then(|v| v.as_str())
+            .context("element needs a factory")?
+            .to_string();
+        let name = match table.get("name") {
+            Some(v) => Some(v.as_str().context("name must be a string")?.to_string()),
+            None => None,
+        };
+        let properties = match table.get("properties") {
+            Some(toml::Value::Table(properties)) => properties
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        toml::Value::String(s) => s.clone(),
+                        toml::Value::Integer(v) => v.to_string(),
+                        toml::Value::Float(v) => v.to_string(),
+                        toml::Value::Boolean(v) => v.to_string(),
+                        _ => anyhow::bail!("property {key} must be a string, number or boolean"),
+                    };
+                    Ok((key.clone(), value))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            Some(_) => anyhow::bail!("properties must be a table"),
+            None => Vec::new(),
+        };
+        Ok(Self {
+            factory,
+            name,
+            properties,
+        })
+    }
+
+    /// エレメントを作ってプロパティを設定する
+    fn make(&self) -> anyhow::Result<gst::Element> {
+        anyhow::ensure!(
+            gst::ElementFactory::find(&self.factory).is_some(),
+            "no such element factory {:?}",
+            self.factory
+        );
+        let element = gst::ElementFactory::make(&self.factory, self.name.as_deref())?;
+        for (key, value) in &self.properties {
+            element
+                .try_set_property_from_str(key, value)
+                .with_context(|| format!("property {key}={value:?}"))?;
+        }
+        Ok(element)
+    }
+}
+
+/// リンクの端。padを省略するとgst_element_link_padsに選ばせる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub element: String,
+    pub pad: Option<String>,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (element, pad) = match s.split_once('.') {
+            Some((element, pad)) => (element, Some(pad.to_string())),
+            None => (s, None),
+        };
+        anyhow::ensure!(!element.is_empty(), "{s:?} has no element name");
+        Ok(Self {
+            element: element.to_string(),
+            pad: pad.filter(|pad| !pad.is_empty()),
+        })
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.pad {
+            Some(pad) => write!(f, "{}.{pad}", self.element),
+            None => write!(f, "{}", self.element),
+        }
+    }
+}
+
+/// リンク1つの設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkConfig {
+    pub from: Endpoint,
+    pub to: Endpoint,
+    pub caps: Option<String>,
+}
+
+impl LinkConfig {
+    fn from_toml(value: &toml::Value) -> anyhow::Result<Self> {
+        let table = value.as_table().context("link must be a table")?;
+        let endpoint = |key: &str| -> anyhow::Result<Endpoint> {
+            table
+                .get(key)
+                .and_then(|v| v.as_str())
+                .with_context(|| format!("link needs {key}"))?
+                .parse()
+        };
+        let caps = match table.get("caps") {
+            Some(v) => Some(v.as_str().context("caps must be a string")?.to_string()),
+            None => None,
+        };
+        Ok(Self {
+            from: endpoint("from")?,
+            to: endpoint("to")?,
+            caps,
+        })
+    }
+}
+
+/// TOMLから読んだパイプラインの設定
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PipelineConfig {
+    pub elements: Vec<ElementConfig>,
+    pub links: Vec<LinkConfig>,
+}
+
+/// `[[key]]`の配列を読む
+fn entries<T>(
+    table: &toml::value::Table,
+    key: &str,
+    parse: impl Fn(&toml::Value) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    match table.get(key) {
+        Some(toml::Value::Array(values)) => values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| parse(value).with_context(|| format!("{key} {i}")))
+            .collect(),
+        Some(_) => anyhow::bail!("{key} must be an array of tables ([[{key}]])"),
+        None => Ok(Vec::new()),
+    }
+}
+
+impl PipelineConfig {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        let value = s.parse::<toml::Value>()?;
+        let table = value.as_table().context("config must be a table")?;
+        let config = Self {
+            elements: entries(table, "element", ElementConfig::from_toml)?,
+            links: entries(table, "link", LinkConfig::from_toml)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("parse {}", path.display()))
+    }
+
+    /// GStreamerに問い合わせなくても分かる間違いを調べる
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.elements.is_empty(), "no elements");
+        let mut names = HashSet::new();
+        for (i, element) in self.elements.iter().enumerate() {
+            if let Some(name) = &element.name {
+                anyhow::ensure!(
+                    names.insert(name.as_str()),
+                    "element {i}: duplicate name {name:?}"
+                );
+            }
+        }
+        for (i, link) in self.links.iter().enumerate() {
+            for endpoint in [&link.from, &link.to] {
+                anyhow::ensure!(
+                    names.contains(endpoint.element.as_str()),
+                    "link {i}: no element named {:?}",
+                    endpoint.element
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// エレメントを作って繋いだパイプライン
+    pub fn build(&self) -> anyhow::Result<gst::Pipeline> {
+        let pipeline = gst::Pipeline::new(None);
+        for (i, config) in self.elements.iter().enumerate() {
+            let element = config.make().with_context(|| {
+                format!(
+                    "element {i} ({})",
+                    config.name.as_deref().unwrap_or(&config.factory)
+                )
+            })?;
+            pipeline.add(&element)?;
+        }
+        for (i, config) in self.links.iter().enumerate() {
+            link(&pipeline, config)
+                .with_context(|| format!("link {i} ({} -> {})", config.from, config.to))?;
+        }
+        Ok(pipeline)
+    }
+}
+
+/// 出てくるかもしれないsrc pad(sometimes pad)があるならtrue
+fn has_sometimes_src(element: &gst::Element) -> bool {
+    element.pad_template_list().iter().any(|template| {
+        template.direction() == gst::PadDirection::Src
+            && template.presence() == gst::PadPresence::Sometimes
+    })
+}
+
+/// 設定の通りに繋ぐ。まだないpadからのリンクはpad-addedで繋ぐ
+fn link(pipeline: &gst::Pipeline, config: &LinkConfig) -> anyhow::Result<()> {
+    let src = pipeline
+        .by_name(&config.from.element)
+        .context("no source element")?;
+    let sink = pipeline
+        .by_name(&config.to.element)
+        .context("no sink element")?;
+    let caps = config
+        .caps
+        .as_deref()
+        .map(|caps| caps.parse::<gst::Caps>().context("invalid caps"))
+        .transpose()?;
+    let src_pad = config.from.pad.as_deref();
+    let sink_pad = config.to.pad.as_deref();
+
+    let linked = match &caps {
+        Some(caps) => src.link_pads_filtered(src_pad, &sink, sink_pad, caps),
+        None => src.link_pads(src_pad, &sink, sink_pad),
+    };
+    match linked {
+        Ok(()) => Ok(()),
+        Err(_) if has_sometimes_src(&src) => {
+            link_later(&src, &sink, config, caps);
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// src padが出てきたら繋ぐ。capsがあれば、capsが交わるpadだけを繋ぐ
+fn link_later(
+    src: &gst::Element,
+    sink: &gst::Element,
+    config: &LinkConfig,
+    caps: Option<gst::Caps>,
+) {
+    let sink_weak = sink.downgrade();
+    let src_pad_name = config.from.pad.clone();
+    let sink_pad_name = config.to.pad.clone();
+    src.connect_pad_added(move |_, pad| {
+        let sink = match sink_weak.upgrade() {
+            Some(sink) => sink,
+            None => return,
+        };
+        if pad.direction() != gst::PadDirection::Src || pad.is_linked() {
+            return;
+        }
+        if let Some(name) = &src_pad_name {
+            if pad.name().as_str() != name {
+                return;
+            }
+        }
+        if let Some(caps) = &caps {
+            if !pad.query_caps(None).can_intersect(caps) {
+                return;
+            }
+        }
+        let sink_pad = match sink_pad_name.as_deref() {
+            Some(name) => sink
+                .static_pad(name)
+                .or_else(|| sink.request_pad_simple(name)),
+            None => sink.compatible_pad(pad, None),
+        };
+        let sink_pad = match sink_pad.filter(|sink_pad| !sink_pad.is_linked()) {
+            Some(sink_pad) => sink_pad,
+            None => return,
+        };
+        match pad.link(&sink_pad) {
+            Ok(_) => log::info!("linked {} to {}", pad.name(), sink_pad.path_string()),
+            Err(err) => log::error!("failed to link {}: {err:?}", pad.name()),
+        }
+    });
+}
+
+/// 設定ファイルから作ったパイプラインの実行の設定
+#[derive(Debug)]
+pub struct ConfigOptions {
+    pub config: PathBuf,
+    /// 組み立てるだけで再生せず、gst-launch-1.0の記述を出す
+    pub check: bool,
+}
+
+/// 設定ファイルからパイプラインを組み立てて、EOSかエラーまで再生する
+pub fn run(options: &ConfigOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = PipelineConfig::load(&options.config)?.build()?;
+    if options.check {
+        println!("gst-launch-1.0 {}", launch::describe(pipeline.upcast_ref()));
+        return Ok(());
+    }
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,162 @@
+//! 記録・トランスコードで使うコンテナ(muxer)の選択
+//!
+//! コンテナによって使えるコーデックやmuxerの設定が違うので、ここにまとめておく。
+//! MP4は通常moov(インデックス)をファイル末尾に書くため、EOSで正しく閉じないと再生できず、
+//! 先頭から順に読むストリーミング再生にも向かない。faststartを有効にすると閉じる時にmoovを先頭へ移動する。
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{audio_encoder::AudioCodec, video_encoder::VideoCodec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+}
+
+impl FromStr for Container {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mp4" => Ok(Self::Mp4),
+            "mkv" | "matroska" => Ok(Self::Mkv),
+            "webm" => Ok(Self::Webm),
+            _ => anyhow::bail!("unknown container {s}, expected mp4|mkv|webm"),
+        }
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// muxerの追加設定
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MuxerOptions {
+    /// MP4のmoovを先頭に置いてプログレッシブ再生できるようにする
+    /// 閉じる時にファイル全体を書き直すので、非常に長い記録には向かない
+    pub faststart: bool,
+    /// MP4のmoovを先頭に予約領域として確保し、定期的に更新する
+    /// 途中でプロセスが落ちても予約した長さまでは再生できるファイルが残る
+    pub reserved_moov: Option<gst::ClockTime>,
+    /// パイプなどシークできない出力に書く
+    /// MP4はmoovを後から書き戻せないのでfragmented MP4にし、matroskaはcuesなどを書かない
+    pub streamable: bool,
+    /// matroskaのclusterの最大の長さ。短くすると途中で落ちたときに失うのが最後のclusterだけで済む
+    pub cluster_duration: Option<gst::ClockTime>,
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Webm => "webm",
+        }
+    }
+
+    pub fn muxer_factory(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4mux",
+            Self::Mkv => "matroskamux",
+            Self::Webm => "webmmux",
+        }
+    }
+
+    /// コンテナに格納できる映像コーデック。エンコーダがある最初のものが既定
+    /// WebMはVP8/VP9/AV1しか格納できない
+    pub fn video_codecs(&self) -> &'static [VideoCodec] {
+        match self {
+            Self::Mp4 => &[
+                VideoCodec::X264,
+                VideoCodec::Nvenc,
+                VideoCodec::Av1,
+                VideoCodec::Vp9,
+            ],
+            Self::Mkv => &[
+                VideoCodec::X264,
+                VideoCodec::Nvenc,
+                VideoCodec::Vp8,
+                VideoCodec::Vp9,
+                VideoCodec::Av1,
+            ],
+            Self::Webm => &[VideoCodec::Vp8, VideoCodec::Vp9, VideoCodec::Av1],
+        }
+    }
+
+    /// コンテナに格納できる音声コーデック。先頭が既定
+    /// WebMはOpusとVorbisしか格納できない
+    pub fn audio_codecs(&self) -> &'static [AudioCodec] {
+        match self {
+            Self::Mp4 => &[AudioCodec::Aac, AudioCodec::Opus],
+            Self::Mkv => &[
+                AudioCodec::Opus,
+                AudioCodec::Aac,
+                AudioCodec::Vorbis,
+                AudioCodec::Flac,
+            ],
+            Self::Webm => &[AudioCodec::Opus, AudioCodec::Vorbis],
+        }
+    }
+
+    /// muxerを作って設定する
+    pub fn make_muxer(&self, name: &str, options: MuxerOptions) -> anyhow::Result<gst::Element> {
+        let muxer = gst::ElementFactory::make(self.muxer_factory(), Some(name))
+            .with_context(|| format!("failed to create {}", self.muxer_factory()))?;
+
+        match self {
+            Self::Mp4 => {
+                if options.streamable {
+                    // fragment-duration(ms)ごとにmoofを書いて、先頭から順に読めるようにする
+                    muxer.set_property("fragment-duration", 1000u32);
+                } else if let Some(duration) = options.reserved_moov {
+                    muxer.set_property("reserved-max-duration", duration.nseconds());
+                    muxer.set_property(
+                        "reserved-moov-update-period",
+                        gst::ClockTime::SECOND.nseconds(),
+                    );
+                } else if options.faststart {
+                    muxer.set_property("faststart", true);
+                }
+            }
+            // matroskaは先頭にseek headを置くだけで追記型のためそのまま再生できる
+            // streamableにすると書いた位置に戻らなくなるので、パイプにも出力できる
+            Self::Mkv | Self::Webm => {
+                if options.streamable {
+                    muxer.set_property("streamable", true);
+                }
+                if let Some(duration) = options.cluster_duration {
+                    muxer.set_property("max-cluster-duration", duration.nseconds() as i64);
+                }
+            }
+        }
+
+        Ok(muxer)
+    }
+}
+
+/// EOS後にファイルが正しく閉じられたかをDiscovererで読み直して確認する
+/// moovが書かれていないMP4などはここでエラーになる
+pub fn verify(path: &Path) -> anyhow::Result<gst::ClockTime> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("{} was not written", path.display()))?;
+    let uri = glib::filename_to_uri(&path, None)?;
+
+    let discoverer = gstreamer_pbutils::Discoverer::new(5 * gst::ClockTime::SECOND)?;
+    let info = discoverer
+        .discover_uri(&uri)
+        .with_context(|| format!("{} is not readable", path.display()))?;
+    let duration = info
+        .duration()
+        .with_context(|| format!("{} has no duration", path.display()))?;
+
+    log::info!("verified {} ({duration})", path.display());
+    Ok(duration)
+}
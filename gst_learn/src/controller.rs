@@ -0,0 +1,121 @@
+//! GstControllerでエレメントのプロパティを時間軸に沿って自動で動かす例
+//!
+//! プロパティにControlBindingを付けておくと、エレメントがバッファを処理する際に
+//! running-timeに対応する値をControlSourceから取り出して自動的に設定してくれる。
+//! アプリケーション側でタイマーを回してset_propertyする必要がなく、フレーム単位で正確に同期する。
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_controller::{
+    prelude::*, DirectControlBinding, InterpolationControlSource, InterpolationMode,
+};
+
+use crate::fallback;
+
+const DURATION: gst::ClockTime = gst::ClockTime::from_seconds(10);
+
+/// 制御点の列を持つInterpolationControlSourceを作る
+/// 制御点の間は線形補間される
+fn interpolation(points: &[(gst::ClockTime, f64)]) -> InterpolationControlSource {
+    let source = InterpolationControlSource::new();
+    source.set_mode(InterpolationMode::Linear);
+    for (timestamp, value) in points {
+        source.set(*timestamp, *value);
+    }
+    source
+}
+
+/// volumeエレメントの音量をフェードイン/アウトさせる
+fn volume_pipeline() -> anyhow::Result<gst::Pipeline> {
+    let pipeline = fallback::parse_launch(
+        "audiotestsrc wave=sine freq=440 ! volume name=volume ! audioconvert ! autoaudiosink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let volume = pipeline.by_name("volume").context("volume")?;
+
+    let source = interpolation(&[
+        (gst::ClockTime::ZERO, 0.0),
+        (2 * gst::ClockTime::SECOND, 1.0),
+        (6 * gst::ClockTime::SECOND, 1.0),
+        (8 * gst::ClockTime::SECOND, 0.0),
+    ]);
+    // absoluteを使うと制御値がそのままプロパティ値になる
+    // 通常のnewだと0.0-1.0がプロパティの範囲全体(volumeなら0-10)に正規化される
+    let binding = DirectControlBinding::new_absolute(&volume, "volume", &source);
+    volume.add_control_binding(&binding)?;
+
+    Ok(pipeline)
+}
+
+/// rsroiのマスク位置を左右に往復させる
+/// GST_PLUGIN_PATHにgst-plugin-tutorialのビルド結果を含めておく必要がある
+fn roi_pipeline() -> anyhow::Result<gst::Pipeline> {
+    let pipeline = fallback::parse_launch(
+        "videotestsrc pattern=ball ! videoconvert ! rsroi name=roi y=80 width=80 height=80 mode=pixelate ! videoconvert ! autovideosink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let roi = pipeline.by_name("roi").context("roi")?;
+
+    let source = interpolation(&[
+        (gst::ClockTime::ZERO, 0.0),
+        (5 * gst::ClockTime::SECOND, 240.0),
+        (10 * gst::ClockTime::SECOND, 0.0),
+    ]);
+    let binding = DirectControlBinding::new_absolute(&roi, "x", &source);
+    roi.add_control_binding(&binding)?;
+
+    Ok(pipeline)
+}
+
+/// 10秒間プロパティを自動制御しながら再生する
+pub fn run(roi: bool) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = if roi {
+        roi_pipeline()?
+    } else {
+        volume_pipeline()?
+    };
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("bus")?;
+    loop {
+        match bus.timed_pop(500 * gst::ClockTime::MSECOND) {
+            Some(msg) => {
+                use gst::MessageView;
+
+                match msg.view() {
+                    MessageView::Eos(_) => break,
+                    MessageView::Error(err) => {
+                        log::error!(
+                            "Error from {:?}: {} ({:?})",
+                            err.src().map(|s| s.path_string()),
+                            err.error(),
+                            err.debug()
+                        );
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            None => {
+                let position = match pipeline.query_position::<gst::ClockTime>() {
+                    Some(position) => position,
+                    None => continue,
+                };
+                log::info!("Position {position}");
+                if position >= DURATION {
+                    break;
+                }
+            }
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! タグに埋め込まれたカバーアートなどの画像を取り出す
+//!
+//! imageやpreview-imageのタグは文字列ではなくGstSampleで、バッファにJPEGやPNGのデータ、
+//! capsにその形式と、表紙か裏表紙かなどを表すimage-typeが入っている。
+//! 形式はファイルによってまちまちなので、小さなパイプラインでデコードしてPNGに揃えて書き出す。
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSrc;
+
+use crate::fallback;
+
+/// 画像のタグの種類と中身
+pub struct ImageTag {
+    pub tag: &'static str,
+    pub sample: gst::Sample,
+}
+
+/// タグリストから画像のタグを全て取り出す
+pub fn image_tags(tags: &gst::TagListRef) -> Vec<ImageTag> {
+    let images = tags.iter_tag::<gst::tags::Image>().map(|v| ImageTag {
+        tag: "image",
+        sample: v.get().clone(),
+    });
+    let previews = tags
+        .iter_tag::<gst::tags::PreviewImage>()
+        .map(|v| ImageTag {
+            tag: "preview-image",
+            sample: v.get().clone(),
+        });
+    images.chain(previews).collect()
+}
+
+/// 画像のサンプルを1行で説明する。タグの値をそのまま文字列にするとデータ全体が出てしまうため
+pub fn describe(sample: &gst::SampleRef) -> String {
+    let (format, image_type) = sample
+        .caps()
+        .and_then(|caps| caps.structure(0))
+        .map(|s| {
+            let image_type = s
+                .value("image-type")
+                .ok()
+                .and_then(|v| v.serialize().ok())
+                .map(|v| v.to_string());
+            (s.name().to_string(), image_type)
+        })
+        .unwrap_or_else(|| ("unknown".to_string(), None));
+    let size = sample.buffer().map_or(0, |b| b.size());
+    match image_type {
+        Some(image_type) => format!("{format} ({image_type}), {size} bytes"),
+        None => format!("{format}, {size} bytes"),
+    }
+}
+
+/// 画像のサンプルをデコードしてPNGで書き出す
+pub fn export_png(sample: &gst::SampleRef, path: &Path) -> anyhow::Result<()> {
+    let buffer = sample.buffer_owned().context("image tag without data")?;
+    let caps = sample.caps_owned().context("image tag without caps")?;
+
+    let pipeline = fallback::parse_launch(
+        "appsrc name=src ! decodebin ! videoconvert ! pngenc snapshot=true ! filesink name=sink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let appsrc = pipeline
+        .by_name("src")
+        .context("src")?
+        .dynamic_cast::<AppSrc>()
+        .unwrap();
+    appsrc.set_caps(Some(&caps));
+    pipeline
+        .by_name("sink")
+        .context("sink")?
+        .set_property("location", path.to_str().context("non UTF-8 path")?);
+
+    pipeline.set_state(gst::State::Playing)?;
+    appsrc.push_buffer(buffer)?;
+    appsrc.end_of_stream()?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let res = bus
+        .iter_timed(gst::ClockTime::from_seconds(10))
+        .find_map(|msg| match msg.view() {
+            gst::MessageView::Eos(_) => Some(Ok(())),
+            gst::MessageView::Error(err) => Some(Err(anyhow::anyhow!(
+                "failed to convert image: {} ({:?})",
+                err.error(),
+                err.debug()
+            ))),
+            _ => None,
+        })
+        .unwrap_or_else(|| Err(anyhow::anyhow!("timed out converting image")));
+    pipeline.set_state(gst::State::Null)?;
+    res
+}
+
+/// タグリストの画像を全てdirに<prefix>-<tag>-<n>.pngとして書き出す
+pub fn export_all(
+    tags: &gst::TagListRef,
+    dir: &Path,
+    prefix: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    image_tags(tags)
+        .iter()
+        .enumerate()
+        .map(|(i, image)| {
+            let path = dir.join(format!("{prefix}-{}-{i}.png", image.tag));
+            export_png(&image.sample, &path)?;
+            log::info!(
+                "{}: {} -> {}",
+                image.tag,
+                describe(&image.sample),
+                path.display()
+            );
+            Ok(path)
+        })
+        .collect()
+}
@@ -0,0 +1,247 @@
+//! teeで分けたバッファが共有され、書き込むときにだけコピーされる(copy-on-write)ことを確かめる例
+//!
+//! teeは受け取ったバッファを複製せず、参照カウントを増やして全てのsrc padに流す。
+//! 参照が複数あるバッファはwritableではないので、書き換える前にmake_mut(gst_buffer_make_writable)が要る。
+//! make_mutはバッファの構造(タイムスタンプやメモリの一覧)だけをコピーし、中身のメモリは共有したままにする。
+//! メモリも共有されているので、map_writableしたときに初めて中身がコピーされる。map_readableではコピーしない。
+//!
+//! teeの前と後ろの2つのブランチでprobeを付け、バッファとメモリのアドレスを比べてコピーされたかを数える。
+//! - readerブランチはmap_readableで読むだけなので、teeの前と同じバッファとメモリのまま届く
+//! - writerブランチは--writeに従って、meta(make_mutしてoffsetを書く)かdata(さらに中身を書く)をする
+//!
+//! 片方のブランチが先にバッファを手放していれば参照は1つになっているので、make_mutでもコピーされない。
+//! どちらになるかはスレッドのタイミング次第なので、数で見る。
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// writerブランチでの書き込み方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Write {
+    /// 読むだけ
+    None,
+    /// make_mutしてバッファのoffsetだけを書く
+    Meta,
+    /// make_mutしてからmap_writableで中身も書く
+    Data,
+}
+
+impl FromStr for Write {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "meta" => Ok(Self::Meta),
+            "data" => Ok(Self::Data),
+            _ => anyhow::bail!("unknown write {s}, expected none|meta|data"),
+        }
+    }
+}
+
+impl fmt::Display for Write {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Meta => "meta",
+            Self::Data => "data",
+        })
+    }
+}
+
+/// バッファとその中身のアドレス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Address {
+    buffer: usize,
+    data: usize,
+}
+
+impl Address {
+    /// 読むだけのmapで中身のアドレスを調べる
+    fn of(buffer: &gst::BufferRef) -> Option<Self> {
+        let map = buffer.map_readable().ok()?;
+        Some(Self {
+            buffer: buffer as *const gst::BufferRef as usize,
+            data: map.as_slice().as_ptr() as usize,
+        })
+    }
+}
+
+/// ブランチごとの数
+#[derive(Debug, Default)]
+struct BranchStats {
+    frames: u64,
+    /// teeの前と同じバッファのまま届いた
+    same_buffer: u64,
+    /// teeの前と同じメモリのまま届いた
+    same_data: u64,
+    /// 届いた時点でwritableだった(参照が1つだけだった)
+    writable: u64,
+    /// make_mutでバッファがコピーされた
+    buffer_copies: u64,
+    /// map_writableで中身がコピーされた
+    data_copies: u64,
+    /// 2回目のmap_writableで中身がコピーされた。1回目でコピーした後は自分だけのものなので0になる
+    second_copies: u64,
+    /// map_writableにかかった時間の合計
+    map_time: Duration,
+}
+
+impl fmt::Display for BranchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames {}, same buffer as before tee {}, same data {}, writable on arrival {}, \
+             buffer copies {}, data copies {} (2nd map {}), map_writable {:?}",
+            self.frames,
+            self.same_buffer,
+            self.same_data,
+            self.writable,
+            self.buffer_copies,
+            self.data_copies,
+            self.second_copies,
+            self.map_time
+        )
+    }
+}
+
+/// teeの前のアドレスをPTSごとに覚えておく
+type Origins = Arc<Mutex<HashMap<gst::ClockTime, Address>>>;
+
+/// teeのsink padで、流れてきたバッファのアドレスを覚える
+fn record_origin(pad: &gst::Pad, origins: Origins) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            if let (Some(pts), Some(address)) = (buffer.pts(), Address::of(buffer)) {
+                origins.lock().unwrap().insert(pts, address);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// ブランチのpadで、teeの前と比べながらwriteの通りに書き込む
+fn observe(
+    pad: &gst::Pad,
+    name: &'static str,
+    write: Write,
+    origins: Origins,
+    stats: Arc<Mutex<BranchStats>>,
+) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        let buffer = match info.data {
+            Some(gst::PadProbeData::Buffer(ref mut buffer)) => buffer,
+            _ => return gst::PadProbeReturn::Ok,
+        };
+        let origin = buffer
+            .pts()
+            .and_then(|pts| origins.lock().unwrap().get(&pts).copied());
+        let arrived = Address::of(buffer);
+        let mut stats = stats.lock().unwrap();
+        stats.frames += 1;
+        if let (Some(origin), Some(arrived)) = (origin, arrived) {
+            stats.same_buffer += (origin.buffer == arrived.buffer) as u64;
+            stats.same_data += (origin.data == arrived.data) as u64;
+        }
+        if buffer.is_writable() {
+            stats.writable += 1;
+        }
+        if write == Write::None {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        // 参照が他にもあれば、ここでバッファの構造だけがコピーされる
+        let frame = stats.frames;
+        let buffer = buffer.make_mut();
+        buffer.set_offset(frame);
+        let written = Address::of(buffer);
+        if arrived.map(|a| a.buffer) != written.map(|w| w.buffer) {
+            stats.buffer_copies += 1;
+        }
+        if write == Write::Meta {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        // メモリが他のバッファと共有されていれば、ここで中身がコピーされる
+        let start = Instant::now();
+        let data = match buffer.map_writable() {
+            Ok(mut map) => {
+                if let Some(byte) = map.as_mut_slice().first_mut() {
+                    *byte = !*byte;
+                }
+                map.as_slice().as_ptr() as usize
+            }
+            Err(_) => return gst::PadProbeReturn::Ok,
+        };
+        stats.map_time += start.elapsed();
+        if written.map(|w| w.data) != Some(data) {
+            stats.data_copies += 1;
+        }
+        // もう自分だけのメモリなので、2回目はコピーされない
+        if let Ok(map) = buffer.map_writable() {
+            if map.as_slice().as_ptr() as usize != data {
+                stats.second_copies += 1;
+            }
+        }
+        log::debug!(
+            "{name}: frame {frame} buffer {:#x} -> {:#x}, data {:#x} -> {data:#x}",
+            arrived.map(|a| a.buffer).unwrap_or_default(),
+            written.map(|w| w.buffer).unwrap_or_default(),
+            written.map(|w| w.data).unwrap_or_default()
+        );
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// teeで2つに分けて流し、それぞれのブランチでコピーされた数を出す
+pub fn run(frames: u32, write: Write, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "videotestsrc num-buffers={frames} ! video/x-raw,width=640,height=480 ! tee name=t \
+         t. ! queue name=reader ! fakesink \
+         t. ! queue name=writer ! fakesink"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    let origins = Origins::default();
+    let tee = pipeline.by_name("t").context("tee")?;
+    record_origin(
+        &tee.static_pad("sink").context("tee sink pad")?,
+        origins.clone(),
+    );
+
+    let reader = Arc::new(Mutex::new(BranchStats::default()));
+    let writer = Arc::new(Mutex::new(BranchStats::default()));
+    for (name, branch_write, stats) in
+        [("reader", Write::None, &reader), ("writer", write, &writer)]
+    {
+        let queue = pipeline.by_name(name).context("queue")?;
+        observe(
+            &queue.static_pad("src").context("queue src pad")?,
+            name,
+            branch_write,
+            origins.clone(),
+            stats.clone(),
+        );
+    }
+
+    runner::run(&pipeline, bus_loop)?;
+
+    println!("reader (map_readable): {}", reader.lock().unwrap());
+    println!("writer ({write}): {}", writer.lock().unwrap());
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! 記録したセグメントを閉じるたびにAES-GCMで暗号化し、暗号化したファイルを復号しながら再生する
+//!
+//! 鍵は32バイト(AES-256)で、16進数の文字列にして鍵ファイルに置く。
+//! SegRecordの--encrypt-keyに指定した鍵ファイルがなければ新しい鍵を作って書き出す(unixではパーミッションを0600にする)。
+//! 鍵ファイルをなくすと記録は読めなくなるので、記録とは別の場所に保管する。
+//!
+//! splitmuxsinkはセグメントを閉じるとsplitmuxsink-fragment-closedのエレメントメッセージを出す。
+//! バスのsync handlerでそれを受け取り、別のスレッドで<名前>.encに暗号化してから平文のファイルを消す。
+//!
+//! ファイルは先頭にヘッダー(MAGICとファイルごとにランダムなFILE_ID_SIZEバイトのID)があり、
+//! その後に平文をCHUNK_SIZEごとに区切って暗号化したレコードが並ぶ。
+//! レコードはランダムなnonce(12バイト)と暗号文(認証タグ16バイトを含む)で、最後のレコードだけが短い。
+//! レコードの大きさが決まっているので、読む側は任意の位置のレコードだけを読んで復号できる。
+//! 追加認証データ(AAD)にはヘッダー、レコードの番号、最後のレコードかどうかを入れているので、
+//! レコードを入れ替えたり、途中で切り詰めたり、同じ鍵で暗号化した別のファイルのレコードを混ぜたりしたファイルは認証に失敗する。
+//!
+//! DecryptPlayはplaybinのuriをappsrc://にして、source-setupで作られたappsrcに復号したデータを流す。
+//! appsrcはseekable_srcと同じseekableモードで使い、seekされたら該当するレコードから復号し直す。
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSrc;
+
+use crate::{
+    runner::{self, BusLoopOpt},
+    seekable_src::{self, ByteSource},
+};
+
+const MAGIC: &[u8; 8] = b"GSTLENC2";
+/// ヘッダーのファイルID
+const FILE_ID_SIZE: usize = 16;
+/// MAGICとファイルID
+const HEADER_SIZE: u64 = (MAGIC.len() + FILE_ID_SIZE) as u64;
+const KEY_SIZE: usize = 32;
+/// 1レコードで暗号化する平文の大きさ
+const CHUNK_SIZE: u64 = 64 * 1024;
+const NONCE_SIZE: u64 = 12;
+const TAG_SIZE: u64 = 16;
+/// 最後以外のレコードの大きさ
+const RECORD_SIZE: u64 = NONCE_SIZE + CHUNK_SIZE + TAG_SIZE;
+
+/// セグメントを暗号化、復号する鍵
+#[derive(Clone)]
+pub struct SegmentKey {
+    cipher: Aes256Gcm,
+}
+
+impl SegmentKey {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    /// 鍵ファイルから読む
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let key =
+            parse_hex(text.trim()).with_context(|| format!("invalid key in {}", path.display()))?;
+        Ok(Self::new(&key))
+    }
+
+    /// 鍵ファイルがあれば読み、なければ新しい鍵を作って書き出す
+    pub fn load_or_create(path: &Path) -> anyhow::Result<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let key = Aes256Gcm::generate_key(OsRng);
+        write_keyfile(path, &to_hex(&key))?;
+        log::info!("generated a new key in {}", path.display());
+        Ok(Self::new(&key))
+    }
+
+    /// inputを暗号化してoutputに書く
+    /// 書き終わるまでは.partの名前にしておき、途中で止まっても不完全な.encが残らないようにする
+    pub fn encrypt_file(&self, input: &Path, output: &Path) -> anyhow::Result<()> {
+        let mut reader =
+            File::open(input).with_context(|| format!("failed to open {}", input.display()))?;
+        let size = reader.metadata()?.len();
+        let records = record_count(size);
+
+        let part = append_extension(output, "part");
+        let file =
+            File::create(&part).with_context(|| format!("failed to create {}", part.display()))?;
+        let mut writer = BufWriter::new(file);
+        let header = new_header();
+        writer.write_all(&header)?;
+        let mut chunk = vec![0; CHUNK_SIZE as usize];
+        for index in 0..records {
+            let len = (size - index * CHUNK_SIZE).min(CHUNK_SIZE) as usize;
+            reader.read_exact(&mut chunk[..len])?;
+            // nonceはレコードごとにランダムに作る。96ビットあれば同じ鍵で重なる心配はない
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let aad = aad(&header, index, index + 1 == records);
+            let ciphertext = self
+                .cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: &chunk[..len],
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| anyhow::anyhow!("failed to encrypt record {index}"))?;
+            writer.write_all(&nonce)?;
+            writer.write_all(&ciphertext)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&part, output)
+            .with_context(|| format!("failed to rename {}", part.display()))?;
+        Ok(())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex(text: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        text.len() == KEY_SIZE * 2,
+        "expected {} hex digits, got {}",
+        KEY_SIZE * 2,
+        text.len()
+    );
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .context("not a hex digit")
+        })
+        .collect()
+}
+
+/// 鍵ファイルを作る。既にあれば上書きせずにエラーにする
+fn write_keyfile(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    writeln!(file, "{contents}")?;
+    Ok(())
+}
+
+/// 平文の大きさからレコードの数を求める。空のファイルも最後のレコードを1つ書く
+fn record_count(size: u64) -> u64 {
+    ((size + CHUNK_SIZE - 1) / CHUNK_SIZE).max(1)
+}
+
+/// ランダムなファイルIDを付けたヘッダーを作る
+fn new_header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    let mut file_id = [0; FILE_ID_SIZE];
+    OsRng.fill_bytes(&mut file_id);
+    header.extend_from_slice(&file_id);
+    header
+}
+
+/// レコードの追加認証データ
+fn aad(header: &[u8], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(u8::from(last));
+    aad
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// 暗号化したファイルのパス
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    append_extension(path, "enc")
+}
+
+/// 暗号化したファイルをレコード単位で復号しながら読み出す
+struct EncryptedSource {
+    file: File,
+    key: SegmentKey,
+    /// MAGICとファイルID。各レコードの追加認証データに入れる
+    header: Vec<u8>,
+    /// 平文の大きさ
+    size: u64,
+    records: u64,
+    /// 直前に復号したレコードの番号と平文
+    cache: Option<(u64, Vec<u8>)>,
+}
+
+impl EncryptedSource {
+    fn open(path: &Path, key: SegmentKey) -> anyhow::Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut header = vec![0; HEADER_SIZE as usize];
+        let valid = file.read_exact(&mut header).is_ok() && header.starts_with(MAGIC);
+        anyhow::ensure!(valid, "{} is not an encrypted segment", path.display());
+
+        let body = file.metadata()?.len() - HEADER_SIZE;
+        let (full, rest) = (body / RECORD_SIZE, body % RECORD_SIZE);
+        let overhead = NONCE_SIZE + TAG_SIZE;
+        let (records, size) = match rest {
+            0 if full > 0 => (full, full * CHUNK_SIZE),
+            rest if rest >= overhead => (full + 1, full * CHUNK_SIZE + rest - overhead),
+            _ => anyhow::bail!("{} is truncated", path.display()),
+        };
+        Ok(Self {
+            file,
+            key,
+            header,
+            size,
+            records,
+            cache: None,
+        })
+    }
+
+    fn decrypt(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let len = (self.size - index * CHUNK_SIZE).min(CHUNK_SIZE) + NONCE_SIZE + TAG_SIZE;
+        let mut record = vec![0; len as usize];
+        self.file
+            .seek(SeekFrom::Start(HEADER_SIZE + index * RECORD_SIZE))?;
+        self.file.read_exact(&mut record)?;
+
+        let (nonce, ciphertext) = record.split_at(NONCE_SIZE as usize);
+        let aad = aad(&self.header, index, index + 1 == self.records);
+        self.key
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record {index} failed authentication (wrong key or tampered file)"),
+                )
+            })
+    }
+}
+
+impl ByteSource for EncryptedSource {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// offsetを含むレコードだけを復号し、そのレコードの終わりまでを返す
+    fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if offset >= self.size {
+            return Ok(Vec::new());
+        }
+        let index = offset / CHUNK_SIZE;
+        if !matches!(&self.cache, Some((cached, _)) if *cached == index) {
+            let plain = self.decrypt(index)?;
+            self.cache = Some((index, plain));
+        }
+        let plain = match &self.cache {
+            Some((_, plain)) => plain,
+            None => return Ok(Vec::new()),
+        };
+        let start = (offset % CHUNK_SIZE) as usize;
+        let end = start.saturating_add(len).min(plain.len());
+        Ok(plain[start..end].to_vec())
+    }
+}
+
+/// 閉じたセグメントを順に暗号化する
+pub struct Encryptor {
+    sender: mpsc::Sender<PathBuf>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl Encryptor {
+    /// 暗号化するスレッドを動かす
+    pub fn start(key: SegmentKey) -> Self {
+        let (sender, receiver) = mpsc::channel::<PathBuf>();
+        let worker = thread::spawn(move || {
+            for path in receiver {
+                let output = encrypted_path(&path);
+                let result = key.encrypt_file(&path, &output).and_then(|()| {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove {}", path.display()))
+                });
+                match result {
+                    Ok(()) => log::info!("encrypted {}\r", output.display()),
+                    // 平文が残っているかもしれないので、パスを出して気づけるようにする
+                    Err(err) => log::error!("failed to encrypt {}: {err:#}\r", path.display()),
+                }
+            }
+        });
+        Self { sender, worker }
+    }
+
+    /// バスのsync handlerで、閉じたセグメントをキューに積む
+    pub fn watch(&self, bus: &gst::Bus) {
+        let sender = Mutex::new(self.sender.clone());
+        bus.set_sync_handler(move |_, msg| {
+            let location = msg
+                .structure()
+                .filter(|s| s.name() == "splitmuxsink-fragment-closed")
+                .and_then(|s| s.get::<String>("location").ok());
+            if let Some(location) = location {
+                let _ = sender.lock().unwrap().send(PathBuf::from(location));
+            }
+            gst::BusSyncReply::Pass
+        });
+    }
+
+    /// EOSで閉じた最後のセグメントまで暗号化し終えるのを待つ
+    pub fn finish(self, bus: &gst::Bus) {
+        bus.unset_sync_handler();
+        drop(self.sender);
+        log::info!("waiting for the encryption");
+        if self.worker.join().is_err() {
+            log::error!("the encryption thread panicked");
+        }
+    }
+}
+
+/// DecryptPlayの設定
+#[derive(Debug, Clone)]
+pub struct DecryptPlayOptions {
+    /// 暗号化したセグメント
+    pub path: PathBuf,
+    /// 鍵ファイル
+    pub key: PathBuf,
+}
+
+/// 暗号化したセグメントを復号しながらplaybinで再生する
+/// 平文はディスクに書かず、appsrcが要求した位置のレコードだけをメモリ上で復号する
+pub fn play(options: &DecryptPlayOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let key = SegmentKey::load(&options.key)?;
+    // 鍵が合うかどうかを再生を始める前に確かめる
+    let mut source = EncryptedSource::open(&options.path, key.clone())?;
+    source
+        .read_at(0, 0)
+        .with_context(|| format!("failed to decrypt {}", options.path.display()))?;
+    log::info!(
+        "{}: {} bytes in {} records",
+        options.path.display(),
+        source.size,
+        source.records
+    );
+
+    let pipeline = gst::ElementFactory::make("playbin", None)?
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+    pipeline.set_property("uri", "appsrc://");
+    // playbinはNullに戻すとappsrcを作り直すので、そのたびに開き直す
+    let path = options.path.clone();
+    pipeline.connect("source-setup", false, move |args| {
+        let appsrc = match args[1].get::<gst::Element>().unwrap().downcast::<AppSrc>() {
+            Ok(appsrc) => appsrc,
+            Err(source) => {
+                log::error!("{} is not an appsrc", source.name());
+                return None;
+            }
+        };
+        match EncryptedSource::open(&path, key.clone()) {
+            Ok(source) => seekable_src::attach(&appsrc, Box::new(source), false),
+            Err(err) => {
+                gst::element_error!(appsrc, gst::ResourceError::OpenRead, ("{:#}", err));
+            }
+        }
+        None
+    });
+
+    runner::run(&pipeline, bus_loop)
+}
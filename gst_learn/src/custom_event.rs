@@ -0,0 +1,114 @@
+//! 独自のダウンストリームイベントでパイプラインにマーカーを流す例
+//!
+//! CustomDownstreamイベントはシリアライズされたイベントなので、バッファと同じ順序で下流に届く。
+//! ソース付近でイベントを差し込めば、下流でどのバッファの直前に来たかを見て位置合わせに使える。
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+const MARKER_NAME: &str = "gst-learn-marker";
+
+/// 下流で受け取ったマーカー。次のバッファが来たら位置関係をログに出す
+#[derive(Debug)]
+struct PendingMarker {
+    label: String,
+    timestamp: gst::ClockTime,
+}
+
+/// interval枚ごとにソースの出力にマーカーイベントを差し込み、appsink側で検出する
+pub fn run(interval: u64, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(
+        "videotestsrc name=src num-buffers=300 ! videoconvert ! timeoverlay ! appsink name=sink",
+    )?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let src = pipeline.by_name("src").context("src")?;
+    let sink = pipeline
+        .by_name("sink")
+        .context("sink")?
+        .dynamic_cast::<AppSink>()
+        .unwrap();
+
+    // ソースのsrc padを通るバッファを数えて、一定間隔でバッファの前にイベントを送る
+    // probeはストリーミングスレッドで呼ばれるので、ここでpush_eventすると
+    // このバッファより先にイベントが下流へ流れる
+    let src_pad = src.static_pad("src").context("src pad")?;
+    let count = AtomicU64::new(0);
+    src_pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            let count = count.fetch_add(1, Ordering::Relaxed);
+            if count.checked_rem(interval) == Some(0) {
+                if let Some(pts) = buffer.pts() {
+                    let label = format!("marker-{}", count / interval);
+                    log::info!("send {label} before buffer {pts}");
+                    let s = gst::Structure::builder(MARKER_NAME)
+                        .field("label", &label)
+                        .field("timestamp", pts)
+                        .build();
+                    pad.push_event(gst::event::CustomDownstream::new(s));
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    // appsinkのsink padでイベントを拾う
+    let pending = Arc::new(Mutex::new(None::<PendingMarker>));
+    let sink_pad = sink.static_pad("sink").context("sink pad")?;
+    let pending_clone = pending.clone();
+    sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+            if let gst::EventView::CustomDownstream(custom) = event.view() {
+                if let Some(s) = custom.structure().filter(|s| s.name() == MARKER_NAME) {
+                    let label = s.get::<String>("label").unwrap_or_default();
+                    let timestamp = s
+                        .get::<gst::ClockTime>("timestamp")
+                        .unwrap_or(gst::ClockTime::ZERO);
+                    log::info!("received {label} ({timestamp})");
+                    *pending_clone.lock().unwrap() = Some(PendingMarker { label, timestamp });
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    // マーカーの直後に届いたバッファのPTSと比較する
+    // 間にvideoconvertやtimeoverlayがあっても順序は保たれている
+    sink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let pts = sample.buffer().and_then(|b| b.pts());
+                if let Some(marker) = pending.lock().unwrap().take() {
+                    match pts {
+                        Some(pts) if pts == marker.timestamp => {
+                            log::info!("{} aligned with buffer {}", marker.label, pts)
+                        }
+                        Some(pts) => log::warn!(
+                            "{} ({}) followed by buffer {}",
+                            marker.label,
+                            marker.timestamp,
+                            pts
+                        ),
+                        None => log::warn!("{} followed by buffer without pts", marker.label),
+                    }
+                }
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,307 @@
+//! 名前を付けた複数のパイプラインをWebSocketのJSONで作って操作する、gstdのような小さなサーバー
+//!
+//! 1つのメッセージが1つの要求で、cmdに応じて処理し、同じidを付けた結果を返す。
+//! ```text
+//! {"id":1,"cmd":"create","name":"test","description":"videotestsrc ! autovideosink"}
+//! {"id":2,"cmd":"play","name":"test"}
+//! {"id":3,"cmd":"seek","name":"test","position":10.5}
+//! {"id":4,"cmd":"position","name":"test"}      -> {"id":4,"ok":true,"position":12.3,"duration":null}
+//! {"id":5,"cmd":"pause","name":"test"}
+//! {"id":6,"cmd":"delete","name":"test"}
+//! {"id":7,"cmd":"list"}
+//! ```
+//! 失敗したときは{"id":..,"ok":false,"error":"..."}を返す。
+//!
+//! パイプラインごとにバスを読むスレッドを動かし、EOS、エラー、警告、パイプラインの状態の変化、バッファリングを
+//! {"event":"eos","name":"test"}のように全てのクライアントに送る。
+//! 状態の変更は共通ループと同じくrunner::set_stateで--state-timeoutまで待ち、失敗したらNullに戻す。
+//! descriptionはgst-launch-1.0の記述なので、filesinkなどで何でも書けてしまう。既定ではlocalhostでだけ待ち受ける。
+//!
+//! 要求はserde_jsonでcmdをタグにしたRequestとして読み、足りないキーや知らないcmdはエラーで返す。
+use std::{
+    collections::HashMap,
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+    websocket::{WebSocket, WebSocketWriter},
+};
+
+/// 秒をJSONの数にする。ミリ秒に丸め、不明ならnull
+fn json_seconds(time: Option<gst::ClockTime>) -> Value {
+    match time {
+        Some(time) => json!((time.nseconds() as f64 / 1e6).round() / 1e3),
+        None => Value::Null,
+    }
+}
+
+/// クライアントからの要求。cmdで種類を分ける
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    Create { name: String, description: String },
+    Play { name: String },
+    Pause { name: String },
+    Seek { name: String, position: f64 },
+    Position { name: String },
+    Delete { name: String },
+    List,
+}
+
+/// クライアントに送るバスのイベント。送らないメッセージはNone
+fn event_json(name: &str, msg: &gst::Message) -> Option<Value> {
+    use gst::MessageView;
+    let source = msg
+        .src()
+        .map(|s| s.path_string().to_string())
+        .unwrap_or_default();
+    let from_pipeline = matches!(msg.src(), Some(src) if src.is::<gst::Pipeline>());
+    Some(match msg.view() {
+        MessageView::Eos(_) => json!({"event": "eos", "name": name}),
+        MessageView::Error(err) => json!({
+            "event": "error",
+            "name": name,
+            "source": source,
+            "message": err.error().to_string(),
+        }),
+        MessageView::Warning(warning) => json!({
+            "event": "warning",
+            "name": name,
+            "source": source,
+            "message": warning.error().to_string(),
+        }),
+        MessageView::StateChanged(state) if from_pipeline => json!({
+            "event": "state-changed",
+            "name": name,
+            "old": format!("{:?}", state.old()),
+            "new": format!("{:?}", state.current()),
+        }),
+        MessageView::Buffering(buffering) => json!({
+            "event": "buffering",
+            "name": name,
+            "percent": buffering.percent(),
+        }),
+        _ => return None,
+    })
+}
+
+/// 名前を付けたパイプラインと、イベントを送るクライアント
+struct Daemon {
+    pipelines: Mutex<HashMap<String, gst::Pipeline>>,
+    clients: Mutex<Vec<WebSocketWriter>>,
+    timeout: Option<gst::ClockTime>,
+}
+
+impl Daemon {
+    /// 全てのクライアントに送る。送れなかったクライアントは切れたとみなして外す
+    fn broadcast(&self, json: &str) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.send_text(json).is_ok());
+    }
+
+    fn pipeline(&self, name: &str) -> anyhow::Result<gst::Pipeline> {
+        self.pipelines
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .with_context(|| format!("no pipeline named {name}"))
+    }
+
+    /// パイプラインを作り、バスのメッセージをクライアントに送るスレッドを動かす
+    fn create(self: &Arc<Self>, name: &str, description: &str) -> anyhow::Result<()> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        anyhow::ensure!(
+            !pipelines.contains_key(name),
+            "pipeline {name} already exists"
+        );
+        let element = fallback::parse_launch(description)?;
+        // エレメントが1つだけの記述はパイプラインにならないので、包む
+        let pipeline = match element.downcast::<gst::Pipeline>() {
+            Ok(pipeline) => pipeline,
+            Err(element) => {
+                let pipeline = gst::Pipeline::new(None);
+                pipeline.add(&element)?;
+                pipeline
+            }
+        };
+        let bus = pipeline.bus().context("bus")?;
+        let daemon = self.clone();
+        let pipeline_name = name.to_string();
+        // deleteでバスをflushingにするとiter_timedが終わる
+        thread::spawn(move || {
+            for msg in bus.iter_timed(gst::ClockTime::NONE) {
+                if let Some(event) = event_json(&pipeline_name, &msg) {
+                    daemon.broadcast(&event.to_string());
+                }
+            }
+            log::debug!("{pipeline_name}: bus closed");
+        });
+        pipelines.insert(name.to_string(), pipeline);
+        log::info!("created {name}: {description}");
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> anyhow::Result<()> {
+        let pipeline = self
+            .pipelines
+            .lock()
+            .unwrap()
+            .remove(name)
+            .with_context(|| format!("no pipeline named {name}"))?;
+        runner::rollback(&pipeline);
+        if let Some(bus) = pipeline.bus() {
+            bus.set_flushing(true);
+        }
+        log::info!("deleted {name}");
+        Ok(())
+    }
+
+    fn set_state(&self, name: &str, state: gst::State) -> anyhow::Result<()> {
+        let pipeline = self.pipeline(name)?;
+        runner::set_state(&pipeline, state, self.timeout)?;
+        log::info!("{name}: {state:?}");
+        Ok(())
+    }
+
+    fn seek(&self, name: &str, position: f64) -> anyhow::Result<()> {
+        anyhow::ensure!(position >= 0.0, "negative position {position}");
+        let pipeline = self.pipeline(name)?;
+        let position = gst::ClockTime::from_nseconds((position * 1e9) as u64);
+        pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT, position)?;
+        log::info!("{name}: seek to {position}");
+        Ok(())
+    }
+
+    /// 位置と長さ(秒)
+    fn position(&self, name: &str) -> anyhow::Result<Map<String, Value>> {
+        let pipeline = self.pipeline(name)?;
+        let mut fields = Map::new();
+        fields.insert(
+            "position".to_string(),
+            json_seconds(pipeline.query_position::<gst::ClockTime>()),
+        );
+        fields.insert(
+            "duration".to_string(),
+            json_seconds(pipeline.query_duration::<gst::ClockTime>()),
+        );
+        Ok(fields)
+    }
+
+    fn list(&self) -> Map<String, Value> {
+        let pipelines = self
+            .pipelines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, pipeline)| {
+                json!({"name": name, "state": format!("{:?}", pipeline.current_state())})
+            })
+            .collect::<Vec<_>>();
+        let mut fields = Map::new();
+        fields.insert("pipelines".to_string(), Value::Array(pipelines));
+        fields
+    }
+
+    /// 要求を処理して、結果に付けるフィールドを返す
+    fn execute(self: &Arc<Self>, request: Request) -> anyhow::Result<Map<String, Value>> {
+        match request {
+            Request::Create { name, description } => self.create(&name, &description)?,
+            Request::Play { name } => self.set_state(&name, gst::State::Playing)?,
+            Request::Pause { name } => self.set_state(&name, gst::State::Paused)?,
+            Request::Seek { name, position } => self.seek(&name, position)?,
+            Request::Position { name } => return self.position(&name),
+            Request::Delete { name } => self.delete(&name)?,
+            Request::List => return Ok(self.list()),
+        }
+        Ok(Map::new())
+    }
+
+    /// 要求に応える。idは書かれたまま返す
+    fn handle(self: &Arc<Self>, request: &str) -> String {
+        // 要求の形が間違っていてもidは返せるように、先に値として読む
+        let value = serde_json::from_str::<Value>(request);
+        let id = match &value {
+            Ok(value) => value.get("id").cloned().unwrap_or(Value::Null),
+            Err(_) => Value::Null,
+        };
+        let result = value
+            .and_then(serde_json::from_value::<Request>)
+            .context("invalid request")
+            .and_then(|request| self.execute(request));
+
+        let mut response = Map::new();
+        response.insert("id".to_string(), id);
+        match result {
+            Ok(fields) => {
+                response.insert("ok".to_string(), Value::Bool(true));
+                response.extend(fields);
+            }
+            Err(err) => {
+                log::warn!("{}: {err:#}", request.trim());
+                response.insert("ok".to_string(), Value::Bool(false));
+                response.insert("error".to_string(), Value::String(format!("{err:#}")));
+            }
+        }
+        Value::Object(response).to_string()
+    }
+
+    /// 接続が切れるまで要求を読んで応える
+    fn respond(self: &Arc<Self>, socket: &mut WebSocket) -> anyhow::Result<()> {
+        let writer = socket.writer();
+        while let Some(request) = socket.read_text()? {
+            writer.send_text(&self.handle(&request))?;
+        }
+        Ok(())
+    }
+
+    /// クライアントをイベントの送り先に加え、切れるまで要求に応える
+    fn serve(self: &Arc<Self>, stream: TcpStream) -> anyhow::Result<()> {
+        let peer = stream.peer_addr()?;
+        let mut socket = WebSocket::accept(stream)?;
+        let writer = socket.writer();
+        self.clients.lock().unwrap().push(writer.clone());
+        log::info!("{peer} connected");
+        let result = self.respond(&mut socket);
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| !client.ptr_eq(&writer));
+        log::info!("{peer} disconnected");
+        result
+    }
+}
+
+/// WebSocketで待ち受け、クライアントごとのスレッドで要求に応える
+pub fn run(host: &str, port: u16, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let daemon = Arc::new(Daemon {
+        pipelines: Mutex::default(),
+        clients: Mutex::default(),
+        timeout: bus_loop.state_timeout(),
+    });
+    let listener =
+        TcpListener::bind((host, port)).with_context(|| format!("failed to bind {host}:{port}"))?;
+    log::info!("listening on ws://{}", listener.local_addr()?);
+    for stream in listener.incoming().flatten() {
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            if let Err(err) = daemon.serve(stream) {
+                log::warn!("client: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+//! 入力を複数のビットレートのDASHにエンコードし、マニフェストとセグメントをHTTPで配信する
+//!
+//! 映像はladderモジュールで解像度とビットレートの異なるエンコード(レンディション)に分岐し、
+//! dashsinkのvideo_%u padにそれぞれ繋ぐ。
+//! dashsinkは1つのマニフェストに同じAdaptationSetのRepresentationとして並べるので、
+//! プレイヤーは回線の速さに合わせて切り替えられる。
+//! 切り替えはセグメントの境界で起きるため、全てのブランチでキーフレームの間隔をセグメントの長さに揃える。
+//! HTTPの配信はhlsモジュールのサーバーを使う。
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    audio_encoder::{AudioCodec, AudioEncodeOpt},
+    fallback, hls,
+    ladder::{Ladder, Rendition, DEFAULT_LADDER},
+    runner::{self, BusLoopOpt},
+    transcode,
+};
+
+/// エンコードする映像のフレームレート
+const FRAMERATE: u32 = 30;
+/// fragmented MP4のセグメントに格納する音声コーデック
+const AUDIO_CODECS: &[AudioCodec] = &[AudioCodec::Aac, AudioCodec::Opus];
+const MANIFEST: &str = "manifest.mpd";
+/// レンディションごとのビットレートを出力する間隔
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// DASH配信の設定
+#[derive(Debug)]
+pub struct DashServeOptions {
+    /// ファイルパスまたはURI。Noneならテストソース
+    pub input: Option<String>,
+    /// HTTPで配信するポート
+    pub port: u16,
+    /// セグメントの長さ(秒)
+    pub target_duration: u32,
+    /// 映像のレンディション。空ならDEFAULT_LADDER
+    pub renditions: Vec<Rendition>,
+    /// マニフェストとセグメントを書くディレクトリ。Noneなら一時ディレクトリを作り、終了時に消す
+    pub dir: Option<PathBuf>,
+}
+
+/// dashsinkを作り、fragmented MP4のセグメントをdirに書くように設定する
+fn make_sink(dir: &Path, options: &DashServeOptions) -> anyhow::Result<gst::Element> {
+    let sink = gst::ElementFactory::make("dashsink", Some("sink")).context("dashsink")?;
+    sink.set_property("mpd-root-path", dir.to_string_lossy().to_string());
+    sink.set_property("mpd-filename", MANIFEST);
+    sink.set_property("target-duration", options.target_duration);
+    sink.set_property_from_str("muxer", "mp4");
+    // ライブの入力ではマニフェストを更新し続けるdynamicにする
+    sink.set_property("dynamic", options.input.is_none());
+    Ok(sink)
+}
+
+/// 映像のフレームレートを揃えてからラダーで分岐し、それぞれdashsinkに繋ぐ
+/// フレームレートを揃えておくと、全てのブランチのキーフレームが同じフレームになる
+/// 入力を繋ぐpadを返す
+fn add_video(
+    pipeline: &gst::Pipeline,
+    sink: &gst::Element,
+    renditions: &[Rendition],
+    key_int_max: u32,
+) -> anyhow::Result<gst::Pad> {
+    let ladder = Ladder::new(renditions, key_int_max)?;
+    ladder.link_to(pipeline, sink, "video_%u")?;
+    ladder.report_every(REPORT_INTERVAL);
+
+    let head = fallback::parse_bin_from_description(
+        &format!("queue ! videorate ! video/x-raw,framerate={FRAMERATE}/1"),
+        true,
+    )?;
+    pipeline.add(&head)?;
+    head.static_pad("src")
+        .context("src pad")?
+        .link(&ladder.sink_pad())?;
+    head.sync_state_with_parent()?;
+    head.static_pad("sink").context("sink pad")
+}
+
+/// 音声は1つだけエンコードしてdashsinkに繋ぐ。audioはエンコードのgst-launchの書式
+fn add_audio(
+    pipeline: &gst::Pipeline,
+    sink: &gst::Element,
+    audio: &str,
+) -> anyhow::Result<gst::Pad> {
+    let branch = fallback::parse_bin_from_description(&format!("queue ! {audio}"), true)?;
+    pipeline.add(&branch)?;
+    let dash_pad = sink
+        .request_pad_simple("audio_%u")
+        .context("dashsink audio pad")?;
+    branch
+        .static_pad("src")
+        .context("src pad")?
+        .link(&dash_pad)?;
+    branch.sync_state_with_parent()?;
+    branch.static_pad("sink").context("sink pad")
+}
+
+/// デコードされたpadの種類に応じてブランチを作る。映像と音声はそれぞれ最初の1つだけ使う
+fn add_stream(
+    pipeline: &gst::Pipeline,
+    sink: &gst::Element,
+    media: &str,
+    renditions: &[Rendition],
+    key_int_max: u32,
+    audio: &str,
+) -> anyhow::Result<Option<gst::Pad>> {
+    let kind = media.split('/').next().unwrap_or("");
+    if !matches!(kind, "video" | "audio") {
+        log::info!("ignoring {media} stream");
+        return Ok(None);
+    }
+    if sink.pads().iter().any(|pad| pad.name().starts_with(kind)) {
+        log::info!("ignoring extra {media} stream");
+        return Ok(None);
+    }
+    let pad = if kind == "video" {
+        add_video(pipeline, sink, renditions, key_int_max)?
+    } else {
+        add_audio(pipeline, sink, audio)?
+    };
+    Ok(Some(pad))
+}
+
+/// 入力をDASHにしながら配信し、EOSかエラーまで続ける
+pub fn run(
+    options: &DashServeOptions,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+    let audio = audio.launch(audio.codec(AUDIO_CODECS)?);
+
+    let (dir, temporary) = match &options.dir {
+        Some(dir) => (dir.clone(), false),
+        None => (
+            std::env::temp_dir().join(format!("gst_learn-dash-{}", std::process::id())),
+            true,
+        ),
+    };
+    fs::create_dir_all(&dir)?;
+
+    let renditions = if options.renditions.is_empty() {
+        DEFAULT_LADDER.to_vec()
+    } else {
+        options.renditions.clone()
+    };
+    let key_int_max = options.target_duration * FRAMERATE;
+
+    let pipeline = gst::Pipeline::new(Some("dash"));
+    let sink = make_sink(&dir, options)?;
+    pipeline.add(&sink)?;
+
+    match &options.input {
+        Some(input) => {
+            let decode = transcode::add_source(&pipeline, input)?;
+            let pipeline_weak = pipeline.downgrade();
+            decode.connect_pad_added(move |_, pad| {
+                let pipeline = match pipeline_weak.upgrade() {
+                    Some(pipeline) => pipeline,
+                    None => return,
+                };
+                let media = pad
+                    .current_caps()
+                    .and_then(|caps| caps.structure(0).map(|s| s.name().to_string()))
+                    .unwrap_or_default();
+                let result = add_stream(&pipeline, &sink, &media, &renditions, key_int_max, &audio)
+                    .and_then(|branch| match branch {
+                        Some(branch) => Ok(pad.link(&branch).map(|_| ())?),
+                        None => Ok(()),
+                    });
+                if let Err(err) = result {
+                    log::error!("failed to link {}: {err:#}", pad.name());
+                }
+            });
+        }
+        None => {
+            for (src, media) in [
+                ("videotestsrc is-live=true", "video/x-raw"),
+                ("audiotestsrc is-live=true wave=ticks", "audio/x-raw"),
+            ] {
+                let src = fallback::parse_bin_from_description(src, true)?;
+                pipeline.add(&src)?;
+                let branch = add_stream(&pipeline, &sink, media, &renditions, key_int_max, &audio)?
+                    .context("branch for test source")?;
+                src.static_pad("src").context("src pad")?.link(&branch)?;
+            }
+        }
+    }
+
+    let port = hls::start_server(Arc::new(dir.clone()), options.port, MANIFEST)?;
+    log::info!(
+        "serving http://localhost:{port}/{MANIFEST} from {}",
+        dir.display()
+    );
+
+    let result = runner::run(&pipeline, bus_loop);
+    if temporary {
+        fs::remove_dir_all(&dir)?;
+    }
+    result
+}
@@ -0,0 +1,228 @@
+//! urisourcebin ! decodebin3を手で組み立てる、tutorial B3(uridecodebin)の新しい版
+//!
+//! urisourcebinはデータを取ってくるだけでデコードしない。出てきたpadをdecodebin3のsink padに繋ぐ。
+//! 2本目以降のpad(アダプティブストリーミングなど)はdecodebin3のsink_%uをrequestして繋ぐ。
+//!
+//! decodebin3は見つけたストリームの一覧をStreamCollectionメッセージで知らせ、
+//! select-streamsイベントで選ばれたストリームだけをデコードしてpadを出す。
+//! 選ばなかったストリームはデコードしないので、uridecodebinで全部デコードしてから捨てるより軽い。
+//! ここでは--capsに合うcapsのストリームだけを選び、省略したときは映像と音声を1本ずつ選ぶ。
+//!
+//! 選び直すとdecodebin3はpadを消して出し直すので、pad-removedで繋いでいた出力も片付ける。
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// 指定がなければtutorial B3と同じ動画を使う
+const DEFAULT_URI: &str =
+    "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm";
+
+/// decodebin3の例の設定
+#[derive(Debug)]
+pub struct Decodebin3Options {
+    pub uri: Option<String>,
+    /// 選ぶストリームのcaps。encodedのcaps(video/x-vp8など)と比べる
+    pub caps: Option<String>,
+}
+
+/// 一覧から選ぶストリームのstream-id
+/// filterがあればcapsが交わるものを全て、なければ映像と音声の最初の1本ずつ
+fn choose(collection: &gst::StreamCollection, filter: Option<&gst::Caps>) -> Vec<String> {
+    let mut chosen = Vec::new();
+    let mut chosen_types = gst::StreamType::empty();
+    for stream in collection.iter() {
+        let id = match stream.stream_id() {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let stream_type = stream.stream_type();
+        let caps = stream.caps();
+        log::info!(
+            "stream {id}: {stream_type:?} {}",
+            caps.as_ref().map(|c| c.to_string()).unwrap_or_default()
+        );
+        let selected = match filter {
+            Some(filter) => caps.map(|caps| caps.can_intersect(filter)).unwrap_or(false),
+            None => {
+                (stream_type == gst::StreamType::VIDEO || stream_type == gst::StreamType::AUDIO)
+                    && !chosen_types.contains(stream_type)
+            }
+        };
+        if selected {
+            chosen_types |= stream_type;
+            chosen.push(id);
+        }
+    }
+    chosen
+}
+
+/// decodebin3が出したpadの種類に合う出力を作って繋ぐ
+fn link_output(pipeline: &gst::Pipeline, pad: &gst::Pad) -> anyhow::Result<()> {
+    let stream = pad.stream();
+    let stream_type = stream
+        .as_ref()
+        .map(|s| s.stream_type())
+        .unwrap_or_else(gst::StreamType::empty);
+    let description = if stream_type.contains(gst::StreamType::VIDEO) {
+        "queue ! videoconvert ! autovideosink"
+    } else if stream_type.contains(gst::StreamType::AUDIO) {
+        "queue ! audioconvert ! audioresample ! autoaudiosink"
+    } else {
+        "queue ! fakesink"
+    };
+    let output = fallback::parse_bin_from_description(description, true)?;
+    // padが消えたときに見つけられるように、padの名前から付ける
+    output.set_property("name", output_name(pad));
+    pipeline.add(&output)?;
+    output.sync_state_with_parent()?;
+    pad.link(&output.static_pad("sink").context("output sink pad")?)?;
+    log::info!(
+        "exposed {} ({})",
+        pad.name(),
+        stream
+            .and_then(|s| s.stream_id())
+            .map(|id| id.to_string())
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// padに繋ぐ出力のbinの名前
+fn output_name(pad: &gst::Pad) -> String {
+    format!("output-{}", pad.name())
+}
+
+/// 消えたpadに繋いでいた出力をパイプラインから外す
+/// pad-removedが呼ばれた時点でリンクは切れているので、名前で探す
+fn unlink_output(pipeline: &gst::Pipeline, pad: &gst::Pad) {
+    let output = match pipeline.by_name(&output_name(pad)) {
+        Some(output) => output,
+        None => return,
+    };
+    log::info!("removed {}", pad.name());
+    // decodebin3のストリーミングスレッドから状態を変えないように、別スレッドで片付ける
+    let pipeline = pipeline.clone();
+    output.call_async(move |output| {
+        let _ = output.set_state(gst::State::Null);
+        let _ = pipeline.remove(output);
+    });
+}
+
+/// urisourcebinとdecodebin3で再生し、EOSかエラーまで続ける
+pub fn run(options: &Decodebin3Options) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let filter = options
+        .caps
+        .as_deref()
+        .map(|caps| caps.parse::<gst::Caps>().context("invalid --caps"))
+        .transpose()?;
+
+    let source =
+        gst::ElementFactory::make("urisourcebin", Some("source")).context("make urisourcebin")?;
+    let decodebin =
+        gst::ElementFactory::make("decodebin3", Some("decodebin")).context("make decodebin3")?;
+    source.set_property("uri", options.uri.as_deref().unwrap_or(DEFAULT_URI));
+
+    let pipeline = gst::Pipeline::new(None);
+    pipeline
+        .add_many(&[&source, &decodebin])
+        .context("add element")?;
+
+    // 最初のpadはdecodebin3のsink、2本目以降はsink_%uをrequestして繋ぐ
+    let decodebin_weak = decodebin.downgrade();
+    source.connect_pad_added(move |_, src_pad| {
+        let decodebin = match decodebin_weak.upgrade() {
+            Some(decodebin) => decodebin,
+            None => return,
+        };
+        let sink_pad = match decodebin.static_pad("sink") {
+            Some(pad) if !pad.is_linked() => Some(pad),
+            _ => decodebin.request_pad_simple("sink_%u"),
+        };
+        let result = match sink_pad {
+            Some(sink_pad) => src_pad
+                .link(&sink_pad)
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            None => Err(anyhow::anyhow!("no sink pad on decodebin3")),
+        };
+        match result {
+            Ok(()) => log::info!("linked {} to decodebin3", src_pad.name()),
+            Err(err) => log::error!("failed to link {}: {err:#}", src_pad.name()),
+        }
+    });
+
+    let pipeline_weak = pipeline.downgrade();
+    decodebin.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(err) = link_output(&pipeline, pad) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+    let pipeline_weak = pipeline.downgrade();
+    decodebin.connect_pad_removed(move |_, pad| {
+        if let Some(pipeline) = pipeline_weak.upgrade() {
+            unlink_output(&pipeline, pad);
+        }
+    });
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("make bus")?;
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        use gst::MessageView;
+
+        match msg.view() {
+            // urisourcebinの中のdemuxerなども一覧を出すので、decodebin3からのものだけを見る
+            MessageView::StreamCollection(collection)
+                if msg.src().as_ref() == Some(decodebin.upcast_ref()) =>
+            {
+                let chosen = choose(&collection.stream_collection(), filter.as_ref());
+                if chosen.is_empty() {
+                    log::warn!("no stream matches, keeping the default selection");
+                    continue;
+                }
+                log::info!("selecting {chosen:?}");
+                let ids = chosen.iter().map(String::as_str).collect::<Vec<_>>();
+                if !decodebin.send_event(gst::event::SelectStreams::new(&ids)) {
+                    log::warn!("select-streams was not handled");
+                }
+            }
+            MessageView::StreamsSelected(selected) => {
+                for stream in selected.streams() {
+                    log::info!(
+                        "selected {:?} {}",
+                        stream.stream_type(),
+                        stream
+                            .stream_id()
+                            .map(|id| id.to_string())
+                            .unwrap_or_default()
+                    );
+                }
+            }
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error received from element {:?} {} {:?}",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                break;
+            }
+            MessageView::Eos(_) => break,
+            _ => {}
+        }
+    }
+
+    pipeline
+        .set_state(gst::State::Null)
+        .context("unable to set the pipeline to the `Null` state")?;
+    Ok(())
+}
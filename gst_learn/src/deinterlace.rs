@@ -0,0 +1,124 @@
+//! インターレースの映像をプログレッシブにするdeinterlaceを、再生とトランスコードに組み込む
+//!
+//! capsのinterlace-modeがprogressive以外(interleaved, mixedなど)ならインターレースの映像。
+//! autoはインターレースのときだけ解除し、forceはcapsに関係なく常に解除し、offは何もしない。
+//! playbinでは映像のcapsが決まる前にvideo-filterを設定するので、deinterlaceのmodeに同じ判断を任せる。
+//! トランスコードでは、デコードされたpadのcapsを見てブランチに入れるかを決める。
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+use gst::prelude::*;
+use structopt::StructOpt;
+
+/// インターレースを解除するか
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Auto,
+    Force,
+    Off,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "force" => Ok(Self::Force),
+            "off" => Ok(Self::Off),
+            _ => anyhow::bail!("unknown deinterlace mode {s}, expected auto|force|off"),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Force => "force",
+            Self::Off => "off",
+        })
+    }
+}
+
+/// インターレース解除の設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct DeinterlaceOpt {
+    /// インターレースの解除(auto, force, off)
+    #[structopt(long, default_value = "auto")]
+    pub deinterlace: Mode,
+    /// deinterlaceのmethod(linear, greedyh, greedyl, vfir, yadifなど)
+    #[structopt(long)]
+    pub deinterlace_method: Option<String>,
+}
+
+/// capsがインターレースの映像か
+pub fn is_interlaced(caps: &gst::CapsRef) -> bool {
+    matches!(
+        caps.structure(0)
+            .and_then(|s| s.get::<&str>("interlace-mode").ok()),
+        Some(mode) if mode != "progressive"
+    )
+}
+
+impl DeinterlaceOpt {
+    fn make(&self) -> anyhow::Result<gst::Element> {
+        let deinterlace = gst::ElementFactory::make("deinterlace", None)
+            .context("failed to create deinterlace")?;
+        // interlacedはcapsがprogressiveでも解除する
+        deinterlace.set_property_from_str(
+            "mode",
+            match self.deinterlace {
+                Mode::Force => "interlaced",
+                _ => "auto",
+            },
+        );
+        if let Some(method) = &self.deinterlace_method {
+            deinterlace.set_property_from_str("method", method);
+        }
+        Ok(deinterlace)
+    }
+
+    /// playbinのvideo-filterにdeinterlaceを入れる。playbinがPlayingになる前に呼ぶ
+    pub fn apply(&self, playbin: &gst::Element) -> anyhow::Result<()> {
+        if self.deinterlace == Mode::Off {
+            return Ok(());
+        }
+        let filter = self.make()?;
+        // autoで実際に解除されるかは入ってくるcapsで分かる
+        filter.static_pad("sink").context("sink pad")?.add_probe(
+            gst::PadProbeType::EVENT_DOWNSTREAM,
+            |_, info| {
+                if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                    if let gst::EventView::Caps(caps) = event.view() {
+                        log::info!(
+                            "deinterlace: {} video",
+                            if is_interlaced(caps.caps()) {
+                                "interlaced"
+                            } else {
+                                "progressive"
+                            }
+                        );
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+        playbin.set_property("video-filter", &filter);
+        Ok(())
+    }
+
+    /// デコードされた映像のcapsから、ブランチに入れるdeinterlaceを作る。不要ならNone
+    pub fn element_for(&self, caps: &gst::CapsRef) -> anyhow::Result<Option<gst::Element>> {
+        match self.deinterlace {
+            Mode::Off => Ok(None),
+            Mode::Auto if !is_interlaced(caps) => Ok(None),
+            mode => {
+                log::info!("deinterlacing ({mode})");
+                self.make().map(Some)
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! 映像のフレームを物体検出に渡し、返ってきた枠をプレビューに重ねる
+//!
+//! teeで表示と検出に分け、検出側はvideorateで間引いてvideoscaleで縮め、RGBにしてappsinkから取り出す。
+//! appsinkはmax-buffers=1 drop=trueにして、検出が遅くても常に最新のフレームだけを渡す。
+//! 検出はObjectDetectorトレイトの実装に任せ、フレームに対する0〜1の比率で枠を返させるので、
+//! 縮めた大きさと表示の大きさが違っても同じ枠を使える。
+//!
+//! 表示側はDrawOverlay(overlaycomposition)で、最後の検出結果の枠とラベル、スコアをcairoで描いて重ねる。
+//!
+//! モデルを持たなくても試せるように、前のフレームとの差から動いた範囲を返すMotionDetectorを付ける。
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+use gstreamer_video::VideoInfo;
+
+use crate::{
+    draw_overlay::{self, DrawOverlay, OverlayFrame},
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// これより古い検出結果は描かない
+const STALE_DETECTIONS: Duration = Duration::from_secs(1);
+/// 枠の線の太さ(表示のピクセル)
+const LINE_WIDTH: f64 = 3.0;
+
+/// 検出に渡すRGBのフレーム
+pub struct Frame<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// 1行のバイト数。幅 * 3より大きいことがある
+    pub stride: usize,
+    pub data: &'a [u8],
+    pub pts: Option<gst::ClockTime>,
+}
+
+impl Frame<'_> {
+    /// (x, y)の画素のR, G, B
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let offset = y * self.stride + x * 3;
+        [
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+        ]
+    }
+}
+
+/// 検出した物体。位置と大きさはフレームに対する0〜1の比率
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub label: String,
+    pub score: f64,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// 物体検出の差し込み口
+pub trait ObjectDetector: Send {
+    fn detect(&mut self, frame: &Frame) -> anyhow::Result<Vec<Detection>>;
+}
+
+/// 前のフレームとの輝度の差が大きい画素を囲む枠を返す
+#[derive(Debug, Default)]
+pub struct MotionDetector {
+    previous: Vec<u8>,
+}
+
+impl MotionDetector {
+    /// 動いたとみなす輝度の差
+    const THRESHOLD: u8 = 32;
+    /// 動いた画素がこの割合より少なければノイズとみなす
+    const MIN_AREA: f64 = 0.002;
+}
+
+impl ObjectDetector for MotionDetector {
+    fn detect(&mut self, frame: &Frame) -> anyhow::Result<Vec<Detection>> {
+        let luma = (0..frame.height)
+            .flat_map(|y| (0..frame.width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let [r, g, b] = frame.pixel(x, y);
+                ((r as u32 * 77 + g as u32 * 150 + b as u32 * 29) >> 8) as u8
+            })
+            .collect::<Vec<_>>();
+        let previous = std::mem::replace(&mut self.previous, luma);
+        if previous.len() != self.previous.len() {
+            return Ok(Vec::new());
+        }
+
+        let mut moved = 0usize;
+        let (mut left, mut top, mut right, mut bottom) = (frame.width, frame.height, 0, 0);
+        for (i, (now, before)) in self.previous.iter().zip(&previous).enumerate() {
+            if now.abs_diff(*before) < Self::THRESHOLD {
+                continue;
+            }
+            let (x, y) = (i % frame.width, i / frame.width);
+            moved += 1;
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+        let area = moved as f64 / self.previous.len() as f64;
+        if area < Self::MIN_AREA {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Detection {
+            label: "motion".to_string(),
+            score: area,
+            x: left as f64 / frame.width as f64,
+            y: top as f64 / frame.height as f64,
+            width: (right - left) as f64 / frame.width as f64,
+            height: (bottom - top) as f64 / frame.height as f64,
+        }])
+    }
+}
+
+/// 物体検出の設定
+#[derive(Debug)]
+pub struct DetectOptions {
+    /// 入力のURI。Noneならボールが動くvideotestsrc
+    pub uri: Option<String>,
+    /// 1秒に検出する回数
+    pub fps: i32,
+    /// 検出に渡すフレームの幅。高さは縦横比から決まる
+    pub width: i32,
+}
+
+/// 最後の検出結果
+#[derive(Default)]
+struct Latest {
+    detections: Vec<Detection>,
+    at: Option<Instant>,
+}
+
+/// ラベルごとに決まる枠の色(R, G, B)
+fn color(label: &str) -> (f64, f64, f64) {
+    const COLORS: [(f64, f64, f64); 4] = [
+        (0.0, 1.0, 0.0),
+        (1.0, 0.0, 0.0),
+        (0.0, 0.5, 1.0),
+        (1.0, 1.0, 0.0),
+    ];
+    let hash = label
+        .bytes()
+        .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+    COLORS[hash % COLORS.len()]
+}
+
+/// 検出結果を表示の大きさに合わせて、枠とラベルを描く
+fn draw_detections(
+    cr: &cairo::Context,
+    frame: &OverlayFrame,
+    detections: &[Detection],
+) -> anyhow::Result<bool> {
+    let (width, height) = (frame.width as f64, frame.height as f64);
+    for detection in detections {
+        let (r, g, b) = color(&detection.label);
+        let x = detection.x * width;
+        let y = detection.y * height;
+        cr.set_source_rgb(r, g, b);
+        cr.set_line_width(LINE_WIDTH);
+        cr.rectangle(x, y, detection.width * width, detection.height * height);
+        cr.stroke()?;
+        let label = format!("{} {:.2}", detection.label, detection.score);
+        draw_overlay::text_box(cr, x, (y - 24.0).max(0.0), 14.0, &[label])?;
+    }
+    Ok(!detections.is_empty())
+}
+
+/// 最新のフレームを検出に渡し続ける。EOSかNullで終わる
+fn run_detector(
+    appsink: AppSink,
+    mut detector: Box<dyn ObjectDetector>,
+    latest: Arc<Mutex<Latest>>,
+) {
+    while let Ok(sample) = appsink.pull_sample() {
+        if let Err(err) = detect_sample(&sample, detector.as_mut(), &latest) {
+            log::warn!("detection failed: {err:#}");
+        }
+    }
+}
+
+fn detect_sample(
+    sample: &gst::Sample,
+    detector: &mut dyn ObjectDetector,
+    latest: &Mutex<Latest>,
+) -> anyhow::Result<()> {
+    let caps = sample.caps().context("sample without caps")?;
+    let info = VideoInfo::from_caps(caps)?;
+    let buffer = sample.buffer().context("sample without buffer")?;
+    let map = buffer.map_readable()?;
+    let frame = Frame {
+        width: info.width() as usize,
+        height: info.height() as usize,
+        stride: info.stride()[0] as usize,
+        data: map.as_slice(),
+        pts: buffer.pts(),
+    };
+    let started = Instant::now();
+    let detections = detector.detect(&frame)?;
+    for detection in &detections {
+        log::info!(
+            "{:.3} {} {:.2} at ({:.2}, {:.2}) {:.2}x{:.2} in {:?}",
+            frame.pts.display(),
+            detection.label,
+            detection.score,
+            detection.x,
+            detection.y,
+            detection.width,
+            detection.height,
+            started.elapsed()
+        );
+    }
+    let mut latest = latest.lock().unwrap();
+    latest.detections = detections;
+    latest.at = Some(Instant::now());
+    Ok(())
+}
+
+/// 表示しながら間引いたフレームで検出し、枠を重ねる
+pub fn run(
+    options: &DetectOptions,
+    detector: Box<dyn ObjectDetector>,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(options.fps > 0, "--fps must be positive");
+    anyhow::ensure!(options.width > 0, "--width must be positive");
+    let source = match &options.uri {
+        // 音声のストリームは出さず、映像だけを繋ぐ
+        Some(uri) => {
+            format!("uridecodebin uri=\"{uri}\" caps=video/x-raw expose-all-streams=false")
+        }
+        None => "videotestsrc is-live=true pattern=ball".to_string(),
+    };
+    let pipeline = fallback::parse_launch(&format!(
+        "{source} ! videoconvert ! tee name=t \
+         t. ! queue ! videoconvert name=preview videoconvert name=display ! autovideosink \
+         t. ! queue leaky=downstream max-size-buffers=1 ! videorate drop-only=true max-rate={fps} \
+         ! videoscale ! videoconvert ! video/x-raw,format=RGB,width={width} \
+         ! appsink name=detect sync=false max-buffers=1 drop=true",
+        fps = options.fps,
+        width = options.width,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let appsink = pipeline
+        .by_name("detect")
+        .context("detect")?
+        .dynamic_cast::<AppSink>()
+        .unwrap();
+
+    let latest = Arc::new(Mutex::new(Latest::default()));
+    let latest_clone = latest.clone();
+    let overlay = DrawOverlay::new(Some("overlay"), move |cr, frame| {
+        let latest = latest_clone.lock().unwrap();
+        match latest.at {
+            Some(at) if at.elapsed() < STALE_DETECTIONS => {
+                draw_detections(cr, frame, &latest.detections)
+            }
+            _ => Ok(false),
+        }
+    })?;
+    pipeline.add(overlay.element())?;
+    gst::Element::link_many(&[
+        &pipeline.by_name("preview").context("preview")?,
+        overlay.element(),
+        &pipeline.by_name("display").context("display")?,
+    ])?;
+
+    let detector = thread::spawn(move || run_detector(appsink, detector, latest));
+    let result = runner::run(&pipeline, bus_loop);
+    let _ = detector.join();
+    result
+}
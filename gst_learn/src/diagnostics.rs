@@ -0,0 +1,386 @@
+//! パイプラインの状態を観察するための診断ユーティリティ
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use structopt::StructOpt;
+
+/// 滞留量を読み出せるqueue系エレメントのファクトリ名
+const QUEUE_FACTORIES: &[&str] = &["queue", "queue2"];
+
+/// queueに溜まっているデータ量のスナップショット
+#[derive(Debug, Clone)]
+pub struct QueueLevel {
+    pub name: String,
+    pub buffers: u32,
+    pub bytes: u32,
+    pub time: gst::ClockTime,
+}
+
+/// bin配下(子binも含む)のqueueを探して現在の滞留量を読み出す
+pub fn queue_levels(bin: &impl IsA<gst::Bin>) -> Vec<QueueLevel> {
+    bin.iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|element| {
+            element
+                .factory()
+                .map(|f| QUEUE_FACTORIES.contains(&f.name().as_str()))
+                .unwrap_or(false)
+        })
+        .map(|queue| QueueLevel {
+            name: queue.name().to_string(),
+            buffers: queue.property::<u32>("current-level-buffers"),
+            bytes: queue.property::<u32>("current-level-bytes"),
+            time: gst::ClockTime::from_nseconds(queue.property::<u64>("current-level-time")),
+        })
+        .collect()
+}
+
+/// queueの監視設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Default, StructOpt)]
+pub struct QueueMonitorOpt {
+    /// queueの滞留量を指定間隔(ms)で読み出してログに出す
+    #[structopt(long)]
+    pub monitor_queues: Option<u64>,
+    /// 読み出した滞留量をCSVに書き出す
+    #[structopt(long, parse(from_os_str))]
+    pub queue_csv: Option<PathBuf>,
+}
+
+impl QueueMonitorOpt {
+    /// 監視が有効なら監視スレッドを開始する
+    pub fn start(&self, bin: &impl IsA<gst::Bin>) -> anyhow::Result<Option<QueueMonitor>> {
+        match self.monitor_queues {
+            Some(interval) => Ok(Some(QueueMonitor::start(
+                bin,
+                Duration::from_millis(interval),
+                self.queue_csv.clone(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// 一定間隔でqueueの滞留量を読み出す監視スレッド
+/// teeで分岐したパイプラインのどこでバックプレッシャーが発生しているかを見るのに使う
+/// dropすると監視を停止する
+pub struct QueueMonitor {
+    stop_tx: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl QueueMonitor {
+    pub fn start(
+        bin: &impl IsA<gst::Bin>,
+        interval: Duration,
+        csv: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let mut writer = match csv {
+            Some(path) => {
+                let file = File::create(&path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                let mut writer = BufWriter::new(file);
+                writeln!(writer, "elapsed_ms,queue,buffers,bytes,time_ns")?;
+                Some(writer)
+            }
+            None => None,
+        };
+
+        // binはスレッドを跨いで持ち回るので弱参照にしてパイプラインの破棄を妨げないようにする
+        let bin_weak = bin.upcast_ref::<gst::Bin>().downgrade();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let started = Instant::now();
+
+        let handle = thread::spawn(move || {
+            // 停止要求(もしくはSenderのdrop)が来るまでintervalごとに読み出す
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let bin = match bin_weak.upgrade() {
+                    Some(bin) => bin,
+                    None => break,
+                };
+                let elapsed = started.elapsed().as_millis();
+
+                for level in queue_levels(&bin) {
+                    log::info!(
+                        "queue {}: buffers={} bytes={} time={}",
+                        level.name,
+                        level.buffers,
+                        level.bytes,
+                        level.time
+                    );
+                    if let Some(writer) = writer.as_mut() {
+                        let res = writeln!(
+                            writer,
+                            "{elapsed},{},{},{},{}",
+                            level.name,
+                            level.buffers,
+                            level.bytes,
+                            level.time.nseconds()
+                        );
+                        if let Err(err) = res {
+                            log::error!("failed to write queue level: {err}");
+                        }
+                    }
+                }
+            }
+
+            if let Some(mut writer) = writer {
+                let _ = writer.flush();
+            }
+        });
+
+        Ok(Self {
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for QueueMonitor {
+    fn drop(&mut self) {
+        // Senderを落とすとrecv_timeoutがDisconnectedを返してループを抜ける
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// エレメントから上流に向けたレイテンシクエリの結果
+#[derive(Debug, Clone)]
+pub struct ElementLatency {
+    pub name: String,
+    pub live: bool,
+    pub min: gst::ClockTime,
+    pub max: Option<gst::ClockTime>,
+}
+
+/// bin配下の、srcパッドを持つエレメントごとにレイテンシを問い合わせる
+/// エレメントへのクエリはsrcパッドから上流に伝わるので、ソースからそのエレメントまでの合計になる
+pub fn element_latencies(bin: &impl IsA<gst::Bin>) -> Vec<ElementLatency> {
+    bin.iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|element| !element.is::<gst::Bin>() && !element.src_pads().is_empty())
+        .filter_map(|element| {
+            let mut query = gst::query::Latency::new();
+            if !element.query(&mut query) {
+                return None;
+            }
+            let (live, min, max) = query.result();
+            Some(ElementLatency {
+                name: element.name().to_string(),
+                live,
+                min,
+                max,
+            })
+        })
+        .collect()
+}
+
+/// パイプライン全体とエレメントごとのレイテンシを表にして出す
+pub fn print_latency(pipeline: &gst::Pipeline) {
+    let mut query = gst::query::Latency::new();
+    if pipeline.query(&mut query) {
+        let (live, min, max) = query.result();
+        println!(
+            "pipeline latency: live={live} min={min} max={}",
+            max.display()
+        );
+    } else {
+        println!("pipeline latency query failed");
+    }
+
+    println!(
+        "{:<24} {:>5} {:>16} {:>16}",
+        "element", "live", "min", "max"
+    );
+    // iterate_recurseは下流から返すので、ソース側から並ぶように逆順にする
+    for latency in element_latencies(pipeline).iter().rev() {
+        println!(
+            "{:<24} {:>5} {:>16} {:>16}",
+            latency.name,
+            latency.live,
+            latency.min.to_string(),
+            latency.max.display().to_string()
+        );
+    }
+}
+
+/// システムメモリ以外を表すcapsのfeatureのうち、dmabufのもの
+const DMABUF_FEATURE: &str = "memory:DMABuf";
+/// 何も付いていないcapsのfeature
+const SYSTEM_MEMORY_FEATURE: &str = "memory:SystemMemory";
+
+/// リンク1本で受け渡されているメモリの調べた結果
+#[derive(Debug, Clone)]
+pub struct LinkMemory {
+    /// "エレメント名:pad名"
+    pub src: String,
+    pub sink: String,
+    pub media: String,
+    /// 交渉済みのcapsのfeature(memory:SystemMemoryなど)
+    pub features: String,
+    /// allocationクエリで下流が提案したアロケータとバッファプールの型
+    pub allocators: Vec<String>,
+    pub pools: Vec<String>,
+    /// 下流がVideoMetaを扱える。ストライドやオフセットを付けたまま渡せるので、詰め直しのコピーが要らない
+    pub video_meta: bool,
+}
+
+impl LinkMemory {
+    /// dmabufで受け渡している
+    pub fn dmabuf(&self) -> bool {
+        self.features.contains(DMABUF_FEATURE)
+            || self
+                .allocators
+                .iter()
+                .any(|allocator| allocator.contains("DmaBuf"))
+    }
+
+    /// システムメモリに降ろさずにデバイスのメモリ(GLやCUDA、VAなど)のまま受け渡している
+    pub fn device_memory(&self) -> bool {
+        !self.features.is_empty() && !self.features.contains(SYSTEM_MEMORY_FEATURE)
+    }
+
+    /// ゼロコピーで受け渡しているか、その見込みの説明
+    pub fn path(&self) -> &'static str {
+        if self.dmabuf() {
+            "zero-copy (dmabuf)"
+        } else if self.device_memory() {
+            "zero-copy (device memory)"
+        } else if !self.media.starts_with("video/") {
+            "system memory"
+        } else if self.video_meta {
+            "system memory, no repacking (VideoMeta)"
+        } else {
+            "system memory, may copy to repack"
+        }
+    }
+}
+
+/// padの先の実際のpadを探す。ghost padはbinの中へ、binの外へとたどる
+fn resolve_peer(pad: &gst::Pad) -> Option<gst::Pad> {
+    let mut peer = pad.peer()?;
+    loop {
+        // binのsink側のghost padなら中のエレメントのpadへ
+        if let Some(ghost) = peer.downcast_ref::<gst::GhostPad>() {
+            peer = ghost.target()?;
+            continue;
+        }
+        // binのsrc側のghost padの内側のpadなら、ghost padの先へ
+        if let Some(ghost) = peer
+            .parent()
+            .and_then(|parent| parent.downcast::<gst::GhostPad>().ok())
+        {
+            peer = ghost.peer()?;
+            continue;
+        }
+        return Some(peer);
+    }
+}
+
+fn pad_path(pad: &gst::Pad) -> String {
+    let element = pad
+        .parent_element()
+        .map(|element| element.name().to_string())
+        .unwrap_or_default();
+    format!("{element}:{}", pad.name())
+}
+
+/// padから下流へallocationクエリを送り、提案されたアロケータ、バッファプールとVideoMetaを調べる
+/// 下流はクエリに答えるためにプールを作ることがあるが、実際に使われるものとは別になる
+fn query_allocation(pad: &gst::Pad, caps: &gst::Caps) -> (Vec<String>, Vec<String>, bool) {
+    let mut query = gst::query::Allocation::new(caps, true);
+    if !pad.peer_query(&mut query) {
+        return (Vec::new(), Vec::new(), false);
+    }
+    let allocators = query
+        .allocation_params()
+        .into_iter()
+        .filter_map(|(allocator, _)| allocator)
+        .map(|allocator| allocator.type_().name().to_string())
+        .collect();
+    let pools = query
+        .allocation_pools()
+        .into_iter()
+        .filter_map(|(pool, _, _, _)| pool)
+        .map(|pool| pool.type_().name().to_string())
+        .collect();
+    let video_meta = query
+        .find_allocation_meta::<gstreamer_video::VideoMeta>()
+        .is_some();
+    (allocators, pools, video_meta)
+}
+
+/// bin配下のエレメント同士のリンクごとに、交渉済みのcapsのfeatureとallocationクエリの結果を調べる
+/// プリロールが終わってcapsが決まった後に呼ぶ
+pub fn link_memories(bin: &impl IsA<gst::Bin>) -> Vec<LinkMemory> {
+    bin.iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|element| !element.is::<gst::Bin>())
+        .flat_map(|element| element.src_pads())
+        .filter_map(|pad| {
+            let caps = pad.current_caps()?;
+            let peer = resolve_peer(&pad)?;
+            let media = caps.structure(0)?.name().to_string();
+            let features = caps
+                .features(0)
+                .map(|features| features.to_string())
+                .unwrap_or_default();
+            let (allocators, pools, video_meta) = query_allocation(&pad, &caps);
+            Some(LinkMemory {
+                src: pad_path(&pad),
+                sink: pad_path(&peer),
+                media,
+                features,
+                allocators,
+                pools,
+                video_meta,
+            })
+        })
+        .collect()
+}
+
+/// リンクごとのメモリの受け渡しを表にして出す
+pub fn print_zero_copy(pipeline: &gst::Pipeline) {
+    // iterate_recurseは下流から返すので、ソース側から並ぶように逆順にする
+    let links = link_memories(pipeline);
+    for link in links.iter().rev() {
+        println!("{} -> {} ({})", link.src, link.sink, link.media);
+        println!(
+            "    features: {}  allocators: [{}]  pools: [{}]  VideoMeta: {}",
+            if link.features.is_empty() {
+                SYSTEM_MEMORY_FEATURE
+            } else {
+                &link.features
+            },
+            link.allocators.join(", "),
+            link.pools.join(", "),
+            link.video_meta
+        );
+        println!("    {}", link.path());
+    }
+    let video = links
+        .iter()
+        .filter(|link| link.media.starts_with("video/"))
+        .collect::<Vec<_>>();
+    let zero_copy = video
+        .iter()
+        .filter(|link| link.dmabuf() || link.device_memory())
+        .count();
+    println!(
+        "{zero_copy} of {} video links pass dmabuf or device memory",
+        video.len()
+    );
+}
@@ -0,0 +1,638 @@
+//! GStreamerの環境とレジストリを調べ、各サブコマンドに必要なものが揃っているかを出す
+//!
+//! 出すもの
+//! - 実行時のGStreamerのバージョン。gstreamer-rsのv1_10より古いと動かない
+//! - GST_で始まる環境変数と、GST_PLUGIN_PATHなどに書かれた存在しないディレクトリ
+//! - ブラックリストに入ったプラグイン。依存するライブラリが読めないなどで、スキャンに失敗したもの
+//! - サブコマンドごとの必要なエレメント。なければ入っているはずのプラグインのパッケージを示す
+//! - 一部のオプションでだけ使うエレメントと外部コマンド(whisper-cli、aws、curl)。なくても失敗にはしない
+//!
+//! 必要なものはREQUIREMENTSに手で書いている。`hlssink3|hlssink2`のように|で区切ったものはどれか1つあればよい。
+//! サブコマンドを指定しなければすべてを調べる。必要なエレメントが1つでも足りなければ0以外で終わる。
+use std::{env, path::Path};
+
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// 実行時に必要なGStreamerのバージョン。Cargo.tomlのgstreamerのfeaturesに合わせる
+const MIN_VERSION: (u32, u32) = (1, 10);
+
+/// 表示する環境変数。GST_で始まるものはこれ以外も出す
+const ENVIRONMENT: &[&str] = &["LD_LIBRARY_PATH", "XDG_CONFIG_HOME"];
+
+/// プラグインを探すディレクトリの環境変数
+const PLUGIN_PATHS: &[&str] = &[
+    "GST_PLUGIN_PATH",
+    "GST_PLUGIN_PATH_1_0",
+    "GST_PLUGIN_SYSTEM_PATH",
+    "GST_PLUGIN_SYSTEM_PATH_1_0",
+];
+
+/// 1つのサブコマンドに必要なもの
+struct Requirement {
+    /// サブコマンドのコマンドラインでの名前
+    subcommand: &'static str,
+    /// なければ動かないエレメント
+    elements: &'static [&'static str],
+    /// オプションによって使うエレメント
+    optional: &'static [&'static str],
+    /// オプションによって使う外部コマンド
+    programs: &'static [&'static str],
+    /// 必要なcargoのfeature
+    feature: Option<&'static str>,
+}
+
+const fn requires(subcommand: &'static str, elements: &'static [&'static str]) -> Requirement {
+    Requirement {
+        subcommand,
+        elements,
+        optional: &[],
+        programs: &[],
+        feature: None,
+    }
+}
+
+impl Requirement {
+    const fn optional(self, optional: &'static [&'static str]) -> Self {
+        Self { optional, ..self }
+    }
+
+    const fn programs(self, programs: &'static [&'static str]) -> Self {
+        Self { programs, ..self }
+    }
+
+    const fn feature(self, feature: &'static str) -> Self {
+        Self {
+            feature: Some(feature),
+            ..self
+        }
+    }
+}
+
+/// 映像を記録するサブコマンドが既定で使うもの。エンコーダは--video-codecで変わる
+const RECORDING: &[&str] = &[
+    "videotestsrc",
+    "videoconvert",
+    "timeoverlay",
+    "x264enc",
+    "h264parse",
+    "mp4mux",
+];
+/// --video-codecと--containerで選べるもの
+const ENCODERS: &[&str] = &[
+    "vp8enc",
+    "vp9enc",
+    "av1enc",
+    "nvh264enc",
+    "matroskamux",
+    "webmmux",
+];
+
+const REQUIREMENTS: &[Requirement] = &[
+    requires("b1", &["playbin"]),
+    requires("b2", &["videotestsrc", "autovideosink"]),
+    requires(
+        "b3",
+        &[
+            "uridecodebin",
+            "audioconvert",
+            "audioresample",
+            "autoaudiosink",
+        ],
+    ),
+    requires("b4", &["playbin"]),
+    requires("b5", &["playbin"]).feature("tutorial5"),
+    requires("b6", &["audiotestsrc", "autoaudiosink"]),
+    requires(
+        "b7",
+        &[
+            "audiotestsrc",
+            "tee",
+            "queue",
+            "audioconvert",
+            "autoaudiosink",
+            "wavescope",
+            "videoconvert",
+            "autovideosink",
+        ],
+    ),
+    requires(
+        "b8",
+        &[
+            "appsrc",
+            "appsink",
+            "tee",
+            "queue",
+            "audioconvert",
+            "autoaudiosink",
+            "wavescope",
+            "autovideosink",
+        ],
+    ),
+    requires("b9", &["uridecodebin"]),
+    requires("b12", &["playbin"]),
+    requires("b13", &["playbin"]),
+    requires(
+        "t1",
+        &[
+            "videotestsrc",
+            "timeoverlay",
+            "tee",
+            "queue",
+            "autovideosink",
+            "appsink",
+        ],
+    ),
+    requires(
+        "seekable-src",
+        &["appsrc", "decodebin", "audioconvert", "autoaudiosink"],
+    ),
+    requires(
+        "video-app-src",
+        &[
+            "appsrc",
+            "videoconvert",
+            "x264enc",
+            "matroskamux",
+            "filesink",
+        ],
+    ),
+    requires(
+        "controller",
+        &["audiotestsrc", "volume", "autoaudiosink", "videotestsrc"],
+    ),
+    requires("marker", &["videotestsrc", "timeoverlay", "appsink"]),
+    requires("record", RECORDING)
+        .optional(ENCODERS)
+        .programs(&["aws"]),
+    requires(
+        "seg-record",
+        &["splitmuxsink", "x264enc", "h264parse", "mp4mux"],
+    )
+    .optional(ENCODERS),
+    requires("decrypt-play", &["playbin", "appsrc"]),
+    requires(
+        "av-sync",
+        &["videotestsrc", "audiotestsrc", "level", "appsink"],
+    )
+    .optional(&["autovideosrc", "autoaudiosrc", "v4l2src", "pulsesrc"]),
+    requires("multicam", RECORDING).optional(&["v4l2src", "matroskamux"]),
+    requires("timelapse", &["appsrc", "appsink", "videoscale", "x264enc"])
+        .optional(&["v4l2src"])
+        .programs(&["aws"]),
+    requires("kiosk", &["playbin"]),
+    requires(
+        "detect",
+        &[
+            "uridecodebin",
+            "videorate",
+            "videoscale",
+            "appsink",
+            "autovideosink",
+        ],
+    ),
+    requires(
+        "transcribe",
+        &[
+            "uridecodebin",
+            "tee",
+            "audioresample",
+            "appsink",
+            "autoaudiosink",
+        ],
+    )
+    .programs(&["whisper-cli"]),
+    requires("icecast", &["audioconvert", "shout2send"]).optional(&[
+        "opusenc",
+        "oggmux",
+        "lamemp3enc",
+        "autoaudiosrc",
+    ]),
+    requires(
+        "ducking",
+        &[
+            "uridecodebin",
+            "audiomixer",
+            "volume",
+            "level",
+            "autoaudiosink",
+        ],
+    ),
+    requires(
+        "secondary-audio",
+        &["uridecodebin", "audiomixer", "volume", "autoaudiosink"],
+    ),
+    requires("silence", &["uridecodebin", "audioconvert"]).optional(&["rsdetectsilence"]),
+    requires("split-audio", &["uridecodebin", "audioconvert", "filesink"]).optional(&[
+        "opusenc",
+        "vorbisenc",
+        "flacenc",
+    ]),
+    requires(
+        "subtitles",
+        &["uridecodebin", "appsrc", "textoverlay", "autovideosink"],
+    ),
+    requires("virtual-cam", &["videotestsrc", "v4l2sink"]).optional(&[
+        "v4l2src",
+        "rsrgb2gray",
+        "rsroi",
+    ]),
+    requires("screen", &["videocrop", "videorate", "autovideosink"])
+        .optional(&["ximagesrc", "pipewiresrc", "x264enc"])
+        .programs(&["aws"]),
+    requires(
+        "pipe-wire",
+        &["pipewiresrc", "pipewiresink", "videoconvert"],
+    ),
+    requires("transcode", &["uridecodebin", "decodebin", "filesink"]).optional(ENCODERS),
+    requires(
+        "bridge",
+        &["appsink", "appsrc", "videotestsrc", "autovideosink"],
+    ),
+    requires(
+        "inter",
+        &[
+            "intervideosink",
+            "intervideosrc",
+            "proxysink",
+            "proxysrc",
+            "autovideosink",
+        ],
+    ),
+    requires("ts", &["filesrc", "tsparse", "tsdemux", "decodebin"]),
+    requires(
+        "decodebin3",
+        &["urisourcebin", "decodebin3", "autovideosink"],
+    ),
+    requires("streams", &["playbin3"]),
+    requires("snapshot", &["playbin"]),
+    requires(
+        "offset",
+        &[
+            "videotestsrc",
+            "tee",
+            "compositor",
+            "textoverlay",
+            "autovideosink",
+        ],
+    ),
+    requires(
+        "resample",
+        &["audiotestsrc", "audioresample", "autoaudiosink"],
+    ),
+    requires("clock", &["videotestsrc", "timeoverlay", "autovideosink"]),
+    requires("chromecast", &["appsink", "x264enc", "mp4mux"]),
+    requires(
+        "hls-serve",
+        &["hlssink3|hlssink2", "x264enc", "h264parse", "videorate"],
+    )
+    .programs(&["aws", "curl"]),
+    requires("dash-serve", &["dashsink", "x264enc", "videorate"]),
+    requires("framerate", &["videotestsrc", "videorate", "fakesink"]).optional(&["deinterlace"]),
+    requires("slowmo", &["videorate", "filesink", "x264enc"]),
+    requires(
+        "meta-send",
+        &["videotestsrc", "x264enc", "mpegtsmux", "appsrc", "udpsink"],
+    ),
+    requires(
+        "meta-recv",
+        &["udpsrc", "tsparse", "tsdemux", "decodebin", "appsink"],
+    ),
+    requires("shm-send", &["videotestsrc", "shmsink"]),
+    requires("shm-recv", &["shmsrc", "autovideosink"]),
+    requires(
+        "batch-normalize",
+        &["uridecodebin", "rganalysis", "volume", "filesink"],
+    ),
+    requires("watch", &["uridecodebin", "appsink"]),
+    requires("cow", &["videotestsrc", "tee", "queue", "fakesink"]),
+    requires("hdr", &["playbin", "identity"]),
+    requires(
+        "rtp",
+        &[
+            "rtpbin",
+            "udpsrc",
+            "udpsink",
+            "vp8enc",
+            "vp8dec",
+            "rtpvp8pay",
+            "rtpvp8depay",
+        ],
+    )
+    .optional(&[
+        "rtpulpfecenc",
+        "rtpulpfecdec",
+        "rtprtxsend",
+        "rtprtxreceive",
+    ]),
+];
+
+/// エレメントが入っているはずのプラグインのパッケージ
+const PACKAGES: &[(&str, &[&str])] = &[
+    (
+        "gstreamer core",
+        &[
+            "queue",
+            "queue2",
+            "tee",
+            "identity",
+            "fakesink",
+            "filesink",
+            "filesrc",
+            "fdsrc",
+            "fdsink",
+            "capsfilter",
+        ],
+    ),
+    (
+        "gst-plugins-base",
+        &[
+            "playbin",
+            "playbin3",
+            "decodebin",
+            "decodebin3",
+            "uridecodebin",
+            "urisourcebin",
+            "audioconvert",
+            "audioresample",
+            "videoconvert",
+            "videoscale",
+            "videorate",
+            "videotestsrc",
+            "audiotestsrc",
+            "appsrc",
+            "appsink",
+            "volume",
+            "textoverlay",
+            "timeoverlay",
+            "compositor",
+            "audiomixer",
+            "opusenc",
+            "vorbisenc",
+            "oggmux",
+        ],
+    ),
+    (
+        "gst-plugins-good",
+        &[
+            "autovideosink",
+            "autoaudiosink",
+            "autovideosrc",
+            "autoaudiosrc",
+            "level",
+            "matroskamux",
+            "webmmux",
+            "mp4mux",
+            "splitmuxsink",
+            "v4l2src",
+            "v4l2sink",
+            "pulsesrc",
+            "deinterlace",
+            "rtpbin",
+            "rtprtxsend",
+            "rtprtxreceive",
+            "udpsrc",
+            "udpsink",
+            "rtpvp8pay",
+            "rtpvp8depay",
+            "rtpulpfecenc",
+            "rtpulpfecdec",
+            "vp8enc",
+            "vp8dec",
+            "vp9enc",
+            "flacenc",
+            "lamemp3enc",
+            "shout2send",
+            "ximagesrc",
+            "rganalysis",
+            "rgvolume",
+            "videocrop",
+        ],
+    ),
+    (
+        "gst-plugins-bad",
+        &[
+            "wavescope",
+            "h264parse",
+            "hlssink2",
+            "dashsink",
+            "mpegtsmux",
+            "tsdemux",
+            "tsparse",
+            "intervideosrc",
+            "intervideosink",
+            "proxysrc",
+            "proxysink",
+            "shmsrc",
+            "shmsink",
+            "av1enc",
+            "nvh264enc",
+        ],
+    ),
+    ("gst-plugins-ugly", &["x264enc"]),
+    (
+        "gst-plugins-rs",
+        &["hlssink3", "rsdetectsilence", "rsrgb2gray", "rsroi"],
+    ),
+    (
+        "pipewire (gstreamer plugin)",
+        &["pipewiresrc", "pipewiresink"],
+    ),
+];
+
+/// エレメントが入っているパッケージ
+pub fn package(element: &str) -> Option<&'static str> {
+    PACKAGES
+        .iter()
+        .find(|(_, elements)| elements.contains(&element))
+        .map(|(package, _)| *package)
+}
+
+/// `a|b`のどれかがあればtrue。fallbackが代わりに使うエレメントがあるものもあるとみなす
+fn has_element(alternatives: &str) -> bool {
+    alternatives.split('|').any(fallback::is_available)
+}
+
+/// 足りないエレメントと、入れるパッケージ
+fn missing_note(alternatives: &str) -> String {
+    let packages = alternatives
+        .split('|')
+        .filter_map(package)
+        .collect::<Vec<_>>();
+    if packages.is_empty() {
+        alternatives.to_string()
+    } else {
+        format!("{alternatives} (install {})", packages.join(" or "))
+    }
+}
+
+/// PATHから実行できるファイルを探す
+fn find_program(name: &str) -> bool {
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+fn check_feature(feature: &str) -> bool {
+    match feature {
+        "tutorial5" => cfg!(feature = "tutorial5"),
+        _ => false,
+    }
+}
+
+/// 実行時のバージョンを出し、古すぎればfalse
+fn check_version() -> bool {
+    let (major, minor, _, _) = gst::version();
+    println!("{}", gst::version_string());
+    if (major, minor) < MIN_VERSION {
+        println!(
+            "  ERROR: GStreamer {}.{} or later is required",
+            MIN_VERSION.0, MIN_VERSION.1
+        );
+        return false;
+    }
+    true
+}
+
+fn print_environment() {
+    println!("environment:");
+    let mut vars = env::vars()
+        .filter(|(name, _)| name.starts_with("GST_") || ENVIRONMENT.contains(&name.as_str()))
+        .collect::<Vec<_>>();
+    vars.sort();
+    if vars.is_empty() {
+        println!("  (no GST_ variables)");
+    }
+    for (name, value) in vars {
+        println!("  {name}={value}");
+    }
+}
+
+/// プラグインを探すディレクトリとスキャンの結果を調べる
+fn check_plugins() {
+    println!("plugins:");
+    for name in PLUGIN_PATHS {
+        let paths = match env::var_os(name) {
+            Some(paths) => paths,
+            None => continue,
+        };
+        for dir in env::split_paths(&paths) {
+            if !dir.is_dir() {
+                println!(
+                    "  WARNING: {name} contains {} which is not a directory",
+                    dir.display()
+                );
+            }
+        }
+    }
+    if let Some(registry) = env::var_os("GST_REGISTRY") {
+        let registry = Path::new(&registry);
+        if matches!(registry.parent(), Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir()) {
+            println!(
+                "  WARNING: the directory of GST_REGISTRY {} does not exist",
+                registry.display()
+            );
+        }
+    }
+
+    let plugins = gst::Registry::get().plugins();
+    let blacklisted = plugins
+        .iter()
+        .filter(|plugin| {
+            plugin
+                .plugin_flags()
+                .contains(gst::PluginFlags::BLACKLISTED)
+        })
+        .collect::<Vec<_>>();
+    println!(
+        "  {} plugins loaded, {} blacklisted",
+        plugins.len() - blacklisted.len(),
+        blacklisted.len()
+    );
+    if !blacklisted.is_empty() {
+        println!(
+            "  hint: GST_DEBUG=GST_PLUGIN_LOADING:5 shows why, \
+             remove ~/.cache/gstreamer-1.0 to rescan after fixing"
+        );
+    }
+    for plugin in blacklisted {
+        let file = plugin
+            .filename()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+        println!("  WARNING: blacklisted {} {file}", plugin.plugin_name());
+    }
+}
+
+/// 1つのサブコマンドを調べ、必要なものが揃っていればtrue
+fn check_requirement(requirement: &Requirement) -> bool {
+    let missing = requirement
+        .elements
+        .iter()
+        .filter(|alternatives| !has_element(alternatives))
+        .collect::<Vec<_>>();
+    let missing_optional = requirement
+        .optional
+        .iter()
+        .filter(|alternatives| !has_element(alternatives))
+        .collect::<Vec<_>>();
+    let missing_programs = requirement
+        .programs
+        .iter()
+        .filter(|program| !find_program(program))
+        .collect::<Vec<_>>();
+    let missing_feature = requirement
+        .feature
+        .filter(|feature| !check_feature(feature));
+
+    let ok = missing.is_empty() && missing_feature.is_none();
+    println!(
+        "{} {}",
+        if ok { "ok     " } else { "MISSING" },
+        requirement.subcommand
+    );
+    for alternatives in missing {
+        println!("    element {}", missing_note(alternatives));
+    }
+    if let Some(feature) = missing_feature {
+        println!("    feature {feature} (build with --features {feature})");
+    }
+    for alternatives in missing_optional {
+        println!("    optional element {}", missing_note(alternatives));
+    }
+    for program in missing_programs {
+        println!("    optional program {program} is not in PATH");
+    }
+    ok
+}
+
+/// 環境を調べて出す。指定したサブコマンド(なければすべて)の必要なものが揃っていればtrue
+pub fn run(subcommands: &[String]) -> anyhow::Result<bool> {
+    gst::init()?;
+
+    for name in subcommands {
+        anyhow::ensure!(
+            REQUIREMENTS.iter().any(|r| r.subcommand == name),
+            "no requirements for {name}, known subcommands: {}",
+            REQUIREMENTS
+                .iter()
+                .map(|r| r.subcommand)
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+
+    let mut ok = check_version();
+    print_environment();
+    check_plugins();
+
+    println!("subcommands:");
+    for requirement in REQUIREMENTS
+        .iter()
+        .filter(|r| subcommands.is_empty() || subcommands.iter().any(|name| name == r.subcommand))
+    {
+        ok &= check_requirement(requirement);
+    }
+    Ok(ok)
+}
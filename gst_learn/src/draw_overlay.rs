@@ -0,0 +1,143 @@
+//! overlaycompositionのdrawシグナルにcairoで描いた画像を返し、映像に図形や文字を重ねる
+//!
+//! overlaycompositionはフレームごとにdrawシグナルを出し、返されたVideoOverlayCompositionを
+//! 下流がメタとして扱えればGstVideoOverlayCompositionMetaとして付け、扱えなければフレームに合成する。
+//! 合成は重ねる部分だけなので、フレームのデータを作り直したりエンコードし直したりせずに済む。
+//!
+//! DrawOverlayはフレームと同じ大きさのcairoのARGB32のサーフェスを用意して描画のコールバックを呼び、
+//! 描いた結果を1枚の矩形にして返す。cairoのARGB32はメモリ上でB, G, R, Aの順(リトルエンディアン)で
+//! アルファを掛けた値なので、BGRA(ビッグエンディアンではARGB)とPREMULTIPLIED_ALPHAで渡す。
+//! コールバックがfalseを返したフレームには何も重ねない。
+//!
+//! 検出の枠や統計のHUDなど、サブコマンドが描くものはコールバックに書く。text_boxは背景付きの複数行の文字を描く。
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_video::{
+    VideoFormat, VideoFrameFlags, VideoInfo, VideoMeta, VideoOverlayComposition,
+    VideoOverlayFormatFlags, VideoOverlayRectangle,
+};
+
+/// cairoのARGB32と同じ並びの形式
+#[cfg(target_endian = "little")]
+const CAIRO_FORMAT: VideoFormat = VideoFormat::Bgra;
+#[cfg(target_endian = "big")]
+const CAIRO_FORMAT: VideoFormat = VideoFormat::Argb;
+
+/// 描画のコールバックに渡すフレームの情報
+#[derive(Debug, Clone, Copy)]
+pub struct OverlayFrame {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// cairoで描いたものを重ねるoverlaycomposition
+#[derive(Debug, Clone)]
+pub struct DrawOverlay {
+    element: gst::Element,
+}
+
+impl DrawOverlay {
+    /// overlaycompositionを作ってコールバックを繋ぐ
+    pub fn new<F>(name: Option<&str>, draw: F) -> anyhow::Result<Self>
+    where
+        F: Fn(&cairo::Context, &OverlayFrame) -> anyhow::Result<bool> + Send + Sync + 'static,
+    {
+        let element = gst::ElementFactory::make("overlaycomposition", name)?;
+        element.connect("draw", false, move |args| {
+            let sample = args[1].get::<gst::Sample>().expect("draw args[1]");
+            let composition = render(&sample, &draw).unwrap_or_else(|err| {
+                log::warn!("failed to draw the overlay: {err:#}");
+                None
+            });
+            Some(composition.to_value())
+        });
+        Ok(Self { element })
+    }
+
+    pub fn element(&self) -> &gst::Element {
+        &self.element
+    }
+}
+
+/// フレームの大きさのサーフェスに描いて、重ねる矩形にする
+fn render<F>(sample: &gst::Sample, draw: &F) -> anyhow::Result<Option<VideoOverlayComposition>>
+where
+    F: Fn(&cairo::Context, &OverlayFrame) -> anyhow::Result<bool>,
+{
+    let info = VideoInfo::from_caps(sample.caps().context("sample without caps")?)?;
+    let frame = OverlayFrame {
+        width: info.width(),
+        height: info.height(),
+    };
+    let mut surface = cairo::ImageSurface::create(
+        cairo::Format::ARgb32,
+        frame.width as i32,
+        frame.height as i32,
+    )?;
+    {
+        // サーフェスのデータを取り出す前にContextを捨てる
+        let cr = cairo::Context::new(&surface)?;
+        if !draw(&cr, &frame)? {
+            return Ok(None);
+        }
+    }
+    surface.flush();
+    let stride = surface.stride();
+    let data = surface.data()?.to_vec();
+
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    VideoMeta::add_full(
+        buffer.get_mut().unwrap(),
+        VideoFrameFlags::empty(),
+        CAIRO_FORMAT,
+        frame.width,
+        frame.height,
+        &[0],
+        &[stride],
+    )?;
+    let rectangle = VideoOverlayRectangle::new_raw(
+        &buffer,
+        0,
+        0,
+        frame.width,
+        frame.height,
+        VideoOverlayFormatFlags::PREMULTIPLIED_ALPHA,
+    );
+    Ok(Some(VideoOverlayComposition::new(Some(&rectangle))?))
+}
+
+/// (x, y)を左上にして、半透明の黒の背景に白で複数行の文字を描く
+pub fn text_box(
+    cr: &cairo::Context,
+    x: f64,
+    y: f64,
+    size: f64,
+    lines: &[String],
+) -> anyhow::Result<()> {
+    const PADDING: f64 = 4.0;
+    cr.select_font_face(
+        "monospace",
+        cairo::FontSlant::Normal,
+        cairo::FontWeight::Normal,
+    );
+    cr.set_font_size(size);
+    let extents = cr.font_extents()?;
+    let mut width = 0.0f64;
+    for line in lines {
+        width = width.max(cr.text_extents(line)?.x_advance);
+    }
+    let height = extents.height * lines.len() as f64;
+
+    cr.set_source_rgba(0.0, 0.0, 0.0, 0.6);
+    cr.rectangle(x, y, width + 2.0 * PADDING, height + 2.0 * PADDING);
+    cr.fill()?;
+    cr.set_source_rgb(1.0, 1.0, 1.0);
+    for (i, line) in lines.iter().enumerate() {
+        cr.move_to(
+            x + PADDING,
+            y + PADDING + extents.ascent + extents.height * i as f64,
+        );
+        cr.show_text(line)?;
+    }
+    Ok(())
+}
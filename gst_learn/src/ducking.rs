@@ -0,0 +1,162 @@
+//! 音声が入っている間だけBGMの音量を下げる(ダッキング)例
+//!
+//! 声のブランチにlevelエレメントを入れておくと、一定間隔でRMSやピークをエレメントメッセージとして投げてくる。
+//! アプリケーションはバスでそれを受け取り、別のブランチのvolumeを操作する。
+//! ブランチ同士は直接繋がっていないので、バスを経由したサイドチェイン制御になる。
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// levelメッセージの間隔
+const LEVEL_INTERVAL: gst::ClockTime = gst::ClockTime::from_mseconds(50);
+/// テスト用の声を鳴らす/止める間隔
+const VOICE_TOGGLE: Duration = Duration::from_secs(3);
+
+/// ダッキングの設定
+#[derive(Debug)]
+pub struct DuckingOptions {
+    /// BGMのURI。Noneならテスト音を使う
+    pub music: Option<String>,
+    /// テスト音の代わりにマイクを使う
+    pub mic: bool,
+    /// このdBを超えたら声があるとみなす
+    pub threshold: f64,
+    /// 声がある間のBGMの音量
+    pub duck: f64,
+}
+
+/// 声の大きさからBGMの音量を決める
+/// 下げる時はすぐに、戻す時は声が途切れてから少し待ってゆっくり戻す
+struct Ducker {
+    threshold: f64,
+    duck: f64,
+    hold: Duration,
+    last_voice: Option<Instant>,
+    volume: f64,
+}
+
+impl Ducker {
+    fn new(threshold: f64, duck: f64) -> Self {
+        Self {
+            threshold,
+            duck,
+            hold: Duration::from_millis(500),
+            last_voice: None,
+            volume: 1.0,
+        }
+    }
+
+    fn update(&mut self, rms_db: f64, now: Instant) -> f64 {
+        if rms_db > self.threshold {
+            self.last_voice = Some(now);
+        }
+        let voice = self
+            .last_voice
+            .is_some_and(|last| now.duration_since(last) < self.hold);
+
+        let (target, rate) = if voice { (self.duck, 0.5) } else { (1.0, 0.1) };
+        self.volume += (target - self.volume) * rate;
+        self.volume
+    }
+}
+
+/// levelメッセージから全チャンネルで最大のRMS(dB)を取り出す
+fn max_rms(s: &gst::StructureRef) -> Option<f64> {
+    let rms = s.get::<glib::ValueArray>("rms").ok()?;
+    rms.iter()
+        .filter_map(|v| v.get::<f64>().ok())
+        .reduce(f64::max)
+}
+
+fn music_branch(music: Option<&str>) -> String {
+    match music {
+        Some(uri) => format!("uridecodebin uri={uri} ! audioconvert ! audioresample"),
+        None => "audiotestsrc wave=sine freq=220 volume=0.3".to_string(),
+    }
+}
+
+fn voice_branch(mic: bool) -> &'static str {
+    if mic {
+        "autoaudiosrc ! audioconvert ! audioresample"
+    } else {
+        // マイクの代わりにライブソースを使い、一定間隔でミュートを切り替えて話している状態を再現する
+        "audiotestsrc is-live=true wave=sine freq=880 volume=0.5 ! volume name=voice_gate"
+    }
+}
+
+/// BGMと声をミックスして再生し、声がある間はBGMを下げる
+pub fn run(options: &DuckingOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "audiomixer name=mix ! audioconvert ! autoaudiosink \
+         {music} ! volume name=music_volume ! mix. \
+         {voice} ! level name=voice_level interval={interval} post-messages=true ! mix.",
+        music = music_branch(options.music.as_deref()),
+        voice = voice_branch(options.mic),
+        interval = LEVEL_INTERVAL.nseconds(),
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let music_volume = pipeline.by_name("music_volume").context("music_volume")?;
+    let voice_level = pipeline.by_name("voice_level").context("voice_level")?;
+    let voice_gate = pipeline.by_name("voice_gate");
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let mut ducker = Ducker::new(options.threshold, options.duck);
+    let mut toggled_at = Instant::now();
+    let mut muted = false;
+
+    let bus = pipeline.bus().context("bus")?;
+    loop {
+        if let Some(ref gate) = voice_gate {
+            if toggled_at.elapsed() >= VOICE_TOGGLE {
+                muted = !muted;
+                gate.set_property("mute", muted);
+                toggled_at = Instant::now();
+                log::info!("voice {}", if muted { "off" } else { "on" });
+            }
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                break;
+            }
+            MessageView::Element(element)
+                if element.src().as_ref() == Some(voice_level.upcast_ref()) =>
+            {
+                let rms = match element.structure().and_then(max_rms) {
+                    Some(rms) => rms,
+                    None => continue,
+                };
+                let volume = ducker.update(rms, Instant::now());
+                log::debug!("voice {rms:.1} dB, music volume {volume:.2}");
+                music_volume.set_property("volume", volume);
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! 使いたいエレメントがインストールされていないとき、代わりのエレメントを順に試す
+//!
+//! autovideosinkやx264encのように環境によってない(あるいは読み込めない)エレメントは、
+//! ALTERNATIVESに書いた順に代わりを探し、置き換えたことを警告に出す。
+//! 代わりは同じ種類のデータを扱うものだけにしている。x264encの代わりはH.264を出すopenh264encで、
+//! 後ろのh264parseやmuxerはそのまま繋がる。別のコーデックに替えるのはvideo_encoderが出力に合わせて行う。
+//!
+//! - make: gst::ElementFactory::makeの代わり。代わりもなければ、試したものと入れるパッケージをエラーにする
+//! - parse_launch、parse_bin_from_description: 記述の中のないエレメントを置き換えてから解釈する
+use std::ops::Range;
+
+use gst::prelude::*;
+
+use crate::doctor;
+
+/// エレメントと、なかったときに試す順の代わり
+const ALTERNATIVES: &[(&str, &[&str])] = &[
+    (
+        "autovideosink",
+        &[
+            "glimagesink",
+            "xvimagesink",
+            "ximagesink",
+            "waylandsink",
+            "d3d11videosink",
+            "osxvideosink",
+        ],
+    ),
+    (
+        "autoaudiosink",
+        &["pulsesink", "alsasink", "wasapisink", "osxaudiosink"],
+    ),
+    ("autovideosrc", &["v4l2src", "ksvideosrc", "avfvideosrc"]),
+    (
+        "autoaudiosrc",
+        &["pulsesrc", "alsasrc", "wasapisrc", "osxaudiosrc"],
+    ),
+    ("x264enc", &["openh264enc"]),
+    ("hlssink3", &["hlssink2"]),
+    ("mp4mux", &["qtmux"]),
+];
+
+fn alternatives(factory: &str) -> &'static [&'static str] {
+    ALTERNATIVES
+        .iter()
+        .find(|(name, _)| *name == factory)
+        .map(|(_, alternatives)| *alternatives)
+        .unwrap_or(&[])
+}
+
+fn is_installed(factory: &str) -> bool {
+    gst::ElementFactory::find(factory).is_some()
+}
+
+/// factoryか、その代わりのどれかがインストールされているか
+pub fn is_available(factory: &str) -> bool {
+    is_installed(factory) || alternatives(factory).iter().any(|a| is_installed(a))
+}
+
+/// 作れなかったときのエラー。試した代わりと、入れるパッケージを添える
+fn missing(factory: &str, error: glib::BoolError) -> anyhow::Error {
+    let mut message = format!("failed to create {factory}: {error}");
+    let alternatives = alternatives(factory);
+    if !alternatives.is_empty() {
+        message.push_str(&format!(" (also tried {})", alternatives.join(", ")));
+    }
+    if let Some(package) = doctor::package(factory) {
+        message.push_str(&format!(", install {package}"));
+    }
+    message.push_str(", see the doctor subcommand for details");
+    anyhow::anyhow!(message)
+}
+
+/// factoryのエレメントを作る。作れなければ代わりを順に試す
+pub fn make(factory: &str, name: Option<&str>) -> anyhow::Result<gst::Element> {
+    let error = match gst::ElementFactory::make(factory, name) {
+        Ok(element) => return Ok(element),
+        Err(error) => error,
+    };
+    for alternative in alternatives(factory) {
+        if let Ok(element) = gst::ElementFactory::make(alternative, name) {
+            log::warn!("{factory} is not available, using {alternative} instead");
+            return Ok(element);
+        }
+    }
+    Err(missing(factory, error))
+}
+
+/// 代わりで単位が違うプロパティ。(元, 代わり, プロパティ, 代わりの値にするために掛ける数)
+/// x264encのbitrateはkbit/s、openh264encはbit/s
+const SCALED_PROPERTIES: &[(&str, &str, &str, u64)] =
+    &[("x264enc", "openh264enc", "bitrate", 1000)];
+
+/// gst-launchの記述で、エレメント名の前後に来る文字
+fn is_boundary(c: Option<char>) -> bool {
+    matches!(c, None | Some(' ' | '\t' | '\n' | '!' | '(' | ')'))
+}
+
+/// 記述の中でfactoryがエレメントとして書かれている位置
+/// name=autovideosinkのようにプロパティの値になっているものは含めない
+fn positions(description: &str, factory: &str) -> Vec<usize> {
+    description
+        .match_indices(factory)
+        .map(|(i, _)| i)
+        .filter(|&i| {
+            is_boundary(description[..i].chars().next_back())
+                && is_boundary(description[i + factory.len()..].chars().next())
+        })
+        .collect()
+}
+
+/// posから続くプロパティ(`key=value`)の名前と、前の空白を含めた範囲
+/// 値はダブルクォートで囲まれていてもよい。`!`などプロパティでないものが来たら終わる
+fn properties(description: &str, mut pos: usize) -> Vec<(String, Range<usize>)> {
+    let bytes = description.as_bytes();
+    let mut properties = Vec::new();
+    loop {
+        let start = pos;
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let token_start = pos;
+        let mut quoted = false;
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'\\' if quoted => pos += 1,
+                b'"' => quoted = !quoted,
+                b' ' | b'\t' | b'\n' | b'!' | b')' if !quoted => break,
+                _ => {}
+            }
+            pos += 1;
+        }
+        pos = pos.min(bytes.len());
+        match description[token_start..pos].split_once('=') {
+            Some((key, _))
+                if !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                properties.push((key.to_string(), start..pos));
+            }
+            _ => return properties,
+        }
+    }
+}
+
+/// プロパティ1つを代わりのエレメント向けに書き直す。使えないものはNone
+fn convert_property(
+    property: &str,
+    key: &str,
+    from: &str,
+    to: &str,
+    element: &gst::Element,
+) -> Option<String> {
+    element.find_property(key)?;
+    let scale = SCALED_PROPERTIES
+        .iter()
+        .find(|(f, t, k, _)| *f == from && *t == to && *k == key)
+        .map(|(_, _, _, scale)| *scale);
+    let (name, value) = property.split_once('=')?;
+    match (scale, value.trim_matches('"').parse::<u64>()) {
+        (Some(scale), Ok(value)) => Some(format!("{name}={}", value * scale)),
+        _ => Some(property.to_string()),
+    }
+}
+
+/// 記述の中のエレメントfromをtoに置き換える
+/// fromのプロパティのうち、toにないものは落としてログに出す
+fn replace_element(description: &str, from: &str, to: &str) -> String {
+    let element = gst::ElementFactory::make(to, None).ok();
+    let mut replaced = String::with_capacity(description.len());
+    let mut dropped = Vec::new();
+    let mut last = 0;
+    for i in positions(description, from) {
+        replaced.push_str(&description[last..i]);
+        replaced.push_str(to);
+        last = i + from.len();
+        let element = match &element {
+            Some(element) => element,
+            None => continue,
+        };
+        for (key, range) in properties(description, last) {
+            let property = &description[range.clone()];
+            match convert_property(property, &key, from, to, element) {
+                Some(property) => replaced.push_str(&property),
+                None => dropped.push(key),
+            }
+            last = range.end;
+        }
+    }
+    replaced.push_str(&description[last..]);
+    if !dropped.is_empty() {
+        log::warn!("{to} has no {}, dropped them", dropped.join(", "));
+    }
+    replaced
+}
+
+/// 記述の中のインストールされていないエレメントを代わりのものに置き換える
+fn substitute(description: &str) -> String {
+    let mut description = description.to_string();
+    for (factory, alternatives) in ALTERNATIVES {
+        if is_installed(factory) || positions(&description, factory).is_empty() {
+            continue;
+        }
+        match alternatives.iter().find(|a| is_installed(a)) {
+            Some(alternative) => {
+                log::warn!("{factory} is not available, using {alternative} instead");
+                description = replace_element(&description, factory, alternative);
+            }
+            None => log::warn!(
+                "{factory} is not available and none of {} is installed",
+                alternatives.join(", ")
+            ),
+        }
+    }
+    description
+}
+
+/// gst::parse_launchの代わり。ないエレメントを置き換えてから解釈する
+pub fn parse_launch(description: &str) -> Result<gst::Element, glib::Error> {
+    gst::parse_launch(&substitute(description))
+}
+
+/// gst::parse_bin_from_descriptionの代わり。ないエレメントを置き換えてから解釈する
+pub fn parse_bin_from_description(
+    description: &str,
+    ghost_unlinked_pads: bool,
+) -> Result<gst::Bin, glib::Error> {
+    gst::parse_bin_from_description(&substitute(description), ghost_unlinked_pads)
+}
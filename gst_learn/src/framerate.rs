@@ -0,0 +1,185 @@
+//! フレームレートの変換、テレシネの逆変換(IVTC)、インターレース解除を試す例
+//!
+//! videorateはタイムスタンプを見て、目標のフレームレートより多ければフレームを捨て、少なければ複製する。
+//! drop-onlyにすると複製はせず、入力より高いレートを指定しても上限として扱うだけになる。
+//! 捨てた数と複製した数はvideorateのdrop、duplicateプロパティで読める。
+//!
+//! テレシネの素材はinterlaceエレメントのfield-pattern=2:3で24fpsから作る。
+//! 4フレームを10フィールド(5フレーム)に分けるので30fpsのインターレースになる。
+//! ivtcはフィールドの並びから元の24fpsを組み立て直す。
+//! deinterlaceはfields=allで各フィールドを1フレームにするので、30fpsのインターレースから60fpsになる。
+//! どのモードもエレメントの前後のフレーム数をプローブで数えて、EOSの後に出力する。
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 試す変換
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Videorate,
+    Ivtc,
+    Deinterlace,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "videorate" => Ok(Self::Videorate),
+            "ivtc" => Ok(Self::Ivtc),
+            "deinterlace" => Ok(Self::Deinterlace),
+            _ => anyhow::bail!("unknown mode {s}, expected videorate|ivtc|deinterlace"),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Videorate => "videorate",
+            Self::Ivtc => "ivtc",
+            Self::Deinterlace => "deinterlace",
+        })
+    }
+}
+
+/// "30000/1001"や"24"の形式のフレームレート
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub gst::Fraction);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (numer, denom) = s.split_once('/').unwrap_or((s, "1"));
+        let numer = numer
+            .parse::<i32>()
+            .with_context(|| format!("invalid rate {s}"))?;
+        let denom = denom
+            .parse::<i32>()
+            .with_context(|| format!("invalid rate {s}"))?;
+        anyhow::ensure!(numer > 0 && denom > 0, "rate must be positive: {s}");
+        Ok(Self(gst::Fraction::new(numer, denom)))
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.0.numer(), self.0.denom())
+    }
+}
+
+/// フレームレート変換の設定
+#[derive(Debug)]
+pub struct FramerateOptions {
+    pub mode: Mode,
+    /// ソースのフレームレート。ivtcではテレシネする前の24fps相当のレート
+    pub input_rate: Rate,
+    /// videorateの目標のフレームレート
+    pub rate: Rate,
+    /// videorateで複製せず、捨てるだけにする
+    pub drop_only: bool,
+    /// ソースが出すフレーム数
+    pub frames: u32,
+}
+
+/// padを通ったバッファを数えるプローブを付ける
+fn count(pad: &gst::Pad) -> Arc<AtomicU64> {
+    let counter = Arc::new(AtomicU64::new(0));
+    let c = counter.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+        c.fetch_add(1, Ordering::Relaxed);
+        gst::PadProbeReturn::Ok
+    });
+    counter
+}
+
+/// モードごとのパイプラインの記述
+fn description(options: &FramerateOptions) -> String {
+    let source = format!(
+        "videotestsrc num-buffers={} pattern=ball \
+         ! video/x-raw,width=320,height=240,framerate={}",
+        options.frames, options.input_rate
+    );
+    let convert = match options.mode {
+        Mode::Videorate => format!(
+            "videorate name=convert drop-only={} ! video/x-raw,framerate={}",
+            options.drop_only, options.rate
+        ),
+        // interlaceが出すのはテレシネされたインターレースのフレーム
+        Mode::Ivtc => "interlace field-pattern=2:3 top-field-first=true name=interlace \
+                       ! ivtc name=convert"
+            .to_string(),
+        Mode::Deinterlace => "interlace field-pattern=1:1 top-field-first=true name=interlace \
+                              ! deinterlace name=convert fields=all"
+            .to_string(),
+    };
+    format!("{source} ! {convert} ! fakesink name=sink sync=false")
+}
+
+/// 変換して、変換前後のフレーム数を出力する
+pub fn run(options: &FramerateOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&description(options))?
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+    let convert = pipeline.by_name("convert").context("convert")?;
+    let frames_in = count(&convert.static_pad("sink").context("sink pad")?);
+    let frames_out = count(&convert.static_pad("src").context("src pad")?);
+    let source_frames = match pipeline.by_name("interlace") {
+        Some(interlace) => Some(count(&interlace.static_pad("sink").context("sink pad")?)),
+        None => None,
+    };
+
+    // 出力のcapsでフレームレートが決まったことを確かめる
+    let sink_pad = pipeline
+        .by_name("sink")
+        .and_then(|sink| sink.static_pad("sink"))
+        .context("sink pad")?;
+    sink_pad.connect_notify(Some("caps"), |pad, _| {
+        if let Some(caps) = pad.current_caps() {
+            log::info!("output caps {caps}");
+        }
+    });
+
+    runner::run(&pipeline, bus_loop)?;
+
+    if let Some(source_frames) = source_frames {
+        log::info!(
+            "source: {} progressive frames",
+            source_frames.load(Ordering::Relaxed)
+        );
+    }
+    log::info!(
+        "{}: in={} out={}",
+        options.mode,
+        frames_in.load(Ordering::Relaxed),
+        frames_out.load(Ordering::Relaxed)
+    );
+    if options.mode == Mode::Videorate {
+        // videorate自身も入出力の数を持っている
+        log::info!(
+            "videorate: in={} out={} duplicated={} dropped={}",
+            convert.property::<u64>("in"),
+            convert.property::<u64>("out"),
+            convert.property::<u64>("duplicate"),
+            convert.property::<u64>("drop"),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! HDR(PQ, HLG)の映像を再生し、capsの色空間とHDRのメタデータを表示する
+//!
+//! HDRの情報はcapsで下流に伝わる。colorimetryに伝達関数(PQはsmpte2084、HLGはarib-std-b67)が入り、
+//! PQではmastering-display-info(マスタリングに使ったディスプレイの色域と輝度)と
+//! content-light-level(MaxCLLとMaxFALL)が付くことが多い。
+//! 何もしなければこれらはそのままsinkまで届くので、HDRに対応したsinkなら正しく表示できる。
+//! --tone-mapを付けると、videoconvertでBT.709のcapsに変換してSDRのディスプレイ向けにする。
+//! videoconvertは伝達関数と色域を変換するだけで、明るい部分は切り捨てられる。
+//! 知覚的なトーンマッピングはしないので、PQの明るいハイライトは白く飛ぶ。
+use anyhow::Context;
+use glib::translate::IntoGlib;
+use gst::prelude::*;
+
+use crate::runner::{self, BusLoopOpt};
+
+/// GstVideoTransferFunctionの値。gstreamer-videoのv1_18 featureなしでは名前がないので数値で比べる
+const TRANSFER_SMPTE2084: i32 = 14;
+const TRANSFER_ARIB_STD_B67: i32 = 15;
+
+/// HDR再生の設定
+#[derive(Debug)]
+pub struct HdrOptions {
+    pub uri: String,
+    /// SDRのディスプレイ向けにBT.709に変換する
+    pub tone_map: bool,
+}
+
+/// 伝達関数から見たHDRの種類
+fn dynamic_range(info: &gstreamer_video::VideoInfo) -> &'static str {
+    match info.colorimetry().transfer().into_glib() {
+        TRANSFER_SMPTE2084 => "HDR10 (PQ)",
+        TRANSFER_ARIB_STD_B67 => "HLG",
+        _ => "SDR",
+    }
+}
+
+/// "Rx:Ry:Gx:Gy:Bx:By:Wx:Wy:max:min"の形式のmastering-display-infoを読みやすくする
+/// 色度は0.00002、輝度は0.0001cd/m2の単位
+fn mastering_display(value: &str) -> Option<String> {
+    let v = value
+        .split(':')
+        .map(|n| n.parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if v.len() != 10 {
+        return None;
+    }
+    let xy = |i: usize| format!("({:.4}, {:.4})", v[i] * 0.00002, v[i + 1] * 0.00002);
+    Some(format!(
+        "R{} G{} B{} white{} luminance {:.4}-{:.0} cd/m2",
+        xy(0),
+        xy(2),
+        xy(4),
+        xy(6),
+        v[9] * 0.0001,
+        v[8] * 0.0001
+    ))
+}
+
+/// capsの色空間とHDRのメタデータを出力する
+fn describe(label: &str, caps: &gst::CapsRef) {
+    let s = match caps.structure(0) {
+        Some(s) => s,
+        None => return,
+    };
+    match gstreamer_video::VideoInfo::from_caps(caps) {
+        Ok(info) => log::info!(
+            "{label}: {} {}x{} colorimetry={} ({})",
+            info.format(),
+            info.width(),
+            info.height(),
+            info.colorimetry(),
+            dynamic_range(&info)
+        ),
+        Err(_) => log::info!("{label}: {caps}"),
+    }
+    if let Ok(value) = s.get::<&str>("mastering-display-info") {
+        log::info!(
+            "{label}: mastering display {}",
+            mastering_display(value).unwrap_or_else(|| value.to_string())
+        );
+    }
+    if let Ok(value) = s.get::<&str>("content-light-level") {
+        match value.split_once(':') {
+            Some((max_cll, max_fall)) => {
+                log::info!("{label}: MaxCLL={max_cll} cd/m2 MaxFALL={max_fall} cd/m2")
+            }
+            None => log::info!("{label}: content light level {value}"),
+        }
+    }
+}
+
+/// padのCAPSイベントを見て出力する
+fn watch_caps(pad: &gst::Pad, label: &'static str) {
+    pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::PadProbeData::Event(event)) = &info.data {
+            if let gst::EventView::Caps(caps) = event.view() {
+                describe(label, caps.caps());
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// playbinのvideo-filterに入れるbin。tone_mapならBT.709に変換する
+fn make_filter(tone_map: bool) -> anyhow::Result<gst::Element> {
+    let bin = gst::Bin::new(Some("hdr-filter"));
+    let identity = gst::ElementFactory::make("identity", None)?;
+    bin.add(&identity)?;
+    let mut last = identity.clone();
+    if tone_map {
+        let convert = gst::ElementFactory::make("videoconvert", None)?;
+        // 1.20以降のvideoconvertは色域の変換方法を選べる。fastなら行列で変換する
+        if convert.find_property("primaries-mode").is_some() {
+            convert.set_property_from_str("primaries-mode", "fast");
+        }
+        let filter = gst::ElementFactory::make("capsfilter", None)?;
+        filter.set_property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("colorimetry", "bt709")
+                .build(),
+        );
+        bin.add_many(&[&convert, &filter])?;
+        gst::Element::link_many(&[&identity, &convert, &filter])?;
+        last = filter;
+    }
+
+    let sink = identity.static_pad("sink").context("sink pad")?;
+    let src = last.static_pad("src").context("src pad")?;
+    watch_caps(&sink, "decoded");
+    watch_caps(&src, "output");
+    bin.add_pad(&gst::GhostPad::with_target(Some("sink"), &sink)?)?;
+    bin.add_pad(&gst::GhostPad::with_target(Some("src"), &src)?)?;
+    Ok(bin.upcast())
+}
+
+/// HDRの映像を再生し、デコードされたcapsとsinkに渡すcapsを出力する
+pub fn run(options: &HdrOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let playbin = gst::ElementFactory::make("playbin", None)?;
+    playbin.set_property("uri", &options.uri);
+    playbin.set_property("video-filter", make_filter(options.tone_map)?);
+    if options.tone_map {
+        log::info!("tone-mapping to BT.709 for SDR displays");
+    }
+
+    runner::run(playbin.downcast_ref::<gst::Pipeline>().unwrap(), bus_loop)
+}
@@ -0,0 +1,305 @@
+//! 入力をHLSにエンコードし、プレイリストとセグメントをHTTPで配信する
+//!
+//! hlssink3(gst-plugins-rs)があればそれを、なければhlssink2を使い、一時ディレクトリに書き出す。
+//! どちらもvideoとaudioのrequest padを持ち、キーフレームでセグメントを区切るので、
+//! エンコーダのキーフレームの最大間隔をセグメントの長さに合わせる。
+//! HTTPサーバーはそのディレクトリのファイルだけを返す。
+//! プレイリストは更新され続けるのでキャッシュさせず、セグメントは書き終えたら変わらないのでキャッシュさせる。
+//! 入力を省略するとライブのテスト映像と音声を配信する。
+//! --uploadを指定すると、hls_uploadモジュールで書き終えたセグメントとプレイリストをリモートにも送る。
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    audio_encoder::{AudioCodec, AudioEncodeOpt},
+    fallback,
+    hls_upload::{UploadOptions, Uploader},
+    runner::{self, BusLoopOpt},
+    transcode,
+    video_encoder::{VideoCodec, VideoEncodeOpt},
+};
+
+/// エンコードする映像のフレームレート
+const FRAMERATE: u32 = 30;
+/// セグメントはMPEG-TSなので、プレイヤーが広く対応しているH.264だけにする
+const VIDEO_CODECS: &[VideoCodec] = &[VideoCodec::X264, VideoCodec::Nvenc];
+/// セグメントはMPEG-TSなので、プレイヤーが広く対応しているAACだけにする
+const AUDIO_CODECS: &[AudioCodec] = &[AudioCodec::Aac];
+
+/// HLS配信の設定
+#[derive(Debug)]
+pub struct HlsServeOptions {
+    /// ファイルパスまたはURI。Noneならテストソース
+    pub input: Option<String>,
+    /// HTTPで配信するポート
+    pub port: u16,
+    /// セグメントの長さ(秒)
+    pub target_duration: u32,
+    /// プレイリストに載せるセグメントの数
+    pub playlist_length: u32,
+    /// セグメントを書くディレクトリ。Noneなら一時ディレクトリを作り、終了時に消す
+    pub dir: Option<PathBuf>,
+    /// 書き終えたセグメントを送る先
+    pub upload: Option<UploadOptions>,
+}
+
+/// 拡張子からContent-TypeとCache-Controlを決める
+pub fn content_type(path: &Path) -> Option<(&'static str, &'static str)> {
+    match path.extension()?.to_str()? {
+        "m3u8" => Some(("application/vnd.apple.mpegurl", "no-cache")),
+        "mpd" => Some(("application/dash+xml", "no-cache")),
+        "ts" => Some(("video/mp2t", "max-age=3600")),
+        "m4s" => Some(("video/iso.segment", "max-age=3600")),
+        "mp4" => Some(("video/mp4", "max-age=3600")),
+        _ => None,
+    }
+}
+
+/// 1つの接続に応答する。GETとHEADでディレクトリ直下のファイルだけを返す
+/// "/"にはindexのファイルを返す
+fn serve(stream: TcpStream, dir: &Path, index: &str) -> anyhow::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    log::debug!("{peer}: {}", request_line.trim());
+
+    let mut stream = stream;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+    if method != "GET" && method != "HEAD" {
+        stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    // クエリを除き、ディレクトリの外を指せないようにファイル名だけを使う
+    let name = target
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .trim_start_matches('/');
+    let name = if name.is_empty() { index } else { name };
+    let path = dir.join(name);
+    let file = (!name.contains('/') && !name.contains(".."))
+        .then(|| content_type(&path).zip(File::open(&path).ok()))
+        .flatten();
+    let ((mime, cache), mut file) = match file {
+        Some(file) => file,
+        None => {
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        }
+    };
+
+    let len = file.metadata()?.len();
+    stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {mime}\r\nContent-Length: {len}\r\n\
+             Cache-Control: {cache}\r\nAccess-Control-Allow-Origin: *\r\n\
+             Connection: close\r\n\r\n"
+        )
+        .as_bytes(),
+    )?;
+    if method == "GET" {
+        io::copy(&mut file, &mut stream)?;
+    }
+    Ok(())
+}
+
+/// ディレクトリを配信するHTTPサーバーを別スレッドで動かす
+/// DASHの配信でも使う
+pub fn start_server(dir: Arc<PathBuf>, port: u16, index: &'static str) -> anyhow::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let dir = dir.clone();
+            thread::spawn(move || {
+                if let Err(err) = serve(stream, &dir, index) {
+                    log::debug!("http: {err}");
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+/// hlssink3があればそれを、なければhlssink2を作る
+fn make_sink(dir: &Path, options: &HlsServeOptions) -> anyhow::Result<gst::Element> {
+    let sink = fallback::make("hlssink3", Some("sink"))?;
+    let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+    sink.set_property("location", path("segment%05d.ts"));
+    sink.set_property("playlist-location", path("playlist.m3u8"));
+    sink.set_property("target-duration", options.target_duration);
+    sink.set_property("playlist-length", options.playlist_length);
+    // プレイリストから外れたセグメントは消す。取得中のクライアントのために少し多めに残す
+    sink.set_property("max-files", options.playlist_length * 2);
+    Ok(sink)
+}
+
+/// 映像か音声のエンコードのブランチを作り、sinkのrequest padに繋ぐ
+/// 繋いだブランチのsink padを返す。それ以外のストリームはNone
+/// audioは音声のエンコードのgst-launchの書式
+fn add_branch(
+    pipeline: &gst::Pipeline,
+    sink: &gst::Element,
+    media: &str,
+    key_int_max: u32,
+    video: &VideoEncodeOpt,
+    video_codec: VideoCodec,
+    audio: &str,
+) -> anyhow::Result<Option<gst::Pad>> {
+    let pad_name = if media.starts_with("video/") {
+        "video"
+    } else if media.starts_with("audio/") {
+        "audio"
+    } else {
+        log::info!("ignoring {media} stream");
+        return Ok(None);
+    };
+    if sink.static_pad(pad_name).is_some() {
+        log::info!("ignoring extra {media} stream");
+        return Ok(None);
+    }
+
+    let elements: Vec<gst::Element> = if pad_name == "video" {
+        let convert = fallback::parse_bin_from_description(
+            &format!("queue ! videoconvert ! videorate ! video/x-raw,framerate={FRAMERATE}/1"),
+            true,
+        )?;
+        let encode = video.make_bin(video_codec, true, Some(key_int_max))?;
+        vec![convert.upcast(), encode.upcast()]
+    } else {
+        vec![fallback::parse_bin_from_description(&format!("queue ! {audio}"), true)?.upcast()]
+    };
+    let elements = elements.iter().collect::<Vec<_>>();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    let last = elements[elements.len() - 1];
+    let sink_pad = sink
+        .request_pad_simple(pad_name)
+        .with_context(|| format!("no {pad_name} pad"))?;
+    last.static_pad("src").context("src pad")?.link(&sink_pad)?;
+    for element in &elements {
+        element.sync_state_with_parent()?;
+    }
+    Ok(elements[0].static_pad("sink"))
+}
+
+/// 入力をHLSにしながら配信し、EOSかエラーまで続ける
+pub fn run(
+    options: &HlsServeOptions,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+    let video_codec = video.codec(VIDEO_CODECS)?;
+    let audio = audio.launch(audio.codec(AUDIO_CODECS)?);
+
+    let (dir, temporary) = match &options.dir {
+        Some(dir) => (dir.clone(), false),
+        None => (
+            std::env::temp_dir().join(format!("gst_learn-hls-{}", std::process::id())),
+            true,
+        ),
+    };
+    fs::create_dir_all(&dir)?;
+
+    let pipeline = gst::Pipeline::new(Some("hls"));
+    let sink = make_sink(&dir, options)?;
+    pipeline.add(&sink)?;
+    let key_int_max = options.target_duration * FRAMERATE;
+
+    match &options.input {
+        Some(input) => {
+            let decode = transcode::add_source(&pipeline, input)?;
+            let pipeline_weak = pipeline.downgrade();
+            let video = video.clone();
+            decode.connect_pad_added(move |_, pad| {
+                let pipeline = match pipeline_weak.upgrade() {
+                    Some(pipeline) => pipeline,
+                    None => return,
+                };
+                let media = pad
+                    .current_caps()
+                    .and_then(|caps| caps.structure(0).map(|s| s.name().to_string()))
+                    .unwrap_or_default();
+                let result = add_branch(
+                    &pipeline,
+                    &sink,
+                    &media,
+                    key_int_max,
+                    &video,
+                    video_codec,
+                    &audio,
+                )
+                .and_then(|branch| match branch {
+                    Some(branch) => Ok(pad.link(&branch).map(|_| ())?),
+                    None => Ok(()),
+                });
+                if let Err(err) = result {
+                    log::error!("failed to link {}: {err:#}", pad.name());
+                }
+            });
+        }
+        None => {
+            for (src, media) in [
+                ("videotestsrc is-live=true", "video/x-raw"),
+                ("audiotestsrc is-live=true wave=ticks", "audio/x-raw"),
+            ] {
+                let src = fallback::parse_bin_from_description(src, true)?;
+                pipeline.add(&src)?;
+                let branch = add_branch(
+                    &pipeline,
+                    &sink,
+                    media,
+                    key_int_max,
+                    video,
+                    video_codec,
+                    &audio,
+                )?
+                .context("branch for test source")?;
+                src.static_pad("src").context("src pad")?.link(&branch)?;
+            }
+        }
+    }
+
+    let port = start_server(Arc::new(dir.clone()), options.port, "playlist.m3u8")?;
+    log::info!(
+        "serving http://localhost:{port}/playlist.m3u8 from {}",
+        dir.display()
+    );
+
+    let bus = pipeline.bus().context("bus")?;
+    let uploader = options.upload.as_ref().map(|upload| {
+        log::info!("uploading to {:?}", upload.endpoint);
+        let uploader = Uploader::start(upload, dir.join("playlist.m3u8"));
+        uploader.watch(&bus);
+        uploader
+    });
+
+    let result = runner::run(&pipeline, bus_loop);
+    if let Some(uploader) = uploader {
+        uploader.finish(&bus);
+    }
+    if temporary {
+        fs::remove_dir_all(&dir)?;
+    }
+    result
+}
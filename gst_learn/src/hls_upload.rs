@@ -0,0 +1,268 @@
+//! HLSのセグメントを書き終えるたびに、リモートのサーバーにアップロードする
+//!
+//! hlssink2とhlssink3は中のsplitmuxsinkがセグメントを閉じるとsplitmuxsink-fragment-closedの
+//! エレメントメッセージを出し、プレイリストを書き直してからバスに流す。
+//! バスのsync handlerでそのメッセージを受け取り、セグメントとプレイリストのアップロードをキューに積む。
+//! アップロードは別スレッドで順に行い、失敗したら間隔を倍にしながら--upload-retriesの回数までやり直す。
+//!
+//! プレイリストはそこに載っているセグメントがすべて送り終わってから送るので、
+//! プレイヤーがまだないセグメントを取りに行くことはない。やり直しても送れなかったセグメントは諦めて先に進む。
+//! リモートのプレイリストは読んでいる途中で書き換わらないようにする。
+//! - s3://bucket/prefix : aws s3 cpで送る。S3のオブジェクトの置き換えはもともと不可分
+//! - http(s)://host/path : curlでPUTする。プレイリストは一時的な名前にPUTしてからWebDAVのMOVEで置き換える
+//!   (nginxならdav_methods PUT MOVE)
+//!
+//! hlssinkがmax-filesで消したセグメントはリモートには残るので、バケットのライフサイクルなどで消す。
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::hls;
+
+/// 最初にやり直すまでの時間。やり直すたびに倍にする
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// アップロード先
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    S3 { bucket: String, prefix: String },
+    Http { url: String },
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(location) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+            anyhow::ensure!(!bucket.is_empty(), "expected s3://bucket/prefix, got {s}");
+            return Ok(Self::S3 {
+                bucket: bucket.to_string(),
+                prefix: prefix.trim_end_matches('/').to_string(),
+            });
+        }
+        anyhow::ensure!(
+            s.starts_with("http://") || s.starts_with("https://"),
+            "unsupported upload endpoint {s}, use s3://, http:// or https://"
+        );
+        Ok(Self::Http {
+            url: s.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+impl Endpoint {
+    /// nameのリモートの場所
+    fn location(&self, name: &str) -> String {
+        match self {
+            Self::S3 { bucket, prefix } if prefix.is_empty() => format!("s3://{bucket}/{name}"),
+            Self::S3 { bucket, prefix } => format!("s3://{bucket}/{prefix}/{name}"),
+            Self::Http { url } => format!("{url}/{name}"),
+        }
+    }
+
+    /// 1つのファイルを送る。atomicならリモートで不可分に置き換える
+    fn put(&self, path: &Path, name: &str, atomic: bool) -> anyhow::Result<()> {
+        let (content_type, cache_control) =
+            hls::content_type(path).unwrap_or(("application/octet-stream", "no-cache"));
+        let local = path.to_str().context("non UTF-8 path")?;
+        match self {
+            Self::S3 { .. } => run(Command::new("aws").args([
+                "s3",
+                "cp",
+                "--only-show-errors",
+                "--content-type",
+                content_type,
+                "--cache-control",
+                cache_control,
+                local,
+                &self.location(name),
+            ])),
+            Self::Http { .. } => {
+                let target = self.location(name);
+                let temporary = format!("{target}.uploading");
+                let put_to = if atomic { &temporary } else { &target };
+                run(Command::new("curl").args([
+                    "-sSf",
+                    "-H",
+                    &format!("Content-Type: {content_type}"),
+                    "-T",
+                    local,
+                    put_to,
+                ]))?;
+                if !atomic {
+                    return Ok(());
+                }
+                run(Command::new("curl").args([
+                    "-sSf",
+                    "-X",
+                    "MOVE",
+                    "-H",
+                    &format!("Destination: {target}"),
+                    "-H",
+                    "Overwrite: T",
+                    &temporary,
+                ]))
+            }
+        }
+    }
+}
+
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{program} exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// アップロードの設定
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub endpoint: Endpoint,
+    /// 失敗したときにやり直す回数
+    pub retries: u32,
+}
+
+enum Job {
+    Segment(PathBuf),
+    Playlist,
+}
+
+/// プレイリストに載っているセグメントの名前
+fn playlist_segments(playlist: &str) -> impl Iterator<Item = &str> {
+    playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// キューのファイルを順に送る
+struct Worker {
+    options: UploadOptions,
+    playlist: PathBuf,
+    /// 送り終えたか諦めたセグメント
+    done: HashSet<String>,
+}
+
+impl Worker {
+    /// やり直しながら送る。最後まで失敗したらログに出して諦める
+    fn put_with_retry(&self, path: &Path, atomic: bool) {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return,
+        };
+        let mut interval = RETRY_INTERVAL;
+        for attempt in 0..=self.options.retries {
+            match self.options.endpoint.put(path, name, atomic) {
+                Ok(()) => {
+                    log::info!("uploaded {}", self.options.endpoint.location(name));
+                    return;
+                }
+                Err(err) if attempt < self.options.retries => {
+                    log::warn!("failed to upload {name}, retrying in {interval:?}: {err:#}");
+                    thread::sleep(interval);
+                    interval *= 2;
+                }
+                Err(err) => log::error!("gave up uploading {name}: {err:#}"),
+            }
+        }
+    }
+
+    fn segment(&mut self, path: &Path) {
+        self.put_with_retry(path, false);
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            self.done.insert(name.to_string());
+        }
+    }
+
+    /// 載っているセグメントがすべて送り終わっていればプレイリストを送る
+    fn playlist(&mut self) {
+        let playlist = match fs::read_to_string(&self.playlist) {
+            Ok(playlist) => playlist,
+            Err(err) => {
+                log::warn!("failed to read {}: {err}", self.playlist.display());
+                return;
+            }
+        };
+        if let Some(missing) =
+            playlist_segments(&playlist).find(|segment| !self.done.contains(*segment))
+        {
+            log::debug!("{missing} is not uploaded yet, the playlist is sent later");
+            return;
+        }
+        self.put_with_retry(&self.playlist, true);
+    }
+
+    fn run(mut self, receiver: mpsc::Receiver<Job>) {
+        for job in receiver {
+            match job {
+                Job::Segment(path) => self.segment(&path),
+                Job::Playlist => self.playlist(),
+            }
+        }
+    }
+}
+
+/// セグメントを閉じるたびにアップロードする
+pub struct Uploader {
+    sender: mpsc::Sender<Job>,
+    worker: thread::JoinHandle<()>,
+}
+
+impl Uploader {
+    /// playlistとセグメントを送るスレッドを動かす
+    pub fn start(options: &UploadOptions, playlist: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let worker = Worker {
+            options: options.clone(),
+            playlist,
+            done: HashSet::new(),
+        };
+        let worker = thread::spawn(move || worker.run(receiver));
+        Self { sender, worker }
+    }
+
+    /// バスのsync handlerで、閉じたセグメントとその時点のプレイリストをキューに積む
+    pub fn watch(&self, bus: &gst::Bus) {
+        let sender = Mutex::new(self.sender.clone());
+        bus.set_sync_handler(move |_, msg| {
+            let location = msg
+                .structure()
+                .filter(|s| s.name() == "splitmuxsink-fragment-closed")
+                .and_then(|s| s.get::<String>("location").ok());
+            if let Some(location) = location {
+                let sender = sender.lock().unwrap();
+                let _ = sender.send(Job::Segment(PathBuf::from(location)));
+                let _ = sender.send(Job::Playlist);
+            }
+            gst::BusSyncReply::Pass
+        });
+    }
+
+    /// 最後のプレイリスト(EOSで書かれたENDLIST付き)を積んで、キューを送り終えるまで待つ
+    pub fn finish(self, bus: &gst::Bus) {
+        bus.unset_sync_handler();
+        let _ = self.sender.send(Job::Playlist);
+        drop(self.sender);
+        log::info!("waiting for the uploads");
+        if self.worker.join().is_err() {
+            log::error!("the upload thread panicked");
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! HTTP(S)のソースにプロキシ、認証、TLSの設定を渡す
+//!
+//! playbinはURIに合わせてソースを自分で作るので、作られた直後に呼ばれるsource-setupで設定する。
+//! souphttpsrcを想定しているが、同じ名前のプロパティを持つソースなら同じように設定される。
+//! rtspsrcもproxyやuser-id/user-pwを同じ名前で持っている。
+//! ソースが持たないプロパティは設定せずにログに残す。
+//!
+//! --ask-credentialsを付けると、ソースが認証エラー(NotAuthorized)を出したときに
+//! 端末でユーザー名とパスワードを尋ね、パイプラインを作り直して再接続する。
+//! playbinはNullに戻すとソースを捨てるので、次のsource-setupで新しい認証情報が設定される。
+use std::{
+    io::{self, BufRead, Write},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::keyboard;
+
+/// 認証を尋ね直す回数の上限
+const MAX_AUTH_RETRIES: u32 = 3;
+
+/// HTTPソースの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct HttpOpt {
+    /// 使うHTTPプロキシ(http://host:port)
+    #[structopt(long)]
+    pub proxy: Option<String>,
+    /// プロキシの認証のユーザー名
+    #[structopt(long)]
+    pub proxy_id: Option<String>,
+    /// プロキシの認証のパスワード
+    #[structopt(long)]
+    pub proxy_pw: Option<String>,
+    /// リクエストに付けるCookie(name=value)。複数回指定できる
+    #[structopt(long)]
+    pub cookies: Vec<String>,
+    /// User-Agentヘッダ
+    #[structopt(long)]
+    pub user_agent: Option<String>,
+    /// サーバーの証明書を検証しない
+    #[structopt(long)]
+    pub insecure_tls: bool,
+    /// サーバーの認証のユーザー名
+    #[structopt(long)]
+    pub user_id: Option<String>,
+    /// サーバーの認証のパスワード
+    #[structopt(long)]
+    pub user_pw: Option<String>,
+    /// 認証エラーになったらユーザー名とパスワードを尋ねて再接続する
+    #[structopt(long)]
+    pub ask_credentials: bool,
+}
+
+/// 認証情報
+#[derive(Debug, Clone, Default)]
+struct Credentials {
+    user: Option<(String, String)>,
+    proxy: Option<(String, String)>,
+}
+
+impl Credentials {
+    fn values(&self) -> Vec<(&'static str, glib::Value)> {
+        let mut values = Vec::new();
+        if let Some((id, pw)) = &self.user {
+            values.push(("user-id", id.to_value()));
+            values.push(("user-pw", pw.to_value()));
+        }
+        if let Some((id, pw)) = &self.proxy {
+            values.push(("proxy-id", id.to_value()));
+            values.push(("proxy-pw", pw.to_value()));
+        }
+        values
+    }
+}
+
+/// 認証エラーからの再接続
+/// applyが返す。各サブコマンドのバスのループでメッセージを渡す
+pub struct Auth {
+    ask: bool,
+    credentials: Arc<Mutex<Credentials>>,
+    retries: AtomicU32,
+}
+
+impl Auth {
+    /// 認証エラーなら認証情報を尋ね、パイプラインを作り直して再生し直す
+    /// やり直したらtrueを返すので、呼び出し側はそのエラーを無視する
+    pub fn retry(&self, pipeline: &gst::Element, msg: &gst::Message) -> bool {
+        let err = match msg.view() {
+            gst::MessageView::Error(err) if self.ask => err,
+            _ => return false,
+        };
+        if err.error().kind::<gst::ResourceError>() != Some(gst::ResourceError::NotAuthorized) {
+            return false;
+        }
+        if self.retries.fetch_add(1, Ordering::SeqCst) >= MAX_AUTH_RETRIES {
+            log::error!("giving up after {MAX_AUTH_RETRIES} authentication attempts");
+            return false;
+        }
+
+        // souphttpsrcは407もNotAuthorizedで知らせ、メッセージでプロキシと分かる
+        let proxy = err.error().to_string().contains("Proxy");
+        let source = err
+            .src()
+            .map(|s| s.path_string().to_string())
+            .unwrap_or_default();
+        let target = if proxy { "proxy" } else { "server" };
+        let pair = match prompt(&format!("{source}: {target} authentication required")) {
+            Ok(Some(pair)) => pair,
+            Ok(None) => return false,
+            Err(err) => {
+                log::error!("failed to read credentials: {err}");
+                return false;
+            }
+        };
+        {
+            let mut credentials = self.credentials.lock().unwrap();
+            if proxy {
+                credentials.proxy = Some(pair);
+            } else {
+                credentials.user = Some(pair);
+            }
+        }
+
+        log::info!("retrying with new credentials");
+        if pipeline.set_state(gst::State::Null).is_err()
+            || pipeline.set_state(gst::State::Playing).is_err()
+        {
+            log::error!("failed to restart the pipeline");
+            return false;
+        }
+        true
+    }
+}
+
+/// ユーザー名とパスワードを端末で尋ねる。ユーザー名が空ならNone
+fn prompt(reason: &str) -> io::Result<Option<(String, String)>> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{reason}\nuser: ")?;
+    stdout.flush()?;
+    let mut user = String::new();
+    io::stdin().lock().read_line(&mut user)?;
+    let user = user.trim().to_string();
+    if user.is_empty() {
+        return Ok(None);
+    }
+    let password = keyboard::read_password("password: ")?.unwrap_or_default();
+    Ok(Some((user, password)))
+}
+
+impl HttpOpt {
+    fn is_empty(&self) -> bool {
+        self.proxy.is_none()
+            && self.proxy_id.is_none()
+            && self.proxy_pw.is_none()
+            && self.cookies.is_empty()
+            && self.user_agent.is_none()
+            && !self.insecure_tls
+            && self.user_id.is_none()
+            && self.user_pw.is_none()
+    }
+
+    /// ソースに設定するプロパティの名前と値
+    fn values(&self) -> Vec<(&'static str, glib::Value)> {
+        let mut values = Vec::new();
+        if let Some(proxy) = &self.proxy {
+            values.push(("proxy", proxy.to_value()));
+        }
+        if let Some(id) = &self.proxy_id {
+            values.push(("proxy-id", id.to_value()));
+        }
+        if let Some(pw) = &self.proxy_pw {
+            values.push(("proxy-pw", pw.to_value()));
+        }
+        if !self.cookies.is_empty() {
+            values.push(("cookies", self.cookies.to_value()));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            values.push(("user-agent", user_agent.to_value()));
+        }
+        if self.insecure_tls {
+            values.push(("ssl-strict", false.to_value()));
+        }
+        if let Some(id) = &self.user_id {
+            values.push(("user-id", id.to_value()));
+        }
+        if let Some(pw) = &self.user_pw {
+            values.push(("user-pw", pw.to_value()));
+        }
+        values
+    }
+
+    /// playbinのsource-setupでソースに設定する。playbinがPlayingになる前に呼ぶ
+    pub fn apply(&self, playbin: &gst::Element) -> anyhow::Result<Auth> {
+        let auth = Auth {
+            ask: self.ask_credentials,
+            credentials: Arc::new(Mutex::new(Credentials::default())),
+            retries: AtomicU32::new(0),
+        };
+        if self.is_empty() && !self.ask_credentials {
+            return Ok(auth);
+        }
+        let opt = self.clone();
+        let credentials = auth.credentials.clone();
+        playbin.connect("source-setup", false, move |args| {
+            let source = args[1].get::<gst::Element>().unwrap();
+            // 尋ねて得た認証情報はオプションより優先する
+            let asked = credentials.lock().unwrap().values();
+            for (name, value) in opt.values().into_iter().chain(asked) {
+                if source.find_property(name).is_none() {
+                    log::warn!("{} has no {name} property", source.name());
+                    continue;
+                }
+                // パスワードはログに出さない
+                if !name.ends_with("-pw") {
+                    log::debug!("{}: {name}={value:?}", source.name());
+                }
+                source.set_property_from_value(name, &value);
+            }
+            None
+        });
+        Ok(auth)
+    }
+}
@@ -0,0 +1,277 @@
+//! マイクの音声をエンコードしてIcecast(Shoutcast)のサーバーに送り続ける、ネットラジオの配信元
+//!
+//! shout2sendはlibshoutでサーバーに繋ぎ、受け取ったOggかMP3のストリームをそのままマウントポイントに送る。
+//! コンテンツの種類はcapsで決まるので、エンコーダとmuxerを選ぶだけでよい。
+//! サーバーが落ちたりネットワークが切れたりするとshout2sendがエラーを投げるので、
+//! パイプラインごと作り直して--retryの秒数ごとに繋ぎ直す。ソースはライブなので、切れている間の音声は捨てる。
+//!
+//! 標準入力から1行読むたびに、それを今の曲名としてTITLEのタグイベントでshout2sendに送る。
+//! shout2sendはタグイベントを受けるとlibshoutのメタデータ(song)を更新する。
+//! IcecastがこのメタデータをリスナーのICYに載せるのはMP3のストリームだけで、
+//! Oggではストリームの中のコメントが使われるため、曲名の変更は反映されない。
+use std::{
+    fmt,
+    io::{self, BufRead},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// 送るストリームの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Opusをoggmuxに入れる
+    Ogg,
+    /// lamemp3encのCBR
+    Mp3,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ogg" => Ok(Self::Ogg),
+            "mp3" => Ok(Self::Mp3),
+            _ => anyhow::bail!("unknown format {s}, expected ogg|mp3"),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ogg => "ogg",
+            Self::Mp3 => "mp3",
+        })
+    }
+}
+
+impl Format {
+    /// エンコーダからshout2sendの手前まで。bitrateはkbps
+    fn launch(&self, bitrate: u32) -> String {
+        match self {
+            Self::Ogg => format!("opusenc bitrate={} ! oggmux", bitrate * 1000),
+            Self::Mp3 => format!("lamemp3enc target=bitrate cbr=true bitrate={bitrate}"),
+        }
+    }
+}
+
+/// 配信の設定
+#[derive(Debug)]
+pub struct IcecastOptions {
+    pub host: String,
+    pub port: u16,
+    /// マウントポイント。/radio.oggなど
+    pub mount: String,
+    pub username: String,
+    pub password: String,
+    /// ディレクトリに出す局の名前
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub genre: Option<String>,
+    pub format: Format,
+    /// kbps
+    pub bitrate: u32,
+    /// マイクの代わりにライブのテスト音を送る
+    pub test: bool,
+    /// 始めに付ける曲名
+    pub title: Option<String>,
+    /// 切れてから繋ぎ直すまで待つ時間
+    pub retry: Duration,
+}
+
+/// サーバーに送っているパイプライン
+struct Stream {
+    pipeline: gst::Pipeline,
+    shout: gst::Element,
+    bus: gst::Bus,
+    /// Playingになったか
+    playing: bool,
+    /// Playingになったら送る曲名
+    pending_title: Option<String>,
+}
+
+impl Stream {
+    fn start(options: &IcecastOptions, title: Option<String>) -> anyhow::Result<Self> {
+        let source = if options.test {
+            "audiotestsrc is-live=true wave=sine freq=440 volume=0.3"
+        } else {
+            "autoaudiosrc"
+        };
+        let pipeline = fallback::parse_launch(&format!(
+            "{source} ! audioconvert ! audioresample ! {encoder} ! shout2send name=shout",
+            encoder = options.format.launch(options.bitrate),
+        ))?
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let shout = pipeline.by_name("shout").context("shout")?;
+        shout.set_property("ip", &options.host);
+        shout.set_property("port", options.port as i32);
+        shout.set_property("mount", &options.mount);
+        shout.set_property("username", &options.username);
+        shout.set_property("password", &options.password);
+        for (property, value) in [
+            ("streamname", &options.name),
+            ("description", &options.description),
+            ("genre", &options.genre),
+        ] {
+            if let Some(value) = value {
+                shout.set_property(property, value);
+            }
+        }
+        let bus = pipeline.bus().context("bus")?;
+
+        if let Err(err) = pipeline.set_state(gst::State::Playing) {
+            let _ = pipeline.set_state(gst::State::Null);
+            anyhow::bail!("unable to set the pipeline to the `Playing` state: {err}");
+        }
+        log::info!(
+            "streaming {} to icecast://{}:{}{}",
+            options.format,
+            options.host,
+            options.port,
+            options.mount
+        );
+        Ok(Self {
+            pipeline,
+            shout,
+            bus,
+            playing: false,
+            pending_title: title,
+        })
+    }
+
+    /// 曲名をタグイベントでshout2sendに送る
+    fn set_title(&self, title: &str) {
+        let mut tags = gst::TagList::new();
+        tags.make_mut()
+            .add::<gst::tags::Title>(&title, gst::TagMergeMode::Replace);
+        let sent = self
+            .shout
+            .static_pad("sink")
+            .map(|pad| pad.send_event(gst::event::Tag::new(tags)))
+            .unwrap_or_default();
+        if sent {
+            log::info!("title: {title}");
+        } else {
+            log::warn!("failed to send the title {title:?}");
+        }
+    }
+
+    /// 曲名を変える。まだ流れ始めていなければPlayingになるまで取っておく
+    fn update_title(&mut self, title: &str) {
+        if self.playing {
+            self.set_title(title);
+        } else {
+            self.pending_title = Some(title.to_string());
+        }
+    }
+
+    /// バスを見て、止まっていればErr
+    fn poll(&mut self) -> anyhow::Result<()> {
+        while let Some(msg) = self.bus.pop() {
+            use gst::MessageView;
+            match msg.view() {
+                MessageView::Eos(_) => anyhow::bail!("the stream ended"),
+                MessageView::Error(err) => anyhow::bail!(
+                    "{} from {:?} ({:?})",
+                    err.error(),
+                    err.src().map(|s| s.path_string()),
+                    err.debug()
+                ),
+                // 繋がる前のタグイベントは捨てられるので、流れ始めてから送る
+                MessageView::StateChanged(state)
+                    if state.src().as_ref() == Some(self.pipeline.upcast_ref())
+                        && state.current() == gst::State::Playing =>
+                {
+                    self.playing = true;
+                    if let Some(title) = self.pending_title.take() {
+                        self.set_title(&title);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// 標準入力を1行ずつ別スレッドで受け取る
+fn read_lines() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().flatten() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// 'q'の行か Ctrl-C まで配信し続ける。それ以外の行は新しい曲名にする
+pub fn run(options: &IcecastOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(
+        options.mount.starts_with('/'),
+        "--mount must start with '/'"
+    );
+    anyhow::ensure!(options.bitrate > 0, "--bitrate must be positive");
+    if options.format == Format::Ogg {
+        log::warn!("Icecast shows title updates to listeners of MP3 streams only");
+    }
+    println!(
+        "\
+USAGE:
+ type a line and Enter to set the current song title
+ 'q' and Enter to stop"
+    );
+
+    let lines = read_lines();
+    let mut title = options.title.clone();
+    let mut stream: Option<Stream> = None;
+    let mut restart_at = Instant::now();
+    loop {
+        match lines.try_recv().as_deref().map(str::trim) {
+            Ok("q" | "Q") => break,
+            Ok("") | Err(_) => {}
+            Ok(line) => {
+                title = Some(line.to_string());
+                if let Some(stream) = &mut stream {
+                    stream.update_title(line);
+                }
+            }
+        }
+
+        if stream.is_none() && Instant::now() >= restart_at {
+            match Stream::start(options, title.clone()) {
+                Ok(started) => stream = Some(started),
+                Err(err) => {
+                    log::warn!("failed to start, retrying in {:?}: {err:#}", options.retry);
+                    restart_at = Instant::now() + options.retry;
+                }
+            }
+        }
+        if let Some(Err(err)) = stream.as_mut().map(Stream::poll) {
+            log::warn!("disconnected, reconnecting in {:?}: {err:#}", options.retry);
+            stream = None;
+            restart_at = Instant::now() + options.retry;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
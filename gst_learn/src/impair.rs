@@ -0,0 +1,126 @@
+//! netsimでパケットのロス、遅延、ジッタ、順序の入れ替え、重複を起こして悪いネットワークを真似る
+//!
+//! `loss=2%,jitter=30ms,reorder=1%`のように書く。書かなかった項目は何もしない。
+//!
+//! - loss: パケットを捨てる確率
+//! - delay: 全てのパケットに加える最小の遅延
+//! - jitter: delayに加える遅延の揺れ幅。遅延は一様分布で選ぶ
+//! - reorder: 遅延させるパケットの割合。遅延したパケットを後のパケットが追い越すので順序が入れ替わる
+//! - duplicate: パケットを2回送る確率
+//!
+//! netsimは遅延をパケットごとに独立に選ぶので、reorderを指定しなければ全てのパケットを遅延させ、
+//! ジッタがあればそれだけで順序も入れ替わる。
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+
+/// reorderだけ指定されたときに遅延させる最大の時間(ms)
+const REORDER_DELAY_MS: u32 = 30;
+
+/// netsimに設定するネットワークの劣化
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Impairment {
+    pub loss: f64,
+    /// ms
+    pub delay: u32,
+    /// ms
+    pub jitter: u32,
+    pub reorder: Option<f64>,
+    pub duplicate: f64,
+}
+
+/// "2%"か"0.02"の形式の確率
+fn parse_probability(s: &str) -> anyhow::Result<f64> {
+    let p = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>()? / 100.0,
+        None => s.parse::<f64>()?,
+    };
+    anyhow::ensure!((0.0..=1.0).contains(&p), "{s} is not between 0% and 100%");
+    Ok(p)
+}
+
+/// "30ms"か"30"の形式のミリ秒
+fn parse_ms(s: &str) -> anyhow::Result<u32> {
+    Ok(s.strip_suffix("ms").unwrap_or(s).parse::<u32>()?)
+}
+
+impl FromStr for Impairment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut impairment = Self::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("invalid impairment {item}, expected KEY=VALUE"))?;
+            let invalid = || format!("invalid {key} {value}");
+            match key {
+                "loss" => impairment.loss = parse_probability(value).with_context(invalid)?,
+                "delay" => impairment.delay = parse_ms(value).with_context(invalid)?,
+                "jitter" => impairment.jitter = parse_ms(value).with_context(invalid)?,
+                "reorder" => {
+                    impairment.reorder = Some(parse_probability(value).with_context(invalid)?)
+                }
+                "duplicate" => {
+                    impairment.duplicate = parse_probability(value).with_context(invalid)?
+                }
+                _ => anyhow::bail!(
+                    "unknown impairment {key}, expected loss|delay|jitter|reorder|duplicate"
+                ),
+            }
+        }
+        Ok(impairment)
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loss={}%,delay={}ms,jitter={}ms",
+            self.loss * 100.0,
+            self.delay,
+            self.jitter
+        )?;
+        if let Some(reorder) = self.reorder {
+            write!(f, ",reorder={}%", reorder * 100.0)?;
+        }
+        write!(f, ",duplicate={}%", self.duplicate * 100.0)
+    }
+}
+
+impl Impairment {
+    /// netsimのプロパティ。gst-launchの書式でエレメントの後に並べられる
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = vec![
+            ("drop-probability", self.loss.to_string()),
+            ("duplicate-probability", self.duplicate.to_string()),
+        ];
+        if self.delay > 0 || self.jitter > 0 || self.reorder.is_some() {
+            // reorderだけ指定されたときも、追い越されるだけの遅延は要る
+            let jitter = if self.jitter == 0 && self.reorder.is_some() {
+                REORDER_DELAY_MS
+            } else {
+                self.jitter
+            };
+            properties.extend([
+                ("delay-distribution", "uniform".to_string()),
+                ("min-delay", self.delay.to_string()),
+                ("max-delay", (self.delay + jitter).to_string()),
+                ("delay-probability", self.reorder.unwrap_or(1.0).to_string()),
+            ]);
+        }
+        properties
+    }
+
+    /// gst-launchの書式のnetsim。パイプラインの記述に`! `で繋いで使う
+    pub fn launch(&self) -> anyhow::Result<String> {
+        gst::ElementFactory::find("netsim")
+            .context("netsim (gst-plugins-bad) is required for --impair")?;
+        let mut description = String::from("netsim");
+        for (name, value) in self.properties() {
+            description.push_str(&format!(" {name}={value}"));
+        }
+        Ok(description)
+    }
+}
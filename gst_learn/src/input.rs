@@ -0,0 +1,212 @@
+//! 再生操作のキー割り当て
+//!
+//! キー入力そのものはkeyboardモジュールで読み、ここではキーを再生操作のコマンドに変換する。
+//! 割り当てはTOMLで変更できる。書いたコマンドだけが置き換わり、書かなかったものは既定のまま残る。
+//!
+//! ```toml
+//! play_pause = ["space", "p"]
+//! rate_up = "+"
+//! rate_down = "-"
+//! reverse = "r"
+//! next_frame = ["right", "n"]
+//! quit = ["q", "ctrl-c"]
+//! ```
+//!
+//! キーは1文字か、`ctrl-<文字>`、`space`、`enter`、`tab`、`backspace`、`esc`、`up`、`down`、`left`、`right`で書く。
+use std::{fs, path::Path};
+
+use anyhow::Context;
+
+use crate::keyboard::{Key, Keyboard};
+
+/// 再生操作のコマンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    PlayPause,
+    DataRateUp,
+    DataRateDown,
+    ReverseRate,
+    NextFrame,
+    Quit,
+}
+
+impl Command {
+    const ALL: [Command; 6] = [
+        Command::PlayPause,
+        Command::DataRateUp,
+        Command::DataRateDown,
+        Command::ReverseRate,
+        Command::NextFrame,
+        Command::Quit,
+    ];
+
+    /// TOMLとセッションの記録でのコマンド名
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::PlayPause => "play_pause",
+            Command::DataRateUp => "rate_up",
+            Command::DataRateDown => "rate_down",
+            Command::ReverseRate => "reverse",
+            Command::NextFrame => "next_frame",
+            Command::Quit => "quit",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Command::PlayPause => "to toggle between PAUSE and PLAY",
+            Command::DataRateUp => "to increase playback speed",
+            Command::DataRateDown => "to decrease playback speed",
+            Command::ReverseRate => "to toggle playback direction",
+            Command::NextFrame => {
+                "to move to next frame (in the current direction, better in PAUSE)"
+            }
+            Command::Quit => "to quit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|command| command.name() == name)
+    }
+}
+
+/// キーの書き方を解釈する
+fn parse_key(s: &str) -> anyhow::Result<Key> {
+    let mut chars = s.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Char(c));
+    }
+    let lower = s.to_ascii_lowercase();
+    if let Some(c) = lower.strip_prefix("ctrl-") {
+        let mut chars = c.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(Key::Ctrl(c));
+        }
+    }
+    Ok(match lower.as_str() {
+        "space" => Key::Char(' '),
+        "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "backspace" => Key::Backspace,
+        "esc" => Key::Esc,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        _ => anyhow::bail!("unknown key {s:?}"),
+    })
+}
+
+/// キーの表示
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Char(' ') => "space".to_string(),
+        Key::Char('\n') => "enter".to_string(),
+        Key::Char('\t') => "tab".to_string(),
+        Key::Char(c) => format!("'{c}'"),
+        Key::Ctrl(c) => format!("ctrl-{c}"),
+        key => format!("{key:?}").to_ascii_lowercase(),
+    }
+}
+
+/// キーとコマンドの対応
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(Key, Command)>,
+}
+
+impl Default for Keymap {
+    /// basic tutorial 13と同じ割り当て
+    fn default() -> Self {
+        use Command::*;
+        Self {
+            bindings: vec![
+                (Key::Char('p'), PlayPause),
+                (Key::Char('P'), PlayPause),
+                (Key::Char('S'), DataRateUp),
+                (Key::Char('s'), DataRateDown),
+                (Key::Char('d'), ReverseRate),
+                (Key::Char('D'), ReverseRate),
+                (Key::Char('n'), NextFrame),
+                (Key::Char('N'), NextFrame),
+                (Key::Char('q'), Quit),
+                (Key::Char('Q'), Quit),
+                (Key::Ctrl('c'), Quit),
+            ],
+        }
+    }
+}
+
+impl Keymap {
+    /// TOMLの割り当てで既定の割り当てを上書きする
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        let table = s.parse::<toml::Value>()?;
+        let table = table.as_table().context("keymap must be a table")?;
+
+        let mut keymap = Self::default();
+        for (name, keys) in table {
+            let command = Command::from_name(name)
+                .with_context(|| format!("unknown command {name:?} in keymap"))?;
+            let keys = match keys {
+                toml::Value::String(key) => vec![parse_key(key)?],
+                toml::Value::Array(keys) => keys
+                    .iter()
+                    .map(|key| {
+                        key.as_str()
+                            .with_context(|| format!("{name}: key must be a string"))
+                            .and_then(parse_key)
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+                _ => anyhow::bail!("{name}: expected a key or an array of keys"),
+            };
+            keymap.bindings.retain(|(_, c)| *c != command);
+            for key in keys {
+                // 他のコマンドに割り当てられていたキーは奪う
+                keymap.bindings.retain(|(k, _)| *k != key);
+                keymap.bindings.push((key, command));
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn command(&self, key: Key) -> Option<Command> {
+        self.bindings
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, command)| *command)
+    }
+
+    /// 操作の説明を割り当てから作る
+    pub fn usage(&self) -> String {
+        let mut usage = String::from("USAGE: Choose one of the following options:");
+        for command in Command::ALL {
+            let keys = self
+                .bindings
+                .iter()
+                .filter(|(_, c)| *c == command)
+                .map(|(key, _)| key_name(*key))
+                .collect::<Vec<_>>();
+            if !keys.is_empty() {
+                usage.push_str(&format!("\n {} {}", keys.join(", "), command.description()));
+            }
+        }
+        usage
+    }
+
+    /// キー入力を待ち、コマンドに変換して渡す。Quitを渡すか入力が閉じたら戻る
+    pub fn run(&self, keyboard: &Keyboard, mut f: impl FnMut(Command)) {
+        while let Some(key) = keyboard.key() {
+            if let Some(command) = self.command(key) {
+                f(command);
+                if command == Command::Quit {
+                    break;
+                }
+            }
+        }
+    }
+}
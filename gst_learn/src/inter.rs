@@ -0,0 +1,138 @@
+//! intervideosink/intervideosrcとproxysink/proxysrcでパイプラインを繋ぐ例
+//!
+//! appsink/appsrc(Bridge)ではアプリケーションがサンプルを受け渡したが、これらはエレメント同士で直接受け渡す。
+//!
+//! inter系はチャンネル名で繋がり、受け側は自分のクロックで一定間隔にフレームを出す。
+//! 送り側が遅れれば同じフレームを繰り返し、速ければ間引くので、クロックやbase-timeが別でも動くが、
+//! 送り側のタイムスタンプやレイテンシは受け側には伝わらない。
+//!
+//! proxy系はバッファ、イベント、クエリをそのまま転送する。受け側のレイテンシクエリも送り側まで届くが、
+//! タイムスタンプもそのまま渡るので、両方のパイプラインで同じクロックとbase-timeを使う必要がある。
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+fn launch(description: &str) -> anyhow::Result<gst::Pipeline> {
+    Ok(fallback::parse_launch(description)?
+        .downcast::<gst::Pipeline>()
+        .unwrap())
+}
+
+/// 送り側と受け側を作る
+fn inter_pipelines() -> anyhow::Result<(gst::Pipeline, gst::Pipeline)> {
+    // x264encのレイテンシを入れておき、受け側にレイテンシが伝わらないことを確かめる
+    let producer = launch(
+        "videotestsrc is-live=true pattern=ball ! timeoverlay ! videoconvert \
+         ! x264enc tune=zerolatency ! avdec_h264 ! videoconvert \
+         ! intervideosink channel=gst-learn",
+    )?;
+    let consumer = launch(
+        "intervideosrc channel=gst-learn timeout=1000000000 ! videoconvert ! autovideosink",
+    )?;
+    Ok((producer, consumer))
+}
+
+fn proxy_pipelines() -> anyhow::Result<(gst::Pipeline, gst::Pipeline)> {
+    let producer = launch(
+        "videotestsrc is-live=true pattern=ball ! timeoverlay ! videoconvert \
+         ! x264enc tune=zerolatency ! avdec_h264 ! videoconvert \
+         ! proxysink name=proxysink",
+    )?;
+    let consumer = launch("proxysrc name=proxysrc ! videoconvert ! autovideosink")?;
+
+    let proxysink = producer.by_name("proxysink").context("proxysink")?;
+    let proxysrc = consumer.by_name("proxysrc").context("proxysrc")?;
+    proxysrc.set_property("proxysink", &proxysink);
+
+    // タイムスタンプがそのまま渡るので、受け側のクロックとbase-timeを送り側に揃える
+    // start-timeをNONEにすると、状態遷移のたびにbase-timeを選び直さなくなる
+    let clock = gst::SystemClock::obtain();
+    producer.use_clock(Some(&clock));
+    consumer.use_clock(Some(&clock));
+    let base_time = clock.time().context("clock time")?;
+    for pipeline in [&producer, &consumer] {
+        pipeline.set_start_time(gst::ClockTime::NONE);
+        pipeline.set_base_time(base_time);
+    }
+
+    Ok((producer, consumer))
+}
+
+/// パイプラインのレイテンシを問い合わせてログに出す
+fn log_latency(name: &str, pipeline: &gst::Pipeline) {
+    let mut query = gst::query::Latency::new();
+    if pipeline.query(&mut query) {
+        let (live, min, max) = query.result();
+        log::info!("{name}: live={live} min={min} max={}", max.display());
+    } else {
+        log::info!("{name}: latency query failed");
+    }
+}
+
+fn check_bus(bus: &gst::Bus) -> bool {
+    while let Some(msg) = bus.pop() {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => return true,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// 2つのパイプラインを繋いでdurationの間動かし、レイテンシとbase-timeを比較する
+pub fn run(proxy: bool, duration: Duration) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let (producer, consumer) = if proxy {
+        proxy_pipelines()?
+    } else {
+        inter_pipelines()?
+    };
+
+    producer
+        .set_state(gst::State::Playing)
+        .context("Unable to set the producer to the `Playing` state")?;
+    consumer
+        .set_state(gst::State::Playing)
+        .context("Unable to set the consumer to the `Playing` state")?;
+
+    let producer_bus = producer.bus().context("producer bus")?;
+    let consumer_bus = consumer.bus().context("consumer bus")?;
+    let started = Instant::now();
+    let mut reported = false;
+    while started.elapsed() < duration {
+        if check_bus(&producer_bus) || check_bus(&consumer_bus) {
+            break;
+        }
+        // Playingになって少し経ってからレイテンシが確定する
+        if !reported && started.elapsed() > Duration::from_secs(1) {
+            reported = true;
+            log_latency("producer", &producer);
+            log_latency("consumer", &consumer);
+            log::info!(
+                "base-time producer={} consumer={}",
+                producer.base_time().display(),
+                consumer.base_time().display()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    consumer.set_state(gst::State::Null)?;
+    producer.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! 端末からのキー入力
+//!
+//! crosstermで端末をrawモードにしてキーのイベントを1つずつ読み、このクレートで使うKeyにして返す。
+//! crosstermはLinux、macOS、Windowsの端末で同じように動く。
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// キー入力のうち、このクレートで使うもの
+/// Enterは`Char('\n')`、Tabは`Char('\t')`になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Backspace,
+    Esc,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// カーソルのある行を消すエスケープシーケンス
+pub const CLEAR_LINE: &str = "\x1b[2K";
+
+/// rawモードの間保持する。dropすると端末の設定が元に戻る
+pub struct RawMode {
+    _private: (),
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+}
+
+/// 端末をrawモードにする
+pub fn raw_mode() -> io::Result<RawMode> {
+    crossterm::terminal::enable_raw_mode()?;
+    Ok(RawMode { _private: () })
+}
+
+/// crosstermのキーのイベントをKeyにする。離したときのイベントと使わないキーはNone
+fn convert(event: crossterm::event::KeyEvent) -> Option<Key> {
+    use crossterm::event::{KeyCode, KeyEventKind, KeyModifiers};
+
+    if event.kind == KeyEventKind::Release {
+        return None;
+    }
+    Some(match event.code {
+        KeyCode::Char(c) if event.modifiers.contains(KeyModifiers::CONTROL) => {
+            Key::Ctrl(c.to_ascii_lowercase())
+        }
+        KeyCode::Char(c) => Key::Char(c),
+        KeyCode::Enter => Key::Char('\n'),
+        KeyCode::Tab => Key::Char('\t'),
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Esc => Key::Esc,
+        KeyCode::Up => Key::Up,
+        KeyCode::Down => Key::Down,
+        KeyCode::Left => Key::Left,
+        KeyCode::Right => Key::Right,
+        _ => return None,
+    })
+}
+
+/// 標準入力のキーを順に返す。キーが来るまでブロックする
+pub fn keys() -> impl Iterator<Item = io::Result<Key>> {
+    use crossterm::event::{self, Event};
+
+    std::iter::from_fn(|| loop {
+        match event::read() {
+            Ok(Event::Key(event)) => {
+                if let Some(key) = convert(event) {
+                    return Some(Ok(key));
+                }
+            }
+            Ok(_) => {}
+            Err(err) => return Some(Err(err)),
+        }
+    })
+}
+
+/// エコーせずにパスワードを1行読む。rawモードにしてキーを1つずつ読む
+/// Ctrl-CかCtrl-DでNoneを返す
+pub fn read_password(prompt: &str) -> io::Result<Option<String>> {
+    use std::io::Write;
+
+    let mut stdout = io::stdout();
+    write!(stdout, "{prompt}")?;
+    stdout.flush()?;
+    let _raw = raw_mode()?;
+    let mut password = String::new();
+    for key in keys() {
+        match key? {
+            Key::Char('\n') => break,
+            Key::Char(c) => password.push(c),
+            Key::Backspace => {
+                password.pop();
+            }
+            Key::Ctrl('c' | 'd') => {
+                write!(stdout, "\r\n")?;
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+    write!(stdout, "\r\n")?;
+    Ok(Some(password))
+}
+
+/// Keyboardの読み取りスレッドが、止めるように言われていないかを見る間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// stopが立つまでキーを読んでtxに送る
+/// 読み終わった後に標準入力を読み続けないように、キーを待つのはPOLL_INTERVALずつにする
+fn forward_keys(tx: mpsc::Sender<Key>, stop: &AtomicBool) {
+    use crossterm::event::{self, Event};
+
+    while !stop.load(Ordering::Relaxed) {
+        // 読めなくなったら(端末でない、閉じたなど)終わる
+        match event::poll(POLL_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(_) => break,
+        }
+        match event::read() {
+            Ok(Event::Key(event)) => {
+                if let Some(key) = convert(event) {
+                    if tx.send(key).is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// 端末をrawモードにして、キー入力を別スレッドで受け取る
+/// dropするとスレッドを止めて待ち、端末の設定を元に戻す。その後の標準入力はread_passwordなどで読める
+pub struct Keyboard {
+    rx: mpsc::Receiver<Key>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    _raw: RawMode,
+}
+
+impl Keyboard {
+    pub fn new() -> io::Result<Self> {
+        let raw = raw_mode()?;
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let thread = thread::spawn(move || forward_keys(tx, &stop_clone));
+        Ok(Self {
+            rx,
+            stop,
+            thread: Some(thread),
+            _raw: raw,
+        })
+    }
+
+    /// 押されたキーがあれば返す
+    pub fn try_key(&self) -> Option<Key> {
+        self.rx.try_recv().ok()
+    }
+
+    /// キーが押されるまで待つ。入力が閉じたらNone
+    pub fn key(&self) -> Option<Key> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! サイネージ向けに、短いクリップを途切れなく繰り返し再生し続ける
+//!
+//! 終わりでEOSを受けてから先頭にシークすると、パイプラインが一度空になって黒い画面や音の途切れが入る。
+//! 最初のシークにSEGMENTを付けると、最後まで流れたときにEOSの代わりにSEGMENT_DONEが来る。
+//! そこでFLUSHを付けずに先頭へSEGMENTのシークをすると、sinkに溜まっているデータを捨てずに次の周が後ろに続くので、
+//! 継ぎ目なく繰り返せる。
+//!
+//! 人が見ていない状態で動かし続けるので、次の場合はパイプラインを作り直して--retryの秒数後に再開する。
+//! - エラーが来たとき
+//! - 再生位置が--stallの秒数の間変わらないとき(デコーダやsinkが止まったとき)
+//!
+//! --scheduleを指定すると、その時間帯(ローカル時刻)だけ再生し、それ以外はNullにして止めておく。
+//! 日をまたぐ時間帯(22:00-06:00など)も指定できる。
+use std::{
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::runner;
+
+/// 再生する時間帯。startからendの手前まで
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// 0時からの分
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let (hour, minute) = s
+        .split_once(':')
+        .with_context(|| format!("expected HH:MM, got {s:?}"))?;
+    let hour = hour.trim().parse::<u32>()?;
+    let minute = minute.trim().parse::<u32>()?;
+    anyhow::ensure!(hour <= 24 && minute < 60, "invalid time {s:?}");
+    anyhow::ensure!(hour < 24 || minute == 0, "invalid time {s:?}");
+    Ok(hour * 60 + minute)
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .with_context(|| format!("expected HH:MM-HH:MM, got {s:?}"))?;
+        let schedule = Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        anyhow::ensure!(schedule.start != schedule.end, "empty schedule {s:?}");
+        Ok(schedule)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl Schedule {
+    /// 0時からの分がこの時間帯に入るか
+    fn contains(&self, minutes: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minutes)
+        } else {
+            // 日をまたぐ
+            minutes >= self.start || minutes < self.end
+        }
+    }
+
+    fn contains_now(&self) -> anyhow::Result<bool> {
+        let now = glib::DateTime::now_local()?;
+        Ok(self.contains((now.hour() * 60 + now.minute()) as u32))
+    }
+}
+
+/// キオスクの設定
+#[derive(Debug)]
+pub struct KioskOptions {
+    pub path: PathBuf,
+    /// 再生する時間帯。Noneならずっと再生する
+    pub schedule: Option<Schedule>,
+    /// 再生位置がこの間変わらなければ止まったとみなす
+    pub stall: Duration,
+    /// 止まってから作り直すまで待つ時間
+    pub retry: Duration,
+    /// 状態の変更を待つ時間
+    pub state_timeout: Option<gst::ClockTime>,
+}
+
+/// ループ再生しているplaybin
+struct Player {
+    pipeline: gst::Pipeline,
+    bus: gst::Bus,
+    loops: u64,
+    last_position: Option<gst::ClockTime>,
+    /// 最後に再生位置が変わった時刻
+    progressed_at: Instant,
+}
+
+impl Player {
+    fn start(uri: &str, timeout: Option<gst::ClockTime>) -> anyhow::Result<Self> {
+        let pipeline = gst::ElementFactory::make("playbin", None)?
+            .downcast::<gst::Pipeline>()
+            .unwrap();
+        pipeline.set_property("uri", uri);
+        let bus = pipeline.bus().context("bus")?;
+
+        // SEGMENTのシークはPausedでプリロールしてから先頭に向けて出す
+        runner::set_state(&pipeline, gst::State::Paused, timeout)?;
+        if let Err(err) = pipeline.seek_simple(
+            gst::SeekFlags::FLUSH | gst::SeekFlags::SEGMENT,
+            gst::ClockTime::ZERO,
+        ) {
+            runner::rollback(&pipeline);
+            return Err(err).context("the clip is not seekable");
+        }
+        runner::set_state(&pipeline, gst::State::Playing, timeout)?;
+        log::info!("looping {uri}");
+        Ok(Self {
+            pipeline,
+            bus,
+            loops: 0,
+            last_position: None,
+            progressed_at: Instant::now(),
+        })
+    }
+
+    /// バスを見てループを続け、止まっていればErr
+    fn poll(&mut self, stall: Duration) -> anyhow::Result<()> {
+        let mut msg = self.bus.timed_pop(100 * gst::ClockTime::MSECOND);
+        while let Some(message) = msg {
+            use gst::MessageView;
+            match message.view() {
+                MessageView::SegmentDone(_) => {
+                    // FLUSHを付けないので、溜まっているデータの後ろに次の周が続く
+                    self.pipeline
+                        .seek_simple(gst::SeekFlags::SEGMENT, gst::ClockTime::ZERO)
+                        .context("failed to loop")?;
+                    self.loops += 1;
+                    log::debug!("loop {}", self.loops);
+                }
+                MessageView::Eos(_) => anyhow::bail!("unexpected EOS after {} loops", self.loops),
+                MessageView::Error(err) => anyhow::bail!(
+                    "{} from {:?} ({:?})",
+                    err.error(),
+                    err.src().map(|s| s.path_string()),
+                    err.debug()
+                ),
+                _ => {}
+            }
+            msg = self.bus.pop();
+        }
+
+        let position = self.pipeline.query_position::<gst::ClockTime>();
+        if position != self.last_position {
+            self.last_position = position;
+            self.progressed_at = Instant::now();
+        } else if self.progressed_at.elapsed() > stall {
+            anyhow::bail!(
+                "playback stalled at {} for {:?}",
+                position.display(),
+                self.progressed_at.elapsed()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        runner::rollback(&self.pipeline);
+    }
+}
+
+/// 時間帯の間クリップを繰り返し再生し、止まったら作り直す。Ctrl-Cで終わる
+pub fn run(options: &KioskOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let path = options
+        .path
+        .canonicalize()
+        .with_context(|| format!("{}", options.path.display()))?;
+    let uri = glib::filename_to_uri(&path, None)?;
+    if let Some(schedule) = options.schedule {
+        log::info!("playing between {schedule}");
+    }
+
+    let mut player: Option<Player> = None;
+    let mut restart_at = Instant::now();
+    loop {
+        let active = match options.schedule {
+            Some(schedule) => schedule.contains_now()?,
+            None => true,
+        };
+        if !active {
+            if player.take().is_some() {
+                log::info!("outside the schedule, stopped");
+            }
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        if player.is_none() && Instant::now() >= restart_at {
+            match Player::start(&uri, options.state_timeout) {
+                Ok(started) => player = Some(started),
+                Err(err) => {
+                    log::warn!("failed to start, retrying in {:?}: {err:#}", options.retry);
+                    restart_at = Instant::now() + options.retry;
+                }
+            }
+        }
+        match player.as_mut().map(|player| player.poll(options.stall)) {
+            Some(Ok(())) => {}
+            Some(Err(err)) => {
+                log::warn!("restarting in {:?}: {err:#}", options.retry);
+                player = None;
+                restart_at = Instant::now() + options.retry;
+            }
+            None => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! 1つの映像入力から解像度とビットレートの異なる複数のエンコード(レンディション)を作る
+//!
+//! teeで分岐し、レンディションごとにvideoscaleとx264encのブランチを作って1つのbinにまとめる。
+//! binのsink padに映像を繋ぎ、レンディションごとのsrc padをHLS、DASH、SRTなどの出力に繋いで使う。
+//! 作る前に、エンコーダがその解像度を受け付けるか、ビットレートがプロパティの範囲内かを確かめる。
+//! 各ブランチのエンコーダの出力にbandwidth::Meterを付けて、実際に出ているビットレートを報告できる。
+use std::{str::FromStr, sync::Arc, thread, time::Duration};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{bandwidth::Meter, fallback};
+
+const ENCODER: &str = "x264enc";
+
+/// 1つのレンディションの解像度とビットレート
+#[derive(Debug, Clone, Copy)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+    /// kbps
+    pub bitrate: u32,
+}
+
+impl FromStr for Rendition {
+    type Err = anyhow::Error;
+
+    /// "1280x720@3000"の形式
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || -> Option<Self> {
+            let (size, bitrate) = s.split_once('@')?;
+            let (width, height) = size.split_once('x')?;
+            Some(Self {
+                width: width.parse().ok()?,
+                height: height.parse().ok()?,
+                bitrate: bitrate.parse().ok()?,
+            })
+        };
+        parse().with_context(|| format!("invalid rendition {s}, expected WIDTHxHEIGHT@KBPS"))
+    }
+}
+
+impl std::fmt::Display for Rendition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}@{}", self.width, self.height, self.bitrate)
+    }
+}
+
+/// レンディションが指定されなかったときのラダー
+pub const DEFAULT_LADDER: [Rendition; 3] = [
+    Rendition {
+        width: 1280,
+        height: 720,
+        bitrate: 3000,
+    },
+    Rendition {
+        width: 854,
+        height: 480,
+        bitrate: 1500,
+    },
+    Rendition {
+        width: 640,
+        height: 360,
+        bitrate: 800,
+    },
+];
+
+/// エンコーダがレンディションを扱えるかを確かめる
+fn validate(rendition: &Rendition, encoder: &gst::Element) -> anyhow::Result<()> {
+    let Rendition { width, height, .. } = *rendition;
+    // 4:2:0にするので幅と高さは偶数でなければならない
+    anyhow::ensure!(
+        width > 0 && height > 0 && width % 2 == 0 && height % 2 == 0,
+        "{rendition}: width and height must be even and non-zero"
+    );
+
+    let caps = gst::Caps::builder("video/x-raw")
+        .field("width", width as i32)
+        .field("height", height as i32)
+        .build();
+    let template = encoder
+        .static_pad("sink")
+        .context("encoder sink pad")?
+        .pad_template_caps();
+    // x264encがなければfallbackが別のエンコーダに置き換えている
+    let factory = encoder
+        .factory()
+        .map(|f| f.name().to_string())
+        .unwrap_or_else(|| ENCODER.to_string());
+    anyhow::ensure!(
+        template.can_intersect(&caps),
+        "{rendition}: {factory} does not accept {width}x{height}"
+    );
+
+    let pspec = encoder
+        .find_property("bitrate")
+        .and_then(|pspec| pspec.downcast::<glib::ParamSpecUInt>().ok())
+        .context("encoder without bitrate")?;
+    anyhow::ensure!(
+        (pspec.minimum()..=pspec.maximum()).contains(&rendition.bitrate),
+        "{rendition}: bitrate must be in {}..={} kbps",
+        pspec.minimum(),
+        pspec.maximum()
+    );
+    Ok(())
+}
+
+/// teeの後ろに付ける1つのレンディションのブランチ
+/// エンコーダの出力を測るMeterも返す
+fn branch(rendition: &Rendition, key_int_max: u32) -> anyhow::Result<(gst::Bin, Arc<Meter>)> {
+    let Rendition {
+        width,
+        height,
+        bitrate,
+    } = rendition;
+    let bin = fallback::parse_bin_from_description(
+        &format!(
+            "queue ! videoscale ! videoconvert \
+             ! video/x-raw,width={width},height={height},pixel-aspect-ratio=1/1 \
+             ! {ENCODER} name=encoder tune=zerolatency bitrate={bitrate} key-int-max={key_int_max} \
+             ! h264parse"
+        ),
+        true,
+    )?;
+    let encoder = bin.by_name("encoder").context("encoder")?;
+    validate(rendition, &encoder)?;
+    let meter = Meter::attach_pad(&encoder.static_pad("src").context("encoder src pad")?);
+    Ok((bin, meter))
+}
+
+/// 複数のレンディションを作るbin
+#[derive(Debug)]
+pub struct Ladder {
+    bin: gst::Bin,
+    outputs: Vec<(Rendition, gst::Pad, Arc<Meter>)>,
+}
+
+impl Ladder {
+    /// レンディションごとのブランチを作る
+    /// 出力の切り替えはキーフレームで起きるので、全てのブランチでkey_int_maxを揃える
+    pub fn new(renditions: &[Rendition], key_int_max: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(!renditions.is_empty(), "no renditions");
+        let bin = fallback::parse_bin_from_description("queue ! tee name=tee", true)?;
+        let tee = bin.by_name("tee").context("tee")?;
+
+        let mut outputs = Vec::new();
+        for rendition in renditions {
+            let (branch, meter) = branch(rendition, key_int_max)?;
+            bin.add(&branch)?;
+            tee.request_pad_simple("src_%u")
+                .context("tee src pad")?
+                .link(&branch.static_pad("sink").context("sink pad")?)?;
+            let src = gst::GhostPad::with_target(
+                Some(&format!("src_{}", outputs.len())),
+                &branch.static_pad("src").context("src pad")?,
+            )?;
+            bin.add_pad(&src)?;
+            outputs.push((*rendition, src.upcast(), meter));
+        }
+        Ok(Self { bin, outputs })
+    }
+
+    /// 生の映像を繋ぐpad
+    pub fn sink_pad(&self) -> gst::Pad {
+        self.bin.static_pad("sink").unwrap()
+    }
+
+    /// レンディションごとのH.264を出すpad
+    pub fn outputs(&self) -> impl Iterator<Item = (&Rendition, &gst::Pad)> {
+        self.outputs
+            .iter()
+            .map(|(rendition, pad, _)| (rendition, pad))
+    }
+
+    /// パイプラインに加え、出力をそれぞれsinkのrequest padに繋ぐ
+    pub fn link_to(
+        &self,
+        pipeline: &gst::Pipeline,
+        sink: &gst::Element,
+        template: &str,
+    ) -> anyhow::Result<()> {
+        pipeline.add(&self.bin)?;
+        for (rendition, pad) in self.outputs() {
+            let sink_pad = sink
+                .request_pad_simple(template)
+                .with_context(|| format!("{} has no {template} pad", sink.name()))?;
+            pad.link(&sink_pad)?;
+            log::info!("{rendition} -> {}", sink_pad.name());
+        }
+        self.bin.sync_state_with_parent()?;
+        Ok(())
+    }
+
+    /// interval毎にスループットを出力するスレッドを開始する。binが破棄されたら終わる
+    pub fn report_every(&self, interval: Duration) {
+        let bin = self.bin.downgrade();
+        let meters = self
+            .outputs
+            .iter()
+            .map(|(rendition, _, meter)| (*rendition, meter.clone()))
+            .collect::<Vec<_>>();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if bin.upgrade().is_none() {
+                break;
+            }
+            let line = meters
+                .iter()
+                .map(|(rendition, meter)| match meter.bytes_per_sec() {
+                    Some(b) => format!("{rendition}: {:.0}kbps", b * 8.0 / 1000.0),
+                    None => format!("{rendition}: -"),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            log::info!("{line}");
+        });
+    }
+}
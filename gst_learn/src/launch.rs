@@ -0,0 +1,239 @@
+//! コードで組み立てたパイプラインをgst-launch-1.0の記述に戻す
+//!
+//! binの子を上流から順に並べ、既定値から変わったプロパティと、リンクを書き出す。
+//! 1本道で繋がっている間は`a ! b ! c`とつなげ、teeやmuxのような分岐と合流は`name.pad ! name.pad`で書く。
+//! playbinやdecodebinのように自分で子を作るbinは、中身を書かずにエレメントとして扱う。
+//! 自分で組み立てたbinは`bin.( ... )`で中身ごと書く。
+//!
+//! オブジェクトを値に持つプロパティ(video-sinkなど)は文字列にできないので書かない。
+//! 出力はそのままgst-launch-1.0に渡して同じ形のパイプラインを作り直せることを目指すが、
+//! アプリケーションが付けたpad probeやシグナルは再現できない。
+use std::collections::HashSet;
+
+use gst::prelude::*;
+
+/// 中身を書くbinのファクトリ名。ファクトリのないbin(gst::Bin::newで作ったもの)も中身を書く
+const CONTAINER_FACTORIES: &[&str] = &["bin", "pipeline"];
+/// 書かないプロパティ。nameは別に書く
+const SKIPPED_PROPERTIES: &[&str] = &["name", "parent"];
+
+/// binの中身をgst-launch-1.0の記述にする
+pub fn describe(bin: &gst::Bin) -> String {
+    // iterate_sortedはsinkから順に返すので、逆にして上流から並べる
+    let mut elements = bin
+        .iterate_sorted()
+        .into_iter()
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    elements.reverse();
+
+    let mut parts = Vec::new();
+    let mut written = HashSet::new();
+    let mut chained = HashSet::new();
+    for element in &elements {
+        if !written.insert(element.name().to_string()) {
+            continue;
+        }
+        let mut chain = declaration(element);
+        let mut current = element.clone();
+        while let Some((src_pad, next)) = single_link(bin, &current) {
+            if !written.insert(next.name().to_string()) {
+                break;
+            }
+            chain.push_str(" ! ");
+            chain.push_str(&declaration(&next));
+            chained.insert(pad_ref(&src_pad));
+            current = next;
+        }
+        parts.push(chain);
+    }
+
+    // 1本道に入らなかったリンクは、padの名前で繋ぐ
+    for element in &elements {
+        for src_pad in element.src_pads() {
+            if chained.contains(&pad_ref(&src_pad)) {
+                continue;
+            }
+            let sink_pad = match src_pad.peer() {
+                Some(peer) if is_child(bin, &peer) => peer,
+                _ => continue,
+            };
+            parts.push(format!("{} ! {}", pad_ref(&src_pad), pad_ref(&sink_pad)));
+        }
+    }
+    parts.join(" ")
+}
+
+/// 中身を書くbinならtrue
+fn is_container(element: &gst::Element) -> bool {
+    element.is::<gst::Bin>()
+        && element
+            .factory()
+            .map(|f| CONTAINER_FACTORIES.contains(&f.name().as_str()))
+            .unwrap_or(true)
+}
+
+/// padの持ち主がbinの直接の子ならtrue
+fn is_child(bin: &gst::Bin, pad: &gst::Pad) -> bool {
+    pad.parent_element()
+        .and_then(|element| element.parent())
+        .map(|parent| &parent == bin.upcast_ref::<gst::Object>())
+        .unwrap_or(false)
+}
+
+/// src padもsink padも1つずつで、そのまま`!`で繋げられる次のエレメント
+fn single_link(bin: &gst::Bin, element: &gst::Element) -> Option<(gst::Pad, gst::Element)> {
+    let src_pads = element.src_pads();
+    if src_pads.len() != 1 {
+        return None;
+    }
+    let src_pad = &src_pads[0];
+    let sink_pad = src_pad.peer().filter(|peer| is_child(bin, peer))?;
+    let next = sink_pad.parent_element()?;
+    (next.sink_pads().len() == 1).then(|| (src_pad.clone(), next))
+}
+
+/// リンクで使うpadの書き方
+/// 自分で組み立てたbinのghost padは作り直したときに名前が変わるので、binの名前だけにして繋ぎ先を任せる
+fn pad_ref(pad: &gst::Pad) -> String {
+    let element = match pad.parent_element() {
+        Some(element) => element,
+        None => return pad.name().to_string(),
+    };
+    if pad.is::<gst::GhostPad>() && is_container(&element) {
+        format!("{}.", element.name())
+    } else {
+        format!("{}.{}", element.name(), pad.name())
+    }
+}
+
+/// playbinのvideo-filterのように単独で使うエレメントの記述
+/// 自分で組み立てたbinは中身だけを書くので、gst::parse_bin_from_descriptionに渡せば作り直せる
+pub fn describe_element(element: &gst::Element) -> String {
+    match element
+        .downcast_ref::<gst::Bin>()
+        .filter(|_| is_container(element))
+    {
+        Some(bin) => describe(bin),
+        None => declaration(element),
+    }
+}
+
+/// エレメント1つの記述。ファクトリ名、名前、既定値から変わったプロパティを並べる
+fn declaration(element: &gst::Element) -> String {
+    let mut words = Vec::new();
+    let container = element
+        .downcast_ref::<gst::Bin>()
+        .filter(|_| is_container(element));
+    if container.is_some() {
+        words.push("bin.(".to_string());
+    } else {
+        words.push(
+            element
+                .factory()
+                .map(|f| f.name().to_string())
+                .unwrap_or_else(|| element.type_().name().to_string()),
+        );
+    }
+    words.push(format!("name={}", quote(&element.name())));
+    words.extend(properties(element));
+    if let Some(bin) = container {
+        words.push(describe(bin));
+        words.push(")".to_string());
+    }
+    words.join(" ")
+}
+
+/// 読み書きでき、既定値から変わっているプロパティを`name=value`にする
+fn properties(element: &gst::Element) -> Vec<String> {
+    let readwrite = glib::ParamFlags::READABLE | glib::ParamFlags::WRITABLE;
+    element
+        .list_properties()
+        .iter()
+        .filter(|pspec| pspec.flags().contains(readwrite))
+        .filter(|pspec| !SKIPPED_PROPERTIES.contains(&pspec.name()))
+        .filter_map(|pspec| {
+            let value = element.property_value(pspec.name()).serialize().ok()?;
+            let default = pspec.default_value().serialize().ok();
+            (default.as_deref() != Some(value.as_str()))
+                .then(|| format!("{}={}", pspec.name(), quote(&value)))
+        })
+        .collect()
+}
+
+/// gst-launchの区切りと紛れる文字を含む値をダブルクォートで囲む
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+./:".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_plain_values() {
+        assert_eq!(quote("fakesink0"), "fakesink0");
+        assert_eq!(quote("video/x-raw"), "video/x-raw");
+        assert_eq!(quote("file:///tmp/a.mp4"), "file:///tmp/a.mp4");
+        assert_eq!(quote("-1.5e+3"), "-1.5e+3");
+    }
+
+    #[test]
+    fn quote_special_values() {
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("a b"), "\"a b\"");
+        assert_eq!(quote("a!b"), "\"a!b\"");
+        assert_eq!(quote("x=1,y=2"), "\"x=1,y=2\"");
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote(r"C:\media"), r#""C:\\media""#);
+    }
+
+    #[test]
+    fn describe_round_trip() {
+        gst::init().unwrap();
+
+        let pipeline = gst::parse_launch(
+            "fakesrc name=src num-buffers=3 ! tee name=t \
+             t. ! queue name=q1 ! fakesink name=sink1 \
+             t. ! queue name=q2 ! fakesink name=sink2",
+        )
+        .unwrap()
+        .downcast::<gst::Bin>()
+        .unwrap();
+        let description = describe(&pipeline);
+        assert!(
+            description.starts_with("fakesrc name=src num-buffers=3 ! tee name=t"),
+            "{description}"
+        );
+        assert!(
+            description.contains("queue name=q1 ! fakesink name=sink1"),
+            "{description}"
+        );
+        assert!(description.contains("t.src_0 ! q"), "{description}");
+
+        // 書き出した記述から同じ形のパイプラインを作り直せる
+        let rebuilt = gst::parse_launch(&description)
+            .unwrap()
+            .downcast::<gst::Bin>()
+            .unwrap();
+        let tee = rebuilt.by_name("t").unwrap();
+        assert_eq!(tee.src_pads().len(), 2);
+        for (queue, sink) in [("q1", "sink1"), ("q2", "sink2")] {
+            let peer = rebuilt
+                .by_name(queue)
+                .and_then(|queue| queue.static_pad("src"))
+                .and_then(|pad| pad.peer())
+                .and_then(|pad| pad.parent_element())
+                .unwrap();
+            assert_eq!(peer.name().as_str(), sink);
+        }
+    }
+}
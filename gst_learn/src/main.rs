@@ -8,13 +8,109 @@ use gst::{prelude::*, ResourceError};
 use gstreamer_app::AppSink;
 use structopt::StructOpt;
 
-fn tutorial_helloworld() -> anyhow::Result<()> {
+mod adaptive;
+mod appsink_config;
+mod audio_encoder;
+mod av_sync;
+mod bandwidth;
+mod batch_normalize;
+mod bridge;
+mod buffering_stats;
+mod cast;
+mod chromecast;
+mod clocks;
+mod config;
+mod container;
+mod controller;
+mod coverart;
+mod cow;
+mod crypt;
+mod custom_event;
+mod daemon;
+mod dash;
+mod decodebin3;
+mod deinterlace;
+mod detect;
+mod diagnostics;
+mod doctor;
+mod draw_overlay;
+mod ducking;
+mod fallback;
+mod framerate;
+mod hdr;
+mod hls;
+mod hls_upload;
+mod http;
+mod icecast;
+mod impair;
+mod input;
+mod inter;
+mod keyboard;
+mod kiosk;
+mod ladder;
+mod launch;
+mod mpris;
+mod multicam;
+mod notify;
+mod offset;
+mod pipewire;
+mod profiling;
+mod record;
+mod registry;
+mod repl;
+mod replaygain;
+mod resample;
+mod rtp;
+mod runner;
+mod scenario;
+mod screen;
+mod scrub;
+mod secondary_audio;
+mod seekable_src;
+mod session;
+mod settings;
+mod shm;
+mod silence;
+mod slowmo;
+mod snapshot;
+mod split_audio;
+mod storage;
+mod streams;
+mod subtitles;
+mod testclock;
+mod timed_metadata;
+mod timelapse;
+mod transcode;
+mod transcribe;
+mod ts;
+mod tui;
+mod video_appsrc;
+mod video_encoder;
+mod virtualcam;
+mod watch;
+mod websocket;
+
+fn tutorial_helloworld(
+    mpris: &mpris::MprisOpt,
+    replaygain: &replaygain::ReplayGainOpt,
+    tui: &tui::TuiOpt,
+    http: &http::HttpOpt,
+    deinterlace: &deinterlace::DeinterlaceOpt,
+    sync: &testclock::SyncOpt,
+) -> anyhow::Result<()> {
     gst::init().context("failed to init gstreamer")?;
 
     let uri =
         "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm";
 
-    let pipeline = gst::parse_launch(&format!("playbin uri={uri}")).context("failed to set uri")?;
+    let pipeline =
+        fallback::parse_launch(&format!("playbin uri={uri}")).context("failed to set uri")?;
+    replaygain.apply(&pipeline)?;
+    deinterlace.apply(&pipeline)?;
+    let _clock = sync.apply(pipeline.downcast_ref().context("not a pipeline")?);
+    let auth = http.apply(&pipeline)?;
+    let _mpris = mpris.start(&pipeline)?;
+    let _tui = tui.start(&pipeline)?;
 
     pipeline
         .set_state(gst::State::Playing)
@@ -24,6 +120,10 @@ fn tutorial_helloworld() -> anyhow::Result<()> {
     for msg in bus.iter_timed(gst::ClockTime::NONE) {
         use gst::MessageView;
 
+        if auth.retry(&pipeline, &msg) {
+            continue;
+        }
+
         match msg.view() {
             MessageView::Eos(_) => break,
             MessageView::Error(err) => {
@@ -51,8 +151,8 @@ fn tutorial_concept() -> anyhow::Result<()> {
 
     let source = gst::ElementFactory::make("videotestsrc", Some("source"))
         .context("Colud not create source element")?;
-    let sink = gst::ElementFactory::make("autovideosink", Some("sink"))
-        .context("Could not create sink element")?;
+    let sink =
+        fallback::make("autovideosink", Some("sink")).context("Could not create sink element")?;
 
     let pipeline = gst::Pipeline::new(Some("test-pipeline"));
 
@@ -102,8 +202,7 @@ fn tutorial_dynamic_pipeline() -> anyhow::Result<()> {
         gst::ElementFactory::make("uridecodebin", Some("source")).context("make uridecodebin")?;
     let convert =
         gst::ElementFactory::make("audioconvert", Some("convert")).context("make audioconvert")?;
-    let sink =
-        gst::ElementFactory::make("autoaudiosink", Some("sink")).context("make audiosink")?;
+    let sink = fallback::make("autoaudiosink", Some("sink")).context("make audiosink")?;
     let resample =
         gst::ElementFactory::make("audioresample", Some("resample")).context("make resample")?;
 
@@ -201,7 +300,14 @@ fn tutorial_dynamic_pipeline() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn tutorial_queue() -> anyhow::Result<()> {
+fn tutorial_queue(
+    mpris: &mpris::MprisOpt,
+    replaygain: &replaygain::ReplayGainOpt,
+    tui: &tui::TuiOpt,
+    http: &http::HttpOpt,
+    deinterlace: &deinterlace::DeinterlaceOpt,
+    sync: &testclock::SyncOpt,
+) -> anyhow::Result<()> {
     struct CustomData {
         /// Our one and only element
         playbin: gst::Element,
@@ -289,6 +395,12 @@ fn tutorial_queue() -> anyhow::Result<()> {
     let uri =
         "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm";
     playbin.set_property("uri", uri);
+    replaygain.apply(&playbin)?;
+    deinterlace.apply(&playbin)?;
+    let _clock = sync.apply(playbin.downcast_ref().context("not a pipeline")?);
+    let auth = http.apply(&playbin)?;
+    let _mpris = mpris.start(&playbin)?;
+    let _tui = tui.start(&playbin)?;
     playbin
         .set_state(gst::State::Playing)
         .context("set state playing")?;
@@ -303,7 +415,9 @@ fn tutorial_queue() -> anyhow::Result<()> {
 
         match msg {
             Some(msg) => {
-                handle_message(&mut custom_data, &msg)?;
+                if !auth.retry(&custom_data.playbin, &msg) {
+                    handle_message(&mut custom_data, &msg)?;
+                }
             }
             None => {
                 // イベントが特にないなら通常通り更新する
@@ -354,8 +468,18 @@ fn tutorial_queue() -> anyhow::Result<()> {
 /// Gstreamerからの情報で継続的にGUIを更新する
 /// 複数のスレッドからGUIを更新する
 /// 関心のあるメッセージをサブスクライブする
-fn tutorial_guikit() -> anyhow::Result<()> {
+///
+/// sync_handlerがtrueならrealizeの時点ではハンドルを保存するだけにして、
+/// sinkがprepare-window-handleを送ってきたときにバスのsync handlerで渡す
+///
+/// scrubがtrueならスライダーのドラッグ中はキーフレームだけでシークする(scrub::Scrubber)
+/// scrub_audioなら代わりに新しい位置ごとに短い音を鳴らす
+fn tutorial_guikit(sync_handler: bool, scrub: bool, scrub_audio: bool) -> anyhow::Result<()> {
     use std::process;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
     use gdk::prelude::*;
     use gtk::prelude::*;
@@ -409,6 +533,14 @@ fn tutorial_guikit() -> anyhow::Result<()> {
                 if let Some(bitrate) = tags.get::<gst::tags::Bitrate>() {
                     textbuf.insert_at_cursor(&format!("    bitrate: {} \n", bitrate.get()));
                 }
+
+                for image in coverart::image_tags(&tags) {
+                    textbuf.insert_at_cursor(&format!(
+                        "    {}: {} \n",
+                        image.tag,
+                        coverart::describe(&image.sample)
+                    ));
+                }
             }
         }
     }
@@ -424,7 +556,34 @@ fn tutorial_guikit() -> anyhow::Result<()> {
     }
 
     // This creates all the GTK+ widgets that compose our application, and registers the callbacks
-    fn create_ui(playbin: &gst::Element) -> AppWindow {
+    // Waylandのときはアプリのwl_displayのポインタを返す
+    #[cfg(all(target_os = "linux", feature = "tutorial5-wayland"))]
+    fn wayland_display() -> Option<usize> {
+        let display = gdk::Display::default()?;
+        if display.type_().name() != "GdkWaylandDisplay" {
+            return None;
+        }
+        extern "C" {
+            pub fn gdk_wayland_display_get_wl_display(
+                display: *mut glib::object::GObject,
+            ) -> *mut c_void;
+        }
+        let wl_display = unsafe { gdk_wayland_display_get_wl_display(display.as_ptr() as *mut _) };
+        Some(wl_display as usize)
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "tutorial5-wayland")))]
+    fn wayland_display() -> Option<usize> {
+        None
+    }
+
+    // window_handleがあるときは、realizeで得たハンドルをそこに保存するだけにする
+    fn create_ui(
+        playbin: &gst::Element,
+        window_handle: Option<Arc<AtomicUsize>>,
+        wayland: bool,
+        scrubber: Option<scrub::Scrubber>,
+    ) -> AppWindow {
         let main_window = gtk::Window::new(gtk::WindowType::Toplevel);
         main_window.connect_delete_event(|_, _| {
             gtk::main_quit();
@@ -463,20 +622,39 @@ fn tutorial_guikit() -> anyhow::Result<()> {
         });
 
         let slider = gtk::Scale::with_range(gtk::Orientation::Horizontal, 0.0, 100.0, 1.0);
-        let pipeline = playbin.clone();
-        let slider_update_signal_id = slider.connect_value_changed(move |slider| {
-            let pipeline = &pipeline;
-            let value = slider.value() as u64;
-            if pipeline
-                .seek_simple(
-                    gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
-                    value * gst::ClockTime::SECOND,
-                )
-                .is_err()
-            {
-                eprintln!("Seeking to {} failed", value);
+        let slider_update_signal_id = match scrubber.clone() {
+            Some(scrubber) => {
+                let begin = scrubber.clone();
+                slider.connect_button_press_event(move |_, _| {
+                    begin.begin();
+                    Inhibit(false)
+                });
+                let end = scrubber.clone();
+                slider.connect_button_release_event(move |slider, _| {
+                    end.end(gst::ClockTime::from_nseconds((slider.value() * 1e9) as u64));
+                    Inhibit(false)
+                });
+                slider.connect_value_changed(move |slider| {
+                    scrubber.request(gst::ClockTime::from_nseconds((slider.value() * 1e9) as u64));
+                })
             }
-        });
+            None => {
+                let pipeline = playbin.clone();
+                slider.connect_value_changed(move |slider| {
+                    let pipeline = &pipeline;
+                    let value = slider.value() as u64;
+                    if pipeline
+                        .seek_simple(
+                            gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                            value * gst::ClockTime::SECOND,
+                        )
+                        .is_err()
+                    {
+                        eprintln!("Seeking to {} failed", value);
+                    }
+                })
+            }
+        };
 
         slider.set_draw_value(false);
         let pipeline = playbin.clone();
@@ -486,6 +664,10 @@ fn tutorial_guikit() -> anyhow::Result<()> {
             let pipeline = &pipeline;
             let lslider = &lslider;
 
+            // ドラッグしている間はつまみを動かさない
+            if matches!(&scrubber, Some(scrubber) if scrubber.is_scrubbing()) {
+                return Continue(true);
+            }
             if let Some(dur) = pipeline.query_duration::<gst::ClockTime>() {
                 lslider.set_range(0.0, dur.seconds() as f64);
 
@@ -515,8 +697,51 @@ fn tutorial_guikit() -> anyhow::Result<()> {
             .dynamic_cast::<gstreamer_video::VideoOverlay>()
             .unwrap();
 
+        // Waylandではsubsurfaceの位置をトップレベルのウィンドウからの座標で指定する
+        if wayland {
+            let video_overlay = video_overlay.clone();
+            video_window.connect_size_allocate(move |video_window, allocation| {
+                let (x, y) = video_window
+                    .toplevel()
+                    .and_then(|toplevel| video_window.translate_coordinates(&toplevel, 0, 0))
+                    .unwrap_or((0, 0));
+                let _ = video_overlay.set_render_rectangle(
+                    x,
+                    y,
+                    allocation.width(),
+                    allocation.height(),
+                );
+            });
+        }
+
         video_window.connect_realize(move |video_window| {
-            let video_overlay = &video_overlay;
+            let set_window_handle = |handle: usize| match &window_handle {
+                Some(window_handle) => window_handle.store(handle, Ordering::SeqCst),
+                None => unsafe { video_overlay.set_window_handle(handle) },
+            };
+
+            let display_type_name = video_window.display().type_().name();
+            #[cfg(all(target_os = "linux", feature = "tutorial5-wayland"))]
+            {
+                // Waylandでは子ウィンドウを作れないので、トップレベルのwl_surfaceを渡して
+                // waylandsinkにsubsurfaceを重ねてもらう
+                if display_type_name == "GdkWaylandDisplay" {
+                    extern "C" {
+                        pub fn gdk_wayland_window_get_wl_surface(
+                            window: *mut glib::object::GObject,
+                        ) -> *mut c_void;
+                    }
+
+                    let toplevel = video_window.toplevel().and_then(|w| w.window()).unwrap();
+                    unsafe {
+                        let surface =
+                            gdk_wayland_window_get_wl_surface(toplevel.as_ptr() as *mut _);
+                        set_window_handle(surface as usize);
+                    }
+                    return;
+                }
+            }
+
             let gdk_window = video_window.window().unwrap();
 
             if !gdk_window.ensure_native() {
@@ -524,7 +749,6 @@ fn tutorial_guikit() -> anyhow::Result<()> {
                 process::exit(-1);
             }
 
-            let display_type_name = gdk_window.display().type_().name();
             #[cfg(all(target_os = "linux", feature = "tutorial5-x11"))]
             {
                 // Check if we're using X11 or ...
@@ -538,7 +762,25 @@ fn tutorial_guikit() -> anyhow::Result<()> {
                     #[allow(clippy::cast_ptr_alignment)]
                     unsafe {
                         let xid = gdk_x11_window_get_xid(gdk_window.as_ptr() as *mut _);
-                        video_overlay.set_window_handle(xid as usize);
+                        set_window_handle(xid as usize);
+                    }
+                } else {
+                    println!("Add support for display type '{}'", display_type_name);
+                    process::exit(-1);
+                }
+            }
+            #[cfg(all(target_os = "windows", feature = "tutorial5-win32"))]
+            {
+                if display_type_name == "GdkWin32Display" {
+                    extern "C" {
+                        pub fn gdk_win32_window_get_handle(
+                            window: *mut glib::object::GObject,
+                        ) -> *mut c_void;
+                    }
+
+                    unsafe {
+                        let hwnd = gdk_win32_window_get_handle(gdk_window.as_ptr() as *mut _);
+                        set_window_handle(hwnd as usize);
                     }
                 } else {
                     println!("Add support for display type '{}'", display_type_name);
@@ -557,7 +799,7 @@ fn tutorial_guikit() -> anyhow::Result<()> {
                     #[allow(clippy::cast_ptr_alignment)]
                     unsafe {
                         let window = gdk_quartz_window_get_nsview(gdk_window.as_ptr() as *mut _);
-                        video_overlay.set_window_handle(window as usize);
+                        set_window_handle(window as usize);
                     }
                 } else {
                     println!(
@@ -624,13 +866,17 @@ fn tutorial_guikit() -> anyhow::Result<()> {
         )));
     }
 
-    pub fn run() {
+    pub fn run(sync_handler: bool, scrub: bool, scrub_audio: bool) {
         // Make sure the right features were activated
         #[allow(clippy::eq_op)]
         {
-            if !cfg!(feature = "tutorial5-x11") && !cfg!(feature = "tutorial5-quartz") {
+            if !cfg!(feature = "tutorial5-x11")
+                && !cfg!(feature = "tutorial5-wayland")
+                && !cfg!(feature = "tutorial5-win32")
+                && !cfg!(feature = "tutorial5-quartz")
+            {
                 eprintln!(
-                    "No Gdk backend selected, compile with --features tutorial5[-x11][-quartz]."
+                    "No Gdk backend selected, compile with --features tutorial5[-x11][-wayland][-win32][-quartz]."
                 );
 
                 return;
@@ -680,11 +926,104 @@ fn tutorial_guikit() -> anyhow::Result<()> {
             None
         });
 
-        let window = create_ui(&playbin);
+        // Waylandのセッションならwaylandsinkで表示する
+        // waylandsinkにはwl_displayをcontextで、wl_surfaceをsync handlerで渡す
+        let wl_display = wayland_display();
+        if wl_display.is_some() {
+            match gst::ElementFactory::make("waylandsink", None) {
+                Ok(sink) => playbin.set_property("video-sink", &sink),
+                Err(_) => eprintln!("waylandsink is not available"),
+            }
+        }
+
+        // WindowsではDirect3D 11のsinkにHWNDを渡す
+        if cfg!(feature = "tutorial5-win32")
+            && gdk::Display::default().map(|display| display.type_().name())
+                == Some("GdkWin32Display")
+        {
+            match gst::ElementFactory::make("d3d11videosink", None) {
+                Ok(sink) => playbin.set_property("video-sink", &sink),
+                Err(_) => eprintln!("d3d11videosink is not available"),
+            }
+        }
+
+        let window_handle =
+            (sync_handler || wl_display.is_some()).then(|| Arc::new(AtomicUsize::new(0)));
+        // スクラブ中にキーフレーム以外を捨てるフィルタを映像の経路に入れる
+        let scrubber = (scrub || scrub_audio).then(|| {
+            let scrubber = scrub::Scrubber::new(&playbin, std::time::Duration::from_millis(100));
+            if scrub_audio {
+                scrubber.with_audio_snippets(std::time::Duration::from_millis(120))
+            } else {
+                scrubber
+            }
+        });
+        if let Some(scrubber) = &scrubber {
+            match scrubber.keyframe_filter() {
+                Ok(filter) => playbin.set_property("video-filter", &filter),
+                Err(err) => eprintln!("keyframe filter is not available: {}", err),
+            }
+        }
+        let window = create_ui(
+            &playbin,
+            window_handle.clone(),
+            wl_display.is_some(),
+            scrubber,
+        );
 
         let bus = playbin.bus().unwrap();
         bus.add_signal_watch();
 
+        // playbinの中のvideo sinkは再生を始めてから作られるので、sinkがハンドルを求めた時点で渡す
+        // sync handlerはメッセージを送ったsinkのストリーミングスレッドで呼ばれ、
+        // 戻るまでsinkはウィンドウを作らずに待っている
+        if let Some(window_handle) = window_handle {
+            bus.set_sync_handler(move |_, msg| {
+                if let (Some(wl_display), gst::MessageView::NeedContext(need_context)) =
+                    (wl_display, msg.view())
+                {
+                    if need_context.context_type() == "GstWaylandDisplayHandleContextType" {
+                        use glib::translate::UnsafeFrom;
+
+                        // ポインタはSendではないので、GValueから直接SendValueにする
+                        let display = (wl_display as glib::Pointer).to_value();
+                        let display = unsafe { glib::SendValue::unsafe_from(display.into_raw()) };
+                        let mut context = gst::Context::new(need_context.context_type(), true);
+                        context
+                            .get_mut()
+                            .unwrap()
+                            .structure_mut()
+                            .set_value("display", display);
+                        if let Some(element) = msg
+                            .src()
+                            .and_then(|src| src.downcast::<gst::Element>().ok())
+                        {
+                            element.set_context(&context);
+                            return gst::BusSyncReply::Drop;
+                        }
+                    }
+                }
+                if !gstreamer_video::is_video_overlay_prepare_window_handle_message(msg) {
+                    return gst::BusSyncReply::Pass;
+                }
+                let handle = window_handle.load(Ordering::SeqCst);
+                let overlay = msg
+                    .src()
+                    .and_then(|src| src.dynamic_cast::<gstreamer_video::VideoOverlay>().ok());
+                match overlay {
+                    Some(overlay) if handle != 0 => {
+                        println!(
+                            "prepare-window-handle from {:?}",
+                            msg.src().map(|s| s.path_string())
+                        );
+                        unsafe { overlay.set_window_handle(handle) };
+                        gst::BusSyncReply::Drop
+                    }
+                    _ => gst::BusSyncReply::Pass,
+                }
+            });
+        }
+
         let pipeline_weak = playbin.downgrade();
         bus.connect_message(None, move |_, msg| {
             let pipeline = match pipeline_weak.upgrade() {
@@ -733,9 +1072,10 @@ fn tutorial_guikit() -> anyhow::Result<()> {
             .set_state(gst::State::Null)
             .expect("Unable to set the playbin to the `Null` state");
 
+        bus.unset_sync_handler();
         bus.remove_signal_watch();
     }
-    run();
+    run(sync_handler, scrub, scrub_audio);
 
     Ok(())
 }
@@ -900,7 +1240,10 @@ fn tutorial_media_pad() -> anyhow::Result<()> {
 /// パイプラインの一部の実行の新しいスレッドを作成する方法
 /// パッドの可用性とは
 /// ストリームの複製する方法
-fn tutorial_multithread_pad() -> anyhow::Result<()> {
+fn tutorial_multithread_pad(
+    queue_monitor: &diagnostics::QueueMonitorOpt,
+    profile: &profiling::ProfileOpt,
+) -> anyhow::Result<()> {
     // Gstreamはマルチスレッドフレームワーク。ストリーミングをアプリケーションスレッドから切り離すために内部でスレッドの作成と破棄をする。
     // プラグインは独自の処理用のスレッドを作ることも出来る
     // パイプライン小売クジもブランチが別のスレッドで実行されるように明示的に指定できる
@@ -915,13 +1258,13 @@ fn tutorial_multithread_pad() -> anyhow::Result<()> {
     let audio_queue = gst::ElementFactory::make("queue", Some("audio_queue"))?;
     let audio_convert = gst::ElementFactory::make("audioconvert", Some("audio_convert"))?;
     let audio_resample = gst::ElementFactory::make("audioresample", Some("audio_resample"))?;
-    let audio_sink = gst::ElementFactory::make("autoaudiosink", Some("audio_sink"))?;
+    let audio_sink = fallback::make("autoaudiosink", Some("audio_sink"))?;
 
     // 音声シグナルを波形表示に変換する
     let visual = gst::ElementFactory::make("wavescope", Some("visual"))?;
     let video_queue = gst::ElementFactory::make("queue", Some("video_queue"))?;
     let video_convert = gst::ElementFactory::make("videoconvert", Some("video_convert"))?;
-    let video_sink = gst::ElementFactory::make("autovideosink", Some("video_sink"))?;
+    let video_sink = fallback::make("autovideosink", Some("video_sink"))?;
 
     let pipeline = gst::Pipeline::new(Some("pipeline"));
 
@@ -965,7 +1308,9 @@ fn tutorial_multithread_pad() -> anyhow::Result<()> {
     let queue_video_pad = video_queue.static_pad("sink").context("queue_video_pad")?;
     tee_video_pad.link(&queue_video_pad)?;
 
+    let _profiler = profile.instrument(&pipeline)?;
     pipeline.set_state(gst::State::Playing)?;
+    let _monitor = queue_monitor.start(&pipeline)?;
     let bus = pipeline.bus().context("bus")?;
     for msg in bus.iter_timed(gst::ClockTime::NONE) {
         use gst::MessageView::*;
@@ -996,7 +1341,11 @@ fn tutorial_multithread_pad() -> anyhow::Result<()> {
 /// パイプラインに外からデータを注入する方法
 /// パイプラインからデータを取り出す方法
 /// データにアクセス、操作をする方法
-fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
+fn tutorial_shortcut_pipeline(
+    queue_monitor: &diagnostics::QueueMonitorOpt,
+    profile: &profiling::ProfileOpt,
+    appsink_config: &appsink_config::AppSinkConfig,
+) -> anyhow::Result<()> {
     // 幾つかの方法でパイプラインを流れるデータと対話出来る
     // アプリケーションデータをGStreamerに挿入するために使用する要素はappsrc
     // 出力のための要素はappsink
@@ -1033,11 +1382,10 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
         d: f64,
 
         appsrc: AppSrc,
-        appsink: AppSink,
     }
 
     impl CustomData {
-        fn new(appsrc: &AppSrc, appsink: &AppSink) -> Self {
+        fn new(appsrc: &AppSrc) -> Self {
             Self {
                 source_id: None,
                 num_samples: 0,
@@ -1046,7 +1394,6 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
                 c: 0.0,
                 d: 1.0,
                 appsrc: appsrc.clone(),
-                appsink: appsink.clone(),
             }
         }
     }
@@ -1059,14 +1406,14 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
     let audio_queue = gst::ElementFactory::make("queue", Some("audio_queue"))?;
     let audio_convert1 = gst::ElementFactory::make("audioconvert", Some("audio_convert1"))?;
     let audio_resample = gst::ElementFactory::make("audioresample", Some("audio_resample"))?;
-    let audio_sink = gst::ElementFactory::make("autoaudiosink", Some("audio_sink"))?;
+    let audio_sink = fallback::make("autoaudiosink", Some("audio_sink"))?;
 
     // 音声シグナルを波形表示に変換する
     let video_queue = gst::ElementFactory::make("queue", Some("video_queue"))?;
     let audio_convert2 = gst::ElementFactory::make("audioconvert", Some("audio_convert2"))?;
     let visual = gst::ElementFactory::make("wavescope", Some("visual"))?;
     let video_convert = gst::ElementFactory::make("videoconvert", Some("video_convert"))?;
-    let video_sink = gst::ElementFactory::make("autovideosink", Some("video_sink"))?;
+    let video_sink = fallback::make("autovideosink", Some("video_sink"))?;
 
     // appsinkに流す
     let app_queue = gst::ElementFactory::make("queue", Some("app_queue"))?;
@@ -1127,7 +1474,7 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
     appsrc.set_format(gst::Format::Time);
 
     let appsink = appsink.dynamic_cast::<AppSink>().unwrap();
-    let data = Arc::new(Mutex::new(CustomData::new(&appsrc, &appsink)));
+    let data = Arc::new(Mutex::new(CustomData::new(&appsrc)));
     let data_weak = Arc::downgrade(&data);
     let data_weak2 = Arc::downgrade(&data);
 
@@ -1217,33 +1564,15 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
     // configure appsink
     appsink.set_caps(Some(&audio_caps));
 
-    let data_weak = Arc::downgrade(&data);
-    // appsinkのcallbackでnew_sampleは新しいバッファが来るたびに発行される
-    appsink.set_callbacks(
-        gstreamer_app::AppSinkCallbacks::builder()
-            .new_sample(move |_| {
-                let data = match data_weak.upgrade() {
-                    Some(data) => data,
-                    None => return Ok(gst::FlowSuccess::Ok),
-                };
-
-                let appsink = {
-                    let data = data.lock().unwrap();
-                    data.appsink.clone()
-                };
-
-                if let Ok(_sample) = appsink.pull_sample() {
-                    // Sample: https://docs.rs/gstreamer/latest/gstreamer/sample/struct.Sample.html
-                    // has buffer(data detail), caps(format), segment(timestamp)
-                    // The only thing we do in this example is print a * to indicate a received buffer
-                    print!("*");
-                    let _ = std::io::stdout().flush();
-                }
-
-                Ok(gst::FlowSuccess::Ok)
-            })
-            .build(),
-    );
+    // appsinkからサンプルを取り出すたびに呼ばれる
+    // 取り出し方と溜め方は--appsink-*の設定に従う
+    let consumer = appsink_config.consume(&appsink, |_sample| {
+        // Sample: https://docs.rs/gstreamer/latest/gstreamer/sample/struct.Sample.html
+        // has buffer(data detail), caps(format), segment(timestamp)
+        // The only thing we do in this example is print a * to indicate a received buffer
+        print!("*");
+        let _ = std::io::stdout().flush();
+    })?;
 
     let main_loop = glib::MainLoop::new(None, false);
     let main_loop_clone = main_loop.clone();
@@ -1264,9 +1593,11 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
     });
     bus.add_signal_watch();
 
+    let _profiler = profile.instrument(&pipeline)?;
     pipeline
         .set_state(gst::State::Playing)
         .expect("Unable to set the pipeline to the `Playing` state.");
+    let _monitor = queue_monitor.start(&pipeline)?;
 
     main_loop.run();
 
@@ -1275,13 +1606,14 @@ fn tutorial_shortcut_pipeline() -> anyhow::Result<()> {
         .expect("Unable to set the pipeline to the `Null` state.");
 
     bus.remove_signal_watch();
+    consumer.finish();
 
     Ok(())
 }
 
 /// URIに関する情報を復元する方法
 /// URIが再生可能課確認する方法
-fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
+fn tutorial_media_info(uri: &str, cover_dir: Option<&std::path::Path>) -> anyhow::Result<()> {
     // GstDiscoverのpbutilsで１つ以上のURIを受け取ってそれらに関する情報を得られる
     // 同期モードで呼び出す場合はgst_discoverer_discover_uri()
     // 非同期の場合は以下のチュートリアルで行う。
@@ -1294,7 +1626,10 @@ fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
     };
 
     fn send_value_as_str(v: &glib::SendValue) -> Option<String> {
-        if let Ok(s) = v.get::<&str>() {
+        // 画像などのサンプルはシリアライズするとデータ全体になるので概要だけにする
+        if let Ok(sample) = v.get::<gst::Sample>() {
+            Some(coverart::describe(&sample))
+        } else if let Ok(s) = v.get::<&str>() {
             Some(s.to_string())
         } else if let Ok(serialized) = v.serialize() {
             Some(serialized.into())
@@ -1358,10 +1693,29 @@ fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
         }
     }
 
+    /// 全体とストリームごとのタグに含まれる画像を書き出す
+    fn export_images(discoverer_info: &DiscovererInfo, dir: &std::path::Path) {
+        let tags = discoverer_info
+            .tags()
+            .into_iter()
+            .map(|tags| ("global".to_string(), tags));
+        let stream_tags = discoverer_info
+            .stream_list()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, stream)| Some((format!("stream{i}"), stream.tags()?)));
+        for (prefix, tags) in tags.chain(stream_tags) {
+            if let Err(err) = coverart::export_all(&tags, dir, &prefix) {
+                log::error!("failed to export images: {err:#}");
+            }
+        }
+    }
+
     fn on_discovered(
         _discoverer: &Discoverer,
         discoverer_info: &DiscovererInfo,
         error: Option<&glib::Error>,
+        cover_dir: Option<&std::path::Path>,
     ) {
         let uri = discoverer_info.uri().unwrap();
         match discoverer_info.result() {
@@ -1415,6 +1769,10 @@ fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
         if let Some(stream_info) = discoverer_info.stream_info() {
             print_topology(&stream_info, 1);
         }
+
+        if let Some(dir) = cover_dir {
+            export_images(discoverer_info, dir);
+        }
     }
 
     log::info!("Discovering {uri}");
@@ -1424,7 +1782,10 @@ fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
     let loop_ = glib::MainLoop::new(None, false);
     let timeout = 5 * gst::ClockTime::SECOND;
     let discoverer = gstreamer_pbutils::Discoverer::new(timeout)?;
-    discoverer.connect_discovered(on_discovered);
+    let cover_dir = cover_dir.map(|dir| dir.to_path_buf());
+    discoverer.connect_discovered(move |discoverer, info, error| {
+        on_discovered(discoverer, info, error, cover_dir.as_deref())
+    });
     let loop_clone = loop_.clone();
     discoverer.connect_finished(move |_| {
         log::info!("Finished discovering");
@@ -1441,12 +1802,26 @@ fn tutorial_media_info(uri: &str) -> anyhow::Result<()> {
 
 /// bufferingを有効にする方法(ネットワークの問題の軽減)
 /// 中断から回復する方法
-fn tutorial_streaming() -> anyhow::Result<()> {
+/// 1秒ごとにBufferingクエリでバッファの様子を記録し、終了時にグラフを出す
+fn tutorial_streaming(
+    mpris: &mpris::MprisOpt,
+    replaygain: &replaygain::ReplayGainOpt,
+    tui: &tui::TuiOpt,
+    http: &http::HttpOpt,
+    deinterlace: &deinterlace::DeinterlaceOpt,
+    bus_loop: &runner::BusLoopOpt,
+    stats_out: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
     gst::init()?;
 
     let uri =
         "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm";
-    let pipeline = gst::parse_launch(&format!("playbin uri={}", uri))?;
+    let pipeline = fallback::parse_launch(&format!("playbin uri={}", uri))?;
+    replaygain.apply(&pipeline)?;
+    deinterlace.apply(&pipeline)?;
+    let auth = http.apply(&pipeline)?;
+    let _mpris = mpris.start(&pipeline)?;
+    let _tui = tui.start(&pipeline)?;
 
     // Start playing
     let res = pipeline.set_state(gst::State::Playing)?;
@@ -1455,6 +1830,9 @@ fn tutorial_streaming() -> anyhow::Result<()> {
     let main_loop = glib::MainLoop::new(None, false);
     let main_loop_clone = main_loop.clone();
     let pipeline_weak = pipeline.downgrade();
+    let notifier = bus_loop.notifier();
+    let mut buffering_watch = notify::BufferingWatch::default();
+    let mut print_launch = bus_loop.print_launch;
     let bus = pipeline.bus().expect("Pipeline has no bus");
     bus.add_watch(move |_, msg| {
         use gst::MessageView::*;
@@ -1464,6 +1842,10 @@ fn tutorial_streaming() -> anyhow::Result<()> {
         };
         let main_loop = &main_loop_clone;
 
+        if auth.retry(&pipeline, msg) {
+            return glib::Continue(true);
+        }
+
         match msg.view() {
             Error(err) => {
                 log::error!(
@@ -1472,11 +1854,17 @@ fn tutorial_streaming() -> anyhow::Result<()> {
                     err.error(),
                     err.debug(),
                 );
+                if let Some(notifier) = &notifier {
+                    notifier.notify(notify::Event::Error);
+                }
                 main_loop.quit();
             }
             Eos(_) => {
                 // end-of-stream
                 let _ = pipeline.set_state(gst::State::Ready);
+                if let Some(notifier) = &notifier {
+                    notifier.notify(notify::Event::Eos);
+                }
                 main_loop.quit();
             }
             // bufferが所定量貯まるまで再生しない
@@ -1487,6 +1875,10 @@ fn tutorial_streaming() -> anyhow::Result<()> {
                 let percent = buffering.percent();
                 log::info!("Buffering ({percent})");
                 std::io::stdout().flush().unwrap();
+                let completed = buffering_watch.completed(percent);
+                if let Some(notifier) = notifier.as_ref().filter(|_| completed) {
+                    notifier.notify(notify::Event::Buffered);
+                }
 
                 if percent < 30 {
                     let _ = pipeline.set_state(gst::State::Paused);
@@ -1499,22 +1891,62 @@ fn tutorial_streaming() -> anyhow::Result<()> {
                 let _ = pipeline.set_state(gst::State::Paused);
                 let _ = pipeline.set_state(gst::State::Playing);
             }
+            StateChanged(state)
+                if print_launch
+                    && state.current() == gst::State::Playing
+                    && msg.src().as_ref() == Some(pipeline.upcast_ref()) =>
+            {
+                print_launch = false;
+                let bin = pipeline.downcast_ref::<gst::Bin>().unwrap();
+                println!("gst-launch-1.0 {}", launch::describe(bin));
+            }
             _ => {}
         }
         glib::Continue(true)
     })?;
 
+    let stats = std::rc::Rc::new(std::cell::RefCell::new(
+        buffering_stats::BufferingStats::new(stats_out)?,
+    ));
+    let stats_clone = stats.clone();
+    let pipeline_weak = pipeline.downgrade();
+    let sampler = glib::timeout_add_local(std::time::Duration::from_secs(1), move || {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return glib::Continue(false),
+        };
+        if let Err(err) = stats_clone.borrow_mut().sample(&pipeline) {
+            log::warn!("failed to record buffering stats: {err:#}");
+        }
+        glib::Continue(true)
+    });
+
     main_loop.run();
 
+    sampler.remove();
     bus.remove_watch()?;
     pipeline.set_state(gst::State::Null)?;
 
+    let mut stats = stats.borrow_mut();
+    print!("{}", stats.chart());
+    stats.finish()?;
+
     Ok(())
 }
 
 /// 再生速度を変化させる方法
 /// ビデオをフレームごとに進める方法
-fn tutorial_playback_speed() -> anyhow::Result<()> {
+/// keymapで操作のキー割り当てを変えられる
+fn tutorial_playback_speed(
+    mpris: &mpris::MprisOpt,
+    replaygain: &replaygain::ReplayGainOpt,
+    tui: &tui::TuiOpt,
+    http: &http::HttpOpt,
+    deinterlace: &deinterlace::DeinterlaceOpt,
+    keymap: Option<&std::path::Path>,
+    record_session: Option<&std::path::Path>,
+    replay: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
     // 再生速度の変化、逆再生についても再生レートで制御できる
     // 再生速度の変更方法はステップイベントとシークイベントの2種類がある
     // ステップイベントは主に1以上の高速再生でメディアをスキップするのに
@@ -1527,21 +1959,11 @@ fn tutorial_playback_speed() -> anyhow::Result<()> {
 
     use anyhow::Error;
 
-    use termion::event::Key;
-    use termion::input::TermRead;
-    use termion::raw::IntoRawMode;
+    use crate::input::{Command, Keymap};
+    use crate::keyboard::Keyboard;
+    use crate::session::{Action, Session, SessionRecorder};
 
-    use std::{io, thread, time};
-
-    #[derive(Clone, Copy, PartialEq)]
-    enum Command {
-        PlayPause,
-        DataRateUp,
-        DataRateDown,
-        ReverseRate,
-        NextFrame,
-        Quit,
-    }
+    use std::thread;
 
     fn send_seek_event(pipeline: &Element, rate: f64) -> bool {
         let position = match pipeline.query_position() {
@@ -1590,47 +2012,27 @@ fn tutorial_playback_speed() -> anyhow::Result<()> {
         }
     }
 
-    fn handle_keyboard(ready_tx: glib::Sender<Command>) {
+    fn handle_keyboard(keymap: Keymap, ready_tx: glib::Sender<Action>) {
         // We set the terminal in "raw mode" so that we can get the keys without waiting for the user
         // to press return.
-        let _stdout = io::stdout().into_raw_mode().unwrap();
-        let mut stdin = termion::async_stdin().keys();
-
-        loop {
-            if let Some(Ok(input)) = stdin.next() {
-                let command = match input {
-                    Key::Char('p' | 'P') => Command::PlayPause,
-                    Key::Char('s') => Command::DataRateDown,
-                    Key::Char('S') => Command::DataRateUp,
-                    Key::Char('d' | 'D') => Command::ReverseRate,
-                    Key::Char('n' | 'N') => Command::NextFrame,
-                    Key::Char('q' | 'Q') => Command::Quit,
-                    Key::Ctrl('c' | 'C') => Command::Quit,
-                    _ => continue,
-                };
-                ready_tx
-                    .send(command)
-                    .expect("failed to send data through channel");
-                if command == Command::Quit {
-                    break;
-                }
-            }
-            thread::sleep(time::Duration::from_millis(50));
-        }
+        let keyboard = Keyboard::new().unwrap();
+
+        keymap.run(&keyboard, |command| {
+            ready_tx
+                .send(Action::Command(command))
+                .expect("failed to send data through channel");
+        });
     }
 
     gst::init()?;
 
+    let keymap = match keymap {
+        Some(path) => Keymap::load(path)?,
+        None => Keymap::default(),
+    };
+
     // Print usage map.
-    println!(
-        "\
-USAGE: Choose one of the following options, then press enter:
- 'P' to toggle between PAUSE and PLAY
- 'S' to increase playback speed, 's' to decrease playback speed
- 'D' to toggle playback direction
- 'N' to move to next frame (in the current direction, better in PAUSE)
- 'Q' to quit"
-    );
+    println!("{}", keymap.usage());
 
     // Get a main context...
     let main_context = glib::MainContext::default();
@@ -1640,12 +2042,27 @@ USAGE: Choose one of the following options, then press enter:
 
     // Build the channel to get the terminal inputs from a different thread.
     let (ready_tx, ready_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-    thread::spawn(move || handle_keyboard(ready_tx));
+    let replay_tx = ready_tx.clone();
+    thread::spawn(move || handle_keyboard(keymap, ready_tx));
 
     // Build the pipeline.
     let uri =
         "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm";
-    let pipeline = gst::parse_launch(&format!("playbin uri={}", uri))?;
+    let pipeline = fallback::parse_launch(&format!("playbin uri={}", uri))?;
+    replaygain.apply(&pipeline)?;
+    deinterlace.apply(&pipeline)?;
+    http.apply(&pipeline)?;
+    // 記録の秒数も再生も、Playingにする直前から数える
+    let recorder = record_session
+        .map(|path| SessionRecorder::create(path, &pipeline, uri))
+        .transpose()?;
+    let _mpris = mpris.start_recorded(&pipeline, recorder.clone())?;
+    let _tui = tui.start(&pipeline)?;
+    if let Some(path) = replay {
+        Session::load(path)?.replay(uri, move |action| {
+            let _ = replay_tx.send(action);
+        });
+    }
 
     // Start playing.
     let _ = pipeline.set_state(State::Playing)?;
@@ -1655,12 +2072,46 @@ USAGE: Choose one of the following options, then press enter:
     let mut playing = true;
     let mut rate = 1.;
 
-    ready_rx.attach(Some(&main_loop.context()), move |command: Command| {
+    ready_rx.attach(Some(&main_loop.context()), move |action: Action| {
         use Command::*;
         let pipeline = match pipeline_weak.upgrade() {
             Some(pipeline) => pipeline,
             None => return glib::Continue(true),
         };
+        if let Some(recorder) = &recorder {
+            recorder.record(&action);
+        }
+
+        // MPRISから記録した操作は、やり直すときにここで行う
+        let command = match action {
+            Action::Command(command) => command,
+            Action::Play | Action::Pause => {
+                let state = if action == Action::Play {
+                    State::Playing
+                } else {
+                    State::Paused
+                };
+                playing = state == State::Playing;
+                if let Err(err) = pipeline.set_state(state) {
+                    log::warn!("failed to set {state:?}: {err}\r");
+                }
+                return glib::Continue(true);
+            }
+            Action::Seek(position) => {
+                if let Err(err) =
+                    pipeline.seek_simple(SeekFlags::FLUSH | SeekFlags::KEY_UNIT, position)
+                {
+                    log::warn!("failed to seek to {position}: {err}\r");
+                }
+                return glib::Continue(true);
+            }
+            Action::Set(name, value) => {
+                if let Err(err) = pipeline.try_set_property_from_str(&name, &value) {
+                    log::warn!("failed to set {name}={value}: {err}\r");
+                }
+                return glib::Continue(true);
+            }
+        };
 
         match command {
             PlayPause => {
@@ -1713,7 +2164,11 @@ USAGE: Choose one of the following options, then press enter:
 }
 
 /// videotestsrcのプレビューとメタデータの表示を行う
-fn preview_metadata() -> anyhow::Result<()> {
+fn preview_metadata(
+    queue_monitor: &diagnostics::QueueMonitorOpt,
+    profile: &profiling::ProfileOpt,
+    appsink_config: &appsink_config::AppSinkConfig,
+) -> anyhow::Result<()> {
     gst::init()?;
 
     let source = gst::ElementFactory::make("videotestsrc", Some("source"))
@@ -1722,7 +2177,7 @@ fn preview_metadata() -> anyhow::Result<()> {
     let tee = gst::ElementFactory::make("tee", Some("tee"))?;
     let prev_queue = gst::ElementFactory::make("queue", Some("prev_queue"))?;
     let app_queue = gst::ElementFactory::make("queue", Some("app_queue"))?;
-    let prev_sink = gst::ElementFactory::make("autovideosink", Some("sink"))?;
+    let prev_sink = fallback::make("autovideosink", Some("sink"))?;
     let app_sink = gst::ElementFactory::make("appsink", Some("appsink"))?;
 
     let pipeline = gst::Pipeline::new(Some("test-pipeline"));
@@ -1754,23 +2209,19 @@ fn preview_metadata() -> anyhow::Result<()> {
     link_pad(&tee, &app_queue)?;
 
     let app_sink = app_sink.dynamic_cast::<AppSink>().unwrap();
-    app_sink.set_callbacks(
-        gstreamer_app::AppSinkCallbacks::builder()
-            .new_sample(move |app_sink| {
-                if let Ok(sample) = app_sink.pull_sample() {
-                    log::info!(
-                        "Buffer: {:?}, Caps: {:?}, Segment: {:?} BT:{:?}",
-                        sample.buffer().unwrap(),
-                        sample.caps().unwrap(),
-                        sample.segment().unwrap(),
-                        app_sink.base_time().unwrap()
-                    );
-                }
-
-                Ok(gst::FlowSuccess::Ok)
-            })
-            .build(),
-    );
+    // new-sampleのハンドラはappsink自身が持つので、強参照すると循環する
+    let app_sink_weak = app_sink.downgrade();
+    let consumer = appsink_config.consume(&app_sink, move |sample| {
+        log::info!(
+            "Buffer: {:?}, Caps: {:?}, Segment: {:?} BT:{:?}",
+            sample.buffer().unwrap(),
+            sample.caps().unwrap(),
+            sample.segment().unwrap(),
+            app_sink_weak
+                .upgrade()
+                .and_then(|app_sink| app_sink.base_time())
+        );
+    })?;
 
     source.set_property_from_str("pattern", "smpte");
     // 意味はわからないけど設定出来る
@@ -1779,9 +2230,11 @@ fn preview_metadata() -> anyhow::Result<()> {
     source.set_property("is-live", true);
     source.set_property("do-timestamp", true);
 
+    let _profiler = profile.instrument(&pipeline)?;
     pipeline
         .set_state(gst::State::Playing)
         .context("Unable to set the pipeline to the `Playing` state")?;
+    let _monitor = queue_monitor.start(&pipeline)?;
 
     let bus = pipeline.bus().context("fauled to get bus")?;
     for msg in bus.iter_timed(gst::ClockTime::NONE) {
@@ -1806,12 +2259,37 @@ fn preview_metadata() -> anyhow::Result<()> {
     pipeline
         .set_state(gst::State::Null)
         .expect("Unable to set the pipeline to the `Null` state");
+    consumer.finish();
 
     Ok(())
 }
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    #[structopt(flatten)]
+    queue_monitor: diagnostics::QueueMonitorOpt,
+    #[structopt(flatten)]
+    profile: profiling::ProfileOpt,
+    #[structopt(flatten)]
+    bus_loop: runner::BusLoopOpt,
+    #[structopt(flatten)]
+    mpris: mpris::MprisOpt,
+    #[structopt(flatten)]
+    replaygain: replaygain::ReplayGainOpt,
+    #[structopt(flatten)]
+    tui: tui::TuiOpt,
+    #[structopt(flatten)]
+    http: http::HttpOpt,
+    #[structopt(flatten)]
+    deinterlace: deinterlace::DeinterlaceOpt,
+    #[structopt(flatten)]
+    sync: testclock::SyncOpt,
+    #[structopt(flatten)]
+    audio: audio_encoder::AudioEncodeOpt,
+    #[structopt(flatten)]
+    video: video_encoder::VideoEncodeOpt,
+    #[structopt(flatten)]
+    appsink: appsink_config::AppSinkConfig,
     #[structopt(subcommand)]
     tid: Tutorial,
 }
@@ -1827,7 +2305,18 @@ enum Tutorial {
     /// Basic tutorial 4 time managgement
     B4,
     /// Basic tutorial 5 GUI toolkit
-    B5,
+    B5 {
+        /// hand the window handle to the video sink from a bus sync handler
+        /// when it posts prepare-window-handle, instead of setting it on playbin at realize
+        #[structopt(long)]
+        sync_handler: bool,
+        /// show only keyframes while dragging the slider and coalesce its seeks
+        #[structopt(long)]
+        scrub: bool,
+        /// play a short audio snippet at each new slider position instead of showing keyframes
+        #[structopt(long)]
+        scrub_audio: bool,
+    },
     /// Basic tutorial 6 Media format and pads
     B6,
     /// Basic tutorial 7 Multithread
@@ -1840,32 +2329,1488 @@ enum Tutorial {
             default_value = "https://www.freedesktop.org/software/gstreamer-sdk/data/media/sintel_trailer-480p.webm"
         )]
         uri: String,
+        /// write embedded cover art and preview images to this directory as PNG
+        #[structopt(long, parse(from_os_str))]
+        cover_dir: Option<std::path::PathBuf>,
     },
     // Basic tutorial 12 Buffering
-    B12,
+    B12 {
+        /// write the buffering stats sampled every second to this CSV file
+        #[structopt(long, parse(from_os_str))]
+        stats_out: Option<std::path::PathBuf>,
+    },
     // Basic tutorial 13 PlaybackSpeed
-    B13,
+    B13 {
+        /// TOML file remapping the transport keys (play_pause, rate_up, rate_down, reverse, next_frame, quit)
+        #[structopt(long, parse(from_os_str))]
+        keymap: Option<std::path::PathBuf>,
+        /// record every command with its time into this session file
+        #[structopt(long, parse(from_os_str))]
+        record_session: Option<std::path::PathBuf>,
+        /// re-execute the commands of a recorded session file at their recorded times
+        #[structopt(long, parse(from_os_str))]
+        replay: Option<std::path::PathBuf>,
+    },
 
     // test metadata view
     T1,
+    /// Seekable appsrc backed by generated WAV or a local file
+    SeekableSrc {
+        /// read from this file instead of generated in-memory data
+        #[structopt(parse(from_os_str))]
+        path: Option<std::path::PathBuf>,
+        /// use random-access (pull mode) instead of seekable stream type
+        #[structopt(long)]
+        random_access: bool,
+    },
+    /// Push procedurally drawn RGB frames through appsrc
+    VideoAppSrc {
+        /// number of frames to push before EOS
+        #[structopt(long, default_value = "300")]
+        frames: u64,
+        /// encode to this matroska file instead of displaying
+        #[structopt(long, parse(from_os_str))]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Automate properties over time with GstController
+    Controller {
+        /// move the rsroi mask instead of fading the audio volume
+        #[structopt(long)]
+        roi: bool,
+    },
+    /// Send custom downstream marker events and detect them at the sink
+    Marker {
+        /// insert a marker every N buffers
+        #[structopt(long, default_value = "30")]
+        interval: u64,
+    },
+    /// Record live video, 'k' forces a keyframe
+    Record {
+        /// path or s3://bucket/key, defaults to record.<container extension>
+        output: Option<String>,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mp4")]
+        container: container::Container,
+        /// move the MP4 index to the head of the file on EOS for progressive playback
+        #[structopt(long)]
+        faststart: bool,
+        /// reserve an MP4 index for N seconds so an interrupted recording stays playable
+        #[structopt(long)]
+        reserved_moov: Option<u64>,
+        /// start a new matroska cluster at least every N seconds so a crashed recording loses less
+        #[structopt(long)]
+        cluster_duration: Option<u64>,
+        /// stop after recording N seconds
+        #[structopt(long)]
+        max_duration: Option<u64>,
+        /// stop after writing N megabytes
+        #[structopt(long)]
+        max_size: Option<u64>,
+    },
+    /// Record live video into N-second chunks with splitmuxsink
+    SegRecord {
+        /// output directory
+        #[structopt(long, default_value = ".", parse(from_os_str))]
+        dir: std::path::PathBuf,
+        /// length of each chunk in seconds
+        #[structopt(long, default_value = "10")]
+        segment: u64,
+        /// keep at most this many chunks, removing the oldest
+        #[structopt(long)]
+        max_files: Option<usize>,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mp4")]
+        container: container::Container,
+        /// encrypt each closed chunk with the key in this file (created if missing) and remove the plain file
+        #[structopt(long, parse(from_os_str))]
+        encrypt_key: Option<std::path::PathBuf>,
+    },
+    /// Decrypt a chunk recorded with seg-record --encrypt-key into an appsrc and play it
+    DecryptPlay {
+        /// encrypted chunk (*.enc)
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+        /// key file used when recording
+        #[structopt(long, parse(from_os_str))]
+        key: std::path::PathBuf,
+    },
+    /// Play a flash and a beep while capturing them with a camera and a microphone to measure the A/V offset
+    AvSync {
+        /// analyze the generated flash and beep directly instead of capturing them
+        #[structopt(long)]
+        loopback: bool,
+        /// camera device such as /dev/video0, autovideosrc if omitted
+        #[structopt(long)]
+        video_device: Option<String>,
+        /// pulseaudio source name, autoaudiosrc if omitted
+        #[structopt(long)]
+        audio_device: Option<String>,
+        /// milliseconds between flashes
+        #[structopt(long, default_value = "1000")]
+        period: u64,
+        /// seconds to measure
+        #[structopt(long, default_value = "10")]
+        duration: u64,
+        /// generate the beep this many milliseconds after the flash, to check the analysis
+        #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+        skew: i64,
+    },
+    /// Record several cameras into separate files sharing one clock, tagged with the start time
+    Multicam {
+        /// camera device such as /dev/video0, can be repeated
+        #[structopt(long = "device", number_of_values = 1)]
+        devices: Vec<String>,
+        /// number of live test patterns to record in addition to the devices
+        #[structopt(long, default_value = "0")]
+        test: u32,
+        /// output directory or s3://bucket/prefix
+        #[structopt(long, default_value = ".")]
+        dir: String,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mkv")]
+        container: container::Container,
+        /// seconds without frames regarded as stalled
+        #[structopt(long, default_value = "2")]
+        stall: u64,
+    },
+    /// Capture one frame every N seconds from a live source into a time-lapse video
+    Timelapse {
+        /// path or s3://bucket/key, defaults to timelapse.<container extension>
+        output: Option<String>,
+        /// camera device such as /dev/video0, videotestsrc if omitted
+        #[structopt(long)]
+        device: Option<String>,
+        /// seconds between captured frames
+        #[structopt(long, default_value = "5")]
+        interval: f64,
+        /// frame rate of the written video
+        #[structopt(long, default_value = "30")]
+        fps: i32,
+        #[structopt(long, default_value = "1280")]
+        width: i32,
+        #[structopt(long, default_value = "720")]
+        height: i32,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mkv")]
+        container: container::Container,
+        /// stop after capturing N frames
+        #[structopt(long)]
+        max_frames: Option<u64>,
+        /// seconds to wait before restarting a failed capture
+        #[structopt(long, default_value = "5")]
+        retry: u64,
+    },
+    /// Loop a short local clip seamlessly for digital signage, restarting when playback stalls
+    Kiosk {
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+        /// local time range to play in, such as 09:00-18:00 or 22:00-06:00
+        #[structopt(long)]
+        schedule: Option<kiosk::Schedule>,
+        /// seconds without position progress regarded as stalled
+        #[structopt(long, default_value = "5")]
+        stall: u64,
+        /// seconds to wait before restarting after an error or stall
+        #[structopt(long, default_value = "5")]
+        retry: u64,
+    },
+    /// Pass reduced video frames to an object detector and draw the returned boxes on the preview
+    Detect {
+        /// video URI, a moving test pattern is used if omitted
+        uri: Option<String>,
+        /// detections per second
+        #[structopt(long, default_value = "5")]
+        fps: i32,
+        /// width of the frames passed to the detector
+        #[structopt(long, default_value = "320")]
+        width: i32,
+    },
+    /// Play a URI while transcribing its audio in chunks with an external speech recognizer
+    Transcribe {
+        uri: String,
+        /// seconds of audio passed to the recognizer at once
+        #[structopt(long, default_value = "5")]
+        chunk: f64,
+        /// transcribe without playing the audio
+        #[structopt(long)]
+        no_playback: bool,
+        /// whisper.cpp ggml model, the dummy transcriber printing levels is used if omitted
+        #[structopt(long, parse(from_os_str))]
+        whisper_model: Option<std::path::PathBuf>,
+        /// whisper.cpp command line executable
+        #[structopt(long, default_value = "whisper-cli")]
+        whisper_command: String,
+        /// language passed to whisper, such as en or ja
+        #[structopt(long)]
+        language: Option<String>,
+    },
+    /// Capture the microphone and stream it to an Icecast server as Ogg/Opus or MP3
+    Icecast {
+        #[structopt(long, default_value = "localhost")]
+        host: String,
+        #[structopt(long, default_value = "8000")]
+        port: u16,
+        /// mount point such as /radio.ogg
+        #[structopt(long, default_value = "/gst-learn")]
+        mount: String,
+        #[structopt(long, default_value = "source")]
+        username: String,
+        #[structopt(long, default_value = "hackme")]
+        password: String,
+        /// station name shown in the directory
+        #[structopt(long)]
+        name: Option<String>,
+        #[structopt(long)]
+        description: Option<String>,
+        #[structopt(long)]
+        genre: Option<String>,
+        /// ogg|mp3
+        #[structopt(long, default_value = "ogg")]
+        format: icecast::Format,
+        /// kbps
+        #[structopt(long, default_value = "128")]
+        bitrate: u32,
+        /// stream a live test tone instead of the microphone
+        #[structopt(long)]
+        test: bool,
+        /// initial song title, change it at runtime by typing a line
+        #[structopt(long)]
+        title: Option<String>,
+        /// seconds to wait before reconnecting
+        #[structopt(long, default_value = "5")]
+        retry: u64,
+    },
+    /// Mix music and voice, lowering the music while voice is present
+    Ducking {
+        /// music URI, a test tone is used if omitted
+        #[structopt(long)]
+        music: Option<String>,
+        /// use the microphone instead of a test voice
+        #[structopt(long)]
+        mic: bool,
+        /// voice level in dB regarded as speaking
+        #[structopt(long, default_value = "-30")]
+        threshold: f64,
+        /// music volume while ducked
+        #[structopt(long, default_value = "0.2")]
+        duck: f64,
+    },
+    /// Mix a secondary audio track such as audio description over the primary track
+    SecondaryAudio {
+        /// primary audio URI
+        primary: String,
+        /// secondary audio URI mixed over the primary
+        secondary: String,
+        #[structopt(long, default_value = "1.0")]
+        primary_volume: f64,
+        #[structopt(long, default_value = "1.0")]
+        secondary_volume: f64,
+        /// milliseconds to wait before starting the secondary audio
+        #[structopt(long, default_value = "0")]
+        delay: u64,
+    },
+    /// List the sounding segments of an audio file split at silences found by rsdetectsilence
+    Silence {
+        /// input file path or URI
+        input: String,
+        /// level in dBFS below which the audio counts as silent
+        #[structopt(long, default_value = "-50", allow_hyphen_values = true)]
+        threshold: f64,
+        /// milliseconds the level has to stay below the threshold
+        #[structopt(long, default_value = "500")]
+        hold: u64,
+    },
+    /// Encode audio into numbered files, starting a new file after each silence found by rsdetectsilence
+    SplitAudio {
+        /// input file path or URI
+        input: String,
+        /// directory to write the parts to
+        #[structopt(long, default_value = ".", parse(from_os_str))]
+        dir: std::path::PathBuf,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mkv")]
+        container: container::Container,
+        /// level in dBFS below which the audio counts as silent
+        #[structopt(long, default_value = "-50", allow_hyphen_values = true)]
+        threshold: f64,
+        /// milliseconds of silence that start a new file
+        #[structopt(long, default_value = "1000")]
+        min_silence: u64,
+    },
+    /// Render subtitles parsed from an SRT file with textoverlay
+    Subtitles {
+        #[structopt(parse(from_os_str))]
+        srt: std::path::PathBuf,
+        /// video URI, videotestsrc is used if omitted
+        #[structopt(long)]
+        uri: Option<String>,
+    },
+    /// Send processed video to a v4l2loopback device
+    VirtualCam {
+        /// v4l2loopback device
+        #[structopt(long, default_value = "/dev/video10")]
+        device: String,
+        /// camera device to read from, videotestsrc is used if omitted
+        #[structopt(long)]
+        input: Option<String>,
+        /// none|gray|roi
+        #[structopt(long, default_value = "none")]
+        filter: virtualcam::Filter,
+        #[structopt(long, default_value = "YUY2")]
+        format: String,
+        #[structopt(long, default_value = "640")]
+        width: i32,
+        #[structopt(long, default_value = "480")]
+        height: i32,
+        #[structopt(long, default_value = "30")]
+        fps: i32,
+    },
+    /// Capture the desktop, previewing and optionally recording it
+    Screen {
+        /// ximage|pipewire
+        #[structopt(long, default_value = "ximage")]
+        source: screen::ScreenSource,
+        /// capture only x,y,width,height
+        #[structopt(long)]
+        region: Option<screen::Region>,
+        #[structopt(long, default_value = "30")]
+        fps: i32,
+        /// record to this path or s3://bucket/key while previewing
+        #[structopt(long)]
+        output: Option<String>,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mp4")]
+        container: container::Container,
+    },
+    /// List PipeWire nodes or route a node through pipewiresrc/pipewiresink
+    PipeWire {
+        /// list nodes and exit
+        #[structopt(long)]
+        list: bool,
+        /// source node (object.serial or node.name)
+        #[structopt(long)]
+        source: Option<String>,
+        /// sink node (object.serial or node.name)
+        #[structopt(long)]
+        sink: Option<String>,
+        /// capture video instead of audio
+        #[structopt(long)]
+        video: bool,
+    },
+    /// Build and control a pipeline interactively
+    Repl,
+    /// List element factories in the registry
+    List {
+        /// klass such as Encoder/Video
+        #[structopt(long)]
+        klass: Option<String>,
+        /// none|marginal|secondary|primary or a number
+        #[structopt(long)]
+        min_rank: Option<registry::MinRank>,
+        /// elements that can consume these caps, e.g. video/x-raw,format=NV12
+        #[structopt(long)]
+        sink_caps: Option<String>,
+        /// elements that can produce these caps
+        #[structopt(long)]
+        src_caps: Option<String>,
+    },
+    /// Explain whether two elements can be linked from their pad template caps
+    CanLink {
+        src: String,
+        sink: String,
+    },
+    /// Report the GStreamer version, environment, plugin scan problems and missing elements per subcommand,
+    /// exiting non-zero if something required is missing
+    Doctor {
+        /// subcommands to check such as record hls-serve, all when omitted
+        subcommands: Vec<String>,
+    },
+    /// Transcode a file, URI or stdin ("-") into a container, writing to a file or stdout ("-")
+    Transcode {
+        input: String,
+        output: String,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mp4")]
+        container: container::Container,
+        /// analyze the input in a first pass and encode to --bitrate in a second pass
+        #[structopt(long)]
+        two_pass: bool,
+    },
+    /// Bridge two pipelines in one process through appsink and appsrc
+    Bridge {
+        /// frames produced by the first pipeline
+        #[structopt(long, default_value = "300")]
+        frames: u32,
+    },
+    /// Connect two pipelines with intervideosink/intervideosrc or proxysink/proxysrc
+    Inter {
+        /// use proxysink/proxysrc instead of intervideosink/intervideosrc
+        #[structopt(long)]
+        proxy: bool,
+        /// seconds to run
+        #[structopt(long, default_value = "10")]
+        duration: u64,
+    },
+    /// Play one program of a multi-program MPEG-TS and switch programs while playing
+    Ts {
+        /// TS file path or URI
+        input: String,
+        /// program number to play first, the first program in the PAT if omitted
+        #[structopt(long)]
+        program: Option<u16>,
+    },
+    /// Build urisourcebin ! decodebin3 by hand and select streams by caps
+    Decodebin3 {
+        /// defaults to the tutorial B3 trailer
+        uri: Option<String>,
+        /// select the streams whose encoded caps intersect these caps
+        #[structopt(long)]
+        caps: Option<String>,
+    },
+    /// Play with playbin3 and select streams from the StreamCollection by stream-id
+    Streams {
+        uri: String,
+        /// index of the audio stream selected first
+        #[structopt(long)]
+        audio: Option<usize>,
+        /// index of the subtitle stream selected first, none if omitted
+        #[structopt(long)]
+        text: Option<usize>,
+    },
+    /// Play with playbin, save the playback state with 'S' and resume from a saved state
+    Snapshot {
+        /// media URI, replaces the one in the restored snapshot
+        uri: Option<String>,
+        /// snapshot file to resume from
+        #[structopt(long, parse(from_os_str))]
+        restore: Option<std::path::PathBuf>,
+        /// file written when 'S' is pressed
+        #[structopt(long, default_value = "snapshot.toml", parse(from_os_str))]
+        save: std::path::PathBuf,
+        /// video-filter in gst-launch syntax such as "videoflip method=clockwise"
+        #[structopt(long)]
+        video_filter: Option<String>,
+        /// audio-filter in gst-launch syntax such as "audioecho delay=200000000"
+        #[structopt(long)]
+        audio_filter: Option<String>,
+    },
+    /// Delay one branch of a tee with a pad offset and show it next to the original
+    Offset {
+        /// milliseconds added to the running time of the delayed branch
+        #[structopt(long, default_value = "500")]
+        offset: u64,
+    },
+    /// Play audio through audioresample on a drifting clock and report resampler statistics
+    Resample {
+        /// audioresample quality (0-10)
+        #[structopt(long, default_value = "4")]
+        quality: i32,
+        /// auto|interpolated|full
+        #[structopt(long, default_value = "auto")]
+        sinc_filter_mode: String,
+        /// how far the pipeline clock runs from real time, in ppm
+        #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+        drift_ppm: f64,
+        /// resample|skew|none
+        #[structopt(long, default_value = "resample")]
+        slave_method: String,
+        /// output sample rate
+        #[structopt(long, default_value = "44100")]
+        rate: u32,
+    },
+    /// Render a video with sync=true on a clock that runs faster (or slower) than real time
+    Clock {
+        /// clock speed relative to real time
+        #[structopt(long, default_value = "4.0")]
+        rate: f64,
+        /// frames to render at 30fps
+        #[structopt(long, default_value = "300")]
+        frames: u32,
+    },
+    /// Find DLNA renderers with SSDP and play a local file on one of them
+    Cast {
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+        /// pick the first renderer whose friendly name contains this text
+        #[structopt(long)]
+        renderer: Option<String>,
+        /// only list the renderers that answered
+        #[structopt(long)]
+        list: bool,
+        /// HTTP port to serve the file from (0 picks a free port)
+        #[structopt(long, default_value = "0")]
+        port: u16,
+        /// seconds to wait for SSDP answers
+        #[structopt(long, default_value = "3")]
+        timeout: u64,
+    },
+    /// Transcode a file or URI to fragmented MP4 and play it on a Chromecast
+    Chromecast {
+        /// file path or URI
+        input: String,
+        /// address of the Chromecast (host or host:port); found with mDNS when omitted
+        #[structopt(long)]
+        device: Option<String>,
+        /// pick the first device whose name contains this text
+        #[structopt(long)]
+        name: Option<String>,
+        /// HTTP port to serve the stream from (0 picks a free port)
+        #[structopt(long, default_value = "0")]
+        port: u16,
+        /// seconds to wait for mDNS answers
+        #[structopt(long, default_value = "3")]
+        timeout: u64,
+    },
+    /// Encode a file, URI or live test source into HLS and serve it over HTTP
+    HlsServe {
+        /// file path or URI (live test source when omitted)
+        input: Option<String>,
+        #[structopt(long, default_value = "8080")]
+        port: u16,
+        /// segment duration in seconds
+        #[structopt(long, default_value = "2")]
+        target_duration: u32,
+        /// number of segments listed in the playlist
+        #[structopt(long, default_value = "5")]
+        playlist_length: u32,
+        /// directory for the playlist and segments (a temporary one when omitted)
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<std::path::PathBuf>,
+        /// also upload completed segments and the playlist to s3://bucket/prefix or http(s)://host/path
+        #[structopt(long)]
+        upload: Option<hls_upload::Endpoint>,
+        /// times to retry a failed upload
+        #[structopt(long, default_value = "3")]
+        upload_retries: u32,
+    },
+    /// Encode a file, URI or live test source into a multi-bitrate DASH ladder and serve it over HTTP
+    DashServe {
+        /// file path or URI (live test source when omitted)
+        input: Option<String>,
+        #[structopt(long, default_value = "8080")]
+        port: u16,
+        /// segment duration in seconds
+        #[structopt(long, default_value = "2")]
+        target_duration: u32,
+        /// video rendition as WIDTHxHEIGHT@KBPS, repeat for a ladder (720p/480p/360p when omitted)
+        #[structopt(long = "rendition")]
+        renditions: Vec<ladder::Rendition>,
+        /// directory for the manifest and segments (a temporary one when omitted)
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<std::path::PathBuf>,
+    },
+    /// Convert frame rates with videorate, or undo telecine / interlacing, and count the frames
+    Framerate {
+        /// videorate, ivtc or deinterlace
+        #[structopt(long, default_value = "videorate")]
+        mode: framerate::Mode,
+        /// frame rate of the test source (24/1 or 24000/1001 for ivtc)
+        #[structopt(long, default_value = "30/1")]
+        input_rate: framerate::Rate,
+        /// target frame rate for videorate
+        #[structopt(long, default_value = "24/1")]
+        rate: framerate::Rate,
+        /// only drop frames in videorate, never duplicate them
+        #[structopt(long)]
+        drop_only: bool,
+        /// frames produced by the test source
+        #[structopt(long, default_value = "300")]
+        frames: u32,
+    },
+    /// Export a slow-motion copy of a video by stretching its timestamps
+    Slowmo {
+        /// input file path or URI
+        input: String,
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mp4")]
+        container: container::Container,
+        /// how many times slower the output plays
+        #[structopt(long, default_value = "4")]
+        factor: f64,
+        /// none keeps the original frames, duplicate fills the gaps with videorate
+        #[structopt(long, default_value = "duplicate")]
+        interpolation: slowmo::Interpolation,
+        /// output frame rate for duplicate, defaults to the input frame rate
+        #[structopt(long)]
+        fps: Option<framerate::Rate>,
+    },
+    /// Send live MPEG-TS over UDP with ID3 timed metadata injected at runtime
+    MetaSend {
+        #[structopt(long, default_value = "127.0.0.1")]
+        host: String,
+        #[structopt(long, default_value = "5004")]
+        port: u16,
+        /// seconds between wall-clock tags
+        #[structopt(long, default_value = "2")]
+        interval: u64,
+    },
+    /// Receive the MetaSend stream, showing the video and printing its ID3 timed metadata
+    MetaRecv {
+        #[structopt(long, default_value = "5004")]
+        port: u16,
+    },
+    /// Stream raw test video to other processes through shared memory with shmsink
+    ShmSend {
+        /// control socket path, the caps are written next to it with a .caps suffix
+        #[structopt(long, default_value = "/tmp/gst-learn-shm", parse(from_os_str))]
+        socket: std::path::PathBuf,
+        #[structopt(long, default_value = "640")]
+        width: i32,
+        #[structopt(long, default_value = "480")]
+        height: i32,
+        #[structopt(long, default_value = "30")]
+        fps: i32,
+        /// stop after this many frames
+        #[structopt(long)]
+        frames: Option<u32>,
+    },
+    /// Receive and display the ShmSend video with shmsrc
+    ShmRecv {
+        #[structopt(long, default_value = "/tmp/gst-learn-shm", parse(from_os_str))]
+        socket: std::path::PathBuf,
+        /// seconds to wait for the sender's caps file
+        #[structopt(long, default_value = "10")]
+        wait: u64,
+    },
+    /// Host named pipelines created and controlled through a WebSocket JSON API
+    Daemon {
+        /// address to listen on, pipelines can write any file so keep it local
+        #[structopt(long, default_value = "127.0.0.1")]
+        host: String,
+        #[structopt(long, default_value = "5000")]
+        port: u16,
+    },
+    /// Measure the loudness of every audio file in a directory and write normalized copies in parallel
+    BatchNormalize {
+        /// directory with the input audio files
+        #[structopt(parse(from_os_str))]
+        dir: std::path::PathBuf,
+        /// directory to write the normalized files to
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+        /// mp4|mkv|webm
+        #[structopt(long, default_value = "mkv")]
+        container: container::Container,
+        /// loudness to normalize to, as rganalysis reference-level in dB (89 is the ReplayGain standard)
+        #[structopt(long, default_value = "89")]
+        reference_level: f64,
+        /// number of files processed at the same time
+        #[structopt(long, default_value = "4")]
+        jobs: usize,
+    },
+    /// Watch a directory and probe, transcode or thumbnail new media files as they arrive
+    Watch {
+        /// directory to watch for new files
+        #[structopt(parse(from_os_str))]
+        dir: std::path::PathBuf,
+        /// directory to write the results to
+        #[structopt(parse(from_os_str))]
+        output: std::path::PathBuf,
+        /// probe|transcode|thumbnail
+        #[structopt(long, default_value = "probe")]
+        action: watch::Action,
+        /// mp4|mkv|webm, for --action transcode
+        #[structopt(long, default_value = "mkv")]
+        container: container::Container,
+        /// number of files processed at the same time
+        #[structopt(long, default_value = "2")]
+        jobs: usize,
+        /// ledger of processed files, defaults to .gst-learn-watch in the watched directory
+        #[structopt(long, parse(from_os_str))]
+        ledger: Option<std::path::PathBuf>,
+    },
+    /// Share buffers through a tee and count when writing to them copies the buffer or its memory
+    Cow {
+        /// frames produced by the test source
+        #[structopt(long, default_value = "100")]
+        frames: u32,
+        /// none|meta|data, what the writer branch modifies
+        #[structopt(long, default_value = "data")]
+        write: cow::Write,
+    },
+    /// Play HDR (PQ/HLG) content and print its colorimetry and mastering display metadata
+    Hdr {
+        uri: String,
+        /// convert to BT.709 for SDR displays
+        #[structopt(long)]
+        tone_map: bool,
+    },
+    /// Run a pipeline through the timed seek/pause/play/EOS actions of a TOML scenario
+    Scenario {
+        #[structopt(parse(from_os_str))]
+        scenario: std::path::PathBuf,
+        /// gst-launch description used instead of the scenario's pipeline
+        #[structopt(long)]
+        pipeline: Option<String>,
+    },
+    /// Build a pipeline from a TOML file listing elements, properties and links
+    Config {
+        #[structopt(parse(from_os_str))]
+        config: std::path::PathBuf,
+        /// only build the pipeline and print it as a gst-launch description
+        #[structopt(long)]
+        check: bool,
+    },
+    /// Send VP8 over RTP to a local receiver with simulated packet loss, recovered by RTX and/or ULPFEC
+    Rtp {
+        /// base UDP port (RTP on port, RTCP on port+1 and port+5)
+        #[structopt(long, default_value = "5000")]
+        port: u16,
+        /// probability of dropping each packet before it is sent (0.0-1.0)
+        #[structopt(long, default_value = "0.02")]
+        drop: f32,
+        /// retransmit lost packets on NACK with rtprtxsend/rtprtxreceive
+        #[structopt(long)]
+        rtx: bool,
+        /// ULPFEC overhead in percent of the media packets (0 disables FEC)
+        #[structopt(long, default_value = "0")]
+        fec: u32,
+        /// network impairment added with netsim, e.g. loss=2%,jitter=30ms,reorder=1%
+        #[structopt(long)]
+        impair: Option<impair::Impairment>,
+        /// initial VP8 bitrate in kbps
+        #[structopt(long, default_value = "1500")]
+        bitrate: u32,
+        /// follow the loss rate in RTCP receiver reports and adjust the bitrate
+        #[structopt(long)]
+        adaptive: bool,
+        /// lower bound in kbps for --adaptive
+        #[structopt(long, default_value = "300")]
+        min_bitrate: u32,
+        /// upper bound in kbps for --adaptive
+        #[structopt(long, default_value = "3000")]
+        max_bitrate: u32,
+        /// seconds to run
+        #[structopt(long, default_value = "10")]
+        duration: u64,
+    },
+}
+impl Tutorial {
+    /// バスループのフラグのうち、このサブコマンドが扱えるもの
+    fn bus_loop_flags(&self) -> &'static [&'static str] {
+        match self {
+            Tutorial::Marker { .. }
+            | Tutorial::DecryptPlay { .. }
+            | Tutorial::SecondaryAudio { .. }
+            | Tutorial::Detect { .. }
+            | Tutorial::Transcribe { .. }
+            | Tutorial::Subtitles { .. }
+            | Tutorial::VirtualCam { .. }
+            | Tutorial::PipeWire { .. }
+            | Tutorial::Transcode { .. }
+            | Tutorial::Offset { .. }
+            | Tutorial::Resample { .. }
+            | Tutorial::Clock { .. }
+            | Tutorial::HlsServe { .. }
+            | Tutorial::DashServe { .. }
+            | Tutorial::Framerate { .. }
+            | Tutorial::Slowmo { .. }
+            | Tutorial::MetaRecv { .. }
+            | Tutorial::ShmSend { .. }
+            | Tutorial::ShmRecv { .. }
+            | Tutorial::Cow { .. }
+            | Tutorial::Hdr { .. }
+            | Tutorial::Config { .. } => runner::FLAGS,
+            // 再エンコードだけがrunnerを通る
+            Tutorial::Watch { action, .. } if matches!(action, watch::Action::Transcode) => {
+                runner::FLAGS
+            }
+            // 独自のバスウォッチで通知とgst-launchの記述だけを扱う
+            Tutorial::B12 { .. } => &["--beep", "--print-launch"],
+            _ => &[],
+        }
+    }
 }
+
 fn main() {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    let opt = Opt::from_args();
+    let opt = settings::from_args::<Opt>();
+    let unsupported = opt.bus_loop.unsupported(opt.tid.bus_loop_flags());
+    if !unsupported.is_empty() {
+        structopt::clap::Error::with_description(
+            &format!(
+                "{} cannot be used with this subcommand, it does not run the common bus loop",
+                unsupported.join(", ")
+            ),
+            structopt::clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
 
     match opt.tid {
-        Tutorial::B1 => tutorial_helloworld().unwrap(),
+        Tutorial::B1 => tutorial_helloworld(
+            &opt.mpris,
+            &opt.replaygain,
+            &opt.tui,
+            &opt.http,
+            &opt.deinterlace,
+            &opt.sync,
+        )
+        .unwrap(),
         Tutorial::B2 => tutorial_concept().unwrap(),
         Tutorial::B3 => tutorial_dynamic_pipeline().unwrap(),
-        Tutorial::B4 => tutorial_queue().unwrap(),
-        Tutorial::B5 => tutorial_guikit().unwrap(),
+        Tutorial::B4 => tutorial_queue(
+            &opt.mpris,
+            &opt.replaygain,
+            &opt.tui,
+            &opt.http,
+            &opt.deinterlace,
+            &opt.sync,
+        )
+        .unwrap(),
+        Tutorial::B5 {
+            sync_handler,
+            scrub,
+            scrub_audio,
+        } => tutorial_guikit(sync_handler, scrub, scrub_audio).unwrap(),
         Tutorial::B6 => tutorial_media_pad().unwrap(),
-        Tutorial::B7 => tutorial_multithread_pad().unwrap(),
-        Tutorial::B8 => tutorial_shortcut_pipeline().unwrap(),
-        Tutorial::B9 { uri } => tutorial_media_info(&uri).unwrap(),
-        Tutorial::B12 => tutorial_streaming().unwrap(),
-        Tutorial::B13 => tutorial_playback_speed().unwrap(),
-        Tutorial::T1 => preview_metadata().unwrap(),
+        Tutorial::B7 => tutorial_multithread_pad(&opt.queue_monitor, &opt.profile).unwrap(),
+        Tutorial::B8 => {
+            tutorial_shortcut_pipeline(&opt.queue_monitor, &opt.profile, &opt.appsink).unwrap()
+        }
+        Tutorial::B9 { uri, cover_dir } => tutorial_media_info(&uri, cover_dir.as_deref()).unwrap(),
+        Tutorial::B12 { stats_out } => tutorial_streaming(
+            &opt.mpris,
+            &opt.replaygain,
+            &opt.tui,
+            &opt.http,
+            &opt.deinterlace,
+            &opt.bus_loop,
+            stats_out.as_deref(),
+        )
+        .unwrap(),
+        Tutorial::B13 {
+            keymap,
+            record_session,
+            replay,
+        } => tutorial_playback_speed(
+            &opt.mpris,
+            &opt.replaygain,
+            &opt.tui,
+            &opt.http,
+            &opt.deinterlace,
+            keymap.as_deref(),
+            record_session.as_deref(),
+            replay.as_deref(),
+        )
+        .unwrap(),
+        Tutorial::T1 => preview_metadata(&opt.queue_monitor, &opt.profile, &opt.appsink).unwrap(),
+        Tutorial::SeekableSrc {
+            path,
+            random_access,
+        } => seekable_src::run(path.as_deref(), random_access).unwrap(),
+        Tutorial::VideoAppSrc { frames, output } => {
+            video_appsrc::run(frames, output.as_deref()).unwrap()
+        }
+        Tutorial::Controller { roi } => controller::run(roi).unwrap(),
+        Tutorial::Marker { interval } => custom_event::run(interval, &opt.bus_loop).unwrap(),
+        Tutorial::Record {
+            output,
+            container,
+            faststart,
+            reserved_moov,
+            cluster_duration,
+            max_duration,
+            max_size,
+        } => record::run(
+            &record::RecordOptions {
+                output: output.unwrap_or_else(|| format!("record.{}", container.extension())),
+                container,
+                muxer: container::MuxerOptions {
+                    faststart,
+                    reserved_moov: reserved_moov.map(gst::ClockTime::from_seconds),
+                    cluster_duration: cluster_duration.map(gst::ClockTime::from_seconds),
+                    ..Default::default()
+                },
+                auto_stop: record::AutoStop {
+                    max_duration: max_duration.map(gst::ClockTime::from_seconds),
+                    max_size: max_size.map(|mb| mb * 1_000_000),
+                },
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::SegRecord {
+            dir,
+            segment,
+            max_files,
+            container,
+            encrypt_key,
+        } => record::run_segmented(
+            record::SegmentOptions {
+                dir,
+                duration: gst::ClockTime::from_seconds(segment),
+                max_files,
+                container,
+                encrypt_key,
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::DecryptPlay { path, key } => {
+            crypt::play(&crypt::DecryptPlayOptions { path, key }, &opt.bus_loop).unwrap()
+        }
+        Tutorial::AvSync {
+            loopback,
+            video_device,
+            audio_device,
+            period,
+            duration,
+            skew,
+        } => av_sync::run(&av_sync::AvSyncOptions {
+            capture: if loopback {
+                av_sync::Capture::Loopback
+            } else {
+                av_sync::Capture::Devices {
+                    video: video_device,
+                    audio: audio_device,
+                }
+            },
+            period: std::time::Duration::from_millis(period),
+            duration: std::time::Duration::from_secs(duration),
+            skew_ms: skew,
+        })
+        .unwrap(),
+        Tutorial::Multicam {
+            devices,
+            test,
+            dir,
+            container,
+            stall,
+        } => multicam::run(
+            &multicam::MulticamOptions {
+                sources: devices
+                    .into_iter()
+                    .map(multicam::Source::Device)
+                    .chain((0..test).map(multicam::Source::Test))
+                    .collect(),
+                dir,
+                container,
+                stall: std::time::Duration::from_secs(stall),
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::Timelapse {
+            output,
+            device,
+            interval,
+            fps,
+            width,
+            height,
+            container,
+            max_frames,
+            retry,
+        } => timelapse::run(
+            &timelapse::TimelapseOptions {
+                output: output.unwrap_or_else(|| format!("timelapse.{}", container.extension())),
+                device,
+                interval: gst::ClockTime::from_nseconds((interval * 1e9) as u64),
+                fps,
+                width,
+                height,
+                container,
+                max_frames,
+                retry: gst::ClockTime::from_seconds(retry),
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::SecondaryAudio {
+            primary,
+            secondary,
+            primary_volume,
+            secondary_volume,
+            delay,
+        } => secondary_audio::run(
+            &secondary_audio::SecondaryAudioOptions {
+                primary,
+                secondary,
+                primary_volume,
+                secondary_volume,
+                delay: gst::ClockTime::from_mseconds(delay),
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Silence {
+            input,
+            threshold,
+            hold,
+        } => silence::run(&silence::SilenceOptions {
+            input,
+            threshold,
+            hold: gst::ClockTime::from_mseconds(hold),
+        })
+        .unwrap(),
+        Tutorial::SplitAudio {
+            input,
+            dir,
+            container,
+            threshold,
+            min_silence,
+        } => split_audio::run(
+            split_audio::SplitAudioOptions {
+                input,
+                dir,
+                container,
+                threshold,
+                min_silence: gst::ClockTime::from_mseconds(min_silence),
+            },
+            &opt.audio,
+        )
+        .unwrap(),
+        Tutorial::Kiosk {
+            path,
+            schedule,
+            stall,
+            retry,
+        } => kiosk::run(&kiosk::KioskOptions {
+            path,
+            schedule,
+            stall: std::time::Duration::from_secs(stall),
+            retry: std::time::Duration::from_secs(retry),
+            state_timeout: opt.bus_loop.state_timeout(),
+        })
+        .unwrap(),
+        Tutorial::Detect { uri, fps, width } => detect::run(
+            &detect::DetectOptions { uri, fps, width },
+            Box::new(detect::MotionDetector::default()),
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Transcribe {
+            uri,
+            chunk,
+            no_playback,
+            whisper_model,
+            whisper_command,
+            language,
+        } => {
+            let transcriber: Box<dyn transcribe::Transcriber> = match whisper_model {
+                Some(model) => Box::new(transcribe::WhisperCli {
+                    command: whisper_command,
+                    model,
+                    language,
+                }),
+                None => Box::new(transcribe::DummyTranscriber),
+            };
+            transcribe::run(
+                &transcribe::TranscribeOptions {
+                    uri,
+                    chunk: gst::ClockTime::from_nseconds((chunk * 1e9) as u64),
+                    no_playback,
+                },
+                transcriber,
+                &opt.bus_loop,
+            )
+            .unwrap()
+        }
+        Tutorial::Icecast {
+            host,
+            port,
+            mount,
+            username,
+            password,
+            name,
+            description,
+            genre,
+            format,
+            bitrate,
+            test,
+            title,
+            retry,
+        } => icecast::run(&icecast::IcecastOptions {
+            host,
+            port,
+            mount,
+            username,
+            password,
+            name,
+            description,
+            genre,
+            format,
+            bitrate,
+            test,
+            title,
+            retry: std::time::Duration::from_secs(retry),
+        })
+        .unwrap(),
+        Tutorial::Ducking {
+            music,
+            mic,
+            threshold,
+            duck,
+        } => ducking::run(&ducking::DuckingOptions {
+            music,
+            mic,
+            threshold,
+            duck,
+        })
+        .unwrap(),
+        Tutorial::Subtitles { srt, uri } => {
+            subtitles::run(&srt, uri.as_deref(), &opt.bus_loop).unwrap()
+        }
+        Tutorial::VirtualCam {
+            device,
+            input,
+            filter,
+            format,
+            width,
+            height,
+            fps,
+        } => virtualcam::run(
+            &virtualcam::VirtualCamOptions {
+                device,
+                input,
+                filter,
+                format,
+                width,
+                height,
+                fps,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Screen {
+            source,
+            region,
+            fps,
+            output,
+            container,
+        } => screen::run(
+            &screen::ScreenOptions {
+                source,
+                region,
+                fps,
+                output,
+                container,
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::PipeWire {
+            list,
+            source,
+            sink,
+            video,
+        } => pipewire::run(
+            &pipewire::PipeWireOptions {
+                list,
+                source,
+                sink,
+                video,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Repl => repl::run().unwrap(),
+        Tutorial::List {
+            klass,
+            min_rank,
+            sink_caps,
+            src_caps,
+        } => registry::run_list(&registry::ListOptions {
+            klass,
+            min_rank,
+            sink_caps,
+            src_caps,
+        })
+        .unwrap(),
+        Tutorial::CanLink { src, sink } => registry::run_can_link(&src, &sink).unwrap(),
+        Tutorial::Doctor { subcommands } => {
+            if !doctor::run(&subcommands).unwrap() {
+                std::process::exit(1);
+            }
+        }
+        Tutorial::Transcode {
+            input,
+            output,
+            container,
+            two_pass,
+        } => transcode::run(
+            &transcode::TranscodeOptions {
+                input,
+                output,
+                container,
+                two_pass,
+            },
+            &opt.deinterlace,
+            &opt.video,
+            &opt.audio,
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Bridge { frames } => bridge::run(frames).unwrap(),
+        Tutorial::Inter { proxy, duration } => {
+            inter::run(proxy, std::time::Duration::from_secs(duration)).unwrap()
+        }
+        Tutorial::Ts { input, program } => ts::run(&input, program).unwrap(),
+        Tutorial::Decodebin3 { uri, caps } => {
+            decodebin3::run(&decodebin3::Decodebin3Options { uri, caps }).unwrap()
+        }
+        Tutorial::Streams { uri, audio, text } => {
+            streams::run(&streams::StreamsOptions { uri, audio, text }).unwrap()
+        }
+        Tutorial::Snapshot {
+            uri,
+            restore,
+            save,
+            video_filter,
+            audio_filter,
+        } => snapshot::run(
+            &snapshot::SnapshotOptions {
+                uri,
+                restore,
+                save,
+                video_filter,
+                audio_filter,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Offset { offset } => offset::run(offset, &opt.bus_loop).unwrap(),
+        Tutorial::Resample {
+            quality,
+            sinc_filter_mode,
+            drift_ppm,
+            slave_method,
+            rate,
+        } => resample::run(
+            &resample::ResampleOptions {
+                quality,
+                sinc_filter_mode,
+                drift_ppm,
+                slave_method,
+                rate,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Clock { rate, frames } => clocks::run(rate, frames, &opt.bus_loop).unwrap(),
+        Tutorial::Cast {
+            path,
+            renderer,
+            list,
+            port,
+            timeout,
+        } => cast::run(&cast::CastOptions {
+            path,
+            renderer,
+            list,
+            port,
+            timeout: std::time::Duration::from_secs(timeout),
+        })
+        .unwrap(),
+        Tutorial::Chromecast {
+            input,
+            device,
+            name,
+            port,
+            timeout,
+        } => chromecast::run(
+            &chromecast::ChromecastOptions {
+                input,
+                device,
+                name,
+                port,
+                timeout: std::time::Duration::from_secs(timeout),
+            },
+            &opt.deinterlace,
+            &opt.video,
+            &opt.audio,
+        )
+        .unwrap(),
+        Tutorial::HlsServe {
+            input,
+            port,
+            target_duration,
+            playlist_length,
+            dir,
+            upload,
+            upload_retries,
+        } => hls::run(
+            &hls::HlsServeOptions {
+                input,
+                port,
+                target_duration,
+                playlist_length,
+                dir,
+                upload: upload.map(|endpoint| hls_upload::UploadOptions {
+                    endpoint,
+                    retries: upload_retries,
+                }),
+            },
+            &opt.video,
+            &opt.audio,
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::DashServe {
+            input,
+            port,
+            target_duration,
+            renditions,
+            dir,
+        } => dash::run(
+            &dash::DashServeOptions {
+                input,
+                port,
+                target_duration,
+                renditions,
+                dir,
+            },
+            &opt.audio,
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Framerate {
+            mode,
+            input_rate,
+            rate,
+            drop_only,
+            frames,
+        } => framerate::run(
+            &framerate::FramerateOptions {
+                mode,
+                input_rate,
+                rate,
+                drop_only,
+                frames,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Slowmo {
+            input,
+            output,
+            container,
+            factor,
+            interpolation,
+            fps,
+        } => slowmo::run(
+            &slowmo::SlowmoOptions {
+                input,
+                output,
+                container,
+                factor,
+                interpolation,
+                fps,
+            },
+            &opt.video,
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::MetaSend {
+            host,
+            port,
+            interval,
+        } => timed_metadata::send(
+            &timed_metadata::TimedMetadataOptions {
+                host,
+                port,
+                interval: std::time::Duration::from_secs(interval),
+            },
+            &opt.video,
+        )
+        .unwrap(),
+        Tutorial::MetaRecv { port } => timed_metadata::receive(port, &opt.bus_loop).unwrap(),
+        Tutorial::ShmSend {
+            socket,
+            width,
+            height,
+            fps,
+            frames,
+        } => shm::send(
+            &shm::ShmSendOptions {
+                socket,
+                width,
+                height,
+                fps,
+                frames,
+            },
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::ShmRecv { socket, wait } => {
+            shm::receive(&socket, std::time::Duration::from_secs(wait), &opt.bus_loop).unwrap()
+        }
+        Tutorial::Daemon { host, port } => daemon::run(&host, port, &opt.bus_loop).unwrap(),
+        Tutorial::BatchNormalize {
+            dir,
+            output,
+            container,
+            reference_level,
+            jobs,
+        } => batch_normalize::run(
+            &batch_normalize::BatchNormalizeOptions {
+                dir,
+                output,
+                container,
+                reference_level,
+                jobs,
+            },
+            &opt.audio,
+        )
+        .unwrap(),
+        Tutorial::Watch {
+            dir,
+            output,
+            action,
+            container,
+            jobs,
+            ledger,
+        } => watch::run(
+            &watch::WatchOptions {
+                dir,
+                output,
+                action,
+                container,
+                jobs,
+                ledger,
+            },
+            &opt.deinterlace,
+            &opt.video,
+            &opt.audio,
+            &opt.bus_loop,
+        )
+        .unwrap(),
+        Tutorial::Cow { frames, write } => cow::run(frames, write, &opt.bus_loop).unwrap(),
+        Tutorial::Hdr { uri, tone_map } => {
+            hdr::run(&hdr::HdrOptions { uri, tone_map }, &opt.bus_loop).unwrap()
+        }
+        Tutorial::Scenario { scenario, pipeline } => {
+            scenario::run(&scenario::ScenarioOptions { scenario, pipeline }).unwrap()
+        }
+        Tutorial::Config { config, check } => {
+            config::run(&config::ConfigOptions { config, check }, &opt.bus_loop).unwrap()
+        }
+        Tutorial::Rtp {
+            port,
+            drop,
+            rtx,
+            fec,
+            impair,
+            bitrate,
+            adaptive,
+            min_bitrate,
+            max_bitrate,
+            duration,
+        } => rtp::run(&rtp::RtpOptions {
+            port,
+            drop,
+            rtx,
+            fec,
+            impair,
+            bitrate,
+            adaptive,
+            min_bitrate,
+            max_bitrate,
+            duration: std::time::Duration::from_secs(duration),
+        })
+        .unwrap(),
     }
 }
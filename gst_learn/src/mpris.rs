@@ -0,0 +1,449 @@
+//! MPRIS2のD-Busインターフェースでplaybinを操作できるようにする
+//!
+//! デスクトップのメディアキーやプレイヤーウィジェット、playerctlなどはMPRIS2に対応したプレイヤーを
+//! セッションバスから探して操作する。org.mpris.MediaPlayer2.gst_learnの名前で
+//! /org/mpris/MediaPlayer2にルートとPlayerのインターフェースを登録する。
+//!
+//! 各サブコマンドはそれぞれのやり方でバスを監視しているので、ここではバスに触らない。
+//! D-Busの処理は専用スレッドのMainContextで動かし、状態やタグは定期的にplaybinに問い合わせて、
+//! 変わっていたらPropertiesChangedシグナルで知らせる。
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use gio::prelude::*;
+use glib::{ToVariant, Variant};
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::session::{Action, SessionRecorder};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.gst_learn";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+/// 曲は1つしかないので、トラックIDは固定
+const TRACK_ID: &str = "/org/gst_learn/track/0";
+/// 状態とメタデータを確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const INTROSPECTION: &str = r#"
+<node>
+  <interface name="org.mpris.MediaPlayer2">
+    <method name="Raise"/>
+    <method name="Quit"/>
+    <property name="CanQuit" type="b" access="read"/>
+    <property name="CanRaise" type="b" access="read"/>
+    <property name="HasTrackList" type="b" access="read"/>
+    <property name="Identity" type="s" access="read"/>
+    <property name="SupportedUriSchemes" type="as" access="read"/>
+    <property name="SupportedMimeTypes" type="as" access="read"/>
+  </interface>
+  <interface name="org.mpris.MediaPlayer2.Player">
+    <method name="Next"/>
+    <method name="Previous"/>
+    <method name="Pause"/>
+    <method name="PlayPause"/>
+    <method name="Stop"/>
+    <method name="Play"/>
+    <method name="Seek">
+      <arg direction="in" name="Offset" type="x"/>
+    </method>
+    <method name="SetPosition">
+      <arg direction="in" name="TrackId" type="o"/>
+      <arg direction="in" name="Position" type="x"/>
+    </method>
+    <signal name="Seeked">
+      <arg name="Position" type="x"/>
+    </signal>
+    <property name="PlaybackStatus" type="s" access="read"/>
+    <property name="Rate" type="d" access="readwrite"/>
+    <property name="Metadata" type="a{sv}" access="read"/>
+    <property name="Volume" type="d" access="readwrite"/>
+    <property name="Position" type="x" access="read"/>
+    <property name="MinimumRate" type="d" access="read"/>
+    <property name="MaximumRate" type="d" access="read"/>
+    <property name="CanGoNext" type="b" access="read"/>
+    <property name="CanGoPrevious" type="b" access="read"/>
+    <property name="CanPlay" type="b" access="read"/>
+    <property name="CanPause" type="b" access="read"/>
+    <property name="CanSeek" type="b" access="read"/>
+    <property name="CanControl" type="b" access="read"/>
+  </interface>
+</node>
+"#;
+
+/// MPRIS2の設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Default, StructOpt)]
+pub struct MprisOpt {
+    /// MPRIS2のD-Busインターフェースを公開してメディアキーなどから操作できるようにする
+    #[structopt(long)]
+    pub mpris: bool,
+}
+
+impl MprisOpt {
+    /// 有効ならD-Busのスレッドを開始する。返り値をdropすると止まる
+    pub fn start(&self, playbin: &gst::Element) -> anyhow::Result<Option<Mpris>> {
+        self.start_recorded(playbin, None)
+    }
+
+    /// startと同じだが、受け付けた操作をrecorderに記録する
+    pub fn start_recorded(
+        &self,
+        playbin: &gst::Element,
+        recorder: Option<SessionRecorder>,
+    ) -> anyhow::Result<Option<Mpris>> {
+        if !self.mpris {
+            return Ok(None);
+        }
+        Mpris::start(playbin, recorder).map(Some)
+    }
+}
+
+/// 前回知らせた値。変わったものだけPropertiesChangedで送る
+#[derive(Default)]
+struct Snapshot {
+    status: Option<&'static str>,
+    metadata: Option<Variant>,
+}
+
+struct Player {
+    playbin: gst::Element,
+    connection: Mutex<Option<gio::DBusConnection>>,
+    last: Mutex<Snapshot>,
+    /// Stopはplaybinの状態では表せないので自分で覚えておく
+    stopped: Mutex<bool>,
+    recorder: Option<SessionRecorder>,
+}
+
+impl Player {
+    fn record(&self, action: Action) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&action);
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        if *self.stopped.lock().unwrap() {
+            return "Stopped";
+        }
+        match self.playbin.current_state() {
+            gst::State::Playing => "Playing",
+            gst::State::Paused => "Paused",
+            _ => "Stopped",
+        }
+    }
+
+    fn position_us(&self) -> i64 {
+        self.playbin
+            .query_position::<gst::ClockTime>()
+            .map_or(0, |pos| pos.useconds() as i64)
+    }
+
+    /// 再生中のストリームのタグ
+    fn tags(&self) -> Option<gst::TagList> {
+        ["get-audio-tags", "get-video-tags"]
+            .iter()
+            .find_map(|signal| {
+                self.playbin
+                    .emit_by_name::<Option<gst::TagList>>(signal, &[&0i32])
+            })
+    }
+
+    fn metadata(&self) -> Variant {
+        let mut map = HashMap::new();
+        map.insert(
+            "mpris:trackid".to_string(),
+            Variant::parse(None, &format!("objectpath '{TRACK_ID}'"))
+                .unwrap()
+                .to_variant(),
+        );
+        if let Some(uri) = self.playbin.property::<Option<String>>("current-uri") {
+            map.insert("xesam:url".to_string(), uri.to_variant().to_variant());
+        }
+        if let Some(duration) = self.playbin.query_duration::<gst::ClockTime>() {
+            map.insert(
+                "mpris:length".to_string(),
+                (duration.useconds() as i64).to_variant().to_variant(),
+            );
+        }
+        if let Some(tags) = self.tags() {
+            if let Some(title) = tags.get::<gst::tags::Title>() {
+                map.insert(
+                    "xesam:title".to_string(),
+                    title.get().to_variant().to_variant(),
+                );
+            }
+            if let Some(artist) = tags.get::<gst::tags::Artist>() {
+                map.insert(
+                    "xesam:artist".to_string(),
+                    vec![artist.get()].to_variant().to_variant(),
+                );
+            }
+            if let Some(album) = tags.get::<gst::tags::Album>() {
+                map.insert(
+                    "xesam:album".to_string(),
+                    album.get().to_variant().to_variant(),
+                );
+            }
+        }
+        map.to_variant()
+    }
+
+    fn property(&self, interface: &str, name: &str) -> Variant {
+        match (interface, name) {
+            (ROOT_INTERFACE, "CanQuit") => true.to_variant(),
+            (ROOT_INTERFACE, "CanRaise" | "HasTrackList") => false.to_variant(),
+            (ROOT_INTERFACE, "Identity") => "gst_learn".to_variant(),
+            (ROOT_INTERFACE, "SupportedUriSchemes") => vec!["file", "http", "https"].to_variant(),
+            (ROOT_INTERFACE, "SupportedMimeTypes") => Vec::<String>::new().to_variant(),
+            (PLAYER_INTERFACE, "PlaybackStatus") => self.status().to_variant(),
+            (PLAYER_INTERFACE, "Rate" | "MinimumRate" | "MaximumRate") => 1.0.to_variant(),
+            (PLAYER_INTERFACE, "Metadata") => self.metadata(),
+            (PLAYER_INTERFACE, "Volume") => self.playbin.property::<f64>("volume").to_variant(),
+            (PLAYER_INTERFACE, "Position") => self.position_us().to_variant(),
+            (PLAYER_INTERFACE, "CanGoNext" | "CanGoPrevious") => false.to_variant(),
+            (PLAYER_INTERFACE, "CanPlay" | "CanPause" | "CanControl") => true.to_variant(),
+            (PLAYER_INTERFACE, "CanSeek") => {
+                let mut query = gst::query::Seeking::new(gst::Format::Time);
+                (self.playbin.query(&mut query) && query.result().0).to_variant()
+            }
+            _ => {
+                log::warn!("unknown property {interface}.{name}");
+                ().to_variant()
+            }
+        }
+    }
+
+    fn set_property(&self, interface: &str, name: &str, value: &Variant) -> bool {
+        match (interface, name, value.get::<f64>()) {
+            (PLAYER_INTERFACE, "Volume", Some(volume)) => {
+                let volume = volume.clamp(0.0, 10.0);
+                self.playbin.set_property("volume", volume);
+                self.record(Action::Set("volume".to_string(), volume.to_string()));
+                true
+            }
+            // 再生速度はB13で扱うので、ここでは1.0以外を受け付けない
+            (PLAYER_INTERFACE, "Rate", Some(rate)) => rate == 1.0,
+            _ => false,
+        }
+    }
+
+    fn set_state(&self, state: gst::State) {
+        *self.stopped.lock().unwrap() = false;
+        match state {
+            gst::State::Playing => self.record(Action::Play),
+            gst::State::Paused => self.record(Action::Pause),
+            _ => {}
+        }
+        if let Err(err) = self.playbin.set_state(state) {
+            log::error!("failed to set {state:?}: {err}");
+        }
+    }
+
+    fn seek_to(&self, position_us: i64) {
+        let position = gst::ClockTime::from_useconds(position_us.max(0) as u64);
+        if let Err(err) = self
+            .playbin
+            .seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT, position)
+        {
+            log::error!("failed to seek to {position}: {err}");
+            return;
+        }
+        self.record(Action::Seek(position));
+        self.emit(
+            PLAYER_INTERFACE,
+            "Seeked",
+            &(position_us.max(0),).to_variant(),
+        );
+    }
+
+    fn call(&self, interface: &str, method: &str, params: &Variant) -> Result<(), String> {
+        match (interface, method) {
+            (ROOT_INTERFACE, "Raise") => {}
+            // EOSを送ると、各サブコマンドのループがいつもどおり終了処理をする
+            (ROOT_INTERFACE, "Quit") => {
+                self.playbin.send_event(gst::event::Eos::new());
+            }
+            (PLAYER_INTERFACE, "Play") => self.set_state(gst::State::Playing),
+            (PLAYER_INTERFACE, "Pause") => self.set_state(gst::State::Paused),
+            (PLAYER_INTERFACE, "PlayPause") => {
+                let next = if self.status() == "Playing" {
+                    gst::State::Paused
+                } else {
+                    gst::State::Playing
+                };
+                self.set_state(next);
+            }
+            (PLAYER_INTERFACE, "Stop") => {
+                self.set_state(gst::State::Paused);
+                self.seek_to(0);
+                *self.stopped.lock().unwrap() = true;
+            }
+            (PLAYER_INTERFACE, "Next" | "Previous") => {}
+            (PLAYER_INTERFACE, "Seek") => {
+                let (offset,) = params.get::<(i64,)>().ok_or("invalid arguments")?;
+                self.seek_to(self.position_us() + offset);
+            }
+            (PLAYER_INTERFACE, "SetPosition") => {
+                let track = params.child_value(0);
+                let position = params
+                    .child_value(1)
+                    .get::<i64>()
+                    .ok_or("invalid arguments")?;
+                // 別のトラック向けの指示は無視する決まり
+                if track.str() == Some(TRACK_ID) {
+                    self.seek_to(position);
+                }
+            }
+            _ => return Err(format!("unknown method {interface}.{method}")),
+        }
+        self.notify_changes();
+        Ok(())
+    }
+
+    fn emit(&self, interface: &str, signal: &str, params: &Variant) {
+        let connection = self.connection.lock().unwrap();
+        if let Some(connection) = connection.as_ref() {
+            if let Err(err) =
+                connection.emit_signal(None, OBJECT_PATH, interface, signal, Some(params))
+            {
+                log::warn!("failed to emit {signal}: {err}");
+            }
+        }
+    }
+
+    /// 状態とメタデータが前回から変わっていたらPropertiesChangedを送る
+    fn notify_changes(&self) {
+        let status = self.status();
+        let metadata = self.metadata();
+
+        let mut changed = HashMap::new();
+        {
+            let mut last = self.last.lock().unwrap();
+            if last.status != Some(status) {
+                log::info!("mpris: PlaybackStatus {status}");
+                changed.insert("PlaybackStatus".to_string(), status.to_variant());
+                last.status = Some(status);
+            }
+            if last.metadata.as_ref() != Some(&metadata) {
+                changed.insert("Metadata".to_string(), metadata.clone());
+                last.metadata = Some(metadata);
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        self.emit(
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &(PLAYER_INTERFACE, changed, Vec::<String>::new()).to_variant(),
+        );
+    }
+}
+
+/// D-Busのスレッド。dropすると名前を手放して止まる
+pub struct Mpris {
+    main_loop: glib::MainLoop,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Mpris {
+    fn start(playbin: &gst::Element, recorder: Option<SessionRecorder>) -> anyhow::Result<Self> {
+        // DBusNodeInfoはスレッド間で渡せないので、ここでは書式の確認だけして使うときに作り直す
+        gio::DBusNodeInfo::for_xml(INTROSPECTION)?;
+        let player = Arc::new(Player {
+            playbin: playbin.clone(),
+            connection: Mutex::new(None),
+            last: Mutex::new(Snapshot::default()),
+            stopped: Mutex::new(false),
+            recorder,
+        });
+
+        let context = glib::MainContext::new();
+        let main_loop = glib::MainLoop::new(Some(&context), false);
+        let main_loop_clone = main_loop.clone();
+        let thread = std::thread::spawn(move || {
+            // bus_own_nameのコールバックは呼び出し時のthread-defaultなcontextで動く
+            context
+                .with_thread_default(|| {
+                    let owner = own_name(player.clone());
+
+                    let timer = glib::timeout_source_new(
+                        POLL_INTERVAL,
+                        None,
+                        glib::PRIORITY_DEFAULT,
+                        move || {
+                            player.notify_changes();
+                            glib::Continue(true)
+                        },
+                    );
+                    timer.attach(Some(&context));
+
+                    main_loop_clone.run();
+                    timer.destroy();
+                    gio::bus_unown_name(owner);
+                })
+                .expect("MainContext is owned by another thread");
+        });
+
+        Ok(Self {
+            main_loop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Mpris {
+    fn drop(&mut self) {
+        self.main_loop.quit();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// セッションバスで名前を取り、オブジェクトを登録する
+fn own_name(player: Arc<Player>) -> gio::OwnerId {
+    gio::bus_own_name(
+        gio::BusType::Session,
+        BUS_NAME,
+        gio::BusNameOwnerFlags::NONE,
+        move |connection, _| {
+            let node = gio::DBusNodeInfo::for_xml(INTROSPECTION).unwrap();
+            for interface in [ROOT_INTERFACE, PLAYER_INTERFACE] {
+                let info = node.lookup_interface(interface).unwrap();
+                let call_player = player.clone();
+                let get_player = player.clone();
+                let set_player = player.clone();
+                let res = connection.register_object(
+                    OBJECT_PATH,
+                    &info,
+                    move |_, _, _, interface, method, params, invocation| match call_player
+                        .call(interface, method, &params)
+                    {
+                        Ok(()) => invocation.return_value(None),
+                        Err(err) => {
+                            invocation.return_dbus_error("org.freedesktop.DBus.Error.Failed", &err)
+                        }
+                    },
+                    move |_, _, _, interface, name| get_player.property(interface, name),
+                    move |_, _, _, interface, name, value| {
+                        set_player.set_property(interface, name, &value)
+                    },
+                );
+                if let Err(err) = res {
+                    log::error!("failed to register {interface}: {err}");
+                }
+            }
+            *player.connection.lock().unwrap() = Some(connection);
+        },
+        |_, name| log::info!("mpris: acquired {name}"),
+        |_, name| log::warn!("mpris: lost {name}, is another instance running?"),
+    )
+}
@@ -0,0 +1,371 @@
+//! 複数のカメラを1つのパイプラインでそれぞれ別のファイルに記録し、後から時刻を揃えられるようにする
+//!
+//! カメラごとにsource ! queue ! エンコーダ ! muxer ! filesinkのbinを作り、すべて同じパイプラインに入れる。
+//! パイプラインの中のエレメントは同じクロックと同じbase-timeを使うので、ライブソースが付けるタイムスタンプ
+//! (クロックの時刻 - base-time)はどのファイルでも同じ時刻に対して同じ値になる。
+//! v4l2srcはそれぞれクロックを提供できるので、どれが選ばれるかに任せずにシステムクロックを使わせ、
+//! start-timeをNONEにしてbase-timeを自分で決める(interモジュールのproxyと同じ方法)。
+//!
+//! base-timeを決めたときの壁時計の時刻を、各muxerにTagSetterでdatetimeのタグとして書く。
+//! commentにはマイクロ秒までの時刻とbase-timeを書くので、ファイルの位置tの壁時計の時刻は
+//! 開始時刻 + tで求まり、編集するときにファイル同士を揃えられる。
+//!
+//! 各カメラの健康状態はsourceのsrc padのプローブで見る。
+//! - --stallの秒数フレームが来なければ警告し、また来るようになったらログに出す
+//! - 一定間隔でカメラごとのフレームレートをログに出す
+//! - エラーを出したカメラはそのbinにEOSを送って閉じ、他のカメラの記録は続ける
+//!
+//! 出力先はstorageモジュールで選び、--dirにs3://bucket/prefixを渡すとカメラごとにS3へアップロードする。
+//! 'q'で全体にEOSを送り、各出力のfinishで確定させる(ローカルのファイルは.partから確認して名前を変える)。
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    container::{Container, MuxerOptions},
+    fallback,
+    keyboard::{Key, Keyboard},
+    storage::{self, StorageBackend},
+    video_encoder::VideoEncodeOpt,
+};
+
+/// カメラごとのフレームレートをログに出す間隔
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 記録するカメラ
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// v4l2srcのデバイス
+    Device(String),
+    /// ライブのvideotestsrc。値はpattern
+    Test(u32),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Device(device) => write!(f, "{device}"),
+            Source::Test(pattern) => write!(f, "videotestsrc pattern {pattern}"),
+        }
+    }
+}
+
+impl Source {
+    fn launch(&self) -> String {
+        match self {
+            Source::Device(device) => format!("v4l2src device=\"{device}\" ! videoconvert"),
+            Source::Test(pattern) => format!(
+                "videotestsrc is-live=true pattern={pattern} \
+                 ! video/x-raw,width=640,height=480,framerate=30/1 ! timeoverlay ! videoconvert"
+            ),
+        }
+    }
+}
+
+/// マルチカメラ記録の設定
+#[derive(Debug)]
+pub struct MulticamOptions {
+    pub sources: Vec<Source>,
+    /// 出力先のディレクトリかS3のプレフィックス
+    pub dir: String,
+    pub container: Container,
+    /// この間フレームが来なければ止まったとみなす
+    pub stall: Duration,
+}
+
+/// sourceのプローブが更新するカメラの状態
+#[derive(Debug, Default)]
+struct Health {
+    frames: u64,
+    last_frame: Option<Instant>,
+}
+
+/// 1台のカメラの記録
+struct Branch {
+    name: String,
+    source: Source,
+    bin: gst::Bin,
+    queue: gst::Element,
+    storage: Box<dyn StorageBackend>,
+    health: Arc<Mutex<Health>>,
+    /// 前回ログに出したときのフレーム数
+    reported_frames: u64,
+    stalled: bool,
+    failed: bool,
+}
+
+impl Branch {
+    fn new(
+        index: usize,
+        source: &Source,
+        mut storage: Box<dyn StorageBackend>,
+        container: Container,
+        video: &VideoEncodeOpt,
+    ) -> anyhow::Result<Self> {
+        let name = format!("cam{index}");
+        let bin = gst::Bin::new(Some(&name));
+        let src = fallback::parse_bin_from_description(&source.launch(), true)
+            .with_context(|| format!("failed to create {source}"))?;
+        let queue = gst::ElementFactory::make("queue", None)?;
+        let encoder = video.make_bin(video.codec(container.video_codecs())?, true, None)?;
+        let mux = container.make_muxer(
+            "mux",
+            MuxerOptions {
+                streamable: !storage.seekable(),
+                ..Default::default()
+            },
+        )?;
+        let sink = storage.make_sink(None)?;
+
+        bin.add_many(&[src.upcast_ref(), &queue, encoder.upcast_ref(), &mux, &sink])?;
+        gst::Element::link_many(&[src.upcast_ref(), &queue, encoder.upcast_ref(), &mux, &sink])?;
+
+        let health = Arc::new(Mutex::new(Health::default()));
+        let health_clone = health.clone();
+        src.static_pad("src").context("source src pad")?.add_probe(
+            gst::PadProbeType::BUFFER,
+            move |_, _| {
+                let mut health = health_clone.lock().unwrap();
+                health.frames += 1;
+                health.last_frame = Some(Instant::now());
+                gst::PadProbeReturn::Ok
+            },
+        );
+
+        Ok(Self {
+            name,
+            source: source.clone(),
+            bin,
+            queue,
+            storage,
+            health,
+            reported_frames: 0,
+            stalled: false,
+            failed: false,
+        })
+    }
+
+    /// 開始時刻とbase-timeをmuxerのタグに書く
+    fn tag_start(&self, start: &glib::DateTime, base_time: gst::ClockTime) -> anyhow::Result<()> {
+        let mux = self.bin.by_name("mux").context("mux")?;
+        let setter = match mux.dynamic_cast_ref::<gst::TagSetter>() {
+            Some(setter) => setter,
+            None => {
+                log::warn!(
+                    "{} can't write tags, the start time is not recorded",
+                    self.name
+                );
+                return Ok(());
+            }
+        };
+        let comment = format!(
+            "multicam start={} base-time={}",
+            iso8601(start)?,
+            base_time.nseconds()
+        );
+        setter.add::<gst::tags::DateTime>(
+            &gst::DateTime::from(start.clone()),
+            gst::TagMergeMode::Replace,
+        );
+        setter.add::<gst::tags::Title>(
+            &format!("{} {}", self.name, self.source).as_str(),
+            gst::TagMergeMode::Replace,
+        );
+        setter.add::<gst::tags::Comment>(&comment.as_str(), gst::TagMergeMode::Replace);
+        Ok(())
+    }
+
+    /// フレームが来ているか確かめ、止まったときと戻ったときにログに出す
+    fn check_stall(&mut self, stall: Duration) {
+        if self.failed {
+            return;
+        }
+        let last_frame = self.health.lock().unwrap().last_frame;
+        let stalled = match last_frame {
+            Some(last_frame) => last_frame.elapsed() > stall,
+            // 最初のフレームが来るまではカメラの準備中とみなす
+            None => false,
+        };
+        if stalled && !self.stalled {
+            log::warn!(
+                "{} ({}) has no frames for {stall:?}\r",
+                self.name,
+                self.source
+            );
+        } else if !stalled && self.stalled {
+            log::info!("{} ({}) recovered\r", self.name, self.source);
+        }
+        self.stalled = stalled;
+    }
+
+    /// 前回からのフレームレートをログに出す
+    fn report(&mut self, elapsed: Duration) {
+        let frames = self.health.lock().unwrap().frames;
+        let status = if self.failed {
+            "failed"
+        } else if self.stalled {
+            "stalled"
+        } else {
+            "ok"
+        };
+        log::info!(
+            "{}: {status}, {frames} frames, {:.1} fps\r",
+            self.name,
+            (frames - self.reported_frames) as f64 / elapsed.as_secs_f64()
+        );
+        self.reported_frames = frames;
+    }
+
+    /// エラーを出したカメラのbinにEOSを送ってファイルを閉じる
+    /// basesrcはエラーで止まるときに自分でもEOSを流すが、途中のエレメントのエラーでは流れないので送っておく
+    fn fail(&mut self) {
+        self.failed = true;
+        if let Some(pad) = self.queue.static_pad("sink") {
+            pad.send_event(gst::event::Eos::new());
+        }
+    }
+}
+
+/// 全カメラで同じクロックとbase-timeを使い、その時の壁時計の時刻を返す
+fn share_base_time(pipeline: &gst::Pipeline) -> anyhow::Result<(glib::DateTime, gst::ClockTime)> {
+    let clock = gst::SystemClock::obtain();
+    pipeline.use_clock(Some(&clock));
+    pipeline.set_start_time(gst::ClockTime::NONE);
+    let base_time = clock.time().context("clock time")?;
+    let start = glib::DateTime::now_utc()?;
+    pipeline.set_base_time(base_time);
+    Ok((start, base_time))
+}
+
+/// UTCの時刻をマイクロ秒までISO 8601で書く
+fn iso8601(time: &glib::DateTime) -> anyhow::Result<String> {
+    Ok(format!(
+        "{}.{:06}Z",
+        time.format("%Y-%m-%dT%H:%M:%S")?,
+        time.microsecond()
+    ))
+}
+
+/// エラーを出したエレメントを含む、まだ記録しているカメラ
+fn failed_branch(branches: &[Branch], src: &gst::Object) -> Option<usize> {
+    branches
+        .iter()
+        .position(|branch| !branch.failed && src.has_as_ancestor(&branch.bin))
+}
+
+/// カメラを記録し、'q'でEOSを送って閉じる
+fn record(
+    pipeline: &gst::Pipeline,
+    branches: &mut [Branch],
+    stall: Duration,
+) -> anyhow::Result<()> {
+    println!(
+        "\
+USAGE:
+ 'Q' to stop recording\r"
+    );
+
+    let keyboard = Keyboard::new()?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let mut reported_at = Instant::now();
+    loop {
+        if let Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) = keyboard.try_key() {
+            log::info!("stopping\r");
+            pipeline.send_event(gst::event::Eos::new());
+        }
+        for branch in branches.iter_mut() {
+            branch.check_stall(stall);
+        }
+        if reported_at.elapsed() >= STATUS_INTERVAL {
+            for branch in branches.iter_mut() {
+                branch.report(reported_at.elapsed());
+            }
+            reported_at = Instant::now();
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        use gst::MessageView;
+        match msg.view() {
+            // すべてのfilesinkがEOSを受け取るとパイプラインのEOSになる
+            MessageView::Eos(_) => return Ok(()),
+            MessageView::Error(err) => {
+                match err.src().and_then(|src| failed_branch(branches, &src)) {
+                    Some(index) => {
+                        let branch = &mut branches[index];
+                        log::error!(
+                            "{} ({}) failed: {} ({:?})\r",
+                            branch.name,
+                            branch.source,
+                            err.error(),
+                            err.debug()
+                        );
+                        branch.fail();
+                    }
+                    None => anyhow::bail!(
+                        "recording stopped by an error from {:?}: {}",
+                        err.src().map(|s| s.path_string()),
+                        err.error()
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 複数のカメラを同じクロックとbase-timeで別々のファイルに記録する
+pub fn run(options: &MulticamOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(!options.sources.is_empty(), "no sources to record");
+
+    let pipeline = gst::Pipeline::new(Some("multicam"));
+    let (start, base_time) = share_base_time(&pipeline)?;
+    let timestamp = start.to_local()?.format("%Y%m%d-%H%M%S")?;
+    let mut branches = Vec::new();
+    for (index, source) in options.sources.iter().enumerate() {
+        let storage = storage::open(&storage::join(
+            &options.dir,
+            &format!("{timestamp}-cam{index}.{}", options.container.extension()),
+        ))?;
+        let branch = Branch::new(index, source, storage, options.container, video)?;
+        branch.tag_start(&start, base_time)?;
+        pipeline.add(&branch.bin)?;
+        branches.push(branch);
+    }
+    log::info!(
+        "recording {} sources from {} (base-time {base_time})",
+        branches.len(),
+        iso8601(&start)?
+    );
+
+    let result = record(&pipeline, &mut branches, options.stall);
+    pipeline.set_state(gst::State::Null)?;
+    result?;
+
+    let mut failed = 0;
+    for branch in &mut branches {
+        if let Err(err) = branch.storage.finish() {
+            log::error!("{} ({}): {err:#}", branch.name, branch.source);
+            failed += 1;
+        }
+    }
+    anyhow::ensure!(
+        failed == 0,
+        "{failed} of {} recordings failed",
+        branches.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! パイプラインのイベントを短い音で知らせる
+//!
+//! 画面を見ていないときやヘッドレスで動かしているときに、バッファリングが終わった、
+//! エラーで止まった、最後まで再生した、といったことを音で分かるようにする。
+//! 音は本体とは別の小さなパイプライン(audiotestsrc ! autoaudiosink)で鳴らす。
+//! バスのハンドラを止めないように専用スレッドで順番に鳴らし、dropするときは鳴り終わるまで待つ。
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// 知らせるイベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// バッファリングが終わった
+    Buffered,
+    /// エラーで止まった
+    Error,
+    /// 最後まで再生した
+    Eos,
+}
+
+impl Event {
+    /// 鳴らす音の周波数(Hz)と長さ(ms)の並び
+    fn tones(self) -> &'static [(u32, u32)] {
+        match self {
+            Event::Buffered => &[(880, 100)],
+            Event::Error => &[(440, 200), (220, 400)],
+            Event::Eos => &[(660, 120), (880, 200)],
+        }
+    }
+}
+
+/// 10msごとのバッファにして長さをnum-buffersで指定する
+const RATE: u32 = 44100;
+const SAMPLES_PER_BUFFER: u32 = RATE / 100;
+
+/// 1つの音を鳴らし終わるまで待つ
+fn play_tone(freq: u32, duration_ms: u32) -> anyhow::Result<()> {
+    let pipeline = fallback::parse_launch(&format!(
+        "audiotestsrc wave=sine freq={freq} volume=0.3 samplesperbuffer={SAMPLES_PER_BUFFER} \
+         num-buffers={} ! audio/x-raw,rate={RATE} ! audioconvert ! autoaudiosink",
+        (duration_ms / 10).max(1)
+    ))?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let res = bus
+        .iter_timed(gst::ClockTime::from_mseconds(u64::from(duration_ms) + 2000))
+        .find_map(|msg| match msg.view() {
+            gst::MessageView::Eos(_) => Some(Ok(())),
+            gst::MessageView::Error(err) => Some(Err(anyhow::anyhow!("{}", err.error()))),
+            _ => None,
+        })
+        .unwrap_or(Ok(()));
+    pipeline.set_state(gst::State::Null)?;
+    res
+}
+
+/// 通知の音を鳴らすスレッド
+pub struct Notifier {
+    tx: Option<mpsc::Sender<Event>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel::<Event>();
+        let thread = thread::spawn(move || {
+            for event in rx {
+                for &(freq, duration_ms) in event.tones() {
+                    if let Err(err) = play_tone(freq, duration_ms) {
+                        log::warn!("failed to play the {event:?} notification: {err:#}");
+                        break;
+                    }
+                }
+            }
+        });
+        Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn notify(&self, event: Event) {
+        log::debug!("notify {event:?}");
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(event);
+        }
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Notifier {
+    /// 送信側を閉じると、スレッドは残りを鳴らし終えてから終わる
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// バッファリングの割合から、終わった瞬間を見つける
+#[derive(Debug, Default)]
+pub struct BufferingWatch {
+    buffering: bool,
+}
+
+impl BufferingWatch {
+    /// 100%未満から100%になったときにtrue
+    pub fn completed(&mut self, percent: i32) -> bool {
+        let was_buffering = self.buffering;
+        self.buffering = percent < 100;
+        was_buffering && !self.buffering
+    }
+}
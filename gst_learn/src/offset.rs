@@ -0,0 +1,91 @@
+//! pad.set_offset()でteeの片方のブランチだけrunning-timeをずらし、映像を音声より遅らせる例
+//!
+//! padのoffsetは、そのpadから出ていくバッファのrunning-timeにoffsetを足したように見せる。
+//! 実際にはバッファのPTSは書き換えず、下流に送るsegmentのbaseをずらしている。
+//! sinkやcompositorはrunning-timeで同期するので、offsetを付けたブランチのフレームはその分だけ遅れて使われる。
+//!
+//! 同じvideotestsrcをteeで分け、左はそのまま、右はoffsetを付けてcompositorで並べる。
+//! 音声は1秒ごとのtickで、左の映像とは揃い、右の映像はoffsetだけ遅れる。
+//! compositorのsink padでバッファのrunning-timeをときどき出して、2つのブランチの差を確かめる。
+//!
+//! 遅らせたブランチのフレームはcompositorが使うまで待たされるので、その間のフレームを溜めておけるように
+//! queueの上限をoffsetより長くしておく。溜められないとteeが詰まり、もう片方のブランチも止まる。
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// フレームレート。running-timeはこのフレーム数ごとに出す
+const FRAMERATE: u32 = 30;
+/// 1枚の映像の幅
+const WIDTH: u32 = 320;
+
+/// compositorのsink padに届いたバッファのrunning-timeをときどきログに出す
+fn log_running_time(pad: &gst::Pad, label: &'static str) {
+    let count = AtomicU32::new(0);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        if count.fetch_add(1, Ordering::Relaxed) % FRAMERATE != 0 {
+            return gst::PadProbeReturn::Ok;
+        }
+        let buffer = match &info.data {
+            Some(gst::PadProbeData::Buffer(buffer)) => buffer,
+            _ => return gst::PadProbeReturn::Ok,
+        };
+        // offsetは上流のpadからsegmentのbaseに足されて届く
+        let running_time = pad
+            .sticky_event::<gst::event::Segment>(0)
+            .and_then(|event| event.segment().downcast_ref::<gst::ClockTime>().cloned())
+            .zip(buffer.pts())
+            .and_then(|(segment, pts)| segment.to_running_time(pts));
+        log::info!(
+            "{label}: pts {} running-time {}",
+            buffer.pts().display(),
+            running_time.display()
+        );
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// 右のブランチをoffset_msだけ遅らせて、左と並べて再生する
+pub fn run(offset_ms: u64, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let offset = gst::ClockTime::from_mseconds(offset_ms);
+    let pipeline = fallback::parse_launch(&format!(
+        "compositor name=mix sink_1::xpos={WIDTH} ! videoconvert ! autovideosink \
+         videotestsrc is-live=true pattern=ball \
+         ! video/x-raw,width={WIDTH},height=240,framerate={FRAMERATE}/1 \
+         ! timeoverlay ! tee name=t \
+         t. ! queue ! mix.sink_0 \
+         t. ! queue name=delayed max-size-buffers=0 max-size-bytes=0 max-size-time={max_size_time} \
+         ! textoverlay text=\"+{offset_ms}ms\" valignment=bottom ! mix.sink_1 \
+         audiotestsrc name=ticks is-live=true wave=ticks ! autoaudiosink",
+        max_size_time = (offset + gst::ClockTime::SECOND).nseconds(),
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    let ticks = pipeline.by_name("ticks").context("audiotestsrc")?;
+    // 1秒ごとのtickにして、映像の秒の切り替わりと比べやすくする
+    if ticks.find_property("tick-interval").is_some() {
+        ticks.set_property("tick-interval", gst::ClockTime::SECOND.nseconds());
+    }
+
+    let delayed = pipeline.by_name("delayed").context("delayed queue")?;
+    let src_pad = delayed.static_pad("src").context("queue src pad")?;
+    src_pad.set_offset(offset.nseconds() as i64);
+    log::info!("delaying the right branch by {offset}");
+
+    let mix = pipeline.by_name("mix").context("compositor")?;
+    for (name, label) in [("sink_0", "original"), ("sink_1", "delayed")] {
+        let pad = mix.static_pad(name).context("compositor sink pad")?;
+        log_running_time(&pad, label);
+    }
+
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,136 @@
+//! PipeWireのノードを列挙し、pipewiresrc/pipewiresinkで直接入出力する
+//!
+//! PipeWireが動いている環境ではautoaudiosrcがPulseAudio互換層やALSAを選んでしまい、
+//! 思ったデバイスにならなかったり遅延が大きくなったりすることがある。
+//! pipewiredeviceproviderからノードを取得し、target-objectで明示的に接続先を指定すると確実になる。
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+const PROVIDER: &str = "pipewiredeviceprovider";
+
+/// PipeWireのノード
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub display_name: String,
+    pub class: String,
+    /// target-objectに指定する値。object.serialがなければnode.nameを使う
+    pub target: Option<String>,
+    pub device: gst::Device,
+}
+
+/// デバイスのプロパティから文字列か数値の値を取り出す
+fn property_string(props: &gst::StructureRef, name: &str) -> Option<String> {
+    let value = props.value(name).ok()?;
+    value
+        .get::<String>()
+        .ok()
+        .or_else(|| value.get::<u64>().ok().map(|v| v.to_string()))
+        .or_else(|| value.get::<u32>().ok().map(|v| v.to_string()))
+        .or_else(|| value.get::<i32>().ok().map(|v| v.to_string()))
+}
+
+/// pipewiredeviceproviderが見つけたノードを返す
+/// DeviceMonitorと違い、ほかのプロバイダ(pulse, alsa, v4l2)の重複したデバイスが混ざらない
+pub fn list_nodes() -> anyhow::Result<Vec<Node>> {
+    let factory = gst::DeviceProviderFactory::find(PROVIDER)
+        .with_context(|| format!("{PROVIDER} not found, is gst-plugin-pipewire installed?"))?;
+    let provider = factory
+        .get()
+        .with_context(|| format!("failed to create {PROVIDER}"))?;
+
+    let nodes = provider
+        .devices()
+        .map(|device| {
+            let target = device.properties().and_then(|props| {
+                property_string(&props, "object.serial")
+                    .or_else(|| property_string(&props, "node.name"))
+            });
+            Node {
+                display_name: device.display_name().to_string(),
+                class: device.device_class().to_string(),
+                target,
+                device,
+            }
+        })
+        .collect();
+    Ok(nodes)
+}
+
+/// pipewiresrc/pipewiresinkを作り、targetがあれば接続先に設定する
+/// 古いプラグインにはtarget-objectがなく、pathでノードIDを指定する
+pub fn make_element(factory: &str, target: Option<&str>) -> anyhow::Result<gst::Element> {
+    let element = gst::ElementFactory::make(factory, None)
+        .with_context(|| format!("failed to create {factory}"))?;
+    if let Some(target) = target {
+        if element.find_property("target-object").is_some() {
+            element.set_property("target-object", target);
+        } else {
+            element.set_property("path", target);
+        }
+    }
+    Ok(element)
+}
+
+fn print_nodes() -> anyhow::Result<()> {
+    for node in list_nodes()? {
+        println!(
+            "{:<16} {:<8} {}",
+            node.class,
+            node.target.as_deref().unwrap_or("-"),
+            node.display_name
+        );
+        if let Some(caps) = node.device.caps() {
+            log::debug!("  {caps}");
+        }
+    }
+    Ok(())
+}
+
+/// PipeWireの入出力の設定
+#[derive(Debug)]
+pub struct PipeWireOptions {
+    /// ノードを列挙して終了する
+    pub list: bool,
+    /// 入力ノード(object.serialかnode.name)。Noneならデフォルト
+    pub source: Option<String>,
+    /// 出力ノード。Noneならデフォルト
+    pub sink: Option<String>,
+    /// 音声の代わりに映像(カメラや画面)を受け取って表示する
+    pub video: bool,
+}
+
+/// 指定したノードから受け取り、音声ならPipeWireの出力ノードへ、映像なら画面へ流す
+pub fn run(options: &PipeWireOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    if options.list {
+        return print_nodes();
+    }
+
+    let pipeline = gst::Pipeline::new(Some("pipewire"));
+    let src = make_element("pipewiresrc", options.source.as_deref())?;
+    let elements = if options.video {
+        vec![
+            src,
+            gst::ElementFactory::make("videoconvert", None)?,
+            fallback::make("autovideosink", None)?,
+        ]
+    } else {
+        vec![
+            src,
+            gst::ElementFactory::make("audioconvert", None)?,
+            gst::ElementFactory::make("audioresample", None)?,
+            make_element("pipewiresink", options.sink.as_deref())?,
+        ]
+    };
+    let elements = elements.iter().collect::<Vec<_>>();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,181 @@
+//! エレメントごとの処理時間を計測する
+//!
+//! パイプラインのリンクの間にすべてidentityを挟み、handoffシグナルでバッファの通過時刻を記録する。
+//! あるエレメントの前後のidentityを同じPTSのバッファが通過した時刻の差が、そのエレメントの処理時間になる。
+//! pushは同じスレッドで下流へ順に呼ばれるので、queue以外ではこの差がそのエレメントに費やしたCPU時間に近い。
+//! queueでは滞留していた時間も含まれる。sinkの後ろにはidentityを挟めないので計測できない。
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use structopt::StructOpt;
+
+/// 対応が取れないまま残ったPTSをいつまでも持たないようにする上限
+const MAX_PENDING: usize = 256;
+
+/// 計測の設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Default, StructOpt)]
+pub struct ProfileOpt {
+    /// エレメントごとの処理時間を計測して終了時に表にする
+    #[structopt(long)]
+    pub profile: bool,
+}
+
+impl ProfileOpt {
+    /// 計測が有効ならパイプラインに計測用のidentityを挿入する
+    /// リンクを組み替えるのでNull状態のうちに呼ぶ
+    pub fn instrument(&self, pipeline: &gst::Pipeline) -> anyhow::Result<Option<Profiler>> {
+        if self.profile {
+            Ok(Some(Profiler::instrument(pipeline)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ElementStats {
+    buffers: u64,
+    bytes: u64,
+    busy: Duration,
+    /// エレメントに入ったバッファのPTSと時刻
+    pending: HashMap<Option<gst::ClockTime>, Instant>,
+}
+
+type Stats = Arc<Mutex<HashMap<String, ElementStats>>>;
+
+/// 計測結果を集める。dropすると表を出力する
+pub struct Profiler {
+    stats: Stats,
+    /// 表示順。パイプラインに追加された順にする
+    order: Vec<String>,
+    started: Instant,
+}
+
+impl Profiler {
+    pub fn instrument(pipeline: &gst::Pipeline) -> anyhow::Result<Self> {
+        let stats: Stats = Default::default();
+        let elements = pipeline
+            .iterate_elements()
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .context("pipeline changed while iterating")?;
+        // iterate_elementsは追加と逆順に返す
+        let order = elements
+            .iter()
+            .rev()
+            .map(|e| e.name().to_string())
+            .collect();
+
+        for element in &elements {
+            for src_pad in element.src_pads() {
+                let sink_pad = match src_pad.peer() {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                let downstream = match sink_pad.parent_element() {
+                    Some(downstream) => downstream,
+                    None => continue,
+                };
+                insert_probe(pipeline, element, &src_pad, &downstream, &sink_pad, &stats)?;
+            }
+        }
+
+        Ok(Self {
+            stats,
+            order,
+            started: Instant::now(),
+        })
+    }
+
+    fn print(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let stats = self.stats.lock().unwrap();
+        println!(
+            "{:<20} {:>8} {:>10} {:>10} {:>9} {:>8}",
+            "ELEMENT", "BUFFERS", "AVG(us)", "TOTAL(ms)", "BUF/s", "MB/s"
+        );
+        for name in &self.order {
+            let s = match stats.get(name) {
+                Some(s) if s.buffers > 0 => s,
+                _ => {
+                    println!("{name:<20} {:>8}", "-");
+                    continue;
+                }
+            };
+            println!(
+                "{:<20} {:>8} {:>10.1} {:>10.1} {:>9.1} {:>8.2}",
+                name,
+                s.buffers,
+                s.busy.as_secs_f64() * 1e6 / s.buffers as f64,
+                s.busy.as_secs_f64() * 1e3,
+                s.buffers as f64 / elapsed,
+                s.bytes as f64 / elapsed / 1e6,
+            );
+        }
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.print();
+    }
+}
+
+/// upstreamとdownstreamの間にidentityを挟み、通過時刻を記録する
+fn insert_probe(
+    pipeline: &gst::Pipeline,
+    upstream: &gst::Element,
+    src_pad: &gst::Pad,
+    downstream: &gst::Element,
+    sink_pad: &gst::Pad,
+    stats: &Stats,
+) -> anyhow::Result<()> {
+    let identity = gst::ElementFactory::make(
+        "identity",
+        Some(&format!("prof_{}_{}", upstream.name(), src_pad.name())),
+    )?;
+    identity.set_property("signal-handoffs", true);
+    identity.set_property("silent", true);
+
+    src_pad.unlink(sink_pad)?;
+    pipeline.add(&identity)?;
+    src_pad.link(&identity.static_pad("sink").context("identity sink")?)?;
+    identity
+        .static_pad("src")
+        .context("identity src")?
+        .link(sink_pad)?;
+
+    let upstream = upstream.name().to_string();
+    let downstream = downstream.name().to_string();
+    let stats = stats.clone();
+    identity.connect("handoff", false, move |args| {
+        let buffer = args[1].get::<gst::Buffer>().expect("handoff args[1]");
+        let now = Instant::now();
+        let pts = buffer.pts();
+        let mut stats = stats.lock().unwrap();
+
+        // 上流のエレメントから出てきた
+        let up = stats.entry(upstream.clone()).or_default();
+        up.buffers += 1;
+        up.bytes += buffer.size() as u64;
+        if let Some(entered) = up.pending.remove(&pts) {
+            up.busy += now - entered;
+        }
+
+        // 下流のエレメントに入る
+        let down = stats.entry(downstream.clone()).or_default();
+        if down.pending.len() >= MAX_PENDING {
+            down.pending.clear();
+        }
+        down.pending.insert(pts, now);
+        None
+    });
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! ライブ映像をエンコードしてファイルに記録する
+//!
+//! 記録中に'k'を押すとForceKeyUnitイベントを上流(エンコーダ)へ送り、次のフレームをキーフレームにさせる。
+//! エンコーダは要求に応えるとダウンストリームのForceKeyUnitイベントを下流へ流すので、
+//! エンコーダのsrc padを監視すると要求が処理されたことがわかる。
+//! セグメント分割記録では各セグメントの先頭がキーフレームでないと単独で再生できないため、この仕組みが必要になる。
+//!
+//! SegRecordはsplitmuxsinkで一定時間ごとにファイルを分割して記録する。
+//! --encrypt-keyを付けると閉じたセグメントをcryptモジュールで暗号化し、平文のファイルは消す。
+//!
+//! Recordの出力はパスかURIで、storageモジュールが出力先を選ぶ(s3://ならS3にアップロードする)。
+//! ローカルのファイルでは出力名に.partを付けた一時ファイルに書き、EOSで閉じてから読み直して確認できたら名前を変える。
+//! 途中で落ちたりエラーになったりしたときは.partのファイルが残るので、完成したファイルと取り違えない。
+//! MP4は閉じるまでmoovが書かれないが、matroskaは追記型なので--cluster-durationでclusterを短く区切ると
+//! 落ちても.partのファイルを最後のclusterの手前まで再生できる。
+//! --max-duration、--max-sizeを指定すると、記録した長さか書いたサイズが達したところでEOSを送って止める。
+//!
+//! 格納するコンテナは--containerで選び、muxerはcontainerモジュールで、エンコーダはvideo_encoderモジュールで決める。
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_video::{DownstreamForceKeyUnitEvent, UpstreamForceKeyUnitEvent};
+
+use crate::{
+    container::{self, Container, MuxerOptions},
+    crypt,
+    keyboard::{Key, Keyboard},
+    storage,
+    video_encoder::VideoEncodeOpt,
+};
+
+/// 自動で入るキーフレームの最大間隔(フレーム数)
+const KEY_INT_MAX: u32 = 300;
+
+/// パイプラインのsinkからエンコーダに向けてキーフレームを要求する
+/// パイプラインに送った上流イベントはsinkエレメントから上流へ伝わっていく
+pub fn request_key_unit(pipeline: &gst::Pipeline) -> bool {
+    let event = UpstreamForceKeyUnitEvent::builder()
+        .all_headers(true)
+        .build();
+    pipeline.send_event(event)
+}
+
+/// エンコーダのsrc padを監視して、キーフレームとダウンストリームのForceKeyUnitイベントをログに出す
+pub fn watch_keyframes(encoder: &gst::Element) -> anyhow::Result<()> {
+    let src_pad = encoder.static_pad("src").context("encoder src pad")?;
+    src_pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+        |_, info| {
+            match info.data {
+                Some(gst::PadProbeData::Buffer(ref buffer))
+                    if !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) =>
+                {
+                    log::info!("keyframe at {}\r", buffer.pts().display());
+                }
+                Some(gst::PadProbeData::Event(ref event)) => {
+                    if let Ok(fku) = DownstreamForceKeyUnitEvent::parse(event) {
+                        log::info!(
+                            "encoder forced key unit: running-time {} count {}\r",
+                            fku.running_time.display(),
+                            fku.count
+                        );
+                    }
+                }
+                _ => {}
+            }
+            gst::PadProbeReturn::Ok
+        },
+    );
+    Ok(())
+}
+
+/// ライブのvideotestsrcからコンテナに合うエンコーダ(とパーサー)までをパイプラインに追加する
+/// 返すのは最後のエレメントで、後ろにmuxerやsplitmuxsinkを繋いで使う
+fn add_live_encoder(
+    pipeline: &gst::Pipeline,
+    container: Container,
+    video: &VideoEncodeOpt,
+) -> anyhow::Result<gst::Element> {
+    let codec = video.codec(container.video_codecs())?;
+    let source = gst::ElementFactory::make("videotestsrc", Some("source"))?;
+    let overlay = gst::ElementFactory::make("timeoverlay", Some("overlay"))?;
+    let convert = gst::ElementFactory::make("videoconvert", Some("convert"))?;
+    // 自動で入るキーフレームの間隔を長くして、要求したキーフレームがわかりやすいようにする
+    let encoder = video.make(codec, Some("encoder"), true, Some(KEY_INT_MAX))?;
+
+    source.set_property("is-live", true);
+
+    pipeline.add_many(&[&source, &overlay, &convert, &encoder])?;
+    gst::Element::link_many(&[&source, &overlay, &convert, &encoder])?;
+
+    watch_keyframes(&encoder)?;
+
+    let parser = match codec.parser() {
+        Some(factory) => factory,
+        None => return Ok(encoder),
+    };
+    let parse = gst::ElementFactory::make(parser, Some("parse"))?;
+    pipeline.add(&parse)?;
+    encoder.link(&parse)?;
+
+    Ok(parse)
+}
+
+/// 記録を自動で止める条件
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AutoStop {
+    /// 記録した長さ
+    pub max_duration: Option<gst::ClockTime>,
+    /// sinkが書いたバイト数
+    pub max_size: Option<u64>,
+}
+
+impl AutoStop {
+    /// 条件に達したか
+    fn reached(&self, pipeline: &gst::Pipeline) -> bool {
+        let duration = self
+            .max_duration
+            .zip(pipeline.query_position::<gst::ClockTime>());
+        if let Some((max, position)) = duration.filter(|(max, position)| position >= max) {
+            log::info!("recorded {position}, reached --max-duration {max}\r");
+            return true;
+        }
+        // filesinkはBYTESの位置の問い合わせに書いたバイト数を返す
+        let size = self
+            .max_size
+            .zip(pipeline.query_position::<gst::format::Bytes>());
+        if let Some((max, written)) = size.filter(|(max, written)| written.0 >= *max) {
+            log::info!("wrote {} bytes, reached --max-size {max}\r", written.0);
+            return true;
+        }
+        false
+    }
+}
+
+/// キー操作を受け付けながらEOSかエラーまでバスを監視する
+/// 'k'でキーフレームを要求し、'q'かauto_stopの条件でEOSを送ってファイルを正しく閉じてから終了する
+/// エラーで止まったときはErrを返す
+pub fn run_until_eos(pipeline: &gst::Pipeline, auto_stop: AutoStop) -> anyhow::Result<()> {
+    println!(
+        "\
+USAGE:
+ 'K' to request a keyframe
+ 'Q' to stop recording\r"
+    );
+
+    let keyboard = Keyboard::new()?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let mut stopping = false;
+    let mut result = Ok(());
+    loop {
+        if !stopping && auto_stop.reached(pipeline) {
+            stopping = true;
+            pipeline.send_event(gst::event::Eos::new());
+        }
+        match keyboard.try_key() {
+            Some(Key::Char('k' | 'K')) => {
+                log::info!("requesting keyframe\r");
+                if !request_key_unit(pipeline) {
+                    log::warn!("force-key-unit event was not handled\r");
+                }
+            }
+            // muxerはEOSを受け取ってから最後のデータを書き出すので、直接Nullにせずに送る
+            Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) => {
+                log::info!("stopping\r");
+                stopping = true;
+                pipeline.send_event(gst::event::Eos::new());
+            }
+            _ => {}
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})\r",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!(
+                    "recording stopped by an error: {}",
+                    err.error()
+                ));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    result
+}
+
+/// 記録の設定
+#[derive(Debug)]
+pub struct RecordOptions {
+    /// パスかURI。storageモジュールで出力先を選ぶ
+    pub output: String,
+    pub container: Container,
+    pub muxer: MuxerOptions,
+    pub auto_stop: AutoStop,
+}
+
+/// ライブのvideotestsrcをエンコードして出力先に記録する
+/// EOSで閉じた後に出力先のfinishで確定させる。ローカルのファイルなら読み直して確認してから出力名に変える
+pub fn run(options: &RecordOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let mut storage = storage::open(&options.output)?;
+    let pipeline = gst::Pipeline::new(Some("record"));
+    let encoder = add_live_encoder(&pipeline, options.container, video)?;
+    let mux = options.container.make_muxer(
+        "mux",
+        MuxerOptions {
+            streamable: options.muxer.streamable || !storage.seekable(),
+            ..options.muxer
+        },
+    )?;
+    let sink = storage.make_sink(Some("sink"))?;
+
+    pipeline.add_many(&[&mux, &sink])?;
+    gst::Element::link_many(&[&encoder, &mux, &sink])?;
+
+    run_until_eos(&pipeline, options.auto_stop)
+        .with_context(|| format!("recording to {storage} stopped"))?;
+    storage.finish()
+}
+
+/// 分割記録の設定
+#[derive(Debug)]
+pub struct SegmentOptions {
+    /// 出力先ディレクトリ
+    pub dir: PathBuf,
+    /// 1ファイルあたりの長さ
+    pub duration: gst::ClockTime,
+    /// 残しておくファイル数。超えたら古いものから削除する
+    pub max_files: Option<usize>,
+    pub container: Container,
+    /// 閉じたセグメントを暗号化する鍵ファイル。なければ作る
+    pub encrypt_key: Option<PathBuf>,
+}
+
+/// 書き出したファイルを覚えておき、上限を超えたら古いものから削除する
+struct Retention {
+    files: VecDeque<PathBuf>,
+    max_files: Option<usize>,
+}
+
+impl Retention {
+    fn push(&mut self, path: PathBuf) {
+        self.files.push_back(path);
+        let max_files = match self.max_files {
+            Some(max_files) => max_files,
+            None => return,
+        };
+        // format-locationが呼ばれた時点で以前のファイルは閉じられているので削除してよい
+        while self.files.len() > max_files {
+            if let Some(old) = self.files.pop_front() {
+                match fs::remove_file(&old) {
+                    Ok(()) => log::info!("removed {}\r", old.display()),
+                    Err(err) => log::warn!("failed to remove {}: {err}\r", old.display()),
+                }
+            }
+        }
+    }
+}
+
+/// splitmuxsinkで一定時間ごとにファイルを切り替えながら記録する
+/// ファイル名は開始時刻から付け、max_filesを超えたら古いファイルを削除する
+pub fn run_segmented(options: SegmentOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    fs::create_dir_all(&options.dir)
+        .with_context(|| format!("failed to create {}", options.dir.display()))?;
+    let key = options
+        .encrypt_key
+        .as_deref()
+        .map(crypt::SegmentKey::load_or_create)
+        .transpose()?;
+
+    let pipeline = gst::Pipeline::new(Some("segrecord"));
+    let encoder = add_live_encoder(&pipeline, options.container, video)?;
+    let splitmux = gst::ElementFactory::make("splitmuxsink", Some("splitmux"))?;
+
+    // max-size-timeに達すると次のキーフレームでファイルを切り替える
+    // send-keyframe-requestsを有効にすると、切り替え時刻にsplitmuxsink自身がエンコーダに
+    // ForceKeyUnitを送るので、各ファイルの先頭がキーフレームになり長さも揃う
+    splitmux.set_property("max-size-time", options.duration.nseconds());
+    splitmux.set_property("send-keyframe-requests", true);
+    // セグメントは短いので、閉じる時にmoovを先頭へ移動しても負担は小さい
+    let muxer = options.container.make_muxer(
+        "mux",
+        MuxerOptions {
+            faststart: true,
+            ..Default::default()
+        },
+    )?;
+    splitmux.set_property("muxer", &muxer);
+
+    let retention = Arc::new(Mutex::new(Retention {
+        files: VecDeque::new(),
+        max_files: options.max_files,
+    }));
+    let retention_clone = retention.clone();
+    let dir = options.dir.clone();
+    let extension = options.container.extension();
+    let encrypt = key.is_some();
+    // 新しいファイルを開くたびに呼ばれ、返した文字列がファイル名になる
+    splitmux.connect("format-location", false, move |args| {
+        let fragment_id = args[1].get::<u32>().expect("format-location args[1]");
+        let timestamp = glib::DateTime::now_local()
+            .and_then(|now| now.format("%Y%m%d-%H%M%S"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let path = dir.join(format!("segment-{timestamp}-{fragment_id:05}.{extension}"));
+        log::info!("new segment {}\r", path.display());
+
+        // 暗号化するときは平文を消すので、残すのは.encのほう
+        let kept = if encrypt {
+            crypt::encrypted_path(&path)
+        } else {
+            path.clone()
+        };
+        retention_clone.lock().unwrap().push(kept);
+        Some(path.to_string_lossy().to_value())
+    });
+
+    pipeline.add(&splitmux)?;
+    encoder.link(&splitmux)?;
+    let bus = pipeline.bus().context("bus")?;
+
+    let encryptor = key.map(|key| {
+        let encryptor = crypt::Encryptor::start(key);
+        encryptor.watch(&bus);
+        encryptor
+    });
+    let result = run_until_eos(&pipeline, AutoStop::default());
+    // エラーで止まっても閉じたセグメントは平文のまま残さない
+    // 平文を消すので最後のセグメントは確認できない。DecryptPlayで再生して確かめる
+    if let Some(encryptor) = encryptor {
+        encryptor.finish(&bus);
+        return result;
+    }
+    result?;
+
+    // 最後のセグメントはEOSで閉じられるので、これが正しく書き終わっているか確認する
+    let last = retention.lock().unwrap().files.back().cloned();
+    if let Some(last) = last {
+        container::verify(&last)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! レジストリからエレメントファクトリを検索する
+//!
+//! B6ではファクトリを名前で引いてpad templateを表示したが、レジストリには読み込まれた全プラグインの
+//! ファクトリが登録されているので、klass(分類)やrank、受け付けるcapsで絞り込める。
+//! decodebinやautovideosinkも同じように、capsとrankから使うエレメントを選んでいる。
+//! CanLinkは2つのエレメントのpad templateのcapsを突き合わせ、リンクできない理由を示す。
+use std::str::FromStr;
+
+use anyhow::Context;
+use glib::translate::IntoGlib;
+use gst::prelude::*;
+
+/// rankの下限。名前か数値で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinRank(pub i32);
+
+impl FromStr for MinRank {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rank = match s {
+            "none" => gst::Rank::None,
+            "marginal" => gst::Rank::Marginal,
+            "secondary" => gst::Rank::Secondary,
+            "primary" => gst::Rank::Primary,
+            _ => {
+                return s
+                    .parse()
+                    .map(Self)
+                    .with_context(|| format!("invalid rank {s}"))
+            }
+        };
+        Ok(Self(rank.into_glib()))
+    }
+}
+
+/// 検索条件
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// "Encoder/Video"のように'/'で区切った分類。全部を含むものに絞る
+    pub klass: Option<String>,
+    pub min_rank: Option<MinRank>,
+    /// このcapsを受け取れるもの
+    pub sink_caps: Option<String>,
+    /// このcapsを出力できるもの
+    pub src_caps: Option<String>,
+}
+
+/// 表示する1行分
+#[derive(Debug)]
+pub struct FactoryEntry {
+    pub name: String,
+    pub rank: i32,
+    pub klass: String,
+    pub plugin: String,
+}
+
+/// klassの各要素がファクトリのklassに含まれるか
+/// "Encoder/Video"は"Codec/Encoder/Video"にも一致する
+fn match_klass(klass: &str, filter: &str) -> bool {
+    let parts = klass.split('/').collect::<Vec<_>>();
+    filter
+        .split('/')
+        .filter(|f| !f.is_empty())
+        .all(|f| parts.iter().any(|p| p.eq_ignore_ascii_case(f)))
+}
+
+fn parse_caps(caps: Option<&str>) -> anyhow::Result<Option<gst::Caps>> {
+    caps.map(|caps| gst::Caps::from_str(caps).with_context(|| format!("invalid caps {caps}")))
+        .transpose()
+}
+
+/// 条件に合うファクトリをrankの高い順に返す
+pub fn find_factories(options: &ListOptions) -> anyhow::Result<Vec<FactoryEntry>> {
+    let sink_caps = parse_caps(options.sink_caps.as_deref())?;
+    let src_caps = parse_caps(options.src_caps.as_deref())?;
+    let min_rank = options
+        .min_rank
+        .unwrap_or(MinRank(gst::Rank::None.into_glib()));
+
+    let mut entries =
+        gst::ElementFactory::factories_with_type(gst::ElementFactoryType::ANY, gst::Rank::None)
+            .filter(|factory| factory.rank().into_glib() >= min_rank.0)
+            .filter(|factory| {
+                options.klass.as_deref().is_none_or(|filter| {
+                    match_klass(factory.metadata("klass").unwrap_or_default(), filter)
+                })
+            })
+            .filter(|factory| {
+                sink_caps
+                    .as_ref()
+                    .is_none_or(|caps| factory.can_sink_any_caps(caps))
+            })
+            .filter(|factory| {
+                src_caps
+                    .as_ref()
+                    .is_none_or(|caps| factory.can_src_any_caps(caps))
+            })
+            .map(|factory| FactoryEntry {
+                name: factory.name().to_string(),
+                rank: factory.rank().into_glib(),
+                klass: factory.metadata("klass").unwrap_or_default().to_string(),
+                plugin: factory
+                    .plugin()
+                    .map(|p| p.plugin_name().to_string())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| b.rank.cmp(&a.rank).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// 検索して表にする
+pub fn run_list(options: &ListOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let entries = find_factories(options)?;
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    println!("{:<width$} {:>5} {:<16} KLASS", "NAME", "RANK", "PLUGIN");
+    for entry in &entries {
+        println!(
+            "{:<width$} {:>5} {:<16} {}",
+            entry.name, entry.rank, entry.plugin, entry.klass
+        );
+    }
+    log::info!("{} factories", entries.len());
+
+    Ok(())
+}
+
+/// 2つのcapsが交わらない理由を構造ごとに調べる
+/// メディアタイプが違うか、共通するフィールドのどれかで値が重ならないかのどちらか
+fn explain_mismatch(src: &gst::CapsRef, sink: &gst::CapsRef) -> Vec<String> {
+    let mut reasons = Vec::new();
+    for s in src.iter() {
+        for t in sink.iter() {
+            if s.name() != t.name() {
+                reasons.push(format!("media type {} vs {}", s.name(), t.name()));
+                continue;
+            }
+            for (field, value) in s.iter() {
+                let other = match t.value(field) {
+                    Ok(other) => other,
+                    // 片方にしかないフィールドは制約がないものとして扱われる
+                    Err(_) => continue,
+                };
+                if value.intersect(other).is_none() {
+                    reasons.push(format!(
+                        "{} field {field}: {} vs {}",
+                        s.name(),
+                        value.serialize().map(|v| v.to_string()).unwrap_or_default(),
+                        other.serialize().map(|v| v.to_string()).unwrap_or_default(),
+                    ));
+                }
+            }
+        }
+    }
+    reasons.sort();
+    reasons.dedup();
+    reasons
+}
+
+fn presence_note(presence: gst::PadPresence) -> &'static str {
+    match presence {
+        gst::PadPresence::Always => "always",
+        gst::PadPresence::Sometimes => "sometimes: linkable only after pad-added",
+        gst::PadPresence::Request => "request: created when linking",
+        _ => "unknown",
+    }
+}
+
+/// 2つのファクトリのpad templateを突き合わせて、リンクできるかとその理由を表示する
+pub fn run_can_link(src_factory: &str, sink_factory: &str) -> anyhow::Result<()> {
+    gst::init()?;
+
+    // ファクトリのstatic pad templateではなく、インスタンスのpad templateを使う
+    // エレメントによっては生成時に対応フォーマットを調べてcapsを決めるため
+    let src = gst::ElementFactory::make(src_factory, None)
+        .with_context(|| format!("no such element {src_factory}"))?;
+    let sink = gst::ElementFactory::make(sink_factory, None)
+        .with_context(|| format!("no such element {sink_factory}"))?;
+
+    let src_templates = src
+        .pad_template_list()
+        .into_iter()
+        .filter(|t| t.direction() == gst::PadDirection::Src)
+        .collect::<Vec<_>>();
+    let sink_templates = sink
+        .pad_template_list()
+        .into_iter()
+        .filter(|t| t.direction() == gst::PadDirection::Sink)
+        .collect::<Vec<_>>();
+    if src_templates.is_empty() {
+        println!("{src_factory} has no src pad templates, it is a sink");
+    }
+    if sink_templates.is_empty() {
+        println!("{sink_factory} has no sink pad templates, it is a source");
+    }
+
+    let mut compatible = false;
+    for s in &src_templates {
+        for t in &sink_templates {
+            println!(
+                "{src_factory}.{} ({}) -> {sink_factory}.{} ({})",
+                s.name_template().as_deref().unwrap_or("?"),
+                presence_note(s.presence()),
+                t.name_template().as_deref().unwrap_or("?"),
+                presence_note(t.presence())
+            );
+            let caps = s.caps().intersect(&t.caps());
+            if caps.is_empty() {
+                println!("  no common caps");
+                for reason in explain_mismatch(&s.caps(), &t.caps()) {
+                    println!("    {reason}");
+                }
+            } else {
+                compatible = true;
+                println!("  common caps: {caps}");
+            }
+        }
+    }
+
+    // 実際にリンクしてみる。sometimes padはまだ存在しないのでここでは失敗する
+    let pipeline = gst::Pipeline::new(None);
+    pipeline.add_many(&[&src, &sink])?;
+    match src.link(&sink) {
+        Ok(()) => println!("link: ok"),
+        Err(_) if compatible => {
+            println!("link: failed now, but templates are compatible (wait for a dynamic pad)")
+        }
+        Err(_) => println!("link: failed"),
+    }
+
+    Ok(())
+}
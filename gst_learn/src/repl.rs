@@ -0,0 +1,405 @@
+//! 対話的にパイプラインを組み立てて動かすREPL
+//!
+//! `add videotestsrc name=src`のようにコマンドを打つと、その場でエレメントの追加やリンク、
+//! プロパティの変更、状態遷移ができる。再生中にプロパティを変えたりリンクを組み替えたりして、
+//! エレメントがどう振る舞うかを確かめるのに使う。
+//! Tabでコマンド名、レジストリにあるエレメント名、パイプライン内のエレメント名、プロパティ名を補完する。
+use std::{
+    fs,
+    io::{self, Write},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::keyboard::{self, Key};
+
+const COMMANDS: &[&str] = &[
+    "add", "remove", "link", "unlink", "set", "get", "play", "pause", "ready", "stop", "list",
+    "dot", "help", "quit",
+];
+
+const HELP: &str = "\
+add <factory> [name=<name>] [<prop>=<value>...]  add an element
+remove <name>                                    remove an element
+link <src>[.<pad>] <sink>[.<pad>]                link two elements
+unlink <src> <sink>                              unlink two elements
+set <name> <prop>=<value>...                     set properties
+get <name> <prop>                                print a property
+play | pause | ready | stop                      change the pipeline state
+list                                             list elements in the pipeline
+dot [file]                                       write the pipeline graph (default pipeline.dot)
+help                                             show this help
+quit                                             exit";
+
+/// 入力された1行を解釈したもの
+#[derive(Debug)]
+enum Command {
+    Add {
+        factory: String,
+        props: Vec<(String, String)>,
+    },
+    Remove(String),
+    Link(String, String),
+    Unlink(String, String),
+    Set(String, Vec<(String, String)>),
+    Get(String, String),
+    State(gst::State),
+    List,
+    Dot(String),
+    Help,
+    Quit,
+}
+
+/// "key=value"の並びを読む
+fn parse_props<'a>(args: impl Iterator<Item = &'a str>) -> anyhow::Result<Vec<(String, String)>> {
+    args.map(|arg| {
+        arg.split_once('=')
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .with_context(|| format!("expected <prop>=<value>, got {arg}"))
+    })
+    .collect()
+}
+
+fn parse(line: &str) -> anyhow::Result<Option<Command>> {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    let mut arg = |what: &str| {
+        args.next()
+            .map(str::to_string)
+            .with_context(|| format!("{command}: missing {what}"))
+    };
+
+    let command = match command {
+        "add" => Command::Add {
+            factory: arg("factory")?,
+            props: parse_props(args)?,
+        },
+        "remove" => Command::Remove(arg("name")?),
+        "link" => Command::Link(arg("src")?, arg("sink")?),
+        "unlink" => Command::Unlink(arg("src")?, arg("sink")?),
+        "set" => {
+            let name = arg("name")?;
+            Command::Set(name, parse_props(args)?)
+        }
+        "get" => Command::Get(arg("name")?, arg("prop")?),
+        "play" => Command::State(gst::State::Playing),
+        "pause" => Command::State(gst::State::Paused),
+        "ready" => Command::State(gst::State::Ready),
+        "stop" => Command::State(gst::State::Null),
+        "list" => Command::List,
+        "dot" => Command::Dot(arg("file").unwrap_or_else(|_| "pipeline.dot".to_string())),
+        "help" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        _ => anyhow::bail!("unknown command {command}, type help"),
+    };
+    Ok(Some(command))
+}
+
+/// 値を文字列から設定する。存在しないプロパティを渡すとpanicするので先に確認する
+fn set_props(element: &gst::Element, props: &[(String, String)]) -> anyhow::Result<()> {
+    for (key, value) in props {
+        if element.find_property(key).is_none() {
+            anyhow::bail!("{} has no property {key}", element.name());
+        }
+        element.set_property_from_str(key, value);
+    }
+    Ok(())
+}
+
+/// "name"か"name.pad"を分けて、エレメントとpad名を返す
+fn element_pad(
+    pipeline: &gst::Pipeline,
+    spec: &str,
+) -> anyhow::Result<(gst::Element, Option<String>)> {
+    let (name, pad) = match spec.split_once('.') {
+        Some((name, pad)) => (name, Some(pad.to_string())),
+        None => (spec, None),
+    };
+    let element = pipeline
+        .by_name(name)
+        .with_context(|| format!("no element named {name}"))?;
+    Ok((element, pad))
+}
+
+struct Repl {
+    pipeline: gst::Pipeline,
+}
+
+impl Repl {
+    fn element(&self, name: &str) -> anyhow::Result<gst::Element> {
+        self.pipeline
+            .by_name(name)
+            .with_context(|| format!("no element named {name}"))
+    }
+
+    /// コマンドを実行する。終了するならfalseを返す
+    fn execute(&self, command: Command) -> anyhow::Result<bool> {
+        match command {
+            Command::Add { factory, props } => {
+                let name = props.iter().find(|(k, _)| k == "name").map(|(_, v)| v);
+                let element = gst::ElementFactory::make(&factory, name.map(String::as_str))
+                    .with_context(|| format!("no such element {factory}"))?;
+                let props = props
+                    .iter()
+                    .filter(|(k, _)| k != "name")
+                    .cloned()
+                    .collect::<Vec<_>>();
+                set_props(&element, &props)?;
+                self.pipeline.add(&element)?;
+                // 再生中に追加した場合もパイプラインと同じ状態にする
+                element.sync_state_with_parent()?;
+                println!("added {}", element.name());
+            }
+            Command::Remove(name) => {
+                let element = self.element(&name)?;
+                element.set_state(gst::State::Null)?;
+                self.pipeline.remove(&element)?;
+            }
+            Command::Link(src, sink) => {
+                let (src, src_pad) = element_pad(&self.pipeline, &src)?;
+                let (sink, sink_pad) = element_pad(&self.pipeline, &sink)?;
+                // decodebinなどのsometimes padはまだ存在しないことがあるので、その場合は失敗する
+                src.link_pads(src_pad.as_deref(), &sink, sink_pad.as_deref())
+                    .with_context(|| format!("failed to link {} to {}", src.name(), sink.name()))?;
+            }
+            Command::Unlink(src, sink) => {
+                let src = self.element(&src)?;
+                let sink = self.element(&sink)?;
+                src.unlink(&sink);
+            }
+            Command::Set(name, props) => set_props(&self.element(&name)?, &props)?,
+            Command::Get(name, prop) => {
+                let element = self.element(&name)?;
+                if element.find_property(&prop).is_none() {
+                    anyhow::bail!("{name} has no property {prop}");
+                }
+                let value = element.property_value(&prop);
+                match value.serialize() {
+                    Ok(s) => println!("{name}.{prop} = {s}"),
+                    Err(_) => println!("{name}.{prop} = {value:?}"),
+                }
+            }
+            Command::State(state) => {
+                let ret = self.pipeline.set_state(state)?;
+                println!("{state:?}: {ret:?}");
+            }
+            Command::List => {
+                for element in self.pipeline.iterate_elements().into_iter().flatten() {
+                    let factory = element
+                        .factory()
+                        .map(|f| f.name().to_string())
+                        .unwrap_or_default();
+                    let (_, current, _) = element.state(gst::ClockTime::ZERO);
+                    println!("{:<16} {:<20} {current:?}", element.name(), factory);
+                }
+            }
+            Command::Dot(file) => {
+                let dot = gst::debug_bin_to_dot_data(&self.pipeline, gst::DebugGraphDetails::all());
+                fs::write(&file, dot.as_str())
+                    .with_context(|| format!("failed to write {file}"))?;
+                println!("wrote {file}");
+            }
+            Command::Help => println!("{HELP}"),
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// 入力中の行の最後の単語に対する補完候補
+    fn complete(&self, line: &str) -> Vec<String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (done, word) = if line.ends_with(' ') || words.is_empty() {
+            (&words[..], "")
+        } else {
+            (&words[..words.len() - 1], words[words.len() - 1])
+        };
+
+        let candidates: Vec<String> = match done {
+            [] => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["add"] => gst::ElementFactory::factories_with_type(
+                gst::ElementFactoryType::ANY,
+                gst::Rank::None,
+            )
+            .map(|f| f.name().to_string())
+            .collect(),
+            ["set" | "get", name, ..] => match self.pipeline.by_name(name) {
+                Some(element) => element
+                    .list_properties()
+                    .iter()
+                    .map(|p| p.name().to_string())
+                    .collect(),
+                None => vec![],
+            },
+            ["remove" | "link" | "unlink" | "set" | "get", ..] => self
+                .pipeline
+                .iterate_elements()
+                .into_iter()
+                .flatten()
+                .map(|e| e.name().to_string())
+                .collect(),
+            _ => vec![],
+        };
+
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates
+    }
+
+    /// 溜まったバスのメッセージを表示する
+    fn drain_bus(&self) {
+        let bus = match self.pipeline.bus() {
+            Some(bus) => bus,
+            None => return,
+        };
+        while let Some(msg) = bus.pop() {
+            use gst::MessageView;
+
+            match msg.view() {
+                MessageView::Eos(_) => println!("EOS"),
+                MessageView::Error(err) => println!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ),
+                MessageView::Warning(warn) => println!(
+                    "Warning from {:?}: {}",
+                    warn.src().map(|s| s.path_string()),
+                    warn.error()
+                ),
+                MessageView::StateChanged(state)
+                    if state.src().as_ref() == Some(self.pipeline.upcast_ref()) =>
+                {
+                    println!("pipeline {:?} -> {:?}", state.old(), state.current());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// 候補に共通する先頭部分
+fn common_prefix(candidates: &[String]) -> String {
+    let first = match candidates.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut len = first.len();
+    for c in &candidates[1..] {
+        len = first
+            .chars()
+            .zip(c.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>()
+            .min(len);
+    }
+    first[..len].to_string()
+}
+
+/// rawモードで1行読む。Tabで補完し、上下キーで履歴をたどる
+/// Ctrl-DかCtrl-CでNoneを返す
+fn read_line(
+    prompt: &str,
+    history: &[String],
+    complete: impl Fn(&str) -> Vec<String>,
+) -> io::Result<Option<String>> {
+    let _raw = keyboard::raw_mode()?;
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    let mut index = history.len();
+
+    write!(stdout, "{prompt}")?;
+    stdout.flush()?;
+    for key in keyboard::keys() {
+        match key? {
+            Key::Char('\n') => {
+                write!(stdout, "\r\n")?;
+                return Ok(Some(line));
+            }
+            Key::Char('\t') => {
+                let candidates = complete(&line);
+                let start = if line.ends_with(' ') {
+                    line.len()
+                } else {
+                    line.rfind(' ').map_or(0, |i| i + 1)
+                };
+                match candidates.len() {
+                    0 => {}
+                    1 => {
+                        line.truncate(start);
+                        line.push_str(&candidates[0]);
+                        line.push(' ');
+                    }
+                    _ => {
+                        line.truncate(start);
+                        line.push_str(&common_prefix(&candidates));
+                        write!(stdout, "\r\n{}\r\n", candidates.join("  "))?;
+                    }
+                }
+            }
+            Key::Char(c) => line.push(c),
+            Key::Backspace => {
+                line.pop();
+            }
+            Key::Up if index > 0 => {
+                index -= 1;
+                line = history[index].clone();
+            }
+            Key::Down if index < history.len() => {
+                index += 1;
+                line = history.get(index).cloned().unwrap_or_default();
+            }
+            Key::Ctrl('c' | 'd') => {
+                write!(stdout, "\r\n")?;
+                return Ok(None);
+            }
+            _ => {}
+        }
+        write!(stdout, "\r{}{prompt}{line}", keyboard::CLEAR_LINE)?;
+        stdout.flush()?;
+    }
+    Ok(None)
+}
+
+/// 空のパイプラインを用意してコマンドを受け付ける
+pub fn run() -> anyhow::Result<()> {
+    gst::init()?;
+
+    let repl = Repl {
+        pipeline: gst::Pipeline::new(Some("repl")),
+    };
+    let mut history = Vec::new();
+    println!("type help for commands, Tab to complete");
+
+    while let Some(line) = read_line("gst> ", &history, |line| repl.complete(line))? {
+        repl.drain_bus();
+        let line = line.trim().to_string();
+        let command = match parse(&line) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("{err:#}");
+                continue;
+            }
+        };
+        history.push(line);
+        match repl.execute(command) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => println!("{err:#}"),
+        }
+        repl.drain_bus();
+    }
+
+    repl.pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! ReplayGainのタグに従って再生音量を揃える
+//!
+//! ReplayGainはトラック(またはアルバム)ごとに基準の音量との差(dB)とピークをタグに持っている。
+//! rgvolumeがあればplaybinのaudio-filterに入れて任せ、適用したゲインはresult-gainの通知で知る。
+//! rgvolumeがなければ、audio-tags-changedでタグを読み、playbinのvolumeに換算して設定する。
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::fallback;
+
+/// ReplayGainの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Default, StructOpt)]
+pub struct ReplayGainOpt {
+    /// ReplayGainのタグがあれば音量を自動で調整する
+    #[structopt(long)]
+    pub replaygain: bool,
+    /// トラックではなくアルバムのゲインを使う
+    #[structopt(long)]
+    pub replaygain_album: bool,
+    /// タグのゲインに足す量(dB)
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub replaygain_pre_amp: f64,
+}
+
+/// タグから読んだゲイン
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Gain {
+    db: f64,
+    peak: Option<f64>,
+}
+
+impl Gain {
+    /// アルバムのゲインがなければトラックのゲインを使う
+    fn from_tags(tags: &gst::TagListRef, album: bool) -> Option<Self> {
+        let track = tags.get::<gst::tags::TrackGain>().map(|v| Gain {
+            db: v.get(),
+            peak: tags.get::<gst::tags::TrackPeak>().map(|v| v.get()),
+        });
+        let album_gain = tags.get::<gst::tags::AlbumGain>().map(|v| Gain {
+            db: v.get(),
+            peak: tags.get::<gst::tags::AlbumPeak>().map(|v| v.get()),
+        });
+        if album {
+            album_gain.or(track)
+        } else {
+            track.or(album_gain)
+        }
+    }
+
+    /// playbinのvolumeに設定する倍率。ピークが1.0を超えてクリップしないように抑える
+    fn volume(&self, pre_amp: f64) -> f64 {
+        let volume = 10f64.powf((self.db + pre_amp) / 20.0);
+        match self.peak {
+            Some(peak) if peak > 0.0 => volume.min(1.0 / peak),
+            _ => volume,
+        }
+    }
+}
+
+impl ReplayGainOpt {
+    /// 有効ならplaybinにReplayGainの処理を組み込む。playbinがPlayingになる前に呼ぶ
+    pub fn apply(&self, playbin: &gst::Element) -> anyhow::Result<()> {
+        if !self.replaygain {
+            return Ok(());
+        }
+        if gst::ElementFactory::find("rgvolume").is_some() {
+            self.use_rgvolume(playbin)
+        } else {
+            log::info!("replaygain: rgvolume is not available, adjusting the playbin volume");
+            self.use_volume(playbin);
+            Ok(())
+        }
+    }
+
+    fn use_rgvolume(&self, playbin: &gst::Element) -> anyhow::Result<()> {
+        // rgvolumeはF32/F64しか受け付けないので前後で変換する
+        let filter = fallback::parse_bin_from_description(
+            &format!(
+                "audioconvert ! rgvolume name=rgvolume album-mode={} pre-amp={} ! audioconvert",
+                self.replaygain_album, self.replaygain_pre_amp
+            ),
+            true,
+        )?;
+        let rgvolume = filter.by_name("rgvolume").expect("rgvolume");
+        rgvolume.connect_notify(Some("result-gain"), |rgvolume, _| {
+            log::info!(
+                "replaygain: applied {:+.2} dB (target {:+.2} dB)",
+                rgvolume.property::<f64>("result-gain"),
+                rgvolume.property::<f64>("target-gain")
+            );
+        });
+
+        playbin.set_property("audio-filter", &filter);
+        log::info!(
+            "replaygain: using rgvolume ({} mode, pre-amp {:+.2} dB)",
+            if self.replaygain_album {
+                "album"
+            } else {
+                "track"
+            },
+            self.replaygain_pre_amp
+        );
+        Ok(())
+    }
+
+    fn use_volume(&self, playbin: &gst::Element) {
+        let album = self.replaygain_album;
+        let pre_amp = self.replaygain_pre_amp;
+        let last = std::sync::Mutex::new(None);
+        playbin.connect("audio-tags-changed", false, move |args| {
+            let playbin = args[0].get::<gst::Element>().expect("playbin");
+            let stream = args[1].get::<i32>().expect("stream index");
+            // 再生中のオーディオストリームのタグだけを見る
+            if stream != playbin.property::<i32>("current-audio") {
+                return None;
+            }
+            let tags = playbin.emit_by_name::<Option<gst::TagList>>("get-audio-tags", &[&stream]);
+            let gain = tags.and_then(|tags| Gain::from_tags(&tags, album));
+            let mut last = last.lock().unwrap();
+            if let Some(gain) = gain.filter(|gain| *last != Some(*gain)) {
+                let volume = gain.volume(pre_amp);
+                log::info!(
+                    "replaygain: {:+.2} dB (peak {:?}) -> volume {volume:.3}",
+                    gain.db + pre_amp,
+                    gain.peak
+                );
+                playbin.set_property("volume", volume);
+                *last = Some(gain);
+            }
+            None
+        });
+    }
+}
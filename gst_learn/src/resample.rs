@@ -0,0 +1,163 @@
+//! audioresampleの品質設定と、クロックのずれ(ドリフト)の吸収を確かめる例
+//!
+//! パイプラインのクロックにScaledClockを使い、実時間よりdrift-ppmだけ速く(遅く)進めると、
+//! is-liveなaudiotestsrcはそのクロックに合わせてサンプルを作るので、音声デバイスの消費とずれていく。
+//! audiobasesinkはslave-methodに従ってこのずれを吸収する。
+//! resampleなら再生速度を微調整し、skewならサンプルを捨てたり足したりし、noneなら何もしない。
+//! 毎秒、audioresampleに入ったサンプル数と出たサンプル数、クロックと実時間の差を出力する。
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    clocks::ScaledClock,
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 入力の音声のサンプルレート
+const INPUT_RATE: u32 = 48000;
+
+/// リサンプルの設定
+#[derive(Debug)]
+pub struct ResampleOptions {
+    /// audioresampleのquality(0-10)
+    pub quality: i32,
+    /// audioresampleのsinc-filter-mode(auto, interpolated, full)
+    pub sinc_filter_mode: String,
+    /// パイプラインのクロックを実時間からずらす量(ppm)
+    pub drift_ppm: f64,
+    /// 音声sinkのslave-method(resample, skew, none)
+    pub slave_method: String,
+    /// 出力のサンプルレート
+    pub rate: u32,
+}
+
+/// audioresampleの入出力を数える
+#[derive(Debug)]
+struct Stats {
+    samples_in: u64,
+    samples_out: u64,
+    started: Option<(Instant, gst::ClockTime)>,
+    reported: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            samples_in: 0,
+            samples_out: 0,
+            started: None,
+            reported: 0,
+        }
+    }
+
+    /// 1秒ごとにサンプル数の比と、クロックが実時間からどれだけずれたかを出す
+    fn report(&mut self, clock: &gst::Clock, rate: u32) {
+        let now = match clock.time() {
+            Some(now) => now,
+            None => return,
+        };
+        let (real_start, clock_start) = *self.started.get_or_insert((Instant::now(), now));
+        let real = real_start.elapsed();
+        if real.as_secs() <= self.reported {
+            return;
+        }
+        self.reported = real.as_secs();
+
+        let clock_elapsed = now.saturating_sub(clock_start).nseconds() as f64;
+        let real_elapsed = real.as_nanos() as f64;
+        let drift_ppm = (clock_elapsed / real_elapsed - 1.0) * 1e6;
+        let ratio = self.samples_out as f64 / self.samples_in.max(1) as f64;
+        log::info!(
+            "in={} out={} ratio={:.6} (expected {:.6}) clock drift={:+.1}ppm",
+            self.samples_in,
+            self.samples_out,
+            ratio,
+            rate as f64 / INPUT_RATE as f64,
+            drift_ppm,
+        );
+    }
+}
+
+/// padを通ったバッファのサンプル数を数えるプローブを付ける
+fn count_samples(pad: &gst::Pad, stats: &Arc<Mutex<Stats>>, count: fn(&mut Stats, u64)) {
+    let stats = stats.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let bpf = pad
+            .current_caps()
+            .and_then(|caps| gstreamer_audio::AudioInfo::from_caps(&caps).ok())
+            .map(|info| info.bpf() as usize);
+        if let (Some(gst::PadProbeData::Buffer(buffer)), Some(bpf)) = (&info.data, bpf) {
+            if let Some(samples) = buffer.size().checked_div(bpf) {
+                count(&mut stats.lock().unwrap(), samples as u64);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// 音声sinkの子エレメントが作られたらslave-methodを設定する
+/// autoaudiosinkは中のsinkを後から作るので、deep-element-addedで拾う
+fn set_slave_method(pipeline: &gst::Pipeline, method: String) {
+    pipeline.connect("deep-element-added", false, move |args| {
+        let element = args[2].get::<gst::Element>().unwrap();
+        if element.find_property("slave-method").is_some() {
+            log::info!("{}: slave-method={method}", element.name());
+            element.set_property_from_str("slave-method", &method);
+        }
+        None
+    });
+}
+
+/// ずらしたクロックで音声を再生し、audioresampleの入出力を報告する
+pub fn run(options: &ResampleOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "audiotestsrc is-live=true wave=sine freq=440 volume=0.3 \
+         ! audio/x-raw,rate={INPUT_RATE} ! audioconvert \
+         ! audioresample name=resample quality={} \
+         ! audio/x-raw,rate={} ! autoaudiosink",
+        options.quality, options.rate
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    let resample = pipeline.by_name("resample").context("resample")?;
+    resample.set_property_from_str("sinc-filter-mode", &options.sinc_filter_mode);
+    set_slave_method(&pipeline, options.slave_method.clone());
+
+    // パイプラインのクロックを固定すると、音声sinkが提供するクロックは選ばれなくなる
+    let clock = ScaledClock::new(1.0 + options.drift_ppm * 1e-6);
+    pipeline.use_clock(Some(&clock));
+    log::info!("clock rate={:.6}", clock.rate());
+
+    let stats = Arc::new(Mutex::new(Stats::new()));
+    count_samples(
+        &resample.static_pad("sink").context("sink pad")?,
+        &stats,
+        |stats, n| stats.samples_in += n,
+    );
+    let report = stats.clone();
+    let clock = clock.upcast::<gst::Clock>();
+    let rate = options.rate;
+    count_samples(
+        &resample.static_pad("src").context("src pad")?,
+        &stats,
+        |stats, n| stats.samples_out += n,
+    );
+    resample.static_pad("src").context("src pad")?.add_probe(
+        gst::PadProbeType::BUFFER,
+        move |_, _| {
+            report.lock().unwrap().report(&clock, rate);
+            gst::PadProbeReturn::Ok
+        },
+    );
+
+    runner::run(&pipeline, bus_loop)
+}
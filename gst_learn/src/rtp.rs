@@ -0,0 +1,472 @@
+//! rtpbinで送受信し、パケットロスを再送(RTX)とFEC(ULPFEC)で補う例
+//!
+//! 送り側と受け側は別のパイプラインで、localhostのUDPで繋ぐ。
+//! RTPはport、送り側のRTCPはport+1、受け側からのRTCP(NACKを含む)はport+5に流す。
+//! 送り側のudpsinkの前にidentityのdrop-probabilityを入れて、RTP(再送とFECも含む)を確率で捨てる。
+//! --impairを指定すると、その後ろにnetsimを入れて遅延やジッタ、順序の入れ替えも起こす。
+//!
+//! 再送はrtpbinのrequest-aux-sender/receiverでrtprtxsend/rtprtxreceiveを挟む。
+//! 受け側のjitterbufferは欠けたパケットに気付くとNACKを送り、送り側は別のpt(RTX_PT)で送り直す。
+//! FECはrequest-fec-encoder/decoderでrtpulpfecenc/rtpulpfecdecを挟む。
+//! 受け側は届いたパケットをrtpstorageに溜めておき、jitterbufferがロストを通知したらFECから復元する。
+//! 終了時にjitterbufferの統計、再送とFECの数を出す。
+//!
+//! --adaptiveを付けると、受け側のレシーバーレポート(RTCP RR)のロス率を見て、
+//! adaptiveモジュールの制御でvp8encのtarget-bitrateを動かしながら送る。
+//! 制御が追いつくように、受け側のRTCPの最小間隔をRTCP_INTERVALまで縮める。
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{adaptive::BitrateController, fallback, impair::Impairment};
+
+/// 映像のペイロードタイプ
+const VIDEO_PT: u32 = 96;
+/// 再送に使うペイロードタイプ
+const RTX_PT: u32 = 97;
+/// FECに使うペイロードタイプ
+const FEC_PT: u32 = 122;
+/// FECで復元するために受け側が溜めておく時間
+const STORAGE_TIME: gst::ClockTime = gst::ClockTime::from_mseconds(250);
+/// 受け側のjitterbufferのレイテンシ(ms)。再送が間に合うだけの長さにする
+const LATENCY_MS: u32 = 200;
+/// --adaptiveのときに受け側がレシーバーレポートを送る最小間隔
+const RTCP_INTERVAL: gst::ClockTime = gst::ClockTime::SECOND;
+
+/// RTPの送受信の設定
+#[derive(Debug)]
+pub struct RtpOptions {
+    pub port: u16,
+    /// 送り側で捨てるパケットの割合(0.0-1.0)
+    pub drop: f32,
+    /// 再送を使う
+    pub rtx: bool,
+    /// FECパケットの割合(%)。0ならFECを使わない
+    pub fec: u32,
+    /// udpsinkの前に入れるnetsimの設定
+    pub impair: Option<Impairment>,
+    /// 最初のビットレート(kbps)
+    pub bitrate: u32,
+    /// ロス率に合わせてビットレートを変える
+    pub adaptive: bool,
+    /// --adaptiveで動かすビットレートの範囲(kbps)
+    pub min_bitrate: u32,
+    pub max_bitrate: u32,
+    pub duration: Duration,
+}
+
+fn launch(description: &str) -> anyhow::Result<gst::Pipeline> {
+    Ok(fallback::parse_launch(description)?
+        .downcast::<gst::Pipeline>()
+        .unwrap())
+}
+
+/// rtpbinのaux sender/receiverとして使う、session番号のパッドを持つbin
+fn aux_bin(factory: &str, session: u32) -> anyhow::Result<gst::Element> {
+    let bin = gst::Bin::new(None);
+    let rtx = gst::ElementFactory::make(factory, None)
+        .with_context(|| format!("failed to create {factory}"))?;
+    // 元のptと再送のptの対応
+    rtx.set_property(
+        "payload-type-map",
+        gst::Structure::builder("application/x-rtp-pt-map")
+            .field(&VIDEO_PT.to_string(), RTX_PT)
+            .build(),
+    );
+    bin.add(&rtx)?;
+    for (direction, name) in [
+        ("sink", format!("sink_{session}")),
+        ("src", format!("src_{session}")),
+    ] {
+        let pad = rtx.static_pad(direction).context("rtx pad")?;
+        bin.add_pad(&gst::GhostPad::with_target(Some(&name), &pad)?)?;
+    }
+    Ok(bin.upcast())
+}
+
+/// 送り側。RTCPはport+5で受け取る
+fn sender(options: &RtpOptions) -> anyhow::Result<(gst::Pipeline, gst::Element)> {
+    let port = options.port;
+    let impair = match &options.impair {
+        Some(impairment) => format!("{} ! ", impairment.launch()?),
+        None => String::new(),
+    };
+    let pipeline = launch(&format!(
+        "rtpbin name=rtpbin \
+         videotestsrc is-live=true pattern=ball ! video/x-raw,width=640,height=360,framerate=30/1 \
+         ! timeoverlay ! vp8enc name=enc deadline=1 keyframe-max-dist=60 target-bitrate={} \
+         ! rtpvp8pay pt={VIDEO_PT} \
+         ! rtpbin.send_rtp_sink_0 \
+         rtpbin.send_rtp_src_0 ! identity name=drop drop-probability={} \
+         ! {impair}udpsink host=127.0.0.1 port={port} \
+         rtpbin.send_rtcp_src_0 ! udpsink host=127.0.0.1 port={} sync=false async=false \
+         udpsrc port={} ! rtpbin.recv_rtcp_sink_0",
+        options.bitrate * 1000,
+        options.drop,
+        port + 1,
+        port + 5
+    ))?;
+    let rtpbin = pipeline.by_name("rtpbin").context("rtpbin")?;
+
+    if options.rtx {
+        rtpbin.connect("request-aux-sender", false, |args| {
+            let session = args[1].get::<u32>().unwrap();
+            match aux_bin("rtprtxsend", session) {
+                Ok(bin) => Some(bin.to_value()),
+                Err(err) => {
+                    log::error!("{err:#}");
+                    None
+                }
+            }
+        });
+    }
+    if options.fec > 0 {
+        let percentage = options.fec;
+        rtpbin.connect("request-fec-encoder", false, move |_| {
+            let enc = gst::ElementFactory::make("rtpulpfecenc", None).ok()?;
+            enc.set_property("pt", FEC_PT);
+            enc.set_property("percentage", percentage);
+            Some(enc.to_value())
+        });
+    }
+
+    let identity = pipeline.by_name("drop").context("identity")?;
+    Ok((pipeline, identity))
+}
+
+/// 受け側から取り出す統計の元
+#[derive(Default)]
+struct Receiver {
+    jitterbuffers: Mutex<Vec<gst::Element>>,
+    rtx: Mutex<Option<gst::Element>>,
+    fec: Mutex<Option<gst::Element>>,
+}
+
+/// ptに対応するcaps
+fn pt_caps(pt: u32) -> Option<gst::Caps> {
+    let builder = gst::Caps::builder("application/x-rtp")
+        .field("media", "video")
+        .field("clock-rate", 90000i32)
+        .field("payload", pt as i32);
+    match pt {
+        VIDEO_PT => Some(builder.field("encoding-name", "VP8").build()),
+        RTX_PT => Some(
+            builder
+                .field("encoding-name", "RTX")
+                .field("apt", VIDEO_PT as i32)
+                .build(),
+        ),
+        FEC_PT => Some(builder.field("encoding-name", "ULPFEC").build()),
+        _ => None,
+    }
+}
+
+/// 受け側。RTCPはport+1で受け取り、port+5に返す
+fn receiver(options: &RtpOptions) -> anyhow::Result<(gst::Pipeline, Arc<Receiver>)> {
+    let port = options.port;
+    let pipeline = launch(&format!(
+        "rtpbin name=rtpbin latency={LATENCY_MS} \
+         udpsrc port={port} caps=\"{}\" ! rtpbin.recv_rtp_sink_0 \
+         udpsrc port={} ! rtpbin.recv_rtcp_sink_0 \
+         rtpbin.send_rtcp_src_0 ! udpsink host=127.0.0.1 port={} sync=false async=false",
+        pt_caps(VIDEO_PT).unwrap(),
+        port + 1,
+        port + 5
+    ))?;
+    let rtpbin = pipeline.by_name("rtpbin").context("rtpbin")?;
+    let stats = Arc::new(Receiver::default());
+
+    if options.adaptive {
+        let session = rtpbin.emit_by_name::<glib::Object>("get-session", &[&0u32]);
+        session.set_property("rtcp-min-interval", RTCP_INTERVAL.nseconds());
+    }
+
+    rtpbin.connect("request-pt-map", false, |args| {
+        let pt = args[2].get::<u32>().unwrap();
+        pt_caps(pt).map(|caps| caps.to_value())
+    });
+    let stats_clone = stats.clone();
+    rtpbin.connect("new-jitterbuffer", false, move |args| {
+        let jitterbuffer = args[1].get::<gst::Element>().unwrap();
+        stats_clone.jitterbuffers.lock().unwrap().push(jitterbuffer);
+        None
+    });
+
+    if options.rtx {
+        // 欠けたパケットをNACKで要求する
+        rtpbin.set_property("do-retransmission", true);
+        let stats_clone = stats.clone();
+        rtpbin.connect("request-aux-receiver", false, move |args| {
+            let session = args[1].get::<u32>().unwrap();
+            match aux_bin("rtprtxreceive", session) {
+                Ok(bin) => {
+                    let rtx = bin
+                        .downcast_ref::<gst::Bin>()
+                        .and_then(|bin| bin.children().into_iter().next());
+                    *stats_clone.rtx.lock().unwrap() = rtx;
+                    Some(bin.to_value())
+                }
+                Err(err) => {
+                    log::error!("{err:#}");
+                    None
+                }
+            }
+        });
+    }
+    if options.fec > 0 {
+        // FECの復元はjitterbufferがロストを通知してから行う
+        rtpbin.set_property("do-lost", true);
+        rtpbin.connect("new-storage", false, |args| {
+            let storage = args[1].get::<gst::Element>().unwrap();
+            storage.set_property("size-time", STORAGE_TIME.nseconds());
+            None
+        });
+        let stats_clone = stats.clone();
+        rtpbin.connect("request-fec-decoder", false, move |args| {
+            let rtpbin = args[0].get::<gst::Element>().unwrap();
+            let session = args[1].get::<u32>().unwrap();
+            let storage = rtpbin.emit_by_name::<glib::Object>("get-internal-storage", &[&session]);
+            let dec = gst::ElementFactory::make("rtpulpfecdec", None).ok()?;
+            dec.set_property("pt", FEC_PT);
+            dec.set_property("storage", &storage);
+            *stats_clone.fec.lock().unwrap() = Some(dec.clone());
+            Some(dec.to_value())
+        });
+    }
+
+    let depay = fallback::parse_bin_from_description(
+        "rtpvp8depay ! vp8dec ! videoconvert ! autovideosink",
+        true,
+    )?;
+    pipeline.add(&depay)?;
+    let depay_weak = depay.downgrade();
+    rtpbin.connect_pad_added(move |_, pad| {
+        let depay = match depay_weak.upgrade() {
+            Some(depay) => depay,
+            None => return,
+        };
+        if !pad.name().starts_with("recv_rtp_src_") {
+            return;
+        }
+        let sink = depay.static_pad("sink").unwrap();
+        if sink.is_linked() {
+            return;
+        }
+        if let Err(err) = pad.link(&sink) {
+            log::error!("failed to link {}: {err:?}", pad.name());
+        }
+        let _ = depay.sync_state_with_parent();
+    });
+
+    Ok((pipeline, stats))
+}
+
+/// 受け側から届いた最新のレシーバーレポート
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ReceiverReport {
+    /// 前のレポートからのロス率(0.0-1.0)
+    fraction_lost: f64,
+    /// 受け側が受け取った最大のシーケンス番号。新しいレポートかどうかの判断に使う
+    exthighestseq: u32,
+}
+
+/// 送り側のrtpbinのセッション統計から、受け側のソースが送ってきたレポートを読む
+fn receiver_report(rtpbin: &gst::Element) -> Option<ReceiverReport> {
+    let session = rtpbin.emit_by_name::<glib::Object>("get-session", &[&0u32]);
+    let stats = session.property::<gst::Structure>("stats");
+    let sources = stats.get::<glib::ValueArray>("source-stats").ok()?;
+    let report = sources
+        .iter()
+        .filter_map(|v| v.get::<gst::Structure>().ok())
+        .find(|s| {
+            !s.get::<bool>("internal").unwrap_or(true) && s.get::<bool>("have-rb").unwrap_or(false)
+        })?;
+    Some(ReceiverReport {
+        // 8bitの固定小数点で、256分のいくつ失ったか
+        fraction_lost: report.get::<u32>("rb-fractionlost").ok()? as f64 / 256.0,
+        exthighestseq: report.get::<u32>("rb-exthighestseq").ok()?,
+    })
+}
+
+/// 新しいレシーバーレポートが届いていたらビットレートを決め直す
+struct Adaptation {
+    controller: BitrateController,
+    rtpbin: gst::Element,
+    encoder: gst::Element,
+    last: Option<ReceiverReport>,
+}
+
+impl Adaptation {
+    fn new(sender: &gst::Pipeline, options: &RtpOptions) -> anyhow::Result<Self> {
+        Ok(Self {
+            controller: BitrateController::new(
+                options.bitrate,
+                options.min_bitrate,
+                options.max_bitrate,
+            )?,
+            rtpbin: sender.by_name("rtpbin").context("rtpbin")?,
+            encoder: sender.by_name("enc").context("vp8enc")?,
+            last: None,
+        })
+    }
+
+    fn poll(&mut self) {
+        let report = match receiver_report(&self.rtpbin) {
+            Some(report) if Some(report) != self.last => report,
+            _ => return,
+        };
+        self.last = Some(report);
+
+        let before = self.controller.bitrate();
+        let decision = self.controller.update(report.fraction_lost);
+        let bitrate = decision.bitrate();
+        if bitrate == before {
+            log::debug!(
+                "loss {:.1}%: keep {bitrate} kbps",
+                report.fraction_lost * 100.0
+            );
+            return;
+        }
+        log::info!(
+            "loss {:.1}%: {:?} {before} -> {bitrate} kbps",
+            report.fraction_lost * 100.0,
+            decision
+        );
+        self.encoder
+            .set_property("target-bitrate", (bitrate * 1000) as i32);
+    }
+}
+
+fn check_bus(name: &str, bus: &gst::Bus) -> bool {
+    while let Some(msg) = bus.pop() {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => return true,
+            MessageView::Error(err) => {
+                log::error!(
+                    "{name}: error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// identityの前後を通ったパケットを数えるプローブを付ける
+fn count_packets(pad: &gst::Pad) -> Arc<AtomicU64> {
+    let count = Arc::new(AtomicU64::new(0));
+    let count_clone = count.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+        count_clone.fetch_add(1, Ordering::Relaxed);
+        gst::PadProbeReturn::Ok
+    });
+    count
+}
+
+fn print_stats(sent: u64, passed: u64, receiver: &Receiver) {
+    println!(
+        "sent {sent} packets, dropped {}",
+        sent.saturating_sub(passed)
+    );
+    for jitterbuffer in receiver.jitterbuffers.lock().unwrap().iter() {
+        let stats = jitterbuffer.property::<gst::Structure>("stats");
+        let get = |field: &str| stats.get::<u64>(field).unwrap_or(0);
+        println!(
+            "{}: pushed={} lost={} late={} duplicates={} rtx-requests={} rtx-success={}",
+            jitterbuffer.name(),
+            get("num-pushed"),
+            get("num-lost"),
+            get("num-late"),
+            get("num-duplicates"),
+            get("rtx-count"),
+            get("rtx-success-count")
+        );
+    }
+    if let Some(rtx) = receiver.rtx.lock().unwrap().as_ref() {
+        println!(
+            "rtx: requests={} received={} associated={}",
+            rtx.property::<u32>("num-rtx-requests"),
+            rtx.property::<u32>("num-rtx-packets"),
+            rtx.property::<u32>("num-rtx-assoc-packets")
+        );
+    }
+    if let Some(fec) = receiver.fec.lock().unwrap().as_ref() {
+        println!(
+            "fec: recovered={} unrecovered={}",
+            fec.property::<u32>("recovered"),
+            fec.property::<u32>("unrecovered")
+        );
+    }
+}
+
+/// 送り側と受け側をdurationの間動かして、ロストと復元の数を出す
+pub fn run(options: &RtpOptions) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&options.drop),
+        "drop must be between 0 and 1"
+    );
+    gst::init()?;
+
+    let (sender, identity) = sender(options)?;
+    let (receiver, stats) = receiver(options)?;
+    let sent = count_packets(&identity.static_pad("sink").context("identity sink")?);
+    let passed = count_packets(&identity.static_pad("src").context("identity src")?);
+    let mut adaptation = if options.adaptive {
+        Some(Adaptation::new(&sender, options)?)
+    } else {
+        None
+    };
+
+    // 受け側を先に動かして、最初のパケットから受け取れるようにする
+    receiver
+        .set_state(gst::State::Playing)
+        .context("Unable to set the receiver to the `Playing` state")?;
+    sender
+        .set_state(gst::State::Playing)
+        .context("Unable to set the sender to the `Playing` state")?;
+    log::info!(
+        "drop={} rtx={} fec={}%",
+        options.drop,
+        options.rtx,
+        options.fec
+    );
+    if let Some(impairment) = &options.impair {
+        log::info!("impairment: {impairment}");
+    }
+
+    let sender_bus = sender.bus().context("sender bus")?;
+    let receiver_bus = receiver.bus().context("receiver bus")?;
+    let started = Instant::now();
+    while started.elapsed() < options.duration {
+        if check_bus("sender", &sender_bus) || check_bus("receiver", &receiver_bus) {
+            break;
+        }
+        if let Some(adaptation) = adaptation.as_mut() {
+            adaptation.poll();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Nullに戻すとjitterbufferが解放されるので、先に統計を読む
+    print_stats(
+        sent.load(Ordering::Relaxed),
+        passed.load(Ordering::Relaxed),
+        &stats,
+    );
+    sender.set_state(gst::State::Null)?;
+    receiver.set_state(gst::State::Null)?;
+
+    Ok(())
+}
@@ -0,0 +1,363 @@
+//! パイプラインを再生してEOSかエラーまでバスを監視する共通ループ
+//!
+//! --debug-on-errorを付けると、エラーが起きてもすぐに破棄せずPausedで止め、
+//! DOTファイルと直前のバスメッセージを出力してから、やり直すか終了するかを選べるようにする。
+//! エラーの直前に何が起きていたか(状態遷移、警告、capsの変化など)を追えるようにするため。
+//!
+//! 状態の変更は非同期(Async)に終わることがあり、sinkがプリロールできないとPausedへの変更はいつまでも終わらない。
+//! --state-timeoutの間に終わらなければ、まだ変更中のエレメントと失敗したエレメントを出してからNullに戻す。
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::{
+    diagnostics, launch,
+    notify::{BufferingWatch, Event, Notifier},
+};
+
+/// バスループの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct BusLoopOpt {
+    /// エラーで止まったらPausedにしてDOTと直前のメッセージを出し、再試行か終了かを尋ねる
+    #[structopt(long)]
+    pub debug_on_error: bool,
+    /// 覚えておくバスメッセージの数
+    #[structopt(long, default_value = "20")]
+    pub message_history: usize,
+    /// プリロールが終わったらパイプラインとエレメントごとのレイテンシを出す
+    #[structopt(long)]
+    pub print_latency: bool,
+    /// プリロールが終わったらリンクごとのメモリの種類とallocationクエリの結果を出し、ゼロコピーで流れているかを調べる
+    #[structopt(long)]
+    pub print_zero_copy: bool,
+    /// 再生が始まったらパイプラインをgst-launch-1.0の記述にして出す
+    #[structopt(long)]
+    pub print_launch: bool,
+    /// バッファリングの完了、エラー、EOSを短い音で知らせる
+    #[structopt(long)]
+    pub beep: bool,
+    /// 状態の変更を待つ秒数。0なら終わるまで待つ
+    #[structopt(long, default_value = "10")]
+    pub state_timeout: u64,
+}
+
+/// run(とrun_checked)が扱うフラグ
+/// 独自のループでパイプラインを動かすサブコマンドは、このうち扱えるものだけを使う
+pub const FLAGS: &[&str] = &[
+    "--debug-on-error",
+    "--print-latency",
+    "--print-zero-copy",
+    "--print-launch",
+    "--beep",
+];
+
+impl BusLoopOpt {
+    /// 指定されたフラグのうち、supportedに含まれないもの
+    pub fn unsupported(&self, supported: &[&str]) -> Vec<&'static str> {
+        [
+            (self.debug_on_error, "--debug-on-error"),
+            (self.print_latency, "--print-latency"),
+            (self.print_zero_copy, "--print-zero-copy"),
+            (self.print_launch, "--print-launch"),
+            (self.beep, "--beep"),
+        ]
+        .into_iter()
+        .filter(|(set, flag)| *set && !supported.contains(flag))
+        .map(|(_, flag)| flag)
+        .collect()
+    }
+
+    /// --beepが指定されていれば通知の音を鳴らすスレッドを開始する
+    pub fn notifier(&self) -> Option<Notifier> {
+        self.beep.then(Notifier::new)
+    }
+
+    /// 状態の変更を待つ時間。Noneなら終わるまで待つ
+    pub fn state_timeout(&self) -> Option<gst::ClockTime> {
+        (self.state_timeout > 0).then(|| gst::ClockTime::from_seconds(self.state_timeout))
+    }
+}
+
+/// 直近のバスメッセージを覚えておくリングバッファ
+struct MessageHistory {
+    messages: VecDeque<String>,
+    capacity: usize,
+}
+
+impl MessageHistory {
+    fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, msg: &gst::Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(describe(msg));
+    }
+
+    fn print(&self) {
+        println!("last {} bus messages:", self.messages.len());
+        for msg in &self.messages {
+            println!("  {msg}");
+        }
+    }
+}
+
+/// メッセージを1行にまとめる
+fn describe(msg: &gst::Message) -> String {
+    use gst::MessageView;
+
+    let src = msg
+        .src()
+        .map(|s| s.path_string().to_string())
+        .unwrap_or_default();
+    let detail = match msg.view() {
+        MessageView::Error(err) => format!("{} ({:?})", err.error(), err.debug()),
+        MessageView::Warning(warn) => format!("{} ({:?})", warn.error(), warn.debug()),
+        MessageView::StateChanged(state) => {
+            format!("{:?} -> {:?}", state.old(), state.current())
+        }
+        _ => msg.structure().map(|s| s.to_string()).unwrap_or_default(),
+    };
+    format!("{:?} {src}: {detail}", msg.type_())
+}
+
+/// パイプラインのグラフをDOTファイルに書き出す
+/// GST_DEBUG_DUMP_DOT_DIRが設定されていればそこに、なければカレントディレクトリに書く
+fn dump_dot(pipeline: &gst::Pipeline, name: &str) -> anyhow::Result<PathBuf> {
+    let dir = std::env::var_os("GST_DEBUG_DUMP_DOT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let path = dir.join(format!("{name}.dot"));
+    let dot = gst::debug_bin_to_dot_data(pipeline, gst::DebugGraphDetails::all());
+    fs::write(&path, dot.as_str())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// 再試行するならtrue
+fn ask_retry() -> anyhow::Result<bool> {
+    loop {
+        print!("'r' to retry, 'q' to quit: ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+        match line.trim() {
+            "r" | "R" => return Ok(true),
+            "q" | "Q" => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// 状態の変更が終わっていないか失敗したエレメントを出す
+/// binの状態は子の状態で決まるので、bin以外のエレメントだけを見る
+fn report_stuck(pipeline: &gst::Pipeline) {
+    for element in pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+    {
+        if element.is::<gst::Bin>() {
+            continue;
+        }
+        match element.state(gst::ClockTime::ZERO) {
+            (Err(_), current, pending) => log::error!(
+                "{} failed to change state ({current:?} -> {pending:?})",
+                element.path_string()
+            ),
+            (Ok(gst::StateChangeSuccess::Async), current, pending) => log::error!(
+                "{} is still changing state ({current:?} -> {pending:?})",
+                element.path_string()
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// バスに残っているエラーを1つにまとめる
+fn pending_errors(pipeline: &gst::Pipeline) -> Option<String> {
+    let bus = pipeline.bus()?;
+    let errors = std::iter::from_fn(|| bus.pop_filtered(&[gst::MessageType::Error]))
+        .map(|msg| describe(&msg))
+        .collect::<Vec<_>>();
+    (!errors.is_empty()).then(|| errors.join("; "))
+}
+
+/// パイプラインをNullに戻す。Nullへの変更は非同期にならないので待たない
+/// 戻せなくても後片付けは続けられるように、警告だけ出す
+pub fn rollback(pipeline: &gst::Pipeline) {
+    if pipeline.set_state(gst::State::Null).is_err() {
+        log::warn!("failed to set the pipeline to the `Null` state");
+    }
+}
+
+/// 状態を変え、非同期ならtimeoutまで終わるのを待つ
+/// 失敗するか時間内に終わらなければ、原因のエレメントを出してNullに戻す
+pub fn set_state(
+    pipeline: &gst::Pipeline,
+    state: gst::State,
+    timeout: Option<gst::ClockTime>,
+) -> anyhow::Result<gst::StateChangeSuccess> {
+    let error = match pipeline.set_state(state) {
+        Ok(gst::StateChangeSuccess::Async) => match pipeline.state(timeout) {
+            (Ok(gst::StateChangeSuccess::Async), current, pending) => anyhow::anyhow!(
+                "timed out changing the pipeline to the `{state:?}` state ({current:?} -> {pending:?})"
+            ),
+            (Ok(success), _, _) => return Ok(success),
+            (Err(_), _, _) => anyhow::anyhow!("unable to set the pipeline to the `{state:?}` state"),
+        },
+        Ok(success) => return Ok(success),
+        Err(_) => anyhow::anyhow!("unable to set the pipeline to the `{state:?}` state"),
+    };
+    report_stuck(pipeline);
+    let error = match pending_errors(pipeline) {
+        Some(errors) => anyhow::anyhow!("{error}: {errors}"),
+        None => error,
+    };
+    rollback(pipeline);
+    Err(error)
+}
+
+/// パイプラインを再生し、EOSかエラーまで待ってNullに戻す
+/// バスのエラーは表示するだけでOkを返す
+pub fn run(pipeline: &gst::Pipeline, options: &BusLoopOpt) -> anyhow::Result<()> {
+    run_bus(pipeline, options).map(|_| ())
+}
+
+/// runと同じだが、バスのエラーで終わったときはErrを返す
+/// 結果を次の処理に使うとき(2パスエンコードの1回目など)に使う
+pub fn run_checked(pipeline: &gst::Pipeline, options: &BusLoopOpt) -> anyhow::Result<()> {
+    match run_bus(pipeline, options)? {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// パイプラインを再生してバスを見る。エラーで終わったときはそのエラーを返す
+fn run_bus(
+    pipeline: &gst::Pipeline,
+    options: &BusLoopOpt,
+) -> anyhow::Result<Option<anyhow::Error>> {
+    let timeout = options.state_timeout();
+    set_state(pipeline, gst::State::Playing, timeout)?;
+
+    let mut history = MessageHistory::new(options.message_history);
+    let mut print_latency = options.print_latency;
+    let mut print_zero_copy = options.print_zero_copy;
+    let mut print_launch = options.print_launch;
+    let notifier = options.notifier();
+    let mut buffering = BufferingWatch::default();
+    let bus = pipeline.bus().context("bus")?;
+    let mut failed = None;
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        history.push(&msg);
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => {
+                if let Some(notifier) = &notifier {
+                    notifier.notify(Event::Eos);
+                }
+                break;
+            }
+            MessageView::Buffering(msg) => {
+                let completed = buffering.completed(msg.percent());
+                if let Some(notifier) = notifier.as_ref().filter(|_| completed) {
+                    notifier.notify(Event::Buffered);
+                }
+            }
+            // パイプライン全体のAsyncDoneは全てのsinkのプリロールが終わったことを表す
+            MessageView::AsyncDone(_)
+                if (print_latency || print_zero_copy)
+                    && msg.src().as_ref() == Some(pipeline.upcast_ref()) =>
+            {
+                if print_latency {
+                    print_latency = false;
+                    diagnostics::print_latency(pipeline);
+                }
+                if print_zero_copy {
+                    print_zero_copy = false;
+                    diagnostics::print_zero_copy(pipeline);
+                }
+            }
+            // Playingになった時点ではsometimes padのリンクも済んでいる
+            MessageView::StateChanged(state)
+                if print_launch
+                    && state.current() == gst::State::Playing
+                    && msg.src().as_ref() == Some(pipeline.upcast_ref()) =>
+            {
+                print_launch = false;
+                println!("gst-launch-1.0 {}", launch::describe(pipeline.upcast_ref()));
+            }
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                if let Some(notifier) = &notifier {
+                    notifier.notify(Event::Error);
+                }
+                failed = Some(anyhow::anyhow!(
+                    "error from {}: {}",
+                    err.src()
+                        .map(|s| s.path_string().to_string())
+                        .unwrap_or_default(),
+                    err.error()
+                ));
+                if !options.debug_on_error {
+                    break;
+                }
+
+                // エラーを出したエレメントはもうデータを流さないが、Pausedなら状態やcapsはそのまま残る
+                // Pausedにできなくても、DOTファイルの書き出しに失敗しても、調べられるものは出す
+                if pipeline.set_state(gst::State::Paused).is_err() {
+                    log::warn!("failed to set the pipeline to the `Paused` state");
+                }
+                match dump_dot(pipeline, "error") {
+                    Ok(path) => println!("pipeline graph written to {}", path.display()),
+                    Err(err) => log::warn!("{err:#}"),
+                }
+                history.print();
+
+                match ask_retry() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => {
+                        log::warn!("failed to read the answer: {err}");
+                        break;
+                    }
+                }
+                // Nullに戻してから最初からやり直す
+                // set_stateは失敗したらNullに戻してから返る
+                rollback(pipeline);
+                set_state(pipeline, gst::State::Playing, timeout)?;
+                failed = None;
+            }
+            _ => {}
+        }
+    }
+
+    rollback(pipeline);
+
+    Ok(failed)
+}
@@ -0,0 +1,273 @@
+//! gst-validateのシナリオを真似て、決まった時刻に操作をしながらパイプラインを動かす
+//!
+//! シナリオはTOMLで書く。atは再生開始からの経過秒数で、その時刻にglibのタイマーから操作を実行する。
+//! pipelineはgst-launchの書式で、コマンドラインで指定すればそちらが優先される。
+//!
+//! ```toml
+//! pipeline = "videotestsrc ! autovideosink"
+//!
+//! [[action]]
+//! at = 2.0
+//! type = "seek"
+//! position = 10.0
+//!
+//! [[action]]
+//! at = 5.0
+//! type = "pause"
+//!
+//! [[action]]
+//! at = 7.0
+//! type = "play"
+//!
+//! [[action]]
+//! at = 10.0
+//! type = "eos"
+//! ```
+//!
+//! 操作はseek(positionへのフラッシュシーク)、pause、play、eos(EOSイベントを送る)、stop(その場で終了する)。
+//! 経過時間は壁時計で数えるので、Pausedの間も進む。
+//! EOSかstopまでに全ての操作が実行されなかったり、エラーが起きたりしたらシナリオは失敗する。
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::fallback;
+
+/// シナリオの1つの操作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Seek(gst::ClockTime),
+    Pause,
+    Play,
+    Eos,
+    Stop,
+}
+
+impl Action {
+    fn from_toml(table: &toml::value::Table) -> anyhow::Result<Self> {
+        let kind = table
+            .get("type")
+            .and_then(|v| v.as_str())
+            .context("action needs a type")?;
+        Ok(match kind {
+            "seek" => Self::Seek(gst::ClockTime::from_nseconds(
+                (seconds(table, "position")? * 1e9) as u64,
+            )),
+            "pause" => Self::Pause,
+            "play" => Self::Play,
+            "eos" => Self::Eos,
+            "stop" => Self::Stop,
+            _ => anyhow::bail!("unknown action {kind:?}, expected seek|pause|play|eos|stop"),
+        })
+    }
+}
+
+/// 時刻付きの操作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedAction {
+    pub at: Duration,
+    pub action: Action,
+}
+
+impl TimedAction {
+    fn from_toml(value: &toml::Value) -> anyhow::Result<Self> {
+        let table = value.as_table().context("action must be a table")?;
+        Ok(Self {
+            at: Duration::from_secs_f64(seconds(table, "at")?),
+            action: Action::from_toml(table)?,
+        })
+    }
+}
+
+/// 整数でも小数でも書ける0以上の秒数を読む
+fn seconds(table: &toml::value::Table, key: &str) -> anyhow::Result<f64> {
+    let value = match table.get(key) {
+        Some(toml::Value::Float(v)) => *v,
+        Some(toml::Value::Integer(v)) => *v as f64,
+        Some(_) => anyhow::bail!("{key} must be a number of seconds"),
+        None => anyhow::bail!("missing {key}"),
+    };
+    anyhow::ensure!(
+        value.is_finite() && value >= 0.0,
+        "{key} must not be negative"
+    );
+    Ok(value)
+}
+
+/// TOMLから読んだシナリオ
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    pub pipeline: Option<String>,
+    /// 時刻順に並べた操作
+    pub actions: Vec<TimedAction>,
+}
+
+impl Scenario {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        let value = s.parse::<toml::Value>()?;
+        let table = value.as_table().context("scenario must be a table")?;
+
+        let pipeline = match table.get("pipeline") {
+            Some(v) => Some(v.as_str().context("pipeline must be a string")?.to_string()),
+            None => None,
+        };
+        let mut actions = match table.get("action") {
+            Some(toml::Value::Array(actions)) => actions
+                .iter()
+                .enumerate()
+                .map(|(i, action)| {
+                    TimedAction::from_toml(action).with_context(|| format!("action {i}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            Some(_) => anyhow::bail!("action must be an array of tables ([[action]])"),
+            None => Vec::new(),
+        };
+        // 同じ時刻の操作は書いた順に実行する
+        actions.sort_by_key(|a| a.at);
+        Ok(Self { pipeline, actions })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("parse {}", path.display()))
+    }
+}
+
+/// シナリオ実行の設定
+#[derive(Debug)]
+pub struct ScenarioOptions {
+    pub scenario: PathBuf,
+    /// シナリオのpipelineの代わりに使うgst-launchの書式のパイプライン
+    pub pipeline: Option<String>,
+}
+
+/// 実行中の状態。タイマーとバスウォッチから共有する
+#[derive(Debug, Default)]
+struct Progress {
+    /// 実行済みの操作の数
+    executed: usize,
+    error: Option<String>,
+}
+
+fn execute(pipeline: &gst::Pipeline, action: Action) -> anyhow::Result<()> {
+    match action {
+        Action::Seek(position) => {
+            pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, position)?
+        }
+        Action::Pause => {
+            pipeline.set_state(gst::State::Paused)?;
+        }
+        Action::Play => {
+            pipeline.set_state(gst::State::Playing)?;
+        }
+        Action::Eos => {
+            anyhow::ensure!(
+                pipeline.send_event(gst::event::Eos::new()),
+                "EOS event was not handled"
+            );
+        }
+        Action::Stop => {}
+    }
+    Ok(())
+}
+
+/// シナリオに沿ってパイプラインを動かし、EOSかstopで終える
+pub fn run(options: &ScenarioOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let scenario = Scenario::load(&options.scenario)?;
+    let description = options
+        .pipeline
+        .as_deref()
+        .or(scenario.pipeline.as_deref())
+        .context("no pipeline in the scenario or on the command line")?;
+    let pipeline = fallback::parse_launch(description)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow::anyhow!("{description} is not a pipeline"))?;
+
+    let main_loop = glib::MainLoop::new(None, false);
+    let progress = Rc::new(RefCell::new(Progress::default()));
+
+    let bus = pipeline.bus().context("bus")?;
+    let main_loop_clone = main_loop.clone();
+    let progress_clone = progress.clone();
+    let _watch = bus.add_watch_local(move |_, msg| {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => {
+                log::info!("EOS");
+                main_loop_clone.quit();
+            }
+            MessageView::Error(err) => {
+                let error = format!(
+                    "error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                log::error!("{error}");
+                progress_clone.borrow_mut().error = Some(error);
+                main_loop_clone.quit();
+            }
+            _ => {}
+        }
+        glib::Continue(true)
+    })?;
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+    let started = Instant::now();
+
+    // 操作ごとにタイマーを仕掛ける。同じ時刻のものはglibが登録順に呼ぶ
+    for (i, timed) in scenario.actions.iter().copied().enumerate() {
+        let pipeline = pipeline.clone();
+        let main_loop = main_loop.clone();
+        let progress = progress.clone();
+        glib::timeout_add_local_once(timed.at, move || {
+            let position = pipeline.query_position::<gst::ClockTime>();
+            log::info!(
+                "action {i} at {:.3}s (position {}): {:?}",
+                started.elapsed().as_secs_f64(),
+                position.display(),
+                timed.action
+            );
+            let mut progress = progress.borrow_mut();
+            if let Err(err) = execute(&pipeline, timed.action) {
+                progress.error = Some(format!("action {i} {:?} failed: {err}", timed.action));
+                main_loop.quit();
+                return;
+            }
+            progress.executed += 1;
+            if timed.action == Action::Stop {
+                main_loop.quit();
+            }
+        });
+    }
+
+    main_loop.run();
+    pipeline.set_state(gst::State::Null)?;
+
+    let progress = progress.borrow();
+    println!(
+        "{}/{} actions executed in {:.3}s",
+        progress.executed,
+        scenario.actions.len(),
+        started.elapsed().as_secs_f64()
+    );
+    if let Some(error) = &progress.error {
+        anyhow::bail!("scenario failed: {error}");
+    }
+    anyhow::ensure!(
+        progress.executed == scenario.actions.len(),
+        "scenario ended before all actions were executed"
+    );
+    Ok(())
+}
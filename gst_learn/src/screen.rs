@@ -0,0 +1,196 @@
+//! デスクトップをキャプチャしてプレビュー・記録する
+//!
+//! ximagesrcはX11の画面を、pipewiresrcはWaylandなどでxdg-desktop-portal経由の画面を取り込む。
+//! どちらもライブソースで、画面に変化がない間はフレームを出さなかったり、
+//! framerateが0/1や範囲のままのcapsを出したりするので、videorateで一定のフレームレートに揃えてから使う。
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    container::{Container, MuxerOptions},
+    fallback, record,
+    storage::{self, StorageBackend},
+    video_encoder::VideoEncodeOpt,
+};
+
+/// キャプチャに使うソース
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenSource {
+    Ximage,
+    Pipewire,
+}
+
+impl FromStr for ScreenSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ximage" | "x11" => Ok(Self::Ximage),
+            "pipewire" => Ok(Self::Pipewire),
+            _ => anyhow::bail!("unknown source {s}, expected ximage|pipewire"),
+        }
+    }
+}
+
+impl fmt::Display for ScreenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ximage => "ximage",
+            Self::Pipewire => "pipewire",
+        })
+    }
+}
+
+/// キャプチャする範囲。"x,y,width,height"で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("invalid region {s}"))?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Self {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => anyhow::bail!("invalid region {s}, expected x,y,width,height"),
+        }
+    }
+}
+
+/// 画面キャプチャの設定
+#[derive(Debug)]
+pub struct ScreenOptions {
+    pub source: ScreenSource,
+    pub region: Option<Region>,
+    pub fps: i32,
+    /// 指定するとプレビューしながら記録する。パスかURIで、storageモジュールで出力先を選ぶ
+    pub output: Option<String>,
+    pub container: Container,
+}
+
+/// ソースから指定範囲を切り出すまでのパイプライン記述
+fn source_launch(source: ScreenSource, region: Option<Region>) -> String {
+    match (source, region) {
+        // ximagesrcは自前で範囲を切り出せる。endx/endyは含む座標なので1引く
+        // use-damageを無効にしないと変化のあった部分だけを送ろうとしてタイムスタンプが不揃いになる
+        (ScreenSource::Ximage, Some(r)) => format!(
+            "ximagesrc use-damage=false startx={} starty={} endx={} endy={}",
+            r.x,
+            r.y,
+            r.x + r.width - 1,
+            r.y + r.height - 1
+        ),
+        (ScreenSource::Ximage, None) => "ximagesrc use-damage=false".to_string(),
+        // pipewiresrcには範囲指定がないので、受け取ってからvideocropで切り出す
+        // 画面が変化しない間はバッファが来ないため、do-timestampでパイプラインの時刻を付ける
+        (ScreenSource::Pipewire, Some(r)) => format!(
+            "pipewiresrc do-timestamp=true ! videoconvert ! videocrop name=crop left={} top={}",
+            r.x, r.y
+        ),
+        (ScreenSource::Pipewire, None) => "pipewiresrc do-timestamp=true".to_string(),
+    }
+}
+
+/// 右下の切り落とし量は入力の大きさがわかるまで決まらないので、capsイベントを見て設定する
+fn crop_on_caps(crop: &gst::Element, region: Region) -> anyhow::Result<()> {
+    let sink_pad = crop.static_pad("sink").context("crop sink pad")?;
+    sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |pad, info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+            if let gst::EventView::Caps(caps) = event.view() {
+                let size = caps.caps().structure(0).and_then(|s| {
+                    Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?))
+                });
+                if let (Some((width, height)), Some(crop)) = (size, pad.parent_element()) {
+                    let right = width - (region.x + region.width) as i32;
+                    let bottom = height - (region.y + region.height) as i32;
+                    crop.set_property("right", right.max(0));
+                    crop.set_property("bottom", bottom.max(0));
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
+
+/// teeから分岐してエンコードし、出力先に書き出すブランチを追加する
+fn add_record_branch(
+    pipeline: &gst::Pipeline,
+    tee: &gst::Element,
+    storage: &mut dyn StorageBackend,
+    container: Container,
+    video: &VideoEncodeOpt,
+) -> anyhow::Result<()> {
+    let codec = video.codec(container.video_codecs())?;
+    let mut elements = vec![
+        gst::ElementFactory::make("queue", None)?,
+        gst::ElementFactory::make("videoconvert", None)?,
+        video.make(codec, None, true, None)?,
+    ];
+    if let Some(parser) = codec.parser() {
+        elements.push(gst::ElementFactory::make(parser, None)?);
+    }
+    // 記録中に落ちても途中まで再生できるように、MP4はmoovを予約して書く
+    elements.push(container.make_muxer(
+        "mux",
+        MuxerOptions {
+            reserved_moov: Some(gst::ClockTime::from_seconds(3600)),
+            streamable: !storage.seekable(),
+            ..Default::default()
+        },
+    )?);
+    elements.push(storage.make_sink(None)?);
+
+    let elements = elements.iter().collect::<Vec<_>>();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    tee.link(elements[0])?;
+    Ok(())
+}
+
+/// 画面をプレビューし、outputがあれば同時にエンコードして記録する
+/// 'q'で止めるとEOSを流して出力を閉じ、確定させる
+pub fn run(options: &ScreenOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "{source} ! videorate ! videoconvert ! video/x-raw,framerate={fps}/1 ! tee name=t \
+         t. ! queue ! videoconvert ! autovideosink",
+        source = source_launch(options.source, options.region),
+        fps = options.fps,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    if let (Some(crop), Some(region)) = (pipeline.by_name("crop"), options.region) {
+        crop_on_caps(&crop, region)?;
+    }
+    let mut storage = options.output.as_deref().map(storage::open).transpose()?;
+    if let Some(storage) = storage.as_mut() {
+        let tee = pipeline.by_name("t").context("tee")?;
+        add_record_branch(&pipeline, &tee, storage.as_mut(), options.container, video)?;
+    }
+
+    record::run_until_eos(&pipeline, record::AutoStop::default())?;
+
+    if let Some(storage) = storage.as_mut() {
+        storage.finish()?;
+    }
+    Ok(())
+}
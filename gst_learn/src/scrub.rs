@@ -0,0 +1,197 @@
+//! スライダーをドラッグしている間、キーフレームだけを表示して素早く位置を探す(スクラブ)
+//!
+//! ドラッグ中の値の変化ごとに普通のシークをすると、前のシークのデコードが終わる前に次のシークが来て、
+//! 大きなファイルでは表示が追いつかない。そこで次の3つを組み合わせる。
+//! - シークにKEY_UNITとTRICKMODE_KEY_UNITSを付け、デコーダにキーフレームだけをデコードさせる
+//! - playbinのvideo-filterに入れたidentityのsrc padで、スクラブ中はDELTA_UNITのフレームを捨てる
+//!   (トリックモードに対応しないデコーダでも、表示するのはキーフレームだけになる)
+//! - 値の変化をまとめる。最初の変化はすぐにシークし、その後はintervalごとに最後の値だけでシークする
+//!
+//! ドラッグを終えたら、離した位置にACCURATEで普通にシークし直してトリックモードを抜ける。
+//!
+//! with_audio_snippetsにすると、黙ってキーフレームを出す代わりに、新しい位置ごとに短い音を鳴らす(オーディオスクラブ)。
+//! シークにSEGMENTを付けてstopを位置+スニペットの長さにすると、stopで止まってEOSの代わりにSEGMENT_DONEが来るので、
+//! パイプラインはPlayingのまま、次のシークまで黙る。音を正確な位置から鳴らすためにKEY_UNITとトリックモードは使わず、
+//! 映像もその位置までデコードするので、シークの間隔はスニペットの長さより短くしない。
+//! スライダーをキーボードで動かしたときもスニペットを鳴らし、値の変化が止まったら最後の位置から普通の再生に戻る。
+//! 音を鳴らすにはPlayingにしておく必要がある。
+//! GTKのメインループで使うので、状態はRc<RefCell>に持ち、タイマーはglib::timeout_add_localで回す。
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use gst::prelude::*;
+
+#[derive(Default)]
+struct State {
+    /// まだシークしていない最後の位置
+    pending: Option<gst::ClockTime>,
+    /// 値の変化をまとめているタイマー
+    timer: Option<glib::SourceId>,
+    /// 最後にシークした位置
+    last: Option<gst::ClockTime>,
+}
+
+/// スライダーからのシークをまとめてスクラブする
+#[derive(Clone)]
+pub struct Scrubber {
+    pipeline: gst::Element,
+    interval: Duration,
+    /// ドラッグ中か。ストリーミングスレッドのプローブからも見る
+    scrubbing: Arc<AtomicBool>,
+    /// オーディオスクラブで鳴らす長さ
+    snippet: Option<gst::ClockTime>,
+    state: Rc<RefCell<State>>,
+}
+
+impl Scrubber {
+    pub fn new(pipeline: &gst::Element, interval: Duration) -> Self {
+        Self {
+            pipeline: pipeline.clone(),
+            interval,
+            scrubbing: Arc::new(AtomicBool::new(false)),
+            snippet: None,
+            state: Rc::new(RefCell::new(State::default())),
+        }
+    }
+
+    /// 新しい位置ごとにsnippetの長さだけ音を鳴らす
+    pub fn with_audio_snippets(mut self, snippet: Duration) -> Self {
+        self.interval = self.interval.max(snippet);
+        self.snippet = Some(gst::ClockTime::from_nseconds(snippet.as_nanos() as u64));
+        self
+    }
+
+    /// playbinのvideo-filterに入れる、スクラブ中にキーフレーム以外を捨てるidentity
+    /// オーディオスクラブでは位置のフレームを出すので何も捨てない
+    pub fn keyframe_filter(&self) -> anyhow::Result<gst::Element> {
+        let identity = gst::ElementFactory::make("identity", Some("scrub-filter"))?;
+        let pad = identity.static_pad("src").unwrap();
+        let scrubbing = self.scrubbing.clone();
+        let keyframes_only = self.snippet.is_none();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            let delta = match &info.data {
+                Some(gst::PadProbeData::Buffer(buffer)) => {
+                    buffer.flags().contains(gst::BufferFlags::DELTA_UNIT)
+                }
+                _ => false,
+            };
+            if delta && keyframes_only && scrubbing.load(Ordering::Relaxed) {
+                gst::PadProbeReturn::Drop
+            } else {
+                gst::PadProbeReturn::Ok
+            }
+        });
+        Ok(identity)
+    }
+
+    /// ドラッグしている間はtrue。この間は再生位置でスライダーを動かさない
+    pub fn is_scrubbing(&self) -> bool {
+        self.scrubbing.load(Ordering::Relaxed)
+    }
+
+    /// ドラッグを始めた
+    pub fn begin(&self) {
+        self.scrubbing.store(true, Ordering::Relaxed);
+    }
+
+    /// スライダーの値が変わった。まとめてシークする
+    pub fn request(&self, position: gst::ClockTime) {
+        let mut state = self.state.borrow_mut();
+        if state.timer.is_some() {
+            state.pending = Some(position);
+            return;
+        }
+        // 間が空いていれば、最初の変化はすぐにシークする
+        self.seek(&mut state, position);
+        let this = self.clone();
+        state.timer = Some(glib::timeout_add_local(self.interval, move || {
+            let mut state = this.state.borrow_mut();
+            match state.pending.take() {
+                Some(position) => {
+                    this.seek(&mut state, position);
+                    glib::Continue(true)
+                }
+                None => {
+                    state.timer = None;
+                    // キーボードで動かしたスニペットが止んだら、最後の位置から再生を続ける
+                    if this.snippet.is_some() && !this.is_scrubbing() {
+                        if let Some(position) = state.last.take() {
+                            this.resume(position);
+                        }
+                    }
+                    glib::Continue(false)
+                }
+            }
+        }));
+    }
+
+    /// ドラッグを終えた。離した位置に正確にシークし直す
+    pub fn end(&self, position: gst::ClockTime) {
+        self.scrubbing.store(false, Ordering::Relaxed);
+        let mut state = self.state.borrow_mut();
+        state.pending = None;
+        state.last = None;
+        if let Some(timer) = state.timer.take() {
+            timer.remove();
+        }
+        self.resume(position);
+    }
+
+    /// トリックモードやセグメントのシークを終えて、positionから普通に再生する
+    fn resume(&self, position: gst::ClockTime) {
+        if self
+            .pipeline
+            .seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, position)
+            .is_err()
+        {
+            log::warn!("seeking to {position} failed");
+        }
+    }
+
+    /// positionにシークして、その位置をlastに覚える
+    fn seek(&self, state: &mut State, position: gst::ClockTime) {
+        state.last = Some(position);
+        if let Some(snippet) = self.snippet {
+            // stopで止めてSEGMENT_DONEにし、次のシークまで黙る
+            let result = self.pipeline.seek(
+                1.0,
+                gst::SeekFlags::FLUSH | gst::SeekFlags::SEGMENT,
+                gst::SeekType::Set,
+                Some(position),
+                gst::SeekType::Set,
+                Some(position + snippet),
+            );
+            if result.is_err() {
+                log::warn!("playing a snippet at {position} failed");
+            }
+            return;
+        }
+        let flags = if self.is_scrubbing() {
+            gst::SeekFlags::FLUSH
+                | gst::SeekFlags::KEY_UNIT
+                | gst::SeekFlags::SNAP_NEAREST
+                | gst::SeekFlags::TRICKMODE
+                | gst::SeekFlags::TRICKMODE_KEY_UNITS
+        } else {
+            gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT
+        };
+        let result = self.pipeline.seek(
+            1.0,
+            flags,
+            gst::SeekType::Set,
+            Some(position),
+            gst::SeekType::None,
+            gst::ClockTime::NONE,
+        );
+        if result.is_err() {
+            log::warn!("seeking to {position} failed");
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! 主音声に副音声(音声解説やコメンタリー)を別のURIから重ねて再生する例
+//!
+//! 2つのuridecodebinを同じパイプラインに入れてaudiomixerで混ぜる。
+//! 同じパイプラインのエレメントは同じクロックとbase-timeを使い、どちらのsegmentもrunning-time 0から始まるので、
+//! audiomixerがrunning-timeで揃えるだけで2つの音声は同時に始まり、ずれずに進む。
+//! 副音声を遅れて始めたいときは、副音声のブランチのsrc padにoffsetを付けてrunning-timeをずらす。
+//!
+//! uridecodebinには音声だけを出させ(expose-all-streams=false)、映像のpadが繋がらずに止まらないようにする。
+//! 音量はブランチごとのvolumeで別々に決める。
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 副音声の再生の設定
+#[derive(Debug)]
+pub struct SecondaryAudioOptions {
+    pub primary: String,
+    pub secondary: String,
+    pub primary_volume: f64,
+    pub secondary_volume: f64,
+    /// 副音声を始めるまでの遅れ
+    pub delay: gst::ClockTime,
+}
+
+/// URIをデコードして音量を変えるまでのブランチ
+fn branch(uri: &str, name: &str, volume: f64) -> String {
+    format!(
+        "uridecodebin uri=\"{uri}\" caps=audio/x-raw expose-all-streams=false \
+         ! audioconvert ! audioresample ! volume name={name} volume={volume}"
+    )
+}
+
+/// 主音声と副音声を混ぜてEOSかエラーまで再生する
+pub fn run(options: &SecondaryAudioOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "audiomixer name=mix ! audioconvert ! autoaudiosink \
+         {primary} ! mix. \
+         {secondary} ! mix.",
+        primary = branch(&options.primary, "primary", options.primary_volume),
+        secondary = branch(&options.secondary, "secondary", options.secondary_volume),
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    if options.delay > gst::ClockTime::ZERO {
+        let secondary = pipeline.by_name("secondary").context("secondary volume")?;
+        let src_pad = secondary.static_pad("src").context("volume src pad")?;
+        src_pad.set_offset(options.delay.nseconds() as i64);
+        log::info!("starting the secondary audio {} later", options.delay);
+    }
+
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,267 @@
+//! appsrcをseekable/random-accessモードで使う例
+//!
+//! B8のappsrcはpushモードで先頭から順にデータを流すだけだったが、
+//! stream-typeをseekableにするとseek-dataコールバックで任意の位置からの読み出しを要求される。
+//! random-accessにすると下流(typefindやdemuxer)がpullモードで任意位置を読みに来る。
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::{AppSrc, AppStreamType};
+
+use crate::fallback;
+
+const SAMPLE_RATE: u32 = 44_100;
+const GENERATED_SECONDS: u32 = 10;
+
+/// appsrcに供給するデータの実体
+/// オフセット指定で読み出せればメモリでもファイルでもよい
+pub trait ByteSource: Send {
+    /// 全体のバイト数。appsrcのsizeに設定してduration計算やseekに使わせる
+    fn size(&self) -> u64;
+    /// offsetから最大lenバイト読み出す。末尾では空を返す
+    fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+}
+
+/// メモリ上に確保したデータ
+pub struct MemorySource {
+    data: Vec<u8>,
+}
+
+impl MemorySource {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+}
+
+impl ByteSource for MemorySource {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let start = (offset as usize).min(self.data.len());
+        let end = start.saturating_add(len).min(self.data.len());
+        Ok(self.data[start..end].to_vec())
+    }
+}
+
+/// ローカルファイル
+pub struct FileSource {
+    file: File,
+    size: u64,
+}
+
+impl FileSource {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+impl ByteSource for FileSource {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::with_capacity(len);
+        self.file.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// 1秒ごとに音程が上がるサイン波のWAVをメモリ上に作る
+/// seekした時に位置が変わったことが耳でわかるようにしている
+fn generate_wav(seconds: u32) -> Vec<u8> {
+    let num_samples = SAMPLE_RATE * seconds;
+    let data_len = num_samples * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for i in 0..num_samples {
+        let freq = 220.0 * f64::from(1 + i / SAMPLE_RATE);
+        let t = f64::from(i) / f64::from(SAMPLE_RATE);
+        let sample = (0.3 * f64::from(i16::MAX) * (2.0 * PI * freq * t).sin()) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+struct State {
+    source: Box<dyn ByteSource>,
+    offset: u64,
+}
+
+/// appsrcをバイト列のsourceから読み出すように設定する
+/// need-dataでsourceの現在位置から読んでpushし、seek-dataで読む位置を変える
+pub fn attach(appsrc: &AppSrc, source: Box<dyn ByteSource>, random_access: bool) {
+    let size = source.size();
+    let state = Arc::new(Mutex::new(State { source, offset: 0 }));
+
+    // seekableはpushモードのまま任意位置から読み直せる
+    // random-accessは下流がpullモードで動作し、need-dataの中で同期的にpushする必要がある
+    appsrc.set_stream_type(if random_access {
+        AppStreamType::RandomAccess
+    } else {
+        AppStreamType::Seekable
+    });
+    appsrc.set_format(gst::Format::Bytes);
+    appsrc.set_size(size as i64);
+
+    let need_state = state.clone();
+    let seek_state = state;
+    appsrc.set_callbacks(
+        gstreamer_app::AppSrcCallbacks::builder()
+            // lengthは要求されたバイト数の目安
+            .need_data(move |appsrc, length| {
+                let mut state = need_state.lock().unwrap();
+                let offset = state.offset;
+                let data = match state.source.read_at(offset, length as usize) {
+                    Ok(data) => data,
+                    // 読めないデータを飛ばして続けないように、EOSではなくエラーで止める
+                    Err(err) => {
+                        gst::element_error!(
+                            appsrc,
+                            gst::ResourceError::Read,
+                            ("failed to read at {}: {}", offset, err)
+                        );
+                        return;
+                    }
+                };
+                if data.is_empty() {
+                    let _ = appsrc.end_of_stream();
+                    return;
+                }
+
+                state.offset += data.len() as u64;
+                let mut buffer = gst::Buffer::from_mut_slice(data);
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    buffer.set_offset(offset);
+                    buffer.set_offset_end(state.offset);
+                }
+                drop(state);
+                let _ = appsrc.push_buffer(buffer);
+            })
+            // 下流がseekするとバイトオフセットに変換されてここに届く
+            .seek_data(move |_, offset| {
+                log::info!("seek-data to offset {offset}");
+                let mut state = seek_state.lock().unwrap();
+                if offset > state.source.size() {
+                    return false;
+                }
+                state.offset = offset;
+                true
+            })
+            .build(),
+    );
+}
+
+/// ファイルを指定しなければ生成したWAVをメモリから再生する
+/// 再生開始から2秒後に6秒地点へseekし、seek-dataが呼ばれる様子をログで確認する
+pub fn run(path: Option<&Path>, random_access: bool) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let source: Box<dyn ByteSource> = match path {
+        Some(path) => {
+            Box::new(FileSource::open(path).with_context(|| format!("open {}", path.display()))?)
+        }
+        None => Box::new(MemorySource::new(generate_wav(GENERATED_SECONDS))),
+    };
+
+    let appsrc = gst::ElementFactory::make("appsrc", Some("source"))?;
+    let decodebin = gst::ElementFactory::make("decodebin", Some("decodebin"))?;
+    let convert = gst::ElementFactory::make("audioconvert", Some("convert"))?;
+    let resample = gst::ElementFactory::make("audioresample", Some("resample"))?;
+    let sink = fallback::make("autoaudiosink", Some("sink"))?;
+
+    let pipeline = gst::Pipeline::new(Some("pipeline"));
+    pipeline.add_many(&[&appsrc, &decodebin, &convert, &resample, &sink])?;
+    appsrc.link(&decodebin)?;
+    gst::Element::link_many(&[&convert, &resample, &sink])?;
+
+    // decodebinは中身を判別してからpadを作るのでB3と同様にpad-addedで繋ぐ
+    let convert_weak = convert.downgrade();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let convert = match convert_weak.upgrade() {
+            Some(convert) => convert,
+            None => return,
+        };
+        let sink_pad = convert.static_pad("sink").expect("convert has sink pad");
+        if sink_pad.is_linked() {
+            return;
+        }
+        if let Err(err) = src_pad.link(&sink_pad) {
+            log::error!("failed to link decodebin: {err:?}");
+        }
+    });
+
+    let appsrc = appsrc.dynamic_cast::<AppSrc>().unwrap();
+    attach(&appsrc, source, random_access);
+
+    pipeline.set_state(gst::State::Playing)?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let mut seek_done = false;
+    loop {
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => {
+                if !seek_done {
+                    if let Some(position) = pipeline.query_position::<gst::ClockTime>() {
+                        if position > 2 * gst::ClockTime::SECOND {
+                            log::info!("Reached {position}, performing seek...");
+                            pipeline.seek_simple(
+                                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                                6 * gst::ClockTime::SECOND,
+                            )?;
+                            seek_done = true;
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
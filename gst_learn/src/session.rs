@@ -0,0 +1,200 @@
+//! 再生中の操作を時刻と一緒にファイルに記録し、後から同じ時刻に同じ操作をやり直す
+//!
+//! 再生の不具合は、どの位置でどの順に操作したかで起きたり起きなかったりする。
+//! キー操作(input::Command)と、MPRISなどの操作のインターフェースから来た状態の変更、シーク、プロパティの設定を
+//! 記録を始めてからの秒数と一緒に1行ずつ書く。行の後ろには記録したときの再生位置を#のコメントで付けるので、
+//! やり直したときのログと見比べられる。書くたびにflushするので、落ちたときもそこまでの操作は残る。
+//!
+//! ```text
+//! # media https://example.com/video.webm
+//! 1.520 rate_up # 0:00:01.520000000
+//! 4.003 pause # 0:00:05.100000000
+//! 5.210 seek 30000000000 # 0:00:05.100000000
+//! 6.000 set volume 0.5 # 0:00:30.000000000
+//! 9.800 quit # 0:00:33.800000000
+//! ```
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::input::Command;
+
+/// 記録する操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// キーに割り当てた再生操作
+    Command(Command),
+    /// Playingにする
+    Play,
+    /// Pausedにする
+    Pause,
+    /// この位置にシークする
+    Seek(gst::ClockTime),
+    /// パイプライン(playbin)のプロパティを文字列の値で設定する
+    Set(String, String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Command(command) => f.write_str(command.name()),
+            Action::Play => f.write_str("play"),
+            Action::Pause => f.write_str("pause"),
+            Action::Seek(position) => write!(f, "seek {}", position.nseconds()),
+            Action::Set(name, value) => write!(f, "set {name} {value}"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().context("empty action")?;
+        let action = match name {
+            "play" => Action::Play,
+            "pause" => Action::Pause,
+            "seek" => Action::Seek(gst::ClockTime::from_nseconds(
+                words
+                    .next()
+                    .context("seek without a position")?
+                    .parse()
+                    .context("invalid seek position")?,
+            )),
+            "set" => {
+                let property = words.next().context("set without a property")?;
+                // 値には空白を含められる
+                let value = words.collect::<Vec<_>>().join(" ");
+                anyhow::ensure!(!value.is_empty(), "set {property} without a value");
+                return Ok(Action::Set(property.to_string(), value));
+            }
+            _ => Action::Command(
+                Command::from_name(name).with_context(|| format!("unknown action {name:?}"))?,
+            ),
+        };
+        anyhow::ensure!(words.next().is_none(), "extra words after {name}");
+        Ok(action)
+    }
+}
+
+struct RecorderInner {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+/// 操作をファイルに書く。cloneして操作のインターフェースごとに持たせる
+#[derive(Clone)]
+pub struct SessionRecorder {
+    inner: Arc<Mutex<RecorderInner>>,
+    pipeline: gst::Element,
+}
+
+impl SessionRecorder {
+    /// ファイルを作って記録を始める。秒数はここから数える
+    pub fn create(path: &Path, pipeline: &gst::Element, media: &str) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(
+            File::create(path).with_context(|| format!("create {}", path.display()))?,
+        );
+        writeln!(file, "# media {media}")?;
+        file.flush()?;
+        log::info!("recording the session to {}", path.display());
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                file,
+                started: Instant::now(),
+            })),
+            pipeline: pipeline.clone(),
+        })
+    }
+
+    pub fn record(&self, action: &Action) {
+        let position = self.pipeline.query_position::<gst::ClockTime>();
+        let mut inner = self.inner.lock().unwrap();
+        let elapsed = inner.started.elapsed().as_secs_f64();
+        let result = writeln!(inner.file, "{elapsed:.3} {action} # {}", position.display())
+            .and_then(|_| inner.file.flush());
+        if let Err(err) = result {
+            log::warn!("failed to record {action}: {err}\r");
+        }
+    }
+}
+
+/// 記録したセッション
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// 記録したときのメディア
+    pub media: Option<String>,
+    /// 記録を始めてからの時間と操作
+    pub actions: Vec<(Duration, Action)>,
+}
+
+impl Session {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut media = None;
+        let mut actions = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(uri) = comment.trim().strip_prefix("media ") {
+                    media = Some(uri.trim().to_string());
+                }
+                continue;
+            }
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (at, action) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("line {}: expected <seconds> <action>", number + 1))?;
+            let at = at
+                .parse::<f64>()
+                .ok()
+                .filter(|at| *at >= 0.0)
+                .with_context(|| format!("line {}: invalid seconds {at:?}", number + 1))?;
+            let action = action
+                .parse::<Action>()
+                .with_context(|| format!("line {}", number + 1))?;
+            actions.push((Duration::from_secs_f64(at), action));
+        }
+        actions.sort_by_key(|(at, _)| *at);
+        Ok(Self { media, actions })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::parse(&s).with_context(|| format!("parse {}", path.display()))
+    }
+
+    /// 別スレッドで、記録した時刻に合わせて操作を渡す。最後の操作を渡したら終わる
+    /// 時刻は呼んだときから数える
+    pub fn replay(self, media: &str, f: impl Fn(Action) + Send + 'static) {
+        if let Some(recorded) = &self.media {
+            if recorded != media {
+                log::warn!("the session was recorded with {recorded}, replaying with {media}");
+            }
+        }
+        log::info!("replaying {} actions", self.actions.len());
+        let started = Instant::now();
+        thread::spawn(move || {
+            for (at, action) in self.actions {
+                if let Some(wait) = at.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+                log::info!("replay {:.3}: {action}\r", at.as_secs_f64());
+                f(action);
+            }
+        });
+    }
+}
@@ -0,0 +1,411 @@
+//! よく使うオプションを設定ファイルに書いておき、コマンドラインに足してから解釈する
+//!
+//! 設定ファイルはTOMLで、キーはオプションの長い名前(`--`を除いたもの)、値はその引数。
+//! `[global]`はサブコマンドの前に書くオプション(エンコーダやバスループなど)、
+//! `[サブコマンド名]`はそのサブコマンドのオプションになる。
+//! `[profiles.名前.global]`、`[profiles.名前.サブコマンド名]`は--config-profileで選んだときだけ重ねる。
+//! (--profileはエレメントの処理時間を測るオプションなので、別の名前にしている)
+//!
+//! ```toml
+//! [global]
+//! state-timeout = 20
+//! video-codec = "h264"
+//!
+//! [seg-record]
+//! dir = "/var/recordings"
+//! container = "mkv"
+//!
+//! [profiles.studio.global]
+//! bitrate = 8000
+//!
+//! [profiles.studio.multicam]
+//! device = ["/dev/video0", "/dev/video2"]
+//! ```
+//!
+//! 優先順位は、書いていないもの(structoptの既定値) < 設定ファイル < --config-profile < コマンドライン。
+//! コマンドラインで指定したオプションは設定ファイルから足さないので、いつでも上書きできる。
+//! 値がtrueならフラグだけを、falseなら何も足さない。配列は要素ごとにオプションを繰り返す。
+//! サブコマンドの位置引数は設定ファイルには書けない。
+//!
+//! 設定ファイルは--configで指定するか、`$XDG_CONFIG_HOME/gst_learn/config.toml`
+//! (なければ`~/.config/gst_learn/config.toml`)を読む。足したオプションはログに出す。
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use structopt::{
+    clap::{App, Arg, ArgMatches, ErrorKind},
+    StructOpt,
+};
+
+const GLOBAL: &str = "global";
+const PROFILES: &str = "profiles";
+/// 設定ファイルを読む前にだけ使うオプション。設定ファイルには書けない
+const CONFIG: &str = "config";
+const CONFIG_PROFILE: &str = "config-profile";
+
+/// コマンドラインに--configと--config-profileを足す
+/// 設定ファイルを読む前にだけ使うので、Optには持たせない
+fn app<T: StructOpt>() -> App<'static, 'static> {
+    T::clap()
+        .arg(
+            Arg::with_name(CONFIG)
+                .long(CONFIG)
+                .value_name("path")
+                .help("config file, $XDG_CONFIG_HOME/gst_learn/config.toml if it exists")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(CONFIG_PROFILE)
+                .long(CONFIG_PROFILE)
+                .value_name("name")
+                .help("apply [profiles.<name>] of the config file")
+                .takes_value(true),
+        )
+}
+
+/// 既定の設定ファイルの場所
+fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("gst_learn").join("config.toml"))
+}
+
+/// オプションの名前と値
+type Layer = BTreeMap<String, toml::Value>;
+
+/// セクションの名前と、そのオプション
+type Sections = BTreeMap<String, Layer>;
+
+/// 読んだ設定ファイル
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Settings {
+    /// `[profiles.名前.セクション]`
+    #[serde(default)]
+    profiles: BTreeMap<String, Sections>,
+    /// `[global]`と`[サブコマンド名]`
+    #[serde(flatten)]
+    sections: Sections,
+}
+
+impl Settings {
+    fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn profile(&self, name: &str) -> anyhow::Result<&Sections> {
+        self.profiles
+            .get(name)
+            .with_context(|| format!("no [{PROFILES}.{name}] section"))
+    }
+
+    /// sectionの設定に、profileの同じsectionを重ねる
+    fn layer(&self, section: &str, profile: Option<&str>) -> anyhow::Result<Layer> {
+        let mut layer = Layer::new();
+        let profile = profile.map(|name| self.profile(name)).transpose()?;
+        for sections in [Some(&self.sections), profile].into_iter().flatten() {
+            if let Some(options) = sections.get(section) {
+                layer.extend(options.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+        }
+        Ok(layer)
+    }
+}
+
+/// 1つのオプションをコマンドラインの引数にする
+fn push_option(args: &mut Vec<OsString>, name: &str, value: &toml::Value) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        name != CONFIG && name != CONFIG_PROFILE,
+        "{name} can only be given on the command line"
+    );
+    let flag = format!("--{name}");
+    match value {
+        toml::Value::Boolean(true) => args.push(flag.into()),
+        toml::Value::Boolean(false) => {}
+        toml::Value::Array(values) => {
+            for value in values {
+                push_option(args, name, value)?;
+            }
+        }
+        toml::Value::String(s) => args.extend([flag, s.clone()].map(OsString::from)),
+        toml::Value::Integer(_) | toml::Value::Float(_) => {
+            args.extend([flag, value.to_string()].map(OsString::from))
+        }
+        _ => anyhow::bail!("{name}: use a string, number, boolean or array"),
+    }
+    Ok(())
+}
+
+/// コマンドラインで指定されていないオプションを引数にする
+fn layer_args(layer: &Layer, matches: &ArgMatches) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (name, value) in layer {
+        if matches.occurrences_of(name) > 0 {
+            continue;
+        }
+        push_option(&mut args, name, value).with_context(|| format!("option {name}"))?;
+    }
+    Ok(args)
+}
+
+/// 設定ファイルのオプションを足したコマンドライン
+fn expand(matches: &ArgMatches, mut args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let profile = matches.value_of(CONFIG_PROFILE);
+    let path = match matches.value_of_os(CONFIG) {
+        Some(path) => PathBuf::from(path),
+        None => match default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None if profile.is_some() => anyhow::bail!("--config-profile needs a config file"),
+            None => return Ok(args),
+        },
+    };
+    let settings = Settings::load(&path)?;
+    let context = || format!("in {}", path.display());
+
+    let global = settings.layer(GLOBAL, profile).with_context(context)?;
+    let global_args = layer_args(&global, matches).with_context(context)?;
+    let mut subcommand_args = Vec::new();
+    if let Some((name, sub_matches)) = matches
+        .subcommand_name()
+        .and_then(|name| Some((name, matches.subcommand_matches(name)?)))
+    {
+        let layer = settings.layer(name, profile).with_context(context)?;
+        subcommand_args = layer_args(&layer, sub_matches).with_context(context)?;
+        // サブコマンドのオプションはサブコマンドの引数の最後に入れる
+        // サブコマンド名はグローバルオプションの値にもなりうるので探さない。`--`より後ろは位置引数になる
+        let position = args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(args.len());
+        args.splice(position..position, subcommand_args.iter().cloned());
+    }
+    args.splice(1..1, global_args.iter().cloned());
+
+    let added = global_args
+        .iter()
+        .chain(&subcommand_args)
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>();
+    if !added.is_empty() {
+        log::info!("options from {}: {}", path.display(), added.join(" "));
+    }
+    Ok(args)
+}
+
+/// 必須の引数を外す
+/// 必須のオプションは設定ファイルで足すかもしれないので、足す前の解釈では調べない
+/// clap 2には引数の設定を後から変える方法がないので、解釈に使う必須の一覧を直接空にする
+fn relax(app: &mut App) {
+    app.p.required.clear();
+    app.p.r_ifs.clear();
+    for subcommand in &mut app.p.subcommands {
+        relax(subcommand);
+    }
+}
+
+/// 設定ファイルのオプションを足す前のコマンドラインを解釈する
+/// 必須の引数がないときだけ、必須を外してもう一度解釈する。ヘルプや他の間違いはそのまま知らせる
+fn lenient_matches<T: StructOpt>(args: &[OsString]) -> ArgMatches<'static> {
+    match app::<T>().get_matches_from_safe(args) {
+        Ok(matches) => matches,
+        Err(err) if err.kind == ErrorKind::MissingRequiredArgument => {
+            let mut app = app::<T>();
+            relax(&mut app);
+            app.get_matches_from(args)
+        }
+        Err(err) => err.exit(),
+    }
+}
+
+/// 設定ファイルのオプションを足してからコマンドラインを解釈する
+/// 最初にコマンドラインだけを解釈して、サブコマンドと指定済みのオプションを調べる
+/// 必須の引数は足した後で調べるので、設定ファイルにだけ書いてもよい
+/// 足したオプションが間違っていれば、clapがコマンドラインの間違いと同じように知らせる
+pub fn from_args<T: StructOpt>() -> T {
+    let args = env::args_os().collect::<Vec<_>>();
+    let matches = lenient_matches::<T>(&args);
+    match expand(&matches, args) {
+        Ok(args) => T::from_clap(&app::<T>().get_matches_from(args)),
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, StructOpt)]
+    struct Opt {
+        #[structopt(long)]
+        level: Option<u32>,
+        #[structopt(long)]
+        verbose: bool,
+        #[structopt(subcommand)]
+        command: Command,
+    }
+
+    #[derive(Debug, StructOpt)]
+    enum Command {
+        Play {
+            #[structopt(long)]
+            key: String,
+            #[structopt(long)]
+            device: Vec<String>,
+            input: Option<String>,
+        },
+    }
+
+    const CONFIG_TOML: &str = r#"
+[global]
+level = 1
+verbose = true
+
+[play]
+key = "config.key"
+device = ["a", "b"]
+
+[profiles.loud.global]
+level = 9
+
+[profiles.play.global]
+level = 7
+"#;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    /// 設定ファイルを書いて、from_argsと同じ手順でコマンドラインを解釈する
+    fn parse(name: &str, command_line: &[&str]) -> anyhow::Result<Opt> {
+        let path = env::temp_dir().join(format!(
+            "gst-learn-settings-{}-{name}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, CONFIG_TOML)?;
+        let mut full = vec!["gst_learn", "--config", path.to_str().unwrap()];
+        full.extend_from_slice(command_line);
+        let full = args(&full);
+        let matches = lenient_matches::<Opt>(&full);
+        let expanded = expand(&matches, full);
+        fs::remove_file(&path)?;
+        Ok(Opt::from_clap(
+            &app::<Opt>().get_matches_from_safe(expanded?)?,
+        ))
+    }
+
+    #[test]
+    fn push_option_values() {
+        let mut pushed = Vec::new();
+        push_option(&mut pushed, "flag", &toml::Value::Boolean(true)).unwrap();
+        push_option(&mut pushed, "off", &toml::Value::Boolean(false)).unwrap();
+        push_option(&mut pushed, "rate", &toml::Value::Integer(48000)).unwrap();
+        push_option(&mut pushed, "gain", &toml::Value::Float(0.5)).unwrap();
+        push_option(&mut pushed, "name", &toml::Value::String("a b".into())).unwrap();
+        push_option(
+            &mut pushed,
+            "device",
+            &toml::Value::Array(vec!["x".into(), "y".into()]),
+        )
+        .unwrap();
+        assert_eq!(
+            pushed,
+            args(&[
+                "--flag", "--rate", "48000", "--gain", "0.5", "--name", "a b", "--device", "x",
+                "--device", "y",
+            ])
+        );
+    }
+
+    #[test]
+    fn push_option_rejects() {
+        let mut pushed = Vec::new();
+        let table = toml::Value::Table(Default::default());
+        assert!(push_option(&mut pushed, "nested", &table).is_err());
+        assert!(push_option(&mut pushed, CONFIG, &toml::Value::String("x".into())).is_err());
+        assert!(push_option(&mut pushed, CONFIG_PROFILE, &toml::Value::Boolean(true)).is_err());
+        assert!(pushed.is_empty());
+    }
+
+    #[test]
+    fn layer_with_profile() {
+        let settings = Settings::from_toml(CONFIG_TOML).unwrap();
+        let global = settings.layer(GLOBAL, None).unwrap();
+        assert_eq!(global["level"], toml::Value::Integer(1));
+        let loud = settings.layer(GLOBAL, Some("loud")).unwrap();
+        assert_eq!(loud["level"], toml::Value::Integer(9));
+        assert_eq!(loud["verbose"], toml::Value::Boolean(true));
+        assert!(settings.layer(GLOBAL, Some("quiet")).is_err());
+        assert!(settings.layer("record", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn layer_args_skips_command_line() {
+        let matches = App::new("test")
+            .arg(Arg::with_name("level").long("level").takes_value(true))
+            .arg(Arg::with_name("verbose").long("verbose"))
+            .get_matches_from(["test", "--level", "3"]);
+        let settings = Settings::from_toml(CONFIG_TOML).unwrap();
+        let layer = settings.layer(GLOBAL, None).unwrap();
+        assert_eq!(layer_args(&layer, &matches).unwrap(), args(&["--verbose"]));
+    }
+
+    #[test]
+    fn config_supplies_required_option() {
+        let opt = parse("required", &["play", "input.mp4"]).unwrap();
+        assert_eq!(opt.level, Some(1));
+        assert!(opt.verbose);
+        let Command::Play { key, device, input } = opt.command;
+        assert_eq!(key, "config.key");
+        assert_eq!(device, ["a", "b"]);
+        assert_eq!(input.as_deref(), Some("input.mp4"));
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let opt = parse(
+            "override",
+            &[
+                "--level",
+                "5",
+                "--config-profile",
+                "loud",
+                "play",
+                "--key",
+                "cli.key",
+                "--",
+                "-input",
+            ],
+        )
+        .unwrap();
+        // コマンドラインは--config-profileより強い
+        assert_eq!(opt.level, Some(5));
+        let Command::Play { key, input, .. } = opt.command;
+        assert_eq!(key, "cli.key");
+        assert_eq!(input.as_deref(), Some("-input"));
+    }
+
+    #[test]
+    fn subcommand_name_as_global_value() {
+        // --config-profileの値がサブコマンド名と同じでも、サブコマンドのオプションはサブコマンドに入る
+        let opt = parse("value", &["--config-profile", "play", "play"]).unwrap();
+        assert_eq!(opt.level, Some(7));
+        let Command::Play { key, .. } = opt.command;
+        assert_eq!(key, "config.key");
+    }
+}
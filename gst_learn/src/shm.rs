@@ -0,0 +1,191 @@
+//! shmsink/shmsrcで別のプロセスのパイプラインに生の映像を渡す例
+//!
+//! shmsinkは共有メモリにバッファを書き、制御用のUNIXドメインソケットで受け側にその位置を知らせる。
+//! 中身はコピーせずに受け渡せるが、ソケットで渡るのはバイト列だけで、capsもタイムスタンプも伝わらない。
+//! そこで送り側は交渉が済んだcapsをソケットの隣のファイル(<socket>.caps)に書き、受け側はそれを読んでcapsを付ける。
+//! capsのファイルは一時ファイルに書いてから名前を変えるので、受け側が書きかけを読むことはない。
+//!
+//! ソケットのファイルはshmsinkがNullに戻るときに消すが、プロセスごと落ちると残ってしまい、
+//! 次のshmsinkはbindできずに失敗する。送り側は始める前にソケットに繋いでみて、誰も応えなければ消してから使う。
+//! 送り側が止まると受け側のshmsrcはエラーで終わる。
+use std::{
+    fs, io,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 共有メモリに溜められるフレーム数。受け側が遅れてもこの数までは送り側を待たせない
+const SHM_FRAMES: u32 = 4;
+
+/// capsを書くファイルのパス
+fn caps_path(socket: &Path) -> PathBuf {
+    let mut path = socket.to_path_buf().into_os_string();
+    path.push(".caps");
+    PathBuf::from(path)
+}
+
+/// 送り側の設定
+#[derive(Debug, Clone)]
+pub struct ShmSendOptions {
+    /// 制御用のソケットのパス
+    pub socket: PathBuf,
+    pub width: i32,
+    pub height: i32,
+    pub fps: i32,
+    /// 送るフレーム数。Noneなら止めるまで送る
+    pub frames: Option<u32>,
+}
+
+impl ShmSendOptions {
+    fn caps(&self) -> gst::Caps {
+        gst::Caps::builder("video/x-raw")
+            .field("format", "I420")
+            .field("width", self.width)
+            .field("height", self.height)
+            .field("framerate", gst::Fraction::new(self.fps, 1))
+            .build()
+    }
+}
+
+/// 前に落ちたプロセスが残したソケットなら消す。使われているソケットならErr
+fn remove_stale_socket(socket: &Path) -> anyhow::Result<()> {
+    if !socket.exists() {
+        return Ok(());
+    }
+    match UnixStream::connect(socket) {
+        Ok(_) => anyhow::bail!("{} is used by another sender", socket.display()),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("removing stale socket {}", socket.display());
+            fs::remove_file(socket)
+                .with_context(|| format!("failed to remove {}", socket.display()))
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("{} is not a usable socket", socket.display()))
+        }
+    }
+}
+
+/// capsを一時ファイルに書いてから名前を変える
+fn write_caps(path: &Path, caps: &gst::CapsRef) -> io::Result<()> {
+    let mut part = path.to_path_buf().into_os_string();
+    part.push(".part");
+    fs::write(&part, caps.to_string())?;
+    fs::rename(&part, path)
+}
+
+/// 送り側が終わるときにcapsのファイルを消す。ソケットはshmsinkが消す
+struct CapsFile(PathBuf);
+
+impl Drop for CapsFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.0) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!("failed to remove {}: {err}", self.0.display()),
+        }
+    }
+}
+
+/// テスト映像をshmsinkで共有メモリに書く
+pub fn send(options: &ShmSendOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let caps = options.caps();
+    let info = gstreamer_video::VideoInfo::from_caps(&caps)?;
+    let socket = options.socket.to_str().context("non UTF-8 path")?;
+    remove_stale_socket(&options.socket)?;
+
+    let num_buffers = options.frames.map(|n| n as i32).unwrap_or(-1);
+    let pipeline = fallback::parse_launch(&format!(
+        "videotestsrc is-live=true pattern=ball num-buffers={num_buffers} ! timeoverlay \
+         ! videoconvert ! capsfilter name=caps \
+         ! shmsink name=sink socket-path={socket} shm-size={} wait-for-connection=false sync=true",
+        info.size() as u32 * SHM_FRAMES
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    pipeline
+        .by_name("caps")
+        .context("caps")?
+        .set_property("caps", &caps);
+
+    // 交渉が済んだcapsを受け側に知らせる。途中で変わったら書き直す
+    let caps_file = CapsFile(caps_path(&options.socket));
+    let path = caps_file.0.clone();
+    let sink = pipeline.by_name("sink").context("sink")?;
+    sink.static_pad("sink")
+        .context("shmsink sink pad")?
+        .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if let gst::EventView::Caps(c) = event.view() {
+                    match write_caps(&path, c.caps()) {
+                        Ok(()) => log::info!("wrote {} to {}", c.caps(), path.display()),
+                        Err(err) => {
+                            log::error!("failed to write {}: {err}", path.display())
+                        }
+                    }
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+    log::info!("sending {caps} to {socket}");
+    let result = runner::run(&pipeline, bus_loop);
+    drop(caps_file);
+    result
+}
+
+/// 送り側が書いたcapsのファイルを待って読む
+fn read_caps(path: &Path, wait: Duration) -> anyhow::Result<gst::Caps> {
+    let started = Instant::now();
+    loop {
+        match fs::read_to_string(path) {
+            Ok(caps) => {
+                return caps
+                    .trim()
+                    .parse::<gst::Caps>()
+                    .with_context(|| format!("invalid caps in {}", path.display()))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound && started.elapsed() < wait => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("no caps from the sender in {}", path.display()))
+            }
+        }
+    }
+}
+
+/// 共有メモリの映像をshmsrcで受けて表示する
+/// 送り側がcapsのファイルを書くまでwaitの間待つ
+pub fn receive(socket: &Path, wait: Duration, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let caps = read_caps(&caps_path(socket), wait)?;
+    let socket = socket.to_str().context("non UTF-8 path")?;
+    // 送り側のタイムスタンプは届かないので、受け取った時刻で付け直す
+    let pipeline = fallback::parse_launch(&format!(
+        "shmsrc socket-path={socket} is-live=true do-timestamp=true ! capsfilter name=caps \
+         ! queue ! videoconvert ! autovideosink"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    pipeline
+        .by_name("caps")
+        .context("caps")?
+        .set_property("caps", &caps);
+
+    log::info!("receiving {caps} from {socket}");
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,204 @@
+//! gst-plugin-tutorialのrsdetectsilenceで無音を見つけ、音声を無音で区切った区間の一覧を出す
+//!
+//! rsdetectsilenceは音量(RMS)がthresholdを下回った状態がhold-timeだけ続くとrsdetectsilence-startを、
+//! また音が出るとrsdetectsilence-stopをエレメントメッセージで知らせる。
+//! メッセージのtimestampは無音が始まった位置と終わった位置なので、その間を除いた残りが音のある区間になる。
+//! 再生はせずにfakesinkで最後まで流すので、ファイルの長さによらずすぐに終わる。
+//!
+//! GST_PLUGIN_PATHにgst-plugin-tutorialのビルド結果を含めておく。
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::transcode;
+
+/// 無音の区切りの設定
+#[derive(Debug, Clone)]
+pub struct SilenceOptions {
+    /// ファイルパスかURI
+    pub input: String,
+    /// 無音とみなす音量(dBFS)
+    pub threshold: f64,
+    /// 無音とみなすまでに続く時間
+    pub hold: gst::ClockTime,
+}
+
+/// rsdetectsilenceが知らせる無音の境目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// 無音が始まった位置
+    Start(gst::ClockTime),
+    /// 無音が終わって音が出た位置
+    Stop(gst::ClockTime),
+}
+
+impl Boundary {
+    /// rsdetectsilenceのエレメントメッセージならその境目
+    pub fn from_message(msg: &gst::Message) -> Option<Self> {
+        let s = match msg.view() {
+            gst::MessageView::Element(element) => element.structure()?,
+            _ => return None,
+        };
+        let timestamp = s.get::<gst::ClockTime>("timestamp").ok()?;
+        match s.name() {
+            "rsdetectsilence-start" => Some(Self::Start(timestamp)),
+            "rsdetectsilence-stop" => Some(Self::Stop(timestamp)),
+            _ => None,
+        }
+    }
+}
+
+/// rsdetectsilenceを作ってプロパティを設定する
+pub fn make_detector(threshold: f64, hold: gst::ClockTime) -> anyhow::Result<gst::Element> {
+    let detector = gst::ElementFactory::make("rsdetectsilence", Some("detector"))
+        .context("rsdetectsilence is not available, add gst-plugin-tutorial to GST_PLUGIN_PATH")?;
+    detector.set_property("threshold", threshold);
+    detector.set_property("hold-time", hold.nseconds());
+    Ok(detector)
+}
+
+/// 音のある区間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: gst::ClockTime,
+    pub end: gst::ClockTime,
+}
+
+/// 境目を順に受け取り、音のある区間に組み立てる
+#[derive(Debug)]
+struct Splitter {
+    /// 今の区間の始まり。無音の間はNone
+    start: Option<gst::ClockTime>,
+    segments: Vec<Segment>,
+}
+
+impl Default for Splitter {
+    fn default() -> Self {
+        Self {
+            start: Some(gst::ClockTime::ZERO),
+            segments: Vec::new(),
+        }
+    }
+}
+
+impl Splitter {
+    fn push(&mut self, boundary: Boundary) {
+        match boundary {
+            Boundary::Start(at) => {
+                // 先頭から無音なら空の区間はできない
+                if let Some(start) = self.start.take().filter(|start| *start < at) {
+                    self.segments.push(Segment { start, end: at });
+                }
+            }
+            Boundary::Stop(at) => self.start = Some(at),
+        }
+    }
+
+    /// 最後の区間を入力の終わりで閉じる
+    fn finish(mut self, end: Option<gst::ClockTime>) -> Vec<Segment> {
+        if let (Some(start), Some(end)) = (self.start, end) {
+            if start < end {
+                self.segments.push(Segment { start, end });
+            }
+        }
+        self.segments
+    }
+}
+
+/// デコードした音声をrsdetectsilenceに通して捨てるブランチ
+fn link_audio(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    options: &SilenceOptions,
+) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let name = caps.structure(0).context("empty caps")?.name();
+    if !name.starts_with("audio/") {
+        return Ok(());
+    }
+    let convert = gst::ElementFactory::make("audioconvert", None)?;
+    let detector = make_detector(options.threshold, options.hold)?;
+    let sink = gst::ElementFactory::make("fakesink", None)?;
+    sink.set_property("sync", false);
+    let elements = [&convert, &detector, &sink];
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    for element in elements {
+        element.sync_state_with_parent()?;
+    }
+    pad.link(
+        &convert
+            .static_pad("sink")
+            .context("audioconvert sink pad")?,
+    )?;
+    Ok(())
+}
+
+/// 入力を最後まで流して無音の境目を集め、音のある区間を出す
+pub fn run(options: &SilenceOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = gst::Pipeline::new(None);
+    let decode = transcode::add_source(&pipeline, &options.input)?;
+    let pipeline_weak = pipeline.downgrade();
+    let options_clone = options.clone();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        // 音声が複数あっても最初の1本だけを調べる
+        if pipeline.by_name("detector").is_some() {
+            return;
+        }
+        if let Err(err) = link_audio(&pipeline, pad, &options_clone) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let mut splitter = Splitter::default();
+    let mut result = Ok(());
+    let bus = pipeline.bus().context("bus")?;
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        if let Some(boundary) = Boundary::from_message(&msg) {
+            log::info!("{boundary:?}");
+            splitter.push(boundary);
+            continue;
+        }
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!("analysis failed: {}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let end = pipeline.query_position::<gst::ClockTime>();
+    pipeline.set_state(gst::State::Null)?;
+    result?;
+
+    let segments = splitter.finish(end);
+    println!("{} segments:", segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        println!(
+            "  {i:3}: {} - {} ({})",
+            segment.start,
+            segment.end,
+            segment.end - segment.start
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,249 @@
+//! 動画をfactor倍に引き伸ばしたスローモーションのファイルに書き出す
+//!
+//! 再生速度の変更(rateを付けたシーク)は再生中の見え方を変えるだけで、ファイルのタイムスタンプは変わらない。
+//! ここではデコードした映像のsegmentのrateを1/factorにして、バッファのPTSをそのsegmentでのrunning-timeに
+//! 付け替える。running-timeは (PTS - start) / rate なので、PTSの間隔がfactor倍に広がる。
+//! 下流にはrate 1.0で、引き伸ばしたことをapplied-rateに記録したsegmentを送り直す。
+//!
+//! フレーム数は変わらないので、そのままではフレームレートが1/factorになる。
+//! - none: 元のフレームだけを使い、出力のフレームレートを 元のフレームレート / factor にする
+//! - duplicate: videorateでフレームを複製して--fps(省略すると元のフレームレート)にする
+//!
+//! 音声は引き伸ばすと音程が変わるので書き出さない。
+use std::{fmt, path::PathBuf, str::FromStr, sync::Mutex};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    container::{self, Container, MuxerOptions},
+    framerate::Rate,
+    runner::{self, BusLoopOpt},
+    transcode,
+    video_encoder::VideoEncodeOpt,
+};
+
+/// 引き伸ばしたときに足りないフレームの埋め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    None,
+    Duplicate,
+}
+
+impl FromStr for Interpolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "duplicate" => Ok(Self::Duplicate),
+            _ => anyhow::bail!("unknown interpolation {s}, expected none|duplicate"),
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Duplicate => "duplicate",
+        })
+    }
+}
+
+/// スローモーションの書き出しの設定
+#[derive(Debug, Clone)]
+pub struct SlowmoOptions {
+    /// ファイルパスかURI
+    pub input: String,
+    pub output: PathBuf,
+    pub container: Container,
+    /// 何倍に引き伸ばすか
+    pub factor: f64,
+    pub interpolation: Interpolation,
+    /// duplicateで複製して揃えるフレームレート。省略すると元のフレームレート
+    pub fps: Option<Rate>,
+}
+
+impl SlowmoOptions {
+    /// 出力のフレームレート
+    fn output_rate(&self, input: gst::Fraction) -> anyhow::Result<gst::Fraction> {
+        if let (Interpolation::Duplicate, Some(fps)) = (self.interpolation, self.fps) {
+            return Ok(fps.0);
+        }
+        anyhow::ensure!(
+            input.numer() > 0,
+            "the input has a variable frame rate, use --interpolation duplicate with --fps"
+        );
+        match self.interpolation {
+            Interpolation::None => {
+                let fps = input.numer() as f64 / input.denom() as f64 / self.factor;
+                gst::Fraction::approximate_f64(fps)
+                    .with_context(|| format!("can't write {fps} fps"))
+            }
+            Interpolation::Duplicate => Ok(input),
+        }
+    }
+}
+
+/// padを通るsegmentのrateを1/factorにしてバッファのPTSをrunning-timeに付け替え、
+/// 下流にはrate 1.0のsegmentを送る
+fn retime(pad: &gst::Pad, factor: f64) {
+    let segment = Mutex::new(gst::FormattedSegment::<gst::ClockTime>::new());
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+        move |_, info| {
+            match info.data {
+                Some(gst::PadProbeData::Event(ref mut event)) => {
+                    let received = match event.view() {
+                        gst::EventView::Segment(s) => {
+                            s.segment().downcast_ref::<gst::ClockTime>().cloned()
+                        }
+                        _ => None,
+                    };
+                    if let Some(received) = received {
+                        let mut slow = received.clone();
+                        slow.set_rate(received.rate() / factor);
+                        *segment.lock().unwrap() = slow;
+
+                        // 引き伸ばしはもう済んでいるので、下流はそのまま使えばよい
+                        let mut applied = gst::FormattedSegment::<gst::ClockTime>::new();
+                        applied.set_applied_rate(received.applied_rate() / factor);
+                        log::info!("retiming {received:?} to {applied:?}");
+                        *event = gst::event::Segment::builder(&applied)
+                            .seqnum(event.seqnum())
+                            .build();
+                    }
+                }
+                Some(gst::PadProbeData::Buffer(ref mut buffer)) => {
+                    let segment = segment.lock().unwrap();
+                    let buffer = buffer.make_mut();
+                    let pts = buffer.pts().and_then(|pts| segment.to_running_time(pts));
+                    let duration = buffer.duration().map(|duration| {
+                        gst::ClockTime::from_nseconds((duration.nseconds() as f64 * factor) as u64)
+                    });
+                    buffer.set_pts(pts);
+                    buffer.set_dts(gst::ClockTime::NONE);
+                    buffer.set_duration(duration);
+                }
+                _ => {}
+            }
+            gst::PadProbeReturn::Ok
+        },
+    );
+}
+
+/// デコードした映像を引き伸ばしてエンコードし、muxerに繋ぐ
+fn link_video(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    mux: &gst::Element,
+    options: &SlowmoOptions,
+    video: &VideoEncodeOpt,
+) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let s = caps.structure(0).context("empty caps")?;
+    if !s.name().starts_with("video/") {
+        log::info!("ignoring {} stream", s.name());
+        return Ok(());
+    }
+    let input_rate = s
+        .get::<gst::Fraction>("framerate")
+        .unwrap_or_else(|_| gst::Fraction::new(0, 1));
+    let output_rate = options.output_rate(input_rate)?;
+
+    let codec = video.codec(options.container.video_codecs())?;
+    let queue = gst::ElementFactory::make("queue", None)?;
+    let convert = gst::ElementFactory::make("videoconvert", None)?;
+    let rate = gst::ElementFactory::make("videorate", Some("rate"))?;
+    let filter = gst::ElementFactory::make("capsfilter", None)?;
+    filter.set_property(
+        "caps",
+        gst::Caps::builder("video/x-raw")
+            .field("framerate", output_rate)
+            .build(),
+    );
+    let encoder = video.make_bin(codec, false, None)?;
+
+    let branch = [
+        &queue,
+        &convert,
+        &rate,
+        &filter,
+        encoder.upcast_ref::<gst::Element>(),
+    ];
+    pipeline.add_many(&branch)?;
+    gst::Element::link_many(&branch)?;
+    encoder.link(mux)?;
+    for element in branch {
+        element.sync_state_with_parent()?;
+    }
+    let sink_pad = queue.static_pad("sink").context("queue sink pad")?;
+    retime(&sink_pad, options.factor);
+    pad.link(&sink_pad)?;
+
+    log::info!(
+        "slowing down {}x with {}: {input_rate} fps -> {output_rate} fps",
+        options.factor,
+        options.interpolation
+    );
+    Ok(())
+}
+
+/// 入力の映像をfactor倍に引き伸ばしてファイルに書く
+pub fn run(
+    options: &SlowmoOptions,
+    video: &VideoEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(options.factor > 0.0, "--factor must be positive");
+    let pipeline = gst::Pipeline::new(Some("slowmo"));
+    let decode = transcode::add_source(&pipeline, &options.input)?;
+    let mux = options.container.make_muxer(
+        "mux",
+        MuxerOptions {
+            faststart: true,
+            ..Default::default()
+        },
+    )?;
+    let sink = gst::ElementFactory::make("filesink", Some("sink"))?;
+    sink.set_property(
+        "location",
+        options.output.to_str().context("non UTF-8 path")?,
+    );
+    pipeline.add_many(&[&mux, &sink])?;
+    mux.link(&sink)?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let options_clone = options.clone();
+    let video = video.clone();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        // 映像が複数あっても最初の1本だけを書く
+        if pipeline.by_name("rate").is_some() {
+            return;
+        }
+        if let Err(err) = link_video(&pipeline, pad, &mux, &options_clone, &video) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+
+    runner::run(&pipeline, bus_loop)?;
+
+    let rate = pipeline.by_name("rate").context("no video stream")?;
+    log::info!(
+        "videorate: in={} out={} duplicated={} dropped={}",
+        rate.property::<u64>("in"),
+        rate.property::<u64>("out"),
+        rate.property::<u64>("duplicate"),
+        rate.property::<u64>("drop")
+    );
+    let duration = container::verify(&options.output)?;
+    println!("wrote {} ({duration})", options.output.display());
+    Ok(())
+}
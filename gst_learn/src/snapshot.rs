@@ -0,0 +1,499 @@
+//! playbinの再生状態をファイルに保存し、後から(別のマシンでも)同じ状態から再生を続ける
+//!
+//! 保存するのはuri、再生位置、再生レート、一時停止中かどうか、選んでいるストリームの番号、音量とミュート、
+//! flags、av-offset、video-filterとaudio-filterのgst-launch記述。TOMLなので手で直してもよい。
+//!
+//! ```toml
+//! uri = "https://example.com/video.webm"
+//! position = 12.5
+//! rate = 2.0
+//! paused = false
+//! volume = 0.5
+//! mute = false
+//! flags = "video+audio+text+soft-volume+deinterlace+soft-colorbalance"
+//! av-offset = 0
+//! current-video = 0
+//! current-audio = 1
+//! current-text = 0
+//! video-filter = "videoflip name=videoflip0 method=clockwise"
+//! ```
+//!
+//! 戻すときは次の順に設定する。
+//! 1. Nullのうちにuri、flags、フィルタ、音量、av-offsetを設定する。
+//!    フィルタはPausedに向かう途中でplaybinが中のパイプラインを組むときに使われるので、後からでは入らない
+//! 2. Pausedにしてプリロールを待つ。ストリームの数(n-audioなど)はここで決まる
+//! 3. current-video/audio/textを設定する。プリロールの前はまだないストリームの番号になってしまう
+//! 4. 保存した位置とレートで1回のACCURATEなフラッシュシークをして、もう一度プリロールを待つ。
+//!    レートと位置を別々のシークで変えると、その間に位置がずれる。逆再生は位置をstopに入れる
+//! 5. 一時停止中でなければPlayingにする
+//!
+//! 再生中は's'で保存、スペースで一時停止、'+'/'-'で速度、'r'で逆再生、←/→で10秒移動、
+//! ↑/↓で音量、'a'/'t'で音声と字幕を次のストリームに切り替える。
+use std::{fs, path::Path, path::PathBuf};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    keyboard::{Key, Keyboard},
+    launch,
+    runner::{self, BusLoopOpt},
+};
+
+/// ←/→で移動する時間
+const SEEK_STEP: gst::ClockTime = gst::ClockTime::from_seconds(10);
+/// ↑/↓で変える音量
+const VOLUME_STEP: f64 = 0.1;
+/// playbinのvolumeの上限
+const MAX_VOLUME: f64 = 10.0;
+
+/// playbinの再生状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub uri: String,
+    pub position: gst::ClockTime,
+    pub rate: f64,
+    pub paused: bool,
+    pub volume: f64,
+    pub mute: bool,
+    /// GstPlayFlagsのnickを+で繋いだもの。Noneならplaybinの既定のまま
+    pub flags: Option<String>,
+    pub av_offset: i64,
+    /// 選んでいるストリームの番号。Noneならplaybinに任せる
+    pub current_video: Option<i32>,
+    pub current_audio: Option<i32>,
+    pub current_text: Option<i32>,
+    /// フィルタのgst-launch記述
+    pub video_filter: Option<String>,
+    pub audio_filter: Option<String>,
+}
+
+/// ストリームの種類ごとの、選ぶプロパティと数のプロパティ
+const STREAM_PROPERTIES: [(&str, &str); 3] = [
+    ("current-video", "n-video"),
+    ("current-audio", "n-audio"),
+    ("current-text", "n-text"),
+];
+
+impl Snapshot {
+    /// 先頭から等速で再生する状態
+    pub fn new(uri: String) -> Self {
+        Self {
+            uri,
+            position: gst::ClockTime::ZERO,
+            rate: 1.0,
+            paused: false,
+            volume: 1.0,
+            mute: false,
+            flags: None,
+            av_offset: 0,
+            current_video: None,
+            current_audio: None,
+            current_text: None,
+            video_filter: None,
+            audio_filter: None,
+        }
+    }
+
+    fn current_streams(&self) -> [Option<i32>; 3] {
+        [self.current_video, self.current_audio, self.current_text]
+    }
+
+    /// 再生中のplaybinから状態を読む
+    pub fn capture(pipeline: &gst::Pipeline) -> anyhow::Result<Self> {
+        let uri = pipeline
+            .property::<Option<String>>("current-uri")
+            .or_else(|| pipeline.property("uri"))
+            .context("playbin has no uri")?;
+        let position = pipeline
+            .query_position::<gst::ClockTime>()
+            .context("unable to query the position")?;
+        let [current_video, current_audio, current_text] =
+            STREAM_PROPERTIES.map(|(current, _)| Some(pipeline.property::<i32>(current)));
+        let filter = |name| {
+            pipeline
+                .property::<Option<gst::Element>>(name)
+                .map(|filter| launch::describe_element(&filter))
+        };
+        Ok(Self {
+            uri,
+            position,
+            rate: rate(pipeline),
+            paused: pipeline.current_state() == gst::State::Paused,
+            volume: pipeline.property("volume"),
+            mute: pipeline.property("mute"),
+            flags: pipeline
+                .property_value("flags")
+                .serialize()
+                .ok()
+                .map(|flags| flags.to_string()),
+            av_offset: pipeline.property("av-offset"),
+            current_video,
+            current_audio,
+            current_text,
+            video_filter: filter("video-filter"),
+            audio_filter: filter("audio-filter"),
+        })
+    }
+
+    /// モジュールのコメントの順にplaybinへ設定し、保存したときと同じ状態にする
+    pub fn restore(
+        &self,
+        pipeline: &gst::Pipeline,
+        timeout: Option<gst::ClockTime>,
+    ) -> anyhow::Result<()> {
+        // 1. Nullのうちに設定するもの
+        pipeline.set_property("uri", &self.uri);
+        if let Some(flags) = &self.flags {
+            pipeline
+                .try_set_property_from_str("flags", flags)
+                .with_context(|| format!("invalid flags {flags:?}"))?;
+        }
+        for (name, description) in [
+            ("video-filter", &self.video_filter),
+            ("audio-filter", &self.audio_filter),
+        ] {
+            if let Some(description) = description {
+                let filter = fallback::parse_bin_from_description(description, true)
+                    .with_context(|| format!("invalid {name} {description:?}"))?;
+                pipeline.set_property(name, &filter);
+            }
+        }
+        pipeline.set_property("volume", self.volume.clamp(0.0, MAX_VOLUME));
+        pipeline.set_property("mute", self.mute);
+        pipeline.set_property("av-offset", self.av_offset);
+
+        // 2. プリロールしてストリームの数を決める
+        runner::set_state(pipeline, gst::State::Paused, timeout)?;
+
+        // 3. ストリームを選ぶ
+        for ((current, count), index) in STREAM_PROPERTIES.iter().zip(self.current_streams()) {
+            let index = match index {
+                Some(index) if index >= 0 => index,
+                _ => continue,
+            };
+            let available = pipeline.property::<i32>(count);
+            if index < available {
+                pipeline.set_property(current, index);
+            } else {
+                log::warn!("{current}={index} is not available, the media has {available}");
+            }
+        }
+
+        // 4. 位置とレートを1回のシークで戻し、プリロールし直すのを待つ
+        if self.position > gst::ClockTime::ZERO || self.rate != 1.0 {
+            if let Err(err) = seek(pipeline, self.position, self.rate) {
+                log::warn!("failed to restore the position, playing from the start: {err}");
+            } else if let (Ok(gst::StateChangeSuccess::Async), _, _) = pipeline.state(timeout) {
+                log::warn!("the seek did not finish prerolling in time");
+            }
+        }
+
+        // 5. 再生を続ける
+        if !self.paused {
+            runner::set_state(pipeline, gst::State::Playing, timeout)?;
+        }
+        log::info!(
+            "restored {} at {} rate {}",
+            self.uri,
+            self.position,
+            self.rate
+        );
+        Ok(())
+    }
+
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        let value = s.parse::<toml::Value>()?;
+        let table = value.as_table().context("snapshot must be a table")?;
+
+        let uri = table
+            .get("uri")
+            .and_then(|v| v.as_str())
+            .context("missing uri")?;
+        let mut snapshot = Self::new(uri.to_string());
+        if let Some(position) = number(table, "position")? {
+            anyhow::ensure!(
+                position.is_finite() && position >= 0.0,
+                "position must not be negative"
+            );
+            snapshot.position = gst::ClockTime::from_nseconds((position * 1e9).round() as u64);
+        }
+        if let Some(rate) = number(table, "rate")? {
+            anyhow::ensure!(rate.is_finite() && rate != 0.0, "rate must not be 0");
+            snapshot.rate = rate;
+        }
+        if let Some(volume) = number(table, "volume")? {
+            snapshot.volume = volume;
+        }
+        snapshot.paused = boolean(table, "paused")?.unwrap_or(false);
+        snapshot.mute = boolean(table, "mute")?.unwrap_or(false);
+        snapshot.flags = string(table, "flags")?;
+        snapshot.av_offset = integer(table, "av-offset")?.unwrap_or(0);
+        let [video, audio, text] = STREAM_PROPERTIES.map(|(current, _)| integer(table, current));
+        snapshot.current_video = video?.map(|i| i as i32);
+        snapshot.current_audio = audio?.map(|i| i as i32);
+        snapshot.current_text = text?.map(|i| i as i32);
+        snapshot.video_filter = string(table, "video-filter")?;
+        snapshot.audio_filter = string(table, "audio-filter")?;
+        Ok(snapshot)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = toml::value::Table::new();
+        let mut insert = |key: &str, value: toml::Value| {
+            table.insert(key.to_string(), value);
+        };
+        insert("uri", self.uri.clone().into());
+        insert("position", (self.position.nseconds() as f64 / 1e9).into());
+        insert("rate", self.rate.into());
+        insert("paused", self.paused.into());
+        insert("volume", self.volume.into());
+        insert("mute", self.mute.into());
+        insert("av-offset", self.av_offset.into());
+        if let Some(flags) = &self.flags {
+            insert("flags", flags.clone().into());
+        }
+        for ((current, _), index) in STREAM_PROPERTIES.iter().zip(self.current_streams()) {
+            if let Some(index) = index {
+                insert(current, i64::from(index).into());
+            }
+        }
+        if let Some(filter) = &self.video_filter {
+            insert("video-filter", filter.clone().into());
+        }
+        if let Some(filter) = &self.audio_filter {
+            insert("audio-filter", filter.clone().into());
+        }
+        toml::Value::Table(table).to_string()
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let s = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_toml()).with_context(|| format!("write {}", path.display()))
+    }
+}
+
+/// 整数でも小数でも書ける数を読む
+fn number(table: &toml::value::Table, key: &str) -> anyhow::Result<Option<f64>> {
+    match table.get(key) {
+        Some(toml::Value::Float(v)) => Ok(Some(*v)),
+        Some(toml::Value::Integer(v)) => Ok(Some(*v as f64)),
+        Some(_) => anyhow::bail!("{key} must be a number"),
+        None => Ok(None),
+    }
+}
+
+fn integer(table: &toml::value::Table, key: &str) -> anyhow::Result<Option<i64>> {
+    match table.get(key) {
+        Some(toml::Value::Integer(v)) => Ok(Some(*v)),
+        Some(_) => anyhow::bail!("{key} must be an integer"),
+        None => Ok(None),
+    }
+}
+
+fn boolean(table: &toml::value::Table, key: &str) -> anyhow::Result<Option<bool>> {
+    match table.get(key) {
+        Some(toml::Value::Boolean(v)) => Ok(Some(*v)),
+        Some(_) => anyhow::bail!("{key} must be true or false"),
+        None => Ok(None),
+    }
+}
+
+fn string(table: &toml::value::Table, key: &str) -> anyhow::Result<Option<String>> {
+    match table.get(key) {
+        Some(toml::Value::String(v)) => Ok(Some(v.clone())),
+        Some(_) => anyhow::bail!("{key} must be a string"),
+        None => Ok(None),
+    }
+}
+
+/// 今の再生レート。問い合わせられなければ等速とみなす
+fn rate(pipeline: &gst::Pipeline) -> f64 {
+    let mut segment = gst::query::Segment::new(gst::Format::Time);
+    if pipeline.query(&mut segment) {
+        segment.result().0
+    } else {
+        1.0
+    }
+}
+
+/// positionからrateで再生するようにシークする。逆再生はpositionから先頭に向かう
+fn seek(pipeline: &gst::Pipeline, position: gst::ClockTime, rate: f64) -> anyhow::Result<()> {
+    let flags = gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE;
+    if rate > 0.0 {
+        pipeline.seek(
+            rate,
+            flags,
+            gst::SeekType::Set,
+            position,
+            gst::SeekType::None,
+            gst::ClockTime::ZERO,
+        )?;
+    } else {
+        pipeline.seek(
+            rate,
+            flags,
+            gst::SeekType::Set,
+            gst::ClockTime::ZERO,
+            gst::SeekType::Set,
+            position,
+        )?;
+    }
+    Ok(())
+}
+
+/// 今の位置からレートだけを変える
+fn change_rate(pipeline: &gst::Pipeline, rate: f64) {
+    let position = match pipeline.query_position::<gst::ClockTime>() {
+        Some(position) => position,
+        None => return,
+    };
+    match seek(pipeline, position, rate) {
+        Ok(()) => println!("rate {rate}\r"),
+        Err(err) => log::warn!("failed to change the rate to {rate}: {err}\r"),
+    }
+}
+
+/// 次のストリームを選ぶ
+fn next_stream(pipeline: &gst::Pipeline, current: &str, count: &str) {
+    let available = pipeline.property::<i32>(count);
+    if available == 0 {
+        return;
+    }
+    let next = (pipeline.property::<i32>(current) + 1).rem_euclid(available);
+    pipeline.set_property(current, next);
+    println!("{current} {next}/{available}\r");
+}
+
+/// Snapshotの設定
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    /// 再生するURI。restoreと一緒に指定すると保存したuriの代わりに使う
+    pub uri: Option<String>,
+    /// 戻す状態のファイル
+    pub restore: Option<PathBuf>,
+    /// 's'で状態を書くファイル
+    pub save: PathBuf,
+    pub video_filter: Option<String>,
+    pub audio_filter: Option<String>,
+}
+
+/// 保存した状態かuriから再生を始め、'q'かEOSかエラーまでキーで操作する
+pub fn run(options: &SnapshotOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let mut snapshot = match (&options.restore, &options.uri) {
+        (Some(path), _) => Snapshot::load(path)?,
+        (None, Some(uri)) => Snapshot::new(uri.clone()),
+        (None, None) => anyhow::bail!("specify a uri or --restore"),
+    };
+    // 別のマシンで戻すときは、メディアの場所やフィルタを差し替えられる
+    if let Some(uri) = &options.uri {
+        snapshot.uri = uri.clone();
+    }
+    if options.video_filter.is_some() {
+        snapshot.video_filter = options.video_filter.clone();
+    }
+    if options.audio_filter.is_some() {
+        snapshot.audio_filter = options.audio_filter.clone();
+    }
+
+    let pipeline = gst::ElementFactory::make("playbin", None)?
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+    let timeout = bus_loop.state_timeout();
+    snapshot.restore(&pipeline, timeout)?;
+
+    println!(
+        "\
+USAGE:
+ 'S' to save a snapshot to {}
+ SPACE to toggle between PAUSE and PLAY
+ '+' / '-' to change the playback speed, 'R' to reverse
+ LEFT / RIGHT to seek, UP / DOWN to change the volume
+ 'A' / 'T' to switch the audio / subtitle stream
+ 'Q' to quit\r",
+        options.save.display()
+    );
+    let keyboard = Keyboard::new()?;
+    let bus = pipeline.bus().context("bus")?;
+    let mut result = Ok(());
+    loop {
+        match keyboard.try_key() {
+            Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) => break,
+            Some(Key::Char('s' | 'S')) => {
+                match Snapshot::capture(&pipeline).and_then(|s| s.save(&options.save)) {
+                    Ok(()) => log::info!("saved {}\r", options.save.display()),
+                    Err(err) => log::warn!("failed to save a snapshot: {err:#}\r"),
+                }
+            }
+            Some(Key::Char(' ')) => {
+                let state = if pipeline.current_state() == gst::State::Playing {
+                    gst::State::Paused
+                } else {
+                    gst::State::Playing
+                };
+                if let Err(err) = pipeline.set_state(state) {
+                    log::warn!("failed to set {state:?}: {err}\r");
+                }
+            }
+            Some(Key::Char('+')) => change_rate(&pipeline, rate(&pipeline) * 2.0),
+            Some(Key::Char('-')) => change_rate(&pipeline, rate(&pipeline) / 2.0),
+            Some(Key::Char('r' | 'R')) => change_rate(&pipeline, -rate(&pipeline)),
+            Some(key @ (Key::Left | Key::Right)) => {
+                if let Some(position) = pipeline.query_position::<gst::ClockTime>() {
+                    let position = if key == Key::Left {
+                        position.saturating_sub(SEEK_STEP)
+                    } else {
+                        position + SEEK_STEP
+                    };
+                    if let Err(err) = seek(&pipeline, position, rate(&pipeline)) {
+                        log::warn!("failed to seek to {position}: {err}\r");
+                    }
+                }
+            }
+            Some(key @ (Key::Up | Key::Down)) => {
+                let step = if key == Key::Up {
+                    VOLUME_STEP
+                } else {
+                    -VOLUME_STEP
+                };
+                let volume = (pipeline.property::<f64>("volume") + step).clamp(0.0, MAX_VOLUME);
+                pipeline.set_property("volume", volume);
+                println!("volume {volume:.1}\r");
+            }
+            Some(Key::Char('a' | 'A')) => next_stream(&pipeline, "current-audio", "n-audio"),
+            Some(Key::Char('t' | 'T')) => next_stream(&pipeline, "current-text", "n-text"),
+            _ => {}
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})\r",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!("playback failed: {}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    runner::rollback(&pipeline);
+    result
+}
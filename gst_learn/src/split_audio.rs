@@ -0,0 +1,289 @@
+//! 入力の音声をエンコードしてファイルに書き、一定より長い無音の後で次のファイルに切り替える
+//!
+//! 無音はgst-plugin-tutorialのrsdetectsilenceで見つける(silenceサブコマンドを参照)。
+//! 音が戻ったとき(rsdetectsilence-stop)にファイルを切り替えるので、各ファイルは音で始まり、後ろに無音が付く。
+//!
+//! エンコーダからfilesinkまでをファイルごとのbin(ブランチ)にして、切り替えるときは次のようにする。
+//! 1. バスのsync handlerでrsdetectsilence-stopを受け取る。これはrsdetectsilenceのストリーミングスレッドで、
+//!    音が戻ったバッファを下流に渡す前に呼ばれるので、rsdetectsilenceのsrc padをブロックするprobeを付けておく。
+//! 2. そのバッファがブロックされたら、古いブランチを切り離してEOSを送る。EOSでmuxerがファイルを閉じる。
+//! 3. 新しいブランチを作って繋ぎ、ブロックを外す。音が戻ったバッファから新しいファイルに入る。
+//! 4. 古いブランチのfilesinkにEOSが届いたら、ストリーミングスレッドの外でNullにして外す。
+//!
+//! 古いブランチを外してもパイプラインのEOSは残りのブランチ(最後のファイル)のEOSで届く。
+//! GST_PLUGIN_PATHにgst-plugin-tutorialのビルド結果を含めておく。
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    audio_encoder::{AudioCodec, AudioEncodeOpt},
+    container::{self, Container, MuxerOptions},
+    fallback,
+    silence::{self, Boundary},
+    transcode,
+};
+
+/// 無音での分割の設定
+#[derive(Debug)]
+pub struct SplitAudioOptions {
+    /// ファイルパスかURI
+    pub input: String,
+    /// 分割したファイルを書くディレクトリ
+    pub dir: PathBuf,
+    pub container: Container,
+    /// 無音とみなす音量(dBFS)
+    pub threshold: f64,
+    /// この長さより長い無音で分割する
+    pub min_silence: gst::ClockTime,
+}
+
+/// 書いたファイル
+#[derive(Debug, Clone)]
+struct Part {
+    path: PathBuf,
+    /// 入力の中での始まりの位置
+    start: gst::ClockTime,
+}
+
+/// 今書いているブランチと、これまでに書いたファイル
+struct Output {
+    dir: PathBuf,
+    container: Container,
+    codec: AudioCodec,
+    audio: AudioEncodeOpt,
+    branch: Option<gst::Bin>,
+    parts: Vec<Part>,
+}
+
+impl Output {
+    /// 次のファイルに書くブランチを作る。ファイル名は連番にする
+    fn make_branch(&mut self, start: gst::ClockTime) -> anyhow::Result<gst::Bin> {
+        let index = self.parts.len();
+        let path = self
+            .dir
+            .join(format!("part-{index:03}.{}", self.container.extension()));
+
+        let bin = gst::Bin::new(Some(&format!("part-{index}")));
+        let encode = fallback::parse_bin_from_description(&self.audio.launch(self.codec), true)?;
+        let mux = self.container.make_muxer("mux", MuxerOptions::default())?;
+        let sink = gst::ElementFactory::make("filesink", Some("sink"))?;
+        sink.set_property("location", path.to_str().context("non UTF-8 path")?);
+        bin.add_many(&[encode.upcast_ref::<gst::Element>(), &mux, &sink])?;
+        encode.link(&mux)?;
+        mux.link(&sink)?;
+        let sink_pad = encode.static_pad("sink").context("encoder sink pad")?;
+        bin.add_pad(&gst::GhostPad::with_target(Some("sink"), &sink_pad)?)?;
+
+        log::info!("part {index} from {start}: {}", path.display());
+        self.parts.push(Part { path, start });
+        Ok(bin)
+    }
+
+    /// 新しいブランチを作ってsrc_padに繋ぐ
+    fn attach(
+        &mut self,
+        pipeline: &gst::Pipeline,
+        src_pad: &gst::Pad,
+        start: gst::ClockTime,
+    ) -> anyhow::Result<()> {
+        let branch = self.make_branch(start)?;
+        pipeline.add(&branch)?;
+        branch.sync_state_with_parent()?;
+        src_pad.link(&branch.static_pad("sink").context("branch sink pad")?)?;
+        self.branch = Some(branch);
+        Ok(())
+    }
+
+    /// 今のブランチを切り離してEOSで閉じ、次のファイルのブランチに繋ぎ替える
+    /// src_padをブロックしているprobeの中から呼ぶ
+    fn roll_over(
+        &mut self,
+        pipeline: &gst::Pipeline,
+        src_pad: &gst::Pad,
+        start: gst::ClockTime,
+    ) -> anyhow::Result<()> {
+        if let Some(old) = self.branch.take() {
+            let sink_pad = old.static_pad("sink").context("branch sink pad")?;
+            src_pad.unlink(&sink_pad)?;
+            remove_on_eos(pipeline, &old)?;
+            // muxerによっては自分のスレッドで書くので、ファイルが閉じるのはfilesinkにEOSが届いたとき
+            sink_pad.send_event(gst::event::Eos::new());
+        }
+        self.attach(pipeline, src_pad, start)
+    }
+}
+
+/// ブランチのfilesinkにEOSが届いたら、ブランチをパイプラインから外す
+fn remove_on_eos(pipeline: &gst::Pipeline, branch: &gst::Bin) -> anyhow::Result<()> {
+    let sink = branch.by_name("sink").context("branch filesink")?;
+    let sink_pad = sink.static_pad("sink").context("filesink sink pad")?;
+    let pipeline_weak = pipeline.downgrade();
+    let branch_weak = branch.downgrade();
+    sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        match &info.data {
+            Some(gst::PadProbeData::Event(event)) if event.type_() == gst::EventType::Eos => {}
+            _ => return gst::PadProbeReturn::Ok,
+        }
+        if let (Some(pipeline), Some(branch)) = (pipeline_weak.upgrade(), branch_weak.upgrade()) {
+            log::info!("closed {}", branch.name());
+            // filesinkのストリーミングスレッドから自分の状態は変えられないので、別スレッドで片付ける
+            branch.call_async(move |branch| {
+                let _ = branch.set_state(gst::State::Null);
+                let _ = pipeline.remove(branch);
+            });
+        }
+        gst::PadProbeReturn::Remove
+    });
+    Ok(())
+}
+
+/// rsdetectsilenceのsrc padをブロックし、次のバッファから新しいファイルに書くようにする
+fn schedule_roll_over(pipeline: &gst::Pipeline, output: Arc<Mutex<Output>>, start: gst::ClockTime) {
+    let src_pad = match pipeline
+        .by_name("detector")
+        .and_then(|detector| detector.static_pad("src"))
+    {
+        Some(pad) => pad,
+        None => return,
+    };
+    let pipeline_weak = pipeline.downgrade();
+    src_pad.add_probe(gst::PadProbeType::BLOCK_DOWNSTREAM, move |pad, _| {
+        if let Some(pipeline) = pipeline_weak.upgrade() {
+            if let Err(err) = output.lock().unwrap().roll_over(&pipeline, pad, start) {
+                log::error!("failed to start a new part: {err:#}");
+            }
+        }
+        gst::PadProbeReturn::Remove
+    });
+}
+
+/// デコードした音声をrsdetectsilenceに通し、最初のファイルのブランチに繋ぐ
+fn link_audio(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    options: &SplitAudioOptions,
+    output: &Mutex<Output>,
+) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let name = caps.structure(0).context("empty caps")?.name();
+    if !name.starts_with("audio/") {
+        return Ok(());
+    }
+    let convert = gst::ElementFactory::make("audioconvert", None)?;
+    let detector = silence::make_detector(options.threshold, options.min_silence)?;
+    pipeline.add_many(&[&convert, &detector])?;
+    convert.link(&detector)?;
+    convert.sync_state_with_parent()?;
+    detector.sync_state_with_parent()?;
+
+    let src_pad = detector.static_pad("src").context("detector src pad")?;
+    output
+        .lock()
+        .unwrap()
+        .attach(pipeline, &src_pad, gst::ClockTime::ZERO)?;
+    pad.link(
+        &convert
+            .static_pad("sink")
+            .context("audioconvert sink pad")?,
+    )?;
+    Ok(())
+}
+
+/// 入力を最後まで流し、無音で区切りながらファイルに書く
+pub fn run(options: SplitAudioOptions, audio: &AudioEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    fs::create_dir_all(&options.dir)
+        .with_context(|| format!("failed to create {}", options.dir.display()))?;
+    let codec = audio.codec(options.container.audio_codecs())?;
+    let output = Arc::new(Mutex::new(Output {
+        dir: options.dir.clone(),
+        container: options.container,
+        codec,
+        audio: audio.clone(),
+        branch: None,
+        parts: Vec::new(),
+    }));
+
+    let pipeline = gst::Pipeline::new(Some("split-audio"));
+    let decode = transcode::add_source(&pipeline, &options.input)?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let output_clone = output.clone();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        // 音声が複数あっても最初の1本だけを書く
+        if pipeline.by_name("detector").is_some() {
+            return;
+        }
+        if let Err(err) = link_audio(&pipeline, pad, &options, &output_clone) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+
+    // rsdetectsilenceのストリーミングスレッドで、境目のバッファを下流に渡す前に受け取る
+    let bus = pipeline.bus().context("bus")?;
+    let pipeline_weak = pipeline.downgrade();
+    let output_clone = output.clone();
+    bus.set_sync_handler(move |_, msg| {
+        if let Some(Boundary::Stop(at)) = Boundary::from_message(msg) {
+            if let Some(pipeline) = pipeline_weak.upgrade() {
+                schedule_roll_over(&pipeline, output_clone.clone(), at);
+            }
+        }
+        gst::BusSyncReply::Pass
+    });
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let mut result = Ok(());
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        if let Some(boundary) = Boundary::from_message(&msg) {
+            log::info!("{boundary:?}");
+            continue;
+        }
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!("splitting failed: {}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+    bus.unset_sync_handler();
+    result?;
+
+    let parts = output.lock().unwrap().parts.clone();
+    println!("{} parts:", parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let duration = container::verify(&part.path)?;
+        println!(
+            "  {i:3}: {} from {} ({duration})",
+            part.path.display(),
+            part.start
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,429 @@
+//! 記録の出力先をURIのスキームで選べるようにする
+//!
+//! 記録するサブコマンドはStorageBackendからsinkを受け取ってmuxerの後ろに繋ぎ、EOSで閉じたらfinishを呼ぶ。
+//! - パス、file:// : LocalFile。recordと同じく.partを付けたファイルにfilesinkで書き、
+//!   finishで読み直して確認できたら名前を変える。親ディレクトリがなければ作る
+//! - s3://bucket/key : S3Object。appsinkで受け取ったデータをPART_SIZEごとにマルチパートアップロードの
+//!   1パートとして送り、finishでアップロードを完了する
+//!
+//! S3はawsコマンド(AWS CLI)のs3apiで操作するので、認証やリージョンはAWS CLIの設定や環境変数に従う。
+//! MinIOなどに送るときはAWS_ENDPOINT_URLを設定する。
+//! アップロードは別スレッドで行い、送る前のバッファはQUEUED_BUFFERSまで溜める。
+//! それより遅れるとストリーミングスレッドはアップロードが追いつくまで待つので、メモリを使い続けることはない。
+//! awsコマンドがなければ、記録を始める前にopenがエラーにする。
+//!
+//! S3には書いた位置に戻れないので、seekableがfalseの出力ではmuxerをstreamableにする
+//! (MP4はfragmented MP4になる)。書いたバイト数も問い合わせられないので、--max-sizeは効かない。
+//! finishを呼ばずに捨てたときは、LocalFileは.partが残っていることをログに出し、
+//! S3Objectはアップロードを中止して送ったパートを消す。
+//!
+//! SegRecordはsplitmuxsinkが自分でファイル名を付けてfilesinkを作り直すので、ローカルのディレクトリにだけ書ける。
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+
+use crate::container;
+
+/// S3のマルチパートアップロードの1パートの大きさ。最後以外は5MiB以上にする必要がある
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// アップロードのスレッドに渡す前に溜めておくバッファの数
+const QUEUED_BUFFERS: usize = 64;
+
+static PART_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// 記録の出力先
+pub trait StorageBackend: fmt::Display + Send {
+    /// muxerの後ろに繋ぐsinkを作る
+    fn make_sink(&mut self, name: Option<&str>) -> anyhow::Result<gst::Element>;
+    /// 書いた位置に戻って書き直せるか。できなければmuxerをstreamableにする
+    fn seekable(&self) -> bool;
+    /// EOSでsinkが閉じた後に呼び、出力を確定させる
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+/// パスかURIから出力先を選ぶ
+pub fn open(output: &str) -> anyhow::Result<Box<dyn StorageBackend>> {
+    if let Some(location) = output.strip_prefix("s3://") {
+        let (bucket, key) = location
+            .split_once('/')
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .with_context(|| format!("expected s3://bucket/key, got {output}"))?;
+        check_aws()?;
+        return Ok(Box::new(S3Object::new(bucket, key)));
+    }
+    if output.starts_with("file://") {
+        let (path, _) = glib::filename_from_uri(output)?;
+        return Ok(Box::new(LocalFile::new(path)));
+    }
+    anyhow::ensure!(
+        !output.contains("://"),
+        "unsupported output {output}, use a path, file:// or s3://"
+    );
+    Ok(Box::new(LocalFile::new(PathBuf::from(output))))
+}
+
+/// ディレクトリやS3のプレフィックスの下の名前
+pub fn join(base: &str, name: &str) -> String {
+    format!("{}/{name}", base.trim_end_matches('/'))
+}
+
+/// 記録中に書く一時ファイル。出力名に.partを付ける
+fn part_path(output: &Path) -> PathBuf {
+    let mut part = output.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// ローカルのファイル
+#[derive(Debug)]
+pub struct LocalFile {
+    path: PathBuf,
+    part: PathBuf,
+    finished: bool,
+}
+
+impl LocalFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            part: part_path(&path),
+            path,
+            finished: false,
+        }
+    }
+}
+
+impl fmt::Display for LocalFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+impl StorageBackend for LocalFile {
+    fn make_sink(&mut self, name: Option<&str>) -> anyhow::Result<gst::Element> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let sink = gst::ElementFactory::make("filesink", name)?;
+        sink.set_property("location", self.part.to_str().context("non UTF-8 path")?);
+        Ok(sink)
+    }
+
+    fn seekable(&self) -> bool {
+        true
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let duration = container::verify(&self.part)
+            .with_context(|| format!("partial recording left in {}", self.part.display()))?;
+        fs::rename(&self.part, &self.path).with_context(|| {
+            format!(
+                "failed to rename {} to {}",
+                self.part.display(),
+                self.path.display()
+            )
+        })?;
+        self.finished = true;
+        log::info!("saved {} ({duration})", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if !self.finished && self.part.exists() {
+            log::warn!("partial recording left in {}", self.part.display());
+        }
+    }
+}
+
+/// awsコマンドが使えるかを確かめる
+fn check_aws() -> anyhow::Result<()> {
+    let output = Command::new("aws")
+        .arg("--version")
+        .output()
+        .context("s3:// outputs need the aws command (AWS CLI) in PATH")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "aws --version exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// awsコマンドでバケットの1つのキーを操作する
+#[derive(Debug, Clone)]
+struct S3Client {
+    bucket: String,
+    key: String,
+}
+
+impl S3Client {
+    /// aws s3apiを実行して、標準出力を返す
+    fn s3api(&self, operation: &str, args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("aws")
+            .args([
+                "s3api",
+                operation,
+                "--bucket",
+                &self.bucket,
+                "--key",
+                &self.key,
+            ])
+            .args(args)
+            .output()
+            .context("failed to run aws")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "aws s3api {operation} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn create_upload(&self) -> anyhow::Result<String> {
+        self.s3api(
+            "create-multipart-upload",
+            &["--query", "UploadId", "--output", "text"],
+        )
+    }
+
+    /// 1パートを送ってETagを返す。--bodyはファイルしか受け付けないので一時ファイルに書く
+    fn upload_part(&self, upload_id: &str, number: u32, data: &[u8]) -> anyhow::Result<String> {
+        let path = std::env::temp_dir().join(format!(
+            "gst-learn-s3-{}-{}.part",
+            std::process::id(),
+            PART_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, data).with_context(|| format!("failed to write {}", path.display()))?;
+        let result = self.s3api(
+            "upload-part",
+            &[
+                "--upload-id",
+                upload_id,
+                "--part-number",
+                &number.to_string(),
+                "--body",
+                path.to_str().context("non UTF-8 path")?,
+                "--query",
+                "ETag",
+                "--output",
+                "text",
+            ],
+        );
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    /// ETagは引用符を含むので、JSONの中ではエスケープする
+    fn complete_upload(&self, upload_id: &str, parts: &[(u32, String)]) -> anyhow::Result<()> {
+        let parts = parts
+            .iter()
+            .map(|(number, etag)| {
+                format!(
+                    "{{\"ETag\":\"{}\",\"PartNumber\":{number}}}",
+                    etag.replace('"', "\\\"")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        self.s3api(
+            "complete-multipart-upload",
+            &[
+                "--upload-id",
+                upload_id,
+                "--multipart-upload",
+                &format!("{{\"Parts\":[{parts}]}}"),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn abort_upload(&self, upload_id: &str) -> anyhow::Result<()> {
+        self.s3api("abort-multipart-upload", &["--upload-id", upload_id])?;
+        Ok(())
+    }
+}
+
+/// 受け取ったデータをPART_SIZEごとに送る。送信側が閉じたら残りを最後のパートにして、送ったパートを返す
+fn upload_parts(
+    client: S3Client,
+    upload_id: String,
+    receiver: mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<Vec<(u32, String)>> {
+    let mut parts = Vec::new();
+    let mut pending = Vec::with_capacity(PART_SIZE);
+    loop {
+        let data = receiver.recv().ok();
+        if let Some(data) = &data {
+            pending.extend_from_slice(data);
+            if pending.len() < PART_SIZE {
+                continue;
+            }
+        }
+        // パートが1つもなければ空でも送る
+        if !pending.is_empty() || (data.is_none() && parts.is_empty()) {
+            let number = parts.len() as u32 + 1;
+            let etag = client.upload_part(&upload_id, number, &pending)?;
+            log::debug!(
+                "uploaded part {number} ({} bytes) of {}",
+                pending.len(),
+                client.key
+            );
+            parts.push((number, etag));
+            pending.clear();
+        }
+        if data.is_none() {
+            return Ok(parts);
+        }
+    }
+}
+
+/// アップロードのスレッドにデータを渡す口。EOSかcloseで閉じる
+type Sender = Arc<Mutex<Option<mpsc::SyncSender<Vec<u8>>>>>;
+
+/// 進行中のマルチパートアップロード
+struct Upload {
+    id: String,
+    sender: Sender,
+    worker: Option<thread::JoinHandle<anyhow::Result<Vec<(u32, String)>>>>,
+}
+
+impl Upload {
+    /// 送信側を閉じて、残りのパートを送り終えるのを待つ
+    fn close(&mut self) -> anyhow::Result<Vec<(u32, String)>> {
+        self.sender.lock().unwrap().take();
+        self.worker
+            .take()
+            .context("the upload is already closed")?
+            .join()
+            .map_err(|_| anyhow::anyhow!("the upload thread panicked"))?
+    }
+}
+
+/// S3のオブジェクト
+pub struct S3Object {
+    client: S3Client,
+    upload: Option<Upload>,
+}
+
+impl S3Object {
+    pub fn new(bucket: &str, key: &str) -> Self {
+        Self {
+            client: S3Client {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            },
+            upload: None,
+        }
+    }
+}
+
+impl fmt::Display for S3Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.client.bucket, self.client.key)
+    }
+}
+
+impl StorageBackend for S3Object {
+    fn make_sink(&mut self, name: Option<&str>) -> anyhow::Result<gst::Element> {
+        anyhow::ensure!(self.upload.is_none(), "{self} is already being written");
+        let id = self
+            .client
+            .create_upload()
+            .with_context(|| format!("failed to start uploading {self}"))?;
+        log::info!("uploading {self} ({id})");
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUED_BUFFERS);
+        // appsinkのコールバックはエレメントが捨てられるまで残るので、EOSで送信側を閉じる
+        let sender: Sender = Arc::new(Mutex::new(Some(sender)));
+        let client = self.client.clone();
+        let upload_id = id.clone();
+        let worker = thread::spawn(move || upload_parts(client, upload_id, receiver));
+
+        let appsink = gst::ElementFactory::make("appsink", name)?
+            .dynamic_cast::<AppSink>()
+            .unwrap();
+        appsink.set_sync(false);
+        let sample_sender = sender.clone();
+        let eos_sender = sender.clone();
+        appsink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    // 溜まっているときは待つので、ロックを持ったままにしない
+                    let sender = sample_sender.lock().unwrap().clone();
+                    // 送れないのはアップロードのスレッドがエラーで終わったとき
+                    match sender {
+                        Some(sender) => sender
+                            .send(map.as_slice().to_vec())
+                            .map_err(|_| gst::FlowError::Error)?,
+                        None => return Err(gst::FlowError::Eos),
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .eos(move |_| {
+                    eos_sender.lock().unwrap().take();
+                })
+                .build(),
+        );
+        self.upload = Some(Upload {
+            id,
+            sender,
+            worker: Some(worker),
+        });
+        Ok(appsink.upcast())
+    }
+
+    fn seekable(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let mut upload = self.upload.take().context("nothing was written")?;
+        let parts = match upload.close() {
+            Ok(parts) => parts,
+            Err(err) => {
+                // Dropで中止させる
+                self.upload = Some(upload);
+                return Err(err).with_context(|| format!("failed to upload {self}"));
+            }
+        };
+        if let Err(err) = self.client.complete_upload(&upload.id, &parts) {
+            self.upload = Some(upload);
+            return Err(err).with_context(|| format!("failed to complete {self}"));
+        }
+        log::info!("uploaded {self} in {} parts", parts.len());
+        Ok(())
+    }
+}
+
+impl Drop for S3Object {
+    fn drop(&mut self) {
+        let mut upload = match self.upload.take() {
+            Some(upload) => upload,
+            None => return,
+        };
+        let _ = upload.close();
+        match self.client.abort_upload(&upload.id) {
+            Ok(()) => log::warn!("aborted uploading {self}"),
+            Err(err) => log::warn!("failed to abort uploading {self} ({}): {err:#}", upload.id),
+        }
+    }
+}
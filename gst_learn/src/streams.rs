@@ -0,0 +1,228 @@
+//! playbin3でGstStream/GstStreamCollectionを使ってストリームを選ぶ
+//!
+//! playbin(playbin2)はn-audioやcurrent-audioのように種類ごとの番号でストリームを選ぶが、
+//! playbin3はdemuxerが見つけたストリームの一覧をStreamCollectionメッセージで知らせ、
+//! アプリケーションはstream-idのリストをselect-streamsイベントで送って選ぶ。
+//! 選んだ結果はStreamsSelectedメッセージで返ってくる。
+//! stream-idはストリームを一意に表すので、番号と違って一覧が更新されても同じストリームを指せる。
+//!
+//! 'v'で映像、'a'で音声、't'で字幕を次のストリームに切り替える。字幕は最後の次で表示しなくなる。
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::keyboard::{Key, Keyboard};
+
+/// playbin3の設定
+#[derive(Debug)]
+pub struct StreamsOptions {
+    pub uri: String,
+    /// 最初に選ぶ音声の番号
+    pub audio: Option<usize>,
+    /// 最初に選ぶ字幕の番号。省略すると字幕を表示しない
+    pub text: Option<usize>,
+}
+
+/// ある種類のストリームと、その中で選んでいる番号
+#[derive(Debug, Default)]
+struct Track {
+    ids: Vec<String>,
+    current: Option<usize>,
+}
+
+impl Track {
+    /// 次のストリームに切り替える。optionalなら最後の次は何も選ばない
+    fn next(&mut self, optional: bool) {
+        self.current = match self.current {
+            Some(i) if i + 1 < self.ids.len() => Some(i + 1),
+            Some(_) if optional => None,
+            _ if self.ids.is_empty() => None,
+            _ => Some(0),
+        };
+    }
+
+    /// 指定された番号を選ぶ。なければ先頭にする
+    fn select(&mut self, index: usize) {
+        if index < self.ids.len() {
+            self.current = Some(index);
+        } else if self.ids.is_empty() {
+            self.current = None;
+        } else {
+            log::warn!("no stream #{index}, using the first one\r");
+            self.current = Some(0);
+        }
+    }
+
+    fn selected(&self) -> Option<&str> {
+        self.current
+            .and_then(|i| self.ids.get(i))
+            .map(String::as_str)
+    }
+}
+
+/// 種類ごとに選んでいるストリーム
+#[derive(Debug, Default)]
+struct Selection {
+    video: Track,
+    audio: Track,
+    text: Track,
+}
+
+impl Selection {
+    /// 一覧を種類ごとに分け、指定された番号か先頭を選ぶ
+    fn new(collection: &gst::StreamCollection, options: &StreamsOptions) -> Self {
+        let mut selection = Self::default();
+        for stream in collection.iter() {
+            let id = match stream.stream_id() {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let stream_type = stream.stream_type();
+            let track = if stream_type.contains(gst::StreamType::VIDEO) {
+                &mut selection.video
+            } else if stream_type.contains(gst::StreamType::AUDIO) {
+                &mut selection.audio
+            } else if stream_type.contains(gst::StreamType::TEXT) {
+                &mut selection.text
+            } else {
+                continue;
+            };
+            track.ids.push(id);
+        }
+        selection.video.select(0);
+        selection.audio.select(options.audio.unwrap_or(0));
+        if let Some(text) = options.text {
+            selection.text.select(text);
+        }
+        selection
+    }
+
+    fn ids(&self) -> Vec<&str> {
+        [&self.video, &self.audio, &self.text]
+            .iter()
+            .filter_map(|track| track.selected())
+            .collect()
+    }
+}
+
+/// ストリームを1行で表す
+fn describe(stream: &gst::Stream) -> String {
+    let mut line = format!(
+        "{:?} {}",
+        stream.stream_type(),
+        stream
+            .stream_id()
+            .map(|id| id.to_string())
+            .unwrap_or_default()
+    );
+    if let Some(tags) = stream.tags() {
+        if let Some(language) = tags.get::<gst::tags::LanguageCode>() {
+            line.push_str(&format!(" language={}", language.get()));
+        }
+        if let Some(title) = tags.get::<gst::tags::Title>() {
+            line.push_str(&format!(" title=\"{}\"", title.get()));
+        }
+        if let Some(codec) = tags.get::<gst::tags::Codec>() {
+            line.push_str(&format!(" codec=\"{}\"", codec.get()));
+        }
+    }
+    if let Some(caps) = stream.caps() {
+        if let Some(s) = caps.structure(0) {
+            line.push_str(&format!(" caps={}", s.name()));
+        }
+    }
+    line
+}
+
+/// playbin3に選んだストリームを送る
+fn select(playbin: &gst::Element, selection: &Selection) {
+    let ids = selection.ids();
+    log::info!("selecting {ids:?}\r");
+    if !playbin.send_event(gst::event::SelectStreams::new(&ids)) {
+        log::warn!("select-streams was not handled\r");
+    }
+}
+
+/// playbin3で再生し、StreamCollectionを受け取ったらストリームを選ぶ
+/// キーでストリームを切り替えながら、EOSかエラーか'q'まで続ける
+pub fn run(options: &StreamsOptions) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let playbin = gst::ElementFactory::make("playbin3", Some("playbin3"))
+        .context("playbin3 is not available")?;
+    playbin.set_property("uri", &options.uri);
+
+    println!(
+        "\
+USAGE:
+ 'V' to switch the video stream
+ 'A' to switch the audio stream
+ 'T' to switch the subtitle stream
+ 'Q' to quit\r"
+    );
+    let keyboard = Keyboard::new()?;
+    playbin
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = playbin.bus().context("bus")?;
+    let mut selection: Option<Selection> = None;
+    let mut result = Ok(());
+    loop {
+        let key = keyboard.try_key();
+        if let Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) = key {
+            break;
+        }
+        if let (Some(Key::Char(c)), Some(selection)) = (key, selection.as_mut()) {
+            let track = match c.to_ascii_lowercase() {
+                'v' => Some((&mut selection.video, false)),
+                'a' => Some((&mut selection.audio, false)),
+                't' => Some((&mut selection.text, true)),
+                _ => None,
+            };
+            if let Some((track, optional)) = track {
+                track.next(optional);
+                select(&playbin, selection);
+            }
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::StreamCollection(msg) => {
+                let collection = msg.stream_collection();
+                println!("streams ({}):\r", collection.len());
+                for stream in collection.iter() {
+                    println!("  {}\r", describe(&stream));
+                }
+                let new = Selection::new(&collection, options);
+                select(&playbin, &new);
+                selection = Some(new);
+            }
+            MessageView::StreamsSelected(msg) => {
+                println!("selected:\r");
+                for stream in msg.streams() {
+                    println!("  {}\r", describe(&stream));
+                }
+            }
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})\r",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!("playback failed: {}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    playbin.set_state(gst::State::Null)?;
+    result
+}
@@ -0,0 +1,240 @@
+//! SRTファイルをRustで読み、appsrcから時刻付きのテキストバッファとしてtextoverlayへ流す例
+//!
+//! テキストも映像や音声と同じくPTSとdurationを持ったバッファとして扱われる。
+//! textoverlayは映像バッファの時刻に重なるテキストバッファを探して描画するので、
+//! appsrcはストリーム時刻でタイムスタンプを付けて先に全部pushしておけばよい。
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSrc;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// SRTの1項目
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: gst::ClockTime,
+    pub end: gst::ClockTime,
+    pub text: String,
+}
+
+/// "00:01:02,345" 形式の時刻を読む。小数点に'.'を使うファイルもあるので両方受け付ける
+/// 小数部は秒の端数なので、"1.5"は1.5秒として3桁に揃え、4桁目以降は切り捨てる
+fn parse_time(s: &str) -> anyhow::Result<gst::ClockTime> {
+    let (hms, fraction) = s
+        .trim()
+        .split_once([',', '.'])
+        .with_context(|| format!("invalid time {s}"))?;
+    let mut parts = hms.split(':').map(str::parse::<u64>);
+    let (h, m, sec) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(h)), Some(Ok(m)), Some(Ok(sec)), None) => (h, m, sec),
+        _ => anyhow::bail!("invalid time {s}"),
+    };
+    anyhow::ensure!(
+        !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()),
+        "invalid time {s}"
+    );
+    let millis = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(3)
+        .fold(0, |millis, b| millis * 10 + u64::from(b - b'0'));
+
+    Ok(gst::ClockTime::from_mseconds(
+        ((h * 60 + m) * 60 + sec) * 1000 + millis,
+    ))
+}
+
+/// SRTの内容を読む
+/// 項目は空行で区切られ、番号、"開始 --> 終了"、本文の順に並んでいる
+pub fn parse_srt(content: &str) -> anyhow::Result<Vec<Cue>> {
+    let content = content.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+
+    let mut cues = Vec::new();
+    for (n, block) in content.split("\n\n").enumerate() {
+        let mut lines = block.lines().skip_while(|l| l.trim().is_empty());
+        if lines.next().is_none() {
+            continue;
+        }
+        let timing = lines
+            .next()
+            .with_context(|| format!("cue {}: missing timing line", n + 1))?;
+        let (start, end) = timing
+            .split_once("-->")
+            .with_context(|| format!("cue {}: invalid timing line {timing}", n + 1))?;
+        let start = parse_time(start).with_context(|| format!("cue {}", n + 1))?;
+        // 終了時刻の後ろに位置指定が付いていることがある
+        let end = end.split_whitespace().next().unwrap_or_default();
+        let end = parse_time(end).with_context(|| format!("cue {}", n + 1))?;
+        let text = lines.collect::<Vec<_>>().join("\n");
+
+        cues.push(Cue { start, end, text });
+    }
+
+    cues.sort_by_key(|cue| cue.start);
+    Ok(cues)
+}
+
+/// pango-markupにしてもそのまま残すSRTのタグ
+const TAGS: &[&str] = &["<i>", "</i>", "<b>", "</b>", "<u>", "</u>"];
+
+/// 本文をpango-markupにする
+/// SRTの<i>、<b>、<u>はそのまま残し、それ以外の'<'や'&'は文字として表示されるようにエスケープする
+fn to_markup(text: &str) -> String {
+    let mut markup = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let tag = TAGS.iter().find(|tag| {
+            rest.get(..tag.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(tag))
+        });
+        if let Some(tag) = tag {
+            markup.push_str(tag);
+            rest = &rest[tag.len()..];
+            continue;
+        }
+        match c {
+            '<' => markup.push_str("&lt;"),
+            '>' => markup.push_str("&gt;"),
+            '&' => markup.push_str("&amp;"),
+            c => markup.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    markup
+}
+
+/// 各項目をテキストバッファにしてappsrcへpushする
+/// SRTの<i>、<b>、<u>はpango-markupとして解釈される
+fn push_cues(appsrc: &AppSrc, cues: &[Cue]) -> anyhow::Result<()> {
+    for cue in cues {
+        let mut buffer = gst::Buffer::from_slice(to_markup(&cue.text).into_bytes());
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(cue.start);
+            buffer.set_duration(cue.end.saturating_sub(cue.start));
+        }
+        appsrc.push_buffer(buffer)?;
+    }
+    appsrc.end_of_stream()?;
+    Ok(())
+}
+
+/// SRTの字幕を映像に重ねて表示する
+/// uriを指定しなければvideotestsrcに重ねる
+pub fn run(srt: &Path, uri: Option<&str>, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let content =
+        fs::read_to_string(srt).with_context(|| format!("failed to read {}", srt.display()))?;
+    let cues = parse_srt(&content)?;
+    log::info!("{} cues loaded", cues.len());
+
+    let video = match uri {
+        Some(uri) => format!(
+            "uridecodebin name=dec uri={uri} dec. ! queue ! audioconvert ! autoaudiosink dec."
+        ),
+        None => "videotestsrc is-live=true".to_string(),
+    };
+    let pipeline = fallback::parse_launch(&format!(
+        "{video} ! queue ! videoconvert ! textoverlay name=overlay valignment=bottom \
+         ! videoconvert ! autovideosink \
+         appsrc name=subs format=time ! overlay.text_sink"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let appsrc = pipeline
+        .by_name("subs")
+        .context("subs")?
+        .dynamic_cast::<AppSrc>()
+        .unwrap();
+    appsrc.set_caps(Some(
+        &gst::Caps::builder("text/x-raw")
+            .field("format", "pango-markup")
+            .build(),
+    ));
+
+    push_cues(&appsrc, &cues)?;
+
+    runner::run(&pipeline, bus_loop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    #[test]
+    fn parse_time_separators() {
+        assert_eq!(parse_time("00:01:02,345").unwrap(), ms(62_345));
+        assert_eq!(parse_time("00:01:02.345").unwrap(), ms(62_345));
+        assert_eq!(parse_time(" 01:00:00,000 ").unwrap(), ms(3_600_000));
+    }
+
+    #[test]
+    fn parse_time_short_fraction() {
+        assert_eq!(parse_time("00:00:01.5").unwrap(), ms(1_500));
+        assert_eq!(parse_time("00:00:01,05").unwrap(), ms(1_050));
+        assert_eq!(parse_time("00:00:01,0509").unwrap(), ms(1_050));
+    }
+
+    #[test]
+    fn parse_time_invalid() {
+        assert!(parse_time("00:00:01").is_err());
+        assert!(parse_time("00:01,000").is_err());
+        assert!(parse_time("00:00:01,").is_err());
+        assert!(parse_time("00:00:01,5x").is_err());
+        assert!(parse_time("00:00:01,-5").is_err());
+    }
+
+    #[test]
+    fn parse_srt_crlf() {
+        let cues = parse_srt(
+            "\u{feff}1\r\n00:00:01,000 --> 00:00:02,500\r\nHello\r\nworld\r\n\r\n\
+             2\r\n00:00:03.5 --> 00:00:04,000 X1:0 X2:10\r\n<i>Bye</i>\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            cues,
+            vec![
+                Cue {
+                    start: ms(1_000),
+                    end: ms(2_500),
+                    text: "Hello\nworld".to_string(),
+                },
+                Cue {
+                    start: ms(3_500),
+                    end: ms(4_000),
+                    text: "<i>Bye</i>".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_srt_malformed() {
+        assert!(parse_srt("1\n").is_err());
+        assert!(parse_srt("1\n00:00:01,000 00:00:02,000\nHello\n").is_err());
+        assert!(parse_srt("1\n00:00:01,000 --> soon\nHello\n").is_err());
+        assert!(parse_srt("\n\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn markup_keeps_srt_tags() {
+        assert_eq!(
+            to_markup("<i>a</i> <B>b</B> <u>c</u>"),
+            "<i>a</i> <b>b</b> <u>c</u>"
+        );
+        assert_eq!(
+            to_markup("<font color=\"red\">R&B</font> 1 < 2 > 0"),
+            "&lt;font color=\"red\"&gt;R&amp;B&lt;/font&gt; 1 &lt; 2 &gt; 0"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! 実時間を待たずにパイプラインを流すための設定
+//!
+//! --no-syncはsinkのsyncを切る。sinkはクロックを待たずに届いたバッファをすぐ描画するので、
+//! ファイルの再生ならデコードできる速さで最後まで流れる。
+//! --virtual-clockはgst_checkのTestClockをパイプラインのクロックにする。
+//! TestClockは自分からは進まないので、別スレッドで待っているクロックIDを見つけてはその時刻まで進める(crank)。
+//! sinkはsync=trueのままで、バッファの時刻どおりの順序で待ちが解けるので、タイミングに依存する処理を
+//! 実時間に関係なく同じ順番で試せる。
+//! どちらも音声デバイスのように自分の速さで再生するsinkでは、そのsinkの速さ以上にはならない。
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use gst::prelude::*;
+use gstreamer_check::TestClock;
+use structopt::StructOpt;
+
+/// 待っているクロックIDがないときに見直す間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// 同期の設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct SyncOpt {
+    /// sinkのsyncを切って、クロックを待たずにできるだけ速く流す
+    #[structopt(long)]
+    pub no_sync: bool,
+    /// TestClockをパイプラインのクロックにして、待っている時刻まで即座に進める
+    #[structopt(long)]
+    pub virtual_clock: bool,
+}
+
+impl SyncOpt {
+    /// パイプラインに設定を反映する
+    /// 仮想クロックを使うときは戻り値を持っている間だけクロックを進める
+    pub fn apply(&self, pipeline: &gst::Pipeline) -> Option<VirtualClock> {
+        if self.no_sync {
+            disable_sync(pipeline);
+            log::info!("sinks run with sync=false");
+        }
+        if self.virtual_clock {
+            log::info!("pipeline runs on a virtual clock");
+            return Some(VirtualClock::start(pipeline));
+        }
+        None
+    }
+}
+
+/// bin配下のsinkのsyncを切る。後から追加されるsink(playbinのautovideosinkの中身など)も対象にする
+pub fn disable_sync(bin: &impl IsA<gst::Bin>) {
+    fn set(element: &gst::Element) {
+        if element.element_flags().contains(gst::ElementFlags::SINK)
+            && element.find_property("sync").is_some()
+        {
+            element.set_property("sync", false);
+        }
+    }
+
+    bin.iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .for_each(|element| set(&element));
+    bin.connect_deep_element_added(|_, _, element| set(element));
+}
+
+/// TestClockを進め続けるスレッド。dropすると止まる
+pub struct VirtualClock {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl VirtualClock {
+    pub fn start(pipeline: &gst::Pipeline) -> Self {
+        let clock = TestClock::new();
+        pipeline.use_clock(Some(&clock));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let handle = thread::spawn(move || {
+            while !stop_clone.load(Ordering::Relaxed) {
+                // 一番早いIDの時刻まで進めて解放する。なければsinkが次に待つまで少し待つ
+                if clock.peek_next_pending_id().is_some() {
+                    clock.crank();
+                } else {
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for VirtualClock {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
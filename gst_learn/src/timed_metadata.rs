@@ -0,0 +1,320 @@
+//! ライブのMPEG-TSにID3のタイムドメタデータを入れて送り、受け側で映像と一緒に取り出す
+//!
+//! HLSのタイムドメタデータはMPEG-TSの中にID3タグのストリーム(stream_type 0x15)として入れる。
+//! 送り側はmpegtsmuxにmeta/x-id3のappsrcを繋ぎ、実行中にRustから好きなときにタグを押し込む。
+//! appsrcはdo-timestampで押し込んだ時点のrunning-timeをPTSに付けるので、同じ時刻の映像と揃って多重化される。
+//! ここでは一定間隔で送り側の時計(UNIXエポックからのミリ秒)を、'm'を押したときにマーカーを送る。
+//!
+//! 受け側はtsdemuxが出すmeta/x-id3のpadをappsinkで受け、タグを読んでPTSと一緒に出す。
+//! 同じマシンで動かせば、送り側の時計と受け取った時刻の差が遅延の目安になる。
+//! 送り側と受け側はlocalhostのUDPで繋ぐ。SEIのユーザーデータはコーデックごとに扱いが違うのでここでは使わない。
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+
+use crate::{
+    fallback,
+    keyboard::{Key, Keyboard},
+    runner::{self, BusLoopOpt},
+    video_encoder::{VideoCodec, VideoEncodeOpt},
+};
+
+/// MPEG-TSに入れるので、プレイヤーが広く対応しているH.264だけにする
+const VIDEO_CODECS: &[VideoCodec] = &[VideoCodec::X264, VideoCodec::Nvenc];
+/// 送り側の時計を入れるTXXXフレームのdescription
+const WALLCLOCK: &str = "wallclock";
+/// マーカーを入れるTXXXフレームのdescription
+const MARKER: &str = "marker";
+
+/// タイムドメタデータの送り側の設定
+#[derive(Debug, Clone)]
+pub struct TimedMetadataOptions {
+    pub host: String,
+    pub port: u16,
+    /// 送り側の時計を入れる間隔
+    pub interval: Duration,
+}
+
+/// ID3v2.4のタグのサイズなどに使う7bitずつの整数(syncsafe integer)
+fn syncsafe(value: usize) -> [u8; 4] {
+    [
+        ((value >> 21) & 0x7f) as u8,
+        ((value >> 14) & 0x7f) as u8,
+        ((value >> 7) & 0x7f) as u8,
+        (value & 0x7f) as u8,
+    ]
+}
+
+fn read_syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |value, b| (value << 7) | (*b & 0x7f) as usize)
+}
+
+/// TXXX(ユーザー定義のテキスト)フレームを1つだけ持つID3v2.4のタグを作る
+pub fn id3_txxx(description: &str, value: &str) -> Vec<u8> {
+    // UTF-8(0x03)、descriptionはNULで終わる
+    let mut body = vec![0x03];
+    body.extend_from_slice(description.as_bytes());
+    body.push(0);
+    body.extend_from_slice(value.as_bytes());
+
+    let mut frame = b"TXXX".to_vec();
+    frame.extend_from_slice(&syncsafe(body.len()));
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&body);
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(frame.len()));
+    tag.extend_from_slice(&frame);
+    tag
+}
+
+/// ID3v2.4のタグからTXXXフレームのdescriptionとvalueを読む
+/// ほかのフレームは飛ばす
+pub fn parse_id3_txxx(data: &[u8]) -> Vec<(String, String)> {
+    let mut frames = Vec::new();
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return frames;
+    }
+    let end = (10 + read_syncsafe(&data[6..10])).min(data.len());
+    let mut pos = 10;
+    while pos + 10 <= end {
+        let id = &data[pos..pos + 4];
+        // 残りがパディングなら終わり
+        if id == [0; 4] {
+            break;
+        }
+        let size = read_syncsafe(&data[pos + 4..pos + 8]);
+        let body = &data[(pos + 10).min(end)..(pos + 10 + size).min(end)];
+        pos += 10 + size;
+        if id != b"TXXX" || body.is_empty() {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&body[1..]);
+        let mut parts = text.splitn(2, '\0');
+        let description = parts.next().unwrap_or_default().to_string();
+        let value = parts
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('\0')
+            .to_string();
+        frames.push((description, value));
+    }
+    frames
+}
+
+/// UNIXエポックからのミリ秒
+fn epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// 実行中のパイプラインにID3タグを押し込む
+#[derive(Debug, Clone)]
+pub struct MetadataInjector {
+    appsrc: AppSrc,
+}
+
+impl MetadataInjector {
+    /// TXXXフレームを1つ送る。PTSはappsrcが押し込んだ時点のrunning-timeで付ける
+    pub fn send(&self, description: &str, value: &str) -> anyhow::Result<()> {
+        let buffer = gst::Buffer::from_mut_slice(id3_txxx(description, value));
+        self.appsrc
+            .push_buffer(buffer)
+            .map_err(|err| anyhow::anyhow!("failed to push ID3 tag: {err:?}"))?;
+        log::info!("sent {description}={value}\r");
+        Ok(())
+    }
+}
+
+/// ライブのテスト映像とID3のストリームをMPEG-TSにまとめてUDPで送る
+/// 't'のキーで間隔を待たずに時計を、'm'でマーカーを送り、'q'で止める
+pub fn send(options: &TimedMetadataOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let codec = video.codec(VIDEO_CODECS)?;
+    let pipeline = fallback::parse_launch(&format!(
+        "videotestsrc is-live=true pattern=ball ! timeoverlay ! videoconvert ! queue name=vqueue \
+         mpegtsmux name=mux alignment=7 ! udpsink host={} port={} \
+         appsrc name=meta is-live=true format=time do-timestamp=true \
+           caps=meta/x-id3,parsed=true ! mux.",
+        options.host, options.port
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let encoder = video.make_bin(codec, true, Some(30))?;
+    pipeline.add(&encoder)?;
+    let queue = pipeline.by_name("vqueue").context("vqueue")?;
+    let mux = pipeline.by_name("mux").context("mux")?;
+    gst::Element::link_many(&[&queue, encoder.upcast_ref(), &mux])?;
+    let injector = MetadataInjector {
+        appsrc: pipeline
+            .by_name("meta")
+            .context("meta")?
+            .dynamic_cast::<AppSrc>()
+            .unwrap(),
+    };
+
+    println!(
+        "\
+USAGE:
+ 'T' to send the wall-clock now
+ 'M' to send a marker
+ 'Q' to stop\r"
+    );
+    let keyboard = Keyboard::new()?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+    log::info!(
+        "sending MPEG-TS to udp://{}:{}\r",
+        options.host,
+        options.port
+    );
+
+    let bus = pipeline.bus().context("bus")?;
+    let mut next_clock = Instant::now();
+    let mut markers = 0u32;
+    let mut result = Ok(());
+    loop {
+        let sent = match keyboard.try_key() {
+            Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) => break,
+            Some(Key::Char('m' | 'M')) => {
+                markers += 1;
+                injector.send(MARKER, &markers.to_string())
+            }
+            Some(Key::Char('t' | 'T')) => injector.send(WALLCLOCK, &epoch_millis().to_string()),
+            _ if Instant::now() >= next_clock => {
+                next_clock = Instant::now() + options.interval;
+                injector.send(WALLCLOCK, &epoch_millis().to_string())
+            }
+            _ => Ok(()),
+        };
+        if let Err(err) = sent {
+            log::warn!("{err:#}\r");
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        if let gst::MessageView::Error(err) = msg.view() {
+            log::error!(
+                "Error from {:?}: {} ({:?})\r",
+                err.src().map(|s| s.path_string()),
+                err.error(),
+                err.debug()
+            );
+            result = Err(anyhow::anyhow!(
+                "sending stopped by an error: {}",
+                err.error()
+            ));
+            break;
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
+
+/// 受け取ったID3タグを読んで出す。時計なら受け取った時刻との差も出す
+fn report(sample: &gst::Sample, received: &AtomicU64) {
+    let buffer = match sample.buffer() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let map = match buffer.map_readable() {
+        Ok(map) => map,
+        Err(_) => return,
+    };
+    received.fetch_add(1, Ordering::Relaxed);
+    for (description, value) in parse_id3_txxx(map.as_slice()) {
+        match value.parse::<u64>() {
+            Ok(sent) if description == WALLCLOCK => log::info!(
+                "{}: {description}={value} ({} ms after it was sent)",
+                buffer.pts().display(),
+                epoch_millis() as i64 - sent as i64
+            ),
+            _ => log::info!("{}: {description}={value}", buffer.pts().display()),
+        }
+    }
+}
+
+/// tsdemuxのpadの種類に応じて、映像は表示し、ID3はappsinkで読む
+fn link_stream(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    received: &Arc<AtomicU64>,
+) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let name = caps.structure(0).context("empty caps")?.name();
+    let description = if name.starts_with("video/") {
+        "queue ! decodebin ! videoconvert ! autovideosink"
+    } else if name == "meta/x-id3" {
+        "queue ! appsink name=id3 sync=true"
+    } else {
+        log::info!("ignoring {name} stream");
+        return Ok(());
+    };
+    let branch = fallback::parse_bin_from_description(description, true)?;
+    if let Some(appsink) = branch.by_name("id3") {
+        let received = received.clone();
+        appsink.dynamic_cast::<AppSink>().unwrap().set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    report(&sample, &received);
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+    }
+    pipeline.add(&branch)?;
+    branch.sync_state_with_parent()?;
+    pad.link(&branch.static_pad("sink").context("branch sink pad")?)?;
+    log::info!("receiving {name}");
+    Ok(())
+}
+
+/// UDPでMPEG-TSを受け取り、映像を表示しながらID3のタイムドメタデータを出す
+pub fn receive(port: u16, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let pipeline = fallback::parse_launch(&format!(
+        "udpsrc port={} caps=video/mpegts,systemstream=true,packetsize=188 \
+         ! tsparse set-timestamps=true ! tsdemux name=demux latency=200",
+        port
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let demux = pipeline.by_name("demux").context("demux")?;
+    let received = Arc::new(AtomicU64::new(0));
+    let received_clone = received.clone();
+    let pipeline_weak = pipeline.downgrade();
+    demux.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(err) = link_stream(&pipeline, pad, &received_clone) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+
+    log::info!("receiving MPEG-TS on udp port {port}");
+    let result = runner::run(&pipeline, bus_loop);
+    log::info!("received {} ID3 tags", received.load(Ordering::Relaxed));
+    result
+}
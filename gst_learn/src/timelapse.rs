@@ -0,0 +1,333 @@
+//! ライブの映像からN秒ごとに1フレームを取り出し、指定したフレームレートの動画にまとめる(タイムラプス)
+//!
+//! 撮影と書き込みを別々のパイプラインにしてappsinkからappsrcへフレームを渡す。
+//! - 撮影側はappsinkをmax-buffers=1 drop=trueにして常に最新のフレームだけを持たせ、
+//!   間隔が来たときにそのフレームを取り出す。videorateのmax-rateは整数のfpsなので1秒より長い間隔にできない。
+//! - 書き込み側は受け取ったフレームに 枚数 / 出力のフレームレート のPTSを付け直してエンコードする。
+//!   撮影した時刻とは関係なく詰めて並べるので、撮影が途切れても動画に隙間はできない。
+//!
+//! 何時間も動かすことを想定して、撮影側のエラーやフレームが来なくなった場合は撮影側だけを作り直す。
+//! 書き込み側はそのまま動かし続けるので、それまでに書いたフレームは失われない。
+//! 出力は記録と同じくstorageモジュールで選び、'q'かフレーム数の上限でEOSを送って閉じてから確定させる。
+//! ローカルのファイルでは.partを付けた一時ファイルに書き、閉じてから名前を変える。
+//! matroskaではclusterを短く区切るので、プロセスごと落ちても.partのファイルをほぼ最後まで再生できる。
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+
+use crate::{
+    container::{Container, MuxerOptions},
+    fallback,
+    keyboard::{Key, Keyboard},
+    storage::{self, StorageBackend},
+    video_encoder::VideoEncodeOpt,
+};
+
+/// この間フレームが来なければ撮影側が止まったとみなす
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// タイムラプスの設定
+#[derive(Debug)]
+pub struct TimelapseOptions {
+    /// パスかURI。storageモジュールで出力先を選ぶ
+    pub output: String,
+    /// 入力に使うカメラのデバイス。Noneならvideotestsrc
+    pub device: Option<String>,
+    /// フレームを取り出す間隔
+    pub interval: gst::ClockTime,
+    /// 出力の動画のフレームレート
+    pub fps: i32,
+    pub width: i32,
+    pub height: i32,
+    pub container: Container,
+    /// このフレーム数を取り出したら止める
+    pub max_frames: Option<u64>,
+    /// 撮影側が止まってから作り直すまで待つ時間
+    pub retry: gst::ClockTime,
+}
+
+impl TimelapseOptions {
+    /// 撮影側と書き込み側で受け渡すフレームのcaps。書き込み側には出力のフレームレートも付ける
+    fn caps(&self, framerate: bool) -> gst::Caps {
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "I420")
+            .field("width", self.width)
+            .field("height", self.height)
+            .field("pixel-aspect-ratio", gst::Fraction::new(1, 1));
+        if framerate {
+            caps.field("framerate", gst::Fraction::new(self.fps, 1))
+                .build()
+        } else {
+            caps.build()
+        }
+    }
+
+    /// index枚目のフレームのPTS
+    fn pts(&self, index: u64) -> gst::ClockTime {
+        gst::ClockTime::from_nseconds(index * gst::ClockTime::SECOND.nseconds() / self.fps as u64)
+    }
+}
+
+/// 撮影側のパイプライン
+struct Capture {
+    pipeline: gst::Pipeline,
+    appsink: AppSink,
+    bus: gst::Bus,
+    /// 最後にフレームを受け取った時刻
+    last_frame: Instant,
+}
+
+impl Capture {
+    /// 撮影側を作って動かす。サイズとフォーマットはcapsに揃えるので、作り直しても書き込み側は変わらない
+    fn start(options: &TimelapseOptions) -> anyhow::Result<Self> {
+        let source = match options.device {
+            Some(ref device) => format!("v4l2src device={device}"),
+            None => "videotestsrc is-live=true pattern=ball ! timeoverlay".to_string(),
+        };
+        let pipeline = fallback::parse_launch(&format!(
+            "{source} ! videoconvert ! videoscale ! capsfilter name=caps \
+             ! appsink name=sink max-buffers=1 drop=true sync=false"
+        ))?
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline
+            .by_name("caps")
+            .context("caps")?
+            .set_property("caps", &options.caps(false));
+        let appsink = pipeline
+            .by_name("sink")
+            .context("sink")?
+            .dynamic_cast::<AppSink>()
+            .unwrap();
+        let bus = pipeline.bus().context("capture bus")?;
+
+        if let Err(err) = pipeline.set_state(gst::State::Playing) {
+            let _ = pipeline.set_state(gst::State::Null);
+            return Err(err).context("Unable to set the capture to the `Playing` state");
+        }
+        log::info!("capture started\r");
+        Ok(Self {
+            pipeline,
+            appsink,
+            bus,
+            last_frame: Instant::now(),
+        })
+    }
+
+    /// 最新のフレームを待つ。エラーやEOS、フレームが来なくなったときはErr
+    fn poll(&mut self) -> anyhow::Result<Option<gst::Sample>> {
+        while let Some(msg) = self.bus.pop() {
+            use gst::MessageView;
+            match msg.view() {
+                MessageView::Error(err) => anyhow::bail!(
+                    "error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                ),
+                // カメラが外れたときなど
+                MessageView::Eos(_) => anyhow::bail!("capture ended"),
+                _ => {}
+            }
+        }
+        match self.appsink.try_pull_sample(100 * gst::ClockTime::MSECOND) {
+            Some(sample) => {
+                self.last_frame = Instant::now();
+                Ok(Some(sample))
+            }
+            None if self.last_frame.elapsed() > STALL_TIMEOUT => {
+                anyhow::bail!("no frame for {STALL_TIMEOUT:?}")
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// 受け取ったフレームをエンコードして出力先に書くパイプライン
+fn make_writer(
+    options: &TimelapseOptions,
+    video: &VideoEncodeOpt,
+    storage: &mut dyn StorageBackend,
+) -> anyhow::Result<(gst::Pipeline, AppSrc)> {
+    let codec = video.codec(options.container.video_codecs())?;
+    let pipeline = gst::Pipeline::new(Some("timelapse"));
+    let appsrc = gst::ElementFactory::make("appsrc", Some("src"))?
+        .dynamic_cast::<AppSrc>()
+        .unwrap();
+    appsrc.set_caps(Some(&options.caps(true)));
+    appsrc.set_format(gst::Format::Time);
+
+    let convert = gst::ElementFactory::make("videoconvert", None)?;
+    // 出力の1秒はfps枚の撮影なので、キーフレームは1秒ごとにしてシークしやすくする
+    let encoder = video.make_bin(codec, false, Some(options.fps as u32))?;
+    let mux = options.container.make_muxer(
+        "mux",
+        MuxerOptions {
+            cluster_duration: Some(gst::ClockTime::SECOND),
+            streamable: !storage.seekable(),
+            ..Default::default()
+        },
+    )?;
+    let sink = storage.make_sink(Some("sink"))?;
+
+    pipeline.add_many(&[
+        appsrc.upcast_ref(),
+        &convert,
+        encoder.upcast_ref(),
+        &mux,
+        &sink,
+    ])?;
+    gst::Element::link_many(&[
+        appsrc.upcast_ref(),
+        &convert,
+        encoder.upcast_ref(),
+        &mux,
+        &sink,
+    ])?;
+    Ok((pipeline, appsrc))
+}
+
+/// 書き込み側のバスを見て、EOSならtrue、エラーならErr
+fn writer_finished(bus: &gst::Bus) -> anyhow::Result<bool> {
+    while let Some(msg) = bus.pop() {
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => return Ok(true),
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})\r",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                anyhow::bail!("writing stopped by an error: {}", err.error());
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// 撮影側と書き込み側を動かし、'q'かフレーム数の上限まで間隔ごとにフレームを渡す
+fn capture_until_stopped(
+    options: &TimelapseOptions,
+    writer: &gst::Pipeline,
+    appsrc: &AppSrc,
+) -> anyhow::Result<u64> {
+    println!(
+        "\
+USAGE:
+ 'Q' to stop and close the file\r"
+    );
+
+    let keyboard = Keyboard::new()?;
+    writer
+        .set_state(gst::State::Playing)
+        .context("Unable to set the writer to the `Playing` state")?;
+    let bus = writer.bus().context("writer bus")?;
+
+    let interval = Duration::from_nanos(options.interval.nseconds());
+    let retry = Duration::from_nanos(options.retry.nseconds());
+    let mut capture: Option<Capture> = None;
+    let mut restart_at = Instant::now();
+    let mut next_frame = Instant::now();
+    let mut frames = 0u64;
+    let mut stopping = false;
+    loop {
+        if writer_finished(&bus)? {
+            break;
+        }
+        let stop = match keyboard.try_key() {
+            Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) => true,
+            _ => matches!(options.max_frames, Some(max) if frames >= max),
+        };
+        if stop && !stopping {
+            log::info!("stopping after {frames} frames\r");
+            stopping = true;
+            capture = None;
+            // muxerが最後まで書き出せるように、EOSを流してから止める
+            let _ = appsrc.end_of_stream();
+        }
+        if stopping {
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+
+        if capture.is_none() && Instant::now() >= restart_at {
+            match Capture::start(options) {
+                Ok(started) => capture = Some(started),
+                Err(err) => {
+                    log::warn!("failed to start the capture, retrying in {retry:?}: {err:#}\r");
+                    restart_at = Instant::now() + retry;
+                }
+            }
+        }
+        let sample = match capture.as_mut().map(Capture::poll) {
+            Some(Ok(sample)) => sample,
+            Some(Err(err)) => {
+                log::warn!("capture failed, restarting in {retry:?}: {err:#}\r");
+                capture = None;
+                restart_at = Instant::now() + retry;
+                continue;
+            }
+            None => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        let sample = match sample {
+            Some(sample) if Instant::now() >= next_frame => sample,
+            _ => continue,
+        };
+
+        let mut buffer = sample.buffer_owned().context("sample without buffer")?;
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(options.pts(frames));
+            buffer.set_dts(gst::ClockTime::NONE);
+            buffer.set_duration(options.pts(frames + 1) - options.pts(frames));
+        }
+        appsrc
+            .push_buffer(buffer)
+            .map_err(|err| anyhow::anyhow!("failed to push frame {frames}: {err:?}"))?;
+        frames += 1;
+        log::info!("frame {frames}\r");
+
+        // 撮影が途切れていた分は取り戻さず、次の間隔から撮り直す
+        next_frame += interval;
+        if next_frame < Instant::now() {
+            next_frame = Instant::now() + interval;
+        }
+    }
+    Ok(frames)
+}
+
+/// 間隔ごとにフレームを取り出してタイムラプスの動画を書く
+/// EOSで閉じた後に出力先のfinishで確定させる
+pub fn run(options: &TimelapseOptions, video: &VideoEncodeOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(options.fps > 0, "--fps must be positive");
+    anyhow::ensure!(
+        options.interval > gst::ClockTime::ZERO,
+        "--interval must be positive"
+    );
+    let mut storage = storage::open(&options.output)?;
+    let (writer, appsrc) = make_writer(options, video, storage.as_mut())?;
+
+    let result = capture_until_stopped(options, &writer, &appsrc);
+    writer.set_state(gst::State::Null)?;
+    let frames = result.with_context(|| format!("timelapse to {storage} stopped"))?;
+
+    storage.finish()?;
+    log::info!("saved {frames} frames to {storage}");
+    Ok(())
+}
@@ -0,0 +1,370 @@
+//! 入力をデコードして、選んだコンテナ向けのコーデックで再エンコードする
+//!
+//! 入力と出力に"-"を指定すると標準入出力を使うので、シェルのパイプに繋げられる。
+//!     curl -sL <url> | gst_learn transcode - - --container mkv > out.mkv
+//! 標準入力はfdsrcで読み、decodebinの中のtypefindでフォーマットを判別する。
+//! パイプはシークできないので、moovが末尾にあるMP4などはデコードできないことがある。
+//! 標準出力もシークできないため、muxerは書いた位置に戻らないstreamableな設定にする。
+//! ログは標準エラー出力に出るので、出力データとは混ざらない。
+//!
+//! --two-passでは同じ入力を2回デコードする。1回目はエンコード結果を捨てて統計のファイルだけを書き、
+//! 2回目でその統計を使って出力を書く。入力を2回読むので標準入力は使えない。
+//! 統計のファイルは一時ディレクトリに作り、終わったら成否にかかわらず消す。
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    audio_encoder::AudioEncodeOpt,
+    container::{self, Container, MuxerOptions},
+    deinterlace::DeinterlaceOpt,
+    fallback,
+    runner::{self, BusLoopOpt},
+    video_encoder::{Pass, VideoEncodeOpt},
+};
+
+/// 標準入出力を表す指定
+const STDIO: &str = "-";
+
+/// トランスコードの設定
+#[derive(Debug)]
+pub struct TranscodeOptions {
+    /// ファイルパス、URI、または標準入力を表す"-"
+    pub input: String,
+    /// ファイルパス、または標準出力を表す"-"
+    pub output: String,
+    pub container: Container,
+    /// 2パスでエンコードする
+    pub two_pass: bool,
+}
+
+/// 入力からdecodebinまでを作る。返すのはデコード済みのpadを出すエレメント
+pub fn add_source(pipeline: &gst::Pipeline, input: &str) -> anyhow::Result<gst::Element> {
+    if input.contains("://") {
+        let decode = gst::ElementFactory::make("uridecodebin", Some("decode"))?;
+        decode.set_property("uri", input);
+        pipeline.add(&decode)?;
+        return Ok(decode);
+    }
+
+    let src = if input == STDIO {
+        let src = gst::ElementFactory::make("fdsrc", Some("source"))?;
+        src.set_property("fd", 0i32);
+        src
+    } else {
+        let src = gst::ElementFactory::make("filesrc", Some("source"))?;
+        src.set_property("location", input);
+        src
+    };
+    let decode = gst::ElementFactory::make("decodebin", Some("decode"))?;
+    pipeline.add_many(&[&src, &decode])?;
+    src.link(&decode)?;
+    Ok(decode)
+}
+
+/// 2パスの1回目のようにエンコード結果を捨てるmuxerを作る
+fn add_null_sink(pipeline: &gst::Pipeline, container: Container) -> anyhow::Result<gst::Element> {
+    let mux = container.make_muxer(
+        "mux",
+        MuxerOptions {
+            streamable: true,
+            ..Default::default()
+        },
+    )?;
+    let sink = gst::ElementFactory::make("fakesink", Some("sink"))?;
+    pipeline.add_many(&[&mux, &sink])?;
+    mux.link(&sink)?;
+    Ok(mux)
+}
+
+/// muxerからsinkまでを作り、muxerを返す
+fn add_sink(
+    pipeline: &gst::Pipeline,
+    output: &str,
+    container: Container,
+) -> anyhow::Result<gst::Element> {
+    let streamable = output == STDIO;
+    let mux = container.make_muxer(
+        "mux",
+        MuxerOptions {
+            faststart: !streamable,
+            streamable,
+            ..Default::default()
+        },
+    )?;
+    let sink = if streamable {
+        let sink = gst::ElementFactory::make("fdsink", Some("sink"))?;
+        sink.set_property("fd", 1i32);
+        sink
+    } else {
+        let sink = gst::ElementFactory::make("filesink", Some("sink"))?;
+        sink.set_property("location", output);
+        sink
+    };
+    pipeline.add_many(&[&mux, &sink])?;
+    mux.link(&sink)?;
+    Ok(mux)
+}
+
+/// デコードされたpadの種類に応じて変換とエンコードのブランチを作り、muxerに繋ぐ
+pub fn link_branch(
+    pipeline: &gst::Pipeline,
+    pad: &gst::Pad,
+    mux: &gst::Element,
+    container: Container,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let name = caps.structure(0).context("empty caps")?.name();
+
+    let (mut elements, encoder) = if name.starts_with("video/") {
+        let codec = video.codec(container.video_codecs())?;
+        let mut elements = ["queue", "videoconvert"]
+            .iter()
+            .map(|factory| {
+                gst::ElementFactory::make(factory, None)
+                    .with_context(|| format!("failed to create {factory}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        elements.push(video.make(codec, None, false, None)?);
+        if let Some(parser) = codec.parser() {
+            elements.push(
+                gst::ElementFactory::make(parser, None)
+                    .with_context(|| format!("failed to create {parser}"))?,
+            );
+        }
+        // インターレースの解除はqueueの直後、色空間の変換の前に入れる
+        if let Some(deinterlace) = deinterlace.element_for(&caps)? {
+            elements.insert(1, deinterlace);
+        }
+        (elements, codec.encoder())
+    } else if name.starts_with("audio/") {
+        let codec = audio.codec(container.audio_codecs())?;
+        let branch =
+            fallback::parse_bin_from_description(&format!("queue ! {}", audio.launch(codec)), true)
+                .with_context(|| format!("failed to create {} branch", codec.encoder()))?;
+        (vec![branch.upcast()], codec.encoder())
+    } else {
+        log::info!("ignoring {name} stream");
+        return Ok(());
+    };
+    elements.push(mux.clone());
+
+    let elements = elements.iter().collect::<Vec<_>>();
+    let branch = &elements[..elements.len() - 1];
+    pipeline.add_many(branch)?;
+    // muxerのrequest padはlink時に作られる
+    gst::Element::link_many(&elements)?;
+    for element in branch {
+        element.sync_state_with_parent()?;
+    }
+    pad.link(&branch[0].static_pad("sink").context("sink pad")?)?;
+
+    log::info!("transcoding {name} with {encoder}");
+    Ok(())
+}
+
+/// 何回目のパスか
+#[derive(Debug, Clone, Copy)]
+struct PassIndex {
+    index: u32,
+    count: u32,
+}
+
+/// 1秒ごとに再生位置を問い合わせて、このパスと全体の進み具合を出す
+/// stopが立つまで続ける
+fn report_progress(
+    pipeline: &gst::Pipeline,
+    pass: PassIndex,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    let pipeline_weak = pipeline.downgrade();
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(1));
+            let pipeline = match pipeline_weak.upgrade() {
+                Some(pipeline) => pipeline,
+                None => break,
+            };
+            let position = pipeline.query_position::<gst::ClockTime>();
+            let duration = pipeline.query_duration::<gst::ClockTime>();
+            if let (Some(position), Some(duration)) = (position, duration) {
+                if duration.nseconds() == 0 {
+                    continue;
+                }
+                let fraction = position.nseconds() as f64 / duration.nseconds() as f64;
+                let total = (pass.index as f64 + fraction.min(1.0)) / pass.count as f64;
+                log::info!(
+                    "pass {}/{}: {:.1}% (total {:.1}%)",
+                    pass.index + 1,
+                    pass.count,
+                    fraction.min(1.0) * 100.0,
+                    total * 100.0
+                );
+            }
+        }
+    })
+}
+
+/// 1つのパイプラインでEOSまでトランスコードする
+/// outputがNoneならエンコード結果は捨てる。passがあれば進み具合を出す
+fn run_pass(
+    options: &TranscodeOptions,
+    output: Option<&str>,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+    pass: Option<PassIndex>,
+) -> anyhow::Result<()> {
+    let pipeline = gst::Pipeline::new(Some("transcode"));
+    let decode = add_source(&pipeline, &options.input)?;
+    // 1回目も音声を繋がないとdecodebinがnot-linkedで止まるので、同じようにエンコードして捨てる
+    let mux = match output {
+        Some(output) => add_sink(&pipeline, output, options.container)?,
+        None => add_null_sink(&pipeline, options.container)?,
+    };
+
+    let pipeline_weak = pipeline.downgrade();
+    let container = options.container;
+    let deinterlace = deinterlace.clone();
+    let video = video.clone();
+    let audio = audio.clone();
+    decode.connect_pad_added(move |_, pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        if let Err(err) = link_branch(
+            &pipeline,
+            pad,
+            &mux,
+            container,
+            &deinterlace,
+            &video,
+            &audio,
+        ) {
+            log::error!("failed to link {}: {err:#}", pad.name());
+        }
+    });
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let progress = pass.map(|pass| report_progress(&pipeline, pass, stop.clone()));
+    // 2パスの1回目が失敗したまま2回目に進まないように、バスのエラーもErrにする
+    let result = runner::run_checked(&pipeline, bus_loop);
+    stop.store(true, Ordering::SeqCst);
+    if let Some(progress) = progress {
+        let _ = progress.join();
+    }
+    result
+}
+
+/// 2パスでトランスコードする。統計のファイルは最後に消す
+fn run_two_pass(
+    options: &TranscodeOptions,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        options.input != STDIO,
+        "--two-pass reads the input twice and can't read from stdin"
+    );
+    let stats = std::env::temp_dir().join(format!("gst_learn-2pass-{}.log", std::process::id()));
+
+    let result = (|| -> anyhow::Result<()> {
+        let first = VideoEncodeOpt {
+            pass: Some(Pass::First(stats.clone())),
+            ..video.clone()
+        };
+        log::info!("pass 1/2: analyzing {}", options.input);
+        let pass = PassIndex { index: 0, count: 2 };
+        run_pass(
+            options,
+            None,
+            deinterlace,
+            &first,
+            audio,
+            bus_loop,
+            Some(pass),
+        )
+        .context("first pass")?;
+        anyhow::ensure!(
+            stats.exists(),
+            "first pass did not write the stats file {}",
+            stats.display()
+        );
+
+        let second = VideoEncodeOpt {
+            pass: Some(Pass::Second(stats.clone())),
+            ..video.clone()
+        };
+        log::info!("pass 2/2: encoding {}", options.output);
+        let pass = PassIndex { index: 1, count: 2 };
+        run_pass(
+            options,
+            Some(options.output.as_str()),
+            deinterlace,
+            &second,
+            audio,
+            bus_loop,
+            Some(pass),
+        )
+        .context("second pass")
+    })();
+
+    // x264encは統計とは別にマクロブロックの情報を.mbtreeに書く
+    let mut mbtree = stats.clone().into_os_string();
+    mbtree.push(".mbtree");
+    for path in [stats, PathBuf::from(mbtree)] {
+        if path.exists() {
+            if let Err(err) = fs::remove_file(&path) {
+                log::warn!("failed to remove {}: {err}", path.display());
+            }
+        }
+    }
+    result
+}
+
+/// EOSまでトランスコードし、ファイルに書いた場合は正しく閉じられたか確認する
+pub fn run(
+    options: &TranscodeOptions,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    if options.two_pass {
+        run_two_pass(options, deinterlace, video, audio, bus_loop)?;
+    } else {
+        run_pass(
+            options,
+            Some(options.output.as_str()),
+            deinterlace,
+            video,
+            audio,
+            bus_loop,
+            None,
+        )?;
+    }
+
+    if options.output != STDIO {
+        container::verify(Path::new(&options.output))?;
+    }
+    Ok(())
+}
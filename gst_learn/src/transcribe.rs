@@ -0,0 +1,259 @@
+//! 再生している音声を分岐して、外部の音声認識に渡して文字起こしする
+//!
+//! デコードした音声をteeで再生とappsinkに分け、appsink側はaudioresampleとaudioconvertで
+//! 音声認識が一般に受け付ける16kHz、モノラル、S16LEにそろえる。
+//! appsinkから取り出したサンプルを--chunkの秒数ずつ溜め、Transcriberに渡して返ってきた文字を時刻と一緒に出す。
+//! 認識が遅いと取り出しが遅れるが、appsinkのキューに溜まるだけで再生は止めない。
+//!
+//! Transcriberはサンプルを受け取って文字を返すだけのトレイトで、認識エンジンはここに差し込む。
+//! - DummyTranscriber: 認識はせず、チャンクの音量と有音の割合を出す。パイプラインの確認用
+//! - WhisperCli: チャンクをWAVの一時ファイルに書いてwhisper.cppのwhisper-cliを呼び、標準出力を文字起こしとして使う
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 音声認識に渡すサンプルレート
+pub const SAMPLE_RATE: u32 = 16_000;
+/// これより大きいサンプルを有音とみなす(およそ-40dBFS)
+const SPEECH_THRESHOLD: i16 = 328;
+
+/// 音声認識の差し込み口
+pub trait Transcriber: Send {
+    /// 16kHzモノラルのサンプルを文字にする。何も聞き取れなければ空
+    fn transcribe(&mut self, samples: &[i16]) -> anyhow::Result<String>;
+}
+
+/// 認識せずに、チャンクの音量と有音の割合を返す
+#[derive(Debug, Default)]
+pub struct DummyTranscriber;
+
+impl Transcriber for DummyTranscriber {
+    fn transcribe(&mut self, samples: &[i16]) -> anyhow::Result<String> {
+        if samples.is_empty() {
+            return Ok(String::new());
+        }
+        let square = samples
+            .iter()
+            .map(|s| (*s as f64 / i16::MAX as f64).powi(2))
+            .sum::<f64>();
+        let rms = 20.0 * (square / samples.len() as f64).sqrt().max(1e-10).log10();
+        let voiced = samples
+            .iter()
+            .filter(|s| s.unsigned_abs() > SPEECH_THRESHOLD as u16)
+            .count();
+        if voiced == 0 {
+            return Ok(String::new());
+        }
+        Ok(format!(
+            "(audio: rms {rms:.1} dB, {:.0}% above the threshold)",
+            100.0 * voiced as f64 / samples.len() as f64
+        ))
+    }
+}
+
+/// whisper.cppのwhisper-cliを1チャンクごとに呼ぶ
+#[derive(Debug)]
+pub struct WhisperCli {
+    /// 実行するコマンド
+    pub command: String,
+    /// ggmlのモデルファイル
+    pub model: PathBuf,
+    /// 言語。Noneならwhisper-cliの既定
+    pub language: Option<String>,
+}
+
+/// 一時ファイルの名前を分ける番号
+static CHUNK_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// 16kHzモノラルS16LEのWAV
+fn wav(samples: &[i16]) -> Vec<u8> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+impl WhisperCli {
+    fn run(&self, path: &Path) -> anyhow::Result<String> {
+        let mut command = Command::new(&self.command);
+        // -nt: タイムスタンプを付けない、-np: 結果以外を出さない
+        command.arg("-m").arg(&self.model).arg("-f").arg(path);
+        command.args(["-nt", "-np"]);
+        if let Some(language) = &self.language {
+            command.args(["-l", language]);
+        }
+        let output = command
+            .output()
+            .with_context(|| format!("failed to run {}", self.command))?;
+        anyhow::ensure!(
+            output.status.success(),
+            "{} exited with {}: {}",
+            self.command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+}
+
+impl Transcriber for WhisperCli {
+    fn transcribe(&mut self, samples: &[i16]) -> anyhow::Result<String> {
+        let path = std::env::temp_dir().join(format!(
+            "gst-learn-stt-{}-{}.wav",
+            std::process::id(),
+            CHUNK_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, wav(samples)).with_context(|| format!("write {}", path.display()))?;
+        let result = self.run(&path);
+        let _ = fs::remove_file(&path);
+        result
+    }
+}
+
+/// 文字起こしの設定
+#[derive(Debug)]
+pub struct TranscribeOptions {
+    pub uri: String,
+    /// 1回に認識させる長さ
+    pub chunk: gst::ClockTime,
+    /// 音を鳴らさずに、認識だけをする
+    pub no_playback: bool,
+}
+
+/// サンプルをチャンクの長さまで溜め、時刻を付けて認識に渡す
+struct Chunker {
+    transcriber: Box<dyn Transcriber>,
+    chunk_samples: usize,
+    samples: Vec<i16>,
+    /// 溜めているチャンクの先頭の時刻
+    start: Option<gst::ClockTime>,
+}
+
+impl Chunker {
+    fn push(&mut self, sample: &gst::Sample) -> anyhow::Result<()> {
+        let buffer = sample.buffer().context("sample without buffer")?;
+        if self.start.is_none() {
+            self.start = buffer.pts();
+        }
+        let map = buffer.map_readable()?;
+        self.samples.extend(
+            map.as_slice()
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]])),
+        );
+        while self.samples.len() >= self.chunk_samples {
+            let rest = self.samples.split_off(self.chunk_samples);
+            self.flush();
+            self.samples = rest;
+        }
+        Ok(())
+    }
+
+    /// 溜めたサンプルを認識に渡して空にする
+    fn flush(&mut self) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let start = self.start.unwrap_or(gst::ClockTime::ZERO);
+        let duration = gst::ClockTime::from_nseconds(
+            self.samples.len() as u64 * gst::ClockTime::SECOND.nseconds() / SAMPLE_RATE as u64,
+        );
+        match self.transcriber.transcribe(&self.samples) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => println!("[{start:.3} - {:.3}] {text}", start + duration),
+            Err(err) => log::warn!("transcription of {start:.3} failed: {err:#}"),
+        }
+        self.samples.clear();
+        self.start = Some(start + duration);
+    }
+}
+
+/// appsinkからサンプルを取り出して認識する。EOSかNullで終わり、残りのサンプルも認識する
+fn consume(appsink: AppSink, mut chunker: Chunker) {
+    while let Ok(sample) = appsink.pull_sample() {
+        if let Err(err) = chunker.push(&sample) {
+            log::warn!("{err:#}");
+        }
+    }
+    chunker.flush();
+}
+
+/// 再生しながら一定の長さごとに文字起こしを出す
+pub fn run(
+    options: &TranscribeOptions,
+    transcriber: Box<dyn Transcriber>,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(
+        options.chunk >= 100 * gst::ClockTime::MSECOND,
+        "--chunk is too short"
+    );
+    let playback = if options.no_playback {
+        "fakesink sync=true"
+    } else {
+        "autoaudiosink"
+    };
+    // 映像のストリームは出さず、音声だけをteeに繋ぐ
+    let pipeline = fallback::parse_launch(&format!(
+        "uridecodebin uri=\"{uri}\" caps=audio/x-raw expose-all-streams=false ! tee name=t \
+         t. ! queue ! audioconvert ! audioresample ! {playback} \
+         t. ! queue ! audioconvert ! audioresample \
+         ! audio/x-raw,format=S16LE,layout=interleaved,rate={SAMPLE_RATE},channels=1 \
+         ! appsink name=stt sync=false",
+        uri = options.uri,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let appsink = pipeline
+        .by_name("stt")
+        .context("stt")?
+        .dynamic_cast::<AppSink>()
+        .unwrap();
+
+    let chunker = Chunker {
+        transcriber,
+        chunk_samples: (options.chunk.nseconds() * SAMPLE_RATE as u64
+            / gst::ClockTime::SECOND.nseconds()) as usize,
+        samples: Vec::new(),
+        start: None,
+    };
+    let consumer = thread::spawn(move || consume(appsink, chunker));
+
+    let result = runner::run(&pipeline, bus_loop);
+    // 残りのチャンクの認識が終わるのを待つ
+    let _ = consumer.join();
+    result
+}
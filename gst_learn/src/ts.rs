@@ -0,0 +1,293 @@
+//! 複数のprogramを含むMPEG-TSから1つを選んで再生し、再生中に切り替える
+//!
+//! 放送のTSは1本のストリームに複数のprogram(チャンネル)を多重化している。
+//! どのprogramがあるかはPID 0のPAT(Program Association Table)に書かれていて、
+//! programごとにPMTのPIDが並ぶ。tsparseの後ろでパケット単位に揃ったデータを覗き、PATを読んで一覧を出す。
+//! PATは通常1パケットに収まるので、複数パケットにまたがるものは読まない。
+//!
+//! tsdemuxはprogram-numberのprogramだけをpadとして出す。再生中に変えると今のprogramのpadを消し、
+//! 新しいprogramのpadを出し直すので、pad-addedとpad-removedで下流のブランチを作り直す。
+//! ブランチはpadごとにqueue ! decodebinと出力をまとめたbinにして、消すときはbinごと外す。
+//!
+//! 'n'で次、'p'で前のprogramに切り替える。
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    keyboard::{Key, Keyboard},
+};
+
+/// MPEG-TSのパケットの大きさ
+const PACKET_SIZE: usize = 188;
+/// パケットの先頭の同期バイト
+const SYNC_BYTE: u8 = 0x47;
+/// PATが流れるPID
+const PAT_PID: u16 = 0;
+/// PATのtable_id
+const PAT_TABLE_ID: u8 = 0;
+
+/// PATに書かれたprogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Program {
+    pub number: u16,
+    pub pmt_pid: u16,
+}
+
+/// 1パケットからPATのprogramの一覧を読む。PATの先頭を含むパケットでなければNone
+fn parse_pat(packet: &[u8]) -> Option<Vec<Program>> {
+    if packet.len() != PACKET_SIZE || packet[0] != SYNC_BYTE {
+        return None;
+    }
+    let payload_unit_start = packet[1] & 0x40 != 0;
+    let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+    if pid != PAT_PID || !payload_unit_start {
+        return None;
+    }
+    // adaptation_field_controlの上位ビットがadaptation field、下位ビットがpayloadの有無
+    let adaptation = (packet[3] >> 4) & 0x3;
+    if adaptation & 0x1 == 0 {
+        return None;
+    }
+    let mut offset = 4;
+    if adaptation & 0x2 != 0 {
+        offset += 1 + usize::from(*packet.get(4)?);
+    }
+    // payloadの先頭はpointer_fieldで、その後ろからsectionが始まる
+    let pointer = usize::from(*packet.get(offset)?);
+    let section = packet.get(offset + 1 + pointer..)?;
+    if *section.first()? != PAT_TABLE_ID {
+        return None;
+    }
+    let length = (usize::from(section[1] & 0x0f) << 8) | usize::from(*section.get(2)?);
+    // transport_stream_idからlast_section_numberまでの5バイトの後ろにprogramが並び、最後の4バイトはCRC
+    let entries = section.get(8..(3 + length).checked_sub(4)?)?;
+    Some(
+        entries
+            .chunks_exact(4)
+            .map(|entry| Program {
+                number: u16::from_be_bytes([entry[0], entry[1]]),
+                pmt_pid: (u16::from(entry[2] & 0x1f) << 8) | u16::from(entry[3]),
+            })
+            // program_number 0はNIT(ネットワーク情報)を指すので除く
+            .filter(|program| program.number != 0)
+            .collect(),
+    )
+}
+
+/// tsparseの出力を覗いてPATを読み、一覧が変わったら出す
+fn watch_pat(tsparse: &gst::Element, programs: Arc<Mutex<Vec<Program>>>) -> anyhow::Result<()> {
+    let src_pad = tsparse.static_pad("src").context("tsparse src pad")?;
+    src_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        let buffer = match &info.data {
+            Some(gst::PadProbeData::Buffer(buffer)) => buffer,
+            _ => return gst::PadProbeReturn::Ok,
+        };
+        let map = match buffer.map_readable() {
+            Ok(map) => map,
+            Err(_) => return gst::PadProbeReturn::Ok,
+        };
+        // tsparseはパケットの境界で区切って出すので、先頭から188バイトずつ見ればよい
+        for found in map.chunks_exact(PACKET_SIZE).filter_map(parse_pat) {
+            let mut programs = programs.lock().unwrap();
+            if *programs != found {
+                println!("programs:\r");
+                for program in &found {
+                    println!("  {} (PMT PID {:#x})\r", program.number, program.pmt_pid);
+                }
+                *programs = found;
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
+
+/// tsdemuxのpadに繋ぐブランチのbinの名前
+fn branch_name(pad: &gst::Pad) -> String {
+    format!("branch-{}", pad.name())
+}
+
+/// decodebinがデコードしたpadに出力を繋ぐ
+fn link_output(bin: &gst::Bin, pad: &gst::Pad) -> anyhow::Result<()> {
+    let caps = pad.current_caps().context("pad without caps")?;
+    let name = caps.structure(0).context("empty caps")?.name();
+    let description = if name.starts_with("video/") {
+        "queue ! videoconvert ! autovideosink"
+    } else if name.starts_with("audio/") {
+        "queue ! audioconvert ! audioresample ! autoaudiosink"
+    } else {
+        "queue ! fakesink"
+    };
+    let output = fallback::parse_bin_from_description(description, true)?;
+    bin.add(&output)?;
+    output.sync_state_with_parent()?;
+    pad.link(&output.static_pad("sink").context("output sink pad")?)?;
+    Ok(())
+}
+
+/// tsdemuxのpadごとにデコードと出力のブランチを作る
+/// 映像と音声以外(字幕やデータ)はデコードせずに捨てる
+fn add_branch(pipeline: &gst::Pipeline, pad: &gst::Pad) -> anyhow::Result<()> {
+    // capsがまだなければ、video_0_0100のようなpadの名前で判断する
+    let media = pad
+        .current_caps()
+        .and_then(|caps| caps.structure(0).map(|s| s.name().to_string()))
+        .unwrap_or_else(|| pad.name().to_string());
+    let bin = gst::Bin::new(Some(&branch_name(pad)));
+    let queue = gst::ElementFactory::make("queue", None)?;
+    bin.add(&queue)?;
+    if media.starts_with("video") || media.starts_with("audio") {
+        let decode = gst::ElementFactory::make("decodebin", None)?;
+        bin.add(&decode)?;
+        queue.link(&decode)?;
+        let bin_weak = bin.downgrade();
+        decode.connect_pad_added(move |_, pad| {
+            if let Some(bin) = bin_weak.upgrade() {
+                if let Err(err) = link_output(&bin, pad) {
+                    log::error!("failed to link {}: {err:#}\r", pad.name());
+                }
+            }
+        });
+    } else {
+        let sink = gst::ElementFactory::make("fakesink", None)?;
+        bin.add(&sink)?;
+        queue.link(&sink)?;
+    }
+    let sink_pad = queue.static_pad("sink").context("queue sink pad")?;
+    bin.add_pad(&gst::GhostPad::with_target(Some("sink"), &sink_pad)?)?;
+
+    pipeline.add(&bin)?;
+    bin.sync_state_with_parent()?;
+    pad.link(&bin.static_pad("sink").context("branch sink pad")?)?;
+    log::info!("added {} ({media})\r", pad.name());
+    Ok(())
+}
+
+/// 消えたpadのブランチを外す
+/// tsdemuxのストリーミングスレッドから状態を変えないように、別スレッドで片付ける
+fn remove_branch(pipeline: &gst::Pipeline, pad: &gst::Pad) {
+    let branch = match pipeline.by_name(&branch_name(pad)) {
+        Some(branch) => branch,
+        None => return,
+    };
+    log::info!("removed {}\r", pad.name());
+    let pipeline = pipeline.clone();
+    branch.call_async(move |branch| {
+        let _ = branch.set_state(gst::State::Null);
+        let _ = pipeline.remove(branch);
+    });
+}
+
+/// 一覧の中で今のprogramからstepだけ進めたprogram
+fn step_program(programs: &[Program], current: i32, step: isize) -> Option<u16> {
+    if programs.is_empty() {
+        return None;
+    }
+    let len = programs.len() as isize;
+    let index = programs
+        .iter()
+        .position(|program| i32::from(program.number) == current)
+        .map_or(0, |i| (i as isize + step).rem_euclid(len));
+    Some(programs[index as usize].number)
+}
+
+/// TSのファイルかURIを再生する。programを省略するとtsdemuxが最初のprogramを選ぶ
+pub fn run(input: &str, program: Option<u16>) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let source = if input.contains("://") {
+        gst::Element::make_from_uri(gst::URIType::Src, input, Some("source"))?
+    } else {
+        let source = gst::ElementFactory::make("filesrc", Some("source"))?;
+        source.set_property("location", input);
+        source
+    };
+    let tsparse = gst::ElementFactory::make("tsparse", Some("parse")).context("make tsparse")?;
+    let demux = gst::ElementFactory::make("tsdemux", Some("demux")).context("make tsdemux")?;
+    if let Some(program) = program {
+        demux.set_property("program-number", i32::from(program));
+    }
+
+    let pipeline = gst::Pipeline::new(Some("ts"));
+    pipeline.add_many(&[&source, &tsparse, &demux])?;
+    gst::Element::link_many(&[&source, &tsparse, &demux])?;
+
+    let programs = Arc::new(Mutex::new(Vec::new()));
+    watch_pat(&tsparse, programs.clone())?;
+
+    let pipeline_weak = pipeline.downgrade();
+    demux.connect_pad_added(move |_, pad| {
+        if let Some(pipeline) = pipeline_weak.upgrade() {
+            if let Err(err) = add_branch(&pipeline, pad) {
+                log::error!("failed to link {}: {err:#}\r", pad.name());
+            }
+        }
+    });
+    let pipeline_weak = pipeline.downgrade();
+    demux.connect_pad_removed(move |_, pad| {
+        if let Some(pipeline) = pipeline_weak.upgrade() {
+            remove_branch(&pipeline, pad);
+        }
+    });
+
+    println!(
+        "\
+USAGE:
+ 'N' to switch to the next program
+ 'P' to switch to the previous program
+ 'Q' to quit\r"
+    );
+    let keyboard = Keyboard::new()?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("bus")?;
+    let mut result = Ok(());
+    loop {
+        let step = match keyboard.try_key() {
+            Some(Key::Char('q' | 'Q')) | Some(Key::Ctrl('c' | 'C')) => break,
+            Some(Key::Char('n' | 'N')) => 1,
+            Some(Key::Char('p' | 'P')) => -1,
+            _ => 0,
+        };
+        if step != 0 {
+            let current = demux.property::<i32>("program-number");
+            let next = step_program(&programs.lock().unwrap(), current, step);
+            match next {
+                Some(next) => {
+                    log::info!("switching to program {next}\r");
+                    demux.set_property("program-number", i32::from(next));
+                }
+                None => log::warn!("no programs found yet\r"),
+            }
+        }
+
+        let msg = match bus.timed_pop(100 * gst::ClockTime::MSECOND) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        use gst::MessageView;
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})\r",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                result = Err(anyhow::anyhow!("playback failed: {}", err.error()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+    result
+}
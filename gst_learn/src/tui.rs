@@ -0,0 +1,243 @@
+//! 再生中の状態を端末の数行に書き換えながら表示する
+//!
+//! 状態、位置と長さのプログレスバー、バッファリングの割合、ビットレート、
+//! video sinkが落としたフレーム数、再生レート、ソースのスループットをまとめて表示する。
+//! どれもパイプラインへの問い合わせとプロパティから定期的に読むので、
+//! サブコマンドのバスの処理には手を入れない。
+//! 同じ行をエスケープシーケンスで上書きするので、ログはRUST_LOGで絞ると見やすい。
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::{bandwidth, keyboard::CLEAR_LINE};
+
+/// 表示を更新する間隔
+const INTERVAL: Duration = Duration::from_millis(250);
+/// プログレスバーの幅
+const BAR_WIDTH: usize = 40;
+
+/// TUIの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Default, StructOpt)]
+pub struct TuiOpt {
+    /// 状態、位置、バッファリング、ビットレート、ドロップしたフレーム、再生レートを端末に表示し続ける
+    #[structopt(long)]
+    pub tui: bool,
+}
+
+impl TuiOpt {
+    /// 有効なら表示のスレッドを開始する。返り値をdropすると止まる
+    /// ソースの計測を仕掛けるので、パイプラインをPlayingにする前に呼ぶ
+    pub fn start(&self, pipeline: &gst::Element) -> anyhow::Result<Option<Tui>> {
+        if !self.tui {
+            return Ok(None);
+        }
+        Ok(Some(Tui::start(pipeline)))
+    }
+}
+
+/// 1回分の表示内容
+#[derive(Debug, Default)]
+struct Status {
+    state: Option<gst::State>,
+    position: Option<gst::ClockTime>,
+    duration: Option<gst::ClockTime>,
+    rate: Option<f64>,
+    buffering: Option<i32>,
+    bitrate: Option<u32>,
+    frames: Option<(u64, u64)>,
+    throughput: Option<f64>,
+    received: u64,
+    source_stats: Option<gst::Structure>,
+}
+
+impl Status {
+    fn query(pipeline: &gst::Element, meter: &bandwidth::Meter) -> Self {
+        let mut buffering = gst::query::Buffering::new(gst::Format::Percent);
+        let buffering = pipeline
+            .query(&mut buffering)
+            .then(|| buffering.percent().1);
+        let mut segment = gst::query::Segment::new(gst::Format::Time);
+        let rate = pipeline.query(&mut segment).then(|| segment.result().0);
+
+        Self {
+            state: Some(pipeline.current_state()),
+            position: pipeline.query_position(),
+            duration: pipeline.query_duration(),
+            rate,
+            buffering,
+            bitrate: bitrate(pipeline),
+            frames: frames(pipeline),
+            throughput: meter.bytes_per_sec(),
+            received: meter.total_bytes(),
+            source_stats: meter.source_stats(),
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let fmt_time = |t: Option<gst::ClockTime>| {
+            t.map_or_else(|| "--:--:--".to_string(), |t| format!("{:.0}", t.display()))
+        };
+        let progress = match (self.position, self.duration) {
+            (Some(pos), Some(dur)) if dur > gst::ClockTime::ZERO => {
+                (pos.nseconds() as f64 / dur.nseconds() as f64).clamp(0.0, 1.0)
+            }
+            _ => 0.0,
+        };
+        let filled = (progress * BAR_WIDTH as f64).round() as usize;
+
+        let mut lines = vec![
+            format!(
+                "state {:<8} rate {}",
+                self.state.map_or("-".to_string(), |s| format!("{s:?}")),
+                self.rate.map_or("-".to_string(), |r| format!("{r:.2}x")),
+            ),
+            format!(
+                "[{}{}] {} / {}",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled),
+                fmt_time(self.position),
+                fmt_time(self.duration),
+            ),
+            format!(
+                "buffering {}  bitrate {}  dropped {}",
+                self.buffering.map_or("-".to_string(), |p| format!("{p}%")),
+                self.bitrate.map_or("-".to_string(), |b| format!(
+                    "{:.1} kbit/s",
+                    b as f64 / 1000.0
+                )),
+                self.frames
+                    .map_or("-".to_string(), |(dropped, rendered)| format!(
+                        "{dropped}/{}",
+                        dropped + rendered
+                    )),
+            ),
+            format!(
+                "throughput {}  received {:.1} MB",
+                self.throughput.map_or("-".to_string(), |b| format!(
+                    "{:.1} kbit/s",
+                    b * 8.0 / 1000.0
+                )),
+                self.received as f64 / 1e6,
+            ),
+        ];
+        if let Some(stats) = &self.source_stats {
+            lines.push(format!("source {stats}"));
+        }
+        lines
+    }
+}
+
+/// playbinなら選ばれている音声と映像のストリームのタグからビットレートを合計する
+fn bitrate(pipeline: &gst::Element) -> Option<u32> {
+    let mut total = None;
+    for stype in ["video", "audio"] {
+        let current = pipeline
+            .try_property::<i32>(&format!("current-{stype}"))
+            .ok()?;
+        if current < 0 {
+            continue;
+        }
+        let tags = pipeline
+            .emit_by_name::<Option<gst::TagList>>(&format!("get-{stype}-tags"), &[&current]);
+        let rate = tags.and_then(|tags| {
+            tags.get::<gst::tags::Bitrate>()
+                .or_else(|| tags.get::<gst::tags::NominalBitrate>())
+                .map(|v| v.get())
+        });
+        if let Some(rate) = rate {
+            *total.get_or_insert(0) += rate;
+        }
+    }
+    total
+}
+
+/// video sinkのstatsから(落としたフレーム, 表示したフレーム)を読む
+/// playbinのvideo-sinkはautovideosinkのようなbinのこともあるので、中のsinkを探す
+fn frames(pipeline: &gst::Element) -> Option<(u64, u64)> {
+    let sink = pipeline
+        .try_property::<Option<gst::Element>>("video-sink")
+        .ok()
+        .flatten()?;
+    let sink = match sink.downcast::<gst::Bin>() {
+        Ok(bin) => bin
+            .iterate_recurse()
+            .into_iter()
+            .flatten()
+            .find(|e| e.find_property("stats").is_some())?,
+        Err(sink) => sink,
+    };
+    let stats = sink.try_property::<gst::Structure>("stats").ok()?;
+    Some((
+        stats.get::<u64>("dropped").ok()?,
+        stats.get::<u64>("rendered").ok()?,
+    ))
+}
+
+/// 状態を表示し続けるスレッド
+pub struct Tui {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    fn start(pipeline: &gst::Element) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let meter = bandwidth::Meter::attach(pipeline);
+        let pipeline_weak = pipeline.downgrade();
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                let mut drawn = 0;
+                while running.load(Ordering::SeqCst) {
+                    let pipeline = match pipeline_weak.upgrade() {
+                        Some(pipeline) => pipeline,
+                        None => break,
+                    };
+                    let lines = Status::query(&pipeline, &meter).lines();
+                    if draw(&lines, drawn).is_err() {
+                        break;
+                    }
+                    drawn = drawn.max(lines.len());
+                    thread::sleep(INTERVAL);
+                }
+            })
+        };
+        Self {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// 前回書いた行まで戻って上書きする。前回より行が減ったら残りは空行で消す
+/// rawモードの端末でも崩れないように改行は\r\nで書く
+fn draw(lines: &[String], drawn: usize) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    if drawn > 0 {
+        write!(stdout, "\x1b[{drawn}A")?;
+    }
+    for i in 0..drawn.max(lines.len()) {
+        let line = lines.get(i).map_or("", String::as_str);
+        write!(stdout, "\r{CLEAR_LINE}{line}\r\n")?;
+    }
+    stdout.flush()
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
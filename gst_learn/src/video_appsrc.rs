@@ -0,0 +1,172 @@
+//! Rust側で描いた映像フレームをappsrcからパイプラインに流す例
+//!
+//! B8は音声サンプルを生成していたが、映像でも同じようにバッファを作ってpushできる。
+//! 映像の場合はcapsに解像度とフレームレートを書き、各バッファにPTSとdurationを正しく付けないと
+//! sinkでの同期やエンコーダのレート制御がおかしくなる。
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer_app::AppSrc;
+use gstreamer_video::{VideoFormat, VideoInfo};
+
+use crate::fallback;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+const FPS: i32 = 30;
+const BOX_SIZE: u32 = 40;
+
+/// 背景のグラデーションの上を四角が跳ね回るアニメーションを描く
+struct Animation {
+    info: VideoInfo,
+    frame: u64,
+    x: i32,
+    y: i32,
+    dx: i32,
+    dy: i32,
+}
+
+impl Animation {
+    fn new(info: VideoInfo) -> Self {
+        Self {
+            info,
+            frame: 0,
+            x: 0,
+            y: 0,
+            dx: 4,
+            dy: 3,
+        }
+    }
+
+    /// 1フレーム分をRGBで描く。strideは行末のパディングを含む
+    fn draw(&mut self, data: &mut [u8]) {
+        let stride = self.info.stride()[0] as usize;
+        let width = self.info.width() as usize;
+        let shift = self.frame as usize;
+
+        for (y, line) in data.chunks_exact_mut(stride).enumerate() {
+            for (x, pixel) in line[..width * 3].chunks_exact_mut(3).enumerate() {
+                let inside = x as i32 >= self.x
+                    && (x as i32) < self.x + BOX_SIZE as i32
+                    && y as i32 >= self.y
+                    && (y as i32) < self.y + BOX_SIZE as i32;
+                if inside {
+                    pixel.copy_from_slice(&[255, 255, 255]);
+                } else {
+                    pixel[0] = ((x + shift) % 256) as u8;
+                    pixel[1] = ((y + shift / 2) % 256) as u8;
+                    pixel[2] = 128;
+                }
+            }
+        }
+
+        // 壁に当たったら反射させる
+        self.x += self.dx;
+        self.y += self.dy;
+        if self.x <= 0 || self.x + BOX_SIZE as i32 >= self.info.width() as i32 {
+            self.dx = -self.dx;
+        }
+        if self.y <= 0 || self.y + BOX_SIZE as i32 >= self.info.height() as i32 {
+            self.dy = -self.dy;
+        }
+        self.frame += 1;
+    }
+}
+
+/// 描画したフレームを`frames`枚流してEOSを送る
+/// outputを指定するとx264encでエンコードしてmatroskaに書き出し、無ければ画面に表示する
+pub fn run(frames: u64, output: Option<&Path>) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let info = VideoInfo::builder(VideoFormat::Rgb, WIDTH, HEIGHT)
+        .fps(gst::Fraction::new(FPS, 1))
+        .build()?;
+    let video_caps = info.to_caps()?;
+
+    let pipeline = gst::Pipeline::new(Some("pipeline"));
+    let appsrc = gst::ElementFactory::make("appsrc", Some("source"))?;
+    let convert = gst::ElementFactory::make("videoconvert", Some("convert"))?;
+    pipeline.add_many(&[&appsrc, &convert])?;
+    appsrc.link(&convert)?;
+
+    match output {
+        Some(path) => {
+            let encoder = fallback::make("x264enc", Some("encoder"))?;
+            let mux = gst::ElementFactory::make("matroskamux", Some("mux"))?;
+            let sink = gst::ElementFactory::make("filesink", Some("sink"))?;
+            sink.set_property("location", path.to_str().context("non UTF-8 path")?);
+            pipeline.add_many(&[&encoder, &mux, &sink])?;
+            gst::Element::link_many(&[&convert, &encoder, &mux, &sink])?;
+        }
+        None => {
+            let sink = fallback::make("autovideosink", Some("sink"))?;
+            pipeline.add(&sink)?;
+            convert.link(&sink)?;
+        }
+    }
+
+    let appsrc = appsrc.dynamic_cast::<AppSrc>().unwrap();
+    appsrc.set_caps(Some(&video_caps));
+    appsrc.set_format(gst::Format::Time);
+
+    let frame_duration = gst::ClockTime::SECOND
+        .mul_div_floor(1, FPS as u64)
+        .expect("u64 overflow");
+    let animation = Arc::new(Mutex::new(Animation::new(info.clone())));
+
+    // 下流が要求するたびに1フレームずつ描いてpushする
+    appsrc.set_callbacks(
+        gstreamer_app::AppSrcCallbacks::builder()
+            .need_data(move |appsrc, _| {
+                let mut animation = animation.lock().unwrap();
+                if animation.frame >= frames {
+                    let _ = appsrc.end_of_stream();
+                    return;
+                }
+
+                let mut buffer = gst::Buffer::with_size(info.size()).unwrap();
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    buffer.set_pts(animation.frame * frame_duration);
+                    buffer.set_duration(frame_duration);
+                    let mut map = buffer.map_writable().unwrap();
+                    animation.draw(map.as_mut_slice());
+                }
+                drop(animation);
+
+                let _ = appsrc.push_buffer(buffer);
+            })
+            .build(),
+    );
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Unable to set the pipeline to the `Playing` state")?;
+
+    let bus = pipeline.bus().context("bus")?;
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        use gst::MessageView;
+
+        match msg.view() {
+            MessageView::Eos(_) => break,
+            MessageView::Error(err) => {
+                log::error!(
+                    "Error from {:?}: {} ({:?})",
+                    err.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
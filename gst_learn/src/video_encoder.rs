@@ -0,0 +1,391 @@
+//! 記録・トランスコード・配信で共通に使う映像エンコードの設定
+//!
+//! コーデックごとにエンコーダとmuxerの前に入れるパーサーを決め、レート制御を各エンコーダのプロパティに読み替える。
+//!
+//! - cbr: 指定したビットレートを保つ
+//! - vbr: 画質(--crf)を保ち、ビットレートは内容に合わせて変わる。x264encのqual、vpx/aomのcq
+//! - cqp: 量子化パラメータ(--crf)を固定する。x264encのquant、vpx/aomのq、nvencのconstqp
+//!
+//! エンコーダの種類やバージョンによってはプロパティがないので、作ったエレメントに問い合わせてから設定し、
+//! ないものは警告して飛ばす。
+//! --video-codecを省略したときは、出力が格納できるコーデックのうちエンコーダがインストールされている最初のものを使う。
+//!
+//! x264encがなければopenh264encで代わりにH.264を出す。openh264encはvbrとcqpの画質を細かく指定できず、
+//! vbrはquality、cqpは量子化パラメータの上下限を--crfに揃えて近づける。
+//!
+//! 2パスエンコードは1回目で統計をファイルに書き、2回目でそれを読んで--bitrateに合わせて配分する。
+//! x264encとvp8enc/vp9encだけが対応している。
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use gst::prelude::*;
+use structopt::StructOpt;
+
+use crate::fallback;
+
+/// --bitrateを省略したときのビットレート(kbps)
+const DEFAULT_BITRATE: u32 = 2000;
+/// --crfを省略したときの画質。x264の既定のCRFに合わせる
+const DEFAULT_CRF: u32 = 23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    X264,
+    Vp8,
+    Vp9,
+    Av1,
+    Nvenc,
+}
+
+impl FromStr for VideoCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x264" | "h264" => Ok(Self::X264),
+            "vp8" => Ok(Self::Vp8),
+            "vp9" => Ok(Self::Vp9),
+            "av1" => Ok(Self::Av1),
+            "nvenc" => Ok(Self::Nvenc),
+            _ => anyhow::bail!("unknown video codec {s}, expected x264|vp8|vp9|av1|nvenc"),
+        }
+    }
+}
+
+impl fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::X264 => "x264",
+            Self::Vp8 => "vp8",
+            Self::Vp9 => "vp9",
+            Self::Av1 => "av1",
+            Self::Nvenc => "nvenc",
+        })
+    }
+}
+
+impl VideoCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::X264 => "x264enc",
+            Self::Vp8 => "vp8enc",
+            Self::Vp9 => "vp9enc",
+            Self::Av1 => "av1enc",
+            Self::Nvenc => "nvh264enc",
+        }
+    }
+
+    /// muxerの前に必要なパーサー
+    pub fn parser(&self) -> Option<&'static str> {
+        match self {
+            Self::X264 | Self::Nvenc => Some("h264parse"),
+            Self::Av1 => Some("av1parse"),
+            Self::Vp8 | Self::Vp9 => None,
+        }
+    }
+
+    /// エンコーダか、その代わりがインストールされているか
+    pub fn is_available(&self) -> bool {
+        fallback::is_available(self.encoder())
+    }
+}
+
+/// レート制御の方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateControl {
+    Cbr,
+    Vbr,
+    Cqp,
+}
+
+impl FromStr for RateControl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cbr" => Ok(Self::Cbr),
+            "vbr" => Ok(Self::Vbr),
+            "cqp" => Ok(Self::Cqp),
+            _ => anyhow::bail!("unknown rate control {s}, expected cbr|vbr|cqp"),
+        }
+    }
+}
+
+/// 2パスエンコードのどちらのパスか。統計のファイルを1回目で書き、2回目で読む
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pass {
+    First(PathBuf),
+    Second(PathBuf),
+}
+
+impl Pass {
+    fn stats(&self) -> &Path {
+        match self {
+            Self::First(stats) | Self::Second(stats) => stats,
+        }
+    }
+}
+
+/// 映像エンコードの設定
+/// 各サブコマンドで`#[structopt(flatten)]`して使う
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct VideoEncodeOpt {
+    /// 映像のコーデック(x264, vp8, vp9, av1, nvenc)。省略すると出力に合わせて選ぶ
+    #[structopt(long)]
+    pub video_codec: Option<VideoCodec>,
+    /// レート制御(cbr, vbr, cqp)。省略するとcbr
+    #[structopt(long)]
+    pub rc: Option<RateControl>,
+    /// cbrのビットレート(kbps)
+    #[structopt(long)]
+    pub bitrate: Option<u32>,
+    /// vbrとcqpの画質(量子化パラメータ)。小さいほど高画質
+    #[structopt(long)]
+    pub crf: Option<u32>,
+    /// 2パスエンコードのパス。コマンドラインではなく呼び出し側が設定する
+    #[structopt(skip)]
+    pub pass: Option<Pass>,
+}
+
+fn factory_name(element: &gst::Element) -> String {
+    element
+        .factory()
+        .map(|f| f.name().to_string())
+        .unwrap_or_default()
+}
+
+/// プロパティがあれば文字列から設定し、なければ警告する
+fn set(encoder: &gst::Element, name: &str, value: impl ToString) {
+    if encoder.find_property(name).is_some() {
+        encoder.set_property_from_str(name, &value.to_string());
+    } else {
+        log::warn!("{} has no {name} property, ignoring", factory_name(encoder));
+    }
+}
+
+impl VideoEncodeOpt {
+    /// 出力が格納できるコーデックから使うものを選ぶ
+    /// 省略されたときはsupportedのうちエンコーダがある最初のもの
+    pub fn codec(&self, supported: &[VideoCodec]) -> anyhow::Result<VideoCodec> {
+        let codec = match self.video_codec {
+            Some(codec) if supported.contains(&codec) => codec,
+            Some(codec) => anyhow::bail!(
+                "{codec} is not supported by this output, expected one of {}",
+                supported
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("|")
+            ),
+            None => {
+                let codec = supported
+                    .iter()
+                    .copied()
+                    .find(VideoCodec::is_available)
+                    .with_context(|| {
+                        format!(
+                            "none of the video encoders for this output is installed, tried {}",
+                            supported
+                                .iter()
+                                .map(|c| c.encoder())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    })?;
+                // 出力の既定のコーデックが使えなかったことを知らせる
+                if let Some(preferred) = supported.first().filter(|&&c| c != codec) {
+                    log::warn!(
+                        "{} is not available, using {} ({codec}) instead",
+                        preferred.encoder(),
+                        codec.encoder()
+                    );
+                }
+                codec
+            }
+        };
+        anyhow::ensure!(
+            codec.is_available(),
+            "{} for {codec} is not installed",
+            codec.encoder()
+        );
+        Ok(codec)
+    }
+
+    /// エンコーダを作ってレート制御を設定する
+    /// liveならリアルタイムで間に合うように速度を優先し、key_int_maxはキーフレームの最大間隔(フレーム数)
+    pub fn make(
+        &self,
+        codec: VideoCodec,
+        name: Option<&str>,
+        live: bool,
+        key_int_max: Option<u32>,
+    ) -> anyhow::Result<gst::Element> {
+        if self.pass.is_some() {
+            anyhow::ensure!(
+                matches!(codec, VideoCodec::X264 | VideoCodec::Vp8 | VideoCodec::Vp9),
+                "two-pass encoding is not supported by {codec}"
+            );
+            anyhow::ensure!(
+                matches!(self.rc, None | Some(RateControl::Cbr)),
+                "two-pass encoding targets --bitrate and can't be used with --rc vbr|cqp"
+            );
+        }
+        let encoder = fallback::make(codec.encoder(), name)?;
+        let rc = self.rc.unwrap_or(RateControl::Cbr);
+        let bitrate = self.bitrate.unwrap_or(DEFAULT_BITRATE);
+        let crf = self.crf.unwrap_or(DEFAULT_CRF);
+
+        match codec {
+            VideoCodec::X264 if factory_name(&encoder) == "openh264enc" => {
+                anyhow::ensure!(
+                    self.pass.is_none(),
+                    "two-pass encoding is not supported by openh264enc"
+                );
+                match rc {
+                    RateControl::Cbr => {
+                        set(&encoder, "rate-control", "bitrate");
+                        // openh264encはbits/s
+                        set(&encoder, "bitrate", bitrate * 1000);
+                    }
+                    RateControl::Vbr => set(&encoder, "rate-control", "quality"),
+                    RateControl::Cqp => {
+                        set(&encoder, "rate-control", "off");
+                        set(&encoder, "qp-min", crf);
+                        set(&encoder, "qp-max", crf);
+                    }
+                }
+                if live {
+                    set(&encoder, "complexity", "low");
+                }
+                if let Some(key_int_max) = key_int_max {
+                    set(&encoder, "gop-size", key_int_max);
+                }
+            }
+            VideoCodec::X264 => {
+                match rc {
+                    RateControl::Cbr => {
+                        set(&encoder, "pass", "cbr");
+                        set(&encoder, "bitrate", bitrate);
+                    }
+                    RateControl::Vbr => {
+                        set(&encoder, "pass", "qual");
+                        set(&encoder, "quantizer", crf);
+                    }
+                    RateControl::Cqp => {
+                        set(&encoder, "pass", "quant");
+                        set(&encoder, "quantizer", crf);
+                    }
+                }
+                if live {
+                    set(&encoder, "tune", "zerolatency");
+                    set(&encoder, "speed-preset", "veryfast");
+                }
+                if let Some(key_int_max) = key_int_max {
+                    set(&encoder, "key-int-max", key_int_max);
+                }
+                if let Some(pass) = &self.pass {
+                    let mode = match pass {
+                        Pass::First(_) => "pass1",
+                        Pass::Second(_) => "pass2",
+                    };
+                    set(&encoder, "pass", mode);
+                    set(&encoder, "multipass-cache-file", pass.stats().display());
+                }
+            }
+            VideoCodec::Vp8 | VideoCodec::Vp9 | VideoCodec::Av1 => {
+                set(
+                    &encoder,
+                    "end-usage",
+                    match rc {
+                        RateControl::Cbr => "cbr",
+                        RateControl::Vbr => "cq",
+                        RateControl::Cqp => "q",
+                    },
+                );
+                match rc {
+                    // vpxはbits/s、aomはkbps
+                    RateControl::Cbr if codec == VideoCodec::Av1 => {
+                        set(&encoder, "target-bitrate", bitrate)
+                    }
+                    RateControl::Cbr => set(&encoder, "target-bitrate", bitrate * 1000),
+                    RateControl::Vbr | RateControl::Cqp => set(&encoder, "cq-level", crf),
+                }
+                if live {
+                    if codec == VideoCodec::Av1 {
+                        set(&encoder, "usage-profile", "realtime");
+                        set(&encoder, "cpu-used", 8);
+                    } else {
+                        set(&encoder, "deadline", 1);
+                    }
+                }
+                if let Some(key_int_max) = key_int_max {
+                    set(&encoder, "keyframe-max-dist", key_int_max);
+                }
+                if let Some(pass) = &self.pass {
+                    let mode = match pass {
+                        Pass::First(_) => "first-pass",
+                        Pass::Second(_) => "last-pass",
+                    };
+                    // 2パスではcbrより配分に自由のあるvbrで--bitrateを平均として狙う
+                    set(&encoder, "end-usage", "vbr");
+                    set(&encoder, "multipass-mode", mode);
+                    set(&encoder, "multipass-cache-file", pass.stats().display());
+                }
+            }
+            VideoCodec::Nvenc => {
+                match rc {
+                    RateControl::Cbr => {
+                        set(&encoder, "rc-mode", "cbr");
+                        set(&encoder, "bitrate", bitrate);
+                    }
+                    RateControl::Vbr => {
+                        set(&encoder, "rc-mode", "vbr");
+                        set(&encoder, "const-quality", crf);
+                    }
+                    RateControl::Cqp => {
+                        set(&encoder, "rc-mode", "constqp");
+                        set(&encoder, "qp-const", crf);
+                    }
+                }
+                if live {
+                    set(&encoder, "preset", "low-latency-hq");
+                }
+                if let Some(key_int_max) = key_int_max {
+                    set(&encoder, "gop-size", key_int_max);
+                }
+            }
+        }
+        Ok(encoder)
+    }
+
+    /// エンコーダとパーサーを作って繋いだbinを返す。sinkとsrcのパッドを持つ
+    pub fn make_bin(
+        &self,
+        codec: VideoCodec,
+        live: bool,
+        key_int_max: Option<u32>,
+    ) -> anyhow::Result<gst::Bin> {
+        let bin = gst::Bin::new(None);
+        let encoder = self.make(codec, Some("encoder"), live, key_int_max)?;
+        bin.add(&encoder)?;
+        let last = match codec.parser() {
+            Some(parser) => {
+                let parse = gst::ElementFactory::make(parser, None)
+                    .with_context(|| format!("failed to create {parser}"))?;
+                bin.add(&parse)?;
+                encoder.link(&parse)?;
+                parse
+            }
+            None => encoder.clone(),
+        };
+        let sink = encoder.static_pad("sink").context("encoder sink pad")?;
+        let src = last.static_pad("src").context("src pad")?;
+        bin.add_pad(&gst::GhostPad::with_target(Some("sink"), &sink)?)?;
+        bin.add_pad(&gst::GhostPad::with_target(Some("src"), &src)?)?;
+        Ok(bin)
+    }
+}
@@ -0,0 +1,111 @@
+//! 加工した映像をv4l2loopbackの仮想カメラへ出力する
+//!
+//! 事前に`sudo modprobe v4l2loopback exclusive_caps=1`などでループバックデバイスを作っておく。
+//! ビデオ会議アプリはYUY2やI420などの一般的なフォーマットしか受け付けないことが多いので、
+//! v4l2sinkの手前でフォーマット・解像度・フレームレートを固定してから渡す。
+//! rsrgb2grayやrsroiを使う場合はGST_PLUGIN_PATHにgst-plugin-tutorialのビルド結果を含めておく。
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+use gst::prelude::*;
+
+use crate::{
+    fallback,
+    runner::{self, BusLoopOpt},
+};
+
+/// 仮想カメラに流す前の加工
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    Gray,
+    Roi,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gray" => Ok(Self::Gray),
+            "roi" => Ok(Self::Roi),
+            _ => anyhow::bail!("unknown filter {s}, expected none|gray|roi"),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Gray => "gray",
+            Self::Roi => "roi",
+        })
+    }
+}
+
+impl Filter {
+    fn launch(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gray => "rsrgb2gray ! videoconvert !",
+            Self::Roi => "rsroi x=200 y=120 width=240 height=240 mode=pixelate ! videoconvert !",
+        }
+    }
+}
+
+/// 仮想カメラ出力の設定
+#[derive(Debug)]
+pub struct VirtualCamOptions {
+    /// v4l2loopbackのデバイス
+    pub device: String,
+    /// 入力に使うカメラのデバイス。Noneならvideotestsrc
+    pub input: Option<String>,
+    pub filter: Filter,
+    /// 出力するフォーマット。YUY2, I420, NV12あたりが無難
+    pub format: String,
+    pub width: i32,
+    pub height: i32,
+    pub fps: i32,
+}
+
+/// 入力を加工して仮想カメラへ書き込み続ける
+pub fn run(options: &VirtualCamOptions, bus_loop: &BusLoopOpt) -> anyhow::Result<()> {
+    gst::init()?;
+
+    let format = gstreamer_video::VideoFormat::from_string(&options.format);
+    if format == gstreamer_video::VideoFormat::Unknown {
+        anyhow::bail!("unknown video format {}", options.format);
+    }
+
+    let source = match options.input {
+        Some(ref device) => format!("v4l2src device={device}"),
+        None => "videotestsrc is-live=true pattern=ball".to_string(),
+    };
+    let caps = gst::Caps::builder("video/x-raw")
+        .field("format", format.to_str())
+        .field("width", options.width)
+        .field("height", options.height)
+        .field("framerate", gst::Fraction::new(options.fps, 1))
+        .build();
+
+    // フィルタの前にも変換を入れて、入力がどんなフォーマットでも繋がるようにする
+    // videorateでフレームレートを揃えないと、アプリ側で映像が止まって見えることがある
+    let pipeline = fallback::parse_launch(&format!(
+        "{source} ! videoconvert ! videoscale ! videorate ! {filter} \
+         videoconvert ! capsfilter name=caps ! v4l2sink device={device} sync=false",
+        filter = options.filter.launch(),
+        device = options.device,
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    pipeline
+        .by_name("caps")
+        .context("caps")?
+        .set_property("caps", &caps);
+
+    log::info!("writing {caps} to {}", options.device);
+
+    runner::run(&pipeline, bus_loop)
+}
@@ -0,0 +1,421 @@
+//! ディレクトリを見張り、新しく置かれたメディアのファイルに決めた処理(probe、transcode、thumbnail)をする
+//!
+//! 見張りにはgioのGFileMonitor(Linuxではinotify)を使い、MainLoopの上でイベントを受ける。
+//! Createdはファイルを書き始めた時点で来るので使わず、書き終わったChangesDoneHintと、
+//! 別の場所から移されたMovedIn、ディレクトリの中での名前の変更(.partから本当の名前になったときなど)で処理を始める。
+//!
+//! 処理はジョブのキューに入れて、--jobsの数のワーカーのスレッドで動かす。
+//! 処理が済んだファイルは台帳(既定では見張るディレクトリの.gst-learn-watch)に1行ずつ追記する。
+//! 起動したときに台帳を読み、ディレクトリにあってまだ成功していないファイルも処理するので、
+//! 止めている間に置かれたファイルや、前回失敗したファイルも拾う。
+//! 台帳は処理とファイル名(見張るディレクトリからの名前)の組で見るので、処理を変えれば同じファイルもやり直す。
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::Context;
+use gio::prelude::*;
+use gst::prelude::*;
+use gstreamer_app::AppSink;
+use gstreamer_pbutils::{prelude::*, Discoverer};
+
+use crate::{
+    audio_encoder::AudioEncodeOpt,
+    container::Container,
+    deinterlace::DeinterlaceOpt,
+    fallback,
+    runner::{self, BusLoopOpt},
+    transcode::{self, TranscodeOptions},
+    video_encoder::VideoEncodeOpt,
+};
+
+/// 台帳の既定のファイル名
+const LEDGER: &str = ".gst-learn-watch";
+/// 処理するファイルの拡張子
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "mp4", "m4v", "mov", "mkv", "webm", "avi", "ts", "mts", "m2ts", "flv", "ogv", "wav", "flac",
+    "mp3", "ogg", "opus", "m4a", "aac", "mka",
+];
+/// サムネイルの幅
+const THUMBNAIL_WIDTH: i32 = 320;
+
+/// 新しいファイルにする処理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Discovererで調べて、長さとストリームを書く
+    Probe,
+    /// transcodeサブコマンドと同じように再エンコードする
+    Transcode,
+    /// 長さの1割の位置のフレームをPNGにする
+    Thumbnail,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "probe" => Ok(Self::Probe),
+            "transcode" => Ok(Self::Transcode),
+            "thumbnail" => Ok(Self::Thumbnail),
+            _ => anyhow::bail!("unknown action {s}, expected probe|transcode|thumbnail"),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Probe => "probe",
+            Self::Transcode => "transcode",
+            Self::Thumbnail => "thumbnail",
+        })
+    }
+}
+
+/// 見張りの設定
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// 見張るディレクトリ。サブディレクトリは見ない
+    pub dir: PathBuf,
+    /// 処理の結果を書くディレクトリ
+    pub output: PathBuf,
+    pub action: Action,
+    /// transcodeのコンテナ
+    pub container: Container,
+    /// 同時に処理するファイルの数
+    pub jobs: usize,
+    /// 台帳のパス。Noneなら見張るディレクトリの.gst-learn-watch
+    pub ledger: Option<PathBuf>,
+}
+
+impl WatchOptions {
+    /// 処理の結果のファイル
+    fn output_path(&self, input: &Path) -> anyhow::Result<PathBuf> {
+        let stem = input.file_stem().context("file without a name")?;
+        let extension = match self.action {
+            Action::Probe => "txt",
+            Action::Transcode => self.container.extension(),
+            Action::Thumbnail => "png",
+        };
+        Ok(self
+            .output
+            .join(format!("{}.{extension}", stem.to_string_lossy())))
+    }
+}
+
+/// 処理が済んだファイルの台帳と、キューに入っているか処理中のファイル
+struct Ledger {
+    file: File,
+    action: Action,
+    /// 成功したファイルの名前
+    done: HashSet<String>,
+    pending: HashSet<String>,
+}
+
+impl Ledger {
+    /// 台帳を読み、追記するために開く
+    /// 1行は"<処理>\t<ok|failed>\t<ファイル名>"で、失敗したときは後ろに理由が付く
+    fn open(path: &Path, action: Action) -> anyhow::Result<Self> {
+        let mut done = HashSet::new();
+        if path.exists() {
+            let reader = BufReader::new(
+                File::open(path).with_context(|| format!("read {}", path.display()))?,
+            );
+            for line in reader.lines() {
+                let line = line?;
+                let fields = line.split('\t').collect::<Vec<_>>();
+                if let [entry_action, "ok", name, ..] = fields[..] {
+                    if entry_action == action.to_string() {
+                        done.insert(name.to_string());
+                    }
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        log::info!(
+            "ledger {}: {} files already processed with {action}",
+            path.display(),
+            done.len()
+        );
+        Ok(Self {
+            file,
+            action,
+            done,
+            pending: HashSet::new(),
+        })
+    }
+
+    /// まだ処理していなければキューに入れる印を付けてtrue
+    fn claim(&mut self, name: &str) -> bool {
+        !self.done.contains(name) && self.pending.insert(name.to_string())
+    }
+
+    fn finish(&mut self, name: &str, result: &anyhow::Result<()>) {
+        self.pending.remove(name);
+        let line = match result {
+            Ok(()) => {
+                self.done.insert(name.to_string());
+                format!("{}\tok\t{name}", self.action)
+            }
+            Err(err) => format!(
+                "{}\tfailed\t{name}\t{}",
+                self.action,
+                format!("{err:#}").replace(['\t', '\n'], " ")
+            ),
+        };
+        if let Err(err) = writeln!(self.file, "{line}") {
+            log::warn!("failed to write the ledger: {err}");
+        }
+    }
+}
+
+/// 見張りの対象になるファイルか。台帳や書きかけの隠しファイルは除く
+fn is_media(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(true);
+    let media = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| MEDIA_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or_default();
+    !hidden && media && path.is_file()
+}
+
+/// Discovererで調べた長さとストリームを書く
+fn probe(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let uri = glib::filename_to_uri(input.canonicalize()?, None)?;
+    let discoverer = Discoverer::new(10 * gst::ClockTime::SECOND)?;
+    let info = discoverer.discover_uri(&uri)?;
+    let mut report = format!("uri: {uri}\nduration: {}\n", info.duration().display());
+    for stream in info.stream_list() {
+        report.push_str(&format!(
+            "{}: {}\n",
+            stream.stream_type_nick(),
+            stream
+                .caps()
+                .map(|caps| caps.to_string())
+                .unwrap_or_default()
+        ));
+    }
+    fs::write(output, &report).with_context(|| format!("write {}", output.display()))?;
+    log::info!("{}:\n{report}", input.display());
+    Ok(())
+}
+
+/// プリロールしたフレームをPNGにして書く
+fn write_thumbnail(
+    pipeline: &gst::Pipeline,
+    appsink: &AppSink,
+    output: &Path,
+    timeout: Option<gst::ClockTime>,
+) -> anyhow::Result<()> {
+    runner::set_state(pipeline, gst::State::Paused, timeout)?;
+    if let Some(duration) = pipeline.query_duration::<gst::ClockTime>() {
+        pipeline.seek_simple(
+            gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+            duration / 10,
+        )?;
+        // シークした位置でプリロールし直すのを待つ
+        let _ = pipeline.state(timeout);
+    }
+    let sample = appsink
+        .pull_preroll()
+        .context("no video frame (audio only?)")?;
+    let png = gstreamer_video::convert_sample(
+        &sample,
+        &gst::Caps::builder("image/png")
+            .field("width", THUMBNAIL_WIDTH)
+            .build(),
+        5 * gst::ClockTime::SECOND,
+    )?;
+    let buffer = png.buffer().context("converted sample without buffer")?;
+    let map = buffer.map_readable()?;
+    fs::write(output, map.as_slice()).with_context(|| format!("write {}", output.display()))
+}
+
+/// 長さの1割の位置にシークしてプリロールしたフレームをPNGにする
+fn thumbnail(input: &Path, output: &Path, timeout: Option<gst::ClockTime>) -> anyhow::Result<()> {
+    let uri = glib::filename_to_uri(input.canonicalize()?, None)?;
+    let pipeline = fallback::parse_launch(&format!(
+        "uridecodebin uri=\"{uri}\" ! videoconvert ! appsink name=sink sync=false"
+    ))?
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+    let appsink = pipeline
+        .by_name("sink")
+        .context("sink")?
+        .dynamic_cast::<AppSink>()
+        .unwrap();
+
+    let result = write_thumbnail(&pipeline, &appsink, output, timeout);
+    runner::rollback(&pipeline);
+    result
+}
+
+/// 見張りの処理に使う設定
+struct Worker {
+    options: WatchOptions,
+    deinterlace: DeinterlaceOpt,
+    video: VideoEncodeOpt,
+    audio: AudioEncodeOpt,
+    bus_loop: BusLoopOpt,
+}
+
+impl Worker {
+    fn process(&self, input: &Path) -> anyhow::Result<PathBuf> {
+        let output = self.options.output_path(input)?;
+        match self.options.action {
+            Action::Probe => probe(input, &output)?,
+            Action::Transcode => transcode::run(
+                &TranscodeOptions {
+                    input: input.to_str().context("non UTF-8 path")?.to_string(),
+                    output: output.to_str().context("non UTF-8 path")?.to_string(),
+                    container: self.options.container,
+                    two_pass: false,
+                },
+                &self.deinterlace,
+                &self.video,
+                &self.audio,
+                &self.bus_loop,
+            )?,
+            Action::Thumbnail => thumbnail(input, &output, self.bus_loop.state_timeout())?,
+        }
+        Ok(output)
+    }
+}
+
+/// キューからファイルを取り出して処理し、台帳に書く
+fn run_worker(
+    worker: Arc<Worker>,
+    queue: Arc<Mutex<mpsc::Receiver<PathBuf>>>,
+    ledger: Arc<Mutex<Ledger>>,
+) {
+    loop {
+        // 受け取るときだけロックして、処理している間は他のワーカーが受け取れるようにする
+        let input = match queue.lock().unwrap().recv() {
+            Ok(input) => input,
+            Err(_) => return,
+        };
+        let name = input
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        log::info!("{} {name}", worker.options.action);
+        let result = worker.process(&input);
+        match &result {
+            Ok(output) => log::info!("{name} -> {}", output.display()),
+            Err(err) => log::error!("{name}: {err:#}"),
+        }
+        ledger.lock().unwrap().finish(&name, &result.map(|_| ()));
+    }
+}
+
+/// 台帳にないファイルならキューに入れる
+fn enqueue(path: &Path, ledger: &Mutex<Ledger>, queue: &mpsc::Sender<PathBuf>) {
+    if !is_media(path) {
+        return;
+    }
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return,
+    };
+    if ledger.lock().unwrap().claim(&name) {
+        log::info!("queued {name}");
+        let _ = queue.send(path.to_path_buf());
+    }
+}
+
+/// ディレクトリを見張り、新しいファイルをワーカーで処理し続ける。Ctrl-Cで止める
+/// 止めたときに処理中だったファイルは台帳に載らないので、次に起動したときにやり直す
+pub fn run(
+    options: &WatchOptions,
+    deinterlace: &DeinterlaceOpt,
+    video: &VideoEncodeOpt,
+    audio: &AudioEncodeOpt,
+    bus_loop: &BusLoopOpt,
+) -> anyhow::Result<()> {
+    gst::init()?;
+
+    anyhow::ensure!(options.jobs > 0, "--jobs must be positive");
+    fs::create_dir_all(&options.output)
+        .with_context(|| format!("failed to create {}", options.output.display()))?;
+    let dir = options
+        .dir
+        .canonicalize()
+        .with_context(|| format!("{}", options.dir.display()))?;
+    // 結果を見張るディレクトリに書くと、それがまた新しいファイルとして処理される
+    anyhow::ensure!(
+        options.output.canonicalize()? != dir,
+        "the output directory must differ from the watched directory"
+    );
+    let ledger_path = options.ledger.clone().unwrap_or_else(|| dir.join(LEDGER));
+    let ledger = Arc::new(Mutex::new(Ledger::open(&ledger_path, options.action)?));
+
+    let worker = Arc::new(Worker {
+        options: options.clone(),
+        deinterlace: deinterlace.clone(),
+        video: video.clone(),
+        audio: audio.clone(),
+        bus_loop: bus_loop.clone(),
+    });
+    let (tx, rx) = mpsc::channel();
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..options.jobs {
+        let worker = worker.clone();
+        let rx = rx.clone();
+        let ledger = ledger.clone();
+        thread::spawn(move || run_worker(worker, rx, ledger));
+    }
+
+    // 止めている間に置かれたファイルを先に処理する
+    let mut existing = fs::read_dir(&dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    existing.sort();
+    for path in &existing {
+        enqueue(path, &ledger, &tx);
+    }
+
+    let main_loop = glib::MainLoop::new(None, false);
+    let monitor = gio::File::for_path(&dir)
+        .monitor_directory(
+            gio::FileMonitorFlags::WATCH_MOVES,
+            None::<&gio::Cancellable>,
+        )
+        .with_context(|| format!("failed to watch {}", dir.display()))?;
+    monitor.connect_changed(move |_, file, other, event| {
+        let file = match event {
+            gio::FileMonitorEvent::ChangesDoneHint | gio::FileMonitorEvent::MovedIn => Some(file),
+            // 名前を変えたときは変えた後の名前が2つ目に来る
+            gio::FileMonitorEvent::Renamed => other,
+            _ => None,
+        };
+        if let Some(path) = file.and_then(|file| file.path()) {
+            enqueue(&path, &ledger, &tx);
+        }
+    });
+    log::info!(
+        "watching {} to {} with {} jobs",
+        dir.display(),
+        options.action,
+        options.jobs
+    );
+    main_loop.run();
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! std::netの上に書いた最小限のWebSocketのサーバー側(RFC 6455)
+//!
+//! ハンドシェイクはSec-WebSocket-KeyにGUIDを付けたSHA-1をbase64にして返すだけ。
+//! テキストのメッセージとping/pong/closeだけを扱い、拡張やサブプロトコルは断らずに無視する。
+//! 読み込みと書き込みは別のスレッドから使えるように、書き込み側をWebSocketWriterとして分ける。
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+
+/// Sec-WebSocket-Acceptを作るときにキーに付ける決まった文字列
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// 受け取る1メッセージの大きさの上限
+const MAX_MESSAGE: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// クライアントのキーに返すSec-WebSocket-Accept
+fn accept_key(key: &str) -> String {
+    base64::encode(Sha1::digest(format!("{key}{GUID}").as_bytes()))
+}
+
+/// WebSocketの書き込み側。cloneして別のスレッドから送れる
+#[derive(Debug, Clone)]
+pub struct WebSocketWriter(Arc<Mutex<TcpStream>>);
+
+impl WebSocketWriter {
+    /// サーバーからのフレームはマスクしない
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.0.lock().unwrap().write_all(&frame)
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send(OP_TEXT, text.as_bytes())
+    }
+
+    /// 同じ接続の書き込み側か
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// ハンドシェイクが済んだWebSocketの接続
+#[derive(Debug)]
+pub struct WebSocket {
+    reader: BufReader<TcpStream>,
+    writer: WebSocketWriter,
+}
+
+impl WebSocket {
+    /// HTTPのUpgradeのリクエストを読んで101を返す
+    pub fn accept(stream: TcpStream) -> anyhow::Result<Self> {
+        let writer = WebSocketWriter(Arc::new(Mutex::new(stream.try_clone()?)));
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut key = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                    key = Some(value.trim().to_string());
+                }
+            }
+        }
+        let key = match key {
+            Some(key) if request_line.starts_with("GET ") => key,
+            _ => {
+                let _ = writer.0.lock().unwrap().write_all(
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+                anyhow::bail!("not a WebSocket request: {}", request_line.trim());
+            }
+        };
+        writer.0.lock().unwrap().write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            )
+            .as_bytes(),
+        )?;
+        Ok(Self { reader, writer })
+    }
+
+    pub fn writer(&self) -> WebSocketWriter {
+        self.writer.clone()
+    }
+
+    /// フレームを1つ読む。(FIN, opcode, マスクを外したペイロード)
+    fn read_frame(&mut self) -> anyhow::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        anyhow::ensure!(len <= MAX_MESSAGE, "frame too large ({len} bytes)");
+        // クライアントからのフレームは必ずマスクされている
+        anyhow::ensure!(masked, "unmasked frame from the client");
+        let mut mask = [0u8; 4];
+        self.reader.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len];
+        self.reader.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    /// テキストのメッセージを1つ読む。pingには応え、closeか切断ならNone
+    pub fn read_text(&mut self) -> anyhow::Result<Option<String>> {
+        let mut message = Vec::new();
+        let mut message_opcode = None;
+        loop {
+            let (fin, opcode, payload) = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(err) => match err.downcast_ref::<io::Error>() {
+                    Some(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    _ => return Err(err),
+                },
+            };
+            match opcode {
+                OP_PING => self.writer.send(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let _ = self.writer.send(OP_CLOSE, &payload);
+                    return Ok(None);
+                }
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    if opcode != OP_CONTINUATION {
+                        message_opcode = Some(opcode);
+                        message.clear();
+                    }
+                    message.extend_from_slice(&payload);
+                    anyhow::ensure!(message.len() <= MAX_MESSAGE, "message too large");
+                    if !fin {
+                        continue;
+                    }
+                    match message_opcode {
+                        Some(OP_TEXT) => {
+                            return String::from_utf8(message)
+                                .map(Some)
+                                .context("text message is not UTF-8")
+                        }
+                        _ => log::debug!("ignoring binary message"),
+                    }
+                }
+                _ => anyhow::bail!("unknown opcode {opcode:#x}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_rfc6455_example() {
+        // RFC 6455 1.3の例
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}